@@ -0,0 +1,148 @@
+use std::collections::BTreeMap;
+use serde::Serialize;
+
+/// Kinds of soft anomalies, i.e. inconsistencies in the file system that the scan works around.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AnomalyKind {
+    /// A group descriptor with an invalid checksum; the group is not scanned.
+    BadDescriptorChecksum,
+    /// An extent index pointing outside of the file system; the index is skipped.
+    ExtentIndexOutOfRange,
+    /// A backup superblock whose key fields differ from the primary superblock.
+    BackupSuperblockMismatch,
+    /// A backup superblock with a bad magic value or checksum.
+    StaleBackupSuperblock,
+    /// Bitmaps or an inode table outside of the (flex) group expected to hold them.
+    MisplacedGroupMetadata,
+    /// A bitmap with an invalid checksum, used anyway with `--ignore-csum-errors`.
+    BadBitmapChecksum,
+    /// An inode with an invalid checksum; its blocks are not followed.
+    BadInodeChecksum,
+    /// An extent tree block with an invalid checksum, followed anyway with `--ignore-csum-errors`.
+    BadExtentChecksum,
+    /// A run of blocks on which the block bitmap and the scan disagree (with `--cross-check`).
+    BitmapDiscrepancy,
+    /// A free count in the superblock or a group descriptor that differs from the scan.
+    FreeCountMismatch,
+    /// An F2FS node block in use according to the NAT, but free in the SIT; the block is kept.
+    FreeNodeBlock,
+}
+
+/// Anomalies found during the scan, counted by their kind.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Anomalies(BTreeMap<AnomalyKind, u64>);
+
+impl Anomalies {
+    /// Records an occurrence of an anomaly.
+    pub fn record(&mut self, kind: AnomalyKind)
+    {
+        *self.0.entry(kind).or_insert(0) += 1;
+    }
+
+    /// Returns the number of recorded occurrences of an anomaly.
+    pub fn count(&self, kind: AnomalyKind) -> u64
+    {
+        self.0.get(&kind).copied().unwrap_or(0)
+    }
+
+    pub fn is_empty(&self) -> bool
+    {
+        self.0.is_empty()
+    }
+
+    /// Returns a one-line summary of the anomalies.
+    pub fn summary(&self) -> String
+    {
+        if self.is_empty() {
+            return String::from("none");
+        }
+
+        self.0.iter()
+            .map(|(kind, count)| describe(*kind, *count))
+            .collect::<Vec<String>>()
+            .join(", ")
+    }
+}
+
+/// Describes the number of occurrences of an anomaly.
+fn describe(kind: AnomalyKind, count: u64) -> String
+{
+    let (one, many) = match kind {
+        AnomalyKind::BadDescriptorChecksum => (
+            "descriptor with a bad checksum",
+            "descriptors with bad checksums",
+        ),
+        AnomalyKind::ExtentIndexOutOfRange => (
+            "out-of-range extent index skipped",
+            "out-of-range extent indexes skipped",
+        ),
+        AnomalyKind::BackupSuperblockMismatch => (
+            "backup superblock differing from the primary",
+            "backup superblocks differing from the primary",
+        ),
+        AnomalyKind::StaleBackupSuperblock => (
+            "stale backup superblock",
+            "stale backup superblocks",
+        ),
+        AnomalyKind::MisplacedGroupMetadata => (
+            "misplaced group metadata",
+            "misplaced group metadata",
+        ),
+        AnomalyKind::BadBitmapChecksum => (
+            "bitmap with a bad checksum",
+            "bitmaps with bad checksums",
+        ),
+        AnomalyKind::BadInodeChecksum => (
+            "inode with a bad checksum skipped",
+            "inodes with bad checksums skipped",
+        ),
+        AnomalyKind::BadExtentChecksum => (
+            "extent block with a bad checksum",
+            "extent blocks with bad checksums",
+        ),
+        AnomalyKind::BitmapDiscrepancy => (
+            "block run disagreeing with the bitmaps",
+            "block runs disagreeing with the bitmaps",
+        ),
+        AnomalyKind::FreeCountMismatch => (
+            "free count differing from the scan",
+            "free counts differing from the scan",
+        ),
+        AnomalyKind::FreeNodeBlock => (
+            "node block free in the SIT kept",
+            "node blocks free in the SIT kept",
+        ),
+    };
+
+    format!("{} {}", count, if count == 1 { one } else { many })
+}
+
+// Tests.
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn summary()
+    {
+        let mut anomalies = Anomalies::default();
+        assert_eq!(anomalies.summary(), "none");
+
+        for _ in 0..12 {
+            anomalies.record(AnomalyKind::BadDescriptorChecksum);
+        }
+        anomalies.record(AnomalyKind::ExtentIndexOutOfRange);
+
+        assert_eq!(anomalies.count(AnomalyKind::BadDescriptorChecksum), 12);
+        assert_eq!(
+            anomalies.summary(),
+            "12 descriptors with bad checksums, 1 out-of-range extent index skipped",
+        );
+        assert_eq!(
+            serde_json::to_string(&anomalies).unwrap(),
+            r#"{"bad_descriptor_checksum":12,"extent_index_out_of_range":1}"#,
+        );
+    }
+}
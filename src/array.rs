@@ -12,7 +12,7 @@ where
 {
     fn default() -> Self
     {
-        Self { 0: [T::default(); C] }
+        Self([T::default(); C])
     }
 }
 
@@ -81,7 +81,7 @@ where
     where
         A: SeqAccess<'de>
     {
-        let mut arr = Array { 0: [T::default(); C] };
+        let mut arr = Array([T::default(); C]);
 
         for i in 0..C {
             arr.0[i] = match seq.next_element()? {
@@ -101,44 +101,44 @@ mod tests {
     #[test]
     fn array0_equal()
     {
-        let arr1:  Array<u32, 0> = Array {0: []};
+        let arr1:  Array<u32, 0> = Array([]);
 
-        assert_eq!(arr1, Array {0: []});
+        assert_eq!(arr1, Array([]));
     }
 
     #[test]
     fn array1_equal()
     {
-        let mut arr1:  Array<u32, 1> = Array {0: [0]};
+        let mut arr1:  Array<u32, 1> = Array([0]);
         arr1.0[0] = 4;
 
-        assert_eq!(arr1, Array {0: [4]});
+        assert_eq!(arr1, Array([4]));
     }
 
     #[test]
     #[should_panic]
     fn array1_not_equal()
     {
-        let arr1:  Array<u32, 1> = Array {0: [0]};
+        let arr1:  Array<u32, 1> = Array([0]);
 
-        assert_eq!(arr1, Array {0: [4]});
+        assert_eq!(arr1, Array([4]));
     }
 
     #[test]
     fn array5_equal()
     {
-        let mut arr1:  Array<u32, 5> = Array {0: [0, 2, 4, 5, 6]};
+        let mut arr1:  Array<u32, 5> = Array([0, 2, 4, 5, 6]);
         arr1.0[0] = 4;
 
-        assert_eq!(arr1, Array {0: [4, 2, 4, 5, 6]});
+        assert_eq!(arr1, Array([4, 2, 4, 5, 6]));
     }
 
     #[test]
     #[should_panic]
     fn array5_not_equal()
     {
-        let arr1:  Array<u32, 5> = Array {0: [0, 2, 4, 5, 6]};
+        let arr1:  Array<u32, 5> = Array([0, 2, 4, 5, 6]);
 
-        assert_eq!(arr1, Array {0: [4, 2, 4, 5, 6]});
+        assert_eq!(arr1, Array([4, 2, 4, 5, 6]));
     }
 }
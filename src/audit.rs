@@ -0,0 +1,132 @@
+use std::io::{Read, Seek, SeekFrom};
+use serde::Serialize;
+
+use crate::usage_map::UsageMap;
+
+/// The size of the chunks in which the free space is read.
+const READ_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// The contents of the free space: how much of it still holds data.
+#[derive(Debug, Default, Serialize)]
+pub struct Audit {
+    pub free_segments: u64,
+    pub free_bytes: u64,
+    /// The number of free segments with non-zero bytes.
+    pub nonzero_segments: u64,
+    pub nonzero_bytes: u64,
+    /// The free segments with non-zero bytes.
+    pub remnants: Vec<SegmentAudit>,
+}
+
+/// The contents of a free segment.
+#[derive(Debug, Serialize, PartialEq)]
+pub struct SegmentAudit {
+    pub start: u64,
+    pub end: u64,
+    pub nonzero_bytes: u64,
+    /// The Shannon entropy of the bytes, in bits per byte (0 to 8). Random fills approach 8;
+    /// remnants of files are usually lower, unless they were compressed or encrypted.
+    pub entropy: f64,
+}
+
+/// Reads all the free segments of the map and reports the ones that are not zeroed.
+pub fn audit_free<R: Read + Seek>(map: &UsageMap, drive: &mut R) -> std::io::Result<Audit>
+{
+    let mut audit = Audit::default();
+    let mut buf = vec![0u8; READ_CHUNK_SIZE];
+
+    for seg in map.iter_free() {
+        let mut counts = [0u64; 256];
+
+        drive.seek(SeekFrom::Start(seg.start))?;
+        let mut pos = seg.start;
+        while pos < seg.end {
+            let len = std::cmp::min(seg.end - pos, buf.len() as u64) as usize;
+            drive.read_exact(&mut buf[..len])?;
+            for b in &buf[..len] {
+                counts[*b as usize] += 1;
+            }
+            pos += len as u64;
+        }
+
+        let size = seg.end - seg.start;
+        let nonzero_bytes = size - counts[0];
+
+        audit.free_segments += 1;
+        audit.free_bytes += size;
+
+        if nonzero_bytes > 0 {
+            audit.nonzero_segments += 1;
+            audit.nonzero_bytes += nonzero_bytes;
+            audit.remnants.push(SegmentAudit {
+                start: seg.start,
+                end: seg.end,
+                nonzero_bytes,
+                entropy: entropy(&counts, size),
+            });
+        }
+    }
+
+    Ok(audit)
+}
+
+/// Computes the Shannon entropy of the bytes with the supplied counts, in bits per byte.
+fn entropy(counts: &[u64; 256], total: u64) -> f64
+{
+    counts.iter()
+        .filter(|c| **c > 0)
+        .map(|c| {
+            let p = *c as f64 / total as f64;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+// Tests.
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::usage_map::AllocStatus;
+
+    #[test]
+    fn remnants()
+    {
+        let mut drive = vec![0u8; 4096];
+        // Two distinct values, evenly spread: 1 bit per byte.
+        for (i, b) in drive[1024..2048].iter_mut().enumerate() {
+            *b = if i % 2 == 0 { b'a' } else { b'b' };
+        }
+        // All the byte values, equally often: 8 bits per byte.
+        for (i, b) in drive[2048..2560].iter_mut().enumerate() {
+            *b = i as u8;
+        }
+        // Used data is not audited.
+        drive[3072..].fill(0xff);
+
+        let mut map = UsageMap::new(4096);
+        map.update(0, 1024, AllocStatus::Free);
+        map.update(1024, 1024, AllocStatus::Free);
+        map.update(2048, 1024, AllocStatus::Free);
+        map.update(3072, 1024, AllocStatus::Used);
+        // The free segments are kept apart by tiny used ones.
+        map.update(1023, 1, AllocStatus::Used);
+        map.update(2047, 1, AllocStatus::Used);
+
+        let audit = audit_free(&map, &mut Cursor::new(drive)).unwrap();
+
+        assert_eq!(audit.free_segments, 3);
+        assert_eq!(audit.free_bytes, 3070);
+        assert_eq!(audit.nonzero_segments, 2);
+        // The null byte is among the 512 values, twice.
+        assert_eq!(audit.nonzero_bytes, 1023 + 510);
+
+        assert_eq!(audit.remnants[0].start, 1024);
+        assert!((audit.remnants[0].entropy - 1.0).abs() < 0.01);
+        assert_eq!(audit.remnants[1].nonzero_bytes, 510);
+        // The rest of the segment is zeroed.
+        assert!(audit.remnants[1].entropy > 4.0 && audit.remnants[1].entropy < 8.0);
+    }
+}
@@ -8,7 +8,7 @@ impl Bitmap {
     /// Create a new Bitmap from a slice of bytes.
     pub fn from_bytes(bytes: &[u8]) -> Self
     {
-        Self { 0: bytes.to_vec() }
+        Self(bytes.to_vec())
     }
 
     /// Create a new Bitmap from a Reader.
@@ -18,7 +18,7 @@ impl Bitmap {
         let mut vec = vec![u8::default(); size];
         reader.read_exact(&mut vec)?;
 
-        Ok(Self { 0: vec })
+        Ok(Self(vec))
     }
 
     /// Tests the bit indexed by idx.
@@ -44,7 +44,30 @@ impl Bitmap {
     }
 }
 
+// Debug and Display implementations
+
+impl std::fmt::Display for Bitmap {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error>
+    {
+        f.debug_list()
+            .entries(self.check_all().iter().map(|b| { if *b {1u8} else {0u8} }))
+            .finish()
+    }
+
+}
+
+impl std::fmt::Debug for Bitmap {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error>
+    {
+        f.debug_list()
+            .entries(&self.0)
+            .finish()
+    }
+
+}
+
 #[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
 mod tests {
     use super::Bitmap;
 
@@ -81,25 +104,3 @@ mod tests {
         assert_eq!(true,  bmp.check_bit(23));
     }
 }
-
-// Debug and Display implementations
-
-impl std::fmt::Display for Bitmap {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error>
-    {
-        f.debug_list()
-            .entries(self.check_all().iter().map(|b| { if *b {1u8} else {0u8} }))
-            .finish()
-    }
-
-}
-
-impl std::fmt::Debug for Bitmap {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error>
-    {
-        f.debug_list()
-            .entries(&self.0)
-            .finish()
-    }
-
-}
@@ -0,0 +1,126 @@
+use std::io::{Read, Seek, SeekFrom};
+use std::path::PathBuf;
+
+use crate::Context;
+
+// Reference: LUKS1 On-Disk Format Specification 1.2.3; LUKS2 On-Disk Format Specification 1.1.1.
+
+const MAGIC: &[u8; 6] = b"LUKS\xba\xbe";
+/// The offset of the UUID, the same in both versions.
+const UUID_OFFSET: usize = 168;
+const UUID_LEN: usize = 40;
+
+/// The identifying part of a LUKS header.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Header {
+    pub version: u16,
+    pub uuid: String,
+}
+
+/// Reads the LUKS header, if the drive is a LUKS container.
+pub fn read_header(ctx: &mut Context) -> anyhow::Result<Option<Header>>
+{
+    let drive_size = ctx.drive.seek(SeekFrom::End(0))?;
+    if drive_size < (UUID_OFFSET + UUID_LEN) as u64 {
+        return Ok(None);
+    }
+
+    let mut raw = [0u8; UUID_OFFSET + UUID_LEN];
+    ctx.drive.seek(SeekFrom::Start(0))?;
+    ctx.drive.read_exact(&mut raw)?;
+
+    if &raw[..6] != MAGIC {
+        return Ok(None);
+    }
+
+    let uuid = &raw[UUID_OFFSET..];
+    let uuid_len = uuid.iter().position(|b| *b == 0).unwrap_or(UUID_LEN);
+
+    Ok(Some(Header {
+        version: u16::from_be_bytes([raw[6], raw[7]]),
+        uuid: String::from_utf8_lossy(&uuid[..uuid_len]).into_owned(),
+    }))
+}
+
+/// Checks whether a device-mapper UUID belongs to a mapping of the LUKS container. cryptsetup
+/// names them `CRYPT-LUKS<version>-<UUID without dashes>-<name>`.
+pub fn is_mapping_of(dm_uuid: &str, header: &Header) -> bool
+{
+    let prefix = format!("CRYPT-LUKS{}-{}-", header.version, header.uuid.replace('-', ""));
+
+    dm_uuid.starts_with(&prefix)
+}
+
+/// Finds the opened dm-crypt mapper device of the LUKS container, if it is opened.
+#[cfg(target_os = "linux")]
+pub fn find_mapper(header: &Header) -> anyhow::Result<Option<PathBuf>>
+{
+    for entry in std::fs::read_dir("/sys/block")? {
+        let dm = entry?.path().join("dm");
+
+        let dm_uuid = match std::fs::read_to_string(dm.join("uuid")) {
+            Ok(uuid) => uuid,
+            // Not a device-mapper device.
+            Err(_) => continue,
+        };
+
+        if is_mapping_of(dm_uuid.trim(), header) {
+            let name = std::fs::read_to_string(dm.join("name"))?;
+            return Ok(Some(PathBuf::from("/dev/mapper").join(name.trim())));
+        }
+    }
+
+    Ok(None)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn find_mapper(_header: &Header) -> anyhow::Result<Option<PathBuf>>
+{
+    anyhow::bail!("finding the mapper device of a LUKS container is only supported on Linux");
+}
+
+// Tests.
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::util::test_context;
+
+    pub const TEST_UUID: &str = "0b5ab7e6-4c4c-4d0b-a3bf-3c4a0d7e0f11";
+
+    /// Creates a drive with a LUKS header of the supplied version.
+    pub fn test_drive(version: u16) -> Vec<u8>
+    {
+        let mut drive = vec![0; 4096];
+        drive[..6].copy_from_slice(MAGIC);
+        drive[6..8].copy_from_slice(&version.to_be_bytes());
+        drive[UUID_OFFSET..UUID_OFFSET + TEST_UUID.len()].copy_from_slice(TEST_UUID.as_bytes());
+
+        drive
+    }
+
+    #[test]
+    fn header()
+    {
+        let mut ctx = test_context(&test_drive(2));
+
+        let header = read_header(&mut ctx).unwrap().unwrap();
+
+        assert_eq!(header, Header { version: 2, uuid: String::from(TEST_UUID) });
+
+        let mut ctx = test_context(&[0; 4096]);
+        assert!(read_header(&mut ctx).unwrap().is_none());
+    }
+
+    #[test]
+    fn mapping()
+    {
+        let header = Header { version: 2, uuid: String::from(TEST_UUID) };
+
+        assert!(is_mapping_of("CRYPT-LUKS2-0b5ab7e64c4c4d0ba3bf3c4a0d7e0f11-cryptroot", &header));
+        // Another version, another container, a plain mapping.
+        assert!(!is_mapping_of("CRYPT-LUKS1-0b5ab7e64c4c4d0ba3bf3c4a0d7e0f11-cryptroot", &header));
+        assert!(!is_mapping_of("CRYPT-LUKS2-1b5ab7e64c4c4d0ba3bf3c4a0d7e0f11-cryptroot", &header));
+        assert!(!is_mapping_of("LVM-abcdef", &header));
+    }
+}
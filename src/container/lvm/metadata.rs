@@ -0,0 +1,254 @@
+use anyhow::bail;
+
+// Reference: the text format of the LVM2 metadata (lib/format_text/ and lib/config/ in LVM2).
+
+/// A value of the metadata.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Value {
+    Int(i64),
+    Str(String),
+    List(Vec<Value>),
+    Section(Section),
+}
+
+/// A section of the metadata: named values and subsections, in order.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Section(pub Vec<(String, Value)>);
+
+impl Section {
+    pub fn get(&self, key: &str) -> Option<&Value>
+    {
+        self.0.iter().find(|(k, _)| k == key).map(|(_, v)| v)
+    }
+
+    pub fn int(&self, key: &str) -> anyhow::Result<i64>
+    {
+        match self.get(key) {
+            Some(Value::Int(n)) => Ok(*n),
+            Some(_) => bail!("LVM metadata: `{}` is not a number", key),
+            None => bail!("LVM metadata: missing `{}`", key),
+        }
+    }
+
+    pub fn str(&self, key: &str) -> anyhow::Result<&str>
+    {
+        match self.get(key) {
+            Some(Value::Str(s)) => Ok(s),
+            Some(_) => bail!("LVM metadata: `{}` is not a string", key),
+            None => bail!("LVM metadata: missing `{}`", key),
+        }
+    }
+
+    pub fn section(&self, key: &str) -> anyhow::Result<&Section>
+    {
+        match self.get(key) {
+            Some(Value::Section(s)) => Ok(s),
+            Some(_) => bail!("LVM metadata: `{}` is not a section", key),
+            None => bail!("LVM metadata: missing `{}`", key),
+        }
+    }
+
+    /// Iterates over the subsections.
+    pub fn sections(&self) -> impl Iterator<Item = (&str, &Section)>
+    {
+        self.0.iter().filter_map(|(k, v)| match v {
+            Value::Section(s) => Some((k.as_str(), s)),
+            _ => None,
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Token {
+    Ident(String),
+    Int(i64),
+    Str(String),
+    Punct(char),
+}
+
+/// Parses the metadata text into its top-level section.
+pub fn parse(text: &str) -> anyhow::Result<Section>
+{
+    let tokens = tokenize(text)?;
+    let mut pos = 0;
+
+    let section = parse_section(&tokens, &mut pos)?;
+    if pos != tokens.len() {
+        bail!("LVM metadata: unexpected `}}`");
+    }
+
+    Ok(section)
+}
+
+fn tokenize(text: &str) -> anyhow::Result<Vec<Token>>
+{
+    let mut tokens = Vec::new();
+    let mut chars = text.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => { chars.next(); },
+            '#' => {
+                while chars.next_if(|c| *c != '\n').is_some() {}
+            },
+            '=' | '{' | '}' | '[' | ']' | ',' => {
+                chars.next();
+                tokens.push(Token::Punct(c));
+            },
+            '"' => {
+                chars.next();
+                let mut s = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c) => s.push(c),
+                            None => bail!("LVM metadata: unterminated string"),
+                        },
+                        Some(c) => s.push(c),
+                        None => bail!("LVM metadata: unterminated string"),
+                    }
+                }
+                tokens.push(Token::Str(s));
+            },
+            _ => {
+                let mut word = String::new();
+                while let Some(c) = chars.next_if(|c| is_word_char(*c)) {
+                    word.push(c);
+                }
+
+                let starts_numeric = word.starts_with(|c: char| c.is_ascii_digit() || c == '-');
+                match word.parse::<i64>() {
+                    Ok(n) => tokens.push(Token::Int(n)),
+                    Err(_) if starts_numeric => bail!("LVM metadata: invalid number: {}", word),
+                    Err(_) => tokens.push(Token::Ident(word)),
+                }
+            },
+        }
+    }
+
+    Ok(tokens)
+}
+
+/// Checks whether a character belongs to a name or a number.
+fn is_word_char(c: char) -> bool
+{
+    !c.is_whitespace() && !"#={}[],\"".contains(c)
+}
+
+/// Parses the contents of a section, up to its closing brace or the end of the text.
+fn parse_section(tokens: &[Token], pos: &mut usize) -> anyhow::Result<Section>
+{
+    let mut section = Section::default();
+
+    while let Some(token) = tokens.get(*pos) {
+        let key = match token {
+            Token::Ident(key) => key.clone(),
+            Token::Punct('}') => break,
+            t => bail!("LVM metadata: expected a name, found {:?}", t),
+        };
+        *pos += 1;
+
+        let value = match tokens.get(*pos) {
+            Some(Token::Punct('=')) => {
+                *pos += 1;
+                parse_value(tokens, pos)?
+            },
+            Some(Token::Punct('{')) => {
+                *pos += 1;
+                let sub = parse_section(tokens, pos)?;
+                if tokens.get(*pos) != Some(&Token::Punct('}')) {
+                    bail!("LVM metadata: unterminated section `{}`", key);
+                }
+                *pos += 1;
+                Value::Section(sub)
+            },
+            _ => bail!("LVM metadata: expected `=` or `{{` after `{}`", key),
+        };
+
+        section.0.push((key, value));
+    }
+
+    Ok(section)
+}
+
+fn parse_value(tokens: &[Token], pos: &mut usize) -> anyhow::Result<Value>
+{
+    let value = match tokens.get(*pos) {
+        Some(Token::Int(n)) => Value::Int(*n),
+        Some(Token::Str(s)) => Value::Str(s.clone()),
+        Some(Token::Punct('[')) => {
+            *pos += 1;
+            let mut items = Vec::new();
+            loop {
+                match tokens.get(*pos) {
+                    Some(Token::Punct(']')) => break,
+                    Some(Token::Punct(',')) if !items.is_empty() => *pos += 1,
+                    _ if items.is_empty() => {},
+                    t => bail!("LVM metadata: expected `,` or `]`, found {:?}", t),
+                }
+                if tokens.get(*pos) == Some(&Token::Punct(']')) {
+                    break;
+                }
+                items.push(parse_value(tokens, pos)?);
+            }
+            Value::List(items)
+        },
+        t => bail!("LVM metadata: expected a value, found {:?}", t),
+    };
+    *pos += 1;
+
+    Ok(value)
+}
+
+// Tests.
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn values()
+    {
+        let text = "vg0 {\n\
+            # A comment.\n\
+            id = \"abc-def\"\n\
+            seqno = 3\n\
+            status = [\"READ\", \"WRITE\"]\n\
+            flags = []\n\
+            inner {\n\
+            stripes = [\n\"pv0\", 0,\n\"pv1\", -1\n]\n\
+            }\n\
+            }\n\
+            contents = \"Text \\\"Format\\\"\"\n";
+
+        let top = parse(text).unwrap();
+        let vg = top.section("vg0").unwrap();
+
+        assert_eq!(vg.str("id").unwrap(), "abc-def");
+        assert_eq!(vg.int("seqno").unwrap(), 3);
+        assert_eq!(vg.get("status"), Some(&Value::List(vec![
+            Value::Str(String::from("READ")),
+            Value::Str(String::from("WRITE")),
+        ])));
+        assert_eq!(vg.get("flags"), Some(&Value::List(vec![])));
+        assert_eq!(vg.section("inner").unwrap().get("stripes"), Some(&Value::List(vec![
+            Value::Str(String::from("pv0")),
+            Value::Int(0),
+            Value::Str(String::from("pv1")),
+            Value::Int(-1),
+        ])));
+        assert_eq!(top.str("contents").unwrap(), "Text \"Format\"");
+        assert_eq!(top.sections().count(), 1);
+    }
+
+    #[test]
+    fn invalid()
+    {
+        assert!(parse("vg0 {\nseqno = 3\n").is_err());
+        assert!(parse("vg0 }").is_err());
+        assert!(parse("seqno = 3x").is_err());
+        assert!(parse("list = [1 2]").is_err());
+        assert!(parse("name = \"unterminated").is_err());
+    }
+}
@@ -0,0 +1,678 @@
+use std::io::{Read, Seek, SeekFrom};
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+use bincode::{DefaultOptions, Options};
+
+use crate::usage_map::{AllocStatus, UsageMap};
+use crate::{Config, Context};
+use metadata::{Section, Value};
+
+pub mod metadata;
+
+// Reference: lib/format_text/layout.h and lib/label/label.h in LVM2.
+
+const SECTOR_SIZE: u64 = 512;
+/// The number of sectors at the start of the drive in which the label is looked for.
+const LABEL_SCAN_SECTORS: u64 = 4;
+const LABEL_ID: &[u8; 8] = b"LABELONE";
+const LABEL_TYPE: &[u8; 8] = b"LVM2 001";
+/// The offset of the part of the label sector covered by the label checksum.
+const LABEL_CRC_START: usize = 20;
+const PV_UUID_LEN: usize = 32;
+
+const MDA_MAGIC: &[u8; 16] = b" LVM2 x[5A%r0N*>";
+const MDA_HEADER_SIZE: u64 = 512;
+/// The offset of the metadata locations in the metadata area header.
+const RAW_LOCN_OFFSET: usize = 40;
+/// The metadata in the area is ignored (`pvchange --metadataignore`).
+const RAW_LOCN_IGNORED: u32 = 0x1;
+/// The limit of the size of the metadata text, against corrupted headers.
+const MAX_METADATA_SIZE: u64 = 64 * 1024 * 1024;
+
+/// The initial value of the LVM checksums, which are CRC32s without the final inversion.
+const INITIAL_CRC: u32 = 0xf597a6cf;
+
+/// The header of the label.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct LabelHeader {
+    pub id: [u8; 8],
+    /// The sector of the label.
+    pub sector_xl: u64,
+    pub crc_xl: u32,
+    /// The offset of the PV header in the label sector.
+    pub offset_xl: u32,
+    pub label_type: [u8; 8],
+}
+
+/// A location on the drive, in bytes.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct DiskLocn {
+    pub offset: u64,
+    /// 0 reaches the end of the drive.
+    pub size: u64,
+}
+
+/// The header of a metadata area.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct MdaHeader {
+    pub checksum_xl: u32,
+    pub magic: [u8; 16],
+    pub version: u32,
+    /// The offset of the metadata area on the drive.
+    pub start: u64,
+    pub size: u64,
+}
+
+/// The location of the metadata text in a metadata area, which is a circular buffer.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RawLocn {
+    /// Relative to the start of the metadata area.
+    pub offset: u64,
+    pub size: u64,
+    pub checksum: u32,
+    pub flags: u32,
+}
+
+/// The label of a physical volume.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PvLabel {
+    /// The UUID of the physical volume, without dashes.
+    pub uuid: String,
+    pub device_size: u64,
+    pub data_areas: Vec<DiskLocn>,
+    pub metadata_areas: Vec<DiskLocn>,
+}
+
+/// The physical extents of a physical volume, according to the metadata of its volume group.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PvExtents {
+    pub vg_name: String,
+    pub pv_name: String,
+    /// In bytes.
+    pub extent_size: u64,
+    /// The offset of the first extent, in bytes.
+    pub pe_start: u64,
+    /// Whether each of the extents is allocated to a logical volume.
+    pub allocated: Vec<bool>,
+}
+
+/// Computes an LVM checksum.
+fn lvm_crc(bytes: &[u8]) -> u32
+{
+    !crc::crc32::update(!INITIAL_CRC, &crc::crc32::IEEE_TABLE, bytes)
+}
+
+/// Reads the label of the physical volume, if the drive is an LVM physical volume.
+pub fn read_label(ctx: &mut Context) -> anyhow::Result<Option<PvLabel>>
+{
+    let bincode_opt = DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes();
+
+    let drive_size = ctx.drive.seek(SeekFrom::End(0))?;
+
+    for sector in 0..LABEL_SCAN_SECTORS {
+        if drive_size < (sector + 1) * SECTOR_SIZE {
+            break;
+        }
+
+        let mut raw = vec![0u8; SECTOR_SIZE as usize];
+        ctx.drive.seek(SeekFrom::Start(sector * SECTOR_SIZE))?;
+        ctx.drive.read_exact(&mut raw)?;
+
+        let header: LabelHeader = bincode_opt.deserialize(&raw)?;
+        if &header.id != LABEL_ID || header.sector_xl != sector || &header.label_type != LABEL_TYPE
+        {
+            continue;
+        }
+
+        if lvm_crc(&raw[LABEL_CRC_START..]) != header.crc_xl {
+            bail!("bad LVM label checksum");
+        }
+
+        return read_pv_header(&raw, header.offset_xl as usize).map(Some);
+    }
+
+    Ok(None)
+}
+
+/// Parses the PV header in the label sector: the UUID, the size and the lists of the data and
+/// metadata areas, each terminated by an empty location.
+fn read_pv_header(raw: &[u8], offset: usize) -> anyhow::Result<PvLabel>
+{
+    let bincode_opt = DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes();
+
+    if offset < LABEL_CRC_START + 12 || offset + PV_UUID_LEN + 8 > raw.len() {
+        bail!("invalid LVM PV header offset: {}", offset);
+    }
+
+    let uuid = String::from_utf8_lossy(&raw[offset..offset + PV_UUID_LEN]).into_owned();
+    let mut pos = offset + PV_UUID_LEN;
+    let device_size: u64 = bincode_opt.deserialize(&raw[pos..])?;
+    pos += 8;
+
+    let mut lists = [Vec::new(), Vec::new()];
+    for list in &mut lists {
+        loop {
+            if pos + 16 > raw.len() {
+                bail!("unterminated list of areas in the LVM PV header");
+            }
+            let locn: DiskLocn = bincode_opt.deserialize(&raw[pos..])?;
+            pos += 16;
+
+            if locn.offset == 0 {
+                break;
+            }
+            list.push(locn);
+        }
+    }
+    let [data_areas, metadata_areas] = lists;
+
+    Ok(PvLabel { uuid, device_size, data_areas, metadata_areas })
+}
+
+/// Reads the metadata of the volume group from the first metadata area in use. Returns None if the
+/// physical volume does not belong to a volume group, i.e. if none of its metadata areas holds
+/// metadata. The ignored areas (`pvcreate --metadataignore`) tell nothing about it, so a physical
+/// volume with only ignored and empty areas is refused, as its volume group cannot be read.
+pub fn read_metadata(ctx: &mut Context, label: &PvLabel) -> anyhow::Result<Option<Section>>
+{
+    let bincode_opt = DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes();
+
+    if label.metadata_areas.is_empty() {
+        bail!("the physical volume has no metadata area, the allocation of its extents is unknown");
+    }
+
+    let mut ignored = 0;
+    let mut empty = 0;
+
+    for mda in &label.metadata_areas {
+        let mut raw = vec![0u8; MDA_HEADER_SIZE as usize];
+        ctx.drive.seek(SeekFrom::Start(mda.offset))?;
+        ctx.drive.read_exact(&mut raw)?;
+
+        let header: MdaHeader = bincode_opt.deserialize(&raw)?;
+        if &header.magic != MDA_MAGIC {
+            bail!("bad magic value of the LVM metadata area at offset {}", mda.offset);
+        }
+        if lvm_crc(&raw[4..]) != header.checksum_xl {
+            bail!("bad checksum of the LVM metadata area at offset {}", mda.offset);
+        }
+        if header.start != mda.offset || header.size <= MDA_HEADER_SIZE {
+            bail!("invalid LVM metadata area header at offset {}", mda.offset);
+        }
+
+        let locn: RawLocn = bincode_opt.deserialize(&raw[RAW_LOCN_OFFSET..])?;
+        if locn.flags & RAW_LOCN_IGNORED != 0 {
+            ignored += 1;
+            continue;
+        }
+        if locn.offset == 0 {
+            empty += 1;
+            continue;
+        }
+
+        let text = read_text(ctx, &header, &locn)?;
+
+        return metadata::parse(&text).map(Some);
+    }
+
+    if ignored == 0 {
+        // Not in a volume group.
+        return Ok(None);
+    }
+
+    bail!(
+        "the metadata of the volume group cannot be read: {} LVM metadata areas are ignored and {} \
+        are empty",
+        ignored,
+        empty,
+    );
+}
+
+/// Reads the metadata text from the circular buffer of a metadata area and verifies its checksum.
+fn read_text(ctx: &mut Context, header: &MdaHeader, locn: &RawLocn) -> anyhow::Result<String>
+{
+    let buffer_size = header.size - MDA_HEADER_SIZE;
+    if locn.offset < MDA_HEADER_SIZE
+        || locn.offset >= header.size
+        || locn.size > buffer_size
+        || locn.size > MAX_METADATA_SIZE
+    {
+        bail!("invalid location of the LVM metadata text: {} bytes at {}", locn.size, locn.offset);
+    }
+
+    let mut raw = vec![0u8; locn.size as usize];
+    let first_len = std::cmp::min(locn.size, header.size - locn.offset) as usize;
+
+    ctx.drive.seek(SeekFrom::Start(header.start + locn.offset))?;
+    ctx.drive.read_exact(&mut raw[..first_len])?;
+    // Wrapped around to the start of the buffer.
+    ctx.drive.seek(SeekFrom::Start(header.start + MDA_HEADER_SIZE))?;
+    ctx.drive.read_exact(&mut raw[first_len..])?;
+
+    if lvm_crc(&raw) != locn.checksum {
+        bail!("bad checksum of the LVM metadata text");
+    }
+
+    while raw.last() == Some(&0) {
+        raw.pop();
+    }
+
+    Ok(String::from_utf8(raw)?)
+}
+
+/// Finds the physical volume in the metadata of its volume group and collects the extents allocated
+/// to the logical volumes. Only the `stripes` of the segments refer to physical volumes (other
+/// segment types are made of hidden logical volumes); any other reference to the physical volume
+/// is refused, as its extents could not be accounted for.
+pub fn pv_extents(md: &Section, uuid: &str) -> anyhow::Result<PvExtents>
+{
+    let (vg_name, vg) = match md.sections().find(|(_, s)| s.get("physical_volumes").is_some()) {
+        Some(vg) => vg,
+        None => bail!("LVM metadata: no volume group"),
+    };
+
+    let extent_size = vg.int("extent_size")?;
+    if extent_size <= 0 {
+        bail!("LVM metadata: invalid extent size: {}", extent_size);
+    }
+
+    let pv = vg.section("physical_volumes")?
+        .sections()
+        .find(|(_, pv)| pv.str("id").is_ok_and(|id| id.replace('-', "") == uuid));
+    let (pv_name, pv) = match pv {
+        Some(pv) => pv,
+        None => bail!("the physical volume {} is not in volume group {}", uuid, vg_name),
+    };
+
+    let pe_start = pv.int("pe_start")?;
+    let pe_count = pv.int("pe_count")?;
+    if pe_start < 0 || pe_count < 0 {
+        bail!("LVM metadata: invalid extents of physical volume {}", pv_name);
+    }
+    let mut allocated = vec![false; pe_count as usize];
+
+    let lvs = match vg.get("logical_volumes") {
+        Some(Value::Section(lvs)) => lvs.sections().collect(),
+        Some(_) => bail!("LVM metadata: `logical_volumes` is not a section"),
+        None => Vec::new(),
+    };
+
+    for (lv_name, lv) in lvs {
+        for (seg_name, seg) in lv.sections() {
+            for (key, value) in &seg.0 {
+                if key == "stripes" {
+                    mark_stripes(&mut allocated, seg, value, pv_name)
+                        .map_err(|e| anyhow::anyhow!("{}, {} of {}", e, seg_name, lv_name))?;
+                } else if refers_to(value, pv_name) {
+                    bail!(
+                        "LVM metadata: unsupported reference to {} in `{}` of {}, {}",
+                        pv_name,
+                        key,
+                        lv_name,
+                        seg_name,
+                    );
+                }
+            }
+        }
+    }
+
+    Ok(PvExtents {
+        vg_name: String::from(vg_name),
+        pv_name: String::from(pv_name),
+        extent_size: extent_size as u64 * SECTOR_SIZE,
+        pe_start: pe_start as u64 * SECTOR_SIZE,
+        allocated,
+    })
+}
+
+/// Marks the extents of the physical volume used by the stripes of a segment: the list holds pairs
+/// of a physical volume and the first extent, each stripe taking an equal part of the extents.
+fn mark_stripes(allocated: &mut [bool], seg: &Section, stripes: &Value, pv_name: &str)
+    -> anyhow::Result<()>
+{
+    let items = match stripes {
+        Value::List(items) => items,
+        _ => bail!("LVM metadata: `stripes` is not a list"),
+    };
+
+    let extent_count = seg.int("extent_count")?;
+    let stripe_count = seg.int("stripe_count")?;
+    if stripe_count <= 0 || items.len() as i64 != 2 * stripe_count || extent_count < 0 {
+        bail!("LVM metadata: invalid stripes");
+    }
+    let area_len = (extent_count / stripe_count) as usize;
+
+    for pair in items.chunks(2) {
+        let (pv, first) = match pair {
+            [Value::Str(pv), Value::Int(first)] if *first >= 0 => (pv, *first as usize),
+            _ => bail!("LVM metadata: invalid stripe"),
+        };
+        if pv != pv_name {
+            continue;
+        }
+
+        match allocated.get_mut(first..first + area_len) {
+            Some(extents) => extents.fill(true),
+            None => bail!("LVM metadata: a stripe is out of the physical volume"),
+        }
+    }
+
+    Ok(())
+}
+
+/// Checks whether a value refers to the physical volume.
+fn refers_to(value: &Value, pv_name: &str) -> bool
+{
+    match value {
+        Value::Str(s) => s == pv_name,
+        Value::List(items) => items.iter().any(|v| refers_to(v, pv_name)),
+        _ => false,
+    }
+}
+
+/// Scans an LVM physical volume and returns a map of its usage.
+/// The whole drive is marked as used, except for the physical extents that are not allocated to
+/// any logical volume. A physical volume outside of a volume group is free past the start of its
+/// data area.
+pub fn scan_drive(ctx: &mut Context, _cfg: &Config) -> anyhow::Result<UsageMap>
+{
+    let label = match read_label(ctx)? {
+        Some(label) => label,
+        None => bail!("missing LVM label"),
+    };
+
+    let drive_size = ctx.drive.seek(SeekFrom::End(0))?;
+    if label.device_size > drive_size {
+        bail!(
+            "the physical volume ({} bytes) is larger than the drive ({} bytes)",
+            label.device_size,
+            drive_size,
+        );
+    }
+
+    let mut map = UsageMap::new(drive_size);
+    map.update(0, drive_size, AllocStatus::Used);
+
+    let md = match read_metadata(ctx, &label)? {
+        Some(md) => md,
+        None => {
+            let data = match label.data_areas.first() {
+                Some(data) => data,
+                None => bail!("the physical volume has no data area"),
+            };
+            let data_size = match data.size {
+                0 => drive_size.saturating_sub(data.offset),
+                size => size,
+            };
+            check_area(data.offset, data_size, drive_size)?;
+            for mda in &label.metadata_areas {
+                check_area(mda.offset, mda.size, drive_size)?;
+            }
+
+            ctx.logger.logln(0, &format!(
+                "the physical volume {} is not in a volume group: its data area is free",
+                label.uuid,
+            ));
+
+            map.update(data.offset, data_size, AllocStatus::Free);
+            for mda in &label.metadata_areas {
+                map.update(mda.offset, mda.size, AllocStatus::Used);
+            }
+
+            return Ok(map);
+        },
+    };
+
+    let pv = pv_extents(&md, &label.uuid)?;
+    let pe_count = pv.allocated.len() as u64;
+    let pe_end = pe_count.checked_mul(pv.extent_size).and_then(|n| n.checked_add(pv.pe_start));
+    if pe_end.is_none_or(|end| end > drive_size) {
+        bail!("the extents of physical volume {} reach beyond the end of the drive", pv.pv_name);
+    }
+
+    ctx.logger.logln(1, &format!(
+        "LVM physical volume {} of volume group {}: {} extents of {} bytes, {} free",
+        pv.pv_name,
+        pv.vg_name,
+        pe_count,
+        pv.extent_size,
+        pv.allocated.iter().filter(|a| !**a).count(),
+    ));
+
+    let mut run_start = None;
+
+    for i in 0..=pe_count {
+        let free = i < pe_count && !pv.allocated[i as usize];
+
+        match (free, run_start) {
+            (true, None) => run_start = Some(i),
+            (false, Some(start)) => {
+                map.update(
+                    pv.pe_start + start * pv.extent_size,
+                    (i - start) * pv.extent_size,
+                    AllocStatus::Free,
+                );
+                run_start = None;
+            },
+            _ => {},
+        }
+    }
+
+    Ok(map)
+}
+
+/// Checks that an area of the PV header lies within the drive.
+fn check_area(offset: u64, size: u64, drive_size: u64) -> anyhow::Result<()>
+{
+    if offset.checked_add(size).is_none_or(|end| end > drive_size) {
+        bail!("an area of the physical volume ({} bytes at {}) is out of the drive", size, offset);
+    }
+
+    Ok(())
+}
+
+// Tests.
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::usage_map::Segment;
+    use crate::util::test_context;
+
+    const TEST_UUID: &str = "Aq3Xr7Y1bT2pLm9KzW4cVn6Hs8Ju0EdF";
+    const MDA_OFFSET: u64 = 4096;
+    const MDA_SIZE: u64 = 8192;
+    /// The data area, in sectors: 16 extents of 8 sectors.
+    const PE_START: u64 = 24;
+    const DRIVE_SIZE: usize = (24 + 16 * 8) * 512;
+
+    /// Metadata with a linear logical volume on extents 0-3 and a striped one on extents 8-9 (and
+    /// on another physical volume).
+    pub const TEST_METADATA: &str = "vg0 {\n\
+        id = \"abcdef-0000-0000-0000-0000-0000-000000\"\n\
+        seqno = 3\n\
+        extent_size = 8\n\
+        physical_volumes {\n\
+        pv0 {\n\
+        id = \"Aq3Xr7-Y1bT-2pLm-9KzW-4cVn-6Hs8-Ju0EdF\"\n\
+        device = \"/dev/sdb\"\n\
+        pe_start = 24\n\
+        pe_count = 16\n\
+        }\n\
+        pv1 {\n\
+        id = \"Bq3Xr7-Y1bT-2pLm-9KzW-4cVn-6Hs8-Ju0EdF\"\n\
+        pe_start = 24\n\
+        pe_count = 16\n\
+        }\n\
+        }\n\
+        logical_volumes {\n\
+        lv0 {\n\
+        segment_count = 1\n\
+        segment1 {\n\
+        start_extent = 0\n\
+        extent_count = 4\n\
+        type = \"striped\"\n\
+        stripe_count = 1\n\
+        stripes = [\n\"pv0\", 0\n]\n\
+        }\n\
+        }\n\
+        lv1 {\n\
+        segment_count = 1\n\
+        segment1 {\n\
+        start_extent = 0\n\
+        extent_count = 4\n\
+        type = \"striped\"\n\
+        stripe_count = 2\n\
+        stripes = [\n\"pv1\", 0,\n\"pv0\", 8\n]\n\
+        }\n\
+        }\n\
+        }\n\
+        }\n\
+        contents = \"Text Format Volume Group\"\n\
+        version = 1\n";
+
+    /// Creates a physical volume with the supplied metadata text, or outside of a volume group.
+    pub fn test_drive(text: Option<&str>) -> Vec<u8>
+    {
+        let bincode_opt = DefaultOptions::new()
+            .with_fixint_encoding()
+            .allow_trailing_bytes();
+
+        let mut drive = vec![0u8; DRIVE_SIZE];
+
+        // The label in sector 1.
+        let label = &mut drive[512..1024];
+        label[..8].copy_from_slice(LABEL_ID);
+        label[8..16].copy_from_slice(&1u64.to_le_bytes());
+        label[20..24].copy_from_slice(&32u32.to_le_bytes());
+        label[24..32].copy_from_slice(LABEL_TYPE);
+        label[32..64].copy_from_slice(TEST_UUID.as_bytes());
+        label[64..72].copy_from_slice(&(DRIVE_SIZE as u64).to_le_bytes());
+        let areas = [
+            DiskLocn { offset: PE_START * 512, size: 0 },
+            DiskLocn { offset: 0, size: 0 },
+            DiskLocn { offset: MDA_OFFSET, size: MDA_SIZE },
+            DiskLocn { offset: 0, size: 0 },
+        ];
+        for (i, area) in areas.iter().enumerate() {
+            let raw = bincode_opt.serialize(area).unwrap();
+            label[72 + i * 16..88 + i * 16].copy_from_slice(&raw);
+        }
+        let crc = lvm_crc(&label[LABEL_CRC_START..]);
+        label[16..20].copy_from_slice(&crc.to_le_bytes());
+
+        // The metadata area, with the text right after the header.
+        let mda = &mut drive[MDA_OFFSET as usize..(MDA_OFFSET + MDA_SIZE) as usize];
+        let mut locn = RawLocn { offset: 0, size: 0, checksum: 0, flags: 0 };
+        if let Some(text) = text {
+            let start = MDA_HEADER_SIZE as usize;
+            mda[start..start + text.len()].copy_from_slice(text.as_bytes());
+            locn = RawLocn {
+                offset: MDA_HEADER_SIZE,
+                size: text.len() as u64,
+                checksum: lvm_crc(text.as_bytes()),
+                flags: 0,
+            };
+        }
+        let header = MdaHeader {
+            checksum_xl: 0,
+            magic: *MDA_MAGIC,
+            version: 1,
+            start: MDA_OFFSET,
+            size: MDA_SIZE,
+        };
+        mda[..40].copy_from_slice(&bincode_opt.serialize(&header).unwrap());
+        mda[40..64].copy_from_slice(&bincode_opt.serialize(&locn).unwrap());
+        let crc = lvm_crc(&mda[4..MDA_HEADER_SIZE as usize]);
+        mda[..4].copy_from_slice(&crc.to_le_bytes());
+
+        drive
+    }
+
+    #[test]
+    fn label()
+    {
+        let mut ctx = test_context(&test_drive(Some(TEST_METADATA)));
+
+        let label = read_label(&mut ctx).unwrap().unwrap();
+
+        assert_eq!(label.uuid, TEST_UUID);
+        assert_eq!(label.data_areas, vec![DiskLocn { offset: PE_START * 512, size: 0 }]);
+        assert_eq!(label.metadata_areas, vec![DiskLocn { offset: MDA_OFFSET, size: MDA_SIZE }]);
+
+        let mut ctx = test_context(&[0; 4096]);
+        assert!(read_label(&mut ctx).unwrap().is_none());
+    }
+
+    #[test]
+    fn scan()
+    {
+        let mut ctx = test_context(&test_drive(Some(TEST_METADATA)));
+
+        let map = scan_drive(&mut ctx, &Config::default()).unwrap();
+
+        let extent = |n: u64| (PE_START + n * 8) * 512;
+        assert_eq!(map.segments(), vec![
+            Segment { start: 0, end: extent(4), status: AllocStatus::Used },
+            Segment { start: extent(4), end: extent(8), status: AllocStatus::Free },
+            Segment { start: extent(8), end: extent(10), status: AllocStatus::Used },
+            Segment { start: extent(10), end: extent(16), status: AllocStatus::Free },
+        ]);
+    }
+
+    #[test]
+    fn orphan()
+    {
+        let mut ctx = test_context(&test_drive(None));
+
+        let map = scan_drive(&mut ctx, &Config::default()).unwrap();
+
+        assert_eq!(map.segments(), vec![
+            Segment { start: 0, end: PE_START * 512, status: AllocStatus::Used },
+            Segment { start: PE_START * 512, end: DRIVE_SIZE as u64, status: AllocStatus::Free },
+        ]);
+    }
+
+    #[test]
+    fn refused()
+    {
+        // A corrupted metadata text.
+        let mut drive = test_drive(Some(TEST_METADATA));
+        drive[(MDA_OFFSET + MDA_HEADER_SIZE) as usize] = b'x';
+        let mut ctx = test_context(&drive);
+        assert!(scan_drive(&mut ctx, &Config::default()).is_err());
+
+        // A segment type that refers to the physical volume by other means than stripes.
+        let text = TEST_METADATA.replace("stripes = [\n\"pv0\", 0\n]", "devices = [\"pv0\"]");
+        let mut ctx = test_context(&test_drive(Some(&text)));
+        assert!(scan_drive(&mut ctx, &Config::default()).is_err());
+
+        // An ignored metadata area does not make an orphan of the physical volume.
+        let mut drive = test_drive(None);
+        let mda = &mut drive[MDA_OFFSET as usize..(MDA_OFFSET + MDA_HEADER_SIZE) as usize];
+        mda[60..64].copy_from_slice(&RAW_LOCN_IGNORED.to_le_bytes());
+        let crc = lvm_crc(&mda[4..]);
+        mda[..4].copy_from_slice(&crc.to_le_bytes());
+        let mut ctx = test_context(&drive);
+        assert!(scan_drive(&mut ctx, &Config::default()).is_err());
+
+        // A data area out of the drive.
+        let mut drive = test_drive(None);
+        drive[512 + 72..512 + 80].copy_from_slice(&(u64::MAX - 10).to_le_bytes());
+        let crc = lvm_crc(&drive[512 + LABEL_CRC_START..1024]);
+        drive[512 + 16..512 + 20].copy_from_slice(&crc.to_le_bytes());
+        let mut ctx = test_context(&drive);
+        assert!(scan_drive(&mut ctx, &Config::default()).is_err());
+
+        // A stripe out of the physical volume.
+        let text = TEST_METADATA.replace("\"pv0\", 8", "\"pv0\", 15");
+        let mut ctx = test_context(&test_drive(Some(&text)));
+        assert!(scan_drive(&mut ctx, &Config::default()).is_err());
+    }
+}
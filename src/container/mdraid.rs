@@ -0,0 +1,276 @@
+use std::io::{Read, Seek, SeekFrom};
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+use bincode::{DefaultOptions, Options};
+
+use crate::drive::Window;
+use crate::Context;
+
+// Reference: include/uapi/linux/raid/md_p.h in Linux.
+
+const MD_SB_MAGIC: u32 = 0xa92b4efc;
+const SECTOR_SIZE: u64 = 512;
+/// The space reserved at the end of the drive for the 0.90 superblock, which is aligned to it.
+const MD_RESERVED_BYTES: u64 = 64 * 1024;
+
+/// The version 1 superblock, up to the location of the superblock.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SuperBlock1 {
+    pub magic: u32,
+    pub major_version: u32,
+    pub feature_map: u32,
+    pub pad0: u32,
+    pub set_uuid: [u8; 16],
+    pub set_name: [u8; 32],
+    pub ctime: u64,
+    pub level: u32,
+    pub layout: u32,
+    pub size: u64,
+    pub chunksize: u32,
+    pub raid_disks: u32,
+    pub bitmap_offset: u32,
+    pub new_level: u32,
+    pub reshape_position: u64,
+    pub delta_disks: u32,
+    pub new_layout: u32,
+    pub new_chunk: u32,
+    pub new_offset: u32,
+    /// In sectors, as are the following fields.
+    pub data_offset: u64,
+    pub data_size: u64,
+    pub super_offset: u64,
+}
+
+/// A member of an md RAID array.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Member {
+    /// The metadata version, as in `mdadm --metadata`.
+    pub version: &'static str,
+    /// The name of the array (version 1 only).
+    pub name: String,
+    /// The areas of the drive outside of the data of the array: the superblock, the write-intent
+    /// bitmap and the bad block log.
+    pub metadata: Vec<Window>,
+}
+
+/// Reads the md superblock, if the drive is a member of an md RAID array.
+pub fn read_superblock(ctx: &mut Context) -> anyhow::Result<Option<Member>>
+{
+    let drive_size = ctx.drive.seek(SeekFrom::End(0))?;
+    let sectors = drive_size / SECTOR_SIZE;
+
+    // Version 1.1 at the start, 1.2 4K from the start and 1.0 at the end, 8K from the end and
+    // aligned to 4K.
+    let mut candidates = vec![(0, "1.1"), (4096, "1.2")];
+    if sectors >= 16 {
+        candidates.push((((sectors - 16) & !7) * SECTOR_SIZE, "1.0"));
+    }
+
+    for (offset, version) in candidates {
+        if let Some(sb) = read_superblock1(ctx, offset, drive_size)? {
+            return member1(&sb, version, drive_size).map(Some);
+        }
+    }
+
+    // Version 0.90 in the last 64K-aligned 64K.
+    if drive_size >= 2 * MD_RESERVED_BYTES {
+        let offset = (drive_size & !(MD_RESERVED_BYTES - 1)) - MD_RESERVED_BYTES;
+        if read_magic(ctx, offset)? == (MD_SB_MAGIC, 0) {
+            return Ok(Some(Member {
+                version: "0.90",
+                name: String::new(),
+                metadata: vec![Window { offset, len: drive_size - offset }],
+            }));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Reads the magic value and the major version of a superblock candidate.
+fn read_magic(ctx: &mut Context, offset: u64) -> anyhow::Result<(u32, u32)>
+{
+    let mut raw = [0u8; 8];
+    ctx.drive.seek(SeekFrom::Start(offset))?;
+    ctx.drive.read_exact(&mut raw)?;
+
+    Ok((
+        u32::from_le_bytes(raw[..4].try_into().unwrap()),
+        u32::from_le_bytes(raw[4..].try_into().unwrap()),
+    ))
+}
+
+/// Reads a version 1 superblock candidate, which has to record its own location.
+fn read_superblock1(ctx: &mut Context, offset: u64, drive_size: u64)
+    -> anyhow::Result<Option<SuperBlock1>>
+{
+    let bincode_opt = DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes();
+
+    if offset + 4096 > drive_size || read_magic(ctx, offset)? != (MD_SB_MAGIC, 1) {
+        return Ok(None);
+    }
+
+    ctx.drive.seek(SeekFrom::Start(offset))?;
+    let sb: SuperBlock1 = bincode_opt.deserialize_from(&ctx.drive)?;
+
+    if sb.super_offset.checked_mul(SECTOR_SIZE) != Some(offset) {
+        return Ok(None);
+    }
+
+    Ok(Some(sb))
+}
+
+/// Describes a member with a version 1 superblock: everything outside of the data is metadata.
+fn member1(sb: &SuperBlock1, version: &'static str, drive_size: u64) -> anyhow::Result<Member>
+{
+    let data_start = sb.data_offset.checked_mul(SECTOR_SIZE);
+    let data_end = sb.data_size.checked_mul(SECTOR_SIZE)
+        .and_then(|size| size.checked_add(data_start?));
+    let (data_start, data_end) = match (data_start, data_end) {
+        (Some(start), Some(end)) if sb.data_size != 0 && end <= drive_size => (start, end),
+        _ => bail!(
+            "invalid data area of the md RAID member: {} sectors at sector {}",
+            sb.data_size,
+            sb.data_offset,
+        ),
+    };
+
+    let name_len = sb.set_name.iter().position(|c| *c == 0).unwrap_or(32);
+    let mut metadata = Vec::new();
+    if data_start > 0 {
+        metadata.push(Window { offset: 0, len: data_start });
+    }
+    if data_end < drive_size {
+        metadata.push(Window { offset: data_end, len: drive_size - data_end });
+    }
+
+    Ok(Member {
+        version,
+        name: String::from_utf8_lossy(&sb.set_name[..name_len]).into_owned(),
+        metadata,
+    })
+}
+
+// Tests.
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::util::test_context;
+
+    pub const DRIVE_SIZE: u64 = 1024 * 1024;
+
+    /// Creates a member of an array with the supplied metadata version. The data of the version 1.2
+    /// member starts at 64K; the data of the 1.0 member ends 64K from the end of the drive.
+    pub fn test_drive(version: &str) -> Vec<u8>
+    {
+        let bincode_opt = DefaultOptions::new()
+            .with_fixint_encoding()
+            .allow_trailing_bytes();
+
+        let mut drive = vec![0u8; DRIVE_SIZE as usize];
+        let sectors = DRIVE_SIZE / SECTOR_SIZE;
+
+        let (offset, data_offset, data_size) = match version {
+            "0.90" => {
+                let offset = (DRIVE_SIZE - MD_RESERVED_BYTES) as usize;
+                drive[offset..offset + 4].copy_from_slice(&MD_SB_MAGIC.to_le_bytes());
+                return drive;
+            },
+            "1.0" => (((sectors - 16) & !7) * SECTOR_SIZE, 0, sectors - 128),
+            "1.2" => (4096, 128, sectors - 128),
+            _ => panic!("unsupported test metadata version"),
+        };
+
+        let mut set_name = [0u8; 32];
+        set_name[..6].copy_from_slice(b"host:0");
+        let sb = SuperBlock1 {
+            magic: MD_SB_MAGIC,
+            major_version: 1,
+            feature_map: 0,
+            pad0: 0,
+            set_uuid: [0x5a; 16],
+            set_name,
+            ctime: 0,
+            level: 1,
+            layout: 0,
+            size: data_size,
+            chunksize: 0,
+            raid_disks: 2,
+            bitmap_offset: 0,
+            new_level: 0,
+            reshape_position: 0,
+            delta_disks: 0,
+            new_layout: 0,
+            new_chunk: 0,
+            new_offset: 0,
+            data_offset,
+            data_size,
+            super_offset: offset / SECTOR_SIZE,
+        };
+        let raw = bincode_opt.serialize(&sb).unwrap();
+        drive[offset as usize..offset as usize + raw.len()].copy_from_slice(&raw);
+
+        drive
+    }
+
+    #[test]
+    fn versions()
+    {
+        let mut ctx = test_context(&test_drive("1.2"));
+        assert_eq!(read_superblock(&mut ctx).unwrap().unwrap(), Member {
+            version: "1.2",
+            name: String::from("host:0"),
+            metadata: vec![Window { offset: 0, len: 64 * 1024 }],
+        });
+
+        let mut ctx = test_context(&test_drive("1.0"));
+        assert_eq!(read_superblock(&mut ctx).unwrap().unwrap(), Member {
+            version: "1.0",
+            name: String::from("host:0"),
+            metadata: vec![Window { offset: DRIVE_SIZE - 64 * 1024, len: 64 * 1024 }],
+        });
+
+        let mut ctx = test_context(&test_drive("0.90"));
+        assert_eq!(read_superblock(&mut ctx).unwrap().unwrap(), Member {
+            version: "0.90",
+            name: String::new(),
+            metadata: vec![Window { offset: DRIVE_SIZE - 64 * 1024, len: 64 * 1024 }],
+        });
+
+        let mut ctx = test_context(&[0; DRIVE_SIZE as usize]);
+        assert!(read_superblock(&mut ctx).unwrap().is_none());
+    }
+
+    #[test]
+    fn invalid_data_area()
+    {
+        let bincode_opt = DefaultOptions::new()
+            .with_fixint_encoding()
+            .allow_trailing_bytes();
+
+        let drive = test_drive("1.2");
+        let mut sb: SuperBlock1 = bincode_opt.deserialize(&drive[4096..]).unwrap();
+        assert!(member1(&sb, "1.2", DRIVE_SIZE).is_ok());
+
+        // The multiplication and the sum would wrap around.
+        sb.data_offset = u64::MAX / 2;
+        assert!(member1(&sb, "1.2", DRIVE_SIZE).is_err());
+        sb.data_offset = 128;
+        sb.data_size = u64::MAX / SECTOR_SIZE;
+        assert!(member1(&sb, "1.2", DRIVE_SIZE).is_err());
+    }
+
+    #[test]
+    fn misplaced_superblock()
+    {
+        // A version 1.2 superblock that records another location (e.g. a copy in the data).
+        let mut drive = test_drive("1.2");
+        drive[4096 + 144] = 16;
+        let mut ctx = test_context(&drive);
+
+        assert!(read_superblock(&mut ctx).unwrap().is_none());
+    }
+}
@@ -0,0 +1,5 @@
+//! Containers of file systems that are not partition tables: encryption, volume management and RAID.
+
+pub mod luks;
+pub mod lvm;
+pub mod mdraid;
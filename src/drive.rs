@@ -0,0 +1,528 @@
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+/// A part of the drive, in bytes.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Window {
+    pub offset: u64,
+    pub len: u64,
+}
+
+/// The drive, optionally restricted to a window (e.g. a partition).
+/// With a window, all the positions are relative to the start of the window, the end of the window
+/// is the end of the drive, reads stop at the end of the window and writes past it fail.
+#[derive(Debug)]
+pub struct Drive {
+    file: File,
+    window: Option<Window>,
+}
+
+impl Drive {
+    pub fn new(file: File) -> Self
+    {
+        Self { file, window: None }
+    }
+
+    /// Restricts the drive to a window, or lifts the restriction. The position is reset to the
+    /// start of the drive.
+    pub fn set_window(&mut self, window: Option<Window>) -> std::io::Result<()>
+    {
+        self.window = window;
+        self.seek(SeekFrom::Start(0))?;
+
+        Ok(())
+    }
+
+    pub fn window(&self) -> Option<Window>
+    {
+        self.window
+    }
+
+    /// The offset of the start of the drive in the underlying file.
+    pub fn offset(&self) -> u64
+    {
+        self.window.map_or(0, |w| w.offset)
+    }
+
+    /// The underlying file. Its positions are not relative to the window.
+    pub fn file(&self) -> &File
+    {
+        &self.file
+    }
+
+    pub fn sync_all(&self) -> std::io::Result<()>
+    {
+        self.file.sync_all()
+    }
+
+    pub fn sync_data(&self) -> std::io::Result<()>
+    {
+        self.file.sync_data()
+    }
+
+    /// Opens the drive again, for writing with O_DIRECT, with the same window. The writes to the new
+    /// drive bypass the page cache: their buffers, positions and lengths must be aligned to the
+    /// logical sectors, in the underlying file.
+    #[cfg(target_os = "linux")]
+    pub fn open_direct(&self) -> std::io::Result<Drive>
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        use std::os::unix::io::AsRawFd;
+
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .custom_flags(libc::O_DIRECT)
+            .open(format!("/proc/self/fd/{}", self.file.as_raw_fd()))?;
+
+        Ok(Drive { file, window: self.window })
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn open_direct(&self) -> std::io::Result<Drive>
+    {
+        Err(std::io::ErrorKind::Unsupported.into())
+    }
+
+    /// Writes the whole buffer at a position, without moving the position of the drive (`pwrite`),
+    /// so that several threads can write to the drive at once.
+    #[cfg(target_os = "linux")]
+    pub fn write_all_at(&self, buf: &[u8], offset: u64) -> std::io::Result<()>
+    {
+        use std::os::unix::fs::FileExt;
+
+        self.check_range(offset, buf.len() as u64)?;
+        self.file.write_all_at(buf, self.offset() + offset)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn write_all_at(&self, _buf: &[u8], _offset: u64) -> std::io::Result<()>
+    {
+        Err(std::io::ErrorKind::Unsupported.into())
+    }
+
+    /// Discards a range of the drive, without writing to it: a block device is told that the range
+    /// is unused (`BLKDISCARD`) and a regular file gets a hole punched into it. Reads of a
+    /// discarded range may return zeroes or the old data, depending on the device.
+    /// The range of a block device must be aligned to its logical sectors.
+    #[cfg(target_os = "linux")]
+    pub fn discard(&self, offset: u64, len: u64) -> std::io::Result<()>
+    {
+        use std::os::unix::fs::FileTypeExt;
+        use std::os::unix::io::AsRawFd;
+
+        /// `_IO(0x12, 119)`, from linux/fs.h.
+        const BLKDISCARD: u64 = 0x1277;
+
+        self.check_range(offset, len)?;
+
+        let file_type = self.file.metadata()?.file_type();
+
+        if file_type.is_file() {
+            return self.punch_hole(offset, len);
+        } else if !file_type.is_block_device() {
+            return Err(std::io::ErrorKind::Unsupported.into());
+        }
+
+        let range = [self.offset() + offset, len];
+        // SAFETY: the ioctl reads two u64 values from the pointer, which points to `range`.
+        if unsafe { libc::ioctl(self.file.as_raw_fd(), BLKDISCARD as _, range.as_ptr()) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+
+    /// Punches a hole into a range of a regular file (e.g. a disk image), so that it reads as
+    /// zeroes and the host file system can free its blocks. The size of the file is kept.
+    #[cfg(target_os = "linux")]
+    pub fn punch_hole(&self, offset: u64, len: u64) -> std::io::Result<()>
+    {
+        use std::os::unix::io::AsRawFd;
+
+        self.check_range(offset, len)?;
+
+        if !self.file.metadata()?.file_type().is_file() {
+            return Err(std::io::ErrorKind::Unsupported.into());
+        }
+
+        // SAFETY: the call does not access any memory and the descriptor is owned by `self`.
+        let ret = unsafe {
+            libc::fallocate(
+                self.file.as_raw_fd(),
+                libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+                (self.offset() + offset) as libc::off_t,
+                len as libc::off_t,
+            )
+        };
+        if ret != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn punch_hole(&self, _offset: u64, _len: u64) -> std::io::Result<()>
+    {
+        Err(std::io::ErrorKind::Unsupported.into())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn discard(&self, _offset: u64, _len: u64) -> std::io::Result<()>
+    {
+        Err(std::io::ErrorKind::Unsupported.into())
+    }
+
+    /// Zeroes a range of a block device in the kernel (`BLKZEROOUT`), which may let the device
+    /// zero it without transferring the data. The range must be aligned to the logical sectors.
+    #[cfg(target_os = "linux")]
+    pub fn zero_out(&self, offset: u64, len: u64) -> std::io::Result<()>
+    {
+        use std::os::unix::io::AsRawFd;
+
+        /// `_IO(0x12, 127)`, from linux/fs.h.
+        const BLKZEROOUT: u64 = 0x127f;
+
+        self.check_range(offset, len)?;
+
+        if !self.is_block_device() {
+            return Err(std::io::ErrorKind::Unsupported.into());
+        }
+
+        let range = [self.offset() + offset, len];
+        // SAFETY: the ioctl reads two u64 values from the pointer, which points to `range`.
+        if unsafe { libc::ioctl(self.file.as_raw_fd(), BLKZEROOUT as _, range.as_ptr()) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn zero_out(&self, _offset: u64, _len: u64) -> std::io::Result<()>
+    {
+        Err(std::io::ErrorKind::Unsupported.into())
+    }
+
+    /// Drops the cached pages of a range of the drive (`POSIX_FADV_DONTNEED`), so that it is read
+    /// from the device again. Pages that are not written back yet stay cached, so the drive should
+    /// be synced first.
+    #[cfg(target_os = "linux")]
+    pub fn drop_cache(&self, offset: u64, len: u64) -> std::io::Result<()>
+    {
+        use std::os::unix::io::AsRawFd;
+
+        self.check_range(offset, len)?;
+
+        // SAFETY: the call does not access any memory and the descriptor is owned by `self`.
+        let ret = unsafe {
+            libc::posix_fadvise(
+                self.file.as_raw_fd(),
+                (self.offset() + offset) as libc::off_t,
+                len as libc::off_t,
+                libc::POSIX_FADV_DONTNEED,
+            )
+        };
+        if ret != 0 {
+            return Err(std::io::Error::from_raw_os_error(ret));
+        }
+
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn drop_cache(&self, _offset: u64, _len: u64) -> std::io::Result<()>
+    {
+        Err(std::io::ErrorKind::Unsupported.into())
+    }
+
+    /// Checks whether the drive is a block device, rather than e.g. an image file.
+    pub fn is_block_device(&self) -> bool
+    {
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::unix::fs::FileTypeExt;
+
+            self.file.metadata().is_ok_and(|m| m.file_type().is_block_device())
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            false
+        }
+    }
+
+    /// Returns the logical sector size of a block device (`BLKSSZGET`), or 512 for image files.
+    #[cfg(target_os = "linux")]
+    pub fn logical_sector_size(&self) -> std::io::Result<u64>
+    {
+        use std::os::unix::io::AsRawFd;
+
+        /// `_IO(0x12, 104)`, from linux/fs.h.
+        const BLKSSZGET: u64 = 0x1268;
+
+        if !self.is_block_device() {
+            return Ok(512);
+        }
+
+        let mut size: libc::c_int = 0;
+        // SAFETY: the ioctl writes an int to the pointer, which points to `size`.
+        if unsafe { libc::ioctl(self.file.as_raw_fd(), BLKSSZGET as _, &mut size) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        Ok(size as u64)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn logical_sector_size(&self) -> std::io::Result<u64>
+    {
+        Ok(512)
+    }
+
+    /// Returns the serial numbers of the block device: those recorded by udev (`ID_SERIAL` and
+    /// `ID_SERIAL_SHORT`) and the one reported by the kernel (`device/serial` in sysfs, of the
+    /// device or of the disk of a partition). None are returned for image files.
+    #[cfg(target_os = "linux")]
+    pub fn serials(&self) -> Vec<String>
+    {
+        use std::os::unix::fs::MetadataExt;
+
+        let rdev = match self.file.metadata() {
+            Ok(m) if self.is_block_device() => m.rdev(),
+            _ => return Vec::new(),
+        };
+        let dev = format!("{}:{}", libc::major(rdev), libc::minor(rdev));
+
+        let mut serials = std::fs::read_to_string(format!("/run/udev/data/b{}", dev))
+            .map_or(Vec::new(), |data| udev_serials(&data));
+
+        for path in ["device/serial", "../device/serial"] {
+            if let Ok(serial) = std::fs::read_to_string(format!("/sys/dev/block/{}/{}", dev, path)) {
+                let serial = serial.trim();
+                if !serial.is_empty() && !serials.iter().any(|s| s == serial) {
+                    serials.push(serial.to_string());
+                }
+            }
+        }
+
+        serials
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn serials(&self) -> Vec<String>
+    {
+        Vec::new()
+    }
+
+    /// Checks that a range lies within the window, if there is one.
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    pub fn check_range(&self, offset: u64, len: u64) -> std::io::Result<()>
+    {
+        match self.window {
+            Some(w) if offset.checked_add(len).is_none_or(|end| end > w.len) => {
+                Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "range past the end of the drive window",
+                ))
+            },
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns the number of bytes from the current position to the end of the window, if there is
+    /// a window.
+    fn remaining(&self) -> std::io::Result<Option<u64>>
+    {
+        match self.window {
+            Some(w) => {
+                let pos = (&self.file).stream_position()?;
+                Ok(Some((w.offset + w.len).saturating_sub(pos)))
+            },
+            None => Ok(None),
+        }
+    }
+}
+
+impl Read for &Drive {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize>
+    {
+        match self.remaining()? {
+            Some(remaining) => {
+                let len = std::cmp::min(buf.len() as u64, remaining) as usize;
+                (&self.file).read(&mut buf[..len])
+            },
+            None => (&self.file).read(buf),
+        }
+    }
+}
+
+impl Read for Drive {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize>
+    {
+        (&*self).read(buf)
+    }
+}
+
+impl Write for &Drive {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize>
+    {
+        match self.remaining()? {
+            Some(0) if !buf.is_empty() => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "write past the end of the drive window",
+            )),
+            Some(remaining) => {
+                let len = std::cmp::min(buf.len() as u64, remaining) as usize;
+                (&self.file).write(&buf[..len])
+            },
+            None => (&self.file).write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()>
+    {
+        (&self.file).flush()
+    }
+}
+
+impl Write for Drive {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize>
+    {
+        (&*self).write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()>
+    {
+        (&*self).flush()
+    }
+}
+
+impl Seek for &Drive {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64>
+    {
+        let w = match self.window {
+            Some(w) => w,
+            None => return (&self.file).seek(pos),
+        };
+
+        let target = match pos {
+            SeekFrom::Start(n) => Some(w.offset + n),
+            SeekFrom::End(n) => (w.offset + w.len).checked_add_signed(n),
+            SeekFrom::Current(n) => (&self.file).stream_position()?.checked_add_signed(n),
+        };
+
+        match target {
+            Some(target) if target >= w.offset => {
+                Ok((&self.file).seek(SeekFrom::Start(target))? - w.offset)
+            },
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "seek before the start of the drive window",
+            )),
+        }
+    }
+}
+
+impl Seek for Drive {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64>
+    {
+        (&*self).seek(pos)
+    }
+}
+
+/// Returns the serial numbers in the udev database entry of a device.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn udev_serials(data: &str) -> Vec<String>
+{
+    data.lines()
+        .filter_map(|line| {
+            line.strip_prefix("E:ID_SERIAL=").or_else(|| line.strip_prefix("E:ID_SERIAL_SHORT="))
+        })
+        .filter(|serial| !serial.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+// Tests.
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::temp_drive;
+
+    #[test]
+    fn window()
+    {
+        let contents: Vec<u8> = (0..64).collect();
+        let mut drive = Drive::new(temp_drive(&contents));
+        drive.set_window(Some(Window { offset: 16, len: 32 })).unwrap();
+
+        assert_eq!(drive.seek(SeekFrom::End(0)).unwrap(), 32);
+        assert_eq!(drive.seek(SeekFrom::Start(4)).unwrap(), 4);
+        assert_eq!(drive.seek(SeekFrom::Current(-2)).unwrap(), 2);
+        assert!(drive.seek(SeekFrom::Current(-3)).is_err());
+
+        // Reads stop at the end of the window.
+        let mut buf = Vec::new();
+        drive.seek(SeekFrom::Start(28)).unwrap();
+        drive.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, vec![44, 45, 46, 47]);
+
+        // Writes past the end of the window fail.
+        drive.seek(SeekFrom::Start(30)).unwrap();
+        assert!(drive.write_all(&[0xff; 4]).is_err());
+
+        drive.set_window(None).unwrap();
+        let mut buf = Vec::new();
+        drive.read_to_end(&mut buf).unwrap();
+        assert_eq!(&buf[44..50], &[44, 45, 0xff, 0xff, 48, 49]);
+    }
+
+    #[test]
+    fn udev_database()
+    {
+        let data = "S:disk/by-id/ata-WDC_WD10EZEX_WD-ABC123\n\
+                    E:ID_MODEL=WDC_WD10EZEX\n\
+                    E:ID_SERIAL=WDC_WD10EZEX_WD-ABC123\n\
+                    E:ID_SERIAL_SHORT=WD-ABC123\n";
+
+        assert_eq!(udev_serials(data), vec!["WDC_WD10EZEX_WD-ABC123", "WD-ABC123"]);
+        assert!(udev_serials("E:ID_MODEL=QEMU\n").is_empty());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn drop_cache()
+    {
+        let mut drive = Drive::new(temp_drive(&[0xaa; 3 * 4096]));
+        drive.set_window(Some(Window { offset: 4096, len: 2 * 4096 })).unwrap();
+        assert!(drive.drop_cache(4096, 4097).is_err());
+
+        // The data is read back the same.
+        drive.drop_cache(0, 2 * 4096).unwrap();
+        let mut buf = Vec::new();
+        drive.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, vec![0xaa; 2 * 4096]);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn discard()
+    {
+        let mut drive = Drive::new(temp_drive(&[0xaa; 3 * 4096]));
+        drive.set_window(Some(Window { offset: 4096, len: 2 * 4096 })).unwrap();
+        assert!(drive.discard(4096, 4097).is_err());
+
+        // Hole punching may not be supported by the file system of the temporary directory.
+        if drive.discard(0, 4096).is_err() {
+            return;
+        }
+
+        drive.set_window(None).unwrap();
+        let mut buf = Vec::new();
+        drive.read_to_end(&mut buf).unwrap();
+        assert!(buf[..4096].iter().chain(&buf[2 * 4096..]).all(|b| *b == 0xaa));
+        assert!(buf[4096..2 * 4096].iter().all(|b| *b == 0));
+        assert_eq!(buf.len(), 3 * 4096);
+    }
+}
@@ -1,21 +1,68 @@
 use std::io::{Seek, SeekFrom,};
+use anyhow::bail;
 use bincode::{Options, DefaultOptions};
+use crate::container::{luks, lvm, mdraid};
 use crate::Context;
-use super::FsType;
-use super::e2fs;
+use super::Registry;
+use super::{e2fs, f2fs, fat, hfsplus, iso9660, minixfs, ntfs, reiserfs, udf};
 
-/// Attempts to detect the file system.
-pub fn detect_fs(context: &mut Context) -> anyhow::Result<Option<FsType>>
+/// A file system detector.
+/// Returns whether the drive contains the file system recognised by the detector.
+pub type Detector = fn(&mut Context) -> anyhow::Result<bool>;
+
+/// Attempts to detect the file system with the built-in scanners. Returns the name of the file
+/// system type.
+pub fn detect_fs(context: &mut Context) -> anyhow::Result<Option<&'static str>>
+{
+    Ok(Registry::default().detect(context)?.map(|scanner| scanner.name()))
+}
+
+/// Refuses the containers that hide the file systems, before any file system is detected.
+pub fn check_containers(context: &mut Context) -> anyhow::Result<()>
 {
-    if detect_e2fs(context)? {
-        return Ok(Some(FsType::Ext2));
+    check_mdraid(context)?;
+    check_luks(context)
+}
+
+/// Refuses members of md RAID arrays. File systems on the members of mirrors would be detected
+/// otherwise, and filling them would bring the array out of sync.
+fn check_mdraid(context: &mut Context) -> anyhow::Result<()>
+{
+    if let Some(member) = mdraid::read_superblock(context)? {
+        bail!(
+            "the drive is a member of an md RAID array{} (metadata {}): use the array device \
+            instead, or the raw type to wipe the member",
+            if member.name.is_empty() { String::new() } else { format!(" {}", member.name) },
+            member.version,
+        );
     }
 
-    Ok(None)
+    Ok(())
+}
+
+/// Refuses LUKS containers, which would otherwise be reported as unknown.
+fn check_luks(context: &mut Context) -> anyhow::Result<()>
+{
+    if let Some(header) = luks::read_header(context)? {
+        bail!(
+            "the drive is a LUKS{} container ({}): open it and use the mapper device, or pass \
+            --luks-mapper",
+            header.version,
+            header.uuid,
+        );
+    }
+
+    Ok(())
+}
+
+/// Attempts to detect an LVM physical volume.
+pub fn detect_lvm(context: &mut Context) -> anyhow::Result<bool>
+{
+    Ok(lvm::read_label(context)?.is_some())
 }
 
 /// Attempts to detect the ext2/3/4 file system.
-fn detect_e2fs(context: &mut Context) -> anyhow::Result<bool>
+pub fn detect_e2fs(context: &mut Context) -> anyhow::Result<bool>
 {
     let bincode_opt = DefaultOptions::new()
         .with_fixint_encoding()
@@ -45,3 +92,173 @@ fn detect_e2fs(context: &mut Context) -> anyhow::Result<bool>
 
     Ok(true)
 }
+
+/// Attempts to detect the FAT32 file system.
+pub fn detect_fat32(context: &mut Context) -> anyhow::Result<bool>
+{
+    match fat::read_bpb(context)? {
+        Some(bpb) => Ok(fat::is_fat32_bpb(&bpb)),
+        None => Ok(false),
+    }
+}
+
+/// Attempts to detect the NTFS file system.
+pub fn detect_ntfs(context: &mut Context) -> anyhow::Result<bool>
+{
+    match ntfs::read_boot_sector(context)? {
+        Some(bs) => Ok(ntfs::is_ntfs_boot_sector(&bs)),
+        None => Ok(false),
+    }
+}
+
+/// Attempts to detect the F2FS file system.
+pub fn detect_f2fs(context: &mut Context) -> anyhow::Result<bool>
+{
+    Ok(f2fs::read_superblock(context)?.is_some())
+}
+
+/// Attempts to detect the minix file system.
+pub fn detect_minix(context: &mut Context) -> anyhow::Result<bool>
+{
+    match minixfs::read_superblock(context)? {
+        Some(sb) => Ok(minixfs::Fs::from_any(&sb).is_ok()),
+        None => Ok(false),
+    }
+}
+
+/// Attempts to detect the HFS+ file system.
+pub fn detect_hfsplus(context: &mut Context) -> anyhow::Result<bool>
+{
+    Ok(hfsplus::read_volume_header(context)?.is_some())
+}
+
+/// Attempts to detect the ReiserFS file system.
+pub fn detect_reiserfs(context: &mut Context) -> anyhow::Result<bool>
+{
+    Ok(reiserfs::read_superblock(context)?.is_some())
+}
+
+/// Attempts to detect the UDF file system.
+/// Bridge images carry an ISO9660 volume descriptor as well, so this has to be tried first.
+pub fn detect_udf(context: &mut Context) -> anyhow::Result<bool>
+{
+    udf::has_nsr(context)
+}
+
+/// Attempts to detect the ISO9660 file system.
+pub fn detect_iso9660(context: &mut Context) -> anyhow::Result<bool>
+{
+    Ok(iso9660::read_first_vd(context)?.is_some())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::test_context;
+
+    #[test]
+    fn fat32()
+    {
+        use crate::filesys::fat::tests::{test_bpb, test_drive};
+
+        let mut ctx = test_context(&test_drive(&test_bpb(26), 26 * 512, &[]));
+
+        assert_eq!(detect_fs(&mut ctx).unwrap(), Some("fat32"));
+    }
+
+    #[test]
+    fn ntfs()
+    {
+        use bincode::{DefaultOptions, Options};
+        use crate::filesys::ntfs::tests::test_boot_sector;
+
+        let bincode_opt = DefaultOptions::new()
+            .with_fixint_encoding()
+            .allow_trailing_bytes();
+
+        let mut drive = vec![0; 4096];
+        let raw = bincode_opt.serialize(&test_boot_sector(8)).unwrap();
+        drive[..raw.len()].copy_from_slice(&raw);
+        drive[510..512].copy_from_slice(&[0x55, 0xaa]);
+        let mut ctx = test_context(&drive);
+
+        assert_eq!(detect_fs(&mut ctx).unwrap(), Some("ntfs"));
+    }
+
+    #[test]
+    fn minix()
+    {
+        use crate::filesys::minixfs::tests::{test_sb, test_drive};
+
+        let mut ctx = test_context(&test_drive(&test_sb(), &[], 64 * 1024));
+
+        assert_eq!(detect_fs(&mut ctx).unwrap(), Some("minix"));
+    }
+
+    #[test]
+    fn hfsplus()
+    {
+        use crate::filesys::hfsplus::tests::{test_vh, test_drive};
+
+        let mut ctx = test_context(&test_drive(&test_vh(), &[0xff; 8], 64 * 512));
+
+        assert_eq!(detect_fs(&mut ctx).unwrap(), Some("hfsplus"));
+    }
+
+    #[test]
+    fn reiserfs()
+    {
+        use crate::filesys::reiserfs::tests::{test_sb, test_drive};
+
+        let mut ctx = test_context(&test_drive(&test_sb(100), &[], 100 * 1024));
+
+        assert_eq!(detect_fs(&mut ctx).unwrap(), Some("reiserfs"));
+    }
+
+    #[test]
+    fn optical()
+    {
+        let mut ctx = test_context(&crate::filesys::iso9660::tests::test_drive());
+        assert_eq!(detect_fs(&mut ctx).unwrap(), Some("iso9660"));
+
+        let mut ctx = test_context(&crate::filesys::udf::tests::test_drive(true));
+        assert_eq!(detect_fs(&mut ctx).unwrap(), Some("udf"));
+    }
+
+    #[test]
+    fn lvm()
+    {
+        use crate::container::lvm::tests::{test_drive, TEST_METADATA};
+
+        let mut ctx = test_context(&test_drive(Some(TEST_METADATA)));
+
+        assert_eq!(detect_fs(&mut ctx).unwrap(), Some("lvm"));
+    }
+
+    #[test]
+    fn mdraid_refused()
+    {
+        use crate::container::mdraid::tests::test_drive;
+
+        for version in ["0.90", "1.0", "1.2"] {
+            let mut ctx = test_context(&test_drive(version));
+            assert!(detect_fs(&mut ctx).is_err());
+        }
+    }
+
+    #[test]
+    fn luks_refused()
+    {
+        let mut ctx = test_context(&crate::container::luks::tests::test_drive(1));
+
+        assert!(detect_fs(&mut ctx).is_err());
+    }
+
+    #[test]
+    fn zeroed_drive_is_unknown()
+    {
+        let mut ctx = test_context(&[0; 4096]);
+
+        assert!(detect_fs(&mut ctx).unwrap().is_none());
+    }
+}
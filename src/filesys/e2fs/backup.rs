@@ -0,0 +1,225 @@
+use std::io::{Read, Seek, SeekFrom};
+use bincode::{DefaultOptions, Options};
+use clap::ArgEnum;
+
+use crate::Context;
+use crate::bs;
+use super::{Fs, SuperBlock, ext4_style_crc32c_le, has_super_copy, scrub, start_of_bg};
+
+/// The offset of `s_checksum` in the superblock, which covers everything before it.
+const SB_CHECKSUM_OFFSET: usize = 1020;
+
+/// What to do with the backup superblocks that fail the verification.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ArgEnum)]
+pub enum StaleBackups {
+    /// Keep them, as well as their group descriptor tables.
+    Keep,
+    /// Treat them and their group descriptor tables as free space.
+    Ignore,
+    /// Rewrite them and their group descriptor tables from the primary copies before the fill.
+    Refresh,
+}
+
+/// A backup superblock that disagrees with the primary one.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Divergence {
+    pub bg_num: u64,
+    /// The names of the differing fields.
+    pub fields: Vec<&'static str>,
+}
+
+/// Reads the backup superblocks and compares their key fields against the primary superblock.
+/// Returns the backups that disagree.
+pub fn check_backups(fs: &Fs, ctx: &mut Context) -> anyhow::Result<Vec<Divergence>>
+{
+    let bincode_opt = DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes();
+
+    let mut divergences = Vec::new();
+
+    for bg_num in 1..fs.bg_count {
+        if !has_super_copy(bg_num, fs) {
+            continue;
+        }
+
+        ctx.drive.seek(SeekFrom::Start(start_of_bg(bg_num, fs)))?;
+        let backup: SuperBlock = bincode_opt.deserialize_from(&ctx.drive)?;
+
+        let fields = diverging_fields(&fs.sb, &backup);
+        if !fields.is_empty() {
+            divergences.push(Divergence { bg_num, fields });
+        }
+    }
+
+    Ok(divergences)
+}
+
+/// Verifies the backup superblock of a group: its magic value and, with metadata_csum, its
+/// checksum. Returns the reason if the backup is stale.
+pub fn verify_backup(bg_num: u64, fs: &Fs, ctx: &mut Context)
+    -> anyhow::Result<Option<&'static str>>
+{
+    let bincode_opt = DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes();
+
+    let mut raw = [0u8; 1024];
+    ctx.drive.seek(SeekFrom::Start(start_of_bg(bg_num, fs)))?;
+    ctx.drive.read_exact(&mut raw)?;
+    let backup: SuperBlock = bincode_opt.deserialize(&raw)?;
+
+    if backup.s_magic != 0xef53 {
+        return Ok(Some("bad magic"));
+    }
+
+    let has_csum = fs.opts.dyn_cfg.is_some_and(|dyn_cfg| dyn_cfg.ro_compat.has_metadata_csum());
+    if has_csum && backup.s_checksum != sb_checksum(&raw) {
+        return Ok(Some("bad checksum"));
+    }
+
+    Ok(None)
+}
+
+/// Rewrites the backup superblock of a group and the group descriptor table that follows it from
+/// the primary copies. The writes are collected in the context, and applied by `scrub::apply` once
+/// the device is confirmed.
+pub fn refresh_backup(bg_num: u64, fs: &Fs, ctx: &mut Context) -> anyhow::Result<()>
+{
+    let bincode_opt = DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes();
+
+    // Like e2fsprogs, the group number saturates.
+    let s_block_group_nr = std::cmp::min(bg_num, u16::MAX as u64) as u16;
+    let sb = SuperBlock { s_block_group_nr, ..fs.sb };
+    let mut raw = bincode_opt.serialize(&sb)?;
+    let checksum = sb_checksum(&raw);
+    raw[SB_CHECKSUM_OFFSET..SB_CHECKSUM_OFFSET + 4].copy_from_slice(&checksum.to_le_bytes());
+
+    let bg_start = start_of_bg(bg_num, fs);
+    scrub::defer_write(bg_start, raw, ctx);
+
+    let gdt_len = (fs.bg_count * fs.desc_size) as usize;
+    let gdt = fs.desc_table[..gdt_len].to_vec();
+    scrub::defer_write(bg_start + bs!(fs.sb.s_log_block_size), gdt, ctx);
+
+    Ok(())
+}
+
+/// Computes the metadata_csum checksum of a raw superblock.
+fn sb_checksum(raw: &[u8]) -> u32
+{
+    ext4_style_crc32c_le(!0, &raw[..SB_CHECKSUM_OFFSET])
+}
+
+/// Compares the key fields of two superblocks and returns the names of those that differ.
+/// The fields that legitimately differ between the copies (free counts, timestamps, the group
+/// number, ...) are not compared.
+fn diverging_fields(primary: &SuperBlock, backup: &SuperBlock) -> Vec<&'static str>
+{
+    let mut fields = Vec::new();
+
+    if primary.s_magic != backup.s_magic {
+        fields.push("s_magic");
+    }
+    if primary.s_blocks_count_lo != backup.s_blocks_count_lo
+        || primary.s_blocks_count_hi != backup.s_blocks_count_hi
+    {
+        fields.push("s_blocks_count");
+    }
+    if primary.s_inodes_count != backup.s_inodes_count {
+        fields.push("s_inodes_count");
+    }
+    if primary.s_feature_compat != backup.s_feature_compat {
+        fields.push("s_feature_compat");
+    }
+    if primary.s_feature_incompat != backup.s_feature_incompat {
+        fields.push("s_feature_incompat");
+    }
+    if primary.s_feature_ro_compat != backup.s_feature_ro_compat {
+        fields.push("s_feature_ro_compat");
+    }
+    if primary.s_uuid != backup.s_uuid {
+        fields.push("s_uuid");
+    }
+
+    fields
+}
+
+// Tests.
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::test_context;
+    use crate::filesys::e2fs::tests::{test_fs, test_sb};
+
+    const BLOCK_SIZE: usize = 4096;
+
+    fn drive_with_backup(primary: &SuperBlock, backup: &SuperBlock) -> Vec<u8>
+    {
+        let bincode_opt = DefaultOptions::new()
+            .with_fixint_encoding()
+            .allow_trailing_bytes();
+
+        let mut drive = vec![0; 64 * BLOCK_SIZE];
+        drive[1024..2048].copy_from_slice(&bincode_opt.serialize(primary).unwrap());
+        drive[32 * BLOCK_SIZE..32 * BLOCK_SIZE + 1024]
+            .copy_from_slice(&bincode_opt.serialize(backup).unwrap());
+
+        drive
+    }
+
+    #[test]
+    fn matching_backup()
+    {
+        let sb = SuperBlock { s_uuid: [3; 16], ..test_sb() };
+        // Free counts differ between the copies.
+        let backup = SuperBlock { s_free_blocks_count_lo: 12, s_block_group_nr: 1, ..sb };
+        let mut ctx = test_context(&drive_with_backup(&sb, &backup));
+
+        assert!(check_backups(&test_fs(sb), &mut ctx).unwrap().is_empty());
+    }
+
+    #[test]
+    fn stale_backup()
+    {
+        // metadata_csum.
+        let sb = SuperBlock { s_feature_ro_compat: 0x400, s_uuid: [3; 16], ..test_sb() };
+        let fs = test_fs(sb);
+
+        let mut ctx = test_context(&drive_with_backup(&sb, &SuperBlock { s_magic: 0, ..sb }));
+        assert_eq!(verify_backup(1, &fs, &mut ctx).unwrap(), Some("bad magic"));
+
+        // The checksum of the primary is not valid for the backup, which is not refreshed yet.
+        let mut ctx = test_context(&drive_with_backup(&sb, &sb));
+        assert_eq!(verify_backup(1, &fs, &mut ctx).unwrap(), Some("bad checksum"));
+
+        // The backup is only rewritten once the writes are applied.
+        let fs = Fs { desc_table: vec![0x5a; 64], ..fs };
+        refresh_backup(1, &fs, &mut ctx).unwrap();
+        assert_eq!(verify_backup(1, &fs, &mut ctx).unwrap(), Some("bad checksum"));
+        assert_eq!(scrub::apply(&mut ctx).unwrap(), 2);
+        assert_eq!(verify_backup(1, &fs, &mut ctx).unwrap(), None);
+
+        // The group descriptor table follows the backup.
+        let mut gdt = [0u8; 64];
+        ctx.drive.seek(SeekFrom::Start(33 * BLOCK_SIZE as u64)).unwrap();
+        ctx.drive.read_exact(&mut gdt).unwrap();
+        assert_eq!(gdt, [0x5a; 64]);
+    }
+
+    #[test]
+    fn diverging_backup()
+    {
+        let sb = SuperBlock { s_uuid: [3; 16], ..test_sb() };
+        let backup = SuperBlock { s_inodes_count: 48, s_uuid: [4; 16], ..sb };
+        let mut ctx = test_context(&drive_with_backup(&sb, &backup));
+
+        assert_eq!(check_backups(&test_fs(sb), &mut ctx).unwrap(), vec![Divergence {
+            bg_num: 1,
+            fields: vec!["s_inodes_count", "s_uuid"],
+        }]);
+    }
+}
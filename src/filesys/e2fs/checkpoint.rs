@@ -0,0 +1,116 @@
+use std::fs::File;
+use std::io::{BufReader, Read, Write};
+use std::path::Path;
+use anyhow::bail;
+
+use crate::map_format;
+use crate::usage_map::UsageMap;
+use crate::hilo;
+use super::Fs;
+
+// Scan checkpoint format (all integers are little-endian):
+//
+//  * magic: the bytes `FSFC`,
+//  * version: u8, currently 2,
+//  * file system UUID: 16 bytes,
+//  * last write time: u64, from s_wtime (and s_wtime_hi),
+//  * last mount time: u64, from s_mtime (and s_mtime_hi),
+//  * kilobytes written: u64, from s_kbytes_written,
+//  * block group count: u64,
+//  * next block group: u64, the first group that has not been scanned yet,
+//  * the partial usage map, in the binary map format (see map_format).
+
+const MAGIC: &[u8; 4] = b"FSFC";
+const VERSION: u8 = 2;
+
+/// The saved progress of a scan.
+#[derive(Debug)]
+pub struct Checkpoint {
+    /// The first block group that has not been scanned yet.
+    pub next_bg: u64,
+    /// The usage map after scanning the groups before `next_bg`.
+    pub map: UsageMap,
+}
+
+/// Saves the scan progress, replacing the old checkpoint atomically.
+pub fn save(path: &Path, next_bg: u64, map: &UsageMap, fs: &Fs) -> anyhow::Result<()>
+{
+    crate::util::write_atomically(path, |w| {
+        w.write_all(MAGIC)?;
+        w.write_all(&[VERSION])?;
+        w.write_all(&fs.sb.s_uuid)?;
+        for value in write_state(fs) {
+            w.write_all(&value.to_le_bytes())?;
+        }
+        w.write_all(&fs.bg_count.to_le_bytes())?;
+        w.write_all(&next_bg.to_le_bytes())?;
+        map_format::write_bin(map, None, w)?;
+
+        Ok(())
+    })
+}
+
+/// Loads the scan progress, if the checkpoint exists.
+/// The checkpoint is checked against the file system and the drive size.
+pub fn load(path: &Path, fs: &Fs, drive_size: u64) -> anyhow::Result<Option<Checkpoint>>
+{
+    let f = match File::open(path) {
+        Ok(f) => f,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let mut r = BufReader::new(f);
+
+    let mut magic = [0u8; 4];
+    r.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        bail!("not a scan checkpoint: bad magic value");
+    }
+
+    let mut version = [0u8; 1];
+    r.read_exact(&mut version)?;
+    if version[0] != VERSION {
+        bail!("unsupported scan checkpoint version: {}", version[0]);
+    }
+
+    let mut uuid = [0u8; 16];
+    r.read_exact(&mut uuid)?;
+    if uuid != fs.sb.s_uuid {
+        bail!("scan checkpoint belongs to a different file system (UUID mismatch)");
+    }
+
+    let mut buf = [0u8; 8];
+    for value in write_state(fs) {
+        r.read_exact(&mut buf)?;
+        if u64::from_le_bytes(buf) != value {
+            bail!("the file system was modified since the scan checkpoint was saved");
+        }
+    }
+
+    r.read_exact(&mut buf)?;
+    let bg_count = u64::from_le_bytes(buf);
+    r.read_exact(&mut buf)?;
+    let next_bg = u64::from_le_bytes(buf);
+
+    if bg_count != fs.bg_count || next_bg > bg_count {
+        bail!("scan checkpoint does not match the file system layout");
+    }
+
+    let (map, _) = map_format::read_bin(&mut r)?;
+    if map.size() != drive_size {
+        bail!("scan checkpoint map size ({}) does not match the drive size ({})", map.size(), drive_size);
+    }
+
+    Ok(Some(Checkpoint { next_bg, map }))
+}
+
+/// Returns the fields of the superblock that change whenever the file system is mounted or written
+/// to: the last write time, the last mount time and the kilobytes written.
+fn write_state(fs: &Fs) -> [u64; 3]
+{
+    [
+        hilo!(fs.sb.s_wtime_hi, fs.sb.s_wtime),
+        hilo!(fs.sb.s_mtime_hi, fs.sb.s_mtime),
+        fs.sb.s_kbytes_written,
+    ]
+}
@@ -0,0 +1,212 @@
+use std::io::{Read, Seek, SeekFrom};
+
+use crate::anomaly::AnomalyKind;
+use crate::bitmap::Bitmap;
+use crate::usage_map::{AllocStatus, UsageMap};
+use crate::{Config, Context};
+use super::{csum, BgFlags, Fs, fetch_regular_bg_descriptor, start_of_bg, verify_desc_csum};
+use crate::bs;
+use crate::hilo;
+
+/// The ways in which the block bitmaps and the scanned metadata can disagree.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DiscrepancyKind {
+    /// The blocks are referenced by the file system metadata, but free in the block bitmap.
+    Unallocated,
+    /// The blocks are allocated in the block bitmap, but not referenced by anything.
+    Unreferenced,
+}
+
+/// A run of blocks on which the block bitmap and the scanned metadata disagree.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Discrepancy {
+    pub kind: DiscrepancyKind,
+    pub block: u64,
+    pub len: u64,
+}
+
+/// Compares the usage map built from the inodes (and the group metadata) against the block
+/// bitmaps, cluster by cluster, and returns the runs of blocks on which they disagree.
+/// Groups with an uninitialised block bitmap or an invalid descriptor are not compared, as the
+/// scan does not rely on their bitmaps. Neither are the unreferenced bitmaps and inode tables:
+/// they are allocated up front, but may be left uninitialised and are then mapped in part or not
+/// at all.
+pub fn cross_check(map: &UsageMap, fs: &Fs, ctx: &mut Context, cfg: &Config)
+    -> anyhow::Result<Vec<Discrepancy>>
+{
+    let block_size = bs!(fs.sb.s_log_block_size);
+    let cluster_size = bs!(fs.sb.s_log_cluster_size);
+    let fs_end = fs.blocks_count * block_size;
+    let has_csum = match fs.opts.dyn_cfg {
+        Some(dyn_cfg) => dyn_cfg.ro_compat.has_metadata_csum() || dyn_cfg.ro_compat.has_gdt_csum(),
+        None => false,
+    };
+
+    // The map of the space that may be allocated without being referenced.
+    let exempt = with_group_metadata(map, fs)?;
+
+    let mut discrepancies = Vec::new();
+
+    for bg_num in 0..fs.bg_count {
+        let desc = fetch_regular_bg_descriptor(bg_num, fs)?;
+
+        if has_csum && !verify_desc_csum(&desc, bg_num, fs)? {
+            continue;
+        }
+        if BgFlags(desc.bg_flags).has_block_uninit() {
+            continue;
+        }
+
+        let block_bitmap_block = if fs.opts.bit64_cfg.is_some() {
+            hilo!(desc.bg_block_bitmap_hi, desc.bg_block_bitmap_lo)
+        } else {
+            desc.bg_block_bitmap_lo as u64
+        };
+
+        let mut raw_bmp = vec![0u8; block_size as usize];
+        ctx.drive.seek(SeekFrom::Start(block_bitmap_block * block_size))?;
+        ctx.drive.read_exact(&mut raw_bmp)?;
+
+        if csum::has_metadata_csum(fs) && !csum::verify_block_bitmap(&raw_bmp, &desc, fs)? {
+            let what = format!("the block bitmap of group {}", bg_num);
+            csum::csum_error(&what, AnomalyKind::BadBitmapChecksum, ctx, cfg)?;
+            continue;
+        }
+        let b_bmp = Bitmap::from_bytes(&raw_bmp);
+
+        let bg_start = start_of_bg(bg_num, fs);
+        let bg_end = std::cmp::min(bg_start + fs.bg_size, fs_end);
+        let referenced = used_clusters(map, bg_start, bg_end, cluster_size);
+        let covered = used_clusters(&exempt, bg_start, bg_end, cluster_size);
+
+        for (i, is_referenced) in referenced.iter().enumerate() {
+            if i >= block_size as usize * 8 {
+                break;
+            }
+
+            let kind = match (b_bmp.check_bit(i), is_referenced) {
+                (false, true) => DiscrepancyKind::Unallocated,
+                (true, false) if !covered[i] => DiscrepancyKind::Unreferenced,
+                _ => continue,
+            };
+
+            let start = bg_start + i as u64 * cluster_size;
+            let end = std::cmp::min(start + cluster_size, bg_end);
+            push_run(&mut discrepancies, kind, start / block_size, (end - start) / block_size);
+        }
+    }
+
+    Ok(discrepancies)
+}
+
+/// Returns a copy of the map with the bitmaps and the whole inode tables of all the groups marked
+/// as used.
+pub fn with_group_metadata(map: &UsageMap, fs: &Fs) -> anyhow::Result<UsageMap>
+{
+    let block_size = bs!(fs.sb.s_log_block_size);
+    let itable_size = fs.sb.s_inodes_per_group as u64 * fs.inode_size;
+
+    let mut covered = map.clone();
+    for bg_num in 0..fs.bg_count {
+        let desc = fetch_regular_bg_descriptor(bg_num, fs)?;
+        let (block_bitmap_block, inode_bitmap_block, inode_table_block) =
+            if fs.opts.bit64_cfg.is_some() {
+                (
+                    hilo!(desc.bg_block_bitmap_hi, desc.bg_block_bitmap_lo),
+                    hilo!(desc.bg_inode_bitmap_hi, desc.bg_inode_bitmap_lo),
+                    hilo!(desc.bg_inode_table_hi, desc.bg_inode_table_lo),
+                )
+            } else {
+                (
+                    desc.bg_block_bitmap_lo as u64,
+                    desc.bg_inode_bitmap_lo as u64,
+                    desc.bg_inode_table_lo as u64,
+                )
+            };
+
+        covered.update(block_bitmap_block * block_size, block_size, AllocStatus::Used);
+        covered.update(inode_bitmap_block * block_size, block_size, AllocStatus::Used);
+        covered.update(inode_table_block * block_size, itable_size, AllocStatus::Used);
+    }
+
+    Ok(covered)
+}
+
+/// Returns, for each cluster of the range, whether any of its bytes is used in the map.
+pub fn used_clusters(map: &UsageMap, start: u64, end: u64, cluster_size: u64) -> Vec<bool>
+{
+    let mut used = vec![false; (end - start).div_ceil(cluster_size) as usize];
+
+    for s in map.overlapping(start, end) {
+        if s.status != AllocStatus::Used {
+            continue;
+        }
+
+        let first_cluster = (std::cmp::max(s.start, start) - start) / cluster_size;
+        let last_cluster = (std::cmp::min(s.end, end) - start - 1) / cluster_size;
+        for cluster in first_cluster..=last_cluster {
+            used[cluster as usize] = true;
+        }
+    }
+
+    used
+}
+
+/// Adds blocks to the discrepancies, extending the last run if they continue it.
+fn push_run(discrepancies: &mut Vec<Discrepancy>, kind: DiscrepancyKind, block: u64, len: u64)
+{
+    if let Some(last) = discrepancies.last_mut() {
+        if last.kind == kind && last.block + last.len == block {
+            last.len += len;
+            return;
+        }
+    }
+
+    discrepancies.push(Discrepancy { kind, block, len });
+}
+
+// Tests.
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::test_context;
+    use crate::filesys::e2fs::GroupDescriptor;
+    use crate::filesys::e2fs::tests::{test_fs_with_descs, test_sb};
+
+    #[test]
+    fn discrepancies()
+    {
+        // The second group is not initialised; its block bitmap is in the first group.
+        let descs = [
+            GroupDescriptor {
+                bg_block_bitmap_lo: 2,
+                bg_inode_bitmap_lo: 3,
+                bg_inode_table_lo: 4,
+                ..GroupDescriptor::default()
+            },
+            GroupDescriptor { bg_block_bitmap_lo: 5, bg_flags: 0x3, ..GroupDescriptor::default() },
+        ];
+        let fs = test_fs_with_descs(test_sb(), &descs);
+
+        // The bitmap allocates the blocks 0 to 5 and 10 to 12.
+        let mut drive = vec![0u8; 64 * 4096];
+        drive[2 * 4096] = 0x3f;
+        drive[2 * 4096 + 1] = 0x1c;
+        let mut ctx = test_context(&drive);
+
+        // Only a part of the inode table and none of the other group's bitmaps are used. Blocks 11 and
+        // 12 are referenced, 13 and 14 (partially) too, as are the blocks of the second group.
+        let mut map = UsageMap::new(drive.len() as u64);
+        map.update(0, 3 * 4096, AllocStatus::Used);
+        map.update(4 * 4096, 256, AllocStatus::Used);
+        map.update(11 * 4096, 3 * 4096 + 1, AllocStatus::Used);
+        map.update(40 * 4096, 4096, AllocStatus::Used);
+
+        let discrepancies = cross_check(&map, &fs, &mut ctx, &Config::default()).unwrap();
+        assert_eq!(discrepancies, vec![
+            Discrepancy { kind: DiscrepancyKind::Unreferenced, block: 10, len: 1 },
+            Discrepancy { kind: DiscrepancyKind::Unallocated, block: 13, len: 2 },
+        ]);
+    }
+}
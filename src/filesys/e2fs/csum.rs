@@ -0,0 +1,321 @@
+use anyhow::bail;
+
+use crate::anomaly::AnomalyKind;
+use crate::{Config, Context};
+use super::inode::GOOD_OLD_INODE_SIZE;
+use super::extent::EXTENT_TAIL_SIZE;
+use super::{Fs, GroupDescriptor, ext4_style_crc32c_le};
+
+// Reference: https://www.kernel.org/doc/html/latest/filesystems/ext4/checksums.html
+
+/// The size of the descriptors that hold the upper halves of the bitmap checksums.
+const DESC_CSUM_HI_SIZE: u64 = 64;
+
+// The offsets of the inode fields that the checksum depends on.
+const I_GENERATION_OFFSET: usize = 0x64;
+const L_I_CHECKSUM_LO_OFFSET: usize = 0x7c;
+const I_EXTRA_ISIZE_OFFSET: usize = 0x80;
+const I_CHECKSUM_HI_OFFSET: usize = 0x82;
+
+/// Checks whether the file system has metadata checksums (metadata_csum). The bitmaps, the inodes
+/// and the extent blocks are only checksummed with it.
+pub fn has_metadata_csum(fs: &Fs) -> bool
+{
+    fs.opts.dyn_cfg.is_some_and(|dyn_cfg| dyn_cfg.ro_compat.has_metadata_csum())
+}
+
+/// Verifies the checksum of a group's block bitmap, which covers the bits of the clusters of the
+/// group.
+pub fn verify_block_bitmap(bitmap: &[u8], desc: &GroupDescriptor, fs: &Fs) -> anyhow::Result<bool>
+{
+    let expected = csum32(desc.bg_block_bitmap_csum_hi, desc.bg_block_bitmap_csum_lo);
+    let len = fs.sb.s_clusters_per_group as usize / 8;
+
+    verify_bitmap(bitmap, len, expected, fs)
+}
+
+/// Verifies the checksum of a group's inode bitmap, which covers the bits of the inodes of the
+/// group.
+pub fn verify_inode_bitmap(bitmap: &[u8], desc: &GroupDescriptor, fs: &Fs) -> anyhow::Result<bool>
+{
+    let expected = csum32(desc.bg_inode_bitmap_csum_hi, desc.bg_inode_bitmap_csum_lo);
+    let len = fs.sb.s_inodes_per_group as usize / 8;
+
+    verify_bitmap(bitmap, len, expected, fs)
+}
+
+/// Verifies the checksum of a raw inode, which covers the inode number, the generation and the
+/// whole on-disk inode (with the checksum fields zeroed). Zeroed inodes are valid, as mke2fs does
+/// not initialise the unused reserved inodes.
+pub fn verify_inode(raw_inode: &[u8], ino: u64, fs: &Fs) -> anyhow::Result<bool>
+{
+    let seed = match fs.csum_seed {
+        Some(seed) => seed,
+        None => bail!("cannot verify checksum: the checksum seed is not derived"),
+    };
+
+    if raw_inode.iter().all(|b| *b == 0) {
+        return Ok(true);
+    }
+
+    let extra_isize = if raw_inode.len() > GOOD_OLD_INODE_SIZE as usize {
+        read_u16(raw_inode, I_EXTRA_ISIZE_OFFSET) as usize
+    } else {
+        0
+    };
+    // The upper half is only stored in large inodes with enough extra space.
+    let has_hi = extra_isize >= I_CHECKSUM_HI_OFFSET + 2 - GOOD_OLD_INODE_SIZE as usize;
+
+    let lo = read_u16(raw_inode, L_I_CHECKSUM_LO_OFFSET);
+    let hi = if has_hi { read_u16(raw_inode, I_CHECKSUM_HI_OFFSET) } else { 0 };
+
+    let mut raw = raw_inode.to_vec();
+    raw[L_I_CHECKSUM_LO_OFFSET..L_I_CHECKSUM_LO_OFFSET + 2].fill(0);
+    if has_hi {
+        raw[I_CHECKSUM_HI_OFFSET..I_CHECKSUM_HI_OFFSET + 2].fill(0);
+    }
+
+    let generation = read_u32(raw_inode, I_GENERATION_OFFSET);
+    let mut csum = ext4_style_crc32c_le(inode_seed(seed, ino, generation), &raw);
+    if !has_hi {
+        csum &= 0xffff;
+    }
+
+    Ok(csum == csum32(hi, lo))
+}
+
+/// Verifies the checksum of an extent tree block, which covers the block up to the tail, and is
+/// seeded with the inode number and generation of the owner.
+pub fn verify_extent_block(block: &[u8], tail_offset: usize, ino: u64, generation: u32, fs: &Fs)
+    -> anyhow::Result<bool>
+{
+    let seed = match fs.csum_seed {
+        Some(seed) => seed,
+        None => bail!("cannot verify checksum: the checksum seed is not derived"),
+    };
+    if tail_offset + EXTENT_TAIL_SIZE > block.len() {
+        bail!("the extent tail lies beyond the block: offset {}", tail_offset);
+    }
+
+    let csum = ext4_style_crc32c_le(inode_seed(seed, ino, generation), &block[..tail_offset]);
+
+    Ok(csum == read_u32(block, tail_offset))
+}
+
+/// Computes the checksum of a directory block, which covers the block up to the tail (the fake
+/// entry holding the checksum), and is seeded with the inode number and generation of the owner.
+pub fn dir_block_csum(block: &[u8], tail_offset: usize, ino: u64, generation: u32, fs: &Fs)
+    -> anyhow::Result<u32>
+{
+    let seed = match fs.csum_seed {
+        Some(seed) => seed,
+        None => bail!("cannot compute checksum: the checksum seed is not derived"),
+    };
+    if tail_offset > block.len() {
+        bail!("the directory tail lies beyond the block: offset {}", tail_offset);
+    }
+
+    Ok(ext4_style_crc32c_le(inode_seed(seed, ino, generation), &block[..tail_offset]))
+}
+
+/// Handles metadata with an invalid checksum: fails, unless `--ignore-csum-errors` is set, in which
+/// case a warning is logged and the anomaly is recorded.
+pub fn csum_error(what: &str, kind: AnomalyKind, ctx: &mut Context, cfg: &Config)
+    -> anyhow::Result<()>
+{
+    if !cfg.ignore_csum_errors {
+        bail!("{} has an invalid checksum (see --ignore-csum-errors)", what);
+    }
+
+    ctx.logger.logln(0, &format!("warning: {} has an invalid checksum", what));
+    ctx.anomalies.record(kind);
+
+    Ok(())
+}
+
+/// Joins the halves of a checksum.
+fn csum32(hi: u16, lo: u16) -> u32
+{
+    (hi as u32) << 16 | lo as u32
+}
+
+/// Derives the checksum seed of an inode's metadata from the inode number and generation.
+fn inode_seed(seed: u32, ino: u64, generation: u32) -> u32
+{
+    let seed = ext4_style_crc32c_le(seed, &(ino as u32).to_le_bytes());
+
+    ext4_style_crc32c_le(seed, &generation.to_le_bytes())
+}
+
+fn read_u16(buf: &[u8], offset: usize) -> u16
+{
+    u16::from_le_bytes([buf[offset], buf[offset + 1]])
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> u32
+{
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+/// Verifies the crc32c of the first `len` bytes of a bitmap. Only the lower halves of the
+/// checksums are stored in the small (32-byte) descriptors.
+fn verify_bitmap(bitmap: &[u8], len: usize, expected: u32, fs: &Fs) -> anyhow::Result<bool>
+{
+    let seed = match fs.csum_seed {
+        Some(seed) => seed,
+        None => bail!("cannot verify checksum: the checksum seed is not derived"),
+    };
+    if len > bitmap.len() {
+        bail!("the bitmap is too short: {} bytes (expected {})", bitmap.len(), len);
+    }
+
+    let mut csum = ext4_style_crc32c_le(seed, &bitmap[..len]);
+    if fs.desc_size < DESC_CSUM_HI_SIZE {
+        csum &= 0xffff;
+    }
+
+    Ok(csum == expected)
+}
+
+// Tests.
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::test_context;
+    use crate::filesys::e2fs::SuperBlock;
+    use crate::filesys::e2fs::tests::{test_fs, test_sb};
+
+    fn csum_fs(sb: SuperBlock) -> Fs
+    {
+        test_fs(SuperBlock { s_feature_ro_compat: 0x400, ..sb })
+    }
+
+    #[test]
+    fn bitmaps()
+    {
+        let fs = csum_fs(test_sb());
+        let seed = fs.csum_seed.unwrap();
+
+        // 32 clusters and 16 inodes per group.
+        let mut bitmap = vec![0xffu8; 4096];
+        bitmap[0] = 0x0f;
+        let block_csum = ext4_style_crc32c_le(seed, &bitmap[..4]);
+        let inode_csum = ext4_style_crc32c_le(seed, &bitmap[..2]);
+
+        let desc = GroupDescriptor {
+            bg_block_bitmap_csum_lo: block_csum as u16,
+            bg_inode_bitmap_csum_lo: inode_csum as u16,
+            ..GroupDescriptor::default()
+        };
+        assert!(verify_block_bitmap(&bitmap, &desc, &fs).unwrap());
+        assert!(verify_inode_bitmap(&bitmap, &desc, &fs).unwrap());
+
+        // The bits beyond the group are not covered.
+        bitmap[4] = 0;
+        assert!(verify_block_bitmap(&bitmap, &desc, &fs).unwrap());
+        bitmap[1] = 0;
+        assert!(!verify_block_bitmap(&bitmap, &desc, &fs).unwrap());
+        assert!(!verify_inode_bitmap(&bitmap, &desc, &fs).unwrap());
+    }
+
+    #[test]
+    fn upper_halves()
+    {
+        let fs = csum_fs(SuperBlock { s_feature_incompat: 0x80, s_desc_size: 64, ..test_sb() });
+        let bitmap = vec![0x01u8; 4096];
+        let csum = ext4_style_crc32c_le(fs.csum_seed.unwrap(), &bitmap[..4]);
+
+        let desc = GroupDescriptor {
+            bg_block_bitmap_csum_lo: csum as u16,
+            ..GroupDescriptor::default()
+        };
+        assert_eq!(verify_block_bitmap(&bitmap, &desc, &fs).unwrap(), csum >> 16 == 0);
+
+        let desc = GroupDescriptor { bg_block_bitmap_csum_hi: (csum >> 16) as u16, ..desc };
+        assert!(verify_block_bitmap(&bitmap, &desc, &fs).unwrap());
+    }
+
+    /// Creates a raw inode of the supplied size with a valid checksum.
+    fn raw_inode(size: usize, extra_isize: u16, ino: u32, fs: &Fs) -> Vec<u8>
+    {
+        let mut raw: Vec<u8> = (0..size).map(|i| i as u8).collect();
+        raw[I_EXTRA_ISIZE_OFFSET..I_EXTRA_ISIZE_OFFSET + 2]
+            .copy_from_slice(&extra_isize.to_le_bytes());
+        raw[L_I_CHECKSUM_LO_OFFSET..L_I_CHECKSUM_LO_OFFSET + 2].fill(0);
+        raw[I_CHECKSUM_HI_OFFSET..I_CHECKSUM_HI_OFFSET + 2].fill(0);
+
+        let seed = ext4_style_crc32c_le(fs.csum_seed.unwrap(), &ino.to_le_bytes());
+        let seed = ext4_style_crc32c_le(seed, &raw[I_GENERATION_OFFSET..I_GENERATION_OFFSET + 4]);
+        let csum = ext4_style_crc32c_le(seed, &raw);
+
+        raw[L_I_CHECKSUM_LO_OFFSET..L_I_CHECKSUM_LO_OFFSET + 2]
+            .copy_from_slice(&(csum as u16).to_le_bytes());
+        if extra_isize >= 4 {
+            raw[I_CHECKSUM_HI_OFFSET..I_CHECKSUM_HI_OFFSET + 2]
+                .copy_from_slice(&((csum >> 16) as u16).to_le_bytes());
+        }
+
+        raw
+    }
+
+    #[test]
+    fn inodes()
+    {
+        let fs = csum_fs(test_sb());
+
+        let raw = raw_inode(256, 32, 12, &fs);
+        assert!(verify_inode(&raw, 12, &fs).unwrap());
+        // The inode number is covered.
+        assert!(!verify_inode(&raw, 13, &fs).unwrap());
+
+        let mut corrupt = raw.clone();
+        corrupt[40] ^= 1;
+        assert!(!verify_inode(&corrupt, 12, &fs).unwrap());
+        // So is the extra space.
+        let mut corrupt = raw.clone();
+        corrupt[255] ^= 1;
+        assert!(!verify_inode(&corrupt, 12, &fs).unwrap());
+
+        // Without the upper half.
+        let raw = raw_inode(256, 2, 12, &fs);
+        assert!(verify_inode(&raw, 12, &fs).unwrap());
+
+        assert!(verify_inode(&[0; 256], 12, &fs).unwrap());
+    }
+
+    #[test]
+    fn extent_blocks()
+    {
+        let fs = csum_fs(test_sb());
+        let tail_offset = 4092;
+
+        let mut block: Vec<u8> = (0..4096).map(|i| i as u8).collect();
+        let seed = inode_seed(fs.csum_seed.unwrap(), 12, 7);
+        let csum = ext4_style_crc32c_le(seed, &block[..tail_offset]);
+        block[tail_offset..].copy_from_slice(&csum.to_le_bytes());
+
+        assert!(verify_extent_block(&block, tail_offset, 12, 7, &fs).unwrap());
+        // The owner is covered.
+        assert!(!verify_extent_block(&block, tail_offset, 13, 7, &fs).unwrap());
+        assert!(!verify_extent_block(&block, tail_offset, 12, 8, &fs).unwrap());
+
+        block[100] ^= 1;
+        assert!(!verify_extent_block(&block, tail_offset, 12, 7, &fs).unwrap());
+
+        assert!(verify_extent_block(&block, 4093, 12, 7, &fs).is_err());
+    }
+
+    #[test]
+    fn ignore_errors()
+    {
+        let mut ctx = test_context(&[0; 512]);
+        let kind = AnomalyKind::BadBitmapChecksum;
+
+        assert!(csum_error("the bitmap", kind, &mut ctx, &Config::default()).is_err());
+
+        let cfg = Config { ignore_csum_errors: true, ..Config::default() };
+        csum_error("the bitmap", kind, &mut ctx, &cfg).unwrap();
+        assert_eq!(ctx.anomalies.count(kind), 1);
+    }
+}
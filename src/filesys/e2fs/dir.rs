@@ -0,0 +1,393 @@
+use std::io::{Read, Seek, SeekFrom};
+use anyhow::bail;
+
+use crate::usage_map::{UsageMap, AllocStatus};
+use crate::{Config, Context};
+
+use super::inode::{IFlags, Inode};
+use super::extent::{self, ExtentTree, ExtentTreeIterator};
+use super::{scrub, Fs};
+use crate::bs;
+use crate::hilo;
+
+// Reference: https://www.kernel.org/doc/html/latest/filesystems/ext4/directory.html
+
+/// The number of direct block pointers in a block map.
+const DIRECT_BLOCKS: usize = 12;
+/// The offset of `dx_root_info` in the root block of an htree, after the "." and ".." entries.
+const DX_ROOT_INFO_OFFSET: usize = 24;
+/// The offset of the first `dx_entry` (and the count and limit) in the root block.
+const DX_ROOT_ENTRIES_OFFSET: usize = 32;
+/// The offset of the first `dx_entry` in an interior block, after the empty directory entry.
+const DX_NODE_ENTRIES_OFFSET: usize = 8;
+const DX_ENTRY_SIZE: usize = 8;
+
+/// Scans the blocks of a directory.
+/// Directory blocks are used as a whole, as the entries span the whole block, so they are marked
+/// regardless of the directory size. The interior blocks of hashed (htree) directories are
+/// followed from the root and have to be blocks of the directory.
+pub fn scan_dir_blocks(
+    map: &mut UsageMap,
+    ino: u64,
+    inode: &Inode,
+    fs: &Fs,
+    ctx: &mut Context,
+    cfg: &Config,
+) -> anyhow::Result<()>
+{
+    let i_flags = IFlags(inode.i_flags);
+    let block_size = bs!(fs.sb.s_log_block_size);
+
+    let blocks = if i_flags.has_extents() {
+        extent::scan_extent_tree(map, ino, inode, fs, ctx, cfg)?;
+        map_extents(inode, fs, ctx, cfg)?
+    } else {
+        map_block_map(map, inode, fs, ctx)?
+    };
+
+    for block in blocks.iter().filter(|b| **b != 0) {
+        map.update(block * block_size, block_size, AllocStatus::Used);
+    }
+
+    let dx_blocks = if i_flags.has_index() {
+        check_htree(&blocks, fs, ctx)?
+    } else {
+        Vec::new()
+    };
+
+    if cfg.scrub_dirents {
+        scrub::scrub_dir_blocks(ino, inode, &blocks, &dx_blocks, fs, ctx, cfg)?;
+    }
+
+    Ok(())
+}
+
+/// Returns the number of blocks of a directory, from its size.
+/// The high bits of the size are only used by directories with largedir; older file systems kept
+/// the directory ACL in their place.
+fn dir_block_count(inode: &Inode, fs: &Fs) -> u64
+{
+    let size = if has_largedir(fs) {
+        hilo!(inode.i_size_high, inode.i_size_lo)
+    } else {
+        inode.i_size_lo as u64
+    };
+
+    size.div_ceil(bs!(fs.sb.s_log_block_size))
+}
+
+fn has_largedir(fs: &Fs) -> bool
+{
+    fs.opts.dyn_cfg.is_some_and(|dyn_cfg| dyn_cfg.incompat.has_largedir())
+}
+
+/// Records the physical block of a logical block of the directory.
+fn set_block(blocks: &mut Vec<u64>, lblk: u64, count: u64, block: u64) -> anyhow::Result<()>
+{
+    if lblk >= count {
+        bail!("directory block {} lies beyond the directory size ({} blocks)", lblk, count);
+    }
+
+    if blocks.len() <= lblk as usize {
+        blocks.resize(lblk as usize + 1, 0);
+    }
+    blocks[lblk as usize] = block;
+
+    Ok(())
+}
+
+/// Maps the logical blocks of a directory with extents to the physical blocks. Holes are 0.
+fn map_extents(inode: &Inode, fs: &Fs, ctx: &mut Context, cfg: &Config)
+    -> anyhow::Result<Vec<u64>>
+{
+    let count = dir_block_count(inode, fs);
+    let mut blocks = Vec::new();
+
+    let extent_tree = ExtentTree::new(inode, fs, ctx, cfg)?;
+    for e in ExtentTreeIterator::new(&extent_tree) {
+        let start = hilo!(e.ee_start_hi, e.ee_start_lo);
+        for i in 0..e.ee_len as u64 {
+            set_block(&mut blocks, e.ee_block as u64 + i, count, start + i)?;
+        }
+    }
+
+    Ok(blocks)
+}
+
+/// Maps the logical blocks of a directory with a block map to the physical blocks. Holes are 0.
+/// The indirect blocks are marked as used.
+fn map_block_map(map: &mut UsageMap, inode: &Inode, fs: &Fs, ctx: &mut Context)
+    -> anyhow::Result<Vec<u64>>
+{
+    let count = dir_block_count(inode, fs);
+    let per_block = bs!(fs.sb.s_log_block_size) / 4;
+    let mut blocks = Vec::new();
+
+    for (i, block) in inode.i_block[..DIRECT_BLOCKS].iter().enumerate() {
+        if *block != 0 {
+            set_block(&mut blocks, i as u64, count, *block as u64)?;
+        }
+    }
+
+    // The single, double and triple indirect blocks.
+    let mut first = DIRECT_BLOCKS as u64;
+    for level in 1..=3 {
+        let block = inode.i_block[DIRECT_BLOCKS + level as usize - 1] as u64;
+        map_indirect_block(map, &mut blocks, block, first, level, count, fs, ctx)?;
+        first += per_block.pow(level);
+    }
+
+    Ok(blocks)
+}
+
+/// Maps the logical blocks referenced by an indirect block of the supplied level. `first` is the
+/// first logical block that the indirect block covers.
+#[allow(clippy::too_many_arguments)]
+fn map_indirect_block(
+    map: &mut UsageMap,
+    blocks: &mut Vec<u64>,
+    block: u64,
+    first: u64,
+    level: u32,
+    count: u64,
+    fs: &Fs,
+    ctx: &mut Context,
+) -> anyhow::Result<()>
+{
+    if block == 0 || first >= count {
+        return Ok(());
+    }
+
+    let block_size = bs!(fs.sb.s_log_block_size);
+    let per_block = block_size / 4;
+    // The number of logical blocks covered by an entry.
+    let span = per_block.pow(level - 1);
+
+    map.update(block * block_size, block_size, AllocStatus::Used);
+
+    let mut buf = vec![0u8; block_size as usize];
+    ctx.drive.seek(SeekFrom::Start(block * block_size))?;
+    ctx.drive.read_exact(&mut buf)?;
+
+    for (i, raw) in buf.chunks_exact(4).enumerate() {
+        let lblk = first + i as u64 * span;
+        if lblk >= count {
+            break;
+        }
+
+        let entry = u32::from_le_bytes(raw.try_into().unwrap()) as u64;
+        if entry == 0 {
+            continue;
+        }
+
+        if level == 1 {
+            set_block(blocks, lblk, count, entry)?;
+        } else {
+            map_indirect_block(map, blocks, entry, lblk, level - 1, count, fs, ctx)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Checks the htree of a hashed directory: the root and the interior blocks have to be valid and
+/// every block that they reference has to be a block of the directory. Returns the logical blocks
+/// of the root and the interior nodes.
+fn check_htree(blocks: &[u64], fs: &Fs, ctx: &mut Context) -> anyhow::Result<Vec<u64>>
+{
+    let root = read_dir_block(blocks, 0, fs, ctx)?;
+
+    let info = &root[DX_ROOT_INFO_OFFSET..DX_ROOT_ENTRIES_OFFSET];
+    let reserved_zero = u32::from_le_bytes(info[0..4].try_into().unwrap());
+    let info_length = info[5];
+    let indirect_levels = info[6];
+
+    if reserved_zero != 0 || info_length != 8 {
+        bail!("invalid htree root: reserved {:#x}, info length {}", reserved_zero, info_length);
+    }
+
+    let max_levels = if has_largedir(fs) { 2 } else { 1 };
+    if indirect_levels > max_levels {
+        bail!("the htree has too many levels: {} (at most {})", indirect_levels, max_levels);
+    }
+
+    let mut dx_blocks = vec![0];
+    check_dx_node(&root, DX_ROOT_ENTRIES_OFFSET, indirect_levels, &mut dx_blocks, blocks, fs, ctx)?;
+
+    Ok(dx_blocks)
+}
+
+/// Checks the entries of an htree node and descends into the interior blocks below it, which are
+/// added to `dx_blocks`.
+fn check_dx_node(
+    node: &[u8],
+    offset: usize,
+    levels_below: u8,
+    dx_blocks: &mut Vec<u64>,
+    blocks: &[u64],
+    fs: &Fs,
+    ctx: &mut Context,
+) -> anyhow::Result<()>
+{
+    let limit = u16::from_le_bytes(node[offset..offset + 2].try_into().unwrap()) as usize;
+    let count = u16::from_le_bytes(node[offset + 2..offset + 4].try_into().unwrap()) as usize;
+
+    if count == 0 || count > limit || offset + limit * DX_ENTRY_SIZE > node.len() {
+        bail!("invalid htree node: count {}, limit {}", count, limit);
+    }
+
+    for i in 0..count {
+        // The block of the first entry follows the count and the limit, in place of the hash.
+        let at = offset + i * DX_ENTRY_SIZE + 4;
+        let lblk = u32::from_le_bytes(node[at..at + 4].try_into().unwrap()) as u64;
+
+        if levels_below == 0 {
+            // Leaves are regular directory blocks.
+            read_dir_block(blocks, lblk, fs, ctx)?;
+        } else {
+            let child = read_dir_block(blocks, lblk, fs, ctx)?;
+            dx_blocks.push(lblk);
+            let levels = levels_below - 1;
+            check_dx_node(&child, DX_NODE_ENTRIES_OFFSET, levels, dx_blocks, blocks, fs, ctx)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Reads a logical block of the directory, which has to be mapped.
+fn read_dir_block(blocks: &[u64], lblk: u64, fs: &Fs, ctx: &mut Context)
+    -> anyhow::Result<Vec<u8>>
+{
+    let block = match blocks.get(lblk as usize) {
+        Some(block) if *block != 0 => *block,
+        _ => bail!("the htree references an unmapped directory block: {}", lblk),
+    };
+
+    let block_size = bs!(fs.sb.s_log_block_size);
+    let mut buf = vec![0u8; block_size as usize];
+    ctx.drive.seek(SeekFrom::Start(block * block_size))?;
+    ctx.drive.read_exact(&mut buf)?;
+
+    Ok(buf)
+}
+
+// Tests.
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bincode::{DefaultOptions, Options};
+    use crate::util::test_context;
+    use crate::usage_map::Segment;
+    use crate::filesys::e2fs::tests::{test_fs, test_sb};
+
+    const BLOCK_SIZE: usize = 4096;
+
+    /// Creates a directory inode of the supplied size in blocks, with a block map.
+    fn dir_inode(blocks: u32, i_block: &[u32], i_flags: u32) -> Inode
+    {
+        let bincode_opt = DefaultOptions::new()
+            .with_fixint_encoding()
+            .allow_trailing_bytes();
+
+        let mut inode: Inode = bincode_opt.deserialize(&[0u8; 256]).unwrap();
+        inode.i_mode = 0x41ed;
+        inode.i_size_lo = blocks * BLOCK_SIZE as u32;
+        inode.i_flags = i_flags;
+        inode.i_block[..i_block.len()].copy_from_slice(i_block);
+
+        inode
+    }
+
+    /// Writes an htree root, with the supplied levels and the blocks of its entries, into a block.
+    fn write_dx_root(drive: &mut [u8], block: usize, levels: u8, entries: &[u32])
+    {
+        let root = &mut drive[block * BLOCK_SIZE..(block + 1) * BLOCK_SIZE];
+        root[DX_ROOT_INFO_OFFSET + 5] = 8;
+        root[DX_ROOT_INFO_OFFSET + 6] = levels;
+        write_dx_entries(root, DX_ROOT_ENTRIES_OFFSET, entries);
+    }
+
+    fn write_dx_entries(node: &mut [u8], offset: usize, entries: &[u32])
+    {
+        let limit = ((node.len() - offset) / DX_ENTRY_SIZE) as u16;
+        node[offset..offset + 2].copy_from_slice(&limit.to_le_bytes());
+        node[offset + 2..offset + 4].copy_from_slice(&(entries.len() as u16).to_le_bytes());
+        for (i, lblk) in entries.iter().enumerate() {
+            let at = offset + i * DX_ENTRY_SIZE + 4;
+            node[at..at + 4].copy_from_slice(&lblk.to_le_bytes());
+        }
+    }
+
+    #[test]
+    fn block_map()
+    {
+        // 14 blocks: 12 direct ones and 2 through the indirect block 30, with a hole at 13.
+        let fs = test_fs(test_sb());
+        let mut drive = vec![0u8; 64 * BLOCK_SIZE];
+        drive[30 * BLOCK_SIZE..30 * BLOCK_SIZE + 4].copy_from_slice(&40u32.to_le_bytes());
+        let mut i_block: Vec<u32> = (10..22).collect();
+        i_block.push(30);
+        let inode = dir_inode(14, &i_block, 0);
+
+        let mut ctx = test_context(&drive);
+        let mut map = UsageMap::new(64 * BLOCK_SIZE as u64);
+        scan_dir_blocks(&mut map, 12, &inode, &fs, &mut ctx, &Config::default()).unwrap();
+
+        let b = BLOCK_SIZE as u64;
+        assert_eq!(map.segments(), vec![
+            Segment { start: 0, end: 10 * b, status: AllocStatus::Free },
+            Segment { start: 10 * b, end: 22 * b, status: AllocStatus::Used },
+            Segment { start: 22 * b, end: 30 * b, status: AllocStatus::Free },
+            Segment { start: 30 * b, end: 31 * b, status: AllocStatus::Used },
+            Segment { start: 31 * b, end: 40 * b, status: AllocStatus::Free },
+            Segment { start: 40 * b, end: 41 * b, status: AllocStatus::Used },
+            Segment { start: 41 * b, end: 64 * b, status: AllocStatus::Free },
+        ]);
+    }
+
+    #[test]
+    fn beyond_size()
+    {
+        let fs = test_fs(test_sb());
+        let inode = dir_inode(1, &[10, 11], 0);
+        let mut ctx = test_context(&[0u8; 64 * BLOCK_SIZE]);
+        let mut map = UsageMap::new(64 * BLOCK_SIZE as u64);
+
+        assert!(scan_dir_blocks(&mut map, 12, &inode, &fs, &mut ctx, &Config::default()).is_err());
+    }
+
+    #[test]
+    fn htree()
+    {
+        // The root at 10 points to the interior node at 11 (logical 1), which points to the
+        // leaves at logical 2 and 3.
+        let fs = test_fs(test_sb());
+        let mut drive = vec![0u8; 64 * BLOCK_SIZE];
+        write_dx_root(&mut drive, 10, 1, &[1]);
+        let node = &mut drive[11 * BLOCK_SIZE..12 * BLOCK_SIZE];
+        write_dx_entries(node, DX_NODE_ENTRIES_OFFSET, &[2, 3]);
+        let inode = dir_inode(4, &[10, 11, 12, 13], 0x1000);
+
+        let mut ctx = test_context(&drive);
+        let mut map = UsageMap::new(64 * BLOCK_SIZE as u64);
+        scan_dir_blocks(&mut map, 12, &inode, &fs, &mut ctx, &Config::default()).unwrap();
+        assert_eq!(map.segments()[1], Segment {
+            start: 10 * BLOCK_SIZE as u64,
+            end: 14 * BLOCK_SIZE as u64,
+            status: AllocStatus::Used,
+        });
+
+        // A leaf outside of the directory.
+        let node = &mut drive[11 * BLOCK_SIZE..12 * BLOCK_SIZE];
+        write_dx_entries(node, DX_NODE_ENTRIES_OFFSET, &[2, 7]);
+        let mut ctx = test_context(&drive);
+        assert!(scan_dir_blocks(&mut map, 12, &inode, &fs, &mut ctx, &Config::default()).is_err());
+
+        // Too many levels without largedir.
+        write_dx_root(&mut drive, 10, 2, &[1]);
+        let mut ctx = test_context(&drive);
+        assert!(scan_dir_blocks(&mut map, 12, &inode, &fs, &mut ctx, &Config::default()).is_err());
+    }
+}
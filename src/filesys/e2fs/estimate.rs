@@ -0,0 +1,97 @@
+use serde::Serialize;
+
+use crate::{bs, hilo};
+use super::{Fs, fetch_regular_bg_descriptor};
+
+/// Free space estimate, in bytes.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Estimate {
+    /// The sum of the free cluster counts of the group descriptors.
+    pub gross_free: u64,
+    /// The gross figure, less the file system overhead (`s_overhead_clusters`).
+    /// The descriptor counts already exclude the metadata that is allocated in the bitmaps, so the
+    /// net figure is a conservative lower bound.
+    pub net_free: u64,
+}
+
+/// Estimates the free space from the group descriptors' free cluster counts.
+/// The descriptors are not checked; the estimate is only as good as the counts.
+pub fn estimate_free(fs: &Fs) -> anyhow::Result<Estimate>
+{
+    // Both the free counts and the overhead are in clusters.
+    let cluster_size = bs!(fs.sb.s_log_cluster_size);
+    let mut free_clusters = 0;
+
+    for bg_num in 0..fs.bg_count {
+        let desc = fetch_regular_bg_descriptor(bg_num, fs)?;
+
+        free_clusters += if fs.opts.bit64_cfg.is_some() {
+            hilo!(desc.bg_free_blocks_count_hi, desc.bg_free_blocks_count_lo)
+        } else {
+            desc.bg_free_blocks_count_lo as u64
+        };
+    }
+
+    let gross_free = free_clusters * cluster_size;
+    let overhead = fs.sb.s_overhead_clusters as u64 * cluster_size;
+
+    Ok(Estimate {
+        gross_free,
+        net_free: gross_free.saturating_sub(overhead),
+    })
+}
+
+// Tests.
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::{GroupDescriptor, SuperBlock};
+    use crate::filesys::e2fs::tests::{test_fs_with_descs, test_sb};
+
+    /// Creates a two-group file system with the supplied free cluster counts.
+    fn fs_with_free(sb: SuperBlock, free: [u16; 2]) -> Fs
+    {
+        let descs = free.map(|count| GroupDescriptor {
+            bg_free_blocks_count_lo: count,
+            ..GroupDescriptor::default()
+        });
+
+        test_fs_with_descs(sb, &descs)
+    }
+
+    #[test]
+    fn net_estimate()
+    {
+        let fs = fs_with_free(SuperBlock { s_overhead_clusters: 5, ..test_sb() }, [20, 10]);
+
+        assert_eq!(estimate_free(&fs).unwrap(), Estimate {
+            gross_free: 30 * 4096,
+            net_free: 25 * 4096,
+        });
+    }
+
+    #[test]
+    fn no_overhead()
+    {
+        let fs = fs_with_free(test_sb(), [20, 10]);
+
+        assert_eq!(estimate_free(&fs).unwrap(), Estimate {
+            gross_free: 30 * 4096,
+            net_free: 30 * 4096,
+        });
+    }
+
+    #[test]
+    fn overhead_in_clusters()
+    {
+        // 16KiB clusters of four 4KiB blocks.
+        let sb = SuperBlock { s_log_cluster_size: 4, s_overhead_clusters: 40, ..test_sb() };
+        let fs = fs_with_free(sb, [20, 10]);
+
+        assert_eq!(estimate_free(&fs).unwrap(), Estimate {
+            gross_free: 30 * 16384,
+            net_free: 0,
+        });
+    }
+}
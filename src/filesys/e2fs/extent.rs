@@ -4,10 +4,11 @@ use bincode::{DefaultOptions, Options};
 use anyhow::bail;
 
 use crate::usage_map::{UsageMap, AllocStatus};
-use crate::Context;
+use crate::anomaly::AnomalyKind;
+use crate::{Config, Context};
 
 use super::inode::{Inode, N_BLOCKS};
-use super::Fs;
+use super::{csum, Fs};
 use crate::bs;
 use crate::hilo;
 
@@ -34,6 +35,22 @@ pub struct Extent {
     pub ee_start_lo: u32,
 }
 
+/// The maximum length of an initialised extent. Longer extents are unwritten: they are allocated
+/// (e.g. by fallocate), but read as zeroes, and their length is `ee_len - EXT_INIT_MAX_LEN`.
+const EXT_INIT_MAX_LEN: u16 = 32768;
+
+impl Extent {
+    /// Returns the number of blocks covered by the extent, whether it is written or not.
+    pub fn block_count(&self) -> u64
+    {
+        if self.ee_len > EXT_INIT_MAX_LEN {
+            (self.ee_len - EXT_INIT_MAX_LEN) as u64
+        } else {
+            self.ee_len as u64
+        }
+    }
+}
+
 // Reference: https://elixir.bootlin.com/linux/latest/source/fs/ext4/ext4_extents.h
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
 struct ExtentIdx {
@@ -53,6 +70,10 @@ struct ExtentTail {
 
 pub const EXTENT_TAIL_SIZE: usize = 4;
 
+/// The maximum depth of an extent tree.
+/// Reference: https://elixir.bootlin.com/linux/latest/source/fs/ext4/ext4_extents.h
+pub const EXT4_MAX_EXTENT_DEPTH: u16 = 5;
+
 /// E2fs extent tree.
 #[derive(Clone, Debug)]
 pub struct ExtentTree {
@@ -61,7 +82,7 @@ pub struct ExtentTree {
 
 impl ExtentTree {
     /// Reads the inode's extent tree from the drive.
-    pub fn new(inode: &Inode, fs: &Fs, ctx: &mut Context) -> anyhow::Result<Self>
+    pub fn new(inode: &Inode, fs: &Fs, ctx: &mut Context, cfg: &Config) -> anyhow::Result<Self>
     {
         // Get the elements of inode.i_block.
         let mut i_block = [u8::default(); N_BLOCKS * 4];
@@ -74,7 +95,8 @@ impl ExtentTree {
         // Construct the root node and its subnodes.
 
         let mut root_node = Node::from_raw(&i_block)?;
-        root_node.populate_subnodes(fs, ctx)?;
+        let mut node_count = 0;
+        root_node.populate_subnodes(&mut node_count, fs, ctx, cfg)?;
 
         Ok(ExtentTree {
             root_node,
@@ -100,12 +122,15 @@ impl Node {
 
         // Deserialise the extent header.
 
-        let header: ExtentHeader = bincode_opt.deserialize(&raw_node)?;
+        let header: ExtentHeader = bincode_opt.deserialize(raw_node)?;
 
         // TODO: wrap this error with the node id outside of this procedure.
         if header.eh_magic != 0xf30a {
             bail!("extent tree node's header does not match the magic value");
         }
+        if header.eh_depth > EXT4_MAX_EXTENT_DEPTH {
+            bail!("extent tree node has an invalid depth: {}", header.eh_depth);
+        }
 
         // Deserialise the extents or extent indexes.
 
@@ -141,7 +166,15 @@ impl Node {
     }
 
     /// Populates its subnodes from the disk, recursively.
-    pub fn populate_subnodes(&mut self, fs: &Fs, ctx: &mut Context) -> anyhow::Result<()>
+    /// Each subnode has to be exactly one level shallower than its parent. The nodes read from the
+    /// disk are counted in `node_count`.
+    pub fn populate_subnodes(
+        &mut self,
+        node_count: &mut usize,
+        fs: &Fs,
+        ctx: &mut Context,
+        cfg: &Config,
+    ) -> anyhow::Result<()>
     {
         // If the entries are not indexes, we have reached the leaves of the tree.
         let indexes = if let Entries::Indexes(v) = &mut self.entries {
@@ -159,17 +192,33 @@ impl Node {
         for idx in indexes {
             let block = hilo!(idx.ei_leaf_hi, idx.ei_leaf_lo);
 
+            // The skipped indexes are reported when the space of the tree is scanned.
+            if !check_idx_target(block, false, fs, ctx, cfg)? {
+                continue;
+            }
+
+            count_node(node_count, cfg)?;
+
             // Read the raw node block from the drive.
             ctx.drive.seek(SeekFrom::Start(block * bs!(fs.sb.s_log_block_size)))?;
             ctx.drive.read_exact(&mut block_buf)?;
 
-            let mut new_subnode = Self::from_raw(&mut block_buf)?;
+            let mut new_subnode = Self::from_raw(&block_buf)?;
+
+            if new_subnode.header.eh_depth + 1 != self.header.eh_depth {
+                bail!(
+                    "extent tree node in block {} has depth {}, expected {}",
+                    block,
+                    new_subnode.header.eh_depth,
+                    self.header.eh_depth - 1,
+                );
+            }
 
             // TODO: test on drives with deeply nested extent trees (not tested yet, only on
             // simple extent trees).
 
             if new_subnode.header.eh_depth > 0 {
-                Self::populate_subnodes(&mut new_subnode, fs, ctx)?;
+                Self::populate_subnodes(&mut new_subnode, node_count, fs, ctx, cfg)?;
             }
 
             self.subnodes.as_mut().unwrap().push(new_subnode);
@@ -177,6 +226,16 @@ impl Node {
 
         Ok(())
     }
+
+    /// Returns the number of the node's children, i.e. its extents or the subnodes that were read.
+    pub fn child_count(&self) -> usize
+    {
+        match (&self.subnodes, &self.entries) {
+            (Some(subnodes), _) => subnodes.len(),
+            (None, Entries::Extents(v)) => v.len(),
+            (None, Entries::Indexes(_)) => 0,
+        }
+    }
 }
 
 /// Entries of extent nodes.
@@ -188,12 +247,15 @@ enum Entries {
 
 // TODO: implement extent tree scanning utilising the ExtentTree structure. Currently, this
 // procedure implements extent tree parsing on its own.
-/// Scans the space occupied by the extent tree.
+/// Scans the space occupied by the extent tree of inode `ino`.
+/// With metadata_csum, the checksums of the tree blocks are verified as well.
 pub fn scan_extent_tree(
     map: &mut UsageMap,
+    ino: u64,
     inode: &Inode,
     fs: &Fs,
     ctx: &mut Context,
+    cfg: &Config,
 ) -> anyhow::Result<()>
 {
     let bincode_opt = DefaultOptions::new()
@@ -217,6 +279,8 @@ pub fn scan_extent_tree(
         return Ok(());
     }
 
+    let mut node_count = 0;
+
     // Deserialise the entries and scan the extent node blocks.
 
     for i in 0..e_header.eh_entries as usize {
@@ -224,24 +288,40 @@ pub fn scan_extent_tree(
         let e_idx: ExtentIdx = bincode_opt.deserialize(&i_block[e_idx_offset..])?;
 
         let block = hilo!(e_idx.ei_leaf_hi, e_idx.ei_leaf_lo);
-        scan_extent_block(map, block, fs, ctx)?;
+
+        if !check_idx_target(block, true, fs, ctx, cfg)? {
+            continue;
+        }
+
+        let depth = e_header.eh_depth - 1;
+        let owner = (ino, inode.i_generation);
+        scan_extent_block(map, block, depth, owner, &mut node_count, fs, ctx, cfg)?;
     }
 
     Ok(())
 }
 
 /// Scans the space occupied by an extent tree node.
+/// The node is expected to be at the specified depth. The nodes read from the disk are counted in
+/// `node_count`. `owner` is the inode number and generation of the inode that owns the tree.
+#[allow(clippy::too_many_arguments)]
 fn scan_extent_block(
     map: &mut UsageMap,
     block: u64,
+    depth: u16,
+    owner: (u64, u32),
+    node_count: &mut usize,
     fs: &Fs,
-    ctx: &mut Context
+    ctx: &mut Context,
+    cfg: &Config,
 ) -> anyhow::Result<()>
 {
     let bincode_opt = DefaultOptions::new()
         .with_fixint_encoding()
         .allow_trailing_bytes();
 
+    count_node(node_count, cfg)?;
+
     // Read the raw node block.
 
     let mut block_buf = vec![u8::default(); bs!(fs.sb.s_log_block_size) as usize];
@@ -250,18 +330,38 @@ fn scan_extent_block(
 
     let e_header: ExtentHeader = bincode_opt.deserialize(&block_buf)?;
 
-    // Extent header + entries.
-    map.update(
-        block * bs!(fs.sb.s_log_block_size),
-        EXTENT_HEADER_SIZE as u64 + (e_header.eh_entries as u64 * EXTENT_IDX_SIZE as u64),
-        AllocStatus::Used
-    );
-    // Extent tail
-    map.update(
-        (block + 1) * bs!(fs.sb.s_log_block_size) - 4,
-        4,
-        AllocStatus::Used
-    );
+    if e_header.eh_magic != 0xf30a {
+        bail!("extent tree node in block {} does not match the magic value", block);
+    }
+    if e_header.eh_depth != depth {
+        bail!("extent tree node in block {} has depth {}, expected {}", block, e_header.eh_depth, depth);
+    }
+
+    if csum::has_metadata_csum(fs) {
+        check_node_csum(&block_buf, block, &e_header, owner, fs, ctx, cfg)?;
+    }
+
+    if csum::has_metadata_csum(fs) {
+        // The checksum covers the unused entries as well, so the whole block is kept.
+        map.update(
+            block * bs!(fs.sb.s_log_block_size),
+            bs!(fs.sb.s_log_block_size),
+            AllocStatus::Used
+        );
+    } else {
+        // Extent header + entries.
+        map.update(
+            block * bs!(fs.sb.s_log_block_size),
+            EXTENT_HEADER_SIZE as u64 + (e_header.eh_entries as u64 * EXTENT_IDX_SIZE as u64),
+            AllocStatus::Used
+        );
+        // Extent tail
+        map.update(
+            (block + 1) * bs!(fs.sb.s_log_block_size) - 4,
+            4,
+            AllocStatus::Used
+        );
+    }
 
     if e_header.eh_depth == 0 {
         return Ok(());
@@ -276,13 +376,87 @@ fn scan_extent_block(
         let e_idx: ExtentIdx = bincode_opt.deserialize(&block_buf[e_idx_offset..])?;
 
         let block = hilo!(e_idx.ei_leaf_hi, e_idx.ei_leaf_lo);
-        scan_extent_block(map, block, fs, ctx)?;
+
+        if !check_idx_target(block, true, fs, ctx, cfg)? {
+            continue;
+        }
+
+        scan_extent_block(map, block, depth - 1, owner, node_count, fs, ctx, cfg)?;
+    }
+
+    Ok(())
+}
+
+/// Checks whether an extent index points to a block inside the file system.
+/// Indexes pointing outside the file system are skipped, or rejected in strict mode. With `report`
+/// set, a skipped index is logged as a warning and recorded as an anomaly.
+fn check_idx_target(
+    block: u64,
+    report: bool,
+    fs: &Fs,
+    ctx: &mut Context,
+    cfg: &Config,
+) -> anyhow::Result<bool>
+{
+    if block < fs.blocks_count {
+        return Ok(true);
+    }
+
+    if cfg.strict {
+        bail!("extent index points to block {} outside of the file system", block);
+    }
+
+    if report {
+        ctx.logger.logln(0, &format!(
+            "warning: skipping extent index pointing to block {} outside of the file system",
+            block
+        ));
+        ctx.anomalies.record(AnomalyKind::ExtentIndexOutOfRange);
+    }
+
+    Ok(false)
+}
+
+/// Verifies the checksum in the tail of an extent tree block, which follows the `eh_max` entries.
+/// With `--ignore-csum-errors`, the node is still followed: skipping it could make the blocks that
+/// it maps look free.
+fn check_node_csum(
+    block_buf: &[u8],
+    block: u64,
+    e_header: &ExtentHeader,
+    owner: (u64, u32),
+    fs: &Fs,
+    ctx: &mut Context,
+    cfg: &Config,
+) -> anyhow::Result<()>
+{
+    let (ino, generation) = owner;
+    let tail_offset = EXTENT_HEADER_SIZE + e_header.eh_max as usize * EXTENT_SIZE;
+
+    if !csum::verify_extent_block(block_buf, tail_offset, ino, generation, fs)? {
+        let what = format!("extent tree node in block {} (inode {})", block, ino);
+        csum::csum_error(&what, AnomalyKind::BadExtentChecksum, ctx, cfg)?;
+    }
+
+    Ok(())
+}
+
+/// Counts a node read from the disk and checks the count against the configured limit.
+/// The limit guards against corrupt or malicious trees of an excessive breadth.
+fn count_node(node_count: &mut usize, cfg: &Config) -> anyhow::Result<()>
+{
+    *node_count += 1;
+
+    if *node_count > cfg.max_extent_nodes {
+        bail!("extent tree has more than {} nodes (see --max-extent-nodes)", cfg.max_extent_nodes);
     }
 
     Ok(())
 }
 
 /// Iterator for ExtentTree.
+/// The depths of the nodes are validated when the tree is constructed, so the iterator does not
+/// need to handle inconsistent trees.
 pub struct ExtentTreeIterator<'t> {
     tree: &'t ExtentTree,
     indices: Vec<usize>,
@@ -306,7 +480,7 @@ impl<'t> ExtentTreeIterator<'t> {
     /// leaves are eventually iterated.
     fn try_find_element(&mut self) -> SearchResult<<Self as Iterator>::Item>
     {
-        if self.indices[0] >= self.tree.root_node.header.eh_entries as usize {
+        if self.indices[0] >= self.tree.root_node.child_count() {
             return SearchResult::End;
         }
 
@@ -317,7 +491,7 @@ impl<'t> ExtentTreeIterator<'t> {
 
         while cur_node.header.eh_depth > 0 {
             if cur_node_i >= self.indices.len() {
-                panic!("extent tree branches are longer than root node's eh_depth");
+                unreachable!("extent tree branches are longer than root node's eh_depth");
             }
 
             let cur_subnodes = cur_node.subnodes.as_ref().unwrap();
@@ -343,7 +517,7 @@ impl<'t> ExtentTreeIterator<'t> {
         let extents = if let Entries::Extents(v) = &cur_node.entries {
             v
         } else {
-            panic!("extent tree: leaf node has indexes instead of extents");
+            unreachable!("extent tree: leaf node has indexes instead of extents");
         };
 
         if self.indices[cur_node_i] >= extents.len() {
@@ -389,4 +563,213 @@ enum SearchResult<T> {
 
 // Tests
 
-// TODO: test ExtentTree.
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::util::test_context;
+    use crate::filesys::e2fs::tests::{test_fs, test_sb};
+    use crate::usage_map::Segment;
+
+    const BLOCK_SIZE: usize = 4096;
+
+    /// Serialises an extent tree node with the supplied header and raw entries.
+    pub fn raw_node(depth: u16, entries: &[[u8; 12]], size: usize) -> Vec<u8>
+    {
+        let bincode_opt = DefaultOptions::new()
+            .with_fixint_encoding()
+            .allow_trailing_bytes();
+
+        let header = ExtentHeader {
+            eh_magic: 0xf30a,
+            eh_entries: entries.len() as u16,
+            eh_max: ((size - EXTENT_HEADER_SIZE) / EXTENT_SIZE) as u16,
+            eh_depth: depth,
+            eh_generation: 0,
+        };
+
+        let mut raw = bincode_opt.serialize(&header).unwrap();
+        for e in entries {
+            raw.extend_from_slice(e);
+        }
+        raw.resize(size, 0);
+
+        raw
+    }
+
+    fn raw_idx(block: u32, leaf: u32) -> [u8; 12]
+    {
+        let mut raw = [0; 12];
+        raw[0..4].copy_from_slice(&block.to_le_bytes());
+        raw[4..8].copy_from_slice(&leaf.to_le_bytes());
+
+        raw
+    }
+
+    pub fn raw_extent(block: u32, len: u16, start: u32) -> [u8; 12]
+    {
+        let mut raw = [0; 12];
+        raw[0..4].copy_from_slice(&block.to_le_bytes());
+        raw[4..6].copy_from_slice(&len.to_le_bytes());
+        raw[8..12].copy_from_slice(&start.to_le_bytes());
+
+        raw
+    }
+
+    /// Creates an inode with the supplied raw root node in i_block.
+    pub fn inode_with_root(root: &[u8]) -> Inode
+    {
+        let bincode_opt = DefaultOptions::new()
+            .with_fixint_encoding()
+            .allow_trailing_bytes();
+
+        let mut inode: Inode = bincode_opt.deserialize(&[0u8; 256]).unwrap();
+        for (i, chunk) in root.chunks(4).enumerate() {
+            inode.i_block[i] = u32::from_le_bytes(chunk.try_into().unwrap());
+        }
+
+        inode
+    }
+
+    #[test]
+    fn consistent_depth()
+    {
+        let fs = test_fs(test_sb());
+        let mut drive = vec![0u8; BLOCK_SIZE * 2];
+        drive[BLOCK_SIZE..].copy_from_slice(&raw_node(0, &[raw_extent(0, 3, 10)], BLOCK_SIZE));
+        let mut ctx = test_context(&drive);
+
+        let inode = inode_with_root(&raw_node(1, &[raw_idx(0, 1)], N_BLOCKS * 4));
+        let tree = ExtentTree::new(&inode, &fs, &mut ctx, &Config::default()).unwrap();
+        let extents: Vec<&Extent> = ExtentTreeIterator::new(&tree).collect();
+
+        assert_eq!(extents.len(), 1);
+        assert_eq!(extents[0].ee_start_lo, 10);
+        assert_eq!(extents[0].ee_len, 3);
+    }
+
+    #[test]
+    fn subnode_too_shallow()
+    {
+        let fs = test_fs(test_sb());
+        let mut drive = vec![0u8; BLOCK_SIZE * 2];
+        drive[BLOCK_SIZE..].copy_from_slice(&raw_node(0, &[raw_extent(0, 3, 10)], BLOCK_SIZE));
+        let mut ctx = test_context(&drive);
+
+        // The root claims depth 2, but its child is a leaf.
+        let inode = inode_with_root(&raw_node(2, &[raw_idx(0, 1)], N_BLOCKS * 4));
+
+        assert!(ExtentTree::new(&inode, &fs, &mut ctx, &Config::default()).is_err());
+
+        let mut map = UsageMap::new(drive.len() as u64);
+        assert!(scan_extent_tree(&mut map, 12, &inode, &fs, &mut ctx, &Config::default()).is_err());
+    }
+
+    #[test]
+    fn subnode_too_deep()
+    {
+        let fs = test_fs(test_sb());
+        let mut drive = vec![0u8; BLOCK_SIZE * 2];
+        // The node points to itself, claiming the same depth as its parent.
+        drive[BLOCK_SIZE..].copy_from_slice(&raw_node(1, &[raw_idx(0, 1)], BLOCK_SIZE));
+        let mut ctx = test_context(&drive);
+
+        let inode = inode_with_root(&raw_node(1, &[raw_idx(0, 1)], N_BLOCKS * 4));
+
+        assert!(ExtentTree::new(&inode, &fs, &mut ctx, &Config::default()).is_err());
+
+        let mut map = UsageMap::new(drive.len() as u64);
+        assert!(scan_extent_tree(&mut map, 12, &inode, &fs, &mut ctx, &Config::default()).is_err());
+    }
+
+    #[test]
+    fn index_out_of_range()
+    {
+        let fs = test_fs(test_sb());
+        let mut drive = vec![0u8; BLOCK_SIZE * 2];
+        drive[BLOCK_SIZE..].copy_from_slice(&raw_node(0, &[raw_extent(0, 3, 10)], BLOCK_SIZE));
+        let mut ctx = test_context(&drive);
+
+        // The second index points past the end of the 64-block file system.
+        let inode = inode_with_root(&raw_node(1, &[raw_idx(0, 1), raw_idx(3, 64)], N_BLOCKS * 4));
+
+        let tree = ExtentTree::new(&inode, &fs, &mut ctx, &Config::default()).unwrap();
+        let extents: Vec<&Extent> = ExtentTreeIterator::new(&tree).collect();
+        assert_eq!(extents.len(), 1);
+
+        let mut map = UsageMap::new(drive.len() as u64);
+        scan_extent_tree(&mut map, 12, &inode, &fs, &mut ctx, &Config::default()).unwrap();
+        // Only the node in block 1 (header, entries and tail) is mapped.
+        assert_eq!(map.len(), 4);
+        assert_eq!(map.segments()[1], Segment { start: 4096, end: 4096 + 24, status: AllocStatus::Used });
+        // The skipped index is recorded once, even though the tree was also read.
+        assert_eq!(ctx.anomalies.count(AnomalyKind::ExtentIndexOutOfRange), 1);
+        assert_eq!(ctx.anomalies.summary(), "1 out-of-range extent index skipped");
+
+        let strict = Config { strict: true, ..Config::default() };
+        assert!(ExtentTree::new(&inode, &fs, &mut ctx, &strict).is_err());
+        let mut map = UsageMap::new(drive.len() as u64);
+        assert!(scan_extent_tree(&mut map, 12, &inode, &fs, &mut ctx, &strict).is_err());
+    }
+
+    #[test]
+    fn too_many_nodes()
+    {
+        let fs = test_fs(test_sb());
+        let mut drive = vec![0u8; BLOCK_SIZE * 5];
+        for i in 1..5 {
+            drive[BLOCK_SIZE * i..BLOCK_SIZE * (i + 1)]
+                .copy_from_slice(&raw_node(0, &[raw_extent(i as u32, 1, 10 + i as u32)], BLOCK_SIZE));
+        }
+        let mut ctx = test_context(&drive);
+
+        let idxs: Vec<[u8; 12]> = (1..5).map(|i| raw_idx(i, i)).collect();
+        let inode = inode_with_root(&raw_node(1, &idxs, N_BLOCKS * 4));
+
+        let exact = Config { max_extent_nodes: 4, ..Config::default() };
+        assert!(ExtentTree::new(&inode, &fs, &mut ctx, &exact).is_ok());
+        let mut map = UsageMap::new(drive.len() as u64);
+        assert!(scan_extent_tree(&mut map, 12, &inode, &fs, &mut ctx, &exact).is_ok());
+
+        let capped = Config { max_extent_nodes: 3, ..Config::default() };
+        let err = ExtentTree::new(&inode, &fs, &mut ctx, &capped).unwrap_err();
+        assert!(err.to_string().contains("more than 3 nodes"));
+        let mut map = UsageMap::new(drive.len() as u64);
+        assert!(scan_extent_tree(&mut map, 12, &inode, &fs, &mut ctx, &capped).is_err());
+    }
+
+    #[test]
+    fn node_checksum()
+    {
+        use crate::filesys::e2fs::{ext4_style_crc32c_le, SuperBlock};
+
+        let fs = test_fs(SuperBlock { s_feature_ro_compat: 0x400, ..test_sb() });
+        let mut drive = vec![0u8; BLOCK_SIZE * 2];
+        drive[BLOCK_SIZE..].copy_from_slice(&raw_node(0, &[raw_extent(0, 3, 10)], BLOCK_SIZE));
+        let mut inode = inode_with_root(&raw_node(1, &[raw_idx(0, 1)], N_BLOCKS * 4));
+        inode.i_generation = 7;
+
+        let mut ctx = test_context(&drive);
+        let mut map = UsageMap::new(drive.len() as u64);
+        assert!(scan_extent_tree(&mut map, 12, &inode, &fs, &mut ctx, &Config::default()).is_err());
+
+        // The node is still mapped when the error is ignored. The whole block is used, as the
+        // checksum covers the unused entries too.
+        let cfg = Config { ignore_csum_errors: true, ..Config::default() };
+        let mut map = UsageMap::new(drive.len() as u64);
+        scan_extent_tree(&mut map, 12, &inode, &fs, &mut ctx, &cfg).unwrap();
+        assert_eq!(map.segments()[1], Segment { start: 4096, end: 8192, status: AllocStatus::Used });
+        assert_eq!(ctx.anomalies.count(AnomalyKind::BadExtentChecksum), 1);
+
+        // The tail follows the 340 entries of the node.
+        let seed = ext4_style_crc32c_le(fs.csum_seed.unwrap(), &12u32.to_le_bytes());
+        let seed = ext4_style_crc32c_le(seed, &7u32.to_le_bytes());
+        let csum = ext4_style_crc32c_le(seed, &drive[BLOCK_SIZE..2 * BLOCK_SIZE - 4]);
+        drive[2 * BLOCK_SIZE - 4..].copy_from_slice(&csum.to_le_bytes());
+
+        let mut ctx = test_context(&drive);
+        let mut map = UsageMap::new(drive.len() as u64);
+        scan_extent_tree(&mut map, 12, &inode, &fs, &mut ctx, &Config::default()).unwrap();
+        // The checksum depends on the owner.
+        assert!(scan_extent_tree(&mut map, 13, &inode, &fs, &mut ctx, &Config::default()).is_err());
+    }
+}
@@ -0,0 +1,147 @@
+use anyhow::bail;
+
+use crate::hilo;
+use crate::bs;
+use super::{Fs, fetch_regular_bg_descriptor, has_flex_bg};
+
+/// Metadata of a group that lies outside of the groups expected to hold it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Misplaced {
+    pub bg_num: u64,
+    /// The kind of the metadata.
+    pub what: &'static str,
+    pub block: u64,
+}
+
+/// Checks that the bitmaps and the inode table of every group lie within the groups expected to
+/// hold them: the group's flex group with flex_bg, the group itself otherwise.
+/// The metadata is mapped from the descriptors wherever it lies; misplaced metadata is only
+/// suspicious (e.g. mke2fs spills it over to the next flex group when a flex group is full).
+/// Metadata outside of the file system is an error.
+pub fn check_placement(fs: &Fs) -> anyhow::Result<Vec<Misplaced>>
+{
+    let block_size = bs!(fs.sb.s_log_block_size);
+    let itable_blocks = (fs.sb.s_inodes_per_group as u64 * fs.inode_size).div_ceil(block_size);
+    let groups_per_flex = groups_per_flex(fs)?;
+
+    let mut misplaced = Vec::new();
+
+    for bg_num in 0..fs.bg_count {
+        let desc = fetch_regular_bg_descriptor(bg_num, fs)?;
+
+        let (block_bitmap, inode_bitmap, inode_table) = if fs.opts.bit64_cfg.is_some() {
+            (
+                hilo!(desc.bg_block_bitmap_hi, desc.bg_block_bitmap_lo),
+                hilo!(desc.bg_inode_bitmap_hi, desc.bg_inode_bitmap_lo),
+                hilo!(desc.bg_inode_table_hi, desc.bg_inode_table_lo),
+            )
+        } else {
+            (
+                desc.bg_block_bitmap_lo as u64,
+                desc.bg_inode_bitmap_lo as u64,
+                desc.bg_inode_table_lo as u64,
+            )
+        };
+
+        // The blocks of the groups expected to hold the metadata.
+        let first_bg = bg_num / groups_per_flex * groups_per_flex;
+        let last_bg = std::cmp::min(first_bg + groups_per_flex, fs.bg_count);
+        let start = first_block_of_bg(first_bg, fs);
+        let end = std::cmp::min(first_block_of_bg(last_bg, fs), fs.blocks_count);
+
+        let areas = [
+            ("block bitmap", block_bitmap, 1),
+            ("inode bitmap", inode_bitmap, 1),
+            ("inode table", inode_table, itable_blocks),
+        ];
+
+        for (what, block, len) in areas {
+            if block + len > fs.blocks_count {
+                bail!("the {} of group {} lies outside of the file system: {}", what, bg_num, block);
+            }
+            if block < start || block + len > end {
+                misplaced.push(Misplaced { bg_num, what, block });
+            }
+        }
+    }
+
+    Ok(misplaced)
+}
+
+/// Returns the number of groups in a flex group, 1 without flex_bg.
+fn groups_per_flex(fs: &Fs) -> anyhow::Result<u64>
+{
+    if !has_flex_bg(fs) {
+        return Ok(1);
+    }
+
+    if fs.sb.s_log_groups_per_flex > 31 {
+        bail!("invalid flex group size: 2^{} groups", fs.sb.s_log_groups_per_flex);
+    }
+
+    Ok(1 << fs.sb.s_log_groups_per_flex)
+}
+
+/// Returns the number of the first block of a group.
+fn first_block_of_bg(bg_num: u64, fs: &Fs) -> u64
+{
+    fs.sb.s_first_data_block as u64 + bg_num * fs.sb.s_blocks_per_group as u64
+}
+
+// Tests.
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filesys::e2fs::{GroupDescriptor, SuperBlock};
+    use crate::filesys::e2fs::tests::{test_fs_with_descs, test_sb};
+
+    /// Creates file system parameters with 4 groups and the supplied (bitmaps, inode table) blocks
+    /// of each group.
+    fn fs_with_metadata(sb: SuperBlock, metadata: &[(u32, u32)]) -> Fs
+    {
+        let descs: Vec<_> = metadata.iter()
+            .map(|&(bitmaps, inode_table)| GroupDescriptor {
+                bg_block_bitmap_lo: bitmaps,
+                bg_inode_bitmap_lo: bitmaps + 1,
+                bg_inode_table_lo: inode_table,
+                ..GroupDescriptor::default()
+            })
+            .collect();
+
+        test_fs_with_descs(SuperBlock { s_blocks_count_lo: 128, s_inodes_count: 64, ..sb }, &descs)
+    }
+
+    #[test]
+    fn own_groups()
+    {
+        let fs = fs_with_metadata(test_sb(), &[(2, 4), (34, 36), (66, 68), (98, 100)]);
+        assert!(check_placement(&fs).unwrap().is_empty());
+
+        // Packed into the first group, as with flex_bg.
+        let fs = fs_with_metadata(test_sb(), &[(2, 10), (4, 11), (6, 12), (8, 13)]);
+        assert_eq!(check_placement(&fs).unwrap().len(), 9);
+    }
+
+    #[test]
+    fn flex_groups()
+    {
+        // flex_bg, 2 groups per flex group.
+        let sb = SuperBlock { s_feature_incompat: 0x200, s_log_groups_per_flex: 1, ..test_sb() };
+
+        let fs = fs_with_metadata(sb, &[(2, 10), (4, 11), (66, 70), (68, 71)]);
+        assert!(check_placement(&fs).unwrap().is_empty());
+
+        // The metadata of the 4th group spilled over to the first flex group.
+        let fs = fs_with_metadata(sb, &[(2, 10), (4, 11), (66, 70), (6, 12)]);
+        assert_eq!(check_placement(&fs).unwrap(), vec![
+            Misplaced { bg_num: 3, what: "block bitmap", block: 6 },
+            Misplaced { bg_num: 3, what: "inode bitmap", block: 7 },
+            Misplaced { bg_num: 3, what: "inode table", block: 12 },
+        ]);
+
+        // An inode table reaching beyond the file system.
+        let fs = fs_with_metadata(sb, &[(2, 10), (4, 11), (66, 70), (68, 128)]);
+        assert!(check_placement(&fs).is_err());
+    }
+}
@@ -0,0 +1,145 @@
+use serde::Serialize;
+
+use crate::usage_map::UsageMap;
+use super::{Fs, crosscheck, fetch_regular_bg_descriptor, start_of_bg, verify_desc_csum};
+use crate::{bs, hilo};
+
+/// The free space found by the scan, compared against the free counts of the file system, in
+/// bytes.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct FreeCounts {
+    /// The free space in the scanned map.
+    pub scanned: u64,
+    /// The free space recorded in the superblock.
+    pub recorded: u64,
+    /// The groups whose recorded free space differs from the scan.
+    pub groups: Vec<GroupFreeCount>,
+}
+
+impl FreeCounts {
+    /// Checks whether the superblock and all the groups agree with the scan.
+    pub fn is_consistent(&self) -> bool
+    {
+        self.scanned == self.recorded && self.groups.is_empty()
+    }
+}
+
+/// The free space of a group that differs from the scan, in bytes.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct GroupFreeCount {
+    pub bg_num: u64,
+    pub scanned: u64,
+    pub recorded: u64,
+}
+
+/// Counts the free clusters of the scanned map, group by group, and compares them against the free
+/// cluster counts of the group descriptors and the free block count of the superblock.
+/// The bitmaps and the inode tables count as used whole, as they are allocated up front, even if
+/// the scan maps them only in part. Groups with an invalid descriptor are not compared, as their
+/// counts cannot be trusted, but their free space still counts towards the total.
+pub fn check_free_counts(map: &UsageMap, fs: &Fs) -> anyhow::Result<FreeCounts>
+{
+    let block_size = bs!(fs.sb.s_log_block_size);
+    let cluster_size = bs!(fs.sb.s_log_cluster_size);
+    let fs_end = fs.blocks_count * block_size;
+    let has_csum = match fs.opts.dyn_cfg {
+        Some(dyn_cfg) => dyn_cfg.ro_compat.has_metadata_csum() || dyn_cfg.ro_compat.has_gdt_csum(),
+        None => false,
+    };
+
+    let covered = crosscheck::with_group_metadata(map, fs)?;
+
+    let mut counts = FreeCounts {
+        recorded: if fs.opts.bit64_cfg.is_some() {
+            hilo!(fs.sb.s_free_blocks_count_hi, fs.sb.s_free_blocks_count_lo)
+        } else {
+            fs.sb.s_free_blocks_count_lo as u64
+        } * block_size,
+        ..FreeCounts::default()
+    };
+
+    for bg_num in 0..fs.bg_count {
+        let bg_start = start_of_bg(bg_num, fs);
+        let bg_end = std::cmp::min(bg_start + fs.bg_size, fs_end);
+        let scanned = crosscheck::used_clusters(&covered, bg_start, bg_end, cluster_size)
+            .iter()
+            .filter(|used| !**used)
+            .count() as u64 * cluster_size;
+        counts.scanned += scanned;
+
+        let desc = fetch_regular_bg_descriptor(bg_num, fs)?;
+        if has_csum && !verify_desc_csum(&desc, bg_num, fs)? {
+            continue;
+        }
+
+        let recorded = if fs.opts.bit64_cfg.is_some() {
+            hilo!(desc.bg_free_blocks_count_hi, desc.bg_free_blocks_count_lo)
+        } else {
+            desc.bg_free_blocks_count_lo as u64
+        } * cluster_size;
+
+        if scanned != recorded {
+            counts.groups.push(GroupFreeCount { bg_num, scanned, recorded });
+        }
+    }
+
+    Ok(counts)
+}
+
+// Tests.
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::{GroupDescriptor, SuperBlock};
+    use crate::usage_map::AllocStatus;
+    use crate::filesys::e2fs::tests::{test_fs_with_descs, test_sb};
+
+    #[test]
+    fn free_counts()
+    {
+        // The metadata of both groups lies in the first one: blocks 2 to 7, with the inode tables
+        // of one block each.
+        let descs = [
+            GroupDescriptor {
+                bg_block_bitmap_lo: 2,
+                bg_inode_bitmap_lo: 3,
+                bg_inode_table_lo: 4,
+                bg_free_blocks_count_lo: 20,
+                ..GroupDescriptor::default()
+            },
+            GroupDescriptor {
+                bg_block_bitmap_lo: 5,
+                bg_inode_bitmap_lo: 6,
+                bg_inode_table_lo: 7,
+                bg_free_blocks_count_lo: 30,
+                ..GroupDescriptor::default()
+            },
+        ];
+        let fs = test_fs_with_descs(SuperBlock { s_free_blocks_count_lo: 50, ..test_sb() }, &descs);
+
+        // Blocks 0 and 1 and a part of blocks 10 and 40 are used; the inode tables are not.
+        let mut map = UsageMap::new(64 * 4096);
+        map.update(0, 2 * 4096, AllocStatus::Used);
+        map.update(10 * 4096 + 100, 1, AllocStatus::Used);
+        map.update(40 * 4096, 1, AllocStatus::Used);
+
+        // The first group has 32 - 9 free blocks, the second 32 - 1.
+        let counts = check_free_counts(&map, &fs).unwrap();
+        assert_eq!(counts, FreeCounts {
+            scanned: 54 * 4096,
+            recorded: 50 * 4096,
+            groups: vec![
+                GroupFreeCount { bg_num: 0, scanned: 23 * 4096, recorded: 20 * 4096 },
+                GroupFreeCount { bg_num: 1, scanned: 31 * 4096, recorded: 30 * 4096 },
+            ],
+        });
+        assert!(!counts.is_consistent());
+
+        map.update(11 * 4096, 3 * 4096, AllocStatus::Used);
+        map.update(41 * 4096, 4096, AllocStatus::Used);
+        let counts = check_free_counts(&map, &fs).unwrap();
+        assert_eq!(counts.groups, vec![]);
+        assert!(counts.is_consistent());
+    }
+}
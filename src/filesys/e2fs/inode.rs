@@ -3,7 +3,8 @@ use anyhow::bail;
 use serde::{Deserialize, Serialize};
 use bincode::{DefaultOptions, Options};
 
-use crate::Context;
+use crate::{Config, Context};
+use crate::anomaly::AnomalyKind;
 use crate::usage_map::{UsageMap, AllocStatus};
 use crate::hilo;
 
@@ -14,6 +15,10 @@ use crate::{
 use super::{
     Fs,
     FsCreator,
+    csum,
+    dir,
+    orphan,
+    xattr,
     fetch_regular_bg_descriptor,
     extent::{
         self,
@@ -62,6 +67,11 @@ pub struct Inode {
 
 pub const INODE_STRUCT_SIZE: usize = 160;
 
+/// The inode that reserves the blocks for the growth of the group descriptor tables.
+pub const RESIZE_INO: u64 = 7;
+/// The inode of the journal.
+const JOURNAL_INO: u64 = 8;
+
 // Reference: https://elixir.bootlin.com/linux/latest/source/fs/ext4/ext4.h#L811
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
 pub struct Osd2Linux {
@@ -93,7 +103,7 @@ pub struct Osd2Masix {
 
 /// Inode flags (i_flags)
 /// Reference: https://elixir.bootlin.com/linux/latest/source/fs/ext4/ext4.h
-struct IFlags(u32);
+pub struct IFlags(pub u32);
 
 impl IFlags {
     pub fn has_secrm(&self)            -> bool { self.0 & 0x1 != 0 }
@@ -126,12 +136,12 @@ impl IFlags {
     pub fn has_snapfile_shrunk(&self)  -> bool { self.0 & 0x8000000 != 0 }
     pub fn has_inline_data(&self)      -> bool { self.0 & 0x10000000 != 0 }
     pub fn has_projinherit(&self)      -> bool { self.0 & 0x20000000 != 0 }
-    // 0x40000000 missing.
+    pub fn has_casefold(&self)         -> bool { self.0 & 0x40000000 != 0 }
     pub fn has_reserved(&self)         -> bool { self.0 & 0x80000000 != 0 }
 
     pub fn get_unknown(&self) -> u32
     {
-        (self.0 & 0x800000) | (self.0 & 0x2000000) | (self.0 & 0x40000000)
+        (self.0 & 0x800000) | (self.0 & 0x2000000)
     }
 
     pub fn has_unknown(&self) -> bool
@@ -194,6 +204,7 @@ enum InodeType {
     Ea,
     Journal,
     ResizeInode,
+    OrphanFile,
 }
 
 /// Fetches an inode, based on the number of the inode.
@@ -247,6 +258,7 @@ pub fn scan_inode(
     itable: &mut [u8],
     fs: &Fs,
     ctx: &mut Context,
+    cfg: &Config,
 ) -> anyhow::Result<()>
 {
     let bincode_opt = DefaultOptions::new()
@@ -254,21 +266,32 @@ pub fn scan_inode(
         .allow_trailing_bytes();
 
     let inode: Inode = bincode_opt.deserialize(&itable[idx * fs.inode_size as usize..])?;
+    let raw_inode = &itable[idx * fs.inode_size as usize..(idx + 1) * fs.inode_size as usize];
+    let ino = inode_number(idx, bg_num, fs);
+
+    // The block pointers of an inode with an invalid checksum are not trusted.
+    if csum::has_metadata_csum(fs) && !csum::verify_inode(raw_inode, ino, fs)? {
+        let what = format!("inode {}", ino);
+        csum::csum_error(&what, AnomalyKind::BadInodeChecksum, ctx, cfg)?;
+        return Ok(());
+    }
 
-    // NOTE: This is not tested. Linux is the only supported platform.
+    // The layout of osd2 depends on the creator. FreeBSD and Lites use the Linux one.
     let osd2 = match fs.opts.fs_creator {
         FsCreator::Hurd => Osd2::Hurd(bincode_opt.deserialize(&inode.osd2)?),
         FsCreator::Masix => Osd2::Masix(bincode_opt.deserialize(&inode.osd2)?),
         _ => Osd2::Linux(bincode_opt.deserialize(&inode.osd2)?),
     };
-    let i_flags = IFlags { 0: inode.i_flags };
+    let i_flags = IFlags(inode.i_flags);
 
     // Check inode flags.
 
     if i_flags.has_unknown() {
         bail!("inode {} has unknown flags: {:#10x}", idx, i_flags.get_unknown());
-    } else if i_flags.has_encrypt() {
-        bail!("inode {} has is encrypted", idx);
+    } else if i_flags.has_encrypt() && !has_encrypt(fs) {
+        bail!("inode {} is encrypted, but encrypt is not set", idx);
+    } else if i_flags.has_casefold() && !has_casefold(fs) {
+        bail!("inode {} is casefolded, but casefold is not set", idx);
     } else if i_flags.has_imagic() {
         bail!("inode {} has an unsupported feature: imagic", idx);
     } else if i_flags.has_snapfile() {
@@ -283,15 +306,16 @@ pub fn scan_inode(
         bail!("inode {} has an unsupported feature: comprblk", idx);
     }
 
-    let i_mode = IMode { 0: inode.i_mode };
+    let i_mode = IMode(inode.i_mode);
 
     // Determine the inode type.
 
-    let inode_type = if bg_num == 0 && idx + 1 == 7 {
+    let inode_type = if ino == RESIZE_INO {
         InodeType::ResizeInode
-    } else if bg_num == 0 && idx + 1 == 8 {
+    } else if ino == JOURNAL_INO {
         InodeType::Journal
-    // NOTE: feature support is not checked.
+    } else if is_orphan_file(ino, fs) {
+        InodeType::OrphanFile
     } else if i_flags.has_ea_inode() {
         InodeType::Ea
     } else if i_mode.has_ifsock() {
@@ -309,26 +333,19 @@ pub fn scan_inode(
     } else if i_mode.has_ififo() {
         InodeType::Fifo
     // Reserved inodes that are zeroed out.
-    } else if bg_num == 0 && inode.i_mode == 0 && idx + 1 < fs.sb.s_first_ino as usize {
+    } else if inode.i_mode == 0 && is_reserved(ino, fs) {
         return Ok(())
     } else {
         bail!("inode {} has invalid mode: {:x}", idx, inode.i_mode & 0xf000);
     };
 
-    // Scan the iblock.
-    match inode_type {
-        InodeType::ResizeInode => scan_resize_inode_iblock(map, &inode, &osd2, fs, ctx)?,
-        InodeType::Journal => scan_journal_iblock(map, &inode, &osd2, fs, ctx)?,
-        InodeType::Ea => scan_ea_iblock(map, &inode, &osd2, fs, ctx)?,
-        InodeType::Regular => scan_regular_iblock(map, &inode, &osd2, fs, ctx)?,
-        InodeType::Directory => scan_dir_iblock(map, &inode, &osd2, fs, ctx)?,
-        InodeType::SymLink => scan_symlink_iblock(map, &inode, &osd2, fs, ctx)?,
-        // Undocumented special files are handled as regular files, just in case they use external
-        // blocks.
-        InodeType::Fifo |
-        InodeType::Block |
-        InodeType::Character |
-        InodeType::Socket => scan_regular_iblock(map, &inode, &osd2, fs, ctx)?,
+    // Scan the iblock, unless the data is stored in the inode itself.
+    if i_flags.has_inline_data() {
+        check_inline_data(idx, raw_inode, &inode, fs)?;
+    } else if is_fast_symlink(&inode, &inode_type) {
+        // The target is stored in i_block.
+    } else {
+        scan_iblock(map, ino, &inode_type, &inode, &osd2, fs, ctx, cfg)?;
     }
 
     if i_flags.has_verity() {
@@ -339,6 +356,24 @@ pub fn scan_inode(
         bail!("field i_obso_faddr in inode {} is not zero", idx);
     }
 
+    // The Hurd keeps the passive translator (the program serving the node) in a block of its own,
+    // referenced by osd1. The high bits of the mode, the uid and the gid refer to no blocks.
+    if matches!(osd2, Osd2::Hurd(_)) {
+        let translator_block = inode.osd1 as u64;
+
+        if translator_block >= fs.blocks_count {
+            bail!(
+                "the translator of inode {} lies beyond the file system: {}",
+                idx,
+                translator_block,
+            );
+        }
+        if translator_block != 0 {
+            let block_size = bs!(fs.sb.s_log_block_size);
+            map.update(translator_block * block_size, block_size, AllocStatus::Used);
+        }
+    }
+
     // Scan the block of extended attributes.
 
     let xattr_block = if let Osd2::Linux(l) = osd2 {
@@ -350,34 +385,127 @@ pub fn scan_inode(
     };
 
     if xattr_block != 0 {
-        scan_xattr_block(map, xattr_block, fs)?;
+        xattr::scan_xattr_block(map, xattr_block, fs, ctx)?;
+    }
+
+    // Check the extended attributes in the inode, which may refer to EA inodes as well.
+    xattr::check_ibody(raw_inode, inode.i_extra_isize, fs)?;
+
+    Ok(())
+}
+
+/// Scans the iblock of an inode according to its type.
+#[allow(clippy::too_many_arguments)]
+fn scan_iblock(
+    map: &mut UsageMap,
+    ino: u64,
+    inode_type: &InodeType,
+    inode: &Inode,
+    osd2: &Osd2,
+    fs: &Fs,
+    ctx: &mut Context,
+    cfg: &Config,
+) -> anyhow::Result<()>
+{
+    match inode_type {
+        InodeType::ResizeInode => scan_resize_inode_iblock(map, ino, inode, osd2, fs, ctx, cfg),
+        InodeType::Journal => scan_journal_iblock(map, ino, inode, osd2, fs, ctx, cfg),
+        InodeType::OrphanFile => scan_orphan_file_iblock(map, ino, inode, osd2, fs, ctx, cfg),
+        InodeType::Ea => scan_ea_iblock(map, ino, inode, osd2, fs, ctx, cfg),
+        InodeType::Regular => scan_regular_iblock(map, ino, inode, osd2, fs, ctx, cfg),
+        InodeType::Directory => scan_dir_iblock(map, ino, inode, osd2, fs, ctx, cfg),
+        InodeType::SymLink => scan_symlink_iblock(map, ino, inode, osd2, fs, ctx, cfg),
+        // Undocumented special files are handled as regular files, just in case they use external
+        // blocks.
+        InodeType::Fifo |
+        InodeType::Block |
+        InodeType::Character |
+        InodeType::Socket => scan_regular_iblock(map, ino, inode, osd2, fs, ctx, cfg),
+    }
+}
+
+/// The system.data attribute, which holds the inline data beyond i_block.
+const INLINE_DATA_XATTR_INDEX: u8 = 7;
+const INLINE_DATA_XATTR_NAME: &[u8] = b"data";
+/// The size of i_block, which holds the start of the inline data or the target of a fast symlink.
+const I_BLOCK_SIZE: u64 = N_BLOCKS as u64 * 4;
+
+/// Checks an inode with inline data: the data is stored in i_block and in the system.data
+/// attribute in the inode, so no blocks are used.
+fn check_inline_data(idx: usize, raw_inode: &[u8], inode: &Inode, fs: &Fs) -> anyhow::Result<()>
+{
+    if !fs.opts.dyn_cfg.is_some_and(|dyn_cfg| dyn_cfg.incompat.has_inline_data()) {
+        bail!("inode {} has inline data, but inline_data is not set", idx);
+    }
+
+    let xattr_size = xattr::ibody_value_size(
+        raw_inode,
+        inode.i_extra_isize,
+        INLINE_DATA_XATTR_INDEX,
+        INLINE_DATA_XATTR_NAME,
+    )?;
+    let xattr_size = match xattr_size {
+        Some(size) => size as u64,
+        None => bail!("inode {} has inline data, but no system.data attribute", idx),
+    };
+
+    let size = hilo!(inode.i_size_high, inode.i_size_lo);
+    if size > I_BLOCK_SIZE + xattr_size {
+        bail!(
+            "the inline data of inode {} ({} bytes) does not fit into the inode ({} bytes)",
+            idx,
+            size,
+            I_BLOCK_SIZE + xattr_size,
+        );
     }
 
     Ok(())
 }
 
+/// Checks whether an inode is a fast symlink, whose target is stored in i_block.
+/// Like in e2fsprogs, this depends on the size only: the block count also includes the xattr
+/// block.
+fn is_fast_symlink(inode: &Inode, inode_type: &InodeType) -> bool
+{
+    let size = hilo!(inode.i_size_high, inode.i_size_lo);
+
+    matches!(inode_type, InodeType::SymLink) && size < I_BLOCK_SIZE
+}
+
+/// Computes the (1-based) inode number from the index within the block group.
+pub fn inode_number(idx: usize, bg_num: u64, fs: &Fs) -> u64
+{
+    bg_num * fs.sb.s_inodes_per_group as u64 + idx as u64 + 1
+}
+
+/// Checks whether an inode number belongs to the reserved inodes.
+/// The reserved inodes are numbered from 1 to `s_first_ino - 1`; they can span beyond the first
+/// block group.
+fn is_reserved(ino: u64, fs: &Fs) -> bool
+{
+    ino < fs.sb.s_first_ino as u64
+}
+
 /// General-purpose procedure for scanning inode's i_block.
 /// Used for regular files, symlinks, and other file types that do not require special handling.
 fn scan_regular_iblock(
     map: &mut UsageMap,
+    ino: u64,
     inode: &Inode,
     osd2: &Osd2,
     fs: &Fs,
-    ctx: &mut Context
+    ctx: &mut Context,
+    cfg: &Config,
 ) -> anyhow::Result<()>
 {
-    let i_flags = IFlags { 0: inode.i_flags };
+    let i_flags = IFlags(inode.i_flags);
 
     // NOTE: Feature support is not being checked.
-    // Inodes' i_flags fields are trusted.
+    // Inodes' i_flags fields are trusted. Inodes with inline data and fast symlinks do not get
+    // here.
 
-    if i_flags.has_inline_data() {
-        return Ok(());
-    }
-
-    let blocks = get_block_count(inode, osd2, fs);
+    let blocks = get_block_count(inode, osd2, fs)?;
 
-    // Symlinks do not have inline_data flags set when inlined.
     // NOTE: don't have to check the file size here, as the only way for the block count to grow,
     // is verity. Fragments might be an issue, but those are obsolete and not secured by feature
     // flags It is assumed therefore, that they are a non-issue.
@@ -386,24 +514,35 @@ fn scan_regular_iblock(
     }
 
     let file_size = hilo!(inode.i_size_high, inode.i_size_lo);
+    // The slack past the end of the file (the rest of the last block and the blocks allocated
+    // beyond it) is kept, unless it is to be wiped. The ciphertext of encrypted files spans whole
+    // blocks, so their slack is always kept.
+    let data_end = if cfg.wipe_slack && !i_flags.has_encrypt() {
+        file_size
+    } else if i_flags.has_extents() {
+        u64::MAX
+    } else {
+        // Block maps cannot preallocate blocks beyond the end of the file.
+        file_size.next_multiple_of(bs!(fs.sb.s_log_block_size))
+    };
 
     if i_flags.has_extents() {
-        extent::scan_extent_tree(map, inode, fs, ctx)?;
+        extent::scan_extent_tree(map, ino, inode, fs, ctx, cfg)?;
 
-        let extent_tree = ExtentTree::new(inode, fs, ctx)?;
+        let extent_tree = ExtentTree::new(inode, fs, ctx, cfg)?;
         let extent_iterator = ExtentTreeIterator::new(&extent_tree);
 
         for e in extent_iterator {
             // Position within the file.
             let log_start = e.ee_block as u64 * bs!(fs.sb.s_log_block_size);
 
-            if log_start >= file_size {
+            if log_start >= data_end {
                 continue;
             }
 
-            let mut len = e.ee_len as u64 * bs!(fs.sb.s_log_block_size);
-            if log_start + len > file_size {
-                len = file_size - log_start;
+            let mut len = e.block_count() * bs!(fs.sb.s_log_block_size);
+            if log_start + len > data_end {
+                len = data_end - log_start;
             }
 
             // Position on the disk.
@@ -423,13 +562,13 @@ fn scan_regular_iblock(
             // Position within the file.
             let log_start = block_head * bs!(fs.sb.s_log_block_size);
 
-            if log_start >= file_size {
+            if log_start >= data_end {
                 break;
             }
 
             let mut len = bs!(fs.sb.s_log_block_size);
-            if log_start + len > file_size {
-                len = file_size - log_start;
+            if log_start + len > data_end {
+                len = data_end - log_start;
             }
 
             // Position on the disk.
@@ -444,9 +583,11 @@ fn scan_regular_iblock(
             block_head += 1;
         }
 
-        scan_indirect_block(map, &mut block_head, inode.i_block[12] as u64, inode, osd2, fs, ctx)?;
-        scan_double_indirect_block(map, &mut block_head, inode.i_block[13] as u64, inode, osd2, fs, ctx)?;
-        scan_triple_indirect_block(map, &mut block_head, inode.i_block[14] as u64, inode, osd2, fs, ctx)?;
+        let head = &mut block_head;
+        let (ind, dind, tind) = (inode.i_block[12], inode.i_block[13], inode.i_block[14]);
+        scan_indirect_block(map, head, data_end, ind as u64, inode, osd2, fs, ctx)?;
+        scan_double_indirect_block(map, head, data_end, dind as u64, inode, osd2, fs, ctx)?;
+        scan_triple_indirect_block(map, head, data_end, tind as u64, inode, osd2, fs, ctx)?;
     }
 
     Ok(())
@@ -455,76 +596,185 @@ fn scan_regular_iblock(
 /// Scans a directory iblock.
 fn scan_dir_iblock(
     map: &mut UsageMap,
+    ino: u64,
     inode: &Inode,
     osd2: &Osd2,
     fs: &Fs,
-    ctx: &mut Context
+    ctx: &mut Context,
+    cfg: &Config,
 ) -> anyhow::Result<()>
 {
-    // Directory i_blocks appear to behave in the exact same way as regular files.
-    // NOTE: every directory block seems to be initialised.
-    // Therefore, in-depth directory processing does not appear to be necessary. It is, however,
-    // implementation dependent.
-    scan_regular_iblock(map, inode, osd2, fs, ctx)
+    // NOTE: every directory block seems to be initialised, so the blocks are not inspected beyond
+    // the htree index.
+    if get_block_count(inode, osd2, fs)? == 0 {
+        return Ok(());
+    }
+
+    dir::scan_dir_blocks(map, ino, inode, fs, ctx, cfg)
 }
 
 /// Scans a symlink iblock.
 fn scan_symlink_iblock(
     map: &mut UsageMap,
+    ino: u64,
     inode: &Inode,
     osd2: &Osd2,
     fs: &Fs,
-    ctx: &mut Context
+    ctx: &mut Context,
+    cfg: &Config,
 ) -> anyhow::Result<()>
 {
     // Symlinks behave the exact same way as regular files.
-    scan_regular_iblock(map, inode, osd2, fs, ctx)
+    scan_regular_iblock(map, ino, inode, osd2, fs, ctx, cfg)
 }
 
 /// Scans a resize_inode iblock.
+/// Only the double indirect block is used: it lists the reserved GDT blocks of the primary copy,
+/// each of which lists its copies in the backup groups. The chain is marked as used.
 fn scan_resize_inode_iblock(
-    _map: &mut UsageMap,
-    _inode: &Inode,
+    map: &mut UsageMap,
+    _ino: u64,
+    inode: &Inode,
     _osd2: &Osd2,
-    _fs: &Fs,
-    _ctx: &mut Context
+    fs: &Fs,
+    ctx: &mut Context,
+    _cfg: &Config,
 ) -> anyhow::Result<()>
 {
-    // NOTE: the resize inode's block map seems to always point to uninitialised blocks.
-    return Ok(())
+    let block_size = bs!(fs.sb.s_log_block_size);
+    let dind_block = inode.i_block[13] as u64;
+
+    if dind_block == 0 {
+        return Ok(());
+    }
+
+    for reserved in read_resize_list(map, dind_block, fs, ctx)? {
+        for backup in read_resize_list(map, reserved, fs, ctx)? {
+            map.update(backup * block_size, block_size, AllocStatus::Used);
+        }
+    }
+
+    Ok(())
+}
+
+/// Marks a block of the resize inode's chain as used and returns the blocks that it lists.
+fn read_resize_list(map: &mut UsageMap, block: u64, fs: &Fs, ctx: &mut Context)
+    -> anyhow::Result<Vec<u64>>
+{
+    let block_size = bs!(fs.sb.s_log_block_size);
+
+    if block >= fs.blocks_count {
+        bail!("the resize inode refers to a block beyond the file system: {}", block);
+    }
+    map.update(block * block_size, block_size, AllocStatus::Used);
+
+    let mut buf = vec![0u8; block_size as usize];
+    ctx.drive.seek(SeekFrom::Start(block * block_size))?;
+    ctx.drive.read_exact(&mut buf)?;
+
+    let mut blocks = Vec::new();
+    for raw in buf.chunks_exact(4) {
+        let entry = u32::from_le_bytes(raw.try_into().unwrap()) as u64;
+        if entry == 0 {
+            continue;
+        }
+        if entry >= fs.blocks_count {
+            bail!("the resize inode refers to a block beyond the file system: {}", entry);
+        }
+        blocks.push(entry);
+    }
+
+    Ok(blocks)
 }
 
 /// Scans a journal iblock.
 fn scan_journal_iblock(
     map: &mut UsageMap,
+    ino: u64,
     inode: &Inode,
     osd2: &Osd2,
     fs: &Fs,
-    ctx: &mut Context
+    ctx: &mut Context,
+    cfg: &Config,
 ) -> anyhow::Result<()>
 {
     // TODO: deeper inspection of the journal.
-    scan_regular_iblock(map, inode, osd2, fs, ctx)
+    scan_regular_iblock(map, ino, inode, osd2, fs, ctx, cfg)
+}
+
+/// Checks whether the file system has casefolded directories.
+fn has_casefold(fs: &Fs) -> bool
+{
+    fs.opts.dyn_cfg.is_some_and(|dyn_cfg| dyn_cfg.incompat.has_casefold())
+}
+
+/// Checks whether the file system has encryption. Such file systems are only accepted with
+/// `--allow-encrypted`; the encrypted inodes are then scanned like the others, as neither the
+/// extent trees nor the block maps (nor the htree index) are encrypted.
+fn has_encrypt(fs: &Fs) -> bool
+{
+    fs.opts.dyn_cfg.is_some_and(|dyn_cfg| dyn_cfg.incompat.has_encrypt())
+}
+
+/// Checks whether an inode is the orphan file (orphan_file).
+fn is_orphan_file(ino: u64, fs: &Fs) -> bool
+{
+    fs.opts.dyn_cfg.is_some_and(|dyn_cfg| dyn_cfg.compat.has_orphan_file())
+        && ino == fs.sb.s_orphan_file_inum as u64
+}
+
+/// Scans the iblock of the orphan file.
+/// The blocks are mapped like those of a regular file; the orphan list is only reported.
+fn scan_orphan_file_iblock(
+    map: &mut UsageMap,
+    ino: u64,
+    inode: &Inode,
+    osd2: &Osd2,
+    fs: &Fs,
+    ctx: &mut Context,
+    cfg: &Config,
+) -> anyhow::Result<()>
+{
+    scan_regular_iblock(map, ino, inode, osd2, fs, ctx, cfg)?;
+
+    match orphan::count_orphans(inode, fs, ctx, cfg)? {
+        Some(0) => {},
+        Some(count) => ctx.logger.logln(1, &format!(
+            "the orphan file lists {} inode(s); their blocks are kept as used",
+            count,
+        )),
+        None => ctx.logger.logln(1, "the orphan file is block-mapped: its list is not processed"),
+    }
+
+    Ok(())
 }
 
 /// Scans the iblock of an EA inode.
 fn scan_ea_iblock(
     map: &mut UsageMap,
+    ino: u64,
     inode: &Inode,
     osd2: &Osd2,
     fs: &Fs,
-    ctx: &mut Context
+    ctx: &mut Context,
+    cfg: &Config,
 ) -> anyhow::Result<()>
 {
+    if !fs.opts.dyn_cfg.is_some_and(|dyn_cfg| dyn_cfg.incompat.has_ea_inode()) {
+        bail!("an inode has the EA inode flag, but ea_inode is not set");
+    }
+
+    // The value is stored as the data of the inode, up to i_size.
     // NOTE: it is assumed that the ea inode blocks are internally initialised.
-    // TODO: deeper inspection of the ea inode blocks.
-    scan_regular_iblock(map, inode, osd2, fs, ctx)
+    scan_regular_iblock(map, ino, inode, osd2, fs, ctx, cfg)
 }
 
 /// Scans an indirect block.
+#[allow(clippy::too_many_arguments)]
 fn scan_indirect_block(
     map: &mut UsageMap,
     block_head: &mut u64,
+    data_end: u64,
     block: u64,
     inode: &Inode,
     osd2: &Osd2,
@@ -537,6 +787,9 @@ fn scan_indirect_block(
         return Ok(());
     }
 
+    // The indirect block itself is used.
+    map.update(block * bs!(fs.sb.s_log_block_size), bs!(fs.sb.s_log_block_size), AllocStatus::Used);
+
     // Read the block into a buffer.
 
     let block_address = block * bs!(fs.sb.s_log_block_size);
@@ -545,8 +798,7 @@ fn scan_indirect_block(
     ctx.drive.read_exact(&mut block_buf)?;
 
     let mut entry_buf = <[u8; 4]>::default();
-    let max_blocks = get_block_count(inode, osd2, fs);
-    let file_size = hilo!(inode.i_size_high, inode.i_size_lo);
+    let max_blocks = get_block_count(inode, osd2, fs)?;
     let entries_in_a_block = bs!(fs.sb.s_log_block_size) as usize / 4;
 
     // Deserialise and process all the entries.
@@ -559,13 +811,13 @@ fn scan_indirect_block(
         // Position within the file.
         let log_start = *block_head * bs!(fs.sb.s_log_block_size);
 
-        if log_start >= file_size {
+        if log_start >= data_end {
             break;
         }
 
         let mut len = bs!(fs.sb.s_log_block_size);
-        if log_start + len > file_size {
-            len = file_size - log_start;
+        if log_start + len > data_end {
+            len = data_end - log_start;
         }
 
         entry_buf[0] = block_buf[i * 4];
@@ -588,9 +840,11 @@ fn scan_indirect_block(
 }
 
 /// Scan a double indirect block.
+#[allow(clippy::too_many_arguments)]
 fn scan_double_indirect_block(
     map: &mut UsageMap,
     block_head: &mut u64,
+    data_end: u64,
     block: u64,
     inode: &Inode,
     osd2: &Osd2,
@@ -603,6 +857,9 @@ fn scan_double_indirect_block(
         return Ok(());
     }
 
+    // The indirect block itself is used.
+    map.update(block * bs!(fs.sb.s_log_block_size), bs!(fs.sb.s_log_block_size), AllocStatus::Used);
+
     // Read the block into a buffer.
 
     let block_address = block * bs!(fs.sb.s_log_block_size);
@@ -611,7 +868,7 @@ fn scan_double_indirect_block(
     ctx.drive.read_exact(&mut block_buf)?;
 
     let mut entry_buf = <[u8; 4]>::default();
-    let max_blocks = get_block_count(inode, osd2, fs);
+    let max_blocks = get_block_count(inode, osd2, fs)?;
     let entries_in_a_block = bs!(fs.sb.s_log_block_size) as usize / 4;
 
     // Deserialise and process all the entries.
@@ -633,16 +890,18 @@ fn scan_double_indirect_block(
             continue;
         }
 
-        scan_indirect_block(map, block_head, indirect_block, inode, osd2, fs, ctx)?;
+        scan_indirect_block(map, block_head, data_end, indirect_block, inode, osd2, fs, ctx)?;
     }
 
     Ok(())
 }
 
 /// Scan a triple indirect block.
+#[allow(clippy::too_many_arguments)]
 fn scan_triple_indirect_block(
     map: &mut UsageMap,
     block_head: &mut u64,
+    data_end: u64,
     block: u64,
     inode: &Inode,
     osd2: &Osd2,
@@ -655,6 +914,9 @@ fn scan_triple_indirect_block(
         return Ok(());
     }
 
+    // The indirect block itself is used.
+    map.update(block * bs!(fs.sb.s_log_block_size), bs!(fs.sb.s_log_block_size), AllocStatus::Used);
+
     // Read the block into a buffer.
 
     let block_address = block * bs!(fs.sb.s_log_block_size);
@@ -663,7 +925,7 @@ fn scan_triple_indirect_block(
     ctx.drive.read_exact(&mut block_buf)?;
 
     let mut entry_buf = <[u8; 4]>::default();
-    let max_blocks = get_block_count(inode, osd2, fs);
+    let max_blocks = get_block_count(inode, osd2, fs)?;
     let entries_in_a_block = bs!(fs.sb.s_log_block_size) as usize / 4;
 
     // Deserialise and process all the entries.
@@ -685,52 +947,397 @@ fn scan_triple_indirect_block(
             continue;
         }
 
-        scan_double_indirect_block(map, block_head, double_indirect_block, inode, osd2, fs, ctx)?;
+        scan_double_indirect_block(
+            map,
+            block_head,
+            data_end,
+            double_indirect_block,
+            inode,
+            osd2,
+            fs,
+            ctx,
+        )?;
     }
 
     Ok(())
 }
 
-/// Scans the extended attribute block.
-fn scan_xattr_block(map: &mut UsageMap, block: u64, fs: &Fs) -> anyhow::Result<()>
-{
-    let start = block * bs!(fs.sb.s_log_block_size);
-    let size = bs!(fs.sb.s_log_block_size);
-
-    map.update(start, size, AllocStatus::Used);
-
-    // NOTE: it is assumed that the acl/xattr block is initialised.
-    // TODO: deeper inspection of the acl/xattr block.
-
-    Ok(())
-}
-
 /// Returns the number of blocks occupied by the inode's data.
-fn get_block_count(inode: &Inode, osd2: &Osd2, fs: &Fs) -> u64
+/// With huge_file, i_blocks is a 48-bit count, in file system blocks if the inode has the huge
+/// file flag, otherwise in 512-byte sectors.
+fn get_block_count(inode: &Inode, osd2: &Osd2, fs: &Fs) -> anyhow::Result<u64>
 {
-    let i_flags = IFlags { 0: inode.i_flags };
+    let i_flags = IFlags(inode.i_flags);
+    let block_size = bs!(fs.sb.s_log_block_size);
 
     // The number of disk blocks.
+    // Only Linux keeps the high bits; the other creators have the field reserved, so a non-zero
+    // value cannot be interpreted.
     let mut blocks = inode.i_blocks_lo as u64;
     if let Some(dyn_cfg) = fs.opts.dyn_cfg {
         if dyn_cfg.ro_compat.has_huge_file() {
-            if let Osd2::Linux(l) = osd2 {
-                blocks = hilo!(l.l_i_blocks_high, inode.i_blocks_lo);
+            let reserved = match osd2 {
+                Osd2::Linux(l) => {
+                    blocks = hilo!(l.l_i_blocks_high, inode.i_blocks_lo);
+                    0
+                },
+                Osd2::Hurd(h) => h.h_i_reserved1,
+                Osd2::Masix(m) => m.h_i_reserved1,
+            };
+            if reserved != 0 {
+                bail!("the inode has high block count bits, which its creator does not support");
             }
         }
     }
 
     // Multiply by the size of the disk blocks.
     // This block sizing is unique to inodes only.
-    blocks *= if i_flags.has_huge_file() {
-        bs!(fs.sb.s_log_block_size)
+    let unit = if i_flags.has_huge_file() {
+        block_size
     } else {
         512
     };
+    let bytes = match blocks.checked_mul(unit) {
+        Some(bytes) => bytes,
+        None => bail!("the block count of the inode overflows: {} blocks of {} bytes", blocks, unit),
+    };
+
     // Divide by the size of the file system blocks.
-    // FIXME: remove this check.
-    assert!(blocks % bs!(fs.sb.s_log_block_size) == 0);
-    blocks /= bs!(fs.sb.s_log_block_size);
+    if !bytes.is_multiple_of(block_size) {
+        bail!("the block count of the inode is not a multiple of the block size: {} bytes", bytes);
+    }
+
+    Ok(bytes / block_size)
+}
+
+// Tests.
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::test_context;
+    use crate::filesys::e2fs::SuperBlock;
+    use crate::filesys::e2fs::tests::{test_fs, test_sb};
+
+    #[test]
+    fn reserved_inodes_span_groups()
+    {
+        // 16 inodes per group; inodes 1 to 19 are reserved.
+        let fs = test_fs(SuperBlock { s_first_ino: 20, ..test_sb() });
+        let mut ctx = test_context(&[0; 4096]);
+        let cfg = Config::default();
+        let mut map = UsageMap::new(64 * 4096);
+        let mut itable = vec![0; 16 * fs.inode_size as usize];
+
+        assert_eq!(inode_number(2, 1, &fs), 19);
+        assert!(is_reserved(19, &fs));
+        assert!(!is_reserved(20, &fs));
+
+        // Zeroed reserved inodes in the second group are skipped.
+        for idx in 0..3 {
+            scan_inode(&mut map, idx, 1, &mut itable, &fs, &mut ctx, &cfg).unwrap();
+        }
+        assert_eq!(map.len(), 1);
+
+        // The first regular inode must have a valid mode.
+        assert!(scan_inode(&mut map, 3, 1, &mut itable, &fs, &mut ctx, &cfg).is_err());
+    }
+
+    /// Creates a zeroed inode with the supplied block count and flags.
+    fn inode_with_blocks(i_blocks_lo: u32, i_flags: u32) -> Inode
+    {
+        let bincode_opt = DefaultOptions::new()
+            .with_fixint_encoding()
+            .allow_trailing_bytes();
+        let inode: Inode = bincode_opt.deserialize(&[0; INODE_STRUCT_SIZE]).unwrap();
+
+        Inode { i_blocks_lo, i_flags, ..inode }
+    }
+
+    fn osd2_with_blocks_high(l_i_blocks_high: u16) -> Osd2
+    {
+        Osd2::Linux(Osd2Linux {
+            l_i_blocks_high,
+            l_i_file_acl_high: 0,
+            l_i_uid_high: 0,
+            l_i_gid_high: 0,
+            l_i_checksum_lo: 0,
+            l_i_reserved: 0,
+        })
+    }
+
+    #[test]
+    fn indirect_blocks_used()
+    {
+        use crate::usage_map::Segment;
+
+        // 13 blocks: 12 direct ones (10 to 21) and one (31) listed by the indirect block 30.
+        let fs = test_fs(test_sb());
+        let mut drive = vec![0u8; 64 * 4096];
+        drive[30 * 4096..30 * 4096 + 4].copy_from_slice(&31u32.to_le_bytes());
+        let mut inode = inode_with_blocks(14 * 8, 0);
+        inode.i_size_lo = 13 * 4096;
+        for i in 0..12 {
+            inode.i_block[i] = 10 + i as u32;
+        }
+        inode.i_block[12] = 30;
+
+        let mut ctx = test_context(&drive);
+        let mut map = UsageMap::new(64 * 4096);
+        let osd2 = osd2_with_blocks_high(0);
+        scan_regular_iblock(&mut map, 12, &inode, &osd2, &fs, &mut ctx, &Config::default())
+            .unwrap();
+
+        assert_eq!(map.segments(), vec![
+            Segment { start: 0, end: 10 * 4096, status: AllocStatus::Free },
+            Segment { start: 10 * 4096, end: 22 * 4096, status: AllocStatus::Used },
+            Segment { start: 22 * 4096, end: 30 * 4096, status: AllocStatus::Free },
+            Segment { start: 30 * 4096, end: 32 * 4096, status: AllocStatus::Used },
+            Segment { start: 32 * 4096, end: 64 * 4096, status: AllocStatus::Free },
+        ]);
+    }
+
+    #[test]
+    fn slack()
+    {
+        use crate::usage_map::Segment;
+        use crate::filesys::e2fs::extent::tests::{inode_with_root, raw_extent, raw_node};
+
+        // 100 bytes in block 10, and 2 unwritten blocks (20 and 21) allocated beyond them.
+        let extents = [raw_extent(0, 1, 10), raw_extent(1, 32768 + 2, 20)];
+        let mut inode = inode_with_root(&raw_node(0, &extents, N_BLOCKS * 4));
+        inode.i_blocks_lo = 3 * 8;
+        inode.i_flags = 0x80000;
+        inode.i_size_lo = 100;
+
+        let cfg = Config { allow_encrypted: true, ..Config::default() };
+        let fs = Fs::new(SuperBlock { s_feature_incompat: 0x10000, ..test_sb() }, &cfg).unwrap();
+        let mut ctx = test_context(&[0; 4096]);
+        let osd2 = osd2_with_blocks_high(0);
+
+        let mut map = UsageMap::new(64 * 4096);
+        scan_regular_iblock(&mut map, 12, &inode, &osd2, &fs, &mut ctx, &cfg).unwrap();
+        assert_eq!(map.segments()[1], Segment { start: 10 * 4096, end: 11 * 4096, status: AllocStatus::Used });
+        assert_eq!(map.segments()[3], Segment { start: 20 * 4096, end: 22 * 4096, status: AllocStatus::Used });
+
+        let cfg = Config { wipe_slack: true, ..cfg };
+        let mut map = UsageMap::new(64 * 4096);
+        scan_regular_iblock(&mut map, 12, &inode, &osd2, &fs, &mut ctx, &cfg).unwrap();
+        assert_eq!(map.len(), 3);
+        assert_eq!(map.segments()[1], Segment {
+            start: 10 * 4096,
+            end: 10 * 4096 + 100,
+            status: AllocStatus::Used,
+        });
+
+        // The slack of encrypted files holds ciphertext.
+        inode.i_flags |= 0x800;
+        let mut map = UsageMap::new(64 * 4096);
+        scan_regular_iblock(&mut map, 12, &inode, &osd2, &fs, &mut ctx, &cfg).unwrap();
+        assert_eq!(map.len(), 5);
+    }
+
+    #[test]
+    fn hurd()
+    {
+        let fs = test_fs(SuperBlock { s_creator_os: 1, ..test_sb() });
+        let mut ctx = test_context(&[0; 4096]);
+        let cfg = Config::default();
+
+        // An empty file with a passive translator in block 20.
+        let mut inode = inode_with_blocks(8, 0);
+        inode.i_mode = 0x81a4;
+        inode.osd1 = 20;
+        let mut itable = itable_with(&inode, &[]);
+
+        let mut map = UsageMap::new(64 * 4096);
+        scan_inode(&mut map, 0, 1, &mut itable, &fs, &mut ctx, &cfg).unwrap();
+        assert_eq!(map.segments()[1].start, 20 * 4096);
+        assert_eq!(map.segments()[1].status, AllocStatus::Used);
+
+        inode.osd1 = 64;
+        let mut itable = itable_with(&inode, &[]);
+        assert!(scan_inode(&mut map, 0, 1, &mut itable, &fs, &mut ctx, &cfg).is_err());
+
+        // The Hurd has no high block count bits, even with huge_file.
+        let fs = test_fs(SuperBlock { s_creator_os: 1, s_feature_ro_compat: 0x8, ..test_sb() });
+        let osd2 = Osd2::Hurd(Osd2Hurd {
+            h_i_reserved1: 0,
+            h_i_mode_high: 0xffff,
+            h_i_uid_high: 0,
+            h_i_gid_high: 0,
+            h_i_author: 0,
+        });
+        assert_eq!(get_block_count(&inode_with_blocks(16, 0), &osd2, &fs).unwrap(), 2);
+        let osd2 = Osd2::Hurd(Osd2Hurd {
+            h_i_reserved1: 1,
+            h_i_mode_high: 0,
+            h_i_uid_high: 0,
+            h_i_gid_high: 0,
+            h_i_author: 0,
+        });
+        assert!(get_block_count(&inode_with_blocks(16, 0), &osd2, &fs).is_err());
+    }
 
-    blocks
+    /// Serialises an inode into the first slot of an inode table.
+    fn itable_with(inode: &Inode, ibody: &[u8]) -> Vec<u8>
+    {
+        let bincode_opt = DefaultOptions::new()
+            .with_fixint_encoding()
+            .allow_trailing_bytes();
+
+        let mut itable = vec![0u8; 16 * 256];
+        let raw = bincode_opt.serialize(inode).unwrap();
+        itable[..raw.len()].copy_from_slice(&raw);
+        itable[160..160 + ibody.len()].copy_from_slice(ibody);
+
+        itable
+    }
+
+    /// Creates the in-inode attributes with a system.data attribute of the supplied size.
+    fn system_data(size: u32) -> Vec<u8>
+    {
+        let mut ibody = vec![0u8; 24];
+        ibody[0..4].copy_from_slice(&0xea020000u32.to_le_bytes());
+        ibody[4] = 4;
+        ibody[5] = 7;
+        ibody[6..8].copy_from_slice(&20u16.to_le_bytes());
+        ibody[12..16].copy_from_slice(&size.to_le_bytes());
+        ibody[20..24].copy_from_slice(b"data");
+
+        ibody
+    }
+
+    #[test]
+    fn fast_symlink()
+    {
+        let fs = test_fs(test_sb());
+        let mut ctx = test_context(&[0; 4096]);
+        let mut map = UsageMap::new(64 * 4096);
+
+        // The block count includes an xattr block, but the target is stored in i_block.
+        let mut inode = inode_with_blocks(8, 0);
+        inode.i_mode = 0xa1ff;
+        inode.i_size_lo = 40;
+        inode.i_block = [0x6d6f732f; N_BLOCKS];
+        let mut itable = itable_with(&inode, &[]);
+
+        scan_inode(&mut map, 0, 1, &mut itable, &fs, &mut ctx, &Config::default()).unwrap();
+        assert_eq!(map.len(), 1);
+    }
+
+    #[test]
+    fn encrypted()
+    {
+        let mut ctx = test_context(&[0; 4096]);
+        let mut map = UsageMap::new(64 * 4096);
+        let cfg = Config::default();
+
+        // Encrypted contents are scanned like any other.
+        let mut inode = inode_with_blocks(0, 0x800);
+        inode.i_mode = 0x81a4;
+        let mut itable = itable_with(&inode, &[]);
+
+        let fs = test_fs(test_sb());
+        assert!(scan_inode(&mut map, 0, 1, &mut itable, &fs, &mut ctx, &cfg).is_err());
+
+        let cfg = Config { allow_encrypted: true, ..cfg };
+        let fs = Fs::new(SuperBlock { s_feature_incompat: 0x10000, ..test_sb() }, &cfg).unwrap();
+        scan_inode(&mut map, 0, 1, &mut itable, &fs, &mut ctx, &cfg).unwrap();
+    }
+
+    #[test]
+    fn casefold()
+    {
+        let mut ctx = test_context(&[0; 4096]);
+        let mut map = UsageMap::new(64 * 4096);
+        let cfg = Config::default();
+
+        let mut inode = inode_with_blocks(0, 0x40000000);
+        inode.i_mode = 0x41ed;
+        let mut itable = itable_with(&inode, &[]);
+
+        let fs = test_fs(test_sb());
+        assert!(scan_inode(&mut map, 0, 1, &mut itable, &fs, &mut ctx, &cfg).is_err());
+
+        let fs = test_fs(SuperBlock { s_feature_incompat: 0x20000, s_encoding: 1, ..test_sb() });
+        scan_inode(&mut map, 0, 1, &mut itable, &fs, &mut ctx, &cfg).unwrap();
+    }
+
+    #[test]
+    fn inline_data()
+    {
+        let fs = test_fs(SuperBlock { s_feature_incompat: 0x8000, ..test_sb() });
+        let mut ctx = test_context(&[0; 4096]);
+        let mut map = UsageMap::new(64 * 4096);
+        let cfg = Config::default();
+
+        // 100 bytes: 60 in i_block, 40 in system.data.
+        let mut inode = inode_with_blocks(0, 0x10000000);
+        inode.i_mode = 0x81a4;
+        inode.i_size_lo = 100;
+        inode.i_extra_isize = 32;
+        inode.i_block = [0xffffffff; N_BLOCKS];
+
+        let mut itable = itable_with(&inode, &system_data(40));
+        scan_inode(&mut map, 0, 1, &mut itable, &fs, &mut ctx, &cfg).unwrap();
+        assert_eq!(map.len(), 1);
+
+        let mut itable = itable_with(&inode, &system_data(39));
+        assert!(scan_inode(&mut map, 0, 1, &mut itable, &fs, &mut ctx, &cfg).is_err());
+
+        let mut itable = itable_with(&inode, &[]);
+        assert!(scan_inode(&mut map, 0, 1, &mut itable, &fs, &mut ctx, &cfg).is_err());
+
+        let fs = test_fs(test_sb());
+        let mut itable = itable_with(&inode, &system_data(40));
+        assert!(scan_inode(&mut map, 0, 1, &mut itable, &fs, &mut ctx, &cfg).is_err());
+    }
+
+    #[test]
+    fn block_count_48bit()
+    {
+        // huge_file.
+        let fs = test_fs(SuperBlock { s_feature_ro_compat: 0x8, ..test_sb() });
+
+        // The largest count in sectors: just under 2^48 sectors, 2^39 4KiB blocks.
+        let inode = inode_with_blocks(u32::MAX - 7, 0);
+        assert_eq!(
+            get_block_count(&inode, &osd2_with_blocks_high(u16::MAX), &fs).unwrap(),
+            ((1 << 48) - 8) / 8,
+        );
+
+        // The largest count in file system blocks.
+        let inode = inode_with_blocks(u32::MAX, 0x40000);
+        assert_eq!(
+            get_block_count(&inode, &osd2_with_blocks_high(u16::MAX), &fs).unwrap(),
+            (1 << 48) - 1,
+        );
+
+        // Without huge_file, the high bits are ignored.
+        let fs = test_fs(test_sb());
+        let inode = inode_with_blocks(16, 0);
+        assert_eq!(get_block_count(&inode, &osd2_with_blocks_high(u16::MAX), &fs).unwrap(), 2);
+    }
+
+    #[test]
+    fn block_count_overflow()
+    {
+        // huge_file with 2^17-byte blocks: 2^48 - 1 blocks no longer fit into 64 bits of bytes.
+        let fs = test_fs(SuperBlock { s_feature_ro_compat: 0x8, ..test_sb() });
+        let fs = Fs { sb: SuperBlock { s_log_block_size: 7, ..fs.sb }, ..fs };
+        let inode = inode_with_blocks(u32::MAX, 0x40000);
+
+        assert!(get_block_count(&inode, &osd2_with_blocks_high(u16::MAX), &fs).is_err());
+    }
+
+    #[test]
+    fn block_count_partial_block()
+    {
+        // 3 sectors do not make up whole 4KiB blocks.
+        let fs = test_fs(test_sb());
+        let inode = inode_with_blocks(3, 0);
+
+        assert!(get_block_count(&inode, &osd2_with_blocks_high(0), &fs).is_err());
+    }
 }
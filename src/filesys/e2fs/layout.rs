@@ -0,0 +1,181 @@
+use crate::{bs, hilo};
+use crate::usage_map::{AllocStatus, UsageMap};
+use super::{
+    Fs,
+    has_super_copy,
+    fetch_regular_bg_descriptor,
+    start_of_bg,
+    start_of_first_gdt,
+};
+
+/// Byte totals of the categories of space on the file system.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct SpaceSummary {
+    /// Fixed metadata: the superblocks, the group descriptor tables, the bitmaps and the inode
+    /// tables.
+    pub metadata: u64,
+    /// Space reserved for the growth of the file system: the reserved GDT blocks that follow each
+    /// copy of the group descriptor table (resize_inode).
+    pub growth: u64,
+    /// Used space that is neither of the above, i.e. file data and the remaining metadata (extent
+    /// tree blocks, xattr blocks, the journal, ...).
+    pub data: u64,
+}
+
+/// Classifies the space on the file system, using the scanned usage map.
+/// The metadata and growth regions are derived from the superblock and the group descriptors and
+/// are counted whole, regardless of their status in the map. Only the used space of the map that
+/// lies outside of these regions is counted as data.
+pub fn summarize(map: &UsageMap, fs: &Fs) -> anyhow::Result<SpaceSummary>
+{
+    let size = map.size();
+    let mut metadata = UsageMap::new(size);
+    let mut growth = UsageMap::new(size);
+
+    let block_size = bs!(fs.sb.s_log_block_size);
+    let gdt_blocks = (fs.bg_count * fs.desc_size).div_ceil(block_size);
+    let reserved_gdt_blocks = match fs.opts.dyn_cfg {
+        Some(dyn_cfg) if dyn_cfg.compat.has_resize_inode() => fs.sb.s_reserved_gdt_blocks as u64,
+        _ => 0,
+    };
+
+    for bg_num in 0..fs.bg_count {
+        // The superblock and group descriptor copies, followed by the reserved GDT blocks.
+
+        if has_super_copy(bg_num, fs) {
+            let gdt_start = if bg_num == 0 {
+                start_of_first_gdt(&fs.sb)
+            } else {
+                start_of_bg(bg_num, fs) + block_size
+            };
+            let sb_start = gdt_start - block_size.min(gdt_start);
+            let reserved_start = gdt_start + gdt_blocks * block_size;
+
+            mark(&mut metadata, sb_start, gdt_start - sb_start);
+            mark(&mut metadata, gdt_start, gdt_blocks * block_size);
+            mark(&mut growth, reserved_start, reserved_gdt_blocks * block_size);
+        }
+
+        // The bitmaps and the inode table. These are allocated even in uninitialised groups.
+
+        let desc = fetch_regular_bg_descriptor(bg_num, fs)?;
+
+        let (block_bitmap, inode_bitmap, inode_table) = if fs.opts.bit64_cfg.is_some() {
+            (
+                hilo!(desc.bg_block_bitmap_hi, desc.bg_block_bitmap_lo),
+                hilo!(desc.bg_inode_bitmap_hi, desc.bg_inode_bitmap_lo),
+                hilo!(desc.bg_inode_table_hi, desc.bg_inode_table_lo),
+            )
+        } else {
+            (
+                desc.bg_block_bitmap_lo as u64,
+                desc.bg_inode_bitmap_lo as u64,
+                desc.bg_inode_table_lo as u64,
+            )
+        };
+
+        mark(&mut metadata, block_bitmap * block_size, block_size);
+        mark(&mut metadata, inode_bitmap * block_size, block_size);
+        mark(
+            &mut metadata,
+            inode_table * block_size,
+            fs.sb.s_inodes_per_group as u64 * fs.inode_size,
+        );
+    }
+
+    let mut summary = SpaceSummary {
+        metadata: used_within(&metadata, 0, size),
+        growth: used_within(&growth, 0, size),
+        data: 0,
+    };
+
+    for seg in map.iter_used() {
+        summary.data += seg.size() as u64
+            - used_within(&metadata, seg.start, seg.end)
+            - used_within(&growth, seg.start, seg.end);
+    }
+
+    Ok(summary)
+}
+
+/// Marks a region as used, ignoring regions that start beyond the end of the map.
+fn mark(map: &mut UsageMap, start: u64, size: u64)
+{
+    if size != 0 && start < map.size() {
+        map.update(start, size, AllocStatus::Used);
+    }
+}
+
+/// Counts the used bytes of the map within `start..end`.
+fn used_within(map: &UsageMap, start: u64, end: u64) -> u64
+{
+    map.overlapping(start, end)
+        .filter(|s| s.status == AllocStatus::Used)
+        .map(|s| s.end.min(end) - s.start.max(start))
+        .sum()
+}
+
+// Tests.
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::{GroupDescriptor, SuperBlock};
+    use crate::filesys::e2fs::tests::{test_fs_with_descs, test_sb};
+
+    const BLOCK_SIZE: u64 = 4096;
+
+    /// Creates a file system with two groups, each with a superblock copy, one GDT block, two
+    /// reserved GDT blocks, two bitmaps and a one-block inode table, in this order.
+    fn growth_fs() -> Fs
+    {
+        let descs = [0, 32].map(|first| GroupDescriptor {
+            bg_block_bitmap_lo: first + 4,
+            bg_inode_bitmap_lo: first + 5,
+            bg_inode_table_lo: first + 6,
+            ..GroupDescriptor::default()
+        });
+
+        let sb = SuperBlock { s_feature_compat: 0x10, s_reserved_gdt_blocks: 2, ..test_sb() };
+
+        test_fs_with_descs(sb, &descs)
+    }
+
+    #[test]
+    fn categories()
+    {
+        let fs = growth_fs();
+
+        let mut map = UsageMap::new(64 * BLOCK_SIZE);
+        // What the scan marks as used in the metadata of group 0.
+        map.update(0, 2048, AllocStatus::Used);
+        map.update(BLOCK_SIZE, 64, AllocStatus::Used);
+        map.update(4 * BLOCK_SIZE, 3 * BLOCK_SIZE, AllocStatus::Used);
+        // File data, partially overlapping the inode table of group 1.
+        map.update(38 * BLOCK_SIZE + 1024, 3 * BLOCK_SIZE, AllocStatus::Used);
+        map.update(50 * BLOCK_SIZE, 100, AllocStatus::Used);
+
+        let summary = summarize(&map, &fs).unwrap();
+
+        assert_eq!(summary, SpaceSummary {
+            metadata: 10 * BLOCK_SIZE,
+            growth: 4 * BLOCK_SIZE,
+            data: 2 * BLOCK_SIZE + 1024 + 100,
+        });
+    }
+
+    #[test]
+    fn no_resize_inode()
+    {
+        let mut fs = growth_fs();
+        fs.opts.dyn_cfg.as_mut().unwrap().compat.0 = 0;
+
+        let summary = summarize(&UsageMap::new(64 * BLOCK_SIZE), &fs).unwrap();
+
+        assert_eq!(summary, SpaceSummary {
+            metadata: 10 * BLOCK_SIZE,
+            growth: 0,
+            data: 0,
+        });
+    }
+}
@@ -0,0 +1,495 @@
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use anyhow::bail;
+use rand::Rng;
+
+use crate::{Config, Context};
+use crate::bs;
+use crate::interrupt;
+use super::{Fs, ext4_style_crc32c_le};
+
+// Reference: https://www.kernel.org/doc/html/latest/filesystems/ext4/mmp.html
+
+const MMP_MAGIC: u32 = 0x004d4d50;
+/// The file system was cleanly unmounted.
+const MMP_SEQ_CLEAN: u32 = 0xff4d4d50;
+/// The file system is being checked (or modified) by e2fsprogs; fsfill takes the same role.
+const MMP_SEQ_FSCK: u32 = 0xe24d4d50;
+/// The greatest sequence number that a mounting node may write.
+const MMP_SEQ_MAX: u32 = 0xe24d4d4f;
+/// The lowest interval between the updates of the MMP block, in seconds.
+const MMP_MIN_CHECK_INTERVAL: u64 = 5;
+/// The time between the checks for an interrupt while the MMP block is watched.
+const WATCH_STEP: Duration = Duration::from_millis(100);
+
+/// The size of the MMP structure at the start of the block.
+const MMP_SIZE: usize = 1024;
+const MMP_NODENAME_OFFSET: usize = 16;
+const MMP_NODENAME_SIZE: usize = 64;
+const MMP_BDEVNAME_OFFSET: usize = 80;
+const MMP_BDEVNAME_SIZE: usize = 32;
+const MMP_CHECK_INTERVAL_OFFSET: usize = 112;
+/// The offset of `mmp_checksum`, which covers everything before it.
+const MMP_CHECKSUM_OFFSET: usize = 1020;
+
+/// The claim on a file system with multi-mount protection, held from the scan until the fill is
+/// done.
+#[derive(Clone, Debug)]
+pub struct MmpClaim {
+    /// The position of the MMP block in the underlying file, as the drive window may change before
+    /// the release.
+    block_offset: u64,
+    /// The checksum seed, with metadata_csum.
+    csum_seed: Option<u32>,
+    /// The MMP structure, as written by the claim.
+    raw: Vec<u8>,
+}
+
+/// Handles the MMP block of a file system with the mmp feature. The file system must not be in
+/// use elsewhere.
+/// Like e2fsprogs, a file system that is not clean is watched for twice the update interval, and
+/// is then claimed by writing a random sequence number, watched again and marked as being
+/// checked. The claim is added to the context and released by `release` after the fill. Only the
+/// state is checked in the report-only mode.
+pub fn start(fs: &Fs, ctx: &mut Context, cfg: &Config) -> anyhow::Result<()>
+{
+    if cfg.report_only {
+        return check(fs, ctx);
+    }
+
+    let claim = claim(fs, ctx, cfg, &mut |_, interval| watch(interval))?;
+    ctx.mmp.push(claim);
+
+    Ok(())
+}
+
+/// Waits while the MMP block is watched. Fails as soon as an interrupt is requested.
+fn watch(interval: Duration) -> anyhow::Result<()>
+{
+    let deadline = Instant::now() + interval;
+
+    loop {
+        if interrupt::requested() {
+            bail!("interrupted while watching the MMP block");
+        }
+
+        let now = Instant::now();
+        if now >= deadline {
+            return Ok(());
+        }
+        std::thread::sleep(std::cmp::min(WATCH_STEP, deadline - now));
+    }
+}
+
+/// Releases a claim, marking the file system as clean again. Fails if the MMP block was changed by
+/// another node in the meantime, in which case it is left alone.
+pub fn release(claim: MmpClaim, ctx: &mut Context) -> anyhow::Result<()>
+{
+    let window = ctx.drive.window();
+    ctx.drive.set_window(None)?;
+    let released = release_block(&claim, ctx);
+    ctx.drive.set_window(window)?;
+
+    released
+}
+
+/// Releases the MMP block of a claim, with the drive window lifted.
+fn release_block(claim: &MmpClaim, ctx: &mut Context) -> anyhow::Result<()>
+{
+    let mut raw = read_mmp(claim.block_offset, claim.csum_seed, ctx)?;
+    if raw != claim.raw {
+        bail!("the MMP block was changed by another node during the fill ({})", describe(&raw));
+    }
+
+    write_u32(&mut raw, 4, MMP_SEQ_CLEAN);
+    stamp(&mut raw, None);
+    write_mmp(claim.block_offset, claim.csum_seed, &mut raw, ctx)
+}
+
+/// Checks the state of the MMP block without claiming it.
+fn check(fs: &Fs, ctx: &mut Context) -> anyhow::Result<()>
+{
+    let raw = read_mmp(mmp_offset(fs)?, mmp_csum_seed(fs), ctx)?;
+
+    match read_u32(&raw, 4) {
+        MMP_SEQ_CLEAN => {},
+        MMP_SEQ_FSCK => ctx.logger.logln(0, &format!(
+            "warning: the file system is being checked: {}",
+            describe(&raw),
+        )),
+        _ => ctx.logger.logln(0, &format!(
+            "warning: the file system may be mounted: {}",
+            describe(&raw),
+        )),
+    }
+
+    Ok(())
+}
+
+/// Claims the MMP block. `wait` is called with the time to watch the block for. If it fails once
+/// the block is taken over, the block is marked clean again.
+fn claim(
+    fs: &Fs,
+    ctx: &mut Context,
+    cfg: &Config,
+    wait: &mut dyn FnMut(&mut Context, Duration) -> anyhow::Result<()>,
+) -> anyhow::Result<MmpClaim>
+{
+    let block_offset = mmp_offset(fs)?;
+    let csum_seed = mmp_csum_seed(fs);
+    let mut raw = read_mmp(block_offset, csum_seed, ctx)?;
+
+    let mut interval = std::cmp::max(fs.sb.s_mmp_update_interval as u64, MMP_MIN_CHECK_INTERVAL);
+    let watch_time = |interval: u64| {
+        Duration::from_secs(std::cmp::min(2 * interval + 1, interval + 60))
+    };
+
+    let seq = read_u32(&raw, 4);
+    match seq {
+        MMP_SEQ_CLEAN => {},
+        MMP_SEQ_FSCK => bail!("the file system is being checked: {}", describe(&raw)),
+        seq if seq > MMP_SEQ_MAX => bail!("unknown MMP sequence number: {:#010x}", seq),
+        _ => {
+            // The node that holds the file system may use a longer interval.
+            let block_interval = u16::from_le_bytes(
+                raw[MMP_CHECK_INTERVAL_OFFSET..MMP_CHECK_INTERVAL_OFFSET + 2].try_into().unwrap()
+            );
+            interval = std::cmp::max(interval, block_interval as u64);
+
+            ctx.logger.logln(0, &format!(
+                "the MMP block is not clean; watching it for {} seconds",
+                watch_time(interval).as_secs(),
+            ));
+            wait(ctx, watch_time(interval))?;
+
+            let current = read_mmp(block_offset, csum_seed, ctx)?;
+            if read_u32(&current, 4) != seq {
+                bail!("the file system is in use: {}", describe(&current));
+            }
+        },
+    }
+
+    // Take the block over and check that no other node does the same.
+
+    let seq = rand::thread_rng().gen_range(0..=MMP_SEQ_MAX);
+    write_u32(&mut raw, 4, seq);
+    let bdevname = cfg.drive_path.file_name().unwrap_or_default().to_string_lossy();
+    stamp(&mut raw, Some(&bdevname));
+    write_mmp(block_offset, csum_seed, &mut raw, ctx)?;
+
+    if let Err(e) = wait(ctx, watch_time(interval)) {
+        // Give the block up, unless another node has taken it since.
+        let current = read_mmp(block_offset, csum_seed, ctx)?;
+        if read_u32(&current, 4) == seq {
+            write_u32(&mut raw, 4, MMP_SEQ_CLEAN);
+            stamp(&mut raw, None);
+            write_mmp(block_offset, csum_seed, &mut raw, ctx)?;
+        }
+        return Err(e);
+    }
+
+    let current = read_mmp(block_offset, csum_seed, ctx)?;
+    if read_u32(&current, 4) != seq {
+        bail!("the file system was claimed by another node: {}", describe(&current));
+    }
+
+    write_u32(&mut raw, 4, MMP_SEQ_FSCK);
+    write_mmp(block_offset, csum_seed, &mut raw, ctx)?;
+
+    Ok(MmpClaim { block_offset: ctx.drive.offset() + block_offset, csum_seed, raw })
+}
+
+/// Returns the position of the MMP block.
+fn mmp_offset(fs: &Fs) -> anyhow::Result<u64>
+{
+    let block = fs.sb.s_mmp_block;
+    if block == 0 || block >= fs.blocks_count {
+        bail!("invalid MMP block: {}", block);
+    }
+
+    Ok(block * bs!(fs.sb.s_log_block_size))
+}
+
+/// Returns the seed of the MMP checksum, which is only used with metadata_csum.
+fn mmp_csum_seed(fs: &Fs) -> Option<u32>
+{
+    if fs.opts.dyn_cfg.is_some_and(|dyn_cfg| dyn_cfg.ro_compat.has_metadata_csum()) {
+        fs.csum_seed
+    } else {
+        None
+    }
+}
+
+/// Reads the MMP structure and checks its magic value and checksum.
+fn read_mmp(offset: u64, csum_seed: Option<u32>, ctx: &mut Context) -> anyhow::Result<Vec<u8>>
+{
+    let mut raw = vec![0u8; MMP_SIZE];
+    ctx.drive.seek(SeekFrom::Start(offset))?;
+    ctx.drive.read_exact(&mut raw)?;
+
+    let magic = read_u32(&raw, 0);
+    if magic != MMP_MAGIC {
+        bail!("the MMP block has invalid magic: {:#010x}", magic);
+    }
+    if let Some(seed) = csum_seed {
+        if read_u32(&raw, MMP_CHECKSUM_OFFSET) != mmp_checksum(&raw, seed) {
+            bail!("the MMP block has an invalid checksum");
+        }
+    }
+
+    Ok(raw)
+}
+
+/// Writes the MMP structure, with a fresh checksum, and syncs it, as the other nodes have to see
+/// it.
+fn write_mmp(offset: u64, csum_seed: Option<u32>, raw: &mut [u8], ctx: &mut Context)
+    -> anyhow::Result<()>
+{
+    if let Some(seed) = csum_seed {
+        let checksum = mmp_checksum(raw, seed);
+        write_u32(raw, MMP_CHECKSUM_OFFSET, checksum);
+    }
+
+    ctx.drive.seek(SeekFrom::Start(offset))?;
+    ctx.drive.write_all(raw)?;
+    ctx.drive.sync_data()?;
+
+    Ok(())
+}
+
+/// Computes the checksum of the MMP structure.
+fn mmp_checksum(raw: &[u8], seed: u32) -> u32
+{
+    ext4_style_crc32c_le(seed, &raw[..MMP_CHECKSUM_OFFSET])
+}
+
+/// Updates the time and, if supplied, the names of the node and the device.
+fn stamp(raw: &mut [u8], bdevname: Option<&str>)
+{
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    raw[8..16].copy_from_slice(&now.to_le_bytes());
+
+    if let Some(bdevname) = bdevname {
+        write_str(&mut raw[MMP_NODENAME_OFFSET..][..MMP_NODENAME_SIZE], &hostname());
+        write_str(&mut raw[MMP_BDEVNAME_OFFSET..][..MMP_BDEVNAME_SIZE], bdevname);
+    }
+}
+
+/// Describes the node that last wrote the MMP block.
+fn describe(raw: &[u8]) -> String
+{
+    let time = u64::from_le_bytes(raw[8..16].try_into().unwrap());
+
+    format!(
+        "last updated by {} on {} at {}",
+        read_str(&raw[MMP_NODENAME_OFFSET..][..MMP_NODENAME_SIZE]),
+        read_str(&raw[MMP_BDEVNAME_OFFSET..][..MMP_BDEVNAME_SIZE]),
+        time,
+    )
+}
+
+#[cfg(target_os = "linux")]
+fn hostname() -> String
+{
+    let mut buf = [0u8; MMP_NODENAME_SIZE];
+    // SAFETY: the buffer is valid for its length.
+    if unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) } != 0 {
+        return String::new();
+    }
+
+    read_str(&buf)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn hostname() -> String
+{
+    String::new()
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> u32
+{
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn write_u32(buf: &mut [u8], offset: usize, value: u32)
+{
+    buf[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+/// Reads a NUL-padded string.
+fn read_str(buf: &[u8]) -> String
+{
+    let len = buf.iter().position(|b| *b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+/// Writes a NUL-padded string, truncated to the field.
+fn write_str(buf: &mut [u8], s: &str)
+{
+    buf.fill(0);
+    let len = std::cmp::min(s.len(), buf.len() - 1);
+    buf[..len].copy_from_slice(&s.as_bytes()[..len]);
+}
+
+// Tests.
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::test_context;
+    use crate::drive::Window;
+    use crate::filesys::e2fs::SuperBlock;
+    use crate::filesys::e2fs::tests::{test_fs, test_sb};
+
+    const BLOCK_SIZE: usize = 4096;
+
+    /// Creates a drive with an MMP block at block 10.
+    fn test_drive(seq: u32) -> Vec<u8>
+    {
+        let mut drive = vec![0u8; 16 * BLOCK_SIZE];
+        let mmp = &mut drive[10 * BLOCK_SIZE..10 * BLOCK_SIZE + MMP_SIZE];
+        write_u32(mmp, 0, MMP_MAGIC);
+        write_u32(mmp, 4, seq);
+        write_str(&mut mmp[MMP_NODENAME_OFFSET..][..MMP_NODENAME_SIZE], "other");
+
+        drive
+    }
+
+    fn mmp_fs(sb: SuperBlock) -> Fs
+    {
+        test_fs(SuperBlock { s_feature_incompat: 0x100, s_mmp_block: 10, ..sb })
+    }
+
+    fn read_seq(ctx: &mut Context) -> u32
+    {
+        read_u32(&read_mmp(10 * BLOCK_SIZE as u64, None, ctx).unwrap(), 4)
+    }
+
+    fn no_wait(_: &mut Context, _: Duration) -> anyhow::Result<()>
+    {
+        Ok(())
+    }
+
+    #[test]
+    fn claim_and_release()
+    {
+        let fs = mmp_fs(test_sb());
+        let cfg = Config { report_only: false, ..Config::default() };
+
+        // A clean file system and one that is not updated while it is watched.
+        for seq in [MMP_SEQ_CLEAN, 1234] {
+            let mut ctx = test_context(&test_drive(seq));
+
+            let claim = claim(&fs, &mut ctx, &cfg, &mut no_wait).unwrap();
+            assert_eq!(read_seq(&mut ctx), MMP_SEQ_FSCK);
+
+            release(claim, &mut ctx).unwrap();
+            assert_eq!(read_seq(&mut ctx), MMP_SEQ_CLEAN);
+        }
+
+        let mut ctx = test_context(&test_drive(MMP_SEQ_FSCK));
+        assert!(claim(&fs, &mut ctx, &cfg, &mut no_wait).is_err());
+
+        let mut ctx = test_context(&test_drive(MMP_SEQ_FSCK + 1));
+        assert!(claim(&fs, &mut ctx, &cfg, &mut no_wait).is_err());
+
+        // Not an MMP block.
+        let fs = test_fs(SuperBlock { s_feature_incompat: 0x100, s_mmp_block: 11, ..test_sb() });
+        let mut ctx = test_context(&test_drive(MMP_SEQ_CLEAN));
+        assert!(claim(&fs, &mut ctx, &cfg, &mut no_wait).is_err());
+    }
+
+    #[test]
+    fn in_use()
+    {
+        let fs = mmp_fs(test_sb());
+        let cfg = Config { report_only: false, ..Config::default() };
+
+        // Another node updates the block while it is watched.
+        let mut bump = |ctx: &mut Context, _: Duration| {
+            let seq = read_seq(ctx);
+            ctx.drive.seek(SeekFrom::Start(10 * BLOCK_SIZE as u64 + 4))?;
+            ctx.drive.write_all(&(seq.wrapping_add(1) % MMP_SEQ_MAX).to_le_bytes())?;
+            Ok(())
+        };
+
+        let mut ctx = test_context(&test_drive(1234));
+        assert!(claim(&fs, &mut ctx, &cfg, &mut bump).is_err());
+
+        let mut ctx = test_context(&test_drive(MMP_SEQ_CLEAN));
+        assert!(claim(&fs, &mut ctx, &cfg, &mut bump).is_err());
+
+        // The block changes during the fill.
+        let mut ctx = test_context(&test_drive(MMP_SEQ_CLEAN));
+        let claimed = claim(&fs, &mut ctx, &cfg, &mut no_wait).unwrap();
+        bump(&mut ctx, Duration::ZERO).unwrap();
+        assert!(release(claimed, &mut ctx).is_err());
+        assert_ne!(read_seq(&mut ctx), MMP_SEQ_CLEAN);
+    }
+
+    #[test]
+    fn interrupted()
+    {
+        let fs = mmp_fs(test_sb());
+        let cfg = Config { report_only: false, ..Config::default() };
+        let mut wait = |_: &mut Context, interval| watch(interval);
+
+        // Interrupted after the block is taken over: it is left clean.
+        let mut ctx = test_context(&test_drive(MMP_SEQ_CLEAN));
+        interrupt::request_after(0);
+        let err = claim(&fs, &mut ctx, &cfg, &mut wait).unwrap_err();
+        assert_eq!(err.to_string(), "interrupted while watching the MMP block");
+        assert_eq!(read_seq(&mut ctx), MMP_SEQ_CLEAN);
+
+        // Interrupted while the block of another node is watched: it is left alone.
+        let mut ctx = test_context(&test_drive(1234));
+        interrupt::request_after(0);
+        assert!(claim(&fs, &mut ctx, &cfg, &mut wait).is_err());
+        assert_eq!(read_seq(&mut ctx), 1234);
+    }
+
+    #[test]
+    fn partition()
+    {
+        let fs = mmp_fs(test_sb());
+        let cfg = Config { report_only: false, ..Config::default() };
+
+        // The file system starts at block 2; the window is lifted before the release.
+        let mut drive = vec![0u8; 2 * BLOCK_SIZE];
+        drive.extend(test_drive(MMP_SEQ_CLEAN));
+        let mut ctx = test_context(&drive);
+        let window = Window { offset: 2 * BLOCK_SIZE as u64, len: 16 * BLOCK_SIZE as u64 };
+        ctx.drive.set_window(Some(window)).unwrap();
+
+        let claimed = claim(&fs, &mut ctx, &cfg, &mut no_wait).unwrap();
+        assert_eq!(read_seq(&mut ctx), MMP_SEQ_FSCK);
+
+        ctx.drive.set_window(None).unwrap();
+        release(claimed, &mut ctx).unwrap();
+        assert_eq!(ctx.drive.window(), None);
+
+        ctx.drive.set_window(Some(window)).unwrap();
+        assert_eq!(read_seq(&mut ctx), MMP_SEQ_CLEAN);
+    }
+
+    #[test]
+    fn checksum()
+    {
+        let fs = mmp_fs(SuperBlock { s_feature_ro_compat: 0x400, ..test_sb() });
+        let cfg = Config { report_only: false, ..Config::default() };
+        let seed = mmp_csum_seed(&fs).unwrap();
+
+        let mut drive = test_drive(MMP_SEQ_CLEAN);
+        let mut ctx = test_context(&drive);
+        assert!(claim(&fs, &mut ctx, &cfg, &mut no_wait).is_err());
+
+        let mmp = &mut drive[10 * BLOCK_SIZE..10 * BLOCK_SIZE + MMP_SIZE];
+        let checksum = mmp_checksum(mmp, seed);
+        write_u32(mmp, MMP_CHECKSUM_OFFSET, checksum);
+        let mut ctx = test_context(&drive);
+        let claimed = claim(&fs, &mut ctx, &cfg, &mut no_wait).unwrap();
+
+        release(claimed, &mut ctx).unwrap();
+        read_mmp(10 * BLOCK_SIZE as u64, Some(seed), &mut ctx).unwrap();
+    }
+}
@@ -1,17 +1,36 @@
 #![allow(dead_code)]
 use std::io::{Read, Seek, SeekFrom};
+use std::time::{Duration, Instant};
 use anyhow::bail;
 use bincode::{DefaultOptions, Options};
 use serde::{Deserialize, Serialize};
 
+use crate::anomaly::AnomalyKind;
 use crate::array::Array;
 use crate::bitmap::Bitmap;
 use crate::hilo;
-use crate::usage_map::{AllocStatus, UsageMap};
+use crate::interrupt;
+use crate::map_format::FsIdentity;
+use crate::usage_map::{AllocStatus, Segment, UsageMap};
 use crate::{Config, Context};
-
+use backup::StaleBackups;
+
+pub mod backup;
+mod checkpoint;
+mod crosscheck;
+mod csum;
+mod dir;
+pub mod estimate;
 mod extent;
+mod flex;
+pub mod freecount;
 mod inode;
+pub mod layout;
+pub mod mmp;
+mod orphan;
+mod prefetch;
+pub mod scrub;
+mod xattr;
 #[macro_use]
 mod macros;
 
@@ -144,7 +163,7 @@ pub struct SuperBlock {
 
 /// The Ext2/3/4 group descriptor structure.
 /// Reference: https://elixir.bootlin.com/linux/latest/source/fs/ext4/ext4.h
-#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
 pub struct GroupDescriptor {
     pub bg_block_bitmap_lo: u32,
     pub bg_inode_bitmap_lo: u32,
@@ -173,6 +192,8 @@ pub struct GroupDescriptor {
 }
 
 const GROUP_DESC_STRUCT_SIZE: usize = 64;
+/// The offset of `bg_checksum` in the group descriptor.
+const DESC_CHECKSUM_OFFSET: usize = 0x1e;
 
 // FIXME: Debug is derived.
 /// Group descriptor flags (bg_flags).
@@ -437,8 +458,9 @@ impl Flags {
 
 /// Encryption algorithms.
 /// Reference: https://elixir.bootlin.com/linux/latest/source/fs/ext4/ext4.h
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, Default)]
 pub enum EncryptAlgo {
+    #[default]
     Null,
     AES256XTS,
     AES256GCM,
@@ -447,13 +469,6 @@ pub enum EncryptAlgo {
     AES256CTS,
 }
 
-impl Default for EncryptAlgo {
-    fn default() -> Self
-    {
-        Self::Null
-    }
-}
-
 /// Filesystem parameters.
 /// This structure contains all the relevant information about the filesystem. This includes
 /// important data structures and decoded values.
@@ -463,6 +478,7 @@ pub struct Fs {
     pub desc_table: Vec<u8>,
     pub opts: FsOptions,
     // -- computed values --
+    pub blocks_count: u64,
     pub bg_count: u64,
     pub bg_size: u64,
     pub desc_size: u64,
@@ -490,8 +506,23 @@ pub struct DynConfig {
     pub compat: CompatFeatures,
     pub incompat: IncompatFeatures,
     pub ro_compat: RoCompatFeatures,
+    /// The filename encoding of the casefolded directories, with casefold.
+    pub encoding: Option<Encoding>,
+}
+
+/// The filename encoding of a file system with casefold (s_encoding and s_encoding_flags).
+/// Reference: https://elixir.bootlin.com/linux/latest/source/fs/ext4/super.c
+#[derive(Copy, Clone, Debug, Serialize, PartialEq, Eq)]
+pub struct Encoding {
+    pub name: &'static str,
+    /// Names that are invalid in the encoding are refused, instead of being handled as opaque
+    /// byte sequences.
+    pub strict: bool,
 }
 
+const EXT4_ENC_UTF8_12_1: u16 = 1;
+const EXT4_ENC_STRICT_MODE_FL: u16 = 0x1;
+
 /// Configuration for systems with journaling support.
 #[derive(Copy, Clone, Debug)]
 pub struct JournalConfig {
@@ -506,8 +537,84 @@ pub struct Bit64Config {
     pub encrypt_algos: Option<[EncryptAlgo; 4]>,
 }
 
-/// Process an Ext2/3/4 file system.
-pub fn scan_drive(ctx: &mut Context, cfg: &Config) -> anyhow::Result<UsageMap>
+impl Fs {
+    /// Creates the file system parameters from a superblock.
+    /// The superblock is checked for invalid or unsupported configuration. The group descriptor
+    /// table is left empty.
+    pub fn new(sb: SuperBlock, cfg: &Config) -> anyhow::Result<Self>
+    {
+        let opts = get_and_check_fs_options(&sb, cfg)?;
+
+        // Computing values that will be needed across multiple procedures.
+
+        let blocks_count = if opts.bit64_cfg.is_some() {
+            hilo!(sb.s_blocks_count_hi, sb.s_blocks_count_lo)
+        } else {
+            sb.s_blocks_count_lo as u64
+        };
+        if sb.s_first_data_block as u64 >= blocks_count {
+            bail!(
+                "the first data block ({}) lies beyond the file system ({} blocks)",
+                sb.s_first_data_block,
+                blocks_count,
+            );
+        }
+        let mut bg_count = (blocks_count - sb.s_first_data_block as u64) / sb.s_blocks_per_group as u64;
+        if !(blocks_count - sb.s_first_data_block as u64).is_multiple_of(sb.s_blocks_per_group as u64) {
+            bg_count += 1;
+        }
+        let bg_size = sb.s_blocks_per_group as u64 * bs!(sb.s_log_block_size);
+        let desc_size = if sb.s_desc_size == 0 {
+            32
+        } else {
+            sb.s_desc_size as u64
+        };
+        let inode_size = if opts.dyn_cfg.is_some() {
+            sb.s_inode_size as u64
+        } else {
+            GOOD_OLD_INODE_SIZE as u64
+        };
+        let csum_seed = derive_csum_seed(&sb, &opts);
+
+        if let Some(dyn_cfg) = opts.dyn_cfg {
+            if dyn_cfg.ro_compat.has_metadata_csum() && csum_seed.is_none() {
+                bail!("metadata_csum is set, but the checksum seed cannot be derived");
+            }
+        }
+
+        Ok(Self {
+            sb,
+            desc_table: Vec::new(),
+            opts,
+            blocks_count,
+            bg_count,
+            bg_size,
+            desc_size,
+            inode_size,
+            csum_seed,
+        })
+    }
+}
+
+/// Derives the seed of the crc32c checksums (metadata_csum and ea_inode).
+/// The seed is stored in the superblock with the csum_seed feature; otherwise it is computed from
+/// the UUID. Returns None if none of the features that use the seed is enabled.
+/// Reference: https://github.com/tytso/e2fsprogs/blob/master/lib/ext2fs/csum.c#L33
+fn derive_csum_seed(sb: &SuperBlock, opts: &FsOptions) -> Option<u32>
+{
+    let dyn_cfg = opts.dyn_cfg?;
+
+    if dyn_cfg.incompat.has_csum_seed() {
+        Some(sb.s_checksum_seed)
+    } else if dyn_cfg.ro_compat.has_metadata_csum() || dyn_cfg.incompat.has_ea_inode() {
+        Some(ext4_style_crc32c_le(!0, &sb.s_uuid))
+    } else {
+        None
+    }
+}
+
+/// Reads the superblock and the group descriptor table and checks the file system options.
+fn read_fs(ctx: &mut Context, cfg: &Config) -> anyhow::Result<Fs>
 {
     let bincode_opt = DefaultOptions::new()
         .with_fixint_encoding()
@@ -517,88 +624,507 @@ pub fn scan_drive(ctx: &mut Context, cfg: &Config) -> anyhow::Result<UsageMap>
 
     ctx.drive.seek(SeekFrom::Start(1024))?;
     let sb: SuperBlock = bincode_opt.deserialize_from(&ctx.drive)?;
-    let opts = get_and_check_fs_options(&sb, cfg)?;
+    let mut fs = Fs::new(sb, cfg)?;
 
-    // Computing values that will be needed across multiple procedures.
+    if cfg.force_fs_type {
+        ctx.logger.logln(0, "warning: the file system type is forced: the identifying superblock fields are not validated");
+    }
 
-    let blocks_count = if opts.bit64_cfg.is_some() {
-        hilo!(sb.s_blocks_count_hi, sb.s_blocks_count_lo)
-    } else {
-        sb.s_blocks_count_lo as u64
-    };
-    let mut bg_count = (blocks_count - sb.s_first_data_block as u64) / sb.s_blocks_per_group as u64;
-    if (blocks_count - sb.s_first_data_block as u64) % sb.s_blocks_per_group as u64 != 0 {
-        bg_count += 1;
+    if let Some(warning) = creator_os_warning(fs.opts.fs_creator) {
+        ctx.logger.logln(0, &format!("warning: {}", warning));
+    }
+
+    if let Some(encoding) = fs.opts.dyn_cfg.and_then(|dyn_cfg| dyn_cfg.encoding) {
+        ctx.logger.logln(1, &format!(
+            "filename encoding: {}{}",
+            encoding.name,
+            if encoding.strict { " (strict)" } else { "" },
+        ));
+        ctx.encoding = Some(encoding);
+    }
+
+    if has_unknown_incompat(&fs) {
+        ctx.logger.logln(0, &format!(
+            "WARNING: unknown `s_feature_incompat` flags: {:#010x}: only the block bitmaps are \
+            scanned; everything they do not mark as free is reported as used",
+            fs.sb.s_feature_incompat,
+        ));
     }
-    let bg_size = sb.s_blocks_per_group as u64 * bs!(sb.s_log_block_size);
-    let desc_size = if sb.s_desc_size == 0 {
-        32
-    } else {
-        sb.s_desc_size as u64
-    };
-    let inode_size = if opts.dyn_cfg.is_some() {
-        sb.s_inode_size as u64
-    } else {
-        GOOD_OLD_INODE_SIZE as u64
-    };
-    // Reference: https://github.com/tytso/e2fsprogs/blob/master/lib/ext2fs/csum.c#L33
-    let csum_seed = if let Some(dyn_cfg) = opts.dyn_cfg {
-        if dyn_cfg.incompat.has_csum_seed() {
-            Some(sb.s_checksum_seed)
-        } else if dyn_cfg.ro_compat.has_metadata_csum() || dyn_cfg.incompat.has_ea_inode() {
-            Some(ext4_style_crc32c_le(!0, &sb.s_uuid))
-        } else {
-            None
-        }
-    } else {
-        None
-    };
 
     // Reading the raw group descriptor table from the disk.
 
-    let mut desc_table = vec![
+    fs.desc_table = vec![
         u8::default();
-        bg_count as usize * alloc_desc_size!(desc_size)
+        fs.bg_count as usize * alloc_desc_size!(fs.desc_size)
     ];
-    ctx.drive.seek(SeekFrom::Start(start_of_first_gdt(&sb)))?;
+    ctx.drive.seek(SeekFrom::Start(start_of_first_gdt(&fs.sb)))?;
     // FIXME: This could fail if the descriptor is smaller than GROUP_DESC_STRUCT_SIZE and it is
     // located at the end of the disk. The read operation would then attempt to reach beyond the
     // end of the disk.
-    ctx.drive.read_exact(&mut desc_table)?;
-
-    let fs = Fs {
-        sb,
-        desc_table,
-        opts,
-        bg_count,
-        bg_size,
-        desc_size,
-        inode_size,
-        csum_seed,
-    };
+    ctx.drive.read_exact(&mut fs.desc_table)?;
+
+    Ok(fs)
+}
+
+/// Estimates the free space of an Ext2/3/4 file system from the group descriptors, without
+/// scanning it.
+pub fn quick_estimate(ctx: &mut Context, cfg: &Config) -> anyhow::Result<estimate::Estimate>
+{
+    let fs = read_fs(ctx, cfg)?;
+
+    estimate::estimate_free(&fs)
+}
+
+/// Process an Ext2/3/4 file system.
+pub fn scan_drive(ctx: &mut Context, cfg: &Config) -> anyhow::Result<UsageMap>
+{
+    let fs = read_fs(ctx, cfg)?;
+    ctx.fs_identity = Some(FsIdentity {
+        uuid: crate::util::format_uuid(&fs.sb.s_uuid),
+        write_time: hilo!(fs.sb.s_wtime_hi, fs.sb.s_wtime),
+    });
+
+    // Nothing may be written before the file system is claimed.
+    if has_mmp(&fs) {
+        mmp::start(&fs, ctx, cfg)?;
+    }
+
+    if cfg.check_backups {
+        for divergence in backup::check_backups(&fs, ctx)? {
+            ctx.logger.logln(0, &format!(
+                "warning: backup superblock in group {} differs from the primary: {}",
+                divergence.bg_num,
+                divergence.fields.join(", "),
+            ));
+            ctx.anomalies.record(AnomalyKind::BackupSuperblockMismatch);
+        }
+    }
+
+    check_stale_backups(&fs, ctx, cfg)?;
+    check_metadata_placement(&fs, ctx, cfg)?;
 
     // Scan the drive free space on  the drive and return the usage map.
 
+    let scan_start = Instant::now();
     let free_blocks = scan_free_space(&fs, ctx, cfg)?;
+    ctx.logger.logln(1, &format!(
+        "scanned the block groups in {:.2?} (read-ahead {}, {} I/O)",
+        scan_start.elapsed(),
+        if cfg.readahead { "on" } else { "off" },
+        cfg.io_engine,
+    ));
+
+    let summary = layout::summarize(&free_blocks, &fs)?;
+    ctx.logger.logln(1, &format!(
+        "space summary: metadata (fixed): {} bytes, reserved for growth: {} bytes, file data: {} bytes",
+        summary.metadata,
+        summary.growth,
+        summary.data,
+    ));
+    ctx.space = Some(summary);
+
+    check_free_counts(&free_blocks, &fs, ctx)?;
+
+    if cfg.cross_check {
+        check_bitmaps(&free_blocks, &fs, ctx, cfg)?;
+    }
+    if cfg.scrub_inode_table {
+        scrub_inode_tables(&fs, ctx, cfg)?;
+    }
 
     Ok(free_blocks)
 }
 
+/// Compares the free space of the scanned map against the free counts of the file system. The
+/// counts are only checked; a mismatch is logged and counted among the scan anomalies.
+fn check_free_counts(map: &UsageMap, fs: &Fs, ctx: &mut Context) -> anyhow::Result<()>
+{
+    let counts = freecount::check_free_counts(map, fs)?;
+    if counts.is_consistent() {
+        ctx.logger.logln(1, "free counts: consistent with the scan");
+    }
+
+    for group in &counts.groups {
+        ctx.logger.logln(0, &format!(
+            "warning: group {} has {} free bytes, but its descriptor records {}",
+            group.bg_num,
+            group.scanned,
+            group.recorded,
+        ));
+        ctx.anomalies.record(AnomalyKind::FreeCountMismatch);
+    }
+    if counts.scanned != counts.recorded {
+        ctx.logger.logln(0, &format!(
+            "warning: the scan found {} free bytes, but the superblock records {}",
+            counts.scanned,
+            counts.recorded,
+        ));
+        ctx.anomalies.record(AnomalyKind::FreeCountMismatch);
+    }
+
+    ctx.free_counts = Some(counts);
+
+    Ok(())
+}
+
+/// Collects the zeroing of the deleted inodes in the inode tables, for the fill, or only counts them
+/// in the report-only mode.
+fn scrub_inode_tables(fs: &Fs, ctx: &mut Context, cfg: &Config) -> anyhow::Result<()>
+{
+    if has_unknown_incompat(fs) {
+        ctx.logger.logln(0, "warning: the inodes are not scanned: skipping the inode table scrub");
+        return Ok(());
+    }
+
+    let mut count = 0;
+    for bg_num in 0..fs.bg_count {
+        count += scrub::scrub_inode_table(bg_num, fs, ctx, cfg)?;
+    }
+
+    if cfg.report_only {
+        ctx.logger.logln(0, &format!(
+            "{} deleted inode(s) hold residual metadata; not scrubbed in the report-only mode",
+            count,
+        ));
+    } else {
+        ctx.logger.logln(0, &format!("{} deleted inode(s) to scrub before the fill", count));
+    }
+
+    Ok(())
+}
+
+/// Cross-checks the scanned map against the block bitmaps. Any discrepancy means that either is
+/// wrong, so the file system is not filled.
+fn check_bitmaps(map: &UsageMap, fs: &Fs, ctx: &mut Context, cfg: &Config) -> anyhow::Result<()>
+{
+    if has_unknown_incompat(fs) {
+        ctx.logger.logln(0, "warning: the map comes from the block bitmaps: skipping the cross-check");
+        return Ok(());
+    }
+
+    let discrepancies = crosscheck::cross_check(map, fs, ctx, cfg)?;
+
+    for d in &discrepancies {
+        let what = match d.kind {
+            crosscheck::DiscrepancyKind::Unallocated => "are in use, but free in the block bitmap",
+            crosscheck::DiscrepancyKind::Unreferenced => "are allocated, but not referenced",
+        };
+        ctx.logger.logln(0, &format!(
+            "warning: blocks {}-{} {}",
+            d.block,
+            d.block + d.len - 1,
+            what,
+        ));
+        ctx.anomalies.record(AnomalyKind::BitmapDiscrepancy);
+    }
+
+    if !discrepancies.is_empty() && !cfg.report_only {
+        bail!(
+            "the block bitmaps disagree with the scan in {} place(s): refusing to fill",
+            discrepancies.len(),
+        );
+    }
+
+    Ok(())
+}
+
+/// Verifies the backup superblocks, which are marked as used by the scan, and collects the refreshes
+/// of the stale ones if configured to.
+fn check_stale_backups(fs: &Fs, ctx: &mut Context, cfg: &Config) -> anyhow::Result<()>
+{
+    for bg_num in 1..fs.bg_count {
+        if !has_super_copy(bg_num, fs) {
+            continue;
+        }
+
+        let reason = match backup::verify_backup(bg_num, fs, ctx)? {
+            Some(reason) => reason,
+            None => continue,
+        };
+
+        ctx.logger.logln(0, &format!(
+            "warning: backup superblock in group {} is stale ({})",
+            bg_num,
+            reason,
+        ));
+        ctx.anomalies.record(AnomalyKind::StaleBackupSuperblock);
+
+        if cfg.stale_backups == StaleBackups::Refresh {
+            if cfg.report_only {
+                ctx.logger.logln(0, "the stale backup is not refreshed in the report-only mode");
+            } else {
+                backup::refresh_backup(bg_num, fs, ctx)?;
+                ctx.logger.logln(1, &format!(
+                    "the backup superblock in group {} is refreshed before the fill",
+                    bg_num,
+                ));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Checks that the group metadata lies within the expected (flex) groups. The metadata is mapped
+/// from the descriptors regardless; misplaced metadata is flagged, or rejected in strict mode.
+fn check_metadata_placement(fs: &Fs, ctx: &mut Context, cfg: &Config) -> anyhow::Result<()>
+{
+    for misplaced in flex::check_placement(fs)? {
+        let msg = format!(
+            "the {} of group {} lies outside of its {}group: block {}",
+            misplaced.what,
+            misplaced.bg_num,
+            if has_flex_bg(fs) { "flex " } else { "" },
+            misplaced.block,
+        );
+
+        if cfg.strict {
+            bail!("{}", msg);
+        }
+        ctx.logger.logln(0, &format!("warning: {}", msg));
+        ctx.anomalies.record(AnomalyKind::MisplacedGroupMetadata);
+    }
+
+    Ok(())
+}
+
+/// Checks whether the metadata of the groups is packed into flex groups.
+fn has_flex_bg(fs: &Fs) -> bool
+{
+    match fs.opts.dyn_cfg {
+        Some(dyn_cfg) => dyn_cfg.incompat.has_flex_bg(),
+        None => false,
+    }
+}
+
+/// The minimum time between two saves of the scan checkpoint.
+const CHECKPOINT_PERIOD: Duration = Duration::from_secs(30);
+
+/// Scans a block group and updates the supplied UsageMap.
+type GroupScanner = fn(&mut UsageMap, u64, &Fs, &mut Context, &Config) -> anyhow::Result<()>;
+
 /// Scans the drive for free space and returns a map of the usage.
-fn scan_free_space(fs: &Fs, ctx: &mut Context, _cfg: &Config) -> anyhow::Result<UsageMap>
+fn scan_free_space(fs: &Fs, ctx: &mut Context, cfg: &Config) -> anyhow::Result<UsageMap>
+{
+    if has_unknown_incompat(fs) {
+        return scan_groups(scan_bitmap_bg, fs, ctx, cfg);
+    }
+
+    let mut map = scan_groups(scan_regular_bg, fs, ctx, cfg)?;
+    if has_resize_inode(fs) {
+        scan_resize_inode(&mut map, fs, ctx, cfg)?;
+    }
+    // The MMP block is not referenced by any inode.
+    if has_mmp(fs) {
+        let block_size = bs!(fs.sb.s_log_block_size);
+        map.update(fs.sb.s_mmp_block * block_size, block_size, AllocStatus::Used);
+    }
+    if has_bigalloc(fs) {
+        round_to_clusters(&mut map, fs);
+    }
+
+    Ok(map)
+}
+
+/// Checks whether the file system reserves blocks for the growth of the descriptor tables.
+fn has_resize_inode(fs: &Fs) -> bool
+{
+    match fs.opts.dyn_cfg {
+        Some(dyn_cfg) => dyn_cfg.compat.has_resize_inode(),
+        None => false,
+    }
+}
+
+/// Scans the resize inode explicitly, regardless of the inode bitmap.
+/// Its chain reserves the blocks into which the descriptor tables grow on an online resize, which
+/// must survive the fill even if the bitmap does not mark the inode as used.
+fn scan_resize_inode(map: &mut UsageMap, fs: &Fs, ctx: &mut Context, cfg: &Config)
+    -> anyhow::Result<()>
+{
+    let block_size = bs!(fs.sb.s_log_block_size);
+    let inodes_per_group = fs.sb.s_inodes_per_group as u64;
+    let bg_num = (inode::RESIZE_INO - 1) / inodes_per_group;
+    let idx = (inode::RESIZE_INO - 1) % inodes_per_group;
+
+    let has_csum = match fs.opts.dyn_cfg {
+        Some(dyn_cfg) => dyn_cfg.ro_compat.has_metadata_csum() || dyn_cfg.ro_compat.has_gdt_csum(),
+        None => false,
+    };
+    let desc = fetch_regular_bg_descriptor(bg_num, fs)?;
+    if has_csum && !verify_desc_csum(&desc, bg_num, fs)? {
+        return Ok(());
+    }
+
+    let inode_table_block = if fs.opts.bit64_cfg.is_some() {
+        hilo!(desc.bg_inode_table_hi, desc.bg_inode_table_lo)
+    } else {
+        desc.bg_inode_table_lo as u64
+    };
+    map.update(
+        inode_table_block * block_size + idx * fs.inode_size,
+        fs.inode_size,
+        AllocStatus::Used,
+    );
+
+    let mut itable = vec![
+        u8::default();
+        fs.sb.s_inodes_per_group as usize * alloc_inode_size!(fs.inode_size)
+    ];
+    inode::read_itable(bg_num, &mut itable, fs, ctx)?;
+
+    inode::scan_inode(map, idx as usize, bg_num, &mut itable, fs, ctx, cfg)
+}
+
+/// Checks whether the file system allocates its space in clusters of multiple blocks.
+fn has_bigalloc(fs: &Fs) -> bool
+{
+    match fs.opts.dyn_cfg {
+        Some(dyn_cfg) => dyn_cfg.ro_compat.has_bigalloc(),
+        None => false,
+    }
+}
+
+/// Checks whether the file system has multi-mount protection.
+fn has_mmp(fs: &Fs) -> bool
+{
+    match fs.opts.dyn_cfg {
+        Some(dyn_cfg) => dyn_cfg.incompat.has_mmp(),
+        None => false,
+    }
+}
+
+/// Extends the used space to whole clusters.
+/// With bigalloc, the space is allocated in clusters: the blocks of a partially used cluster are
+/// still allocated to its owner (e.g. the rest of the last cluster of a file, or of the cluster
+/// holding a bitmap) and are not free.
+fn round_to_clusters(map: &mut UsageMap, fs: &Fs)
+{
+    let cluster_size = bs!(fs.sb.s_log_cluster_size);
+    let size = map.size();
+
+    let used: Vec<Segment> = map.iter_used()
+        .map(|s| Segment {
+            start: s.start / cluster_size * cluster_size,
+            end: std::cmp::min(s.end.div_ceil(cluster_size) * cluster_size, size),
+            status: AllocStatus::Used,
+        })
+        .collect();
+
+    map.extend_from(used);
+}
+
+/// Checks whether the file system has incompatible features that are not known.
+fn has_unknown_incompat(fs: &Fs) -> bool
+{
+    match fs.opts.dyn_cfg {
+        Some(dyn_cfg) => dyn_cfg.incompat.has_unknown(),
+        None => false,
+    }
+}
+
+/// Scans all the block groups with the supplied scanner and returns a map of the usage.
+/// With a scan checkpoint configured, the scan resumes from the saved progress and the progress
+/// is saved periodically and when the scan fails or is interrupted. The checkpoint is removed once the scan
+/// completes.
+fn scan_groups(
+    scan_bg: GroupScanner,
+    fs: &Fs,
+    ctx: &mut Context,
+    cfg: &Config,
+) -> anyhow::Result<UsageMap>
 {
     let drive_size = ctx.drive.seek(SeekFrom::End(0))?;
     let mut map = UsageMap::new(drive_size);
+    let mut first_bg = 0;
+
+    let fs_size = fs.blocks_count * bs!(fs.sb.s_log_block_size);
+    let scanned = |num: u64| std::cmp::min((num + 1) * fs.bg_size, fs_size);
+    ctx.progress.start("scan", fs_size);
+
+    let path = match &cfg.scan_checkpoint {
+        Some(path) => path,
+        None => {
+            for num in 0..fs.bg_count {
+                if interrupt::requested() {
+                    bail!("the scan was interrupted");
+                }
+                read_ahead(num + 1, fs, ctx, cfg);
+                ctx.progress.set_group(Some(num));
+                scan_bg(&mut map, num, fs, ctx, cfg)?;
+                ctx.progress.update(scanned(num));
+            }
+            ctx.progress.finish();
 
-    for num in 0..fs.bg_count {
-        scan_regular_bg(&mut map, num, fs, ctx)?;
+            return Ok(map);
+        }
+    };
+
+    if let Some(checkpoint) = checkpoint::load(path, fs, drive_size)? {
+        ctx.logger.logln(0, &format!(
+            "resuming the scan from block group {}",
+            checkpoint.next_bg,
+        ));
+        map = checkpoint.map;
+        first_bg = checkpoint.next_bg;
+    }
+
+    let mut last_save = Instant::now();
+
+    for num in first_bg..fs.bg_count {
+        if interrupt::requested() {
+            checkpoint::save(path, num, &map, fs)?;
+            bail!("the scan was interrupted");
+        }
+        read_ahead(num + 1, fs, ctx, cfg);
+        ctx.progress.set_group(Some(num));
+        if let Err(e) = scan_bg(&mut map, num, fs, ctx, cfg) {
+            // The map may be partially updated by the failed group, which is harmless, as the
+            // group is scanned again on resume.
+            checkpoint::save(path, num, &map, fs)?;
+            return Err(e);
+        }
+        ctx.progress.update(scanned(num));
+
+        if last_save.elapsed() >= CHECKPOINT_PERIOD {
+            ctx.logger.logln(1, &format!("saving the scan checkpoint: [{} / {}]", num + 1, fs.bg_count));
+            checkpoint::save(path, num + 1, &map, fs)?;
+            last_save = Instant::now();
+        }
+    }
+
+    ctx.progress.finish();
+
+    if let Err(e) = std::fs::remove_file(path) {
+        if e.kind() != std::io::ErrorKind::NotFound {
+            return Err(e.into());
+        }
     }
 
     Ok(map)
 }
 
+/// Reads the metadata of a block group ahead, so that its reads overlap with the scan of the
+/// previous group. Does nothing past the last group or with the read-ahead disabled.
+fn read_ahead(bg_num: u64, fs: &Fs, ctx: &mut Context, cfg: &Config)
+{
+    if !cfg.readahead || bg_num >= fs.bg_count {
+        return;
+    }
+
+    // The read-ahead is only a hint; a bad descriptor is dealt with by the scan itself.
+    if let Ok(ranges) = prefetch::group_ranges(bg_num, fs) {
+        prefetch::read(&ctx.drive, &ranges, cfg.io_engine);
+    }
+}
+
 /// Processes a regular block group, scans the free space and updates the supplied UsageMap.
-fn scan_regular_bg(map: &mut UsageMap, bg_num: u64, fs: &Fs, ctx: &mut Context) -> anyhow::Result<()>
+fn scan_regular_bg(
+    map: &mut UsageMap,
+    bg_num: u64,
+    fs: &Fs,
+    ctx: &mut Context,
+    cfg: &Config,
+) -> anyhow::Result<()>
 {
     ctx.logger.logln(1, &format!("scanning block group: [{} / {}]", bg_num + 1, fs.bg_count));
 
@@ -612,42 +1138,28 @@ fn scan_regular_bg(map: &mut UsageMap, bg_num: u64, fs: &Fs, ctx: &mut Context)
         Some(dyn_cfg) => dyn_cfg.ro_compat.has_metadata_csum() || dyn_cfg.ro_compat.has_gdt_csum(),
         None => false,
     };
-    let mut skip_super = false;
-
-    // Check if we skip the superblock and gdt.
-    if let Some(dyn_cfg) = fs.opts.dyn_cfg {
-        // Sparse_super2 is more aggressive, so we need to check that first.
-        if dyn_cfg.compat.has_sparse_super2() {
-            if bg_num != 0
-                && bg_num != fs.sb.s_backup_bgs[0] as u64
-                && bg_num != fs.sb.s_backup_bgs[1] as u64
-            {
-                skip_super = true;
-            }
-        } else if dyn_cfg.ro_compat.has_sparse_super()
-            && bg_num != 0
-            && bg_num % 3 != 0
-            && bg_num % 5 != 0
-            && bg_num % 7 != 0
-        {
-            skip_super = true;
-        }
-    }
+    // Stale backups are left to the fill if they are to be ignored.
+    let skip_super = !has_super_copy(bg_num, fs) || (
+        bg_num != 0
+        && cfg.stale_backups == StaleBackups::Ignore
+        && backup::verify_backup(bg_num, fs, ctx)?.is_some()
+    );
 
     // Scan the group's super block group descriptors.
 
     if !skip_super {
-        let gdt_start: u64;
-
         // The superblock.
-        if bg_num == 0 {
-            // The empty space at the beginning of the drive and the superblock.
-            map.update(0, 2048, AllocStatus::Used);
-            gdt_start = start_of_first_gdt(&fs.sb);
+        let gdt_start = if bg_num == 0 {
+            // The empty space at the beginning of the drive and the superblock. Should the first
+            // group start past the superblock, the blocks before it and its first block (reserved
+            // for a superblock copy) are used as well.
+            let reserved_end = if bg_start >= 2048 { bg_start + block_size } else { 2048 };
+            map.update(0, reserved_end, AllocStatus::Used);
+            start_of_first_gdt(&fs.sb)
         } else {
             map.update(bg_start, 1024, AllocStatus::Used);
-            gdt_start = bg_start + block_size;
-        }
+            bg_start + block_size
+        };
 
         // The group descriptors.
         // If the file system has a checksum support, the group descriptors have to be checked and
@@ -683,19 +1195,29 @@ fn scan_regular_bg(map: &mut UsageMap, bg_num: u64, fs: &Fs, ctx: &mut Context)
             // Without checksumming, the whole descriptor table must be initialised.
             map.update(gdt_start, fs.bg_count * fs.desc_size, AllocStatus::Used);
         }
+
+        // The blocks reserved for the growth of the descriptor table (resize_inode) follow it.
+        // Online resizing takes them over as they are, so they must not be filled.
+        if has_resize_inode(fs) {
+            let gdt_blocks = (fs.bg_count * fs.desc_size).div_ceil(block_size);
+            map.update(
+                gdt_start + gdt_blocks * block_size,
+                fs.sb.s_reserved_gdt_blocks as u64 * block_size,
+                AllocStatus::Used,
+            );
+        }
     }
 
     let desc = fetch_regular_bg_descriptor(bg_num, fs)?;
 
     // Do not process groups with invalid descriptors.
-    if has_csum {
-        if !verify_desc_csum(&desc, bg_num, fs)? {
-            ctx.logger.logln(1, &format!("group descriptor {} has invalid checksum", bg_num));
-            return Ok(());
-        }
+    if has_csum && !verify_desc_csum(&desc, bg_num, fs)? {
+        ctx.logger.logln(1, &format!("group descriptor {} has invalid checksum", bg_num));
+        ctx.anomalies.record(AnomalyKind::BadDescriptorChecksum);
+        return Ok(());
     }
 
-    let bg_flags = BgFlags { 0: desc.bg_flags };
+    let bg_flags = BgFlags(desc.bg_flags);
 
     if bg_flags.has_unknown() {
         ctx.logger.logln(0, &format!("group descriptor {} has unknown flags: {}", bg_num, bg_flags.get_unknown()));
@@ -739,17 +1261,13 @@ fn scan_regular_bg(map: &mut UsageMap, bg_num: u64, fs: &Fs, ctx: &mut Context)
     };
 
     // Inode table.
+    // If the table has not been zeroed, only the used inodes are marked, below.
     if bg_flags.has_inode_zeroed() {
         map.update(
             inode_table_block * block_size,
             fs.sb.s_inodes_per_group as u64 * fs.inode_size,
             AllocStatus::Used,
         );
-    } else if !bg_flags.has_inode_uninit() {
-        // TODO: Non-zeroed but used inode tables.
-        // In the case where both inode_zeroed and inode_uninit flags are not present, the
-        // inode table needs to be filled inode-by-inode, according to the inode bitmap.
-        bail!("non-zeroed, but used, inode tables are not supported yet")
     }
 
     // Processing the inodes.
@@ -757,8 +1275,16 @@ fn scan_regular_bg(map: &mut UsageMap, bg_num: u64, fs: &Fs, ctx: &mut Context)
     if !bg_flags.has_inode_uninit() {
         // Read and deserialise the inode bitmap.
 
+        let mut raw_bmp = vec![0u8; block_size as usize];
         ctx.drive.seek(SeekFrom::Start(inode_bitmap_block * block_size))?;
-        let i_bmp = Bitmap::from_reader(&mut ctx.drive, block_size as usize)?;
+        ctx.drive.read_exact(&mut raw_bmp)?;
+
+        // A corrupt bitmap could hide used inodes, and the blocks that they own.
+        if csum::has_metadata_csum(fs) && !csum::verify_inode_bitmap(&raw_bmp, &desc, fs)? {
+            let what = format!("the inode bitmap of group {}", bg_num);
+            csum::csum_error(&what, AnomalyKind::BadBitmapChecksum, ctx, cfg)?;
+        }
+        let i_bmp = Bitmap::from_bytes(&raw_bmp);
 
         // Read the raw inode table.
 
@@ -771,7 +1297,15 @@ fn scan_regular_bg(map: &mut UsageMap, bg_num: u64, fs: &Fs, ctx: &mut Context)
         // Scan the inodes.
         for i in 0..fs.sb.s_inodes_per_group as usize {
             if i_bmp.check_bit(i) {
-                inode::scan_inode(map, i, bg_num, &mut itable, fs, ctx)?;
+                if !bg_flags.has_inode_zeroed() {
+                    map.update(
+                        inode_table_block * block_size + i as u64 * fs.inode_size,
+                        fs.inode_size,
+                        AllocStatus::Used,
+                    );
+                }
+
+                inode::scan_inode(map, i, bg_num, &mut itable, fs, ctx, cfg)?;
             }
         }
     }
@@ -779,31 +1313,151 @@ fn scan_regular_bg(map: &mut UsageMap, bg_num: u64, fs: &Fs, ctx: &mut Context)
     Ok(())
 }
 
-/// Fetches a block group descriptor, based on the number of the block group.
-/// Descriptors are read from the first block group. This procedure assumes that the standard
-/// layout (not META_BG) is used.
-fn fetch_regular_bg_descriptor(bg_num: u64, fs: &Fs) -> anyhow::Result<GroupDescriptor>
+/// Processes a block group conservatively, from its block bitmap only, and updates the supplied
+/// UsageMap. The whole group is marked as used, except for the clusters that the bitmap marks as
+/// free. Groups with an uninitialised bitmap or an invalid descriptor stay used, as well as groups
+/// whose bitmap has an invalid checksum (with `--ignore-csum-errors`).
+fn scan_bitmap_bg(
+    map: &mut UsageMap,
+    bg_num: u64,
+    fs: &Fs,
+    ctx: &mut Context,
+    cfg: &Config,
+) -> anyhow::Result<()>
 {
-    let bincode_opt = DefaultOptions::new()
-        .with_fixint_encoding()
-        .allow_trailing_bytes();
-
-    let desc: GroupDescriptor = bincode_opt.deserialize(
-        &fs.desc_table[(bg_num * fs.desc_size) as usize..]
-    )?;
+    ctx.logger.logln(1, &format!("scanning block group bitmap: [{} / {}]", bg_num + 1, fs.bg_count));
 
-    Ok(desc)
-}
+    let block_size = bs!(fs.sb.s_log_block_size);
+    let cluster_size = bs!(fs.sb.s_log_cluster_size);
+    let fs_end = fs.blocks_count * block_size;
+    let bg_start = start_of_bg(bg_num, fs);
+    let bg_end = std::cmp::min(bg_start + fs.bg_size, fs_end);
 
-/// Verifies the checksum of a group descriptor.
-/// Reference: https://github.com/tytso/e2fsprogs/blob/master/lib/ext2fs/csum.c#L716
-fn verify_desc_csum(desc: &GroupDescriptor, bg_num: u64, fs: &Fs) -> anyhow::Result<bool>
-{
-    if fs.opts.dyn_cfg.is_none() {
-        bail!("cannot verify checksum: dyn_cfg is None");
-    }
+    // The space before the first group (the boot block with 1KiB blocks) belongs to no group.
+    let used_start = if bg_num == 0 { 0 } else { bg_start };
+    map.update(used_start, bg_end - used_start, AllocStatus::Used);
 
-    // Get the group descriptor.
+    let desc = fetch_regular_bg_descriptor(bg_num, fs)?;
+    let has_csum = match fs.opts.dyn_cfg {
+        Some(dyn_cfg) => dyn_cfg.ro_compat.has_metadata_csum() || dyn_cfg.ro_compat.has_gdt_csum(),
+        None => false,
+    };
+
+    if has_csum && !verify_desc_csum(&desc, bg_num, fs)? {
+        ctx.logger.logln(1, &format!("group descriptor {} has invalid checksum", bg_num));
+        ctx.anomalies.record(AnomalyKind::BadDescriptorChecksum);
+        return Ok(());
+    }
+    let bg_flags = BgFlags(desc.bg_flags);
+    if bg_flags.has_block_uninit() {
+        return Ok(());
+    }
+
+    let block_bitmap_block = if fs.opts.bit64_cfg.is_some() {
+        hilo!(desc.bg_block_bitmap_hi, desc.bg_block_bitmap_lo)
+    } else {
+        desc.bg_block_bitmap_lo as u64
+    };
+
+    let mut raw_bmp = vec![0u8; block_size as usize];
+    ctx.drive.seek(SeekFrom::Start(block_bitmap_block * block_size))?;
+    ctx.drive.read_exact(&mut raw_bmp)?;
+
+    // The group stays used if the bitmap is corrupt.
+    if csum::has_metadata_csum(fs) && !csum::verify_block_bitmap(&raw_bmp, &desc, fs)? {
+        let what = format!("the block bitmap of group {}", bg_num);
+        csum::csum_error(&what, AnomalyKind::BadBitmapChecksum, ctx, cfg)?;
+        return Ok(());
+    }
+    let b_bmp = Bitmap::from_bytes(&raw_bmp);
+
+    // Mark the runs of free clusters.
+    let clusters = std::cmp::min(fs.sb.s_clusters_per_group as u64, block_size * 8);
+    let mut run_start = None;
+
+    for i in 0..=clusters {
+        let free = i < clusters && !b_bmp.check_bit(i as usize);
+
+        match (free, run_start) {
+            (true, None) => run_start = Some(i),
+            (false, Some(first)) => {
+                let start = bg_start + first * cluster_size;
+                let end = std::cmp::min(bg_start + i * cluster_size, bg_end);
+                if start < end {
+                    map.update(start, end - start, AllocStatus::Free);
+                }
+                run_start = None;
+            },
+            _ => {},
+        }
+    }
+
+    Ok(())
+}
+
+/// Checks whether a block group holds a copy of the superblock and the group descriptor table.
+fn has_super_copy(bg_num: u64, fs: &Fs) -> bool
+{
+    if let Some(dyn_cfg) = fs.opts.dyn_cfg {
+        // Sparse_super2 is more aggressive, so we need to check that first.
+        if dyn_cfg.compat.has_sparse_super2() {
+            if bg_num != 0
+                && bg_num != fs.sb.s_backup_bgs[0] as u64
+                && bg_num != fs.sb.s_backup_bgs[1] as u64
+            {
+                return false;
+            }
+        } else if dyn_cfg.ro_compat.has_sparse_super() {
+            // Only groups 0, 1 and powers of 3, 5 and 7 hold the copies.
+            return bg_num <= 1 || is_power_of(bg_num, 3) || is_power_of(bg_num, 5) || is_power_of(bg_num, 7);
+        }
+    }
+
+    true
+}
+
+/// Checks whether `n` is a power of `base`.
+fn is_power_of(mut n: u64, base: u64) -> bool
+{
+    if n == 0 {
+        return false;
+    }
+
+    while n.is_multiple_of(base) {
+        n /= base;
+    }
+
+    n == 1
+}
+
+/// Fetches a block group descriptor, based on the number of the block group.
+/// Descriptors are read from the first block group. This procedure assumes that the standard
+/// layout (not META_BG) is used.
+fn fetch_regular_bg_descriptor(bg_num: u64, fs: &Fs) -> anyhow::Result<GroupDescriptor>
+{
+    let bincode_opt = DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes();
+
+    let raw = match fs.desc_table.get((bg_num * fs.desc_size) as usize..) {
+        Some(raw) => raw,
+        None => bail!("group descriptor {} is outside of the descriptor table", bg_num),
+    };
+    let desc: GroupDescriptor = bincode_opt.deserialize(raw)?;
+
+    Ok(desc)
+}
+
+/// Verifies the checksum of a group descriptor.
+/// Reference: https://github.com/tytso/e2fsprogs/blob/master/lib/ext2fs/csum.c#L716
+fn verify_desc_csum(desc: &GroupDescriptor, bg_num: u64, fs: &Fs) -> anyhow::Result<bool>
+{
+    let dyn_cfg = match fs.opts.dyn_cfg {
+        Some(dyn_cfg) => dyn_cfg,
+        None => bail!("cannot verify checksum: dyn_cfg is None"),
+    };
+
+    // Get the group descriptor.
 
     let mut desc: GroupDescriptor = *desc;
     let orig_csum = desc.bg_checksum;
@@ -811,32 +1465,45 @@ fn verify_desc_csum(desc: &GroupDescriptor, bg_num: u64, fs: &Fs) -> anyhow::Res
 
     // Compute the checksum.
 
-    if fs.opts.dyn_cfg.unwrap().ro_compat.has_metadata_csum() {
+    if dyn_cfg.ro_compat.has_metadata_csum() {
+        let seed = match fs.csum_seed {
+            Some(seed) => seed,
+            None => bail!("cannot verify checksum: the checksum seed is not derived"),
+        };
+
         let bincode_opt = DefaultOptions::new()
             .with_fixint_encoding()
             .allow_trailing_bytes();
 
         desc.bg_checksum = 0;
         let raw_desc = bincode_opt.serialize(&desc)?;
-        desc.bg_checksum = orig_csum;
 
         let bg_num_raw = [
-            ((bg_num >> 0)  & 0xff) as u8,
+            (bg_num         & 0xff) as u8,
             ((bg_num >> 8)  & 0xff) as u8,
             ((bg_num >> 16) & 0xff) as u8,
             ((bg_num >> 24) & 0xff) as u8,
         ];
 
-        csum = ext4_style_crc32c_le(fs.csum_seed.unwrap(), &bg_num_raw);
+        csum = ext4_style_crc32c_le(seed, &bg_num_raw);
         csum = ext4_style_crc32c_le(csum, &raw_desc[..fs.desc_size as usize]);
-    } else if fs.opts.dyn_cfg.unwrap().ro_compat.has_gdt_csum() {
-        // TODO: support for gdt_csum
-        bail!("gdt_csum is not supported");
+    } else if dyn_cfg.ro_compat.has_gdt_csum() {
+        // The crc16 covers the UUID, the group number and the descriptor, except for the checksum
+        // field itself.
+        let bincode_opt = DefaultOptions::new()
+            .with_fixint_encoding()
+            .allow_trailing_bytes();
+
+        let raw_desc = bincode_opt.serialize(&desc)?;
+        let desc_size = fs.desc_size as usize;
 
-        #[allow(unreachable_code)]
-        if fs.csum_seed.is_none() {
-            bail!("cannot verify checksum: checksum seed is not initialised");
+        let mut crc = ext2_crc16(!0, &fs.sb.s_uuid);
+        crc = ext2_crc16(crc, &(bg_num as u32).to_le_bytes());
+        crc = ext2_crc16(crc, &raw_desc[..DESC_CHECKSUM_OFFSET]);
+        if desc_size > DESC_CHECKSUM_OFFSET + 2 {
+            crc = ext2_crc16(crc, &raw_desc[DESC_CHECKSUM_OFFSET + 2..desc_size]);
         }
+        csum = crc as u32;
     } else {
         bail!("cannot verify checksum: neither of metadata_csum and gdt_csum is set");
     }
@@ -845,25 +1512,61 @@ fn verify_desc_csum(desc: &GroupDescriptor, bg_num: u64, fs: &Fs) -> anyhow::Res
     Ok((csum & 0xffff) as u16 == orig_csum)
 }
 
+/// Returns a warning about the inode fields that are not handled on file systems created by the
+/// specified OS, or None if they all are.
+/// The osd1/osd2 inode fields differ between the creators: the Linux, Hurd and Masix layouts are
+/// handled, while FreeBSD and Lites are assumed to follow the Linux one.
+fn creator_os_warning(creator: FsCreator) -> Option<&'static str>
+{
+    match creator {
+        FsCreator::Linux |
+        FsCreator::Hurd |
+        FsCreator::Masix => None,
+        FsCreator::FreeBSD |
+        FsCreator::Lites => Some(
+            "file system was not created by Linux: the OS-specific inode fields are handled as \
+            on Linux; results are not trusted"
+        ),
+    }
+}
+
+/// Parses the filename encoding of a file system with casefold.
+fn parse_encoding(sb: &SuperBlock) -> anyhow::Result<Encoding>
+{
+    let name = match sb.s_encoding {
+        EXT4_ENC_UTF8_12_1 => "utf8-12.1",
+        _ => bail!("unknown filename encoding: {}", sb.s_encoding),
+    };
+
+    if sb.s_encoding_flags & !EXT4_ENC_STRICT_MODE_FL != 0 {
+        bail!("unknown `s_encoding_flags` flags: {:#06x}", sb.s_encoding_flags);
+    }
+
+    Ok(Encoding {
+        name,
+        strict: sb.s_encoding_flags & EXT4_ENC_STRICT_MODE_FL != 0,
+    })
+}
+
 /// Creates FsConfig from a super block and checks it for invalid or unsupported configuration.
 fn get_and_check_fs_options(sb: &SuperBlock, cfg: &Config) -> anyhow::Result<FsOptions>
 {
     // Constructing enums and flag fields.
 
-    let state = State { 0: sb.s_state };
-    let error_policy = match sb.s_errors {
+    let state = State(sb.s_state);
+    let mut error_policy = match sb.s_errors {
         0 => Some(ErrorPolicy::Null),
         1 => Some(ErrorPolicy::Continue),
         2 => Some(ErrorPolicy::ReadOnly),
         3 => Some(ErrorPolicy::Panic),
         _ => None,
     };
-    let fs_creator = match sb.s_creator_os {
+    let mut fs_creator = match sb.s_creator_os {
         0 => Some(FsCreator::Linux),
         1 => Some(FsCreator::Hurd),
         2 => Some(FsCreator::Masix),
         3 => Some(FsCreator::FreeBSD),
-        4 => Some(FsCreator::Linux),
+        4 => Some(FsCreator::Lites),
         _ => None,
     };
     let revision = match sb.s_rev_level {
@@ -871,15 +1574,9 @@ fn get_and_check_fs_options(sb: &SuperBlock, cfg: &Config) -> anyhow::Result<FsO
         1 => Some(Revision::Dynamic),
         _ => None,
     };
-    let compat = CompatFeatures {
-        0: sb.s_feature_compat,
-    };
-    let incompat = IncompatFeatures {
-        0: sb.s_feature_incompat,
-    };
-    let ro_compat = RoCompatFeatures {
-        0: sb.s_feature_ro_compat,
-    };
+    let compat = CompatFeatures(sb.s_feature_compat);
+    let incompat = IncompatFeatures(sb.s_feature_incompat);
+    let ro_compat = RoCompatFeatures(sb.s_feature_ro_compat);
     let def_hash_version = match sb.s_def_hash_version {
         0 => Some(HashVersion::Legacy),
         1 => Some(HashVersion::HalfMD4),
@@ -890,10 +1587,8 @@ fn get_and_check_fs_options(sb: &SuperBlock, cfg: &Config) -> anyhow::Result<FsO
         6 => Some(HashVersion::SipHash),
         _ => None,
     };
-    let def_mount_opts = DefMountOpts {
-        0: sb.s_default_mount_opts,
-    };
-    let flags = Flags { 0: sb.s_flags };
+    let def_mount_opts = DefMountOpts(sb.s_default_mount_opts);
+    let flags = Flags(sb.s_flags);
     let mut encrypt_algos: [Option<EncryptAlgo>; 4] = Default::default();
     for (a, b) in encrypt_algos.iter_mut().zip(sb.s_encrypt_algos) {
         *a = match b {
@@ -906,9 +1601,21 @@ fn get_and_check_fs_options(sb: &SuperBlock, cfg: &Config) -> anyhow::Result<FsO
         };
     }
 
+    // When the type is forced, the fields that only serve to identify the file system are not
+    // trusted and fall back to their defaults.
+
+    if cfg.force_fs_type {
+        if matches!(error_policy, None | Some(ErrorPolicy::Null)) {
+            error_policy = Some(ErrorPolicy::Continue);
+        }
+        if fs_creator.is_none() {
+            fs_creator = Some(FsCreator::Linux);
+        }
+    }
+
     // Error checking.
 
-    if state.has_unknown() {
+    if state.has_unknown() && !cfg.force_fs_type {
         bail!("unknown `s_state` flags: {:#06x}", state.0);
     }
     // NOTE: the presence of the `valid` flag is not checked.
@@ -958,8 +1665,21 @@ fn get_and_check_fs_options(sb: &SuperBlock, cfg: &Config) -> anyhow::Result<FsO
             bail!("unsupported feature: exclude_bitmap");
         }
 
+        // Unknown incompatible features can change the on-disk format in any way, so only the
+        // block bitmaps are trusted, and only for a report.
         if incompat.has_unknown() {
-            bail!("unknown `s_feature_incompat` flags: {:#010x}", incompat.0);
+            if !cfg.allow_unknown_incompat {
+                bail!(
+                    "unknown `s_feature_incompat` flags: {:#010x} (see --allow-unknown-incompat)",
+                    incompat.get_unknown(),
+                );
+            }
+            if !cfg.report_only {
+                bail!(
+                    "refusing to fill a file system with unknown `s_feature_incompat` flags: {:#010x}",
+                    incompat.get_unknown(),
+                );
+            }
         }
         if incompat.has_recover() && !cfg.ignore_recovery {
             bail!("filesystem needs recovery: try to unmount and/or run fsck on the file system");
@@ -974,8 +1694,8 @@ fn get_and_check_fs_options(sb: &SuperBlock, cfg: &Config) -> anyhow::Result<FsO
         if incompat.has_dirdata() {
             bail!("unsupported feature: dirdata");
         }
-        if incompat.has_encrypt() {
-            bail!("filesystem has encrypted blocks");
+        if incompat.has_encrypt() && !cfg.allow_encrypted {
+            bail!("filesystem has encrypted blocks (see --allow-encrypted)");
         }
 
         if ro_compat.has_unknown() {
@@ -984,13 +1704,13 @@ fn get_and_check_fs_options(sb: &SuperBlock, cfg: &Config) -> anyhow::Result<FsO
         if ro_compat.has_readonly() && !cfg.ignore_readonly {
             bail!("filesystem is marked as read-only");
         }
-        // NOTE: it is unclear what shared_blocks does.
-        // It has to do with allocation and overlapping blocks. It might be viable to perform
-        // operations on the system, as long as the allocation of blocks is not altered.
+        // With shared_blocks, blocks may be referenced by several files (deduplicated images), so
+        // the allocation cannot be altered. The scan marks every referenced block however often it
+        // is referenced, and the fill only writes the free space, so both are viable.
         //
         // Reference: http://lkml.iu.edu/hypermail/linux/kernel/2010.0/04429.html
-        if ro_compat.has_shared_blocks() {
-            bail!("filesystem has shared blocks");
+        if ro_compat.has_shared_blocks() && !cfg.allow_shared_blocks {
+            bail!("filesystem has shared blocks (see --allow-shared-blocks)");
         }
         if ro_compat.has_metadata_csum() && ro_compat.has_gdt_csum() {
             bail!("gdt_csum and metadata_csum cannot be set at the same time");
@@ -1001,19 +1721,43 @@ fn get_and_check_fs_options(sb: &SuperBlock, cfg: &Config) -> anyhow::Result<FsO
         if ro_compat.has_has_snapshot() {
             bail!("unsupported feature: has_snapshot");
         }
-        // TODO: Add support for gdt_csum.
-        if ro_compat.has_gdt_csum() {
-            bail!("unsupported feature: gdt_csum");
+        if ro_compat.has_bigalloc() {
+            // Block maps cannot address clusters.
+            if !incompat.has_extents() {
+                bail!("bigalloc is set without extents");
+            }
+            if sb.s_log_cluster_size < sb.s_log_block_size {
+                bail!(
+                    "invalid cluster size: s_log_cluster_size {} < s_log_block_size {}",
+                    sb.s_log_cluster_size,
+                    sb.s_log_block_size,
+                );
+            }
         }
         // TODO: Add support for verity.
         if ro_compat.has_verity() {
             bail!("unsupported feature: verity");
         }
+        if compat.has_orphan_file() {
+            if sb.s_orphan_file_inum == 0 || sb.s_orphan_file_inum > sb.s_inodes_count {
+                bail!("invalid orphan file inode: {}", sb.s_orphan_file_inum);
+            }
+        } else if ro_compat.has_orphan_present() {
+            bail!("orphan_present is set without orphan_file");
+        }
+
+        // The encoding fields are only defined with casefold.
+        let encoding = if incompat.has_casefold() {
+            Some(parse_encoding(sb)?)
+        } else {
+            None
+        };
 
         fs_opts.dyn_cfg = Some(DynConfig {
             compat,
             incompat,
             ro_compat,
+            encoding,
         });
     }
 
@@ -1063,6 +1807,8 @@ fn get_and_check_fs_options(sb: &SuperBlock, cfg: &Config) -> anyhow::Result<FsO
         if incompat.has_encrypt() {
             let mut algos: [EncryptAlgo; 4] = Default::default();
 
+            // NOTE: the slots are informational; mke2fs fills the first two and leaves the rest
+            // unused (Null). The algorithms of the files are stored in their policies.
             for (i, algo) in encrypt_algos.iter().enumerate() {
                 match algo {
                     None => bail!(
@@ -1070,10 +1816,7 @@ fn get_and_check_fs_options(sb: &SuperBlock, cfg: &Config) -> anyhow::Result<FsO
                         i,
                         sb.s_encrypt_algos[i]
                     ),
-                    Some(EncryptAlgo::Null) => {
-                        bail!("invalid encryption algorithm in `s_encrypt_algos`[{}]", i)
-                    }
-                    _ => algos[i] = algo.unwrap(),
+                    Some(algo) => algos[i] = *algo,
                 }
             }
 
@@ -1093,22 +1836,29 @@ fn ext4_style_crc32c_le(seed: u32, buf: &[u8]) -> u32
     crc::crc32::update(seed ^ (!0), &crc::crc32::CASTAGNOLI_TABLE, buf) ^ (!0u32)
 }
 
+/// The crc16 (ANSI, reflected, no final inversion) of the gdt_csum descriptor checksums.
+/// Reference: https://github.com/tytso/e2fsprogs/blob/master/lib/ext2fs/crc16.c
+fn ext2_crc16(seed: u16, buf: &[u8]) -> u16
+{
+    !crc::crc16::update(!seed, &crc::crc16::USB_TABLE, buf)
+}
+
 /// Calculates the offset of a specified block group.
 fn start_of_bg(bg_num: u64, fs: &Fs) -> u64
 {
     fs.sb.s_first_data_block as u64 * bs!(fs.sb.s_log_block_size) + bg_num * fs.bg_size
 }
 
-/// Returns the offset of the 1st group desriptor table.
+/// Returns the offset of the 1st group desriptor table, in the block after the first group's
+/// superblock. The primary superblock is always at byte 1024: with 1KiB blocks, it is in block 1,
+/// even if the first group starts at block 0 (with bigalloc). Should the first group start past
+/// it, the table follows the first block of the group, as in e2fsprogs.
 fn start_of_first_gdt(sb: &SuperBlock) -> u64
 {
-    // NOTE: s_first_data_block > 1 is not accounted for.
+    let block_size = bs!(sb.s_log_block_size);
+    let sb_block = std::cmp::max(sb.s_first_data_block as u64, 1024 / block_size);
 
-    if bs!(sb.s_log_block_size) == 1024 {
-        2048
-    } else {
-        bs!(sb.s_log_block_size)
-    }
+    (sb_block + 1) * block_size
 }
 
 // Debug implementations.
@@ -1397,3 +2147,706 @@ impl std::fmt::Debug for Flags {
             .finish()
     }
 }
+
+// Tests.
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    /// Creates a superblock of a small, featureless, dynamic revision file system.
+    /// The file system has 4KiB blocks and two block groups of 32 blocks, with 16 inodes each.
+    pub fn test_sb() -> SuperBlock
+    {
+        SuperBlock {
+            s_inodes_count: 32,
+            s_blocks_count_lo: 64,
+            s_first_data_block: 0,
+            s_log_block_size: 2,
+            s_log_cluster_size: 2,
+            s_blocks_per_group: 32,
+            s_clusters_per_group: 32,
+            s_inodes_per_group: 16,
+            s_magic: 0xef53,
+            s_state: 0x1,
+            s_errors: 1,
+            s_rev_level: 1,
+            s_first_ino: 11,
+            s_inode_size: 256,
+            ..SuperBlock::default()
+        }
+    }
+
+    /// Creates file system parameters from a superblock, with the default configuration.
+    pub fn test_fs(sb: SuperBlock) -> Fs
+    {
+        Fs::new(sb, &Config::default()).unwrap()
+    }
+
+    /// Creates file system parameters from a superblock, with the supplied descriptors of the first
+    /// block groups and zeroed descriptors of the rest.
+    pub fn test_fs_with_descs(sb: SuperBlock, descs: &[GroupDescriptor]) -> Fs
+    {
+        let bincode_opt = DefaultOptions::new()
+            .with_fixint_encoding()
+            .allow_trailing_bytes();
+
+        let mut fs = test_fs(sb);
+        // The descriptors are deserialised as whole structures, even when they are shorter.
+        fs.desc_table = vec![0; fs.bg_count as usize * GROUP_DESC_STRUCT_SIZE];
+
+        for (bg_num, desc) in descs.iter().enumerate() {
+            let raw = bincode_opt.serialize(desc).unwrap();
+            let offset = bg_num * fs.desc_size as usize;
+
+            fs.desc_table[offset..offset + fs.desc_size as usize]
+                .copy_from_slice(&raw[..fs.desc_size as usize]);
+        }
+
+        fs
+    }
+
+    #[test]
+    fn fs_new_computed_values()
+    {
+        let fs = test_fs(test_sb());
+
+        assert_eq!(fs.bg_count, 2);
+        assert_eq!(fs.bg_size, 32 * 4096);
+        assert_eq!(fs.desc_size, 32);
+        assert_eq!(fs.inode_size, 256);
+        assert!(fs.csum_seed.is_none());
+    }
+
+    #[test]
+    fn force_fs_type()
+    {
+        use crate::util::test_context;
+        use crate::filesys::detect_fs;
+
+        // Garbage in the fields that are checked by the detection.
+        let sb = SuperBlock { s_state: 0x81, s_errors: 0, s_creator_os: 9, ..test_sb() };
+
+        let bincode_opt = DefaultOptions::new()
+            .with_fixint_encoding()
+            .allow_trailing_bytes();
+        let mut drive = vec![0; 4096];
+        drive[1024..2048].copy_from_slice(&bincode_opt.serialize(&sb).unwrap());
+        let mut ctx = test_context(&drive);
+
+        assert!(detect_fs(&mut ctx).unwrap().is_none());
+        assert!(Fs::new(sb, &Config::default()).is_err());
+
+        let cfg = Config { force_fs_type: true, ..Config::default() };
+        let fs = Fs::new(sb, &cfg).unwrap();
+
+        assert!(matches!(fs.opts.error_policy, ErrorPolicy::Continue));
+        assert!(matches!(fs.opts.fs_creator, FsCreator::Linux));
+    }
+
+    #[test]
+    fn force_fs_type_keeps_error_state()
+    {
+        let sb = SuperBlock { s_state: 0x2, ..test_sb() };
+        let cfg = Config { force_fs_type: true, ..Config::default() };
+
+        assert!(Fs::new(sb, &cfg).is_err());
+    }
+
+    thread_local! {
+        /// The block group at which the failing test scanner fails.
+        static FAIL_AT: std::cell::Cell<Option<u64>> = const { std::cell::Cell::new(None) };
+    }
+
+    /// Marks a few bytes of each block group as used; fails at the group set in FAIL_AT.
+    fn test_scan_bg(
+        map: &mut UsageMap,
+        bg_num: u64,
+        fs: &Fs,
+        _ctx: &mut Context,
+        _cfg: &Config,
+    ) -> anyhow::Result<()>
+    {
+        // Also touch the following group, like files reaching across groups.
+        map.update(start_of_bg(bg_num, fs) + bg_num * 8, fs.bg_size + 4, AllocStatus::Used);
+
+        if FAIL_AT.with(|f| f.get()) == Some(bg_num) {
+            bail!("interrupted");
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn resume_scan()
+    {
+        use crate::util::{test_context, temp_path};
+
+        let fs = test_fs(SuperBlock {
+            s_blocks_count_lo: 32 * 10,
+            s_inodes_count: 16 * 10,
+            s_uuid: [7; 16],
+            ..test_sb()
+        });
+        let drive = vec![0; fs.bg_count as usize * fs.bg_size as usize];
+
+        let mut ctx = test_context(&drive);
+        let expected = scan_groups(test_scan_bg, &fs, &mut ctx, &Config::default()).unwrap();
+
+        let path = temp_path();
+        let cfg = Config { scan_checkpoint: Some(path.clone()), ..Config::default() };
+
+        FAIL_AT.with(|f| f.set(Some(6)));
+        assert!(scan_groups(test_scan_bg, &fs, &mut ctx, &cfg).is_err());
+        assert!(path.exists());
+
+        FAIL_AT.with(|f| f.set(None));
+        let resumed = scan_groups(test_scan_bg, &fs, &mut ctx, &cfg).unwrap();
+
+        assert_eq!(resumed.segments(), expected.segments());
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn resume_scan_other_fs()
+    {
+        use crate::util::{test_context, temp_path};
+
+        let fs = test_fs(SuperBlock { s_uuid: [1; 16], ..test_sb() });
+        let other = test_fs(SuperBlock { s_uuid: [2; 16], ..test_sb() });
+        let mut ctx = test_context(&vec![0; 2 * fs.bg_size as usize]);
+        let map = UsageMap::new(2 * fs.bg_size);
+
+        let path = temp_path();
+        checkpoint::save(&path, 1, &map, &fs).unwrap();
+        let cfg = Config { scan_checkpoint: Some(path.clone()), ..Config::default() };
+
+        assert!(scan_groups(test_scan_bg, &other, &mut ctx, &cfg).is_err());
+
+        // The same file system, mounted or written to since.
+        for sb in [
+            SuperBlock { s_wtime: 1, ..fs.sb },
+            SuperBlock { s_mtime_hi: 1, ..fs.sb },
+            SuperBlock { s_kbytes_written: 4, ..fs.sb },
+        ] {
+            let modified = test_fs(sb);
+            assert!(scan_groups(test_scan_bg, &modified, &mut ctx, &cfg).is_err());
+        }
+        assert!(scan_groups(test_scan_bg, &fs, &mut ctx, &cfg).is_ok());
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn sparse_super_copies()
+    {
+        let fs = test_fs(SuperBlock {
+            s_blocks_count_lo: 32 * 128,
+            s_inodes_count: 16 * 128,
+            s_feature_ro_compat: 0x1,
+            ..test_sb()
+        });
+
+        let copies: Vec<u64> = (0..fs.bg_count).filter(|n| has_super_copy(*n, &fs)).collect();
+
+        assert_eq!(copies, vec![0, 1, 3, 5, 7, 9, 25, 27, 49, 81, 125]);
+    }
+
+    #[test]
+    fn csum_seed()
+    {
+        let uuid = [5; 16];
+        let from_uuid = ext4_style_crc32c_le(!0, &uuid);
+
+        let fs = test_fs(SuperBlock { s_feature_ro_compat: 0x400, s_uuid: uuid, ..test_sb() });
+        assert_eq!(fs.csum_seed, Some(from_uuid));
+
+        // The stored seed takes precedence.
+        let fs = test_fs(SuperBlock {
+            s_feature_ro_compat: 0x400,
+            s_feature_incompat: 0x2000,
+            s_checksum_seed: 42,
+            s_uuid: uuid,
+            ..test_sb()
+        });
+        assert_eq!(fs.csum_seed, Some(42));
+
+        // No seed in the good old revision.
+        let fs = test_fs(SuperBlock { s_rev_level: 0, s_feature_ro_compat: 0x400, ..test_sb() });
+        assert_eq!(fs.csum_seed, None);
+    }
+
+    #[test]
+    fn csum_without_seed()
+    {
+        let mut fs = test_fs(SuperBlock { s_feature_ro_compat: 0x400, ..test_sb() });
+        fs.csum_seed = None;
+
+        let err = verify_desc_csum(&GroupDescriptor::default(), 0, &fs).unwrap_err();
+
+        assert!(err.to_string().contains("seed"));
+    }
+
+    #[test]
+    fn crc16()
+    {
+        // The check value of CRC-16/ARC.
+        assert_eq!(ext2_crc16(0, b"123456789"), 0xbb3d);
+    }
+
+    #[test]
+    fn gdt_csum()
+    {
+        /// A bitwise crc16, as in the kernel's lib/crc16.c.
+        fn crc16_bitwise(mut crc: u16, buf: &[u8]) -> u16
+        {
+            for byte in buf {
+                crc ^= *byte as u16;
+                for _ in 0..8 {
+                    crc = if crc & 1 != 0 { (crc >> 1) ^ 0xa001 } else { crc >> 1 };
+                }
+            }
+            crc
+        }
+
+        let bincode_opt = DefaultOptions::new()
+            .with_fixint_encoding()
+            .allow_trailing_bytes();
+
+        // 64-bit descriptors, so that the part after the checksum is covered as well.
+        let sb = SuperBlock {
+            s_feature_ro_compat: 0x10,
+            s_feature_incompat: 0x80,
+            s_desc_size: 64,
+            s_uuid: [7; 16],
+            ..test_sb()
+        };
+        let fs = test_fs(sb);
+        let mut desc = GroupDescriptor {
+            bg_block_bitmap_lo: 3,
+            bg_flags: 0x4,
+            bg_block_bitmap_hi: 1,
+            ..GroupDescriptor::default()
+        };
+        let raw = bincode_opt.serialize(&desc).unwrap();
+        let mut crc = crc16_bitwise(!0, &[7; 16]);
+        crc = crc16_bitwise(crc, &[1, 0, 0, 0]);
+        crc = crc16_bitwise(crc, &raw[..0x1e]);
+        crc = crc16_bitwise(crc, &raw[0x20..64]);
+        desc.bg_checksum = crc;
+
+        assert!(verify_desc_csum(&desc, 1, &fs).unwrap());
+        assert!(!verify_desc_csum(&desc, 0, &fs).unwrap());
+        desc.bg_inode_table_hi = 1;
+        assert!(!verify_desc_csum(&desc, 1, &fs).unwrap());
+    }
+
+    #[test]
+    fn creator_os_warning_hurd()
+    {
+        let fs = test_fs(SuperBlock { s_creator_os: 1, ..test_sb() });
+
+        assert!(matches!(fs.opts.fs_creator, FsCreator::Hurd));
+        assert!(creator_os_warning(fs.opts.fs_creator).is_none());
+    }
+
+    #[test]
+    fn creator_os_warning_linux()
+    {
+        let fs = test_fs(test_sb());
+
+        assert!(creator_os_warning(fs.opts.fs_creator).is_none());
+    }
+
+    #[test]
+    fn creator_os_lites()
+    {
+        let fs = test_fs(SuperBlock { s_creator_os: 4, ..test_sb() });
+
+        assert!(matches!(fs.opts.fs_creator, FsCreator::Lites));
+        assert!(creator_os_warning(fs.opts.fs_creator).is_some());
+    }
+
+    #[test]
+    fn unknown_incompat()
+    {
+        use crate::util::test_context;
+        use crate::usage_map::Segment;
+
+        let bincode_opt = DefaultOptions::new()
+            .with_fixint_encoding()
+            .allow_trailing_bytes();
+
+        let sb = SuperBlock { s_feature_incompat: 0x40000, ..test_sb() };
+        let mut drive = vec![0; 64 * 4096];
+        drive[1024..2048].copy_from_slice(&bincode_opt.serialize(&sb).unwrap());
+
+        // The block bitmap of the first group marks its first 4 blocks as used; the bitmap of the
+        // second group is not initialised.
+        let descs = [
+            GroupDescriptor { bg_block_bitmap_lo: 2, ..GroupDescriptor::default() },
+            GroupDescriptor { bg_flags: 0x2, ..GroupDescriptor::default() },
+        ];
+        for (i, desc) in descs.iter().enumerate() {
+            let raw = bincode_opt.serialize(desc).unwrap();
+            drive[4096 + i * 32..4096 + (i + 1) * 32].copy_from_slice(&raw[..32]);
+        }
+        drive[2 * 4096] = 0x0f;
+
+        let mut ctx = test_context(&drive);
+
+        assert!(scan_drive(&mut ctx, &Config::default()).is_err());
+
+        let report = Config { allow_unknown_incompat: true, ..Config::default() };
+        let map = scan_drive(&mut ctx, &report).unwrap();
+        assert_eq!(map.segments(), vec![
+            Segment { start: 0, end: 4 * 4096, status: AllocStatus::Used },
+            Segment { start: 4 * 4096, end: 32 * 4096, status: AllocStatus::Free },
+            Segment { start: 32 * 4096, end: 64 * 4096, status: AllocStatus::Used },
+        ]);
+
+        let fill = Config { report_only: false, ..report };
+        assert!(scan_drive(&mut ctx, &fill).is_err());
+    }
+
+    #[test]
+    fn mmp_block_used()
+    {
+        use crate::util::test_context;
+
+        let bincode_opt = DefaultOptions::new()
+            .with_fixint_encoding()
+            .allow_trailing_bytes();
+
+        let sb = SuperBlock { s_feature_incompat: 0x100, s_mmp_block: 10, ..test_sb() };
+        let mut drive = vec![0; 64 * 4096];
+        drive[1024..2048].copy_from_slice(&bincode_opt.serialize(&sb).unwrap());
+
+        let descs = [
+            GroupDescriptor {
+                bg_block_bitmap_lo: 4,
+                bg_inode_bitmap_lo: 5,
+                bg_inode_table_lo: 6,
+                bg_flags: 0x4,
+                ..GroupDescriptor::default()
+            },
+            GroupDescriptor { bg_flags: 0x3, ..GroupDescriptor::default() },
+        ];
+        for (i, desc) in descs.iter().enumerate() {
+            let raw = bincode_opt.serialize(desc).unwrap();
+            drive[4096 + i * 32..4096 + (i + 1) * 32].copy_from_slice(&raw[..32]);
+        }
+
+        // A clean MMP block.
+        drive[10 * 4096..10 * 4096 + 4].copy_from_slice(&0x004d4d50u32.to_le_bytes());
+        drive[10 * 4096 + 4..10 * 4096 + 8].copy_from_slice(&0xff4d4d50u32.to_le_bytes());
+
+        let mut ctx = test_context(&drive);
+        let map = scan_drive(&mut ctx, &Config::default()).unwrap();
+
+        let mmp = map.iter().find(|s| s.start <= 10 * 4096 && 10 * 4096 < s.end).unwrap();
+        assert_eq!(mmp.status, AllocStatus::Used);
+        assert!(ctx.mmp.is_empty());
+    }
+
+    #[test]
+    fn reserved_gdt_blocks()
+    {
+        use crate::util::test_context;
+        use crate::usage_map::Segment;
+        use inode::Inode;
+
+        let bincode_opt = DefaultOptions::new()
+            .with_fixint_encoding()
+            .allow_trailing_bytes();
+
+        // resize_inode, with 2 reserved GDT blocks after each copy of the GDT.
+        let sb = SuperBlock { s_feature_compat: 0x10, s_reserved_gdt_blocks: 2, ..test_sb() };
+        let mut drive = vec![0; 64 * 4096];
+        drive[1024..2048].copy_from_slice(&bincode_opt.serialize(&sb).unwrap());
+
+        let descs = [
+            GroupDescriptor {
+                bg_block_bitmap_lo: 4,
+                bg_inode_bitmap_lo: 5,
+                bg_inode_table_lo: 6,
+                bg_flags: 0x4,
+                ..GroupDescriptor::default()
+            },
+            GroupDescriptor { bg_flags: 0x3, ..GroupDescriptor::default() },
+        ];
+        for (i, desc) in descs.iter().enumerate() {
+            let raw = bincode_opt.serialize(desc).unwrap();
+            drive[4096 + i * 32..4096 + (i + 1) * 32].copy_from_slice(&raw[..32]);
+        }
+
+        // The reserved inodes are used; the resize inode's double indirect block (7) lists the
+        // primary reserved GDT blocks, which list their backups.
+        drive[5 * 4096] = 0x7f;
+        let mut resize_inode: Inode = bincode_opt.deserialize(&[0; INODE_STRUCT_SIZE]).unwrap();
+        resize_inode.i_mode = 0x8180;
+        resize_inode.i_block[13] = 7;
+        let raw = bincode_opt.serialize(&resize_inode).unwrap();
+        drive[6 * 4096 + 6 * 256..6 * 4096 + 6 * 256 + raw.len()].copy_from_slice(&raw);
+        drive[7 * 4096..7 * 4096 + 4].copy_from_slice(&2u32.to_le_bytes());
+        drive[7 * 4096 + 4..7 * 4096 + 8].copy_from_slice(&3u32.to_le_bytes());
+        drive[2 * 4096..2 * 4096 + 4].copy_from_slice(&34u32.to_le_bytes());
+        drive[3 * 4096..3 * 4096 + 4].copy_from_slice(&35u32.to_le_bytes());
+
+        let mut ctx = test_context(&drive);
+        let map = scan_drive(&mut ctx, &Config::default()).unwrap();
+
+        assert_eq!(map.segments(), vec![
+            Segment { start: 0, end: 2048, status: AllocStatus::Used },
+            Segment { start: 2048, end: 4096, status: AllocStatus::Free },
+            Segment { start: 4096, end: 4096 + 2 * 32, status: AllocStatus::Used },
+            Segment { start: 4096 + 2 * 32, end: 2 * 4096, status: AllocStatus::Free },
+            Segment { start: 2 * 4096, end: 8 * 4096, status: AllocStatus::Used },
+            Segment { start: 8 * 4096, end: 32 * 4096, status: AllocStatus::Free },
+            Segment { start: 32 * 4096, end: 32 * 4096 + 1024, status: AllocStatus::Used },
+            Segment { start: 32 * 4096 + 1024, end: 33 * 4096, status: AllocStatus::Free },
+            Segment { start: 33 * 4096, end: 33 * 4096 + 2 * 32, status: AllocStatus::Used },
+            Segment { start: 33 * 4096 + 2 * 32, end: 34 * 4096, status: AllocStatus::Free },
+            Segment { start: 34 * 4096, end: 36 * 4096, status: AllocStatus::Used },
+            Segment { start: 36 * 4096, end: 64 * 4096, status: AllocStatus::Free },
+        ]);
+
+        // The resize inode is scanned even if the inode bitmap does not mark it as used.
+        drive[5 * 4096] = 0x3f;
+        let mut ctx = test_context(&drive);
+        assert_eq!(scan_drive(&mut ctx, &Config::default()).unwrap().segments(), map.segments());
+
+        // A chain leading outside of the file system.
+        drive[3 * 4096..3 * 4096 + 4].copy_from_slice(&64u32.to_le_bytes());
+        let mut ctx = test_context(&drive);
+        assert!(scan_drive(&mut ctx, &Config::default()).is_err());
+    }
+
+    #[test]
+    fn first_gdt()
+    {
+        assert_eq!(start_of_first_gdt(&test_sb()), 4096);
+
+        // 1KiB blocks: the first group starts at block 1, or at block 0 with bigalloc.
+        let sb = SuperBlock { s_log_block_size: 0, s_first_data_block: 1, ..test_sb() };
+        assert_eq!(start_of_first_gdt(&sb), 2048);
+        assert_eq!(start_of_first_gdt(&SuperBlock { s_first_data_block: 0, ..sb }), 2048);
+
+        // A first group past the superblock.
+        assert_eq!(start_of_first_gdt(&SuperBlock { s_first_data_block: 3, ..sb }), 4096);
+        let sb = SuperBlock { s_first_data_block: 2, ..test_sb() };
+        assert_eq!(start_of_first_gdt(&sb), 3 * 4096);
+        assert!(Fs::new(SuperBlock { s_first_data_block: 64, ..sb }, &Config::default()).is_err());
+    }
+
+    #[test]
+    fn one_kib_blocks()
+    {
+        use crate::util::test_context;
+        use crate::usage_map::Segment;
+
+        let bincode_opt = DefaultOptions::new()
+            .with_fixint_encoding()
+            .allow_trailing_bytes();
+
+        // Two groups of 32 1KiB blocks, after the boot block.
+        let sb = SuperBlock {
+            s_blocks_count_lo: 65,
+            s_first_data_block: 1,
+            s_log_block_size: 0,
+            s_log_cluster_size: 0,
+            ..test_sb()
+        };
+        let mut drive = vec![0; 65 * 1024];
+        drive[1024..2048].copy_from_slice(&bincode_opt.serialize(&sb).unwrap());
+
+        let descs = [
+            GroupDescriptor {
+                bg_block_bitmap_lo: 3,
+                bg_inode_bitmap_lo: 4,
+                bg_inode_table_lo: 5,
+                bg_flags: 0x4,
+                ..GroupDescriptor::default()
+            },
+            GroupDescriptor { bg_flags: 0x3, ..GroupDescriptor::default() },
+        ];
+        for (i, desc) in descs.iter().enumerate() {
+            let raw = bincode_opt.serialize(desc).unwrap();
+            drive[2048 + i * 32..2048 + (i + 1) * 32].copy_from_slice(&raw[..32]);
+        }
+
+        let mut ctx = test_context(&drive);
+        let map = scan_drive(&mut ctx, &Config::default()).unwrap();
+
+        // The backup of the second group is in its first block (33), its GDT in the next one.
+        assert_eq!(map.segments(), vec![
+            Segment { start: 0, end: 2048 + 2 * 32, status: AllocStatus::Used },
+            Segment { start: 2048 + 2 * 32, end: 3 * 1024, status: AllocStatus::Free },
+            Segment { start: 3 * 1024, end: 9 * 1024, status: AllocStatus::Used },
+            Segment { start: 9 * 1024, end: 33 * 1024, status: AllocStatus::Free },
+            Segment { start: 33 * 1024, end: 34 * 1024 + 2 * 32, status: AllocStatus::Used },
+            Segment { start: 34 * 1024 + 2 * 32, end: 65 * 1024, status: AllocStatus::Free },
+        ]);
+
+        // The first group starts at block 3: the blocks before it and its first block are used.
+        let sb = SuperBlock { s_blocks_count_lo: 67, s_first_data_block: 3, ..sb };
+        let mut drive = vec![0; 67 * 1024];
+        drive[1024..2048].copy_from_slice(&bincode_opt.serialize(&sb).unwrap());
+        let descs = [
+            GroupDescriptor {
+                bg_block_bitmap_lo: 5,
+                bg_inode_bitmap_lo: 6,
+                bg_inode_table_lo: 7,
+                bg_flags: 0x4,
+                ..GroupDescriptor::default()
+            },
+            GroupDescriptor { bg_flags: 0x3, ..GroupDescriptor::default() },
+        ];
+        for (i, desc) in descs.iter().enumerate() {
+            let raw = bincode_opt.serialize(desc).unwrap();
+            drive[4096 + i * 32..4096 + (i + 1) * 32].copy_from_slice(&raw[..32]);
+        }
+
+        let mut ctx = test_context(&drive);
+        let map = scan_drive(&mut ctx, &Config::default()).unwrap();
+
+        assert_eq!(map.segments(), vec![
+            Segment { start: 0, end: 4096 + 2 * 32, status: AllocStatus::Used },
+            Segment { start: 4096 + 2 * 32, end: 5 * 1024, status: AllocStatus::Free },
+            Segment { start: 5 * 1024, end: 11 * 1024, status: AllocStatus::Used },
+            Segment { start: 11 * 1024, end: 35 * 1024, status: AllocStatus::Free },
+            Segment { start: 35 * 1024, end: 36 * 1024 + 2 * 32, status: AllocStatus::Used },
+            Segment { start: 36 * 1024 + 2 * 32, end: 67 * 1024, status: AllocStatus::Free },
+        ]);
+    }
+
+    #[test]
+    fn non_zeroed_inode_table()
+    {
+        use crate::util::test_context;
+        use crate::usage_map::Segment;
+
+        let bincode_opt = DefaultOptions::new()
+            .with_fixint_encoding()
+            .allow_trailing_bytes();
+
+        let mut drive = vec![0; 64 * 4096];
+        drive[1024..2048].copy_from_slice(&bincode_opt.serialize(&test_sb()).unwrap());
+
+        // The inode table of the first group is not zeroed and its first 3 (reserved) inodes are
+        // used; the second group is not initialised.
+        let descs = [
+            GroupDescriptor {
+                bg_block_bitmap_lo: 2,
+                bg_inode_bitmap_lo: 3,
+                bg_inode_table_lo: 4,
+                ..GroupDescriptor::default()
+            },
+            GroupDescriptor { bg_flags: 0x3, ..GroupDescriptor::default() },
+        ];
+        for (i, desc) in descs.iter().enumerate() {
+            let raw = bincode_opt.serialize(desc).unwrap();
+            drive[4096 + i * 32..4096 + (i + 1) * 32].copy_from_slice(&raw[..32]);
+        }
+        drive[3 * 4096] = 0x07;
+
+        let mut ctx = test_context(&drive);
+        let map = scan_drive(&mut ctx, &Config::default()).unwrap();
+
+        assert_eq!(map.segments()[..5], [
+            Segment { start: 0, end: 2048, status: AllocStatus::Used },
+            Segment { start: 2048, end: 4096, status: AllocStatus::Free },
+            Segment { start: 4096, end: 4096 + 2 * 32, status: AllocStatus::Used },
+            Segment { start: 4096 + 2 * 32, end: 2 * 4096, status: AllocStatus::Free },
+            Segment { start: 2 * 4096, end: 4 * 4096 + 3 * 256, status: AllocStatus::Used },
+        ]);
+        assert_eq!(map.segments()[5].status, AllocStatus::Free);
+        assert_eq!(map.segments()[5].end, 32 * 4096);
+    }
+
+    #[test]
+    fn bigalloc()
+    {
+        use crate::usage_map::Segment;
+
+        // 16KiB clusters of 4 blocks.
+        let sb = SuperBlock {
+            s_log_cluster_size: 4,
+            s_clusters_per_group: 8,
+            s_feature_incompat: 0x40,
+            s_feature_ro_compat: 0x200,
+            ..test_sb()
+        };
+        let fs = test_fs(sb);
+        assert!(has_bigalloc(&fs));
+
+        // A bitmap block, the tail of a file and a range straddling two clusters.
+        let mut map = UsageMap::new(64 * 4096);
+        map.update(4096, 4096, AllocStatus::Used);
+        map.update(9 * 4096, 100, AllocStatus::Used);
+        map.update(19 * 4096, 2 * 4096, AllocStatus::Used);
+        round_to_clusters(&mut map, &fs);
+
+        assert_eq!(map.segments(), vec![
+            Segment { start: 0, end: 4 * 4096, status: AllocStatus::Used },
+            Segment { start: 4 * 4096, end: 8 * 4096, status: AllocStatus::Free },
+            Segment { start: 8 * 4096, end: 12 * 4096, status: AllocStatus::Used },
+            Segment { start: 12 * 4096, end: 16 * 4096, status: AllocStatus::Free },
+            Segment { start: 16 * 4096, end: 24 * 4096, status: AllocStatus::Used },
+            Segment { start: 24 * 4096, end: 64 * 4096, status: AllocStatus::Free },
+        ]);
+
+        // Clusters cannot be addressed by block maps.
+        assert!(Fs::new(SuperBlock { s_feature_incompat: 0, ..sb }, &Config::default()).is_err());
+        assert!(Fs::new(SuperBlock { s_log_cluster_size: 1, ..sb }, &Config::default()).is_err());
+    }
+
+    #[test]
+    fn encrypt()
+    {
+        let cfg = Config { allow_encrypted: true, ..Config::default() };
+
+        let sb = SuperBlock { s_feature_incompat: 0x10000, ..test_sb() };
+        assert!(Fs::new(sb, &Config::default()).is_err());
+        Fs::new(sb, &cfg).unwrap();
+
+        // As created by mke2fs: XTS for contents, CTS for names, the rest unused.
+        let sb = SuperBlock {
+            s_feature_incompat: 0x10080,
+            s_desc_size: 64,
+            s_encrypt_algos: [1, 4, 0, 0],
+            ..test_sb()
+        };
+        Fs::new(sb, &cfg).unwrap();
+        assert!(Fs::new(SuperBlock { s_encrypt_algos: [1, 4, 9, 0], ..sb }, &cfg).is_err());
+    }
+
+    #[test]
+    fn shared_blocks()
+    {
+        let sb = SuperBlock { s_feature_ro_compat: 0x4000, ..test_sb() };
+        assert!(Fs::new(sb, &Config::default()).is_err());
+
+        let cfg = Config { allow_shared_blocks: true, ..Config::default() };
+        Fs::new(sb, &cfg).unwrap();
+        Fs::new(sb, &Config { report_only: false, ..cfg }).unwrap();
+    }
+
+    #[test]
+    fn casefold_encoding()
+    {
+        let sb = SuperBlock { s_feature_incompat: 0x20000, s_encoding: 1, ..test_sb() };
+        let fs = test_fs(sb);
+        let encoding = fs.opts.dyn_cfg.unwrap().encoding.unwrap();
+        assert_eq!(encoding, Encoding { name: "utf8-12.1", strict: false });
+
+        let fs = test_fs(SuperBlock { s_encoding_flags: 0x1, ..sb });
+        assert!(fs.opts.dyn_cfg.unwrap().encoding.unwrap().strict);
+
+        assert!(Fs::new(SuperBlock { s_encoding: 2, ..sb }, &Config::default()).is_err());
+        assert!(Fs::new(SuperBlock { s_encoding_flags: 0x2, ..sb }, &Config::default()).is_err());
+
+        // Without casefold, the fields are not used.
+        let fs = test_fs(SuperBlock { s_feature_incompat: 0, s_encoding: 2, ..sb });
+        assert!(fs.opts.dyn_cfg.unwrap().encoding.is_none());
+    }
+}
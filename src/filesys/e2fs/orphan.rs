@@ -0,0 +1,162 @@
+use std::io::{Read, Seek, SeekFrom};
+use anyhow::bail;
+
+use crate::{Config, Context};
+
+use super::inode::{IFlags, Inode};
+use super::extent::{ExtentTree, ExtentTreeIterator};
+use super::Fs;
+use crate::bs;
+use crate::hilo;
+
+// Reference: https://www.kernel.org/doc/html/latest/filesystems/ext4/orphan.html
+
+const ORPHAN_BLOCK_MAGIC: u32 = 0x0b10ca04;
+/// The size of the tail of an orphan block: the magic value and the checksum.
+const ORPHAN_BLOCK_TAIL_SIZE: usize = 8;
+
+/// Processes the orphan file (orphan_file): every block holds a list of orphaned inodes, followed
+/// by a tail. Returns the number of orphaned inodes.
+/// The orphaned inodes keep their blocks allocated until they are released on mount or by fsck,
+/// so they are scanned like any other inode. Only orphan files with extents are processed;
+/// `None` is returned for block-mapped ones.
+pub fn count_orphans(inode: &Inode, fs: &Fs, ctx: &mut Context, cfg: &Config)
+    -> anyhow::Result<Option<u64>>
+{
+    let i_flags = IFlags(inode.i_flags);
+    let block_size = bs!(fs.sb.s_log_block_size);
+    let size = hilo!(inode.i_size_high, inode.i_size_lo);
+
+    if !size.is_multiple_of(block_size) {
+        bail!("the size of the orphan file is not a multiple of the block size: {}", size);
+    }
+    if !i_flags.has_extents() {
+        return Ok(None);
+    }
+
+    let mut count = 0;
+    let mut buf = vec![0u8; block_size as usize];
+
+    let extent_tree = ExtentTree::new(inode, fs, ctx, cfg)?;
+    for e in ExtentTreeIterator::new(&extent_tree) {
+        let start = hilo!(e.ee_start_hi, e.ee_start_lo);
+
+        for i in 0..e.ee_len as u64 {
+            if (e.ee_block as u64 + i) * block_size >= size {
+                break;
+            }
+
+            ctx.drive.seek(SeekFrom::Start((start + i) * block_size))?;
+            ctx.drive.read_exact(&mut buf)?;
+            count += count_block_orphans(&buf, start + i, fs)?;
+        }
+    }
+
+    Ok(Some(count))
+}
+
+/// Counts the orphaned inodes listed in a block of the orphan file.
+fn count_block_orphans(buf: &[u8], block: u64, fs: &Fs) -> anyhow::Result<u64>
+{
+    let (entries, tail) = buf.split_at(buf.len() - ORPHAN_BLOCK_TAIL_SIZE);
+
+    let magic = u32::from_le_bytes(tail[0..4].try_into().unwrap());
+    if magic != ORPHAN_BLOCK_MAGIC {
+        bail!("orphan file block {} has invalid magic: {:#010x}", block, magic);
+    }
+
+    let mut count = 0;
+    for raw in entries.chunks_exact(4) {
+        let ino = u32::from_le_bytes(raw.try_into().unwrap());
+        if ino == 0 {
+            continue;
+        }
+        if ino > fs.sb.s_inodes_count {
+            bail!("orphan file block {} lists an invalid inode: {}", block, ino);
+        }
+        count += 1;
+    }
+
+    Ok(count)
+}
+
+// Tests.
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::test_context;
+    use crate::filesys::e2fs::tests::{test_fs, test_sb};
+    use crate::filesys::e2fs::extent::tests::{inode_with_root, raw_extent, raw_node};
+    use crate::filesys::e2fs::inode::N_BLOCKS;
+
+    const BLOCK_SIZE: usize = 4096;
+
+    /// Creates an orphan block listing the supplied inodes.
+    fn orphan_block(inodes: &[u32], magic: u32) -> Vec<u8>
+    {
+        let mut block = vec![0u8; BLOCK_SIZE];
+        for (i, ino) in inodes.iter().enumerate() {
+            block[i * 4..i * 4 + 4].copy_from_slice(&ino.to_le_bytes());
+        }
+        block[BLOCK_SIZE - 8..BLOCK_SIZE - 4].copy_from_slice(&magic.to_le_bytes());
+
+        block
+    }
+
+    /// Creates an orphan file of 2 blocks, at blocks 10 and 11.
+    fn orphan_inode(size: u32) -> Inode
+    {
+        let mut inode = inode_with_root(&raw_node(0, &[raw_extent(0, 2, 10)], N_BLOCKS * 4));
+        inode.i_flags = 0x80000;
+        inode.i_size_lo = size;
+
+        inode
+    }
+
+    #[test]
+    fn orphans()
+    {
+        let fs = test_fs(test_sb());
+        let cfg = Config::default();
+
+        let mut drive = vec![0u8; 12 * BLOCK_SIZE];
+        drive[10 * BLOCK_SIZE..11 * BLOCK_SIZE]
+            .copy_from_slice(&orphan_block(&[13, 0, 20], ORPHAN_BLOCK_MAGIC));
+        drive[11 * BLOCK_SIZE..].copy_from_slice(&orphan_block(&[31], ORPHAN_BLOCK_MAGIC));
+        let mut ctx = test_context(&drive);
+
+        let inode = orphan_inode(2 * BLOCK_SIZE as u32);
+        assert_eq!(count_orphans(&inode, &fs, &mut ctx, &cfg).unwrap(), Some(3));
+
+        // Only the first block is within the size.
+        let inode = orphan_inode(BLOCK_SIZE as u32);
+        assert_eq!(count_orphans(&inode, &fs, &mut ctx, &cfg).unwrap(), Some(2));
+
+        let inode = orphan_inode(BLOCK_SIZE as u32 + 1);
+        assert!(count_orphans(&inode, &fs, &mut ctx, &cfg).is_err());
+
+        // Block-mapped orphan files are not processed.
+        let inode = Inode { i_flags: 0, ..orphan_inode(2 * BLOCK_SIZE as u32) };
+        assert_eq!(count_orphans(&inode, &fs, &mut ctx, &cfg).unwrap(), None);
+    }
+
+    #[test]
+    fn invalid_blocks()
+    {
+        let fs = test_fs(test_sb());
+        let cfg = Config::default();
+        let inode = orphan_inode(BLOCK_SIZE as u32);
+
+        let mut drive = vec![0u8; 12 * BLOCK_SIZE];
+        drive[10 * BLOCK_SIZE..11 * BLOCK_SIZE].copy_from_slice(&orphan_block(&[13], 0));
+        let mut ctx = test_context(&drive);
+        assert!(count_orphans(&inode, &fs, &mut ctx, &cfg).is_err());
+
+        // An inode beyond s_inodes_count.
+        drive[10 * BLOCK_SIZE..11 * BLOCK_SIZE]
+            .copy_from_slice(&orphan_block(&[33], ORPHAN_BLOCK_MAGIC));
+        let mut ctx = test_context(&drive);
+        assert!(count_orphans(&inode, &fs, &mut ctx, &cfg).is_err());
+    }
+}
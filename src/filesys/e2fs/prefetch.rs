@@ -0,0 +1,133 @@
+use crate::{bs, hilo};
+use crate::drive::Drive;
+use crate::io_engine::IoEngine;
+use super::{BgFlags, Fs, fetch_regular_bg_descriptor};
+
+/// A range of the drive, in bytes.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Range {
+    pub offset: u64,
+    pub len: u64,
+}
+
+/// Returns the metadata that the scan of a block group reads: the inode bitmap and the inode
+/// table. Nothing is read from groups with uninitialised inodes.
+pub fn group_ranges(bg_num: u64, fs: &Fs) -> anyhow::Result<Vec<Range>>
+{
+    let desc = fetch_regular_bg_descriptor(bg_num, fs)?;
+    let bg_flags = BgFlags(desc.bg_flags);
+
+    if bg_flags.has_inode_uninit() {
+        return Ok(Vec::new());
+    }
+
+    let block_size = bs!(fs.sb.s_log_block_size);
+    let (inode_bitmap_block, inode_table_block) = if fs.opts.bit64_cfg.is_some() {
+        (
+            hilo!(desc.bg_inode_bitmap_hi, desc.bg_inode_bitmap_lo),
+            hilo!(desc.bg_inode_table_hi, desc.bg_inode_table_lo),
+        )
+    } else {
+        (desc.bg_inode_bitmap_lo as u64, desc.bg_inode_table_lo as u64)
+    };
+
+    Ok(vec![
+        Range { offset: inode_bitmap_block * block_size, len: block_size },
+        Range {
+            offset: inode_table_block * block_size,
+            len: fs.sb.s_inodes_per_group as u64 * fs.inode_size,
+        },
+    ])
+}
+
+/// Asks the kernel to read the ranges ahead (`POSIX_FADV_WILLNEED`). The advice is only a hint:
+/// failures are ignored.
+#[cfg(target_os = "linux")]
+pub fn advise(drive: &Drive, ranges: &[Range])
+{
+    use std::os::unix::io::AsRawFd;
+
+    for range in ranges {
+        // SAFETY: the call does not access any memory and the descriptor is owned by `drive`.
+        unsafe {
+            libc::posix_fadvise(
+                drive.file().as_raw_fd(),
+                (drive.offset() + range.offset) as libc::off_t,
+                range.len as libc::off_t,
+                libc::POSIX_FADV_WILLNEED,
+            );
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn advise(_drive: &Drive, _ranges: &[Range]) {}
+
+/// Reads the ranges ahead with the I/O engine: with io_uring, the ranges are read into the page
+/// cache with many reads in flight at once, instead of being advised. Failures are ignored.
+pub fn read(drive: &Drive, ranges: &[Range], engine: IoEngine)
+{
+    match engine {
+        IoEngine::Sync => advise(drive, ranges),
+        #[cfg(all(target_os = "linux", feature = "io-uring"))]
+        IoEngine::IoUring => {
+            let spans: Vec<_> = ranges.iter().map(|r| r.offset..r.offset + r.len).collect();
+            if crate::io_engine::read_ahead(drive, &spans).is_err() {
+                advise(drive, ranges);
+            }
+        },
+        #[cfg(not(all(target_os = "linux", feature = "io-uring")))]
+        IoEngine::IoUring => unreachable!("the I/O engine is checked at the start"),
+    }
+}
+
+// Tests.
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::{GroupDescriptor, SuperBlock};
+    use crate::filesys::e2fs::tests::{test_fs_with_descs, test_sb};
+
+    #[test]
+    fn requested_ranges()
+    {
+        let fs = test_fs_with_descs(test_sb(), &[
+            GroupDescriptor { bg_inode_bitmap_lo: 3, bg_inode_table_lo: 4, ..GroupDescriptor::default() },
+            GroupDescriptor {
+                bg_inode_bitmap_lo: 33,
+                bg_inode_table_lo: 34,
+                bg_flags: 0x1,
+                ..GroupDescriptor::default()
+            },
+        ]);
+
+        assert_eq!(group_ranges(0, &fs).unwrap(), vec![
+            Range { offset: 3 * 4096, len: 4096 },
+            // 16 inodes of 256 bytes.
+            Range { offset: 4 * 4096, len: 4096 },
+        ]);
+        // Uninitialised inodes are not read.
+        assert!(group_ranges(1, &fs).unwrap().is_empty());
+    }
+
+    #[test]
+    fn requested_ranges_64bit()
+    {
+        // 64bit, with 64-byte descriptors.
+        let sb = SuperBlock { s_feature_incompat: 0x80, s_desc_size: 64, ..test_sb() };
+        let desc = GroupDescriptor {
+            bg_inode_bitmap_lo: 3,
+            bg_inode_bitmap_hi: 1,
+            bg_inode_table_lo: 4,
+            bg_inode_table_hi: 1,
+            ..GroupDescriptor::default()
+        };
+        let fs = test_fs_with_descs(sb, &[desc, desc]);
+
+        assert_eq!(group_ranges(1, &fs).unwrap(), vec![
+            Range { offset: ((1 << 32) + 3) * 4096, len: 4096 },
+            Range { offset: ((1 << 32) + 4) * 4096, len: 4096 },
+        ]);
+    }
+}
@@ -0,0 +1,467 @@
+use std::io::{Read, Seek, SeekFrom, Write};
+use anyhow::bail;
+
+use crate::anomaly::AnomalyKind;
+use crate::bitmap::Bitmap;
+use crate::{Config, Context};
+use super::{
+    csum,
+    inode,
+    BgFlags,
+    Fs,
+    fetch_regular_bg_descriptor,
+    has_resize_inode,
+    verify_desc_csum,
+};
+use super::inode::{IFlags, Inode, INODE_STRUCT_SIZE};
+use crate::alloc_inode_size;
+use crate::bs;
+use crate::hilo;
+
+// Reference: https://www.kernel.org/doc/html/latest/filesystems/ext4/directory.html
+
+/// The size of the fixed part of a directory entry: the inode, the record length, the name length
+/// and the file type.
+const DIRENT_HEADER_SIZE: usize = 8;
+/// The size of the fake directory entry that holds the checksum, at the end of a block.
+const DIRENT_TAIL_SIZE: usize = 12;
+const DIRENT_TAIL_FILE_TYPE: u8 = 0xde;
+/// The size of the hash that follows the names in encrypted and casefolded directories.
+const DIRENT_HASH_SIZE: usize = 8;
+
+/// A write of scrubbed metadata or of a refreshed backup. The scan only collects the writes in the
+/// context; they are applied by `apply` before the fill, once the device is confirmed.
+#[derive(Clone, Debug)]
+pub struct ScrubWrite {
+    /// The position in the underlying file, as the drive window may change before the fill.
+    offset: u64,
+    data: Vec<u8>,
+}
+
+/// Applies the metadata writes collected by the scan. Returns the number of writes.
+pub fn apply(ctx: &mut Context) -> anyhow::Result<usize>
+{
+    let writes = std::mem::take(&mut ctx.scrub_writes);
+
+    let window = ctx.drive.window();
+    ctx.drive.set_window(None)?;
+    let written = write_all(&writes, ctx);
+    ctx.drive.set_window(window)?;
+    written?;
+
+    ctx.drive.sync_data()?;
+
+    Ok(writes.len())
+}
+
+/// Writes the collected metadata, with the drive window lifted.
+fn write_all(writes: &[ScrubWrite], ctx: &mut Context) -> anyhow::Result<()>
+{
+    for write in writes {
+        ctx.drive.seek(SeekFrom::Start(write.offset))?;
+        ctx.drive.write_all(&write.data)?;
+    }
+
+    Ok(())
+}
+
+/// Collects a write of metadata, at a position relative to the drive window.
+pub(super) fn defer_write(offset: u64, data: Vec<u8>, ctx: &mut Context)
+{
+    let offset = ctx.drive.offset() + offset;
+    ctx.scrub_writes.push(ScrubWrite { offset, data });
+}
+
+/// Zeroes the inodes of a group that are not in use (their bit in the inode bitmap is clear), but
+/// still hold the metadata of deleted files: timestamps, sizes and block pointers. The writes are
+/// collected in the context; in the report-only mode, the inodes are only counted. Returns the
+/// number of such inodes.
+/// Nothing else has to be updated: zeroed inodes are what mke2fs leaves in the table, and pass the
+/// checksum verification. Groups whose inode bitmap is not initialised or cannot be trusted (an
+/// invalid descriptor or bitmap checksum) are skipped, as their bitmap could hide inodes in use.
+pub fn scrub_inode_table(bg_num: u64, fs: &Fs, ctx: &mut Context, cfg: &Config)
+    -> anyhow::Result<u64>
+{
+    let block_size = bs!(fs.sb.s_log_block_size);
+    let inode_size = fs.inode_size as usize;
+    let has_csum = match fs.opts.dyn_cfg {
+        Some(dyn_cfg) => dyn_cfg.ro_compat.has_metadata_csum() || dyn_cfg.ro_compat.has_gdt_csum(),
+        None => false,
+    };
+
+    let desc = fetch_regular_bg_descriptor(bg_num, fs)?;
+    if has_csum && !verify_desc_csum(&desc, bg_num, fs)? {
+        return Ok(0);
+    }
+    if BgFlags(desc.bg_flags).has_inode_uninit() {
+        return Ok(0);
+    }
+
+    let (inode_bitmap_block, inode_table_block) = if fs.opts.bit64_cfg.is_some() {
+        (
+            hilo!(desc.bg_inode_bitmap_hi, desc.bg_inode_bitmap_lo),
+            hilo!(desc.bg_inode_table_hi, desc.bg_inode_table_lo),
+        )
+    } else {
+        (desc.bg_inode_bitmap_lo as u64, desc.bg_inode_table_lo as u64)
+    };
+
+    let mut raw_bmp = vec![0u8; block_size as usize];
+    ctx.drive.seek(SeekFrom::Start(inode_bitmap_block * block_size))?;
+    ctx.drive.read_exact(&mut raw_bmp)?;
+    if csum::has_metadata_csum(fs) && !csum::verify_inode_bitmap(&raw_bmp, &desc, fs)? {
+        let what = format!("the inode bitmap of group {}", bg_num);
+        csum::csum_error(&what, AnomalyKind::BadBitmapChecksum, ctx, cfg)?;
+        return Ok(0);
+    }
+    let i_bmp = Bitmap::from_bytes(&raw_bmp);
+
+    let mut itable = vec![
+        0u8;
+        fs.sb.s_inodes_per_group as usize * alloc_inode_size!(fs.inode_size)
+    ];
+    inode::read_itable(bg_num, &mut itable, fs, ctx)?;
+
+    let mut count = 0;
+
+    for i in 0..fs.sb.s_inodes_per_group as usize {
+        let raw_inode = &itable[i * inode_size..(i + 1) * inode_size];
+        if i_bmp.check_bit(i) || raw_inode.iter().all(|b| *b == 0) {
+            continue;
+        }
+        // The resize inode is kept, even if the bitmap says otherwise.
+        if has_resize_inode(fs) && inode::inode_number(i, bg_num, fs) == inode::RESIZE_INO {
+            continue;
+        }
+
+        count += 1;
+        if cfg.report_only {
+            continue;
+        }
+
+        let offset = inode_table_block * block_size + (i * inode_size) as u64;
+        defer_write(offset, vec![0u8; inode_size], ctx);
+    }
+
+    Ok(count)
+}
+
+/// Zeroes what is left of deleted entries in the leaf blocks of a directory (`dx_blocks` are the
+/// logical blocks of the htree root and interior nodes, which are skipped). The writes are collected
+/// in the context; in the report-only mode, the blocks are only counted.
+/// Unlinking an entry only merges it into the record of the preceding one (or clears its inode if
+/// it is the first in the block), so its name stays on the disk. The entries are not moved: the
+/// unused part of each record is zeroed instead, which removes the same remnants without touching
+/// the layout that the htree hashes may depend on. With `metadata_csum`, the checksum in the tail
+/// is updated; blocks without a tail or with an invalid checksum are left alone. In directories
+/// that are both encrypted and casefolded, the entries keep the hash of the name after it, as the
+/// name cannot be hashed without the key.
+pub fn scrub_dir_blocks(
+    ino: u64,
+    inode: &Inode,
+    blocks: &[u64],
+    dx_blocks: &[u64],
+    fs: &Fs,
+    ctx: &mut Context,
+    cfg: &Config,
+) -> anyhow::Result<()>
+{
+    let block_size = bs!(fs.sb.s_log_block_size);
+    let i_flags = IFlags(inode.i_flags);
+    let hashed = i_flags.has_encrypt() && i_flags.has_casefold();
+    let mut buf = vec![0u8; block_size as usize];
+    let mut count = 0;
+
+    for (lblk, &block) in blocks.iter().enumerate() {
+        if block == 0 || dx_blocks.contains(&(lblk as u64)) {
+            continue;
+        }
+
+        ctx.drive.seek(SeekFrom::Start(block * block_size))?;
+        ctx.drive.read_exact(&mut buf)?;
+
+        let end = if csum::has_metadata_csum(fs) {
+            let tail_offset = buf.len() - DIRENT_TAIL_SIZE;
+            if !is_dirent_tail(&buf[tail_offset..]) {
+                continue;
+            }
+
+            let expected = u32::from_le_bytes(buf[buf.len() - 4..].try_into().unwrap());
+            if csum::dir_block_csum(&buf, tail_offset, ino, inode.i_generation, fs)? != expected {
+                ctx.logger.logln(0, &format!(
+                    "warning: directory block {} (inode {}) has an invalid checksum: not scrubbed",
+                    block,
+                    ino,
+                ));
+                continue;
+            }
+
+            tail_offset
+        } else {
+            buf.len()
+        };
+
+        if !scrub_dir_block(&mut buf[..end], block, block_size as usize, hashed)? {
+            continue;
+        }
+
+        count += 1;
+        if cfg.report_only {
+            continue;
+        }
+
+        if end != buf.len() {
+            let csum = csum::dir_block_csum(&buf, end, ino, inode.i_generation, fs)?;
+            let len = buf.len();
+            buf[len - 4..].copy_from_slice(&csum.to_le_bytes());
+        }
+
+        defer_write(block * block_size, buf.clone(), ctx);
+    }
+
+    if count > 0 {
+        let msg = if cfg.report_only {
+            format!(
+                "{} block(s) of directory inode {} hold deleted entries; not scrubbed in the \
+                report-only mode",
+                count,
+                ino,
+            )
+        } else {
+            format!("{} block(s) of directory inode {} to scrub before the fill", count, ino)
+        };
+        ctx.logger.logln(1, &msg);
+    }
+
+    Ok(())
+}
+
+/// Zeroes the unused parts of the records in the entries of a directory block (without the tail).
+/// With `hashed`, the entries other than "." and ".." hold the hash of the name after it. Returns
+/// whether anything was zeroed.
+fn scrub_dir_block(entries: &mut [u8], block: u64, block_size: usize, hashed: bool)
+    -> anyhow::Result<bool>
+{
+    let mut changed = false;
+    let mut offset = 0;
+
+    while offset < entries.len() {
+        if offset + DIRENT_HEADER_SIZE > entries.len() {
+            bail!("truncated directory entry at offset {} of block {}", offset, block);
+        }
+
+        let inode = u32::from_le_bytes(entries[offset..offset + 4].try_into().unwrap());
+        let raw_rec_len = u16::from_le_bytes(entries[offset + 4..offset + 6].try_into().unwrap());
+        let rec_len = rec_len_from_disk(raw_rec_len, block_size);
+        let name_len = entries[offset + 6] as usize;
+
+        // Of an unused entry, only the inode and the record length are kept.
+        let kept = if inode == 0 {
+            6
+        } else {
+            let name_start = offset + DIRENT_HEADER_SIZE;
+            let name = entries.get(name_start..name_start + name_len);
+            let is_dot = matches!(name, Some(b".") | Some(b".."));
+            let len = (DIRENT_HEADER_SIZE + name_len).next_multiple_of(4);
+
+            if hashed && !is_dot { len + DIRENT_HASH_SIZE } else { len }
+        };
+
+        if !rec_len.is_multiple_of(4) || rec_len < kept || offset + rec_len > entries.len() {
+            bail!(
+                "invalid directory entry at offset {} of block {}: record length {}",
+                offset,
+                block,
+                rec_len,
+            );
+        }
+
+        let unused = &mut entries[offset + kept..offset + rec_len];
+        if unused.iter().any(|b| *b != 0) {
+            unused.fill(0);
+            changed = true;
+        }
+
+        offset += rec_len;
+    }
+
+    Ok(changed)
+}
+
+/// Decodes the record length of a directory entry. With 64KiB blocks, the length of a record
+/// spanning the whole block does not fit into 16 bits: the lowest bits hold the upper ones.
+fn rec_len_from_disk(raw: u16, block_size: usize) -> usize
+{
+    if block_size < 65536 {
+        return raw as usize;
+    }
+
+    match raw {
+        0 | 65535 => block_size,
+        _ => (raw as usize & 65532) | ((raw as usize & 3) << 16),
+    }
+}
+
+/// Checks whether the end of a directory block is a checksum tail.
+fn is_dirent_tail(tail: &[u8]) -> bool
+{
+    let inode = u32::from_le_bytes(tail[0..4].try_into().unwrap());
+    let rec_len = u16::from_le_bytes(tail[4..6].try_into().unwrap()) as usize;
+
+    inode == 0 && rec_len == DIRENT_TAIL_SIZE && tail[6] == 0 && tail[7] == DIRENT_TAIL_FILE_TYPE
+}
+
+// Tests.
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bincode::{DefaultOptions, Options};
+    use crate::util::test_context;
+    use crate::filesys::e2fs::GroupDescriptor;
+    use crate::filesys::e2fs::tests::{test_fs, test_fs_with_descs, test_sb};
+    use crate::filesys::e2fs::SuperBlock;
+
+    const BLOCK_SIZE: usize = 4096;
+
+    fn read_inode(idx: usize, ctx: &mut Context) -> Vec<u8>
+    {
+        let mut raw = vec![0u8; 256];
+        ctx.drive.seek(SeekFrom::Start((4 * BLOCK_SIZE + idx * 256) as u64)).unwrap();
+        ctx.drive.read_exact(&mut raw).unwrap();
+
+        raw
+    }
+
+    #[test]
+    fn deleted_inodes()
+    {
+        // The inode bitmap of the first group is in block 3, its inode table in block 4; the
+        // second group is not initialised.
+        let descs = [
+            GroupDescriptor { bg_inode_bitmap_lo: 3, bg_inode_table_lo: 4, ..Default::default() },
+            GroupDescriptor { bg_inode_table_lo: 5, bg_flags: 0x1, ..Default::default() },
+        ];
+        let fs = test_fs_with_descs(test_sb(), &descs);
+
+        // Inodes 1 and 2 are in use, inodes 3 and 5 were deleted, inode 4 was never used.
+        let mut drive = vec![0u8; 8 * BLOCK_SIZE];
+        drive[3 * BLOCK_SIZE] = 0x03;
+        for idx in [0, 1, 2, 4] {
+            drive[4 * BLOCK_SIZE + idx * 256..][..256].fill(0xaa);
+        }
+        drive[5 * BLOCK_SIZE..6 * BLOCK_SIZE].fill(0xaa);
+        let mut ctx = test_context(&drive);
+
+        let cfg = Config::default();
+        assert_eq!(scrub_inode_table(0, &fs, &mut ctx, &cfg).unwrap(), 2);
+        assert_eq!(read_inode(2, &mut ctx), vec![0xaa; 256]);
+
+        assert!(ctx.scrub_writes.is_empty());
+
+        // Nothing is written before the writes are applied.
+        let cfg = Config { report_only: false, ..Config::default() };
+        assert_eq!(scrub_inode_table(0, &fs, &mut ctx, &cfg).unwrap(), 2);
+        assert_eq!(read_inode(2, &mut ctx), vec![0xaa; 256]);
+        assert_eq!(apply(&mut ctx).unwrap(), 2);
+        assert_eq!(read_inode(0, &mut ctx), vec![0xaa; 256]);
+        assert_eq!(read_inode(1, &mut ctx), vec![0xaa; 256]);
+        assert_eq!(read_inode(2, &mut ctx), vec![0; 256]);
+        assert_eq!(read_inode(4, &mut ctx), vec![0; 256]);
+        assert_eq!(scrub_inode_table(0, &fs, &mut ctx, &cfg).unwrap(), 0);
+
+        // The bitmap of an uninitialised group is not trusted.
+        assert_eq!(scrub_inode_table(1, &fs, &mut ctx, &cfg).unwrap(), 0);
+    }
+
+    fn read_drive(ctx: &mut Context) -> Vec<u8>
+    {
+        let mut raw = Vec::new();
+        ctx.drive.seek(SeekFrom::Start(0)).unwrap();
+        ctx.drive.read_to_end(&mut raw).unwrap();
+
+        raw
+    }
+
+    fn write_dirent(block: &mut [u8], offset: usize, inode: u32, rec_len: u16, name: &[u8])
+    {
+        block[offset..offset + 4].copy_from_slice(&inode.to_le_bytes());
+        block[offset + 4..offset + 6].copy_from_slice(&rec_len.to_le_bytes());
+        block[offset + 6] = name.len() as u8;
+        block[offset + 8..offset + 8 + name.len()].copy_from_slice(name);
+    }
+
+    #[test]
+    fn deleted_dirents()
+    {
+        let bincode_opt = DefaultOptions::new()
+            .with_fixint_encoding()
+            .allow_trailing_bytes();
+
+        let fs = test_fs(SuperBlock { s_feature_ro_compat: 0x400, ..test_sb() });
+        let mut inode: Inode = bincode_opt.deserialize(&[0u8; 256]).unwrap();
+        inode.i_generation = 7;
+
+        // The first entry was deleted, as was "old", which was merged into the record of "a".
+        let mut drive = vec![0u8; 2 * BLOCK_SIZE];
+        let block = &mut drive[BLOCK_SIZE..];
+        write_dirent(block, 0, 0, 16, b"secret");
+        write_dirent(block, 16, 12, 4068, b"a");
+        write_dirent(block, 28, 13, 4056, b"old");
+        write_dirent(block, 4084, 0, 12, b"");
+        block[4091] = DIRENT_TAIL_FILE_TYPE;
+        let csum = csum::dir_block_csum(block, 4084, 2, 7, &fs).unwrap();
+        block[4092..].copy_from_slice(&csum.to_le_bytes());
+        let original = drive.clone();
+
+        let mut ctx = test_context(&drive);
+        let cfg = Config { scrub_dirents: true, ..Config::default() };
+        scrub_dir_blocks(2, &inode, &[1], &[], &fs, &mut ctx, &cfg).unwrap();
+        assert_eq!(read_drive(&mut ctx), original);
+
+        assert!(ctx.scrub_writes.is_empty());
+
+        // Nothing is written before the writes are applied.
+        let cfg = Config { report_only: false, ..cfg };
+        scrub_dir_blocks(2, &inode, &[1], &[], &fs, &mut ctx, &cfg).unwrap();
+        assert_eq!(read_drive(&mut ctx), original);
+        assert_eq!(apply(&mut ctx).unwrap(), 1);
+        let drive = read_drive(&mut ctx);
+        let block = &drive[BLOCK_SIZE..];
+        assert_eq!(&block[..8], &[0, 0, 0, 0, 16, 0, 0, 0]);
+        assert!(block[8..16].iter().all(|b| *b == 0));
+        assert_eq!(&block[16..28], &original[BLOCK_SIZE + 16..BLOCK_SIZE + 28]);
+        assert!(block[28..4084].iter().all(|b| *b == 0));
+        let csum = csum::dir_block_csum(block, 4084, 2, 7, &fs).unwrap();
+        assert_eq!(&block[4092..], &csum.to_le_bytes());
+
+        // The checksum depends on the owner: the block of another directory is left alone.
+        let mut ctx = test_context(&original);
+        scrub_dir_blocks(3, &inode, &[1], &[], &fs, &mut ctx, &cfg).unwrap();
+        assert!(ctx.scrub_writes.is_empty());
+
+        // Neither are the htree nodes scrubbed.
+        scrub_dir_blocks(2, &inode, &[1], &[0], &fs, &mut ctx, &cfg).unwrap();
+        assert!(ctx.scrub_writes.is_empty());
+    }
+
+    #[test]
+    fn hashed_dirents()
+    {
+        // "." without a hash, "name" with its hash (8 bytes at 24), followed by a remnant.
+        let mut block = vec![0u8; 64];
+        write_dirent(&mut block, 0, 2, 12, b".");
+        write_dirent(&mut block, 12, 12, 52, b"name");
+        block[24..32].fill(0x11);
+        block[32..40].fill(0x22);
+
+        let mut plain = block.clone();
+        assert!(scrub_dir_block(&mut plain, 1, BLOCK_SIZE, false).unwrap());
+        assert!(plain[24..].iter().all(|b| *b == 0));
+
+        assert!(scrub_dir_block(&mut block, 1, BLOCK_SIZE, true).unwrap());
+        assert_eq!(&block[12..24], &plain[12..24]);
+        assert_eq!(&block[24..32], &[0x11; 8]);
+        assert!(block[32..].iter().all(|b| *b == 0));
+    }
+}
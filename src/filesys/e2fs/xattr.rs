@@ -0,0 +1,243 @@
+use std::io::{Read, Seek, SeekFrom};
+use anyhow::bail;
+
+use crate::usage_map::{UsageMap, AllocStatus};
+use crate::Context;
+
+use super::inode::GOOD_OLD_INODE_SIZE;
+use super::Fs;
+use crate::bs;
+
+// Reference: https://www.kernel.org/doc/html/latest/filesystems/ext4/attributes.html
+
+const XATTR_MAGIC: u32 = 0xea020000;
+/// The size of the header of an xattr block.
+const XATTR_BLOCK_HEADER_SIZE: usize = 32;
+/// The size of an entry, without the name.
+const XATTR_ENTRY_SIZE: usize = 16;
+
+/// Scans an extended attribute block, referenced by `i_file_acl`.
+/// The block may be shared by multiple inodes. The values that do not fit into the block are
+/// stored in EA inodes (ea_inode), which are scanned along with the other inodes.
+pub fn scan_xattr_block(map: &mut UsageMap, block: u64, fs: &Fs, ctx: &mut Context)
+    -> anyhow::Result<()>
+{
+    if block >= fs.blocks_count {
+        bail!("the xattr block {} lies beyond the file system", block);
+    }
+
+    let block_size = bs!(fs.sb.s_log_block_size);
+    map.update(block * block_size, block_size, AllocStatus::Used);
+
+    let mut buf = vec![0u8; block_size as usize];
+    ctx.drive.seek(SeekFrom::Start(block * block_size))?;
+    ctx.drive.read_exact(&mut buf)?;
+
+    let magic = read_u32(&buf, 0);
+    let h_blocks = read_u32(&buf, 8);
+    if magic != XATTR_MAGIC {
+        bail!("xattr block {} has invalid magic: {:#010x}", block, magic);
+    }
+    if h_blocks != 1 {
+        bail!("xattr block {} spans {} blocks", block, h_blocks);
+    }
+
+    // The value offsets are relative to the start of the block.
+    check_entries(&buf, XATTR_BLOCK_HEADER_SIZE, fs)
+}
+
+/// Checks the extended attributes stored in the inode, after `i_extra_isize`.
+pub fn check_ibody(raw_inode: &[u8], i_extra_isize: u16, fs: &Fs) -> anyhow::Result<()>
+{
+    match ibody(raw_inode, i_extra_isize) {
+        Some(body) => check_entries(body, 0, fs),
+        None => Ok(()),
+    }
+}
+
+/// Returns the size of the value of an attribute stored in the inode, if the inode has it.
+pub fn ibody_value_size(raw_inode: &[u8], i_extra_isize: u16, name_index: u8, name: &[u8])
+    -> anyhow::Result<Option<usize>>
+{
+    let body = match ibody(raw_inode, i_extra_isize) {
+        Some(body) => body,
+        None => return Ok(None),
+    };
+
+    let entry = parse_entries(body, 0)?
+        .into_iter()
+        .find(|e| e.name_index == name_index && &body[e.name.clone()] == name);
+
+    Ok(entry.map(|e| e.value_size))
+}
+
+/// Returns the extended attributes stored in the inode, after the magic value. The value offsets
+/// are relative to the first entry.
+fn ibody(raw_inode: &[u8], i_extra_isize: u16) -> Option<&[u8]>
+{
+    let start = GOOD_OLD_INODE_SIZE as usize + i_extra_isize as usize;
+    if start + 4 > raw_inode.len() || read_u32(raw_inode, start) != XATTR_MAGIC {
+        return None;
+    }
+
+    Some(&raw_inode[start + 4..])
+}
+
+/// An xattr entry.
+struct Entry {
+    offset: usize,
+    name_index: u8,
+    /// The position of the name in the buffer.
+    name: std::ops::Range<usize>,
+    value_offs: usize,
+    value_inum: u32,
+    value_size: usize,
+}
+
+/// Parses a list of xattr entries, starting at `offset` and terminated by 4 zero bytes.
+fn parse_entries(buf: &[u8], mut offset: usize) -> anyhow::Result<Vec<Entry>>
+{
+    let mut entries = Vec::new();
+
+    loop {
+        if offset + 4 > buf.len() {
+            bail!("the xattr entries are not terminated");
+        }
+        if read_u32(buf, offset) == 0 {
+            return Ok(entries);
+        }
+
+        let name_len = buf[offset] as usize;
+        let entry_size = (XATTR_ENTRY_SIZE + name_len).next_multiple_of(4);
+        if offset + entry_size > buf.len() {
+            bail!("xattr entry at {} is truncated", offset);
+        }
+
+        entries.push(Entry {
+            offset,
+            name_index: buf[offset + 1],
+            name: offset + XATTR_ENTRY_SIZE..offset + XATTR_ENTRY_SIZE + name_len,
+            value_offs: u16::from_le_bytes([buf[offset + 2], buf[offset + 3]]) as usize,
+            value_inum: read_u32(buf, offset + 4),
+            value_size: read_u32(buf, offset + 8) as usize,
+        });
+        offset += entry_size;
+    }
+}
+
+/// Checks a list of xattr entries, starting at `offset`.
+/// Values have to lie within the buffer, unless they are stored in an EA inode.
+fn check_entries(buf: &[u8], offset: usize, fs: &Fs) -> anyhow::Result<()>
+{
+    let has_ea_inode = fs.opts.dyn_cfg.is_some_and(|dyn_cfg| dyn_cfg.incompat.has_ea_inode());
+
+    for e in parse_entries(buf, offset)? {
+        if e.value_inum != 0 {
+            if !has_ea_inode {
+                bail!("xattr entry at {} refers to an EA inode, but ea_inode is not set", e.offset);
+            }
+            if e.value_inum > fs.sb.s_inodes_count {
+                bail!("xattr entry at {} refers to an invalid inode: {}", e.offset, e.value_inum);
+            }
+        } else if e.value_offs + e.value_size > buf.len() {
+            bail!("the value of the xattr entry at {} lies beyond the attributes", e.offset);
+        }
+    }
+
+    Ok(())
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> u32
+{
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+// Tests.
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::test_context;
+    use crate::filesys::e2fs::SuperBlock;
+    use crate::filesys::e2fs::tests::{test_fs, test_sb};
+
+    const BLOCK_SIZE: usize = 4096;
+
+    /// Writes an entry with a one-letter name into the buffer.
+    fn write_entry(buf: &mut [u8], offset: usize, value_offs: u16, inum: u32, size: u32)
+    {
+        buf[offset] = 1;
+        buf[offset + 1] = 1;
+        buf[offset + 2..offset + 4].copy_from_slice(&value_offs.to_le_bytes());
+        buf[offset + 4..offset + 8].copy_from_slice(&inum.to_le_bytes());
+        buf[offset + 8..offset + 12].copy_from_slice(&size.to_le_bytes());
+        buf[offset + 16] = b'a';
+    }
+
+    /// Creates a drive with an xattr block at block 10 and returns it.
+    fn test_drive(inum: u32, value_offs: u16, size: u32) -> Vec<u8>
+    {
+        let mut drive = vec![0u8; 64 * BLOCK_SIZE];
+        let block = &mut drive[10 * BLOCK_SIZE..11 * BLOCK_SIZE];
+        block[0..4].copy_from_slice(&XATTR_MAGIC.to_le_bytes());
+        block[4..8].copy_from_slice(&2u32.to_le_bytes());
+        block[8..12].copy_from_slice(&1u32.to_le_bytes());
+        write_entry(block, XATTR_BLOCK_HEADER_SIZE, value_offs, inum, size);
+
+        drive
+    }
+
+    #[test]
+    fn block()
+    {
+        let fs = test_fs(test_sb());
+        let mut map = UsageMap::new(64 * BLOCK_SIZE as u64);
+
+        let mut ctx = test_context(&test_drive(0, 4000, 96));
+        scan_xattr_block(&mut map, 10, &fs, &mut ctx).unwrap();
+        assert_eq!(map.segments()[1].start, 10 * BLOCK_SIZE as u64);
+        assert_eq!(map.segments()[1].end, 11 * BLOCK_SIZE as u64);
+        assert_eq!(map.segments()[1].status, AllocStatus::Used);
+
+        // The value overflows the block.
+        let mut ctx = test_context(&test_drive(0, 4000, 97));
+        assert!(scan_xattr_block(&mut map, 10, &fs, &mut ctx).is_err());
+
+        // Not an xattr block.
+        assert!(scan_xattr_block(&mut map, 11, &fs, &mut ctx).is_err());
+        assert!(scan_xattr_block(&mut map, 64, &fs, &mut ctx).is_err());
+    }
+
+    #[test]
+    fn ea_inode()
+    {
+        let mut map = UsageMap::new(64 * BLOCK_SIZE as u64);
+        let mut ctx = test_context(&test_drive(12, 0, 1 << 20));
+
+        let fs = test_fs(test_sb());
+        assert!(scan_xattr_block(&mut map, 10, &fs, &mut ctx).is_err());
+
+        let fs = test_fs(SuperBlock { s_feature_incompat: 0x400, ..test_sb() });
+        scan_xattr_block(&mut map, 10, &fs, &mut ctx).unwrap();
+
+        let mut ctx = test_context(&test_drive(33, 0, 1 << 20));
+        assert!(scan_xattr_block(&mut map, 10, &fs, &mut ctx).is_err());
+    }
+
+    #[test]
+    fn ibody()
+    {
+        let fs = test_fs(test_sb());
+        let mut raw_inode = vec![0u8; 256];
+
+        // No in-inode attributes.
+        check_ibody(&raw_inode, 32, &fs).unwrap();
+
+        raw_inode[160..164].copy_from_slice(&XATTR_MAGIC.to_le_bytes());
+        write_entry(&mut raw_inode[164..], 0, 64, 0, 8);
+        check_ibody(&raw_inode, 32, &fs).unwrap();
+
+        write_entry(&mut raw_inode[164..], 0, 64, 0, 64);
+        assert!(check_ibody(&raw_inode, 32, &fs).is_err());
+    }
+}
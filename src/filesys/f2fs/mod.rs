@@ -0,0 +1,879 @@
+use std::collections::HashMap;
+use std::io::{Read, Seek, SeekFrom};
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+use bincode::{DefaultOptions, Options};
+
+use crate::anomaly::AnomalyKind;
+use crate::array::Array;
+use crate::map_format::FsIdentity;
+use crate::usage_map::{AllocStatus, UsageMap};
+use crate::{Config, Context};
+
+// Reference: https://elixir.bootlin.com/linux/latest/source/include/linux/f2fs_fs.h
+
+pub const F2FS_MAGIC: u32 = 0xf2f5_2010;
+
+/// The offset of the superblock; a backup follows in the next block.
+const SUPERBLOCK_OFFSET: u64 = 1024;
+/// The offset of `cp_payload` in the superblock.
+const CP_PAYLOAD_OFFSET: u64 = SUPERBLOCK_OFFSET + 1664;
+
+const LOG_BLOCK_SIZE: u32 = 12;
+const BLOCK_SIZE: u64 = 1 << LOG_BLOCK_SIZE;
+const LOG_BLOCKS_PER_SEG: u32 = 9;
+const BLOCKS_PER_SEG: u64 = 1 << LOG_BLOCKS_PER_SEG;
+
+/// The number of SIT entries in a SIT block.
+const SIT_ENTRY_PER_BLOCK: u64 = 55;
+const SIT_ENTRY_SIZE: usize = 74;
+/// The mask of the valid block count in `vblocks`; the upper bits hold the segment type.
+const SIT_VBLOCKS_MASK: u16 = 0x3ff;
+
+/// The number of NAT entries in a NAT block.
+const NAT_ENTRY_PER_BLOCK: u64 = 455;
+/// The size of a NAT entry: the version, the inode number and the block address.
+const NAT_ENTRY_SIZE: usize = 9;
+/// The address of a node that has been allocated, but not written yet.
+const NEW_ADDR: u32 = 0xffff_ffff;
+
+/// The offset of the SIT and NAT version bitmaps in the checkpoint.
+const CP_BITMAP_OFFSET: usize = 192;
+
+const CP_UMOUNT_FLAG: u32 = 0x1;
+const CP_COMPACT_SUM_FLAG: u32 = 0x4;
+const CP_ERROR_FLAG: u32 = 0x8;
+const CP_LARGE_NAT_BITMAP_FLAG: u32 = 0x400;
+
+/// The size of the summary entries of a summary block; the journal follows them.
+const SUM_ENTRIES_SIZE: usize = 512 * 7;
+const SUM_JOURNAL_SIZE: usize = 507;
+/// The size of a SIT journal entry: the segment number and the SIT entry.
+const SIT_JOURNAL_ENTRY_SIZE: usize = 4 + SIT_ENTRY_SIZE;
+const SIT_JOURNAL_ENTRIES: usize = (SUM_JOURNAL_SIZE - 2) / SIT_JOURNAL_ENTRY_SIZE;
+/// The size of a NAT journal entry: the node ID and the NAT entry.
+const NAT_JOURNAL_ENTRY_SIZE: usize = 4 + NAT_ENTRY_SIZE;
+const NAT_JOURNAL_ENTRIES: usize = (SUM_JOURNAL_SIZE - 2) / NAT_JOURNAL_ENTRY_SIZE;
+
+/// F2FS superblock, up to the UUID.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq, Default)]
+pub struct SuperBlock {
+    pub magic: u32,
+    pub major_ver: u16,
+    pub minor_ver: u16,
+    pub log_sectorsize: u32,
+    pub log_sectors_per_block: u32,
+    pub log_blocksize: u32,
+    pub log_blocks_per_seg: u32,
+    pub segs_per_sec: u32,
+    pub secs_per_zone: u32,
+    pub checksum_offset: u32,
+    pub block_count: u64,
+    pub section_count: u32,
+    pub segment_count: u32,
+    pub segment_count_ckpt: u32,
+    pub segment_count_sit: u32,
+    pub segment_count_nat: u32,
+    pub segment_count_ssa: u32,
+    pub segment_count_main: u32,
+    pub segment0_blkaddr: u32,
+    pub cp_blkaddr: u32,
+    pub sit_blkaddr: u32,
+    pub nat_blkaddr: u32,
+    pub ssa_blkaddr: u32,
+    pub main_blkaddr: u32,
+    pub root_ino: u32,
+    pub node_ino: u32,
+    pub meta_ino: u32,
+    pub uuid: [u8; 16],
+}
+
+/// F2FS checkpoint, up to the version bitmaps.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq, Default)]
+pub struct Checkpoint {
+    pub checkpoint_ver: u64,
+    pub user_block_count: u64,
+    pub valid_block_count: u64,
+    pub rsvd_segment_count: u32,
+    pub overprov_segment_count: u32,
+    pub free_segment_count: u32,
+    pub cur_node_segno: [u32; 8],
+    pub cur_node_blkoff: [u16; 8],
+    pub cur_data_segno: [u32; 8],
+    pub cur_data_blkoff: [u16; 8],
+    pub ckpt_flags: u32,
+    pub cp_pack_total_block_count: u32,
+    pub cp_pack_start_sum: u32,
+    pub valid_node_count: u32,
+    pub valid_inode_count: u32,
+    pub next_free_nid: u32,
+    pub sit_ver_bitmap_bytesize: u32,
+    pub nat_ver_bitmap_bytesize: u32,
+    pub checksum_offset: u32,
+    pub elapsed_time: u64,
+    pub alloc_type: [u8; 16],
+}
+
+/// An entry of the segment information table.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq, Eq, Default)]
+pub struct SitEntry {
+    pub vblocks: u16,
+    /// The valid blocks of the segment, most significant bit first.
+    pub valid_map: Array<u8, 64>,
+    pub mtime: u64,
+}
+
+/// A valid checkpoint, with the raw block that holds its version bitmaps.
+struct ValidCheckpoint {
+    cp: Checkpoint,
+    raw: Vec<u8>,
+    /// The address of the checkpoint pack.
+    pack_addr: u64,
+}
+
+/// Reads the superblock, if the drive contains F2FS.
+pub fn read_superblock(ctx: &mut Context) -> anyhow::Result<Option<SuperBlock>>
+{
+    let bincode_opt = DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes();
+
+    ctx.drive.seek(SeekFrom::Start(SUPERBLOCK_OFFSET))?;
+    let sb: SuperBlock = bincode_opt.deserialize_from(&ctx.drive)?;
+
+    if sb.magic != F2FS_MAGIC {
+        return Ok(None);
+    }
+
+    Ok(Some(sb))
+}
+
+/// Scans an F2FS volume and returns a map of its usage.
+/// The whole volume is marked as used, except for the blocks of the main area that are not valid
+/// in the SIT of the current checkpoint. The node blocks are accounted for in the SIT like the data
+/// blocks; the NAT is then checked against it.
+pub fn scan_drive(ctx: &mut Context, cfg: &Config) -> anyhow::Result<UsageMap>
+{
+    let sb = match read_superblock(ctx)? {
+        Some(sb) => sb,
+        None => bail!("bad F2FS magic value"),
+    };
+    check_superblock(&sb)?;
+
+    let mut cp_payload = [0u8; 4];
+    ctx.drive.seek(SeekFrom::Start(CP_PAYLOAD_OFFSET))?;
+    ctx.drive.read_exact(&mut cp_payload)?;
+    if u32::from_le_bytes(cp_payload) != 0 {
+        bail!("unsupported checkpoint layout: checkpoint payload blocks");
+    }
+
+    let drive_size = ctx.drive.seek(SeekFrom::End(0))?;
+    let fs_size = sb.block_count * BLOCK_SIZE;
+    if fs_size > drive_size {
+        bail!("the F2FS volume ({} bytes) is larger than the drive ({} bytes)", fs_size, drive_size);
+    }
+
+    let checkpoint = read_checkpoint(&sb, ctx)?;
+    let flags = checkpoint.cp.ckpt_flags;
+
+    ctx.logger.logln(1, &format!("using checkpoint version {}", checkpoint.cp.checkpoint_ver));
+
+    // Every checkpoint gets a new version, so the version tells whether the volume was written to.
+    ctx.fs_identity = Some(FsIdentity {
+        uuid: crate::util::format_uuid(&sb.uuid),
+        write_time: checkpoint.cp.checkpoint_ver,
+    });
+
+    if flags & CP_ERROR_FLAG != 0 {
+        bail!("errors present in the filesystem");
+    }
+    // Fsynced data written after the checkpoint is recovered from blocks that the checkpoint
+    // considers free.
+    if flags & CP_UMOUNT_FLAG == 0 && !cfg.ignore_recovery {
+        bail!("filesystem was not unmounted cleanly: try to mount and unmount it, or run fsck");
+    }
+    if flags & CP_LARGE_NAT_BITMAP_FLAG != 0 {
+        bail!("unsupported checkpoint layout: large NAT bitmap");
+    }
+
+    let journal = read_sit_journal(&checkpoint, ctx)?;
+
+    let mut map = UsageMap::new(drive_size);
+    map.update(0, fs_size, AllocStatus::Used);
+
+    let main_start = sb.main_blkaddr as u64;
+    let sit_copy_offset = (sb.segment_count_sit as u64 / 2) * BLOCKS_PER_SEG;
+    let version_bitmap = sit_version_bitmap(&checkpoint)?;
+
+    let mut sit_block = Vec::new();
+    let mut sit_block_num = None;
+    let mut run_start = None;
+
+    for segno in 0..=sb.segment_count_main as u64 {
+        let entry = if segno == sb.segment_count_main as u64 {
+            None
+        } else if let Some(entry) = journal.get(&segno) {
+            Some(*entry)
+        } else {
+            // SIT blocks alternate between the two copies, as selected by the version bitmap.
+            let block_num = segno / SIT_ENTRY_PER_BLOCK;
+            if sit_block_num != Some(block_num) {
+                let mut addr = sb.sit_blkaddr as u64 + block_num;
+                if test_bit(version_bitmap, block_num as usize) {
+                    addr += sit_copy_offset;
+                }
+
+                sit_block = vec![0u8; BLOCK_SIZE as usize];
+                ctx.drive.seek(SeekFrom::Start(addr * BLOCK_SIZE))?;
+                ctx.drive.read_exact(&mut sit_block)?;
+                sit_block_num = Some(block_num);
+            }
+
+            let offset = (segno % SIT_ENTRY_PER_BLOCK) as usize * SIT_ENTRY_SIZE;
+            Some(decode_sit_entry(&sit_block[offset..offset + SIT_ENTRY_SIZE])?)
+        };
+
+        let seg_start = main_start + segno * BLOCKS_PER_SEG;
+
+        for blk in 0..BLOCKS_PER_SEG {
+            let free = match &entry {
+                Some(entry) => !test_bit(&entry.valid_map.0, blk as usize),
+                None => false,
+            };
+
+            match (free, run_start) {
+                (true, None) => run_start = Some(seg_start + blk),
+                (false, Some(first)) => {
+                    let end = seg_start + blk;
+                    map.update(first * BLOCK_SIZE, (end - first) * BLOCK_SIZE, AllocStatus::Free);
+                    run_start = None;
+                },
+                _ => {},
+            }
+
+            if entry.is_none() {
+                break;
+            }
+        }
+    }
+
+    check_nodes(&sb, &checkpoint, &mut map, ctx)?;
+
+    Ok(map)
+}
+
+/// Checks that the superblock describes a layout that is supported.
+fn check_superblock(sb: &SuperBlock) -> anyhow::Result<()>
+{
+    if sb.log_blocksize != LOG_BLOCK_SIZE {
+        bail!("unsupported block size: 2^{}", sb.log_blocksize);
+    }
+    if sb.log_blocks_per_seg != LOG_BLOCKS_PER_SEG {
+        bail!("unsupported segment size: 2^{} blocks", sb.log_blocks_per_seg);
+    }
+    if sb.segment_count_ckpt < 2 || sb.segment_count_sit < 2 {
+        bail!("invalid layout: missing checkpoint or SIT segments");
+    }
+    if sb.segment_count_sit as u64 / 2 * BLOCKS_PER_SEG * SIT_ENTRY_PER_BLOCK
+        < sb.segment_count_main as u64
+    {
+        bail!("invalid layout: the SIT is too small for the main area");
+    }
+
+    let main_end = sb.main_blkaddr as u64 + sb.segment_count_main as u64 * BLOCKS_PER_SEG;
+    if main_end > sb.block_count {
+        bail!("invalid layout: the main area reaches beyond the end of the volume");
+    }
+    let nat_end = sb.nat_blkaddr as u64 + sb.segment_count_nat as u64 * BLOCKS_PER_SEG;
+    if nat_end > sb.block_count {
+        bail!("invalid layout: the NAT reaches beyond the end of the volume");
+    }
+
+    Ok(())
+}
+
+/// Reads both checkpoint packs and returns the newest valid checkpoint. A checkpoint is valid if
+/// the blocks at both ends of its pack have valid checksums and the same version.
+fn read_checkpoint(sb: &SuperBlock, ctx: &mut Context) -> anyhow::Result<ValidCheckpoint>
+{
+    let mut newest: Option<ValidCheckpoint> = None;
+
+    for pack in 0..2 {
+        let pack_addr = sb.cp_blkaddr as u64 + pack * BLOCKS_PER_SEG;
+
+        let (head, raw) = match read_cp_block(pack_addr, ctx)? {
+            Some(head) => head,
+            None => continue,
+        };
+        if head.cp_pack_total_block_count < 2
+            || head.cp_pack_total_block_count as u64 > BLOCKS_PER_SEG
+        {
+            continue;
+        }
+
+        let tail_addr = pack_addr + head.cp_pack_total_block_count as u64 - 1;
+        match read_cp_block(tail_addr, ctx)? {
+            Some((tail, _)) if tail.checkpoint_ver == head.checkpoint_ver => {},
+            _ => continue,
+        }
+
+        if newest.as_ref().is_none_or(|n| head.checkpoint_ver > n.cp.checkpoint_ver) {
+            newest = Some(ValidCheckpoint { cp: head, raw, pack_addr });
+        }
+    }
+
+    match newest {
+        Some(checkpoint) => Ok(checkpoint),
+        None => bail!("no valid checkpoint"),
+    }
+}
+
+/// Reads a checkpoint block. Returns None if its checksum is not valid.
+fn read_cp_block(addr: u64, ctx: &mut Context) -> anyhow::Result<Option<(Checkpoint, Vec<u8>)>>
+{
+    let bincode_opt = DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes();
+
+    let mut raw = vec![0u8; BLOCK_SIZE as usize];
+    ctx.drive.seek(SeekFrom::Start(addr * BLOCK_SIZE))?;
+    ctx.drive.read_exact(&mut raw)?;
+
+    let cp: Checkpoint = bincode_opt.deserialize(&raw)?;
+
+    let csum_offset = cp.checksum_offset as usize;
+    if !(CP_BITMAP_OFFSET..=raw.len() - 4).contains(&csum_offset) {
+        return Ok(None);
+    }
+
+    let mut stored = [0u8; 4];
+    stored.copy_from_slice(&raw[csum_offset..csum_offset + 4]);
+    if u32::from_le_bytes(stored) != f2fs_crc32(&raw[..csum_offset]) {
+        return Ok(None);
+    }
+
+    Ok(Some((cp, raw)))
+}
+
+/// Returns the SIT version bitmap of a checkpoint.
+fn sit_version_bitmap(checkpoint: &ValidCheckpoint) -> anyhow::Result<&[u8]>
+{
+    let end = CP_BITMAP_OFFSET + checkpoint.cp.sit_ver_bitmap_bytesize as usize;
+    if end > checkpoint.cp.checksum_offset as usize {
+        bail!("the SIT version bitmap overlaps the checkpoint checksum");
+    }
+
+    Ok(&checkpoint.raw[CP_BITMAP_OFFSET..end])
+}
+
+/// Reads the SIT journal, i.e. the SIT entries that are newer than the SIT.
+fn read_sit_journal(
+    checkpoint: &ValidCheckpoint,
+    ctx: &mut Context,
+) -> anyhow::Result<HashMap<u64, SitEntry>>
+{
+    let journal = read_journal(checkpoint, Journal::Sit, ctx)?;
+
+    let n_sits = u16::from_le_bytes([journal[0], journal[1]]) as usize;
+    if n_sits > SIT_JOURNAL_ENTRIES {
+        bail!("invalid SIT journal: {} entries", n_sits);
+    }
+
+    let mut entries = HashMap::new();
+
+    for i in 0..n_sits {
+        let raw = &journal[2 + i * SIT_JOURNAL_ENTRY_SIZE..2 + (i + 1) * SIT_JOURNAL_ENTRY_SIZE];
+        let segno = u32::from_le_bytes([raw[0], raw[1], raw[2], raw[3]]) as u64;
+
+        entries.insert(segno, decode_sit_entry(&raw[4..])?);
+    }
+
+    Ok(entries)
+}
+
+/// Reads the NAT journal, i.e. the node addresses that are newer than the NAT, by node ID.
+fn read_nat_journal(
+    checkpoint: &ValidCheckpoint,
+    ctx: &mut Context,
+) -> anyhow::Result<HashMap<u64, u32>>
+{
+    let journal = read_journal(checkpoint, Journal::Nat, ctx)?;
+
+    let n_nats = u16::from_le_bytes([journal[0], journal[1]]) as usize;
+    if n_nats > NAT_JOURNAL_ENTRIES {
+        bail!("invalid NAT journal: {} entries", n_nats);
+    }
+
+    let mut entries = HashMap::new();
+
+    for i in 0..n_nats {
+        let raw = &journal[2 + i * NAT_JOURNAL_ENTRY_SIZE..2 + (i + 1) * NAT_JOURNAL_ENTRY_SIZE];
+        let nid = u32::from_le_bytes([raw[0], raw[1], raw[2], raw[3]]) as u64;
+
+        entries.insert(nid, nat_block_addr(&raw[4..]));
+    }
+
+    Ok(entries)
+}
+
+/// The journals of the current segment summaries.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Journal {
+    Nat,
+    Sit,
+}
+
+/// Reads a journal: the NAT journal from the summary of the hot data log, the SIT journal from
+/// that of the cold data log, or either from the compacted summaries.
+fn read_journal(
+    checkpoint: &ValidCheckpoint,
+    kind: Journal,
+    ctx: &mut Context,
+) -> anyhow::Result<Vec<u8>>
+{
+    let sum_addr = checkpoint.pack_addr + checkpoint.cp.cp_pack_start_sum as u64;
+    let (addr, offset) = match (checkpoint.cp.ckpt_flags & CP_COMPACT_SUM_FLAG != 0, kind) {
+        // The NAT journal and then the SIT journal.
+        (true, Journal::Nat) => (sum_addr, 0),
+        (true, Journal::Sit) => (sum_addr, SUM_JOURNAL_SIZE),
+        // The hot, warm and then the cold data summary.
+        (false, Journal::Nat) => (sum_addr, SUM_ENTRIES_SIZE),
+        (false, Journal::Sit) => (sum_addr + 2, SUM_ENTRIES_SIZE),
+    };
+
+    let mut journal = vec![0u8; SUM_JOURNAL_SIZE];
+    ctx.drive.seek(SeekFrom::Start(addr * BLOCK_SIZE + offset as u64))?;
+    ctx.drive.read_exact(&mut journal)?;
+
+    Ok(journal)
+}
+
+/// Returns the NAT version bitmap of a checkpoint, which follows the SIT version bitmap.
+fn nat_version_bitmap(checkpoint: &ValidCheckpoint) -> anyhow::Result<&[u8]>
+{
+    let start = CP_BITMAP_OFFSET + checkpoint.cp.sit_ver_bitmap_bytesize as usize;
+    let end = start + checkpoint.cp.nat_ver_bitmap_bytesize as usize;
+    if end > checkpoint.cp.checksum_offset as usize {
+        bail!("the NAT version bitmap overlaps the checkpoint checksum");
+    }
+
+    Ok(&checkpoint.raw[start..end])
+}
+
+/// Checks the node blocks against the map: every node in the NAT (or its journal) must lie in the
+/// main area, in a block that is valid in the SIT. A node block that the SIT considers free is
+/// logged, counted among the scan anomalies and kept.
+fn check_nodes(
+    sb: &SuperBlock,
+    checkpoint: &ValidCheckpoint,
+    map: &mut UsageMap,
+    ctx: &mut Context,
+) -> anyhow::Result<()>
+{
+    let mut journal = read_nat_journal(checkpoint, ctx)?;
+    let version_bitmap = nat_version_bitmap(checkpoint)?;
+
+    let nat_blocks = sb.segment_count_nat as u64 / 2 * BLOCKS_PER_SEG;
+    let main_start = sb.main_blkaddr as u64;
+    let main_end = main_start + sb.segment_count_main as u64 * BLOCKS_PER_SEG;
+
+    let mut nat_block = vec![0u8; BLOCK_SIZE as usize];
+    let mut nodes = 0;
+
+    for block_num in 0..nat_blocks {
+        // The NAT segments alternate between the two copies, and the blocks within them are
+        // selected by the version bitmap.
+        let seg_num = block_num / BLOCKS_PER_SEG;
+        let mut addr = sb.nat_blkaddr as u64
+            + seg_num * 2 * BLOCKS_PER_SEG
+            + block_num % BLOCKS_PER_SEG;
+        if test_bit(version_bitmap, block_num as usize) {
+            addr += BLOCKS_PER_SEG;
+        }
+
+        ctx.drive.seek(SeekFrom::Start(addr * BLOCK_SIZE))?;
+        ctx.drive.read_exact(&mut nat_block)?;
+
+        for i in 0..NAT_ENTRY_PER_BLOCK {
+            let nid = block_num * NAT_ENTRY_PER_BLOCK + i;
+            let block_addr = match journal.remove(&nid) {
+                Some(block_addr) => block_addr,
+                None => nat_block_addr(&nat_block[i as usize * NAT_ENTRY_SIZE..]),
+            };
+
+            // The node and meta inodes are not stored in nodes; their entries hold a placeholder.
+            if block_addr == 0 || block_addr == NEW_ADDR
+                || nid == sb.node_ino as u64 || nid == sb.meta_ino as u64
+            {
+                continue;
+            }
+            nodes += 1;
+
+            let block = block_addr as u64;
+            if !(main_start..main_end).contains(&block) {
+                bail!(
+                    "invalid NAT entry: node {} is at block {}, outside of the main area",
+                    nid,
+                    block,
+                );
+            }
+            if map.status_at(block * BLOCK_SIZE) == Some(AllocStatus::Free) {
+                ctx.logger.logln(0, &format!(
+                    "warning: node {} is at block {}, which is free in the SIT: the block is kept",
+                    nid,
+                    block,
+                ));
+                ctx.anomalies.record(AnomalyKind::FreeNodeBlock);
+                map.update(block * BLOCK_SIZE, BLOCK_SIZE, AllocStatus::Used);
+            }
+        }
+    }
+
+    if let Some(nid) = journal.keys().min() {
+        bail!("invalid NAT journal: node {} is outside of the NAT", nid);
+    }
+
+    ctx.logger.logln(1, &format!("checked {} nodes against the SIT", nodes));
+
+    Ok(())
+}
+
+/// Returns the block address of a NAT entry.
+fn nat_block_addr(raw: &[u8]) -> u32
+{
+    u32::from_le_bytes([raw[5], raw[6], raw[7], raw[8]])
+}
+
+/// Decodes a SIT entry and checks its valid block count against its bitmap.
+fn decode_sit_entry(raw: &[u8]) -> anyhow::Result<SitEntry>
+{
+    let bincode_opt = DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes();
+
+    let entry: SitEntry = bincode_opt.deserialize(raw)?;
+
+    let valid: u32 = entry.valid_map.0.iter().map(|b| b.count_ones()).sum();
+    if valid != (entry.vblocks & SIT_VBLOCKS_MASK) as u32 {
+        bail!(
+            "inconsistent SIT entry: {} valid blocks, {} in the bitmap",
+            entry.vblocks & SIT_VBLOCKS_MASK,
+            valid,
+        );
+    }
+
+    Ok(entry)
+}
+
+/// Tests a bit of an F2FS bitmap, most significant bit first.
+fn test_bit(bitmap: &[u8], idx: usize) -> bool
+{
+    match bitmap.get(idx / 8) {
+        Some(byte) => byte & (0x80 >> (idx % 8)) != 0,
+        None => false,
+    }
+}
+
+/// The F2FS crc32: the little-endian crc32, seeded with the magic value, without the final
+/// inversion.
+fn f2fs_crc32(buf: &[u8]) -> u32
+{
+    !crc::crc32::update(!F2FS_MAGIC, &crc::crc32::IEEE_TABLE, buf)
+}
+
+// Tests.
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::usage_map::Segment;
+    use crate::util::test_context;
+
+    // The layout of the test volume, in blocks: the superblocks, 2 segments of checkpoints,
+    // 2 segments of SIT, 2 segments of NAT and 2 main segments.
+    const CP_ADDR: u64 = 512;
+    const SIT_ADDR: u64 = 1536;
+    const NAT_ADDR: u64 = 2560;
+    const MAIN_ADDR: u64 = 3584;
+    const VOLUME_BLOCKS: u64 = MAIN_ADDR + 2 * 512;
+
+    fn test_sb() -> SuperBlock
+    {
+        SuperBlock {
+            magic: F2FS_MAGIC,
+            major_ver: 1,
+            minor_ver: 16,
+            log_sectorsize: 9,
+            log_sectors_per_block: 3,
+            log_blocksize: 12,
+            log_blocks_per_seg: 9,
+            segs_per_sec: 1,
+            secs_per_zone: 1,
+            block_count: VOLUME_BLOCKS,
+            section_count: 2,
+            segment_count: 8,
+            segment_count_ckpt: 2,
+            segment_count_sit: 2,
+            segment_count_nat: 2,
+            segment_count_main: 2,
+            segment0_blkaddr: 512,
+            cp_blkaddr: CP_ADDR as u32,
+            sit_blkaddr: SIT_ADDR as u32,
+            nat_blkaddr: NAT_ADDR as u32,
+            ssa_blkaddr: MAIN_ADDR as u32,
+            main_blkaddr: MAIN_ADDR as u32,
+            root_ino: 3,
+            node_ino: 1,
+            meta_ino: 2,
+            ..SuperBlock::default()
+        }
+    }
+
+    fn test_cp(version: u64, flags: u32) -> Checkpoint
+    {
+        Checkpoint {
+            checkpoint_ver: version,
+            ckpt_flags: flags,
+            cp_pack_total_block_count: 5,
+            cp_pack_start_sum: 1,
+            sit_ver_bitmap_bytesize: 64,
+            nat_ver_bitmap_bytesize: 64,
+            checksum_offset: 4092,
+            ..Checkpoint::default()
+        }
+    }
+
+    fn sit_entry(valid: &[u64]) -> SitEntry
+    {
+        let mut entry = SitEntry { vblocks: valid.len() as u16, ..SitEntry::default() };
+        for blk in valid {
+            entry.valid_map.0[*blk as usize / 8] |= 0x80 >> (blk % 8);
+        }
+
+        entry
+    }
+
+    struct TestDrive(Vec<u8>);
+
+    impl TestDrive {
+        fn new() -> Self
+        {
+            let bincode_opt = DefaultOptions::new()
+                .with_fixint_encoding()
+                .allow_trailing_bytes();
+
+            let mut drive = vec![0u8; (VOLUME_BLOCKS as usize + 1) * BLOCK_SIZE as usize];
+            let raw = bincode_opt.serialize(&test_sb()).unwrap();
+            drive[1024..1024 + raw.len()].copy_from_slice(&raw);
+
+            Self(drive)
+        }
+
+        fn write(&mut self, offset: u64, data: &[u8])
+        {
+            self.0[offset as usize..offset as usize + data.len()].copy_from_slice(data);
+        }
+
+        /// Writes a checkpoint pack, with the SIT version bitmap selecting the second copy for the
+        /// first SIT block if `second_sit` is set.
+        fn write_cp(&mut self, pack: u64, cp: &Checkpoint, second_sit: bool, sit_journal: &[(u32, SitEntry)])
+        {
+            let bincode_opt = DefaultOptions::new()
+                .with_fixint_encoding()
+                .allow_trailing_bytes();
+
+            let mut block = vec![0u8; BLOCK_SIZE as usize];
+            let raw = bincode_opt.serialize(cp).unwrap();
+            block[..raw.len()].copy_from_slice(&raw);
+            if second_sit {
+                block[CP_BITMAP_OFFSET] = 0x80;
+            }
+            let csum = f2fs_crc32(&block[..4092]);
+            block[4092..].copy_from_slice(&csum.to_le_bytes());
+
+            let pack_addr = CP_ADDR + pack * 512;
+            self.write(pack_addr * BLOCK_SIZE, &block);
+            self.write((pack_addr + 4) * BLOCK_SIZE, &block);
+
+            // The cold data summary, in the third summary block.
+            let mut journal = vec![0u8; SUM_JOURNAL_SIZE];
+            journal[..2].copy_from_slice(&(sit_journal.len() as u16).to_le_bytes());
+            for (i, (segno, entry)) in sit_journal.iter().enumerate() {
+                let offset = 2 + i * SIT_JOURNAL_ENTRY_SIZE;
+                journal[offset..offset + 4].copy_from_slice(&segno.to_le_bytes());
+                journal[offset + 4..offset + SIT_JOURNAL_ENTRY_SIZE]
+                    .copy_from_slice(&bincode_opt.serialize(entry).unwrap());
+            }
+            self.write((pack_addr + 3) * BLOCK_SIZE + SUM_ENTRIES_SIZE as u64, &journal);
+        }
+
+        /// Writes the NAT journal of a checkpoint pack, into the hot data summary.
+        fn write_nat_journal(&mut self, pack: u64, entries: &[(u32, u32)])
+        {
+            let mut journal = vec![0u8; SUM_JOURNAL_SIZE];
+            journal[..2].copy_from_slice(&(entries.len() as u16).to_le_bytes());
+            for (i, (nid, block_addr)) in entries.iter().enumerate() {
+                let offset = 2 + i * NAT_JOURNAL_ENTRY_SIZE;
+                journal[offset..offset + 4].copy_from_slice(&nid.to_le_bytes());
+                journal[offset + 9..offset + 13].copy_from_slice(&block_addr.to_le_bytes());
+            }
+            self.write((CP_ADDR + pack * 512 + 1) * BLOCK_SIZE + SUM_ENTRIES_SIZE as u64, &journal);
+        }
+
+        /// Writes the block addresses of nodes into the first copy of the NAT.
+        fn write_nat(&mut self, entries: &[(u32, u32)])
+        {
+            for (nid, block_addr) in entries {
+                let offset = NAT_ADDR * BLOCK_SIZE + (*nid as usize * NAT_ENTRY_SIZE) as u64;
+                self.write(offset + 5, &block_addr.to_le_bytes());
+            }
+        }
+
+        fn write_sit(&mut self, copy: u64, entries: &[SitEntry])
+        {
+            let bincode_opt = DefaultOptions::new()
+                .with_fixint_encoding()
+                .allow_trailing_bytes();
+
+            for (i, entry) in entries.iter().enumerate() {
+                let offset = (SIT_ADDR + copy * 512) * BLOCK_SIZE + (i * SIT_ENTRY_SIZE) as u64;
+                self.write(offset, &bincode_opt.serialize(entry).unwrap());
+            }
+        }
+    }
+
+    fn blocks(start: u64, end: u64, status: AllocStatus) -> Segment
+    {
+        Segment { start: start * BLOCK_SIZE, end: end * BLOCK_SIZE, status }
+    }
+
+    #[test]
+    fn scan()
+    {
+        let mut drive = TestDrive::new();
+        drive.write_cp(0, &test_cp(3, CP_UMOUNT_FLAG), false, &[]);
+        drive.write_sit(0, &[sit_entry(&[0, 1, 2, 100]), sit_entry(&[])]);
+        let mut ctx = test_context(&drive.0);
+
+        let map = scan_drive(&mut ctx, &Config::default()).unwrap();
+
+        assert_eq!(map.segments(), vec![
+            blocks(0, MAIN_ADDR + 3, AllocStatus::Used),
+            blocks(MAIN_ADDR + 3, MAIN_ADDR + 100, AllocStatus::Free),
+            blocks(MAIN_ADDR + 100, MAIN_ADDR + 101, AllocStatus::Used),
+            // The rest of the drive is left free.
+            blocks(MAIN_ADDR + 101, VOLUME_BLOCKS + 1, AllocStatus::Free),
+        ]);
+    }
+
+    #[test]
+    fn newest_checkpoint_and_journal()
+    {
+        let mut drive = TestDrive::new();
+        drive.write_sit(0, &[sit_entry(&[]), sit_entry(&[])]);
+        drive.write_sit(1, &[sit_entry(&[5]), sit_entry(&[])]);
+        // The older checkpoint uses the first SIT copy, the newer one the second copy and the
+        // journal.
+        drive.write_cp(0, &test_cp(3, CP_UMOUNT_FLAG), false, &[]);
+        drive.write_cp(1, &test_cp(4, CP_UMOUNT_FLAG), true, &[(1, sit_entry(&[511]))]);
+        let mut ctx = test_context(&drive.0);
+
+        let map = scan_drive(&mut ctx, &Config::default()).unwrap();
+        let used: Vec<Segment> = map.iter_used().copied().collect();
+
+        assert_eq!(used, vec![
+            blocks(0, MAIN_ADDR, AllocStatus::Used),
+            blocks(MAIN_ADDR + 5, MAIN_ADDR + 6, AllocStatus::Used),
+            blocks(MAIN_ADDR + 1023, MAIN_ADDR + 1024, AllocStatus::Used),
+        ]);
+
+        // With a corrupted newer checkpoint, the older one is used.
+        drive.0[((CP_ADDR + 512) * BLOCK_SIZE) as usize + 100] ^= 1;
+        let mut ctx = test_context(&drive.0);
+        let map = scan_drive(&mut ctx, &Config::default()).unwrap();
+
+        assert_eq!(map.segments(), vec![
+            blocks(0, MAIN_ADDR, AllocStatus::Used),
+            blocks(MAIN_ADDR, VOLUME_BLOCKS + 1, AllocStatus::Free),
+        ]);
+    }
+
+    #[test]
+    fn unclean_unmount()
+    {
+        let mut drive = TestDrive::new();
+        drive.write_cp(0, &test_cp(3, 0), false, &[]);
+        drive.write_sit(0, &[sit_entry(&[]), sit_entry(&[])]);
+        let mut ctx = test_context(&drive.0);
+
+        assert!(scan_drive(&mut ctx, &Config::default()).is_err());
+
+        let cfg = Config { ignore_recovery: true, ..Config::default() };
+        assert!(scan_drive(&mut ctx, &cfg).is_ok());
+    }
+
+    #[test]
+    fn nodes()
+    {
+        let mut drive = TestDrive::new();
+        drive.write_cp(0, &test_cp(3, CP_UMOUNT_FLAG), false, &[]);
+        drive.write_sit(0, &[sit_entry(&[0, 1]), sit_entry(&[])]);
+        // The node inode holds a placeholder, node 4 is free in the SIT and node 5 is deleted in
+        // the journal.
+        drive.write_nat(&[(1, 1), (3, MAIN_ADDR as u32), (4, MAIN_ADDR as u32 + 7)]);
+        drive.write_nat(&[(5, MAIN_ADDR as u32 + 9)]);
+        drive.write_nat_journal(0, &[(5, 0)]);
+        let mut ctx = test_context(&drive.0);
+
+        let map = scan_drive(&mut ctx, &Config::default()).unwrap();
+        let used: Vec<Segment> = map.iter_used().copied().collect();
+
+        assert_eq!(used, vec![
+            blocks(0, MAIN_ADDR + 2, AllocStatus::Used),
+            blocks(MAIN_ADDR + 7, MAIN_ADDR + 8, AllocStatus::Used),
+        ]);
+        assert_eq!(ctx.anomalies.count(AnomalyKind::FreeNodeBlock), 1);
+
+        // A node outside of the main area.
+        drive.write_nat_journal(0, &[(5, 100)]);
+        let mut ctx = test_context(&drive.0);
+        assert!(scan_drive(&mut ctx, &Config::default()).is_err());
+
+        // A journal entry outside of the NAT.
+        drive.write_nat_journal(0, &[(1 << 30, MAIN_ADDR as u32)]);
+        let mut ctx = test_context(&drive.0);
+        assert!(scan_drive(&mut ctx, &Config::default()).is_err());
+    }
+
+    #[test]
+    fn unsupported_sizes()
+    {
+        let bincode_opt = DefaultOptions::new()
+            .with_fixint_encoding()
+            .allow_trailing_bytes();
+
+        for sb in [
+            SuperBlock { log_blocksize: 64, ..test_sb() },
+            SuperBlock { log_blocksize: 10, ..test_sb() },
+            SuperBlock { log_blocks_per_seg: 70, ..test_sb() },
+        ] {
+            let mut drive = TestDrive::new();
+            drive.write_cp(0, &test_cp(3, CP_UMOUNT_FLAG), false, &[]);
+            drive.write(1024, &bincode_opt.serialize(&sb).unwrap());
+            let mut ctx = test_context(&drive.0);
+
+            assert!(scan_drive(&mut ctx, &Config::default()).is_err());
+        }
+    }
+
+    #[test]
+    fn inconsistent_sit_entry()
+    {
+        let mut drive = TestDrive::new();
+        drive.write_cp(0, &test_cp(3, CP_UMOUNT_FLAG), false, &[]);
+        drive.write_sit(0, &[SitEntry { vblocks: 2, ..sit_entry(&[7]) }]);
+        let mut ctx = test_context(&drive.0);
+
+        assert!(scan_drive(&mut ctx, &Config::default()).is_err());
+    }
+}
@@ -0,0 +1,345 @@
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+use bincode::{DefaultOptions, Options};
+
+use crate::map_format::FsIdentity;
+use crate::usage_map::{AllocStatus, UsageMap};
+use crate::{Config, Context};
+
+// Reference: Microsoft Extensible Firmware Initiative FAT32 File System Specification, v1.03.
+
+/// The BIOS parameter block of a FAT32 volume, at the start of the boot sector.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Bpb {
+    pub bs_jmp_boot: [u8; 3],
+    pub bs_oem_name: [u8; 8],
+    pub bpb_byts_per_sec: u16,
+    pub bpb_sec_per_clus: u8,
+    pub bpb_rsvd_sec_cnt: u16,
+    pub bpb_num_fats: u8,
+    pub bpb_root_ent_cnt: u16,
+    pub bpb_tot_sec16: u16,
+    pub bpb_media: u8,
+    pub bpb_fat_sz16: u16,
+    pub bpb_sec_per_trk: u16,
+    pub bpb_num_heads: u16,
+    pub bpb_hidd_sec: u32,
+    pub bpb_tot_sec32: u32,
+    // -- FAT32 only --
+    pub bpb_fat_sz32: u32,
+    pub bpb_ext_flags: u16,
+    pub bpb_fs_ver: u16,
+    pub bpb_root_clus: u32,
+    pub bpb_fs_info: u16,
+    pub bpb_bk_boot_sec: u16,
+    pub bpb_reserved: [u8; 12],
+    pub bs_drv_num: u8,
+    pub bs_reserved1: u8,
+    pub bs_boot_sig: u8,
+    pub bs_vol_id: u32,
+    pub bs_vol_lab: [u8; 11],
+    pub bs_fil_sys_type: [u8; 8],
+}
+
+/// The offset of the boot sector signature.
+const SIGNATURE_OFFSET: u64 = 510;
+const SIGNATURE: [u8; 2] = [0x55, 0xaa];
+
+/// The bits of a FAT32 entry that hold the cluster number; the upper 4 bits are reserved.
+const ENTRY_MASK: u32 = 0x0fff_ffff;
+
+/// FAT32 volume parameters, computed from the BPB.
+#[derive(Clone, Debug)]
+pub struct Fs {
+    pub cluster_size: u64,
+    /// The size of the volume, in bytes.
+    pub size: u64,
+    /// The offset of the FAT that is in use.
+    pub fat_start: u64,
+    /// The offset of the data region (cluster 2).
+    pub data_start: u64,
+    /// The number of data clusters.
+    pub cluster_count: u64,
+}
+
+impl Fs {
+    /// Computes the volume parameters from the BPB and checks them.
+    pub fn new(bpb: &Bpb) -> anyhow::Result<Self>
+    {
+        if !is_fat32_bpb(bpb) {
+            bail!("not a FAT32 volume");
+        }
+
+        let sector_size = bpb.bpb_byts_per_sec as u64;
+        let cluster_size = sector_size * bpb.bpb_sec_per_clus as u64;
+        let total_sectors = if bpb.bpb_tot_sec16 != 0 {
+            bpb.bpb_tot_sec16 as u64
+        } else {
+            bpb.bpb_tot_sec32 as u64
+        };
+        let fat_sectors = bpb.bpb_fat_sz32 as u64;
+
+        // With mirroring disabled, only the FAT selected by the low 4 bits of the extended flags
+        // is in use.
+        let active_fat = if bpb.bpb_ext_flags & 0x80 != 0 {
+            (bpb.bpb_ext_flags & 0xf) as u64
+        } else {
+            0
+        };
+        if active_fat >= bpb.bpb_num_fats as u64 {
+            bail!("invalid active FAT: {}", active_fat);
+        }
+
+        let data_sector = bpb.bpb_rsvd_sec_cnt as u64 + bpb.bpb_num_fats as u64 * fat_sectors;
+        if data_sector >= total_sectors {
+            bail!("the FAT32 volume has no data region");
+        }
+
+        // The FAT can be larger than needed, but not smaller.
+        let cluster_count = std::cmp::min(
+            (total_sectors - data_sector) / bpb.bpb_sec_per_clus as u64,
+            fat_sectors * sector_size / 4 - 2,
+        );
+
+        Ok(Self {
+            cluster_size,
+            size: total_sectors * sector_size,
+            fat_start: (bpb.bpb_rsvd_sec_cnt as u64 + active_fat * fat_sectors) * sector_size,
+            data_start: data_sector * sector_size,
+            cluster_count,
+        })
+    }
+}
+
+/// Checks whether a BPB describes a FAT32 volume. FAT32 is told apart from FAT12/16 by the
+/// missing root directory entries and the 16-bit FAT size, as Linux does.
+pub fn is_fat32_bpb(bpb: &Bpb) -> bool
+{
+    matches!(bpb.bpb_byts_per_sec, 512 | 1024 | 2048 | 4096)
+        && bpb.bpb_sec_per_clus.is_power_of_two()
+        && bpb.bpb_rsvd_sec_cnt != 0
+        && bpb.bpb_num_fats != 0
+        && bpb.bpb_root_ent_cnt == 0
+        && bpb.bpb_fat_sz16 == 0
+        && bpb.bpb_fat_sz32 != 0
+}
+
+/// Reads the BPB, if the drive has a boot sector signature.
+pub fn read_bpb(ctx: &mut Context) -> anyhow::Result<Option<Bpb>>
+{
+    let bincode_opt = DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes();
+
+    let mut signature = [0u8; 2];
+    ctx.drive.seek(SeekFrom::Start(SIGNATURE_OFFSET))?;
+    ctx.drive.read_exact(&mut signature)?;
+    if signature != SIGNATURE {
+        return Ok(None);
+    }
+
+    ctx.drive.seek(SeekFrom::Start(0))?;
+    let bpb: Bpb = bincode_opt.deserialize_from(&ctx.drive)?;
+
+    Ok(Some(bpb))
+}
+
+/// Scans a FAT32 volume and returns a map of its usage.
+/// The whole volume is marked as used, except for the clusters that are free in the FAT in use.
+pub fn scan_drive(ctx: &mut Context, _cfg: &Config) -> anyhow::Result<UsageMap>
+{
+    let bpb = match read_bpb(ctx)? {
+        Some(bpb) => bpb,
+        None => bail!("missing boot sector signature"),
+    };
+    let fs = Fs::new(&bpb)?;
+
+    // The volume serial number, in the form of blkid. FAT records no write time.
+    ctx.fs_identity = Some(FsIdentity {
+        uuid: format!("{:04X}-{:04X}", bpb.bs_vol_id >> 16, bpb.bs_vol_id & 0xffff),
+        write_time: 0,
+    });
+
+    ctx.logger.logln(1, &format!(
+        "FAT32 volume: {} clusters of {} bytes",
+        fs.cluster_count,
+        fs.cluster_size,
+    ));
+
+    let drive_size = ctx.drive.seek(SeekFrom::End(0))?;
+    if fs.size > drive_size {
+        bail!("the FAT32 volume ({} bytes) is larger than the drive ({} bytes)", fs.size, drive_size);
+    }
+
+    let mut map = UsageMap::new(drive_size);
+    map.update(0, fs.size, AllocStatus::Used);
+
+    ctx.drive.seek(SeekFrom::Start(fs.fat_start + 2 * 4))?;
+    let mut fat = BufReader::new(&mut ctx.drive);
+
+    scan_fat(&mut map, &mut fat, &fs)?;
+
+    Ok(map)
+}
+
+/// Reads the FAT entries of the data clusters and marks the runs of free clusters.
+fn scan_fat<R: Read>(map: &mut UsageMap, fat: &mut R, fs: &Fs) -> anyhow::Result<()>
+{
+    let mut run_start = None;
+    let mut entry = [0u8; 4];
+
+    for i in 0..=fs.cluster_count {
+        let free = if i < fs.cluster_count {
+            fat.read_exact(&mut entry)?;
+            u32::from_le_bytes(entry) & ENTRY_MASK == 0
+        } else {
+            false
+        };
+
+        match (free, run_start) {
+            (true, None) => run_start = Some(i),
+            (false, Some(first)) => {
+                map.update(
+                    fs.data_start + first * fs.cluster_size,
+                    (i - first) * fs.cluster_size,
+                    AllocStatus::Free,
+                );
+                run_start = None;
+            },
+            _ => {},
+        }
+    }
+
+    Ok(())
+}
+
+// Tests.
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::usage_map::Segment;
+    use crate::util::test_context;
+
+    /// Creates a BPB of a volume with 512-byte sectors, 1KiB clusters, 4 reserved sectors and two
+    /// FATs of 1 sector (126 clusters).
+    pub fn test_bpb(total_sectors: u32) -> Bpb
+    {
+        Bpb {
+            bs_jmp_boot: [0xeb, 0x58, 0x90],
+            bs_oem_name: *b"mkfs.fat",
+            bpb_byts_per_sec: 512,
+            bpb_sec_per_clus: 2,
+            bpb_rsvd_sec_cnt: 4,
+            bpb_num_fats: 2,
+            bpb_root_ent_cnt: 0,
+            bpb_tot_sec16: 0,
+            bpb_media: 0xf8,
+            bpb_fat_sz16: 0,
+            bpb_sec_per_trk: 32,
+            bpb_num_heads: 64,
+            bpb_hidd_sec: 0,
+            bpb_tot_sec32: total_sectors,
+            bpb_fat_sz32: 1,
+            bpb_ext_flags: 0,
+            bpb_fs_ver: 0,
+            bpb_root_clus: 2,
+            bpb_fs_info: 1,
+            bpb_bk_boot_sec: 0,
+            bpb_reserved: [0; 12],
+            bs_drv_num: 0x80,
+            bs_reserved1: 0,
+            bs_boot_sig: 0x29,
+            bs_vol_id: 0x1234_5678,
+            bs_vol_lab: *b"NO NAME    ",
+            bs_fil_sys_type: *b"FAT32   ",
+        }
+    }
+
+    /// Creates a drive with the supplied BPB and FAT entries (starting with cluster 2) in every
+    /// FAT.
+    pub fn test_drive(bpb: &Bpb, size: usize, entries: &[u32]) -> Vec<u8>
+    {
+        let bincode_opt = DefaultOptions::new()
+            .with_fixint_encoding()
+            .allow_trailing_bytes();
+
+        let mut drive = vec![0; size];
+        let raw = bincode_opt.serialize(bpb).unwrap();
+        drive[..raw.len()].copy_from_slice(&raw);
+        drive[510..512].copy_from_slice(&SIGNATURE);
+
+        let sector_size = bpb.bpb_byts_per_sec as usize;
+        for n in 0..bpb.bpb_num_fats as usize {
+            let fat = (bpb.bpb_rsvd_sec_cnt as usize + n * bpb.bpb_fat_sz32 as usize) * sector_size;
+            drive[fat..fat + 8].copy_from_slice(&[0xf8, 0xff, 0xff, 0x0f, 0xff, 0xff, 0xff, 0x0f]);
+
+            for (i, e) in entries.iter().enumerate() {
+                let offset = fat + (i + 2) * 4;
+                drive[offset..offset + 4].copy_from_slice(&e.to_le_bytes());
+            }
+        }
+
+        drive
+    }
+
+    #[test]
+    fn fs_new_computed_values()
+    {
+        // 6 sectors of metadata and 10 clusters.
+        let fs = Fs::new(&test_bpb(26)).unwrap();
+
+        assert_eq!(fs.cluster_size, 1024);
+        assert_eq!(fs.size, 26 * 512);
+        assert_eq!(fs.fat_start, 4 * 512);
+        assert_eq!(fs.data_start, 6 * 512);
+        assert_eq!(fs.cluster_count, 10);
+
+        // Not FAT32.
+        assert!(Fs::new(&Bpb { bpb_fat_sz16: 1, ..test_bpb(26) }).is_err());
+    }
+
+    #[test]
+    fn scan()
+    {
+        let bpb = test_bpb(26);
+        // The root directory, a two-cluster file with a bad cluster between, free clusters and
+        // the last cluster free with reserved bits set.
+        let entries = [0x0fff_ffff, 4, 0, 6, 0x0fff_fff7, 0x0fff_ffff, 0, 0, 0, 0xf000_0000];
+        let drive = test_drive(&bpb, 32 * 512, &entries);
+        let mut ctx = test_context(&drive);
+
+        let map = scan_drive(&mut ctx, &Config::default()).unwrap();
+
+        let data = 6 * 512;
+        assert_eq!(map.segments(), vec![
+            Segment { start: 0, end: data + 2 * 1024, status: AllocStatus::Used },
+            Segment { start: data + 2 * 1024, end: data + 3 * 1024, status: AllocStatus::Free },
+            Segment { start: data + 3 * 1024, end: data + 6 * 1024, status: AllocStatus::Used },
+            // The volume ends at 26 sectors; the rest of the drive is left free.
+            Segment { start: data + 6 * 1024, end: 32 * 512, status: AllocStatus::Free },
+        ]);
+        assert_eq!(ctx.fs_identity.unwrap().uuid, "1234-5678");
+    }
+
+    #[test]
+    fn scan_active_fat()
+    {
+        // Mirroring disabled, the second FAT is in use.
+        let bpb = Bpb { bpb_ext_flags: 0x81, ..test_bpb(26) };
+        let mut drive = test_drive(&bpb, 26 * 512, &[0x0fff_ffff; 10]);
+        // Free the 3rd cluster in the second FAT only.
+        drive[5 * 512 + 4 * 4..5 * 512 + 5 * 4].copy_from_slice(&[0; 4]);
+        let mut ctx = test_context(&drive);
+
+        let map = scan_drive(&mut ctx, &Config::default()).unwrap();
+        let free: Vec<&Segment> = map.iter().filter(|s| s.status == AllocStatus::Free).collect();
+
+        assert_eq!(free, vec![&Segment {
+            start: 6 * 512 + 2 * 1024,
+            end: 6 * 512 + 3 * 1024,
+            status: AllocStatus::Free,
+        }]);
+    }
+}
@@ -0,0 +1,346 @@
+use std::io::{Read, Seek, SeekFrom};
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+use bincode::{DefaultOptions, Options};
+
+use crate::map_format::FsIdentity;
+use crate::usage_map::{AllocStatus, UsageMap};
+use crate::{Config, Context};
+
+// Reference: Apple Technical Note TN1150, HFS Plus Volume Format.
+
+/// The offset of the volume header.
+const VOLUME_HEADER_OFFSET: u64 = 1024;
+
+const HFSPLUS_SIGNATURE: u16 = 0x482b; // "H+"
+const HFSX_SIGNATURE: u16 = 0x4858; // "HX"
+const HFS_SIGNATURE: u16 = 0x4244; // "BD"
+
+/// The volume was unmounted cleanly.
+const VOLUME_UNMOUNTED: u32 = 1 << 8;
+/// The volume was found inconsistent by the implementation that mounted it.
+const VOLUME_INCONSISTENT: u32 = 1 << 11;
+/// The volume is journaled.
+const VOLUME_JOURNALED: u32 = 1 << 13;
+
+/// An extent of a fork, in allocation blocks.
+#[derive(Copy, Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ExtentDescriptor {
+    pub start_block: u32,
+    pub block_count: u32,
+}
+
+/// The location and size of a special file.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ForkData {
+    pub logical_size: u64,
+    pub clump_size: u32,
+    pub total_blocks: u32,
+    pub extents: [ExtentDescriptor; 8],
+}
+
+/// The volume header. All the fields are big-endian.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct VolumeHeader {
+    pub signature: u16,
+    pub version: u16,
+    pub attributes: u32,
+    pub last_mounted_version: u32,
+    pub journal_info_block: u32,
+    pub create_date: u32,
+    pub modify_date: u32,
+    pub backup_date: u32,
+    pub checked_date: u32,
+    pub file_count: u32,
+    pub folder_count: u32,
+    pub block_size: u32,
+    pub total_blocks: u32,
+    pub free_blocks: u32,
+    pub next_allocation: u32,
+    pub rsrc_clump_size: u32,
+    pub data_clump_size: u32,
+    pub next_catalog_id: u32,
+    pub write_count: u32,
+    pub encodings_bitmap: u64,
+    pub finder_info: [u32; 8],
+    pub allocation_file: ForkData,
+    pub extents_file: ForkData,
+    pub catalog_file: ForkData,
+    pub attributes_file: ForkData,
+    pub startup_file: ForkData,
+}
+
+/// Reads the volume header, if the drive has an HFS+ or HFSX signature.
+pub fn read_volume_header(ctx: &mut Context) -> anyhow::Result<Option<VolumeHeader>>
+{
+    let bincode_opt = DefaultOptions::new()
+        .with_fixint_encoding()
+        .with_big_endian()
+        .allow_trailing_bytes();
+
+    ctx.drive.seek(SeekFrom::Start(VOLUME_HEADER_OFFSET))?;
+    let vh: VolumeHeader = bincode_opt.deserialize_from(&ctx.drive)?;
+
+    if vh.signature != HFSPLUS_SIGNATURE && vh.signature != HFSX_SIGNATURE {
+        return Ok(None);
+    }
+
+    Ok(Some(vh))
+}
+
+/// Checks whether the drive has an HFS signature. Wrapped HFS+ volumes are not supported.
+pub fn is_hfs_wrapper(ctx: &mut Context) -> anyhow::Result<bool>
+{
+    let mut signature = [0u8; 2];
+    ctx.drive.seek(SeekFrom::Start(VOLUME_HEADER_OFFSET))?;
+    ctx.drive.read_exact(&mut signature)?;
+
+    Ok(u16::from_be_bytes(signature) == HFS_SIGNATURE)
+}
+
+/// Scans an HFS+ volume and returns a map of its usage.
+/// The whole volume is marked as used, except for the allocation blocks that are clear in the
+/// allocation file. The allocation file must be described by the extents in the volume header.
+pub fn scan_drive(ctx: &mut Context, cfg: &Config) -> anyhow::Result<UsageMap>
+{
+    let vh = match read_volume_header(ctx)? {
+        Some(vh) => vh,
+        None if is_hfs_wrapper(ctx)? => {
+            bail!("HFS volumes (and HFS+ volumes wrapped in them) are not supported");
+        },
+        None => bail!("bad HFS+ signature"),
+    };
+
+    if !vh.block_size.is_power_of_two() || vh.block_size < 512 {
+        bail!("invalid allocation block size: {}", vh.block_size);
+    }
+    let block_size = vh.block_size as u64;
+    let total_blocks = vh.total_blocks as u64;
+    let size = total_blocks * block_size;
+
+    // The 64-bit volume identifier, which macOS keeps in the last two words of the Finder info.
+    ctx.fs_identity = Some(FsIdentity {
+        uuid: format!("{:08X}{:08X}", vh.finder_info[6], vh.finder_info[7]),
+        write_time: vh.modify_date as u64,
+    });
+
+    ctx.logger.logln(1, &format!(
+        "HFS+ volume: {} blocks of {} bytes, {} free",
+        total_blocks,
+        block_size,
+        vh.free_blocks,
+    ));
+
+    if vh.attributes & VOLUME_INCONSISTENT != 0 {
+        bail!("the volume is marked inconsistent: run fsck");
+    }
+    // The journal of a volume that was not unmounted cleanly has to be replayed, and the
+    // allocation file does not account for the transactions in it.
+    if vh.attributes & VOLUME_UNMOUNTED == 0 && !cfg.ignore_recovery {
+        if vh.attributes & VOLUME_JOURNALED != 0 {
+            bail!("the journal needs recovery: try to mount and unmount the volume, or run fsck");
+        }
+        bail!("the volume was not unmounted cleanly: try to mount and unmount it, or run fsck");
+    }
+
+    let drive_size = ctx.drive.seek(SeekFrom::End(0))?;
+    if size > drive_size {
+        bail!("the HFS+ volume ({} bytes) is larger than the drive ({} bytes)", size, drive_size);
+    }
+
+    let bitmap = read_allocation_file(&vh, ctx)?;
+
+    let mut map = UsageMap::new(drive_size);
+    map.update(0, size, AllocStatus::Used);
+
+    mark_free_blocks(&mut map, &bitmap, total_blocks, block_size);
+
+    Ok(map)
+}
+
+/// Reads the part of the allocation file that covers the volume.
+fn read_allocation_file(vh: &VolumeHeader, ctx: &mut Context) -> anyhow::Result<Vec<u8>>
+{
+    let block_size = vh.block_size as u64;
+    let needed = (vh.total_blocks as u64).div_ceil(8);
+
+    let fork = &vh.allocation_file;
+    if fork.logical_size < needed {
+        bail!("the allocation file is too small for {} blocks", vh.total_blocks);
+    }
+
+    let mut bitmap = Vec::with_capacity(needed as usize);
+
+    for extent in &fork.extents {
+        let remaining = needed - bitmap.len() as u64;
+        if remaining == 0 {
+            break;
+        }
+        if extent.block_count == 0 {
+            break;
+        }
+        if extent.start_block as u64 + extent.block_count as u64 > vh.total_blocks as u64 {
+            bail!("an extent of the allocation file is out of the volume");
+        }
+
+        let len = std::cmp::min(extent.block_count as u64 * block_size, remaining);
+        let start = bitmap.len();
+        bitmap.resize(start + len as usize, 0);
+
+        ctx.drive.seek(SeekFrom::Start(extent.start_block as u64 * block_size))?;
+        ctx.drive.read_exact(&mut bitmap[start..])?;
+    }
+
+    if (bitmap.len() as u64) < needed {
+        bail!("unsupported allocation file: it continues in the extents overflow file");
+    }
+
+    Ok(bitmap)
+}
+
+/// Marks the runs of allocation blocks that are clear in the allocation file.
+fn mark_free_blocks(map: &mut UsageMap, bitmap: &[u8], total_blocks: u64, block_size: u64)
+{
+    let mut run_start = None;
+
+    for i in 0..=total_blocks {
+        let free = i < total_blocks && !test_bit(bitmap, i as usize);
+
+        match (free, run_start) {
+            (true, None) => run_start = Some(i),
+            (false, Some(first)) => {
+                map.update(first * block_size, (i - first) * block_size, AllocStatus::Free);
+                run_start = None;
+            },
+            _ => {},
+        }
+    }
+}
+
+/// Tests a bit of the allocation file, most significant bit first.
+fn test_bit(bitmap: &[u8], idx: usize) -> bool
+{
+    match bitmap.get(idx / 8) {
+        Some(byte) => byte & (0x80 >> (idx % 8)) != 0,
+        None => false,
+    }
+}
+
+// Tests.
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::usage_map::Segment;
+    use crate::util::test_context;
+
+    /// Creates a volume header of a volume with 64 blocks of 512 bytes and the allocation file in
+    /// block 3.
+    pub fn test_vh() -> VolumeHeader
+    {
+        let mut allocation_file = ForkData {
+            logical_size: 512,
+            clump_size: 512,
+            total_blocks: 1,
+            ..ForkData::default()
+        };
+        allocation_file.extents[0] = ExtentDescriptor { start_block: 3, block_count: 1 };
+
+        VolumeHeader {
+            signature: HFSPLUS_SIGNATURE,
+            version: 4,
+            attributes: VOLUME_UNMOUNTED | VOLUME_JOURNALED,
+            last_mounted_version: 0x3130_2e30,
+            journal_info_block: 0,
+            create_date: 0,
+            modify_date: 0,
+            backup_date: 0,
+            checked_date: 0,
+            file_count: 0,
+            folder_count: 0,
+            block_size: 512,
+            total_blocks: 64,
+            free_blocks: 0,
+            next_allocation: 0,
+            rsrc_clump_size: 0,
+            data_clump_size: 0,
+            next_catalog_id: 16,
+            write_count: 0,
+            encodings_bitmap: 0,
+            finder_info: [0; 8],
+            allocation_file,
+            extents_file: ForkData::default(),
+            catalog_file: ForkData::default(),
+            attributes_file: ForkData::default(),
+            startup_file: ForkData::default(),
+        }
+    }
+
+    /// Creates a drive with the volume header and the supplied allocation file bitmap in block 3.
+    pub fn test_drive(vh: &VolumeHeader, bitmap: &[u8], size: usize) -> Vec<u8>
+    {
+        let bincode_opt = DefaultOptions::new()
+            .with_fixint_encoding()
+            .with_big_endian()
+            .allow_trailing_bytes();
+
+        let mut drive = vec![0; size];
+        let raw = bincode_opt.serialize(vh).unwrap();
+        drive[1024..1024 + raw.len()].copy_from_slice(&raw);
+
+        drive[3 * 512..3 * 512 + bitmap.len()].copy_from_slice(bitmap);
+
+        drive
+    }
+
+    #[test]
+    fn scan()
+    {
+        // Blocks 0-5 and 8, 32-35 and 63.
+        let bitmap = [0xfc, 0x80, 0, 0, 0xf0, 0, 0, 0x01];
+        let drive = test_drive(&test_vh(), &bitmap, 80 * 512);
+        let mut ctx = test_context(&drive);
+
+        let map = scan_drive(&mut ctx, &Config::default()).unwrap();
+
+        assert_eq!(map.segments(), vec![
+            Segment { start: 0, end: 6 * 512, status: AllocStatus::Used },
+            Segment { start: 6 * 512, end: 8 * 512, status: AllocStatus::Free },
+            Segment { start: 8 * 512, end: 9 * 512, status: AllocStatus::Used },
+            Segment { start: 9 * 512, end: 32 * 512, status: AllocStatus::Free },
+            Segment { start: 32 * 512, end: 36 * 512, status: AllocStatus::Used },
+            Segment { start: 36 * 512, end: 63 * 512, status: AllocStatus::Free },
+            Segment { start: 63 * 512, end: 64 * 512, status: AllocStatus::Used },
+            // The rest of the drive is left free.
+            Segment { start: 64 * 512, end: 80 * 512, status: AllocStatus::Free },
+        ]);
+    }
+
+    #[test]
+    fn journal_needs_recovery()
+    {
+        let vh = VolumeHeader { attributes: VOLUME_JOURNALED, ..test_vh() };
+        let drive = test_drive(&vh, &[0xff; 8], 64 * 512);
+
+        let mut ctx = test_context(&drive);
+        assert!(scan_drive(&mut ctx, &Config::default()).is_err());
+
+        let mut ctx = test_context(&drive);
+        let cfg = Config { ignore_recovery: true, ..Config::default() };
+        assert!(scan_drive(&mut ctx, &cfg).is_ok());
+    }
+
+    #[test]
+    fn allocation_file_overflow()
+    {
+        // The allocation file needs 2 blocks, but it continues in the extents overflow file after
+        // the first one.
+        let mut vh = VolumeHeader { total_blocks: 4160, ..test_vh() };
+        vh.allocation_file.logical_size = 1024;
+        vh.allocation_file.total_blocks = 2;
+        let drive = test_drive(&vh, &[0xff; 512], 4160 * 512);
+        let mut ctx = test_context(&drive);
+
+        assert!(scan_drive(&mut ctx, &Config::default()).is_err());
+    }
+}
@@ -0,0 +1,438 @@
+use std::collections::HashSet;
+use std::io::{Read, Seek, SeekFrom};
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+use bincode::{DefaultOptions, Options};
+
+use crate::array::Array;
+use crate::usage_map::{AllocStatus, UsageMap};
+use crate::{Config, Context};
+
+// Reference: ECMA-119, 4th edition.
+
+/// The size of the sectors of the volume descriptor area.
+const SECTOR_SIZE: u64 = 2048;
+/// The first sector of the volume descriptor set; the system area precedes it.
+const FIRST_VD_SECTOR: u64 = 16;
+/// The limit of the number of volume descriptors, against a missing terminator.
+const MAX_VDS: u64 = 64;
+
+const VD_PRIMARY: u8 = 1;
+const VD_SUPPLEMENTARY: u8 = 2;
+const VD_TERMINATOR: u8 = 255;
+
+const STANDARD_ID: &[u8; 5] = b"CD001";
+
+const DIR_RECORD_SIZE: usize = 33;
+/// The directory flag of a directory record.
+const FLAG_DIRECTORY: u8 = 0x2;
+
+/// A volume descriptor, up to the root directory record. The primary and the supplementary
+/// volume descriptors share the layout. Both-endian fields are decoded from their little-endian
+/// half.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct VolumeDescriptor {
+    pub vd_type: u8,
+    pub standard_id: [u8; 5],
+    pub version: u8,
+    pub flags: u8,
+    pub system_id: Array<u8, 32>,
+    pub volume_id: Array<u8, 32>,
+    pub unused1: [u8; 8],
+    pub volume_space_size: u32,
+    pub volume_space_size_be: [u8; 4],
+    pub escape_sequences: Array<u8, 32>,
+    pub volume_set_size: u32,
+    pub volume_sequence_number: u32,
+    pub logical_block_size: u16,
+    pub logical_block_size_be: [u8; 2],
+    pub path_table_size: u32,
+    pub path_table_size_be: [u8; 4],
+    pub l_path_table: u32,
+    pub opt_l_path_table: u32,
+    pub m_path_table: [u8; 4],
+    pub opt_m_path_table: [u8; 4],
+    pub root: DirRecord,
+}
+
+/// The fixed part of a directory record; the name follows.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct DirRecord {
+    pub length: u8,
+    pub ext_attr_length: u8,
+    pub extent: u32,
+    pub extent_be: [u8; 4],
+    pub data_length: u32,
+    pub data_length_be: [u8; 4],
+    pub recording_date: [u8; 7],
+    pub flags: u8,
+    pub file_unit_size: u8,
+    pub interleave_gap: u8,
+    pub volume_sequence_number: u32,
+    pub name_length: u8,
+}
+
+/// Reads the first volume descriptor, if the drive contains ISO9660.
+pub fn read_first_vd(ctx: &mut Context) -> anyhow::Result<Option<VolumeDescriptor>>
+{
+    let bincode_opt = DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes();
+
+    ctx.drive.seek(SeekFrom::Start(FIRST_VD_SECTOR * SECTOR_SIZE))?;
+    let vd: VolumeDescriptor = match bincode_opt.deserialize_from(&ctx.drive) {
+        Ok(vd) => vd,
+        // Too small to hold the descriptor.
+        Err(_) => return Ok(None),
+    };
+
+    if &vd.standard_id != STANDARD_ID {
+        return Ok(None);
+    }
+
+    Ok(Some(vd))
+}
+
+/// Scans an ISO9660 volume and returns a map of its usage: the system area and the volume
+/// descriptors, the path tables, the directories and the file extents are used; everything else
+/// is free. The volume can only be scanned for a report.
+pub fn scan_drive(ctx: &mut Context, cfg: &Config) -> anyhow::Result<UsageMap>
+{
+    if !cfg.report_only {
+        bail!("ISO9660 is only supported in the report-only mode");
+    }
+
+    let bincode_opt = DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes();
+
+    // Read the volume descriptor set.
+
+    let mut vds = Vec::new();
+    let mut vd_sector = FIRST_VD_SECTOR;
+
+    loop {
+        if vd_sector >= FIRST_VD_SECTOR + MAX_VDS {
+            bail!("missing volume descriptor set terminator");
+        }
+
+        ctx.drive.seek(SeekFrom::Start(vd_sector * SECTOR_SIZE))?;
+        let vd: VolumeDescriptor = bincode_opt.deserialize_from(&ctx.drive)?;
+        vd_sector += 1;
+
+        if &vd.standard_id != STANDARD_ID {
+            bail!("bad volume descriptor identifier in sector {}", vd_sector - 1);
+        }
+
+        match vd.vd_type {
+            VD_TERMINATOR => break,
+            VD_PRIMARY | VD_SUPPLEMENTARY => vds.push(vd),
+            _ => {},
+        }
+    }
+
+    let pvd = match vds.iter().find(|vd| vd.vd_type == VD_PRIMARY) {
+        Some(pvd) => pvd.clone(),
+        None => bail!("missing primary volume descriptor"),
+    };
+
+    let block_size = pvd.logical_block_size as u64;
+    if !matches!(block_size, 512 | 1024 | 2048) {
+        bail!("invalid logical block size: {}", block_size);
+    }
+
+    let drive_size = ctx.drive.seek(SeekFrom::End(0))?;
+    let volume_size = pvd.volume_space_size as u64 * block_size;
+    if volume_size > drive_size {
+        bail!("the ISO9660 volume ({} bytes) is larger than the drive ({} bytes)", volume_size, drive_size);
+    }
+
+    ctx.logger.logln(1, &format!(
+        "ISO9660 volume: {} blocks of {} bytes, {} volume descriptors",
+        pvd.volume_space_size,
+        block_size,
+        vds.len(),
+    ));
+
+    let mut map = UsageMap::new(drive_size);
+    map.update(0, vd_sector * SECTOR_SIZE, AllocStatus::Used);
+
+    for vd in &vds {
+        if vd.logical_block_size as u64 != block_size {
+            bail!("volume descriptors with different block sizes are not supported");
+        }
+
+        // The path tables.
+        let tables = [
+            vd.l_path_table,
+            vd.opt_l_path_table,
+            u32::from_be_bytes(vd.m_path_table),
+            u32::from_be_bytes(vd.opt_m_path_table),
+        ];
+        let table_len = (vd.path_table_size as u64).div_ceil(block_size) * block_size;
+        for table in tables.iter().filter(|t| **t != 0) {
+            let start = *table as u64 * block_size;
+            if start > map.size() {
+                bail!("path table at block {} is outside of the drive", table);
+            }
+            map.update(start, table_len, AllocStatus::Used);
+        }
+
+        // The directory hierarchy. The directories of the supplementary descriptors are separate,
+        // but they share the file extents.
+        let mut visited = HashSet::new();
+        scan_dir(&mut map, &vd.root, block_size, &mut visited, ctx)?;
+    }
+
+    Ok(map)
+}
+
+/// Marks a directory, its files and its subdirectories as used.
+fn scan_dir(
+    map: &mut UsageMap,
+    dir: &DirRecord,
+    block_size: u64,
+    visited: &mut HashSet<u32>,
+    ctx: &mut Context,
+) -> anyhow::Result<()>
+{
+    if !visited.insert(dir.extent) {
+        return Ok(());
+    }
+
+    let bincode_opt = DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes();
+
+    mark_extent(map, dir, block_size)?;
+
+    let start = (dir.extent as u64 + dir.ext_attr_length as u64) * block_size;
+    if start + dir.data_length as u64 > map.size() {
+        bail!("the directory at block {} reaches beyond the end of the drive", dir.extent);
+    }
+
+    let mut data = vec![0u8; dir.data_length as usize];
+    ctx.drive.seek(SeekFrom::Start(start))?;
+    ctx.drive.read_exact(&mut data)?;
+
+    let mut offset = 0;
+    while offset < data.len() {
+        let length = data[offset] as usize;
+
+        // Records do not cross sector boundaries; the rest of the sector is padded with zeros.
+        if length == 0 {
+            offset = (offset / SECTOR_SIZE as usize + 1) * SECTOR_SIZE as usize;
+            continue;
+        }
+        if length < DIR_RECORD_SIZE || offset + length > data.len() {
+            bail!("invalid directory record in the directory at block {}", dir.extent);
+        }
+
+        let record: DirRecord = bincode_opt.deserialize(&data[offset..])?;
+        let name = &data[offset + DIR_RECORD_SIZE..offset + length];
+        offset += length;
+
+        // The `.` and `..` entries.
+        if record.name_length == 1 && matches!(name[0], 0 | 1) {
+            continue;
+        }
+
+        if record.flags & FLAG_DIRECTORY != 0 {
+            scan_dir(map, &record, block_size, visited, ctx)?;
+        } else {
+            mark_extent(map, &record, block_size)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Marks the extent of a directory record, with its extended attribute record, as used.
+fn mark_extent(map: &mut UsageMap, record: &DirRecord, block_size: u64) -> anyhow::Result<()>
+{
+    let start = record.extent as u64 * block_size;
+    let data_blocks = (record.data_length as u64).div_ceil(block_size);
+
+    // Interleaved files are recorded in units separated by gaps.
+    let blocks = if record.file_unit_size != 0 {
+        let unit = record.file_unit_size as u64;
+        data_blocks.div_ceil(unit) * (unit + record.interleave_gap as u64)
+    } else {
+        data_blocks
+    };
+    let len = (record.ext_attr_length as u64 + blocks) * block_size;
+
+    if start > map.size() {
+        bail!("extent at block {} is outside of the drive", record.extent);
+    }
+    map.update(start, len, AllocStatus::Used);
+
+    Ok(())
+}
+
+// Tests.
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::usage_map::Segment;
+    use crate::util::test_context;
+
+    fn record(extent: u32, data_length: u32, flags: u8, name: &[u8]) -> Vec<u8>
+    {
+        let bincode_opt = DefaultOptions::new()
+            .with_fixint_encoding()
+            .allow_trailing_bytes();
+
+        let length = (DIR_RECORD_SIZE + name.len()).div_ceil(2) * 2;
+        let header = DirRecord {
+            length: length as u8,
+            ext_attr_length: 0,
+            extent,
+            extent_be: extent.to_be_bytes(),
+            data_length,
+            data_length_be: data_length.to_be_bytes(),
+            recording_date: [0; 7],
+            flags,
+            file_unit_size: 0,
+            interleave_gap: 0,
+            volume_sequence_number: 0x0100_0001,
+            name_length: name.len() as u8,
+        };
+
+        let mut raw = bincode_opt.serialize(&header).unwrap();
+        raw.extend_from_slice(name);
+        raw.resize(length, 0);
+
+        raw
+    }
+
+    fn vd(vd_type: u8, root: u32) -> VolumeDescriptor
+    {
+        let bincode_opt = DefaultOptions::new()
+            .with_fixint_encoding()
+            .allow_trailing_bytes();
+
+        VolumeDescriptor {
+            vd_type,
+            standard_id: *STANDARD_ID,
+            version: 1,
+            flags: 0,
+            system_id: Array::default(),
+            volume_id: Array::default(),
+            unused1: [0; 8],
+            volume_space_size: 40,
+            volume_space_size_be: 40u32.to_be_bytes(),
+            escape_sequences: Array::default(),
+            volume_set_size: 0x0100_0001,
+            volume_sequence_number: 0x0100_0001,
+            logical_block_size: 2048,
+            logical_block_size_be: 2048u16.to_be_bytes(),
+            path_table_size: 10,
+            path_table_size_be: 10u32.to_be_bytes(),
+            l_path_table: 18,
+            opt_l_path_table: 0,
+            m_path_table: 19u32.to_be_bytes(),
+            opt_m_path_table: [0; 4],
+            root: bincode_opt.deserialize(&record(root, 2048, FLAG_DIRECTORY, &[0])).unwrap(),
+        }
+    }
+
+    /// Creates a 40-block volume with the root directory at block 20, a file at 22 (2 blocks),
+    /// a directory at 25 and its file at 30. The drive has 44 blocks.
+    pub fn test_drive() -> Vec<u8>
+    {
+        let bincode_opt = DefaultOptions::new()
+            .with_fixint_encoding()
+            .allow_trailing_bytes();
+
+        let mut drive = vec![0u8; 44 * 2048];
+        let mut write = |block: usize, data: &[u8]| {
+            drive[block * 2048..block * 2048 + data.len()].copy_from_slice(data);
+        };
+
+        write(16, &bincode_opt.serialize(&vd(VD_PRIMARY, 20)).unwrap());
+        let mut terminator = vec![VD_TERMINATOR];
+        terminator.extend_from_slice(STANDARD_ID);
+        terminator.push(1);
+        write(17, &terminator);
+
+        let root: Vec<u8> = [
+            record(20, 2048, FLAG_DIRECTORY, &[0]),
+            record(20, 2048, FLAG_DIRECTORY, &[1]),
+            record(22, 3000, 0, b"A.TXT;1"),
+            record(25, 2048, FLAG_DIRECTORY, b"DIR"),
+        ].concat();
+        write(20, &root);
+
+        let dir: Vec<u8> = [
+            record(25, 2048, FLAG_DIRECTORY, &[0]),
+            record(20, 2048, FLAG_DIRECTORY, &[1]),
+            // A loop back to the root.
+            record(20, 2048, FLAG_DIRECTORY, b"LOOP"),
+            record(30, 100, 0, b"B.TXT;1"),
+        ].concat();
+        write(25, &dir);
+
+        drive
+    }
+
+    fn blocks(start: u64, end: u64, status: AllocStatus) -> Segment
+    {
+        Segment { start: start * 2048, end: end * 2048, status }
+    }
+
+    #[test]
+    fn scan()
+    {
+        let mut ctx = test_context(&test_drive());
+
+        let map = scan_drive(&mut ctx, &Config::default()).unwrap();
+
+        assert_eq!(map.segments(), vec![
+            // The system area, the descriptors and the path tables.
+            blocks(0, 21, AllocStatus::Used),
+            blocks(21, 22, AllocStatus::Free),
+            blocks(22, 24, AllocStatus::Used),
+            blocks(24, 25, AllocStatus::Free),
+            blocks(25, 26, AllocStatus::Used),
+            blocks(26, 30, AllocStatus::Free),
+            blocks(30, 31, AllocStatus::Used),
+            blocks(31, 44, AllocStatus::Free),
+        ]);
+    }
+
+    #[test]
+    fn out_of_bounds()
+    {
+        let bincode_opt = DefaultOptions::new()
+            .with_fixint_encoding()
+            .allow_trailing_bytes();
+
+        // A path table past the end of the drive.
+        let mut drive = test_drive();
+        let pvd = VolumeDescriptor { l_path_table: 1000, ..vd(VD_PRIMARY, 20) };
+        let raw = bincode_opt.serialize(&pvd).unwrap();
+        drive[16 * 2048..16 * 2048 + raw.len()].copy_from_slice(&raw);
+        let mut ctx = test_context(&drive);
+        assert!(scan_drive(&mut ctx, &Config::default()).is_err());
+
+        // A directory longer than the drive.
+        let mut drive = test_drive();
+        let dir = record(25, u32::MAX, FLAG_DIRECTORY, b"DIR");
+        let offset = 20 * 2048 + record(20, 2048, FLAG_DIRECTORY, &[0]).len() * 2
+            + record(22, 3000, 0, b"A.TXT;1").len();
+        drive[offset..offset + dir.len()].copy_from_slice(&dir);
+        let mut ctx = test_context(&drive);
+        assert!(scan_drive(&mut ctx, &Config::default()).is_err());
+    }
+
+    #[test]
+    fn fill_refused()
+    {
+        let mut ctx = test_context(&test_drive());
+        let cfg = Config { report_only: false, ..Config::default() };
+
+        assert!(scan_drive(&mut ctx, &cfg).is_err());
+    }
+}
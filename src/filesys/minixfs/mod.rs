@@ -0,0 +1,387 @@
+use std::io::{Read, Seek, SeekFrom};
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+use bincode::{DefaultOptions, Options};
+
+use crate::bitmap::Bitmap;
+use crate::usage_map::{AllocStatus, UsageMap};
+use crate::{Config, Context};
+
+// Reference: linux/fs/minix.
+
+/// The offset of the superblock.
+const SUPERBLOCK_OFFSET: u64 = 1024;
+/// The offset of the magic value of a V3 superblock, relative to the superblock.
+const V3_MAGIC_OFFSET: u64 = 24;
+
+const MINIX_MAGIC: u16 = 0x137f;
+const MINIX_MAGIC2: u16 = 0x138f;
+const MINIX2_MAGIC: u16 = 0x2468;
+const MINIX2_MAGIC2: u16 = 0x2478;
+const MINIX3_MAGIC: u16 = 0x4d5a;
+
+/// The file system was unmounted cleanly.
+const MINIX_VALID_FS: u16 = 0x0001;
+/// The file system has errors.
+const MINIX_ERROR_FS: u16 = 0x0002;
+
+/// The superblock of a V1 or V2 file system.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SuperBlock {
+    pub s_ninodes: u16,
+    /// The number of zones (V1 only).
+    pub s_nzones: u16,
+    pub s_imap_blocks: u16,
+    pub s_zmap_blocks: u16,
+    pub s_firstdatazone: u16,
+    pub s_log_zone_size: u16,
+    pub s_max_size: u32,
+    pub s_magic: u16,
+    pub s_state: u16,
+    /// The number of zones (V2 only).
+    pub s_zones: u32,
+}
+
+/// The superblock of a V3 file system.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SuperBlock3 {
+    pub s_ninodes: u32,
+    pub s_pad0: u16,
+    pub s_imap_blocks: u16,
+    pub s_zmap_blocks: u16,
+    pub s_firstdatazone: u16,
+    pub s_log_zone_size: u16,
+    pub s_pad1: u16,
+    pub s_max_size: u32,
+    pub s_zones: u32,
+    pub s_magic: u16,
+    pub s_pad2: u16,
+    pub s_blocksize: u16,
+    pub s_disk_version: u8,
+}
+
+/// The superblock of any version.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AnySuperBlock {
+    V1V2(SuperBlock),
+    V3(SuperBlock3),
+}
+
+/// Minix file system parameters, computed from the superblock.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Fs {
+    pub version: u8,
+    pub block_size: u64,
+    pub zone_size: u64,
+    /// The number of zones, including the metadata zones.
+    pub zones: u64,
+    pub first_data_zone: u64,
+    /// The offset of the zone bitmap.
+    pub zmap_start: u64,
+    pub zmap_size: u64,
+    /// The state of the file system; always valid for V3.
+    pub state: u16,
+}
+
+impl Fs {
+    /// Computes the file system parameters from a superblock of any version and checks them.
+    pub fn from_any(sb: &AnySuperBlock) -> anyhow::Result<Self>
+    {
+        match sb {
+            AnySuperBlock::V1V2(sb) => Self::new(sb),
+            AnySuperBlock::V3(sb) => Self::new_v3(sb),
+        }
+    }
+
+    /// Computes the file system parameters from a V1 or V2 superblock and checks them.
+    pub fn new(sb: &SuperBlock) -> anyhow::Result<Self>
+    {
+        let (version, zones, inode_size) = match sb.s_magic {
+            MINIX_MAGIC | MINIX_MAGIC2 => (1, sb.s_nzones as u64, 32),
+            MINIX2_MAGIC | MINIX2_MAGIC2 => (2, sb.s_zones as u64, 64),
+            _ => bail!("bad minix magic value"),
+        };
+
+        Self::with_layout(
+            version,
+            1024,
+            inode_size,
+            sb.s_ninodes as u64,
+            sb.s_imap_blocks as u64,
+            sb.s_zmap_blocks as u64,
+            sb.s_firstdatazone as u64,
+            sb.s_log_zone_size as u64,
+            zones,
+            sb.s_state,
+        )
+    }
+
+    /// Computes the file system parameters from a V3 superblock and checks them.
+    pub fn new_v3(sb: &SuperBlock3) -> anyhow::Result<Self>
+    {
+        if sb.s_magic != MINIX3_MAGIC {
+            bail!("bad minix magic value");
+        }
+        if !matches!(sb.s_blocksize, 1024 | 2048 | 4096 | 8192 | 16384 | 32768) {
+            bail!("invalid block size: {}", sb.s_blocksize);
+        }
+
+        Self::with_layout(
+            3,
+            sb.s_blocksize as u64,
+            64,
+            sb.s_ninodes as u64,
+            sb.s_imap_blocks as u64,
+            sb.s_zmap_blocks as u64,
+            sb.s_firstdatazone as u64,
+            sb.s_log_zone_size as u64,
+            sb.s_zones as u64,
+            MINIX_VALID_FS,
+        )
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn with_layout(
+        version: u8,
+        block_size: u64,
+        inode_size: u64,
+        inodes: u64,
+        imap_blocks: u64,
+        zmap_blocks: u64,
+        first_data_zone: u64,
+        log_zone_size: u64,
+        zones: u64,
+        state: u16,
+    ) -> anyhow::Result<Self>
+    {
+        if imap_blocks == 0 || zmap_blocks == 0 {
+            bail!("missing inode or zone bitmap");
+        }
+        if log_zone_size > 8 {
+            bail!("invalid zone size: 2^{} blocks", log_zone_size);
+        }
+        let zone_size = block_size << log_zone_size;
+
+        // The boot block and the superblock, the bitmaps and the inode table precede the data
+        // zones.
+        let inode_table_blocks = inodes.div_ceil(block_size / inode_size);
+        let metadata_blocks = 2 + imap_blocks + zmap_blocks + inode_table_blocks;
+        if first_data_zone * zone_size < metadata_blocks * block_size {
+            bail!("the first data zone ({}) overlaps the metadata", first_data_zone);
+        }
+        if first_data_zone >= zones {
+            bail!("the file system has no data zones");
+        }
+
+        // Bit 0 is reserved, bit 1 is the first data zone.
+        let zmap_bits = zones - first_data_zone + 1;
+        if zmap_blocks * block_size * 8 < zmap_bits {
+            bail!("the zone bitmap is too small for {} zones", zones);
+        }
+
+        Ok(Self {
+            version,
+            block_size,
+            zone_size,
+            zones,
+            first_data_zone,
+            zmap_start: (2 + imap_blocks) * block_size,
+            zmap_size: zmap_bits.div_ceil(8),
+            state,
+        })
+    }
+
+    /// The size of the file system, in bytes.
+    pub fn size(&self) -> u64
+    {
+        self.zones * self.zone_size
+    }
+}
+
+/// Reads the superblock, if the drive has a minix magic value.
+pub fn read_superblock(ctx: &mut Context) -> anyhow::Result<Option<AnySuperBlock>>
+{
+    let bincode_opt = DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes();
+
+    ctx.drive.seek(SeekFrom::Start(SUPERBLOCK_OFFSET))?;
+    let sb: SuperBlock = bincode_opt.deserialize_from(&ctx.drive)?;
+    if matches!(sb.s_magic, MINIX_MAGIC | MINIX_MAGIC2 | MINIX2_MAGIC | MINIX2_MAGIC2) {
+        return Ok(Some(AnySuperBlock::V1V2(sb)));
+    }
+
+    let mut magic = [0u8; 2];
+    ctx.drive.seek(SeekFrom::Start(SUPERBLOCK_OFFSET + V3_MAGIC_OFFSET))?;
+    ctx.drive.read_exact(&mut magic)?;
+    if u16::from_le_bytes(magic) == MINIX3_MAGIC {
+        ctx.drive.seek(SeekFrom::Start(SUPERBLOCK_OFFSET))?;
+        let sb: SuperBlock3 = bincode_opt.deserialize_from(&ctx.drive)?;
+        return Ok(Some(AnySuperBlock::V3(sb)));
+    }
+
+    Ok(None)
+}
+
+/// Scans a minix file system and returns a map of its usage.
+/// The whole file system is marked as used, except for the data zones that are clear in the zone
+/// bitmap.
+pub fn scan_drive(ctx: &mut Context, cfg: &Config) -> anyhow::Result<UsageMap>
+{
+    let fs = match read_superblock(ctx)? {
+        Some(sb) => Fs::from_any(&sb)?,
+        None => bail!("bad minix magic value"),
+    };
+
+    ctx.logger.logln(1, &format!(
+        "minix V{} file system: {} zones of {} bytes",
+        fs.version,
+        fs.zones,
+        fs.zone_size,
+    ));
+
+    if fs.state & MINIX_ERROR_FS != 0 {
+        bail!("errors present in the filesystem");
+    }
+    if fs.state & MINIX_VALID_FS == 0 && !cfg.ignore_recovery {
+        bail!("filesystem was not unmounted cleanly: try to mount and unmount it, or run fsck");
+    }
+
+    let drive_size = ctx.drive.seek(SeekFrom::End(0))?;
+    if fs.size() > drive_size {
+        bail!("the file system ({} bytes) is larger than the drive ({} bytes)", fs.size(), drive_size);
+    }
+
+    ctx.drive.seek(SeekFrom::Start(fs.zmap_start))?;
+    let zmap = Bitmap::from_reader(&mut ctx.drive, fs.zmap_size as usize)?;
+    if !zmap.check_bit(0) {
+        bail!("the reserved bit of the zone bitmap is clear");
+    }
+
+    let mut map = UsageMap::new(drive_size);
+    map.update(0, fs.size(), AllocStatus::Used);
+
+    mark_free_zones(&mut map, &zmap, &fs);
+
+    Ok(map)
+}
+
+/// Marks the runs of data zones that are clear in the zone bitmap.
+fn mark_free_zones(map: &mut UsageMap, zmap: &Bitmap, fs: &Fs)
+{
+    let mut run_start = None;
+
+    for zone in fs.first_data_zone..=fs.zones {
+        let free = zone < fs.zones && !zmap.check_bit((zone - fs.first_data_zone + 1) as usize);
+
+        match (free, run_start) {
+            (true, None) => run_start = Some(zone),
+            (false, Some(first)) => {
+                map.update(first * fs.zone_size, (zone - first) * fs.zone_size, AllocStatus::Free);
+                run_start = None;
+            },
+            _ => {},
+        }
+    }
+}
+
+// Tests.
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::usage_map::Segment;
+    use crate::util::test_context;
+
+    /// Creates a V2 superblock of a file system with 64 zones: 32 inodes in 1 block, 1 inode and
+    /// 1 zone bitmap block and the first data zone at 8.
+    pub fn test_sb() -> SuperBlock
+    {
+        SuperBlock {
+            s_ninodes: 32,
+            s_nzones: 0,
+            s_imap_blocks: 1,
+            s_zmap_blocks: 1,
+            s_firstdatazone: 8,
+            s_log_zone_size: 0,
+            s_max_size: 0x7fff_ffff,
+            s_magic: MINIX2_MAGIC2,
+            s_state: MINIX_VALID_FS,
+            s_zones: 64,
+        }
+    }
+
+    /// Creates a drive with the superblock and the zone bitmap of `test_sb()`, with the supplied
+    /// data zones in use.
+    pub fn test_drive(sb: &SuperBlock, used: &[u64], size: usize) -> Vec<u8>
+    {
+        let bincode_opt = DefaultOptions::new()
+            .with_fixint_encoding()
+            .allow_trailing_bytes();
+
+        let mut drive = vec![0; size];
+        let raw = bincode_opt.serialize(sb).unwrap();
+        drive[1024..1024 + raw.len()].copy_from_slice(&raw);
+
+        // The reserved bit and the bits past the last zone are set, as by mkfs.
+        let zmap = 3 * 1024;
+        let mut bits = vec![0];
+        bits.extend(used.iter().map(|zone| zone - 8 + 1));
+        bits.extend(57..1024 * 8);
+        for bit in bits {
+            drive[zmap + bit as usize / 8] |= 1 << (bit % 8);
+        }
+
+        drive
+    }
+
+    #[test]
+    fn fs_new_computed_values()
+    {
+        let fs = Fs::new(&test_sb()).unwrap();
+
+        assert_eq!(fs.version, 2);
+        assert_eq!(fs.zone_size, 1024);
+        assert_eq!(fs.size(), 64 * 1024);
+        assert_eq!(fs.zmap_start, 3 * 1024);
+        assert_eq!(fs.zmap_size, 8);
+
+        // The inode table reaches into the data zones.
+        assert!(Fs::new(&SuperBlock { s_ninodes: 96, ..test_sb() }).is_err());
+        // The zone bitmap cannot cover the zones.
+        assert!(Fs::new(&SuperBlock { s_zones: 9000, ..test_sb() }).is_err());
+    }
+
+    #[test]
+    fn scan()
+    {
+        let drive = test_drive(&test_sb(), &[8, 9, 12, 63], 80 * 1024);
+        let mut ctx = test_context(&drive);
+
+        let map = scan_drive(&mut ctx, &Config::default()).unwrap();
+
+        assert_eq!(map.segments(), vec![
+            Segment { start: 0, end: 10 * 1024, status: AllocStatus::Used },
+            Segment { start: 10 * 1024, end: 12 * 1024, status: AllocStatus::Free },
+            Segment { start: 12 * 1024, end: 13 * 1024, status: AllocStatus::Used },
+            Segment { start: 13 * 1024, end: 63 * 1024, status: AllocStatus::Free },
+            Segment { start: 63 * 1024, end: 64 * 1024, status: AllocStatus::Used },
+            // The rest of the drive is left free.
+            Segment { start: 64 * 1024, end: 80 * 1024, status: AllocStatus::Free },
+        ]);
+    }
+
+    #[test]
+    fn unclean()
+    {
+        let sb = SuperBlock { s_state: 0, ..test_sb() };
+        let drive = test_drive(&sb, &[8], 64 * 1024);
+
+        let mut ctx = test_context(&drive);
+        assert!(scan_drive(&mut ctx, &Config::default()).is_err());
+
+        let mut ctx = test_context(&drive);
+        let cfg = Config { ignore_recovery: true, ..Config::default() };
+        assert!(scan_drive(&mut ctx, &cfg).is_ok());
+    }
+}
@@ -1,14 +1,15 @@
-use clap::ArgEnum;
-
 mod detect;
 
 pub mod e2fs;
+pub mod f2fs;
+pub mod fat;
+pub mod hfsplus;
+pub mod iso9660;
+pub mod minixfs;
+pub mod ntfs;
+pub mod raw;
+pub mod reiserfs;
+pub mod scanner;
+pub mod udf;
 pub use detect::detect_fs;
-
-/// Supported file system types.
-#[derive(Copy, Clone, Debug, ArgEnum)]
-pub enum FsType {
-    Ext2,
-    Ext3,
-    Ext4,
-}
+pub use scanner::Registry;
@@ -0,0 +1,1109 @@
+use std::io::{Read, Seek, SeekFrom};
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+use bincode::{DefaultOptions, Options};
+
+use crate::bitmap::Bitmap;
+use crate::map_format::FsIdentity;
+use crate::usage_map::{AllocStatus, UsageMap};
+use crate::{Config, Context};
+
+// Reference: https://flatcap.github.io/linux-ntfs/ntfs/
+
+/// The NTFS boot sector, up to the volume serial number.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct BootSector {
+    pub jump: [u8; 3],
+    pub oem_id: [u8; 8],
+    pub bytes_per_sector: u16,
+    pub sectors_per_cluster: u8,
+    pub reserved_sectors: u16,
+    pub unused1: [u8; 5],
+    pub media: u8,
+    pub unused2: [u8; 18],
+    pub total_sectors: u64,
+    pub mft_lcn: u64,
+    pub mft_mirr_lcn: u64,
+    pub clusters_per_mft_record: i8,
+    pub unused3: [u8; 3],
+    pub clusters_per_index_record: i8,
+    pub unused4: [u8; 3],
+    pub serial_number: u64,
+}
+
+pub const OEM_ID: &[u8; 8] = b"NTFS    ";
+
+/// The offset of the boot sector signature.
+const SIGNATURE_OFFSET: u64 = 510;
+const SIGNATURE: [u8; 2] = [0x55, 0xaa];
+
+/// The MFT records of the system files.
+const MFT_RECORD: u64 = 0;
+const LOGFILE_RECORD: u64 = 2;
+const VOLUME_RECORD: u64 = 3;
+const ROOT_RECORD: u64 = 5;
+const BITMAP_RECORD: u64 = 6;
+/// The MFT records before this one are contiguous, so they are read without the runs of $MFT.
+const CONTIGUOUS_RECORDS: u64 = 16;
+
+/// The record is in use.
+const RECORD_IN_USE: u16 = 0x1;
+
+/// The size of the sectors protected by the update sequence, regardless of the sector size of the
+/// volume.
+const USA_SECTOR_SIZE: usize = 512;
+
+const ATTR_VOLUME_INFORMATION: u32 = 0x70;
+const ATTR_DATA: u32 = 0x80;
+const ATTR_INDEX_ROOT: u32 = 0x90;
+const ATTR_INDEX_ALLOCATION: u32 = 0xa0;
+const ATTR_END: u32 = 0xffff_ffff;
+
+/// The volume was not unmounted cleanly (in the flags of $VOLUME_INFORMATION).
+const VOLUME_IS_DIRTY: u16 = 0x1;
+
+/// The restart area has no log client in use, or the volume is marked clean in it.
+const LOGFILE_NO_CLIENT: u16 = 0xffff;
+const RESTART_VOLUME_IS_CLEAN: u16 = 0x2;
+
+/// The name of the directory indexes.
+const INDEX_NAME: &str = "$I30";
+/// The last entry of an index node, which has no key.
+const INDEX_ENTRY_END: u32 = 0x2;
+/// The limit of the size of the root directory index, against corrupted attributes.
+const MAX_INDEX_SIZE: u64 = 16 * 1024 * 1024;
+
+/// The hibernation file, which starts with `hibr` while Windows is hibernated (also by fast
+/// startup).
+const HIBERFILE_NAME: &str = "hiberfil.sys";
+
+/// The header of an MFT record.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RecordHeader {
+    pub magic: [u8; 4],
+    pub usa_offset: u16,
+    pub usa_count: u16,
+    pub lsn: u64,
+    pub sequence_number: u16,
+    pub link_count: u16,
+    pub attrs_offset: u16,
+    pub flags: u16,
+    pub bytes_in_use: u32,
+    pub bytes_allocated: u32,
+}
+
+/// The common header of an attribute.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct AttrHeader {
+    pub attr_type: u32,
+    pub length: u32,
+    pub non_resident: u8,
+    pub name_length: u8,
+    pub name_offset: u16,
+    pub flags: u16,
+    pub instance: u16,
+}
+
+const ATTR_HEADER_SIZE: usize = 16;
+
+/// The rest of a resident attribute's header.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ResidentHeader {
+    pub value_length: u32,
+    pub value_offset: u16,
+}
+
+/// The rest of a non-resident attribute's header.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct NonResidentHeader {
+    pub lowest_vcn: u64,
+    pub highest_vcn: u64,
+    pub runs_offset: u16,
+    pub compression_unit: u16,
+    pub reserved: u32,
+    pub allocated_size: u64,
+    pub data_size: u64,
+    pub initialized_size: u64,
+}
+
+/// A run of clusters of a non-resident attribute.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Run {
+    pub lcn: u64,
+    pub len: u64,
+}
+
+/// NTFS volume parameters, computed from the boot sector.
+#[derive(Clone, Debug)]
+pub struct Fs {
+    pub cluster_size: u64,
+    pub cluster_count: u64,
+    /// The size of the volume, in bytes, including the backup boot sector.
+    pub size: u64,
+    pub mft_start: u64,
+    pub record_size: u64,
+}
+
+impl Fs {
+    /// Computes the volume parameters from the boot sector and checks them.
+    pub fn new(bs: &BootSector) -> anyhow::Result<Self>
+    {
+        if !is_ntfs_boot_sector(bs) {
+            bail!("not an NTFS volume");
+        }
+
+        let sector_size = bs.bytes_per_sector as u64;
+        // Values above 0x80 encode clusters of 2^-n sectors.
+        let sectors_per_cluster = if bs.sectors_per_cluster > 0x80 {
+            1u64.checked_shl(256 - bs.sectors_per_cluster as u32).unwrap_or(0)
+        } else {
+            bs.sectors_per_cluster as u64
+        };
+        if sectors_per_cluster == 0 {
+            bail!("invalid cluster size: {:#0x}", bs.sectors_per_cluster);
+        }
+        let cluster_size = sector_size * sectors_per_cluster;
+
+        // Negative values encode records of 2^-n bytes.
+        let record_size = if bs.clusters_per_mft_record < 0 {
+            1u64.checked_shl(-(bs.clusters_per_mft_record as i32) as u32).unwrap_or(0)
+        } else {
+            bs.clusters_per_mft_record as u64 * cluster_size
+        };
+        if record_size < USA_SECTOR_SIZE as u64 || record_size % USA_SECTOR_SIZE as u64 != 0 {
+            bail!("invalid MFT record size: {}", record_size);
+        }
+
+        let cluster_count = bs.total_sectors / sectors_per_cluster;
+        if bs.mft_lcn >= cluster_count {
+            bail!("the MFT is outside of the volume");
+        }
+        let size = match bs.total_sectors.checked_add(1).and_then(|n| n.checked_mul(sector_size)) {
+            Some(size) => size,
+            None => bail!("invalid volume size: {} sectors", bs.total_sectors),
+        };
+
+        Ok(Self {
+            cluster_size,
+            cluster_count,
+            size,
+            mft_start: bs.mft_lcn * cluster_size,
+            record_size,
+        })
+    }
+}
+
+/// Checks whether a boot sector describes an NTFS volume.
+pub fn is_ntfs_boot_sector(bs: &BootSector) -> bool
+{
+    &bs.oem_id == OEM_ID
+        && matches!(bs.bytes_per_sector, 256 | 512 | 1024 | 2048 | 4096)
+        && bs.sectors_per_cluster != 0
+        && bs.total_sectors != 0
+}
+
+/// Reads the boot sector, if the drive has a boot sector signature.
+pub fn read_boot_sector(ctx: &mut Context) -> anyhow::Result<Option<BootSector>>
+{
+    let bincode_opt = DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes();
+
+    let mut signature = [0u8; 2];
+    ctx.drive.seek(SeekFrom::Start(SIGNATURE_OFFSET))?;
+    ctx.drive.read_exact(&mut signature)?;
+    if signature != SIGNATURE {
+        return Ok(None);
+    }
+
+    ctx.drive.seek(SeekFrom::Start(0))?;
+    let bs: BootSector = bincode_opt.deserialize_from(&ctx.drive)?;
+
+    Ok(Some(bs))
+}
+
+/// Scans an NTFS volume and returns a map of its usage.
+/// The whole volume is marked as used, except for the clusters that are free in $Bitmap. Volumes
+/// that were not unmounted cleanly (the dirty flag of $Volume, or a $LogFile with pending
+/// transactions) are refused unless the recovery error is ignored; hibernated volumes are always
+/// refused.
+pub fn scan_drive(ctx: &mut Context, cfg: &Config) -> anyhow::Result<UsageMap>
+{
+    let bs = match read_boot_sector(ctx)? {
+        Some(bs) => bs,
+        None => bail!("missing boot sector signature"),
+    };
+    let fs = Fs::new(&bs)?;
+
+    // The volume serial number, in the form of blkid. The boot sector records no write time.
+    ctx.fs_identity = Some(FsIdentity {
+        uuid: format!("{:016X}", bs.serial_number),
+        write_time: 0,
+    });
+
+    ctx.logger.logln(1, &format!(
+        "NTFS volume: {} clusters of {} bytes",
+        fs.cluster_count,
+        fs.cluster_size,
+    ));
+
+    let drive_size = ctx.drive.seek(SeekFrom::End(0))?;
+    if fs.size > drive_size {
+        bail!("the NTFS volume ({} bytes) is larger than the drive ({} bytes)", fs.size, drive_size);
+    }
+
+    // The state of the volume.
+
+    if !cfg.ignore_recovery {
+        if volume_is_dirty(&fs, ctx)? {
+            bail!("the NTFS volume is dirty: run chkdsk on it, or mount and unmount it cleanly");
+        }
+        if !logfile_is_clean(&fs, ctx)? {
+            bail!("the NTFS volume needs recovery: $LogFile has pending transactions");
+        }
+    }
+    if is_hibernated(&fs, ctx)? {
+        bail!(
+            "the NTFS volume is hibernated: resume Windows and shut it down fully (with fast \
+            startup disabled)"
+        );
+    }
+
+    // The allocation bitmap.
+
+    let record = read_record(BITMAP_RECORD, None, &fs, ctx)?;
+    let value = match find_attr(&record, ATTR_DATA, "")? {
+        Some(body) => attr_value(body)?,
+        None => bail!("$Bitmap has no unnamed $DATA attribute"),
+    };
+    // Only the bits of the clusters of the volume are read.
+    let bitmap = read_value(&value, fs.cluster_count.div_ceil(8), &fs, ctx)?;
+    if (bitmap.len() as u64) * 8 < fs.cluster_count {
+        bail!("$Bitmap is too small: {} bytes for {} clusters", bitmap.len(), fs.cluster_count);
+    }
+
+    let mut map = UsageMap::new(drive_size);
+    map.update(0, fs.size, AllocStatus::Used);
+    mark_free_clusters(&mut map, &Bitmap::from_bytes(&bitmap), &fs);
+
+    Ok(map)
+}
+
+/// Checks the dirty flag in the $VOLUME_INFORMATION of $Volume.
+fn volume_is_dirty(fs: &Fs, ctx: &mut Context) -> anyhow::Result<bool>
+{
+    let record = read_record(VOLUME_RECORD, None, fs, ctx)?;
+    let info = match find_attr(&record, ATTR_VOLUME_INFORMATION, "")? {
+        Some(body) => read_value(&attr_value(body)?, 12, fs, ctx)?,
+        None => bail!("$Volume has no $VOLUME_INFORMATION attribute"),
+    };
+    if info.len() < 12 {
+        bail!("$VOLUME_INFORMATION is too short: {} bytes", info.len());
+    }
+
+    Ok(u16::from_le_bytes([info[10], info[11]]) & VOLUME_IS_DIRTY != 0)
+}
+
+/// Checks that $LogFile has no pending transactions: its restart area has no client in use or is
+/// marked clean, or the log was emptied (filled with 0xff, as by ntfs-3g).
+fn logfile_is_clean(fs: &Fs, ctx: &mut Context) -> anyhow::Result<bool>
+{
+    let record = read_record(LOGFILE_RECORD, None, fs, ctx)?;
+    let page = match find_attr(&record, ATTR_DATA, "")? {
+        Some(body) => read_value(&attr_value(body)?, USA_SECTOR_SIZE as u64, fs, ctx)?,
+        None => bail!("$LogFile has no unnamed $DATA attribute"),
+    };
+
+    if page.iter().all(|b| *b == 0xff) {
+        return Ok(true);
+    }
+    if page.len() < USA_SECTOR_SIZE || &page[..4] != b"RSTR" {
+        return Ok(false);
+    }
+
+    // The fields read are in the first sector, before its update sequence number.
+    let area = u16::from_le_bytes([page[24], page[25]]) as usize;
+    if area + 16 > USA_SECTOR_SIZE - 2 {
+        bail!("invalid $LogFile restart area offset: {}", area);
+    }
+    let in_use = u16::from_le_bytes([page[area + 12], page[area + 13]]);
+    let flags = u16::from_le_bytes([page[area + 14], page[area + 15]]);
+
+    Ok(in_use == LOGFILE_NO_CLIENT || flags & RESTART_VOLUME_IS_CLEAN != 0)
+}
+
+/// Checks whether Windows is hibernated on the volume: the hibernation file in the root directory
+/// starts with `hibr`.
+fn is_hibernated(fs: &Fs, ctx: &mut Context) -> anyhow::Result<bool>
+{
+    let number = match find_in_root(HIBERFILE_NAME, fs, ctx)? {
+        Some(number) => number,
+        None => return Ok(false),
+    };
+
+    // The record may be anywhere in the MFT.
+    let mft = read_record(MFT_RECORD, None, fs, ctx)?;
+    let mft_runs = match find_attr(&mft, ATTR_DATA, "")? {
+        Some(body) => match attr_value(body)? {
+            AttrValue::NonResident { runs, .. } => runs,
+            AttrValue::Resident(_) => bail!("$MFT has resident data"),
+        },
+        None => bail!("$MFT has no unnamed $DATA attribute"),
+    };
+
+    let record = read_record(number, Some(&mft_runs), fs, ctx)?;
+    if record_flags(&record)? & RECORD_IN_USE == 0 {
+        return Ok(false);
+    }
+    let head = match find_attr(&record, ATTR_DATA, "")? {
+        Some(body) => read_value(&attr_value(body)?, 4, fs, ctx)?,
+        None => bail!("{} has no unnamed $DATA attribute", HIBERFILE_NAME),
+    };
+
+    Ok(head.eq_ignore_ascii_case(b"hibr"))
+}
+
+/// Looks up a file in the root directory index, comparing the names case-insensitively (ASCII
+/// only). Returns the number of its MFT record. All the index entries are read, without walking
+/// the B-tree.
+fn find_in_root(name: &str, fs: &Fs, ctx: &mut Context) -> anyhow::Result<Option<u64>>
+{
+    let record = read_record(ROOT_RECORD, None, fs, ctx)?;
+
+    let root = match find_attr(&record, ATTR_INDEX_ROOT, INDEX_NAME)? {
+        Some(body) => match attr_value(body)? {
+            AttrValue::Resident(value) => value,
+            AttrValue::NonResident { .. } => bail!("the root directory index root is non-resident"),
+        },
+        None => bail!("the root directory has no index root"),
+    };
+    if root.len() < 16 {
+        bail!("the root directory index root is too short");
+    }
+    let block_size = u32::from_le_bytes(root[8..12].try_into().unwrap()) as usize;
+
+    if let Some(number) = find_in_node(&root, 16, name)? {
+        return Ok(Some(number));
+    }
+
+    let alloc = match find_attr(&record, ATTR_INDEX_ALLOCATION, INDEX_NAME)? {
+        Some(body) => attr_value(body)?,
+        None => return Ok(None),
+    };
+    if let AttrValue::NonResident { size, .. } = alloc {
+        if size > MAX_INDEX_SIZE {
+            bail!("the root directory index is too large: {} bytes", size);
+        }
+    }
+    if block_size < USA_SECTOR_SIZE || !block_size.is_multiple_of(USA_SECTOR_SIZE) {
+        bail!("invalid index block size: {}", block_size);
+    }
+
+    let blocks = read_value(&alloc, MAX_INDEX_SIZE, fs, ctx)?;
+
+    for block in blocks.chunks_exact(block_size) {
+        // Blocks that were never used hold no index node.
+        if &block[..4] != b"INDX" {
+            continue;
+        }
+
+        let mut block = block.to_vec();
+        apply_fixups(&mut block, b"INDX")?;
+
+        if let Some(number) = find_in_node(&block, 24, name)? {
+            return Ok(Some(number));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Looks up a file in the entries of an index node, whose header is at `header` in `node`.
+fn find_in_node(node: &[u8], header: usize, name: &str) -> anyhow::Result<Option<u64>>
+{
+    let field = |at: usize| -> anyhow::Result<u32> {
+        match node.get(at..at + 4) {
+            Some(raw) => Ok(u32::from_le_bytes(raw.try_into().unwrap())),
+            None => bail!("index node overruns its buffer"),
+        }
+    };
+
+    let mut offset = header + field(header)? as usize;
+    let end = std::cmp::min(header + field(header + 4)? as usize, node.len());
+    let name: Vec<u16> = name.encode_utf16().collect();
+
+    while offset + 16 <= end {
+        let entry = &node[offset..end];
+        let length = u16::from_le_bytes([entry[8], entry[9]]) as usize;
+        let flags = u32::from_le_bytes(entry[12..16].try_into().unwrap());
+        if flags & INDEX_ENTRY_END != 0 {
+            break;
+        }
+        if length < 16 || length > entry.len() {
+            bail!("index entry has an invalid length: {}", length);
+        }
+
+        // The key is the $FILE_NAME attribute of the file.
+        let key = &entry[16..length];
+        if key.len() >= 66 {
+            let name_len = key[64] as usize;
+            if let Some(raw) = key.get(66..66 + 2 * name_len) {
+                let matches = raw.chunks_exact(2)
+                    .map(|c| u16::from_le_bytes([c[0], c[1]]))
+                    .map(|c| if c < 0x80 { (c as u8).to_ascii_lowercase() as u16 } else { c })
+                    .eq(name.iter().copied());
+                if matches {
+                    let reference = u64::from_le_bytes(entry[..8].try_into().unwrap());
+                    return Ok(Some(reference & 0xffff_ffff_ffff));
+                }
+            }
+        }
+
+        offset += length;
+    }
+
+    Ok(None)
+}
+
+/// Reads an MFT record and applies its update sequence. The records after the first ones are
+/// found through the runs of $MFT.
+fn read_record(number: u64, mft_runs: Option<&[Run]>, fs: &Fs, ctx: &mut Context)
+    -> anyhow::Result<Vec<u8>>
+{
+    let offset = match number.checked_mul(fs.record_size) {
+        Some(offset) => offset,
+        None => bail!("invalid MFT record number: {}", number),
+    };
+
+    let mut record = match mft_runs {
+        Some(runs) if number >= CONTIGUOUS_RECORDS => {
+            read_runs(runs, offset, fs.record_size, fs, ctx)?
+        },
+        _ => {
+            let mut record = vec![0u8; fs.record_size as usize];
+            ctx.drive.seek(SeekFrom::Start(fs.mft_start + offset))?;
+            ctx.drive.read_exact(&mut record)?;
+            record
+        },
+    };
+    apply_fixups(&mut record, b"FILE")?;
+
+    Ok(record)
+}
+
+/// Returns the flags of an MFT record.
+fn record_flags(record: &[u8]) -> anyhow::Result<u16>
+{
+    let bincode_opt = DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes();
+
+    let header: RecordHeader = bincode_opt.deserialize(record)?;
+
+    Ok(header.flags)
+}
+
+/// Checks the update sequence of a multi-sector record and restores the original ends of its
+/// sectors.
+fn apply_fixups(record: &mut [u8], magic: &[u8; 4]) -> anyhow::Result<()>
+{
+    let bincode_opt = DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes();
+
+    let header: RecordHeader = bincode_opt.deserialize(record)?;
+    if &header.magic != magic {
+        bail!("bad {} record magic value", String::from_utf8_lossy(magic));
+    }
+
+    let usa_offset = header.usa_offset as usize;
+    let usa_count = header.usa_count as usize;
+    if usa_count == 0
+        || usa_count - 1 != record.len() / USA_SECTOR_SIZE
+        || usa_offset + usa_count * 2 > record.len()
+    {
+        bail!("bad {} record update sequence", String::from_utf8_lossy(magic));
+    }
+
+    let usn = [record[usa_offset], record[usa_offset + 1]];
+
+    for i in 1..usa_count {
+        let end = i * USA_SECTOR_SIZE;
+        if record[end - 2..end] != usn {
+            bail!("{} record is torn: update sequence number mismatch in sector {}",
+                String::from_utf8_lossy(magic),
+                i - 1,
+            );
+        }
+
+        let fixup = usa_offset + i * 2;
+        record[end - 2] = record[fixup];
+        record[end - 1] = record[fixup + 1];
+    }
+
+    Ok(())
+}
+
+/// The value of an attribute.
+enum AttrValue {
+    Resident(Vec<u8>),
+    /// The runs of clusters and the size of the data.
+    NonResident { runs: Vec<Run>, size: u64 },
+}
+
+/// Finds an attribute of an MFT record by its type and name, and returns its bytes.
+fn find_attr<'a>(record: &'a [u8], attr_type: u32, name: &str) -> anyhow::Result<Option<&'a [u8]>>
+{
+    let bincode_opt = DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes();
+
+    let header: RecordHeader = bincode_opt.deserialize(record)?;
+    let mut offset = header.attrs_offset as usize;
+    let name: Vec<u16> = name.encode_utf16().collect();
+
+    loop {
+        if offset + ATTR_HEADER_SIZE > record.len() {
+            bail!("MFT record attributes overrun the record");
+        }
+
+        let attr: AttrHeader = bincode_opt.deserialize(&record[offset..])?;
+        if attr.attr_type == ATTR_END {
+            return Ok(None);
+        }
+        if (attr.length as usize) < ATTR_HEADER_SIZE || offset + attr.length as usize > record.len() {
+            bail!("MFT record attribute has an invalid length: {}", attr.length);
+        }
+
+        let body = &record[offset..offset + attr.length as usize];
+
+        if attr.attr_type == attr_type {
+            let start = attr.name_offset as usize;
+            let attr_name = match body.get(start..start + 2 * attr.name_length as usize) {
+                Some(raw) => raw,
+                None => bail!("MFT record attribute name overruns the attribute"),
+            };
+            let matches = attr_name.chunks_exact(2)
+                .map(|c| u16::from_le_bytes([c[0], c[1]]))
+                .eq(name.iter().copied());
+            if matches {
+                return Ok(Some(body));
+            }
+        }
+
+        offset += attr.length as usize;
+    }
+}
+
+/// Decodes the value of an attribute: the resident bytes, or the runs of a non-resident value.
+fn attr_value(body: &[u8]) -> anyhow::Result<AttrValue>
+{
+    let bincode_opt = DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes();
+
+    let attr: AttrHeader = bincode_opt.deserialize(body)?;
+
+    if attr.non_resident == 0 {
+        let resident: ResidentHeader = bincode_opt.deserialize(&body[ATTR_HEADER_SIZE..])?;
+        let start = resident.value_offset as usize;
+        let end = start + resident.value_length as usize;
+        if end > body.len() {
+            bail!("resident attribute value overruns its attribute");
+        }
+
+        return Ok(AttrValue::Resident(body[start..end].to_vec()));
+    }
+
+    let non_resident: NonResidentHeader = bincode_opt.deserialize(&body[ATTR_HEADER_SIZE..])?;
+    if non_resident.runs_offset as usize >= body.len() {
+        bail!("the data runs are outside of the attribute");
+    }
+    let runs = decode_runs(&body[non_resident.runs_offset as usize..])?;
+
+    Ok(AttrValue::NonResident { runs, size: non_resident.data_size })
+}
+
+/// Reads at most `limit` bytes of the value of an attribute, from its start.
+fn read_value(value: &AttrValue, limit: u64, fs: &Fs, ctx: &mut Context) -> anyhow::Result<Vec<u8>>
+{
+    match value {
+        AttrValue::Resident(data) => {
+            Ok(data[..std::cmp::min(data.len() as u64, limit) as usize].to_vec())
+        },
+        AttrValue::NonResident { runs, size } => {
+            read_runs(runs, 0, std::cmp::min(*size, limit), fs, ctx)
+        },
+    }
+}
+
+/// Decodes a mapping pairs array into runs of clusters.
+fn decode_runs(mut raw: &[u8]) -> anyhow::Result<Vec<Run>>
+{
+    let mut runs = Vec::new();
+    let mut lcn: i64 = 0;
+
+    while let Some(&header) = raw.first() {
+        if header == 0 {
+            break;
+        }
+
+        let len_size = (header & 0xf) as usize;
+        let offset_size = (header >> 4) as usize;
+        if len_size == 0 || len_size > 8 || offset_size > 8 || raw.len() < 1 + len_size + offset_size {
+            bail!("invalid data run header: {:#04x}", header);
+        }
+        if offset_size == 0 {
+            bail!("sparse data runs are not supported");
+        }
+
+        let len = le_uint(&raw[1..1 + len_size]);
+        let delta = le_int(&raw[1 + len_size..1 + len_size + offset_size]);
+
+        lcn = match lcn.checked_add(delta) {
+            Some(lcn) if lcn >= 0 => lcn,
+            _ => bail!("data run points outside of the volume"),
+        };
+        runs.push(Run { lcn: lcn as u64, len });
+
+        raw = &raw[1 + len_size + offset_size..];
+    }
+
+    Ok(runs)
+}
+
+/// Reads `len` bytes of non-resident data from `offset` on, through its runs.
+fn read_runs(runs: &[Run], offset: u64, len: u64, fs: &Fs, ctx: &mut Context)
+    -> anyhow::Result<Vec<u8>>
+{
+    let end = match offset.checked_add(len) {
+        Some(end) if len <= fs.size => end,
+        _ => bail!("the data is larger than the volume: {} bytes at {}", len, offset),
+    };
+
+    let mut data = Vec::with_capacity(len as usize);
+    let mut pos = offset;
+    // The offset of the run in the data.
+    let mut run_start = 0u64;
+
+    for run in runs {
+        if pos == end {
+            break;
+        }
+        if run.lcn.checked_add(run.len).is_none_or(|end| end > fs.cluster_count) {
+            bail!("data run points outside of the volume");
+        }
+
+        let run_end = run.len.checked_mul(fs.cluster_size).and_then(|n| n.checked_add(run_start));
+        let run_end = match run_end {
+            Some(run_end) => run_end,
+            None => bail!("data run is too long: {} clusters", run.len),
+        };
+
+        if pos < run_end {
+            let n = std::cmp::min(run_end, end) - pos;
+            let start = data.len();
+            data.resize(start + n as usize, 0);
+
+            ctx.drive.seek(SeekFrom::Start(run.lcn * fs.cluster_size + (pos - run_start)))?;
+            ctx.drive.read_exact(&mut data[start..])?;
+            pos += n;
+        }
+
+        run_start = run_end;
+    }
+
+    if pos != end {
+        bail!("the data runs are shorter than the data ({} bytes)", end);
+    }
+
+    Ok(data)
+}
+
+/// Marks the runs of clusters that are free in the bitmap.
+fn mark_free_clusters(map: &mut UsageMap, bitmap: &Bitmap, fs: &Fs)
+{
+    let mut run_start = None;
+
+    for i in 0..=fs.cluster_count {
+        let free = i < fs.cluster_count && !bitmap.check_bit(i as usize);
+
+        match (free, run_start) {
+            (true, None) => run_start = Some(i),
+            (false, Some(first)) => {
+                map.update(first * fs.cluster_size, (i - first) * fs.cluster_size, AllocStatus::Free);
+                run_start = None;
+            },
+            _ => {},
+        }
+    }
+}
+
+/// Decodes a little-endian unsigned integer of up to 8 bytes.
+fn le_uint(bytes: &[u8]) -> u64
+{
+    bytes.iter().rev().fold(0, |acc, b| (acc << 8) | *b as u64)
+}
+
+/// Decodes a little-endian signed integer of 1 to 8 bytes.
+fn le_int(bytes: &[u8]) -> i64
+{
+    let shift = 64 - 8 * bytes.len() as u32;
+
+    ((le_uint(bytes) << shift) as i64) >> shift
+}
+
+// Tests.
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::usage_map::Segment;
+    use crate::util::test_context;
+
+    /// The cluster of the $LogFile data in the test drive.
+    const LOGFILE_LCN: usize = 20;
+
+    /// Creates a boot sector of a volume with 512-byte sectors and clusters, the MFT at cluster 4
+    /// and 1KiB MFT records.
+    pub fn test_boot_sector(total_sectors: u64) -> BootSector
+    {
+        BootSector {
+            jump: [0xeb, 0x52, 0x90],
+            oem_id: *OEM_ID,
+            bytes_per_sector: 512,
+            sectors_per_cluster: 1,
+            reserved_sectors: 0,
+            unused1: [0; 5],
+            media: 0xf8,
+            unused2: [0; 18],
+            total_sectors,
+            mft_lcn: 4,
+            mft_mirr_lcn: 2,
+            clusters_per_mft_record: -10,
+            unused3: [0; 3],
+            clusters_per_index_record: 1,
+            unused4: [0; 3],
+            serial_number: 0x1234,
+        }
+    }
+
+    /// Returns the body of a resident attribute (the part following the common attribute header)
+    /// with the supplied value.
+    fn resident(value: &[u8]) -> Vec<u8>
+    {
+        let mut body = vec![0u8; 8];
+        body[..4].copy_from_slice(&(value.len() as u32).to_le_bytes());
+        body[4..6].copy_from_slice(&24u16.to_le_bytes());
+        body.extend_from_slice(value);
+
+        body
+    }
+
+    /// Returns the body of a non-resident attribute with the supplied mapping pairs and data size.
+    fn non_resident(runs: &[u8], data_size: u64) -> Vec<u8>
+    {
+        let bincode_opt = DefaultOptions::new()
+            .with_fixint_encoding()
+            .allow_trailing_bytes();
+
+        let header = NonResidentHeader {
+            lowest_vcn: 0,
+            highest_vcn: 0,
+            runs_offset: 64,
+            compression_unit: 0,
+            reserved: 0,
+            allocated_size: data_size.div_ceil(512) * 512,
+            data_size,
+            initialized_size: data_size,
+        };
+        let mut body = vec![0u8; 48];
+        body.copy_from_slice(&bincode_opt.serialize(&header).unwrap());
+        body.extend_from_slice(runs);
+        body.push(0);
+
+        body
+    }
+
+    /// Builds a 1KiB MFT record with the supplied attributes (the type, the name, whether it is
+    /// non-resident and the body) and applies the update sequence.
+    fn test_record(attrs: &[(u32, &str, u8, &[u8])]) -> Vec<u8>
+    {
+        let bincode_opt = DefaultOptions::new()
+            .with_fixint_encoding()
+            .allow_trailing_bytes();
+
+        let mut record = vec![0u8; 1024];
+        let header = RecordHeader {
+            magic: *b"FILE",
+            usa_offset: 0x30,
+            usa_count: 3,
+            lsn: 0,
+            sequence_number: 1,
+            link_count: 1,
+            attrs_offset: 0x38,
+            flags: RECORD_IN_USE,
+            bytes_in_use: 0,
+            bytes_allocated: 1024,
+        };
+        let raw = bincode_opt.serialize(&header).unwrap();
+        record[..raw.len()].copy_from_slice(&raw);
+
+        let mut offset = 0x38;
+        for (attr_type, name, non_resident, body) in attrs {
+            let name: Vec<u8> = name.encode_utf16().flat_map(|c| c.to_le_bytes()).collect();
+            let length = (ATTR_HEADER_SIZE + body.len() + name.len()).div_ceil(8) * 8;
+            let attr = AttrHeader {
+                attr_type: *attr_type,
+                length: length as u32,
+                non_resident: *non_resident,
+                name_length: (name.len() / 2) as u8,
+                name_offset: (ATTR_HEADER_SIZE + body.len()) as u16,
+                flags: 0,
+                instance: 0,
+            };
+            let raw = bincode_opt.serialize(&attr).unwrap();
+            let body_start = offset + ATTR_HEADER_SIZE;
+            record[offset..body_start].copy_from_slice(&raw);
+            record[body_start..body_start + body.len()].copy_from_slice(body);
+            let name_start = body_start + body.len();
+            record[name_start..name_start + name.len()].copy_from_slice(&name);
+            offset += length;
+        }
+        record[offset..offset + 4].copy_from_slice(&ATTR_END.to_le_bytes());
+
+        // The update sequence.
+        record[0x30..0x32].copy_from_slice(&[0x07, 0x00]);
+        for i in 1..3 {
+            let end = i * USA_SECTOR_SIZE;
+            let (orig0, orig1) = (record[end - 2], record[end - 1]);
+            record[0x30 + i * 2] = orig0;
+            record[0x30 + i * 2 + 1] = orig1;
+            record[end - 2..end].copy_from_slice(&[0x07, 0x00]);
+        }
+
+        record
+    }
+
+    /// Returns the value of the $INDEX_ROOT of a directory with the supplied files (the name and
+    /// the MFT record) and no index blocks.
+    fn index_root(files: &[(&str, u64)]) -> Vec<u8>
+    {
+        let mut entries = Vec::new();
+        for (name, number) in files {
+            let name: Vec<u8> = name.encode_utf16().flat_map(|c| c.to_le_bytes()).collect();
+            let mut key = vec![0u8; 66];
+            key[..8].copy_from_slice(&ROOT_RECORD.to_le_bytes());
+            key[64] = (name.len() / 2) as u8;
+            key.extend_from_slice(&name);
+
+            let length = (16 + key.len()).div_ceil(8) * 8;
+            let mut entry = vec![0u8; length];
+            entry[..8].copy_from_slice(&number.to_le_bytes());
+            entry[8..10].copy_from_slice(&(length as u16).to_le_bytes());
+            entry[10..12].copy_from_slice(&(key.len() as u16).to_le_bytes());
+            entry[16..16 + key.len()].copy_from_slice(&key);
+            entries.extend_from_slice(&entry);
+        }
+        let mut end = vec![0u8; 16];
+        end[8..10].copy_from_slice(&16u16.to_le_bytes());
+        end[12..16].copy_from_slice(&INDEX_ENTRY_END.to_le_bytes());
+        entries.extend_from_slice(&end);
+
+        let mut root = vec![0u8; 32];
+        root[..4].copy_from_slice(&0x30u32.to_le_bytes());
+        root[8..12].copy_from_slice(&4096u32.to_le_bytes());
+        root[16..20].copy_from_slice(&16u32.to_le_bytes());
+        root[20..24].copy_from_slice(&(16 + entries.len() as u32).to_le_bytes());
+        root[24..28].copy_from_slice(&(16 + entries.len() as u32).to_le_bytes());
+        root.extend_from_slice(&entries);
+
+        root
+    }
+
+    /// Writes an MFT record into the test drive.
+    fn write_record(drive: &mut [u8], number: usize, record: &[u8])
+    {
+        let offset = 4 * 512 + number * 1024;
+        drive[offset..offset + 1024].copy_from_slice(record);
+    }
+
+    /// Creates a drive with 64 sectors of volume, a clean state and the supplied $Bitmap record.
+    /// $MFT spans clusters 4 to 37 (17 records); the $LogFile data is in clusters 20 and 21.
+    fn test_drive(bitmap: &[u8]) -> Vec<u8>
+    {
+        let bincode_opt = DefaultOptions::new()
+            .with_fixint_encoding()
+            .allow_trailing_bytes();
+
+        let mut drive = vec![0; 66 * 512];
+        let raw = bincode_opt.serialize(&test_boot_sector(64)).unwrap();
+        drive[..raw.len()].copy_from_slice(&raw);
+        drive[510..512].copy_from_slice(&SIGNATURE);
+
+        let mft = non_resident(&[0x11, 34, 4], 17 * 1024);
+        write_record(&mut drive, 0, &test_record(&[(ATTR_DATA, "", 1, &mft)]));
+        let logfile = non_resident(&[0x11, 2, LOGFILE_LCN as u8], 1024);
+        write_record(&mut drive, 2, &test_record(&[(ATTR_DATA, "", 1, &logfile)]));
+        drive[LOGFILE_LCN * 512..(LOGFILE_LCN + 2) * 512].fill(0xff);
+        set_volume_flags(&mut drive, 0);
+        let root = resident(&index_root(&[]));
+        write_record(&mut drive, 5, &test_record(&[(ATTR_INDEX_ROOT, INDEX_NAME, 0, &root)]));
+        write_record(&mut drive, 6, bitmap);
+
+        drive
+    }
+
+    /// Writes the $Volume record with the supplied volume flags.
+    fn set_volume_flags(drive: &mut [u8], flags: u16)
+    {
+        let mut info = [0u8; 12];
+        info[8] = 3;
+        info[9] = 1;
+        info[10..12].copy_from_slice(&flags.to_le_bytes());
+        let body = resident(&info);
+        write_record(drive, 3, &test_record(&[(ATTR_VOLUME_INFORMATION, "", 0, &body)]));
+    }
+
+    /// Returns a $Bitmap record with a resident bitmap of 64 clusters, of which only the last one
+    /// is free.
+    fn resident_bitmap() -> Vec<u8>
+    {
+        let body = resident(&[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x7f]);
+
+        test_record(&[(ATTR_DATA, "", 0, &body)])
+    }
+
+    #[test]
+    fn runs()
+    {
+        // A run of 0x18 clusters at 0x5634, then 2 clusters 0x10 clusters before.
+        let raw = [0x21, 0x18, 0x34, 0x56, 0x11, 0x02, 0xf0, 0x00];
+
+        assert_eq!(decode_runs(&raw).unwrap(), vec![
+            Run { lcn: 0x5634, len: 0x18 },
+            Run { lcn: 0x5624, len: 2 },
+        ]);
+        // Before the start of the volume.
+        assert!(decode_runs(&[0x11, 0x01, 0xff, 0x00]).is_err());
+    }
+
+    #[test]
+    fn runs_out_of_bounds()
+    {
+        let mut ctx = test_context(&test_drive(&resident_bitmap()));
+        let fs = Fs::new(&test_boot_sector(64)).unwrap();
+
+        // A data size larger than the volume is refused before anything is allocated.
+        let runs = [Run { lcn: 1, len: 2 }];
+        assert!(read_runs(&runs, 0, u64::MAX, &fs, &mut ctx).is_err());
+        assert!(read_runs(&runs, u64::MAX, 2, &fs, &mut ctx).is_err());
+        // Runs out of the volume, and runs shorter than the data.
+        assert!(read_runs(&[Run { lcn: 1, len: u64::MAX }], 0, 512, &fs, &mut ctx).is_err());
+        assert!(read_runs(&runs, 0, 2048, &fs, &mut ctx).is_err());
+        assert_eq!(read_runs(&runs, 512, 512, &fs, &mut ctx).unwrap().len(), 512);
+    }
+
+    #[test]
+    fn scan()
+    {
+        // The bitmap is in cluster 30: 8 bytes of data, in a run of 1 cluster.
+        let body = non_resident(&[0x11, 0x01, 30], 8);
+        let mut drive = test_drive(&test_record(&[(ATTR_DATA, "", 1, &body)]));
+        // Clusters 0 to 31 and 40 are used.
+        drive[30 * 512..30 * 512 + 8].copy_from_slice(&[0xff, 0xff, 0xff, 0xff, 0x00, 0x01, 0x00, 0x00]);
+        let mut ctx = test_context(&drive);
+
+        let map = scan_drive(&mut ctx, &Config::default()).unwrap();
+
+        assert_eq!(map.segments(), vec![
+            Segment { start: 0, end: 32 * 512, status: AllocStatus::Used },
+            Segment { start: 32 * 512, end: 40 * 512, status: AllocStatus::Free },
+            Segment { start: 40 * 512, end: 41 * 512, status: AllocStatus::Used },
+            Segment { start: 41 * 512, end: 64 * 512, status: AllocStatus::Free },
+            // The backup boot sector.
+            Segment { start: 64 * 512, end: 65 * 512, status: AllocStatus::Used },
+            Segment { start: 65 * 512, end: 66 * 512, status: AllocStatus::Free },
+        ]);
+    }
+
+    #[test]
+    fn scan_resident_bitmap()
+    {
+        let mut ctx = test_context(&test_drive(&resident_bitmap()));
+        let map = scan_drive(&mut ctx, &Config::default()).unwrap();
+        let free: Vec<&Segment> = map.iter_free().collect();
+
+        assert_eq!(free, vec![
+            &Segment { start: 63 * 512, end: 64 * 512, status: AllocStatus::Free },
+            &Segment { start: 65 * 512, end: 66 * 512, status: AllocStatus::Free },
+        ]);
+    }
+
+    #[test]
+    fn torn_record()
+    {
+        let mut record = resident_bitmap();
+        record[1022] = 0x08;
+        let mut ctx = test_context(&test_drive(&record));
+
+        let err = scan_drive(&mut ctx, &Config::default()).unwrap_err();
+
+        assert!(err.to_string().contains("torn"));
+    }
+
+    #[test]
+    fn dirty()
+    {
+        let mut drive = test_drive(&resident_bitmap());
+        set_volume_flags(&mut drive, VOLUME_IS_DIRTY);
+
+        let mut ctx = test_context(&drive);
+        assert!(scan_drive(&mut ctx, &Config::default()).is_err());
+
+        let cfg = Config { ignore_recovery: true, ..Config::default() };
+        let mut ctx = test_context(&drive);
+        assert!(scan_drive(&mut ctx, &cfg).is_ok());
+    }
+
+    #[test]
+    fn logfile()
+    {
+        let mut drive = test_drive(&resident_bitmap());
+        // A restart page with a log client in use.
+        let page = &mut drive[LOGFILE_LCN * 512..(LOGFILE_LCN + 1) * 512];
+        page.fill(0);
+        page[..4].copy_from_slice(b"RSTR");
+        page[24..26].copy_from_slice(&0x30u16.to_le_bytes());
+
+        let mut ctx = test_context(&drive);
+        assert!(scan_drive(&mut ctx, &Config::default()).is_err());
+
+        // Marked clean.
+        let flags = 0x30 + 14;
+        drive[LOGFILE_LCN * 512 + flags] = RESTART_VOLUME_IS_CLEAN as u8;
+        let mut ctx = test_context(&drive);
+        assert!(scan_drive(&mut ctx, &Config::default()).is_ok());
+    }
+
+    #[test]
+    fn hibernated()
+    {
+        let mut drive = test_drive(&resident_bitmap());
+        let root = resident(&index_root(&[("HIBERFIL.SYS", 16)]));
+        write_record(&mut drive, 5, &test_record(&[(ATTR_INDEX_ROOT, INDEX_NAME, 0, &root)]));
+
+        // The hibernation file is record 16, found through the runs of $MFT.
+        let hiberfile = resident(b"HIBR\0\0\0\0");
+        write_record(&mut drive, 16, &test_record(&[(ATTR_DATA, "", 0, &hiberfile)]));
+        let mut ctx = test_context(&drive);
+        let err = scan_drive(&mut ctx, &Config { ignore_recovery: true, ..Config::default() });
+        assert!(err.unwrap_err().to_string().contains("hibernated"));
+
+        // Windows was shut down.
+        let hiberfile = resident(&[0; 8]);
+        write_record(&mut drive, 16, &test_record(&[(ATTR_DATA, "", 0, &hiberfile)]));
+        let mut ctx = test_context(&drive);
+        assert!(scan_drive(&mut ctx, &Config::default()).is_ok());
+    }
+}
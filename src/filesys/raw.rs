@@ -0,0 +1,75 @@
+use std::io::{Seek, SeekFrom};
+
+use crate::container::mdraid;
+use crate::usage_map::{AllocStatus, UsageMap};
+use crate::{Config, Context};
+
+/// "Scans" the drive without parsing anything: the whole drive is free.
+/// If the drive contains a known file system, a warning is logged, as all of it will be
+/// overwritten by a fill. The metadata of an md RAID member is kept, so that the member can still
+/// be used (e.g. replaced) in its array.
+pub fn scan_drive(ctx: &mut Context, _cfg: &Config) -> anyhow::Result<UsageMap>
+{
+    // Detection errors only mean that nothing was recognised.
+    if let Ok(Some(fs_type)) = super::detect_fs(ctx) {
+        ctx.logger.logln(0, &format!(
+            "WARNING: the drive contains a file system ({}), but the whole drive is treated as \
+            free",
+            fs_type,
+        ));
+    }
+
+    let drive_size = ctx.drive.seek(SeekFrom::End(0))?;
+    let mut map = UsageMap::new(drive_size);
+
+    if let Some(member) = mdraid::read_superblock(ctx)? {
+        ctx.logger.logln(0, &format!(
+            "WARNING: the drive is a member of an md RAID array (metadata {}): only its data is \
+            treated as free",
+            member.version,
+        ));
+
+        for area in &member.metadata {
+            map.update(area.offset, area.len, AllocStatus::Used);
+        }
+    }
+
+    Ok(map)
+}
+
+// Tests.
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::usage_map::Segment;
+    use crate::util::test_context;
+
+    #[test]
+    fn whole_drive_free()
+    {
+        use crate::filesys::minixfs::tests::{test_sb, test_drive};
+
+        // Even with a file system on it.
+        let mut ctx = test_context(&test_drive(&test_sb(), &[8, 9], 64 * 1024));
+
+        let map = scan_drive(&mut ctx, &Config::default()).unwrap();
+
+        assert_eq!(map.segments(), vec![Segment { start: 0, end: 64 * 1024, status: AllocStatus::Free }]);
+    }
+
+    #[test]
+    fn mdraid_metadata_kept()
+    {
+        use crate::container::mdraid::tests::{test_drive, DRIVE_SIZE};
+
+        let mut ctx = test_context(&test_drive("1.2"));
+
+        let map = scan_drive(&mut ctx, &Config::default()).unwrap();
+
+        assert_eq!(map.segments(), vec![
+            Segment { start: 0, end: 64 * 1024, status: AllocStatus::Used },
+            Segment { start: 64 * 1024, end: DRIVE_SIZE, status: AllocStatus::Free },
+        ]);
+    }
+}
@@ -0,0 +1,293 @@
+use std::io::{Seek, SeekFrom};
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+use bincode::{DefaultOptions, Options};
+
+use crate::bitmap::Bitmap;
+use crate::map_format::FsIdentity;
+use crate::usage_map::{AllocStatus, UsageMap};
+use crate::{Config, Context};
+
+// Reference: linux/fs/reiserfs.
+
+/// The offset of the superblock. The old layout of 3.5, with the superblock at 8KiB, is not
+/// supported.
+const SUPERBLOCK_OFFSET: u64 = 64 * 1024;
+
+const REISERFS_MAGIC: &[u8] = b"ReIsErFs";
+const REISER2FS_MAGIC: &[u8] = b"ReIsEr2Fs";
+const REISER2FS_JR_MAGIC: &[u8] = b"ReIsEr3Fs";
+
+/// The file system was unmounted cleanly.
+const REISERFS_VALID_FS: u16 = 1;
+
+/// Journal parameters.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct JournalParams {
+    pub jp_journal_1st_block: u32,
+    pub jp_journal_dev: u32,
+    pub jp_journal_size: u32,
+    pub jp_journal_trans_max: u32,
+    pub jp_journal_magic: u32,
+    pub jp_journal_max_batch: u32,
+    pub jp_journal_max_commit_age: u32,
+    pub jp_journal_max_trans_age: u32,
+}
+
+/// The superblock: the part common to the 3.5 and 3.6 formats, followed by the fields of the 3.6
+/// format up to the UUID.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SuperBlock {
+    pub s_block_count: u32,
+    pub s_free_blocks: u32,
+    pub s_root_block: u32,
+    pub s_journal: JournalParams,
+    pub s_blocksize: u16,
+    pub s_oid_maxsize: u16,
+    pub s_oid_cursize: u16,
+    pub s_umount_state: u16,
+    pub s_magic: [u8; 10],
+    pub s_fs_state: u16,
+    pub s_hash_function_code: u32,
+    pub s_tree_height: u16,
+    /// The number of bitmap blocks; 0 if it does not fit.
+    pub s_bmap_nr: u16,
+    pub s_version: u16,
+    pub s_reserved_for_journal: u16,
+    pub s_inode_generation: u32,
+    pub s_flags: u32,
+    /// Only set in the 3.6 format.
+    pub s_uuid: [u8; 16],
+}
+
+/// Checks the magic value of a superblock.
+pub fn has_magic(sb: &SuperBlock) -> bool
+{
+    [REISERFS_MAGIC, REISER2FS_MAGIC, REISER2FS_JR_MAGIC]
+        .iter()
+        .any(|magic| sb.s_magic.starts_with(magic))
+}
+
+/// Reads the superblock, if the drive has a ReiserFS magic value.
+pub fn read_superblock(ctx: &mut Context) -> anyhow::Result<Option<SuperBlock>>
+{
+    let bincode_opt = DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes();
+
+    let drive_size = ctx.drive.seek(SeekFrom::End(0))?;
+    if drive_size < SUPERBLOCK_OFFSET + 1024 {
+        return Ok(None);
+    }
+
+    ctx.drive.seek(SeekFrom::Start(SUPERBLOCK_OFFSET))?;
+    let sb: SuperBlock = bincode_opt.deserialize_from(&ctx.drive)?;
+
+    if !has_magic(&sb) {
+        return Ok(None);
+    }
+
+    Ok(Some(sb))
+}
+
+/// Scans a ReiserFS volume and returns a map of its usage.
+/// The whole volume is marked as used, except for the blocks that are clear in the block bitmaps.
+pub fn scan_drive(ctx: &mut Context, cfg: &Config) -> anyhow::Result<UsageMap>
+{
+    let sb = match read_superblock(ctx)? {
+        Some(sb) => sb,
+        None => bail!("bad ReiserFS magic value"),
+    };
+
+    let block_size = sb.s_blocksize as u64;
+    if !block_size.is_power_of_two() || !(512..=65536).contains(&block_size) {
+        bail!("invalid block size: {}", block_size);
+    }
+    let block_count = sb.s_block_count as u64;
+    let size = block_count * block_size;
+    let bits_per_bitmap = block_size * 8;
+    let bmap_nr = block_count.div_ceil(bits_per_bitmap);
+
+    // The 3.5 format has no UUID, and neither format records a write time.
+    if !sb.s_magic.starts_with(REISERFS_MAGIC) {
+        ctx.fs_identity = Some(FsIdentity {
+            uuid: crate::util::format_uuid(&sb.s_uuid),
+            write_time: 0,
+        });
+    }
+
+    ctx.logger.logln(1, &format!(
+        "ReiserFS volume: {} blocks of {} bytes, {} free",
+        block_count,
+        block_size,
+        sb.s_free_blocks,
+    ));
+
+    if sb.s_fs_state != 0 {
+        bail!("errors present in the filesystem");
+    }
+    if sb.s_umount_state != REISERFS_VALID_FS && !cfg.ignore_recovery {
+        bail!("filesystem was not unmounted cleanly: try to mount and unmount it, or run fsck");
+    }
+    if sb.s_bmap_nr != 0 && sb.s_bmap_nr as u64 != bmap_nr {
+        bail!("inconsistent number of bitmap blocks: {} (expected {})", sb.s_bmap_nr, bmap_nr);
+    }
+
+    let drive_size = ctx.drive.seek(SeekFrom::End(0))?;
+    if size > drive_size {
+        bail!("the ReiserFS volume ({} bytes) is larger than the drive ({} bytes)", size, drive_size);
+    }
+
+    let mut map = UsageMap::new(drive_size);
+    map.update(0, size, AllocStatus::Used);
+
+    for i in 0..bmap_nr {
+        ctx.drive.seek(SeekFrom::Start(bitmap_block(i, block_size) * block_size))?;
+        let bitmap = Bitmap::from_reader(&mut ctx.drive, block_size as usize)?;
+
+        let first = i * bits_per_bitmap;
+        let count = std::cmp::min(bits_per_bitmap, block_count - first);
+        mark_free_blocks(&mut map, &bitmap, first, count, block_size);
+    }
+
+    Ok(map)
+}
+
+/// Returns the location of a bitmap block. The first one follows the superblock, the others are
+/// the first blocks of the ranges that they describe.
+fn bitmap_block(i: u64, block_size: u64) -> u64
+{
+    if i == 0 {
+        SUPERBLOCK_OFFSET / block_size + 1
+    } else {
+        i * block_size * 8
+    }
+}
+
+/// Marks the runs of blocks that are clear in a bitmap block.
+fn mark_free_blocks(map: &mut UsageMap, bitmap: &Bitmap, first: u64, count: u64, block_size: u64)
+{
+    let mut run_start = None;
+
+    for i in 0..=count {
+        let free = i < count && !bitmap.check_bit(i as usize);
+
+        match (free, run_start) {
+            (true, None) => run_start = Some(i),
+            (false, Some(start)) => {
+                map.update(
+                    (first + start) * block_size,
+                    (i - start) * block_size,
+                    AllocStatus::Free,
+                );
+                run_start = None;
+            },
+            _ => {},
+        }
+    }
+}
+
+// Tests.
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::usage_map::Segment;
+    use crate::util::test_context;
+
+    /// Creates a 3.6 superblock of a volume with 1KiB blocks. Each bitmap block covers 8192
+    /// blocks.
+    pub fn test_sb(block_count: u32) -> SuperBlock
+    {
+        let mut s_magic = [0; 10];
+        s_magic[..9].copy_from_slice(REISER2FS_MAGIC);
+
+        SuperBlock {
+            s_block_count: block_count,
+            s_free_blocks: 0,
+            s_root_block: 0,
+            s_journal: JournalParams::default(),
+            s_blocksize: 1024,
+            s_oid_maxsize: 0,
+            s_oid_cursize: 0,
+            s_umount_state: REISERFS_VALID_FS,
+            s_magic,
+            s_fs_state: 0,
+            s_hash_function_code: 3,
+            s_tree_height: 2,
+            s_bmap_nr: block_count.div_ceil(8192) as u16,
+            s_version: 2,
+            s_reserved_for_journal: 0,
+            s_inode_generation: 0,
+            s_flags: 0,
+            s_uuid: [0; 16],
+        }
+    }
+
+    /// Creates a drive with the superblock and the bitmaps, with the supplied blocks in use.
+    pub fn test_drive(sb: &SuperBlock, used: &[u64], size: usize) -> Vec<u8>
+    {
+        let bincode_opt = DefaultOptions::new()
+            .with_fixint_encoding()
+            .allow_trailing_bytes();
+
+        let mut drive = vec![0; size];
+        let raw = bincode_opt.serialize(sb).unwrap();
+        drive[65536..65536 + raw.len()].copy_from_slice(&raw);
+
+        for block in used {
+            let bitmap = bitmap_block(block / 8192, 1024) as usize * 1024;
+            drive[bitmap + (block % 8192) as usize / 8] |= 1 << (block % 8);
+        }
+
+        drive
+    }
+
+    #[test]
+    fn bitmap_locations()
+    {
+        assert_eq!(bitmap_block(0, 4096), 17);
+        assert_eq!(bitmap_block(1, 4096), 32768);
+        assert_eq!(bitmap_block(0, 1024), 65);
+        assert_eq!(bitmap_block(2, 1024), 16384);
+    }
+
+    #[test]
+    fn scan()
+    {
+        // Two bitmap blocks: the second one covers blocks 8192-8999.
+        let sb = test_sb(9000);
+        let mut used: Vec<u64> = (0..=70).collect();
+        used.extend([100, 8191, 8192, 8999]);
+        let drive = test_drive(&sb, &used, 9100 * 1024);
+        let mut ctx = test_context(&drive);
+
+        let map = scan_drive(&mut ctx, &Config::default()).unwrap();
+
+        assert_eq!(map.segments(), vec![
+            Segment { start: 0, end: 71 * 1024, status: AllocStatus::Used },
+            Segment { start: 71 * 1024, end: 100 * 1024, status: AllocStatus::Free },
+            Segment { start: 100 * 1024, end: 101 * 1024, status: AllocStatus::Used },
+            Segment { start: 101 * 1024, end: 8191 * 1024, status: AllocStatus::Free },
+            Segment { start: 8191 * 1024, end: 8193 * 1024, status: AllocStatus::Used },
+            Segment { start: 8193 * 1024, end: 8999 * 1024, status: AllocStatus::Free },
+            Segment { start: 8999 * 1024, end: 9000 * 1024, status: AllocStatus::Used },
+            // The rest of the drive is left free.
+            Segment { start: 9000 * 1024, end: 9100 * 1024, status: AllocStatus::Free },
+        ]);
+    }
+
+    #[test]
+    fn unclean()
+    {
+        let sb = SuperBlock { s_umount_state: 2, ..test_sb(100) };
+        let drive = test_drive(&sb, &(0..=70).collect::<Vec<u64>>(), 100 * 1024);
+
+        let mut ctx = test_context(&drive);
+        assert!(scan_drive(&mut ctx, &Config::default()).is_err());
+
+        let mut ctx = test_context(&drive);
+        let cfg = Config { ignore_recovery: true, ..Config::default() };
+        assert!(scan_drive(&mut ctx, &cfg).is_ok());
+    }
+}
@@ -0,0 +1,342 @@
+use anyhow::bail;
+
+use crate::container::lvm;
+use crate::usage_map::UsageMap;
+use crate::{Config, Context};
+use super::detect::{self, Detector};
+use super::e2fs::estimate::Estimate;
+use super::{e2fs, f2fs, fat, hfsplus, iso9660, minixfs, ntfs, raw, reiserfs, udf};
+
+/// A scanner of a file system type.
+pub trait FsScanner {
+    /// The name of the file system type, as accepted by `--type`.
+    fn name(&self) -> &'static str;
+
+    /// Checks whether the drive contains the file system. Types that cannot be recognised (e.g.
+    /// the raw type) are never detected.
+    fn detect(&self, ctx: &mut Context) -> anyhow::Result<bool>;
+
+    /// Scans the drive and returns a map of its usage.
+    fn scan(&self, ctx: &mut Context, cfg: &Config) -> anyhow::Result<UsageMap>;
+
+    /// Estimates the free space from the file system's own counts, without scanning.
+    fn quick_estimate(&self, _ctx: &mut Context, _cfg: &Config) -> anyhow::Result<Estimate>
+    {
+        bail!("the quick estimate is not supported for {}", self.name());
+    }
+}
+
+type ScanFn = fn(&mut Context, &Config) -> anyhow::Result<UsageMap>;
+type EstimateFn = fn(&mut Context, &Config) -> anyhow::Result<Estimate>;
+
+/// A built-in scanner, made of the functions of its module.
+#[derive(Copy, Clone)]
+struct Builtin {
+    name: &'static str,
+    detect: Option<Detector>,
+    scan: ScanFn,
+    quick_estimate: Option<EstimateFn>,
+}
+
+impl FsScanner for Builtin {
+    fn name(&self) -> &'static str
+    {
+        self.name
+    }
+
+    fn detect(&self, ctx: &mut Context) -> anyhow::Result<bool>
+    {
+        match self.detect {
+            Some(detect) => detect(ctx),
+            None => Ok(false),
+        }
+    }
+
+    fn scan(&self, ctx: &mut Context, cfg: &Config) -> anyhow::Result<UsageMap>
+    {
+        (self.scan)(ctx, cfg)
+    }
+
+    fn quick_estimate(&self, ctx: &mut Context, cfg: &Config) -> anyhow::Result<Estimate>
+    {
+        match self.quick_estimate {
+            Some(quick_estimate) => quick_estimate(ctx, cfg),
+            None => bail!("the quick estimate is not supported for {}", self.name),
+        }
+    }
+}
+
+/// The built-in scanners, in the order in which they are detected. The ext2/3/4 types share the
+/// scanner, which figures out the features by itself, so only ext2 is detected.
+const BUILTIN: &[Builtin] = &[
+    Builtin {
+        name: "lvm",
+        detect: Some(detect::detect_lvm),
+        scan: lvm::scan_drive,
+        quick_estimate: None,
+    },
+    Builtin {
+        name: "ext2",
+        detect: Some(detect::detect_e2fs),
+        scan: e2fs::scan_drive,
+        quick_estimate: Some(e2fs::quick_estimate),
+    },
+    Builtin {
+        name: "ext3",
+        detect: None,
+        scan: e2fs::scan_drive,
+        quick_estimate: Some(e2fs::quick_estimate),
+    },
+    Builtin {
+        name: "ext4",
+        detect: None,
+        scan: e2fs::scan_drive,
+        quick_estimate: Some(e2fs::quick_estimate),
+    },
+    Builtin {
+        name: "fat32",
+        detect: Some(detect::detect_fat32),
+        scan: fat::scan_drive,
+        quick_estimate: None,
+    },
+    Builtin {
+        name: "ntfs",
+        detect: Some(detect::detect_ntfs),
+        scan: ntfs::scan_drive,
+        quick_estimate: None,
+    },
+    Builtin {
+        name: "f2fs",
+        detect: Some(detect::detect_f2fs),
+        scan: f2fs::scan_drive,
+        quick_estimate: None,
+    },
+    Builtin {
+        name: "minix",
+        detect: Some(detect::detect_minix),
+        scan: minixfs::scan_drive,
+        quick_estimate: None,
+    },
+    Builtin {
+        name: "hfsplus",
+        detect: Some(detect::detect_hfsplus),
+        scan: hfsplus::scan_drive,
+        quick_estimate: None,
+    },
+    Builtin {
+        name: "reiserfs",
+        detect: Some(detect::detect_reiserfs),
+        scan: reiserfs::scan_drive,
+        quick_estimate: None,
+    },
+    // Bridge images carry an ISO9660 volume descriptor as well, so UDF has to be tried first.
+    Builtin {
+        name: "udf",
+        detect: Some(detect::detect_udf),
+        scan: udf::scan_drive,
+        quick_estimate: None,
+    },
+    Builtin {
+        name: "iso9660",
+        detect: Some(detect::detect_iso9660),
+        scan: iso9660::scan_drive,
+        quick_estimate: None,
+    },
+    Builtin {
+        name: "raw",
+        detect: None,
+        scan: raw::scan_drive,
+        quick_estimate: None,
+    },
+];
+
+/// The scanners of the file system types, by name.
+pub struct Registry {
+    scanners: Vec<Box<dyn FsScanner>>,
+}
+
+impl Default for Registry {
+    /// Creates a registry of the built-in scanners.
+    fn default() -> Self
+    {
+        let mut registry = Self::empty();
+        for builtin in BUILTIN {
+            registry.register(Box::new(*builtin));
+        }
+
+        registry
+    }
+}
+
+impl Registry {
+    /// Creates a registry without any scanners.
+    pub fn empty() -> Self
+    {
+        Self { scanners: Vec::new() }
+    }
+
+    /// Adds a scanner, which is detected after the ones already registered. A scanner of the same
+    /// name is replaced in place.
+    pub fn register(&mut self, scanner: Box<dyn FsScanner>)
+    {
+        match self.scanners.iter_mut().find(|s| s.name() == scanner.name()) {
+            Some(s) => *s = scanner,
+            None => self.scanners.push(scanner),
+        }
+    }
+
+    /// Returns the scanner of a file system type.
+    pub fn get(&self, name: &str) -> Option<&dyn FsScanner>
+    {
+        self.scanners.iter().find(|s| s.name() == name).map(|s| s.as_ref())
+    }
+
+    /// Returns the scanner of a file system type chosen explicitly, refusing the containers that
+    /// hide the file systems as `detect` does. Only the raw type, which maps the whole drive as
+    /// free, is not checked.
+    pub fn get_checked(&self, ctx: &mut Context, name: &str)
+        -> anyhow::Result<Option<&dyn FsScanner>>
+    {
+        let scanner = self.get(name);
+
+        if scanner.is_some() && name != "raw" {
+            detect::check_containers(ctx)?;
+        }
+
+        Ok(scanner)
+    }
+
+    /// Returns the names of the file system types, in the order of registration.
+    pub fn names(&self) -> Vec<&'static str>
+    {
+        self.scanners.iter().map(|s| s.name()).collect()
+    }
+
+    /// Detects the file system. The scanners are tried in order and the first match is returned.
+    /// Containers that hide the file systems (LUKS, RAID members) are refused first.
+    pub fn detect(&self, ctx: &mut Context) -> anyhow::Result<Option<&dyn FsScanner>>
+    {
+        detect::check_containers(ctx)?;
+
+        for scanner in &self.scanners {
+            if scanner.detect(ctx)? {
+                return Ok(Some(scanner.as_ref()));
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+// Tests.
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::test_context;
+
+    /// A test scanner with a fixed detection result.
+    struct TestScanner(&'static str, Option<bool>);
+
+    impl FsScanner for TestScanner {
+        fn name(&self) -> &'static str
+        {
+            self.0
+        }
+
+        fn detect(&self, _ctx: &mut Context) -> anyhow::Result<bool>
+        {
+            match self.1 {
+                Some(detected) => Ok(detected),
+                None => bail!("detector failed"),
+            }
+        }
+
+        fn scan(&self, ctx: &mut Context, _cfg: &Config) -> anyhow::Result<UsageMap>
+        {
+            use std::io::{Seek, SeekFrom};
+
+            Ok(UsageMap::new(ctx.drive.seek(SeekFrom::End(0))?))
+        }
+    }
+
+    fn registry(scanners: Vec<TestScanner>) -> Registry
+    {
+        let mut registry = Registry::empty();
+        for scanner in scanners {
+            registry.register(Box::new(scanner));
+        }
+
+        registry
+    }
+
+    #[test]
+    fn first_match_wins()
+    {
+        let mut ctx = test_context(&[0; 4096]);
+        let registry = registry(vec![
+            TestScanner("none", Some(false)),
+            TestScanner("ext3", Some(true)),
+            TestScanner("ext4", Some(true)),
+        ]);
+
+        assert_eq!(registry.detect(&mut ctx).unwrap().map(|s| s.name()), Some("ext3"));
+    }
+
+    #[test]
+    fn no_match()
+    {
+        let mut ctx = test_context(&[0; 4096]);
+        let registry = registry(vec![TestScanner("none", Some(false))]);
+
+        assert!(registry.detect(&mut ctx).unwrap().is_none());
+    }
+
+    #[test]
+    fn error_stops_detection()
+    {
+        let mut ctx = test_context(&[0; 4096]);
+        let registry = registry(vec![
+            TestScanner("none", Some(false)),
+            TestScanner("err", None),
+            TestScanner("ext4", Some(true)),
+        ]);
+
+        assert!(registry.detect(&mut ctx).is_err());
+    }
+
+    #[test]
+    fn explicit_type_checks_containers()
+    {
+        let registry = Registry::default();
+
+        let mut ctx = test_context(&crate::container::mdraid::tests::test_drive("1.0"));
+        assert!(registry.get_checked(&mut ctx, "ext4").is_err());
+        assert_eq!(registry.get_checked(&mut ctx, "raw").unwrap().unwrap().name(), "raw");
+
+        let mut ctx = test_context(&[0; 4096]);
+        assert_eq!(registry.get_checked(&mut ctx, "ext4").unwrap().unwrap().name(), "ext4");
+        assert!(registry.get_checked(&mut ctx, "test").unwrap().is_none());
+    }
+
+    #[test]
+    fn register()
+    {
+        let mut registry = Registry::default();
+        assert!(registry.get("ext4").is_some());
+        assert!(registry.get("test").is_none());
+
+        // Replacing a built-in scanner keeps its place in the detection order.
+        registry.register(Box::new(TestScanner("fat32", Some(true))));
+        registry.register(Box::new(TestScanner("test", Some(true))));
+        let names = registry.names();
+        assert_eq!(names.iter().position(|n| *n == "fat32"), Some(4));
+        assert_eq!(names.last(), Some(&"test"));
+
+        let mut ctx = test_context(&[0; 4096]);
+        let scanner = registry.detect(&mut ctx).unwrap().unwrap();
+        assert_eq!(scanner.name(), "fat32");
+        assert!(scanner.quick_estimate(&mut ctx, &Config::default()).is_err());
+        assert_eq!(scanner.scan(&mut ctx, &Config::default()).unwrap().size(), 4096);
+    }
+}
@@ -0,0 +1,413 @@
+use std::io::{Read, Seek, SeekFrom};
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+use bincode::{DefaultOptions, Options};
+
+use crate::array::Array;
+use crate::bitmap::Bitmap;
+use crate::usage_map::{AllocStatus, UsageMap};
+use crate::{Config, Context};
+
+// Reference: ECMA-167, 3rd edition; OSTA UDF 2.60.
+
+/// The supported sector (and logical block) size.
+const SECTOR_SIZE: u64 = 2048;
+/// The first sector of the volume recognition sequence.
+const VRS_START: u64 = 16;
+/// The limit of the length of the volume recognition sequence.
+const VRS_MAX: u64 = 16;
+/// The sector of the anchor volume descriptor pointer.
+const ANCHOR_SECTOR: u64 = 256;
+/// The limit of the length of the volume descriptor sequence, against a missing terminator.
+const VDS_MAX: u64 = 256;
+
+const TAG_ANCHOR: u16 = 2;
+const TAG_PARTITION: u16 = 5;
+const TAG_LOGICAL_VOLUME: u16 = 6;
+const TAG_TERMINATING: u16 = 8;
+const TAG_SPACE_BITMAP: u16 = 264;
+
+/// The extent length bits of a short allocation descriptor; the upper 2 bits hold the type.
+const SHORT_AD_LEN_MASK: u32 = 0x3fff_ffff;
+
+/// A descriptor tag.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Tag {
+    pub ident: u16,
+    pub version: u16,
+    pub checksum: u8,
+    pub reserved: u8,
+    pub serial: u16,
+    pub crc: u16,
+    pub crc_length: u16,
+    pub location: u32,
+}
+
+const TAG_SIZE: usize = 16;
+
+/// An extent of the volume, in bytes and sectors.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ExtentAd {
+    pub length: u32,
+    pub location: u32,
+}
+
+/// An extent of a partition, in bytes and blocks relative to the partition.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ShortAd {
+    pub length: u32,
+    pub position: u32,
+}
+
+/// Anchor volume descriptor pointer.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct AnchorPointer {
+    pub tag: Tag,
+    pub main_vds: ExtentAd,
+    pub reserve_vds: ExtentAd,
+}
+
+/// Partition descriptor, with the partition header descriptor of its contents use.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct PartitionDescriptor {
+    pub tag: Tag,
+    pub vds_number: u32,
+    pub flags: u16,
+    pub number: u16,
+    pub contents: Array<u8, 32>,
+    pub unallocated_space_table: ShortAd,
+    pub unallocated_space_bitmap: ShortAd,
+    pub integrity_table: ShortAd,
+    pub freed_space_table: ShortAd,
+    pub freed_space_bitmap: ShortAd,
+    pub header_reserved: Array<u8, 88>,
+    pub access_type: u32,
+    pub start: u32,
+    pub length: u32,
+}
+
+/// Logical volume descriptor, up to the logical block size.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct LogicalVolumeDescriptor {
+    pub tag: Tag,
+    pub vds_number: u32,
+    pub charset: Array<u8, 64>,
+    pub identifier: Array<u8, 128>,
+    pub block_size: u32,
+}
+
+/// The header of a space bitmap descriptor; the bitmap follows, a set bit marks a free block.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SpaceBitmapHeader {
+    pub tag: Tag,
+    pub bits: u32,
+    pub bytes: u32,
+}
+
+const SPACE_BITMAP_HEADER_SIZE: u64 = 24;
+
+/// Checks whether the drive has a volume recognition sequence with an NSR descriptor, i.e.
+/// whether it contains UDF.
+pub fn has_nsr(ctx: &mut Context) -> anyhow::Result<bool>
+{
+    let drive_size = ctx.drive.seek(SeekFrom::End(0))?;
+
+    for sector in VRS_START..VRS_START + VRS_MAX {
+        if (sector + 1) * SECTOR_SIZE > drive_size {
+            break;
+        }
+
+        let mut id = [0u8; 6];
+        ctx.drive.seek(SeekFrom::Start(sector * SECTOR_SIZE))?;
+        ctx.drive.read_exact(&mut id)?;
+
+        match &id[1..] {
+            b"NSR02" | b"NSR03" => return Ok(true),
+            b"BEA01" | b"CD001" | b"CDW02" | b"BOOT2" => {},
+            _ => break,
+        }
+    }
+
+    Ok(false)
+}
+
+/// Scans a UDF volume and returns a map of its usage.
+/// The whole drive is marked as used, except for the blocks that the unallocated space bitmaps of
+/// the partitions mark as free. Partitions without a bitmap (e.g. on write-once media) stay used.
+/// The volume can only be scanned for a report.
+pub fn scan_drive(ctx: &mut Context, cfg: &Config) -> anyhow::Result<UsageMap>
+{
+    if !cfg.report_only {
+        bail!("UDF is only supported in the report-only mode");
+    }
+
+    let bincode_opt = DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes();
+
+    let drive_size = ctx.drive.seek(SeekFrom::End(0))?;
+
+    let anchor: AnchorPointer = read_descriptor(ANCHOR_SECTOR, TAG_ANCHOR, ctx)?;
+    let vds_start = anchor.main_vds.location as u64;
+    let vds_len = std::cmp::min(anchor.main_vds.length as u64 / SECTOR_SIZE, VDS_MAX);
+
+    // Read the volume descriptor sequence.
+
+    let mut partitions = Vec::new();
+    let mut terminated = false;
+
+    for sector in vds_start..vds_start + vds_len {
+        let raw = read_sector(sector, ctx)?;
+        let tag: Tag = bincode_opt.deserialize(&raw)?;
+        check_tag(&tag, &raw, sector)?;
+
+        match tag.ident {
+            TAG_PARTITION => partitions.push(bincode_opt.deserialize::<PartitionDescriptor>(&raw)?),
+            TAG_LOGICAL_VOLUME => {
+                let lvd: LogicalVolumeDescriptor = bincode_opt.deserialize(&raw)?;
+                if lvd.block_size as u64 != SECTOR_SIZE {
+                    bail!("unsupported logical block size: {}", lvd.block_size);
+                }
+            },
+            TAG_TERMINATING => {
+                terminated = true;
+                break;
+            },
+            _ => {},
+        }
+    }
+
+    if !terminated {
+        bail!("missing volume descriptor sequence terminator");
+    }
+    if partitions.is_empty() {
+        bail!("missing partition descriptor");
+    }
+
+    ctx.logger.logln(1, &format!("UDF volume: {} partitions", partitions.len()));
+
+    let mut map = UsageMap::new(drive_size);
+    map.update(0, drive_size, AllocStatus::Used);
+
+    for pd in &partitions {
+        let start = pd.start as u64;
+        let len = pd.length as u64;
+        if (start + len) * SECTOR_SIZE > drive_size {
+            bail!("partition {} reaches beyond the end of the drive", pd.number);
+        }
+
+        let bitmap_ad = pd.unallocated_space_bitmap;
+        if bitmap_ad.length & SHORT_AD_LEN_MASK == 0 {
+            ctx.logger.logln(1, &format!(
+                "partition {} has no unallocated space bitmap: all of its space is used",
+                pd.number,
+            ));
+            continue;
+        }
+
+        // The bitmap descriptor, located relative to the partition.
+        let bitmap_sector = start + bitmap_ad.position as u64;
+        let header: SpaceBitmapHeader = read_descriptor(bitmap_sector, TAG_SPACE_BITMAP, ctx)?;
+        if (header.bits as u64) < len || (header.bytes as u64) * 8 < header.bits as u64 {
+            bail!("the space bitmap of partition {} is too small", pd.number);
+        }
+
+        ctx.drive.seek(SeekFrom::Start(bitmap_sector * SECTOR_SIZE + SPACE_BITMAP_HEADER_SIZE))?;
+        let bitmap = Bitmap::from_reader(&mut ctx.drive, header.bytes as usize)?;
+
+        mark_free_blocks(&mut map, &bitmap, start, len);
+    }
+
+    Ok(map)
+}
+
+/// Marks the runs of blocks that are free in a partition's space bitmap.
+fn mark_free_blocks(map: &mut UsageMap, bitmap: &Bitmap, start: u64, len: u64)
+{
+    let mut run_start = None;
+
+    for i in 0..=len {
+        let free = i < len && bitmap.check_bit(i as usize);
+
+        match (free, run_start) {
+            (true, None) => run_start = Some(i),
+            (false, Some(first)) => {
+                map.update((start + first) * SECTOR_SIZE, (i - first) * SECTOR_SIZE, AllocStatus::Free);
+                run_start = None;
+            },
+            _ => {},
+        }
+    }
+}
+
+/// Reads a sector.
+fn read_sector(sector: u64, ctx: &mut Context) -> anyhow::Result<Vec<u8>>
+{
+    let mut raw = vec![0u8; SECTOR_SIZE as usize];
+    ctx.drive.seek(SeekFrom::Start(sector * SECTOR_SIZE))?;
+    ctx.drive.read_exact(&mut raw)?;
+
+    Ok(raw)
+}
+
+/// Reads a descriptor and checks its tag.
+fn read_descriptor<T>(sector: u64, ident: u16, ctx: &mut Context) -> anyhow::Result<T>
+where
+    T: serde::de::DeserializeOwned
+{
+    let bincode_opt = DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes();
+
+    let raw = read_sector(sector, ctx)?;
+    let tag: Tag = bincode_opt.deserialize(&raw)?;
+    check_tag(&tag, &raw, sector)?;
+
+    if tag.ident != ident {
+        bail!("unexpected descriptor in sector {}: {} (expected {})", sector, tag.ident, ident);
+    }
+
+    Ok(bincode_opt.deserialize(&raw)?)
+}
+
+/// Checks the checksum of a descriptor tag.
+fn check_tag(tag: &Tag, raw: &[u8], sector: u64) -> anyhow::Result<()>
+{
+    if tag_checksum(&raw[..TAG_SIZE]) != tag.checksum {
+        bail!("bad descriptor tag checksum in sector {}", sector);
+    }
+
+    Ok(())
+}
+
+/// Calculates the checksum of a descriptor tag: the sum of its bytes, except for the checksum.
+fn tag_checksum(tag: &[u8]) -> u8
+{
+    tag.iter()
+        .enumerate()
+        .filter(|(i, _)| *i != 4)
+        .fold(0u8, |sum, (_, b)| sum.wrapping_add(*b))
+}
+
+// Tests.
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::usage_map::Segment;
+    use crate::util::test_context;
+
+    fn tag(ident: u16, location: u32) -> Tag
+    {
+        Tag { ident, version: 2, checksum: 0, reserved: 0, serial: 0, crc: 0, crc_length: 0, location }
+    }
+
+    struct TestDrive(Vec<u8>);
+
+    impl TestDrive {
+        fn write<T: Serialize>(&mut self, sector: u64, descriptor: &T)
+        {
+            let bincode_opt = DefaultOptions::new()
+                .with_fixint_encoding()
+                .allow_trailing_bytes();
+
+            let mut raw = bincode_opt.serialize(descriptor).unwrap();
+            raw[4] = tag_checksum(&raw[..TAG_SIZE]);
+
+            let offset = (sector * SECTOR_SIZE) as usize;
+            self.0[offset..offset + raw.len()].copy_from_slice(&raw);
+        }
+    }
+
+    /// Creates a 400-sector drive with a partition from sector 300 to the end. If `bitmap` is set,
+    /// the partition has a space bitmap, in its first block, that marks the blocks from 50 as
+    /// free.
+    pub fn test_drive(bitmap: bool) -> Vec<u8>
+    {
+        let mut drive = TestDrive(vec![0u8; 400 * SECTOR_SIZE as usize]);
+
+        drive.0[16 * 2048 + 1..16 * 2048 + 6].copy_from_slice(b"BEA01");
+        drive.0[17 * 2048 + 1..17 * 2048 + 6].copy_from_slice(b"NSR02");
+        drive.0[18 * 2048 + 1..18 * 2048 + 6].copy_from_slice(b"TEA01");
+
+        drive.write(256, &AnchorPointer {
+            tag: tag(TAG_ANCHOR, 256),
+            main_vds: ExtentAd { length: 3 * 2048, location: 32 },
+            reserve_vds: ExtentAd { length: 0, location: 0 },
+        });
+
+        let empty = ShortAd { length: 0, position: 0 };
+        drive.write(32, &PartitionDescriptor {
+            tag: tag(TAG_PARTITION, 32),
+            vds_number: 1,
+            flags: 1,
+            number: 0,
+            contents: Array::default(),
+            unallocated_space_table: empty,
+            unallocated_space_bitmap: if bitmap { ShortAd { length: 24 + 13, position: 0 } } else { empty },
+            integrity_table: empty,
+            freed_space_table: empty,
+            freed_space_bitmap: empty,
+            header_reserved: Array::default(),
+            access_type: 4,
+            start: 300,
+            length: 100,
+        });
+        drive.write(33, &LogicalVolumeDescriptor {
+            tag: tag(TAG_LOGICAL_VOLUME, 33),
+            vds_number: 2,
+            charset: Array::default(),
+            identifier: Array::default(),
+            block_size: 2048,
+        });
+        drive.write(34, &tag(TAG_TERMINATING, 34));
+
+        if bitmap {
+            drive.write(300, &SpaceBitmapHeader { tag: tag(TAG_SPACE_BITMAP, 0), bits: 100, bytes: 13 });
+            let offset = 300 * 2048 + 24;
+            // Blocks 50 to 99.
+            drive.0[offset + 6] = 0xfc;
+            for b in &mut drive.0[offset + 7..offset + 13] {
+                *b = 0xff;
+            }
+        }
+
+        drive.0
+    }
+
+    #[test]
+    fn scan()
+    {
+        let mut ctx = test_context(&test_drive(true));
+
+        let map = scan_drive(&mut ctx, &Config::default()).unwrap();
+
+        assert_eq!(map.segments(), vec![
+            Segment { start: 0, end: 350 * 2048, status: AllocStatus::Used },
+            Segment { start: 350 * 2048, end: 400 * 2048, status: AllocStatus::Free },
+        ]);
+    }
+
+    #[test]
+    fn no_bitmap()
+    {
+        let mut ctx = test_context(&test_drive(false));
+
+        let map = scan_drive(&mut ctx, &Config::default()).unwrap();
+
+        assert_eq!(map.segments(), vec![Segment { start: 0, end: 400 * 2048, status: AllocStatus::Used }]);
+    }
+
+    #[test]
+    fn fill_refused_and_bad_tag()
+    {
+        let mut drive = test_drive(true);
+        let mut ctx = test_context(&drive);
+        let cfg = Config { report_only: false, ..Config::default() };
+        assert!(scan_drive(&mut ctx, &cfg).is_err());
+
+        drive[256 * 2048 + 8] ^= 1;
+        let mut ctx = test_context(&drive);
+        assert!(scan_drive(&mut ctx, &Config::default()).is_err());
+    }
+}
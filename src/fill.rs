@@ -1,11 +1,69 @@
-use std::io::{Seek, SeekFrom, Write};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use anyhow::bail;
 use clap::ArgEnum;
 use rand::prelude::*;
 use rand_chacha::ChaCha20Rng;
 use rand_hc::Hc128Rng;
+use rand_xoshiro::Xoshiro256PlusPlus;
+use rand::rngs::OsRng;
+use aes::Aes256;
+use aes::cipher::{KeyIvInit, StreamCipher, StreamCipherSeek};
+use serde::Serialize;
 
 use crate::{Context, Config};
-use crate::usage_map::{UsageMap, AllocStatus};
+use crate::drive::Drive;
+use crate::io_engine::IoEngine;
+use crate::progress::Progress;
+use crate::interrupt::{self, Interrupted};
+use crate::fill_checkpoint::{self, Checkpoint};
+use crate::usage_map::{UsageMap, Segment, AllocStatus};
+
+/// The amount of data written by the throughput probe.
+const PROBE_SIZE: u64 = 256 * 1024 * 1024;
+
+/// The amount of data requested from the operating system's CSPRNG at once.
+const OS_RANDOM_CHUNK: usize = 1024 * 1024;
+
+/// The interval over which the throughput of the fill is sampled, for its minimum and maximum.
+const STATS_WINDOW: Duration = Duration::from_secs(1);
+
+/// The file into which the progress of an interrupted fill is saved, unless configured otherwise.
+const DEFAULT_CHECKPOINT: &str = "fsfill-fill.ckpt";
+
+/// The size of the samples read by the spot check.
+const SPOT_SIZE: u64 = 512;
+
+/// The alignment of the ranges that are discarded or zeroed in the kernel, in the underlying file;
+/// a multiple of the logical sector size of the drives.
+const CLEAR_ALIGN: u64 = 4096;
+
+/// The size of the ranges cleared at once, between which the clearing can be interrupted. A
+/// multiple of CLEAR_ALIGN.
+const CLEAR_CHUNK: u64 = 64 * 1024 * 1024;
+
+/// The alignment of the direct writes, in the underlying file; a multiple of the logical sector
+/// size of the drives.
+const DIRECT_ALIGN: u64 = 4096;
+
+/// The size of the buffer of the direct writes; a multiple of DIRECT_ALIGN.
+const DIRECT_BUF_SIZE: usize = 1024 * 1024;
+
+/// The size of the blocks of the free space that are skipped by `--skip-zeroed`, if all zeroes.
+const SKIP_BLOCK_SIZE: usize = 64 * 1024;
+
+/// The largest write of the fill.
+const WRITE_SIZE: usize = 64 * 1024;
+
+/// The size of the pieces in which the fill buffer is generated, so that the fill data does not
+/// depend on the size of the writes.
+const REFILL_SIZE: usize = 4096;
+
+/// The interval at which the statistics and the progress of a parallel fill are updated.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
 
 #[derive(Copy, Clone, Debug, ArgEnum)]
 pub enum FillMode {
@@ -13,11 +71,113 @@ pub enum FillMode {
     #[clap(name = "chacha20")]
     ChaCha20,
     Hc128,
+    /// AES-256 in counter mode; fast on CPUs with AES instructions.
+    #[clap(name = "aes-ctr")]
+    AesCtr,
+    /// xoshiro256++: not cryptographically secure, but the fastest of the random modes.
+    Xoshiro,
+    /// The CSPRNG of the operating system (getrandom).
+    #[clap(name = "os-random")]
+    OsRandom,
+    /// A constant byte, given by `--pattern-byte`, or the contents of `--pattern-file`, repeated.
+    Pattern,
+    /// DoD 5220.22-M: zeroes, ones and verified random data.
+    Dod3,
+    /// DoD 5220.22-M ECE: the three DoD passes, a random pass and the three DoD passes again.
+    Dod7,
+    /// The free space is discarded (TRIM, or holes in image files) instead of written; what cannot
+    /// be discarded is zeroed.
+    Discard,
+}
+
+impl FillMode {
+    /// Checks whether the mode writes random data.
+    fn is_random(self) -> bool
+    {
+        match self {
+            Self::ChaCha20 |
+            Self::Hc128 |
+            Self::AesCtr |
+            Self::Xoshiro |
+            Self::OsRandom |
+            Self::Dod3 |
+            Self::Dod7 => true,
+            Self::Zero | Self::Pattern | Self::Discard => false,
+        }
+    }
+}
+
+/// Fill profiles that expand to a fill mode and the verification settings.
+#[derive(Copy, Clone, Debug, ArgEnum)]
+pub enum Preset {
+    /// NIST SP 800-88 Clear: a single pass of zeroes, verified.
+    NistClear,
+    /// NIST SP 800-88 Clear with random data: a single pass of random data, verified. This is not
+    /// a Purge, which on flash media takes the cryptographic erase or the sanitize commands of the
+    /// drive.
+    NistClearRandom,
+}
+
+impl Preset {
+    /// Sets the fill mode and the verification of the preset.
+    pub fn apply(self, cfg: &mut Config)
+    {
+        cfg.fill_mode = match self {
+            Self::NistClear => FillMode::Zero,
+            Self::NistClearRandom => FillMode::ChaCha20,
+        };
+        cfg.verify = true;
+    }
+}
+
+/// A pass of a multi-pass fill.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+struct Pass {
+    source: PassSource,
+    /// The pass is read back and compared after it is written.
+    verify: bool,
+}
+
+/// The data written by a pass.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum PassSource {
+    /// A constant byte.
+    Byte(u8),
+    /// ChaCha20 output, from a seed kept for the verification.
+    Random,
+}
+
+/// Returns the passes of a multi-pass fill mode.
+fn schedule(mode: FillMode) -> Vec<Pass>
+{
+    let dod = [
+        Pass { source: PassSource::Byte(0x00), verify: false },
+        Pass { source: PassSource::Byte(0xff), verify: false },
+        Pass { source: PassSource::Random, verify: true },
+    ];
+
+    match mode {
+        FillMode::Dod3 => dod.to_vec(),
+        FillMode::Dod7 => {
+            let mut passes = dod.to_vec();
+            passes.push(Pass { source: PassSource::Random, verify: false });
+            passes.extend_from_slice(&dod);
+            passes
+        },
+        FillMode::Zero |
+        FillMode::ChaCha20 |
+        FillMode::Hc128 |
+        FillMode::AesCtr |
+        FillMode::Xoshiro |
+        FillMode::OsRandom |
+        FillMode::Pattern |
+        FillMode::Discard => vec![],
+    }
 }
 
 /// Zero generator.
-/// The generator does nothing. It relies on the assumption that the buffer is already
-/// zero-initialised.
+/// The generator zeroes the buffer on each refill, so that the zero fill does not depend on the
+/// buffer's initial contents.
 struct ZeroGen;
 
 impl ZeroGen {
@@ -27,121 +187,2579 @@ impl ZeroGen {
 impl RngCore for ZeroGen {
     fn next_u32(&mut self) -> u32 { 0 }
     fn next_u64(&mut self) -> u64 { 0 }
-    fn fill_bytes(&mut self, _dest: &mut [u8]) {}
-    fn try_fill_bytes(&mut self, _dest: &mut [u8]) -> Result<(), rand::Error> { Ok(()) }
+    fn fill_bytes(&mut self, dest: &mut [u8]) { dest.fill(0) }
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> { dest.fill(0); Ok(()) }
 }
 
-/// Fills all the free space on the drive.
-pub fn fill_free_space(map: &UsageMap, ctx: &mut Context, cfg: &Config) -> anyhow::Result<()>
-{
-    match cfg.fill_mode {
-        FillMode::Zero => fill_free_space_with(
-            &mut ZeroGen::new(),
-            map,
-            &mut ctx.drive
-        ),
-        FillMode::ChaCha20 => fill_free_space_with(
-            &mut ChaCha20Rng::from_entropy(),
-            map,
-            &mut ctx.drive
-        ),
-        FillMode::Hc128 => fill_free_space_with(
-            &mut Hc128Rng::from_entropy(),
-            map,
-            &mut ctx.drive
-        ),
-    }
-}
-
-/// Fills all the free space on the disk, using a supplied byte generator.
-fn fill_free_space_with<R, W>(gen: &mut R, map: &UsageMap, drive: &mut W) -> anyhow::Result<()>
-where
-    R: RngCore,
-    W: Write + Seek
-{
-    // NOTE: IMPORTANT: keep this initialised with zeroes for ZeroGen.
-    let mut buf = [0; 4096];
-    // Buffer head.
-    let mut head = 0;
-    gen.fill_bytes(&mut buf);
+/// Constant byte generator.
+struct ByteGen(u8);
 
-    // Iterate through the segments in the map.
-    // If a segment is free, fill the corresponding drive addresses with the bytes from the buffer.
-    // The buffer is refilled with the byte generator when it is used up.
+impl RngCore for ByteGen {
+    fn next_u32(&mut self) -> u32 { u32::from_ne_bytes([self.0; 4]) }
+    fn next_u64(&mut self) -> u64 { u64::from_ne_bytes([self.0; 8]) }
+    fn fill_bytes(&mut self, dest: &mut [u8]) { dest.fill(self.0) }
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> { dest.fill(self.0); Ok(()) }
+}
 
-    for segment in map {
-        if segment.status == AllocStatus::Free {
-            drive.seek(SeekFrom::Start(segment.start))?;
+/// Generator of the AES-256-CTR keystream, keyed with the seed, with a zero initial counter.
+struct AesCtrGen(ctr::Ctr128BE<Aes256>);
 
-            let mut written = 0;
+impl AesCtrGen {
+    fn new(seed: [u8; 32]) -> Self
+    {
+        Self(ctr::Ctr128BE::<Aes256>::new(&seed.into(), &[0; 16].into()))
+    }
+}
 
-            while written < segment.size() {
-                if head == buf.len() {
-                    gen.fill_bytes(&mut buf);
-                    head = 0;
-                }
+impl RngCore for AesCtrGen {
+    fn next_u32(&mut self) -> u32 { rand_chacha::rand_core::impls::next_u32_via_fill(self) }
+    fn next_u64(&mut self) -> u64 { rand_chacha::rand_core::impls::next_u64_via_fill(self) }
 
-                let buf_remaining = buf.len() - head;
-                let to_write = segment.size() - written;
-                let write_size = std::cmp::min(to_write, buf_remaining);
+    fn fill_bytes(&mut self, dest: &mut [u8])
+    {
+        dest.fill(0);
+        self.0.apply_keystream(dest);
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error>
+    {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+/// Generator of the operating system's random data.
+/// The data is requested in chunks of OS_RANDOM_CHUNK bytes, rather than for each refill of the
+/// fill buffer, to limit the number of system calls.
+struct OsRandomGen {
+    chunk: Vec<u8>,
+    pos: usize,
+}
+
+impl OsRandomGen {
+    fn new() -> Self
+    {
+        Self { chunk: vec![0; OS_RANDOM_CHUNK], pos: OS_RANDOM_CHUNK }
+    }
+}
+
+impl RngCore for OsRandomGen {
+    fn next_u32(&mut self) -> u32 { rand_chacha::rand_core::impls::next_u32_via_fill(self) }
+    fn next_u64(&mut self) -> u64 { rand_chacha::rand_core::impls::next_u64_via_fill(self) }
+
+    fn fill_bytes(&mut self, dest: &mut [u8])
+    {
+        self.try_fill_bytes(dest).unwrap()
+    }
 
-                drive.write_all(&buf[head..head + write_size])?;
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error>
+    {
+        let mut filled = 0;
 
-                written += write_size;
-                head += write_size;
+        while filled < dest.len() {
+            if self.pos == self.chunk.len() {
+                OsRng.try_fill_bytes(&mut self.chunk)?;
+                self.pos = 0;
             }
+
+            let len = std::cmp::min(dest.len() - filled, self.chunk.len() - self.pos);
+            dest[filled..filled + len].copy_from_slice(&self.chunk[self.pos..self.pos + len]);
+            filled += len;
+            self.pos += len;
         }
+
+        Ok(())
     }
+}
 
-    Ok(())
+/// Pattern generator.
+/// The pattern is repeated cyclically, continuing where the previous refill stopped.
+struct PatternGen {
+    pattern: Vec<u8>,
+    pos: usize,
 }
 
-// Debug and Display implementations.
+impl PatternGen {
+    fn new(pattern: Vec<u8>) -> Self
+    {
+        assert!(!pattern.is_empty());
 
-impl std::fmt::Display for FillMode {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result
+        Self { pattern, pos: 0 }
+    }
+}
+
+impl RngCore for PatternGen {
+    fn next_u32(&mut self) -> u32 { rand_chacha::rand_core::impls::next_u32_via_fill(self) }
+    fn next_u64(&mut self) -> u64 { rand_chacha::rand_core::impls::next_u64_via_fill(self) }
+
+    fn fill_bytes(&mut self, dest: &mut [u8])
     {
-        match self {
-            Self::Zero =>write!(f, "zero"),
-            Self::ChaCha20 => write!(f, "chacha20"),
-            Self::Hc128 => write!(f, "HC128"),
+        for b in dest {
+            *b = self.pattern[self.pos];
+            self.pos = (self.pos + 1) % self.pattern.len();
         }
     }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error>
+    {
+        self.fill_bytes(dest);
+        Ok(())
+    }
 }
 
-// Tests
+/// A byte generator that can skip to a position of its output, so that the spot check does not
+/// have to generate everything before each sample.
+trait SeekGen: RngCore {
+    /// Moves to a byte of the output, a multiple of REFILL_SIZE. Returns false, without moving, if
+    /// the generator cannot skip ahead.
+    fn seek_to(&mut self, _pos: u64) -> bool
+    {
+        false
+    }
+}
 
-#[cfg(test)]
-mod tests {
-    #[test]
-    fn fill()
+impl SeekGen for ZeroGen {
+    fn seek_to(&mut self, _pos: u64) -> bool { true }
+}
+
+impl SeekGen for ByteGen {
+    fn seek_to(&mut self, _pos: u64) -> bool { true }
+}
+
+impl SeekGen for PatternGen {
+    fn seek_to(&mut self, pos: u64) -> bool
     {
-        use super::*;
+        self.pos = (pos % self.pattern.len() as u64) as usize;
+        true
+    }
+}
 
-        let mut f = std::io::Cursor::new(vec![0xffu8; 4096 * 10]);
-        let len = f.seek(SeekFrom::End(0)).unwrap();
+impl SeekGen for AesCtrGen {
+    fn seek_to(&mut self, pos: u64) -> bool
+    {
+        self.0.seek(pos);
+        true
+    }
+}
 
-        let mut map = UsageMap::new(len);
+impl SeekGen for ChaCha20Rng {
+    fn seek_to(&mut self, pos: u64) -> bool
+    {
+        // The output is consumed in whole 32-bit words.
+        self.set_word_pos((pos / 4) as u128);
+        true
+    }
+}
 
-        map.update(2, 79, AllocStatus::Used);
-        map.update(201, 335, AllocStatus::Used);
-        map.update(700, 1000, AllocStatus::Used);
-        map.update(5000, 7028, AllocStatus::Used);
-        map.update(20000, 2, AllocStatus::Used);
-        map.update(20229, 33, AllocStatus::Used);
+impl SeekGen for Hc128Rng {}
 
-        super::fill_free_space_with(&mut ZeroGen::new(), &map, &mut f).unwrap();
+impl SeekGen for Xoshiro256PlusPlus {}
 
-        for seg in map.0.iter().filter(|s| { s.status == AllocStatus::Free }) {
-            for b in &f.get_ref()[seg.start as usize..seg.end as usize] {
-                assert_eq!(*b, 0u8);
+/// Reads the pattern file, which must not be empty.
+fn read_pattern(path: &Path) -> anyhow::Result<Vec<u8>>
+{
+    let pattern = std::fs::read(path)
+        .map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))?;
+
+    if pattern.is_empty() {
+        bail!("{}: the pattern file is empty", path.display());
+    }
+
+    Ok(pattern)
+}
+
+/// The statistics of a fill. The throughputs are in bytes per second.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct FillStats {
+    /// The bytes written, including those discarded or zeroed in the kernel, over all the passes.
+    pub bytes_written: u64,
+    pub segments_filled: u64,
+    /// The duration of the fill, in seconds.
+    pub duration: f64,
+    pub avg_throughput: f64,
+    /// The lowest throughput over a STATS_WINDOW; the average, if the fill was shorter.
+    pub min_throughput: f64,
+    /// The highest throughput over a STATS_WINDOW; the average, if the fill was shorter.
+    pub max_throughput: f64,
+    /// The errors that the fill recovered from, e.g. failed discards, replaced with writes.
+    pub write_errors: u64,
+    /// The start of the current window and the bytes written in it.
+    #[serde(skip)]
+    window: Option<(Instant, u64)>,
+    #[serde(skip)]
+    windows: u64,
+}
+
+impl FillStats {
+    /// Returns empty statistics, with the first window starting now.
+    fn start() -> Self
+    {
+        Self { window: Some((Instant::now(), 0)), ..Self::default() }
+    }
+
+    /// Counts the bytes as written, closing the current window if it has lasted STATS_WINDOW.
+    fn add(&mut self, bytes: u64)
+    {
+        self.bytes_written += bytes;
+
+        let now = Instant::now();
+        let (start, written) = self.window.get_or_insert((now, 0));
+        *written += bytes;
+
+        let elapsed = now - *start;
+        if elapsed >= STATS_WINDOW {
+            let rate = *written as f64 / elapsed.as_secs_f64();
+            self.window = Some((now, 0));
+
+            if self.windows == 0 || rate < self.min_throughput {
+                self.min_throughput = rate;
+            }
+            if rate > self.max_throughput {
+                self.max_throughput = rate;
             }
+            self.windows += 1;
         }
+    }
 
-        for seg in map.0.iter().filter(|s| { s.status == AllocStatus::Used }) {
-            for b in &f.get_ref()[seg.start as usize..seg.end as usize] {
-                assert_eq!(*b, 0xffu8);
+    /// Computes the average throughput over the whole fill.
+    fn finish(&mut self, duration: Duration)
+    {
+        self.duration = duration.as_secs_f64();
+        self.avg_throughput = self.bytes_written as f64 / self.duration.max(f64::EPSILON);
+
+        if self.windows == 0 {
+            self.min_throughput = self.avg_throughput;
+            self.max_throughput = self.avg_throughput;
+        }
+    }
+}
+
+/// A writer that counts the bytes written into the fill statistics and the progress.
+struct Metered<'a, W> {
+    inner: &'a mut W,
+    stats: &'a mut FillStats,
+    progress: &'a mut Progress,
+}
+
+impl<'a, W> Metered<'a, W> {
+    fn new(inner: &'a mut W, stats: &'a mut FillStats, progress: &'a mut Progress) -> Self
+    {
+        Self { inner, stats, progress }
+    }
+}
+
+impl<W: Write> Write for Metered<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize>
+    {
+        let n = self.inner.write(buf)?;
+        self.stats.add(n as u64);
+        self.progress.update(self.stats.bytes_written);
+
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()>
+    {
+        self.inner.flush()
+    }
+}
+
+impl<W: Seek> Seek for Metered<'_, W> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64>
+    {
+        self.inner.seek(pos)
+    }
+}
+
+/// A pass of the fill pipeline, given by `--passes`.
+#[derive(Copy, Clone, Debug)]
+pub struct FillPass {
+    pub mode: FillMode,
+    /// The pass is read back and compared after it is written, even without `--verify`.
+    pub verify: bool,
+}
+
+/// Parses a pass of the pipeline: a fill mode, optionally followed by `:verify`.
+pub fn parse_pass(s: &str) -> Result<FillPass, String>
+{
+    let (name, verify) = match s.split_once(':') {
+        Some((name, "verify")) => (name, true),
+        Some((_, opt)) => return Err(format!("unknown pass option: {}", opt)),
+        None => (s, false),
+    };
+
+    Ok(FillPass { mode: FillMode::from_str(name, false)?, verify })
+}
+
+/// Returns the seed of a pass of the pipeline: the seed of the fill, with the pass number XORed
+/// into its first 8 bytes, so that the first pass uses the seed as given.
+fn pass_seed(mut seed: [u8; 32], pass: usize) -> [u8; 32]
+{
+    for (b, n) in seed.iter_mut().zip((pass as u64).to_le_bytes()) {
+        *b ^= n;
+    }
+
+    seed
+}
+
+/// Fills all the free space on the drive.
+/// Only the free segments selected by the configuration are filled. The fill runs the passes of
+/// the pipeline in order, or a single pass of the fill mode, if no pipeline is configured.
+/// Returns the statistics of the fill.
+pub fn fill_free_space(map: &UsageMap, ctx: &mut Context, cfg: &Config)
+    -> anyhow::Result<FillStats>
+{
+    let start = Instant::now();
+    let segments = select_segments(map, cfg);
+    ctx.fill_stats = FillStats { segments_filled: segments.len() as u64, ..FillStats::start() };
+    let passes = match cfg.passes.is_empty() {
+        true => vec![FillPass { mode: cfg.fill_mode, verify: false }],
+        false => cfg.passes.clone(),
+    };
+
+    if let Some(n) = cfg.fill_top {
+        ctx.logger.logln(1, &format!("filling the {} largest free segments", segments.len().min(n)));
+    }
+    if !cfg.ranges.is_empty() || cfg.max_fill_bytes.is_some() || cfg.fill_percent.is_some() {
+        let total: u64 = segments.iter().map(|s| s.end - s.start).sum();
+        ctx.logger.logln(0, &format!("partial fill: {} bytes of the free space", total));
+    }
+
+    let pass_count = passes.len() + cfg.final_zero as usize;
+    let (seed, resume) = match &cfg.resume {
+        Some(path) => {
+            let checkpoint = fill_checkpoint::load(path, map.size())?;
+            if checkpoint.pass_count != pass_count as u64 || checkpoint.pass >= pass_count as u64 {
+                bail!("the checkpoint does not match the passes of the fill");
+            }
+            if cfg.seed.is_some_and(|seed| seed != checkpoint.seed) {
+                bail!("the checkpoint was saved with a different seed");
+            }
+
+            // Only the segments that are still free are filled, if the map has changed since.
+            let ranges: Vec<Range<u64>> = checkpoint.remaining.iter()
+                .map(|s| s.start..s.end)
+                .collect();
+            let resume = Resume {
+                pass: checkpoint.pass as usize,
+                segments: intersect_ranges(segments.clone(), &ranges),
+            };
+            ctx.logger.logln(0, &format!(
+                "resuming the fill in pass {} of {}, with {} segments left",
+                resume.pass + 1,
+                pass_count,
+                resume.segments.len(),
+            ));
+
+            (checkpoint.seed, resume)
+        },
+        None => {
+            let seed = cfg.seed.unwrap_or_else(|| rand::thread_rng().gen());
+            (seed, Resume { pass: 0, segments: segments.clone() })
+        },
+    };
+
+    // The passes of the multi-pass modes each write all the segments.
+    let bytes = |segments: &[Segment]| segments.iter().map(|s| s.end - s.start).sum::<u64>();
+    let writes = |mode| schedule(mode).len().max(1) as u64;
+    let mut total = passes.iter()
+        .enumerate()
+        .skip(resume.pass + 1)
+        .map(|(_, p)| writes(p.mode) * bytes(&segments))
+        .sum::<u64>();
+    if let Some(pass) = passes.get(resume.pass) {
+        total += writes(pass.mode) * bytes(&resume.segments);
+    }
+    if cfg.final_zero && passes.iter().any(|p| p.mode.is_random()) {
+        total += match resume.pass == passes.len() {
+            true => bytes(&resume.segments),
+            false => bytes(&segments),
+        };
+    }
+    ctx.progress.start("fill", total);
+
+    let writes_zero = passes.iter().any(|p| matches!(p.mode, FillMode::Zero));
+    if cfg.sparsify && !writes_zero && !cfg.final_zero {
+        ctx.logger.logln(0, "warning: the fill does not write zeroes: nothing is sparsified");
+    }
+    if cfg.skip_zeroed && !writes_zero {
+        ctx.logger.logln(0, "warning: the fill mode is not zero: no free space is skipped");
+    }
+
+    ctx.direct_drive = None;
+    if cfg.direct_io && (cfg.threads > 1 || cfg.io_engine != IoEngine::Sync) {
+        ctx.logger.logln(0, "direct I/O is only used with a single thread and the sync I/O engine");
+    } else if cfg.direct_io {
+        match ctx.drive.open_direct() {
+            Ok(drive) => ctx.direct_drive = Some(drive),
+            Err(e) => ctx.logger.logln(0, &format!(
+                "warning: cannot open the drive for direct I/O: {}: writing through the page cache",
+                e,
+            )),
+        }
+    }
+
+    let mut current = resume.pass;
+    let result = run_pipeline(&passes, seed, &segments, &resume, &mut current, ctx, cfg);
+    ctx.direct_drive = None;
+    if let Err(e) = result {
+        let next = match e.downcast_ref::<Interrupted>() {
+            Some(interrupted) => interrupted.next,
+            None => return Err(e),
+        };
+
+        // Stop cleanly, with everything written so far on the drive.
+        ctx.drive.sync_data()?;
+
+        let path = checkpoint_path(cfg);
+        let pass_segments = match current == resume.pass {
+            true => &resume.segments,
+            false => &segments,
+        };
+        let checkpoint = Checkpoint {
+            seed,
+            pass_count: pass_count as u64,
+            pass: current as u64,
+            remaining: fill_checkpoint::remaining_segments(pass_segments, next),
+        };
+        fill_checkpoint::save(path, &checkpoint, map.size())?;
+        ctx.logger.logln(0, &format!(
+            "interrupted in pass {}: the progress is saved in {}",
+            current + 1,
+            path.display(),
+        ));
+
+        bail!("the fill was interrupted");
+    }
+
+    // Syncing a block device also flushes its volatile write cache.
+    if cfg.barrier {
+        ctx.logger.logln(1, "flushing the drive");
+        ctx.drive.sync_all()?;
+    }
+
+    if let Some(path) = &cfg.resume {
+        if let Err(e) = std::fs::remove_file(path) {
+            ctx.logger.logln(0, &format!("warning: cannot remove {}: {}", path.display(), e));
+        }
+    }
+
+    ctx.progress.finish();
+    let mut stats = std::mem::take(&mut ctx.fill_stats);
+    stats.finish(start.elapsed());
+
+    Ok(stats)
+}
+
+/// The point from which the fill starts: the pass, and the segments left to fill in it.
+struct Resume {
+    pass: usize,
+    segments: Vec<Segment>,
+}
+
+/// Returns the file into which the progress of an interrupted fill is saved: the configured one,
+/// the one the fill was resumed from, or DEFAULT_CHECKPOINT.
+fn checkpoint_path(cfg: &Config) -> &Path
+{
+    cfg.fill_checkpoint.as_deref()
+        .or(cfg.resume.as_deref())
+        .unwrap_or(Path::new(DEFAULT_CHECKPOINT))
+}
+
+/// Runs the passes of the fill, followed by the final zero pass, if configured, keeping the index
+/// of the current pass in `current`. The passes before the resumed one are skipped.
+/// Each pass is synced to the drive before the next one starts, so that it is not merged with the
+/// next one in the drive's cache.
+fn run_pipeline(
+    passes: &[FillPass],
+    seed: [u8; 32],
+    segments: &[Segment],
+    resume: &Resume,
+    current: &mut usize,
+    ctx: &mut Context,
+    cfg: &Config,
+) -> anyhow::Result<()>
+{
+    let pass_segments = |i| match i == resume.pass {
+        true => &resume.segments[..],
+        false => segments,
+    };
+
+    for (i, pass) in passes.iter().enumerate().skip(resume.pass) {
+        *current = i;
+        let segments = pass_segments(i);
+        let pass_cfg = Config {
+            fill_mode: pass.mode,
+            verify: cfg.verify || pass.verify,
+            ..cfg.clone()
+        };
+
+        if passes.len() == 1 {
+            fill_pass(segments, seed, ctx, &pass_cfg)?;
+            continue;
+        }
+
+        ctx.logger.logln(0, &format!("=== pass {}/{}: {}", i + 1, passes.len(), pass));
+        let start = Instant::now();
+
+        fill_pass(segments, pass_seed(seed, i), ctx, &pass_cfg).map_err(|e| {
+            match e.is::<Interrupted>() {
+                true => e,
+                false => anyhow::anyhow!("pass {}: {}", i + 1, e),
+            }
+        })?;
+        ctx.drive.sync_data()?;
+
+        let elapsed = format_duration(start.elapsed());
+        ctx.logger.logln(0, &format!("pass {}/{}: done in {}", i + 1, passes.len(), elapsed));
+    }
+
+    // The random data must reach the drive before it is zeroed, or the zeroes could replace it in
+    // the cache.
+    if cfg.final_zero {
+        *current = passes.len();
+        let segments = pass_segments(passes.len());
+
+        if passes.iter().any(|p| p.mode.is_random()) {
+            ctx.drive.sync_data()?;
+            ctx.logger.logln(0, "final pass: zero");
+            run_zero(segments, ctx, cfg)?;
+        } else {
+            ctx.logger.logln(0, "the fill is not random: skipping the final zero pass");
+        }
+    }
+
+    Ok(())
+}
+
+/// Fills the segments once with the fill mode of the configuration.
+fn fill_pass(segments: &[Segment], seed: [u8; 32], ctx: &mut Context, cfg: &Config)
+    -> anyhow::Result<()>
+{
+    match cfg.fill_mode {
+        FillMode::Zero if cfg.skip_zeroed => {
+            let dirty = dirty_segments(segments, &mut ctx.drive)?;
+            let total: u64 = segments.iter().map(|s| s.end - s.start).sum();
+            let dirty_total: u64 = dirty.iter().map(|s| s.end - s.start).sum();
+            ctx.logger.logln(0, &format!(
+                "skipping the zeroed free space: {} of {} bytes are already zero",
+                total - dirty_total,
+                total,
+            ));
+            run_zero(&dirty, ctx, cfg)
+        },
+        FillMode::Zero => run_zero(segments, ctx, cfg),
+        FillMode::ChaCha20 => {
+            let new_gen = |i| ChaCha20Rng::from_seed(stream_seed(seed, i));
+            fill_and_verify(new_gen, segments, false, ctx, cfg)
+        },
+        FillMode::Hc128 => {
+            let new_gen = |i| Hc128Rng::from_seed(stream_seed(seed, i));
+            fill_and_verify(new_gen, segments, false, ctx, cfg)
+        },
+        FillMode::AesCtr => {
+            let new_gen = |i| AesCtrGen::new(stream_seed(seed, i));
+            fill_and_verify(new_gen, segments, false, ctx, cfg)
+        },
+        FillMode::Xoshiro => {
+            let new_gen = |i| Xoshiro256PlusPlus::from_seed(stream_seed(seed, i));
+            fill_and_verify(new_gen, segments, false, ctx, cfg)
+        },
+        FillMode::OsRandom => {
+            if cfg.verify || cfg.spot_check.is_some() {
+                ctx.logger.logln(0, "the random data of the operating system cannot be verified");
+            }
+            run_fill(&|_| OsRandomGen::new(), segments, false, ctx, cfg)
+        },
+        FillMode::Pattern => match &cfg.pattern_file {
+            Some(path) => {
+                let pattern = read_pattern(path)?;
+                ctx.logger.logln(1, &format!("pattern file: {} ({} bytes)", path.display(), pattern.len()));
+                fill_and_verify(|_| PatternGen::new(pattern.clone()), segments, false, ctx, cfg)
+            },
+            None => {
+                let b = cfg.pattern_byte;
+                ctx.logger.logln(1, &format!("pattern byte: {:#04x}", b));
+                fill_and_verify(|_| ByteGen(b), segments, b == 0 && check_zero(cfg), ctx, cfg)
+            },
+        },
+        FillMode::Dod3 | FillMode::Dod7 => {
+            run_passes(&schedule(cfg.fill_mode), seed, segments, ctx, cfg)
+        },
+        FillMode::Discard => {
+            if cfg.verify || cfg.spot_check.is_some() {
+                ctx.logger.logln(0, "the discarded space is not verified");
             }
+            run_clear(Clear::Discard, segments, ctx, cfg)
+        },
+    }
+}
+
+/// Parses a byte, in decimal or in hexadecimal with the `0x` prefix.
+pub fn parse_byte(s: &str) -> Result<u8, std::num::ParseIntError>
+{
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u8::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+}
+
+/// Checks whether the buffer of a zero fill is to be verified: in debug builds and in the paranoid
+/// mode.
+fn check_zero(cfg: &Config) -> bool
+{
+    cfg.paranoid || cfg!(debug_assertions)
+}
+
+/// Fills the segments, using a supplied byte generator.
+/// When probing, the throughput of the first PROBE_SIZE bytes is measured and the duration of the
+/// whole fill is estimated. The probe is a part of the fill.
+fn run_fill<R, F>(
+    new_gen: &F,
+    segments: &[Segment],
+    expect_zero: bool,
+    ctx: &mut Context,
+    cfg: &Config,
+) -> anyhow::Result<()>
+where
+    R: RngCore,
+    F: Fn(u64) -> R + Sync,
+{
+    if cfg.threads > 1 {
+        if cfg.probe {
+            ctx.logger.logln(0, "the probe is not supported with multiple threads: skipping it");
+        }
+        if cfg.io_engine != IoEngine::Sync {
+            ctx.logger.logln(0, &format!(
+                "the {} I/O engine is not used with multiple threads: using positioned writes",
+                cfg.io_engine,
+            ));
         }
+        return run_parallel(new_gen, segments, expect_zero, ctx, cfg);
+    }
+
+    let gen = &mut new_gen(0);
+
+    if !cfg.probe {
+        return write_segments(gen, segments, expect_zero, ctx, cfg);
+    }
+
+    let total: u64 = segments.iter().map(|s| s.size() as u64).sum();
+    let (probe, rest) = split_segments(segments, PROBE_SIZE);
+    let probe_size: u64 = probe.iter().map(|s| s.size() as u64).sum();
+
+    // Measure the throughput, including the time it takes to get the data onto the drive.
+
+    let start = Instant::now();
+    write_segments(gen, &probe, expect_zero, ctx, cfg)?;
+    ctx.drive.sync_data()?;
+    let elapsed = start.elapsed();
+
+    let rate = probe_size as f64 / elapsed.as_secs_f64().max(f64::EPSILON);
+    ctx.logger.logln(0, &format!(
+        "probe: wrote {} bytes in {:.2}s ({:.2} MiB/s); estimated fill duration: {}",
+        probe_size,
+        elapsed.as_secs_f64(),
+        rate / (1024.0 * 1024.0),
+        format_duration(estimate_duration(total, probe_size, elapsed)),
+    ));
+
+    write_segments(gen, &rest, expect_zero, ctx, cfg)
+}
+
+/// Fills the segments with the generator, through the configured I/O engine, counting the bytes
+/// written into the statistics and the progress.
+fn write_segments<R: RngCore>(
+    gen: &mut R,
+    segments: &[Segment],
+    expect_zero: bool,
+    ctx: &mut Context,
+    cfg: &Config,
+) -> anyhow::Result<()>
+{
+    match cfg.io_engine {
+        IoEngine::Sync if ctx.direct_drive.is_some() => {
+            let direct = ctx.direct_drive.as_ref().unwrap();
+            let mut writer = DirectWriter::new(&ctx.drive, direct);
+            let mut drive = Metered::new(&mut writer, &mut ctx.fill_stats, &mut ctx.progress);
+            // The gathered run is written, even if the fill is interrupted.
+            let result = fill_free_space_with(gen, segments, &mut drive, expect_zero);
+            let flushed = drive.flush();
+            result?;
+            Ok(flushed?)
+        },
+        IoEngine::Sync => {
+            let mut drive = Metered::new(&mut ctx.drive, &mut ctx.fill_stats, &mut ctx.progress);
+            fill_free_space_with(gen, segments, &mut drive, expect_zero)
+        },
+        #[cfg(all(target_os = "linux", feature = "io-uring"))]
+        IoEngine::IoUring => {
+            let mut uring = crate::io_engine::UringWriter::new(&ctx.drive)?;
+            let mut drive = Metered::new(&mut uring, &mut ctx.fill_stats, &mut ctx.progress);
+            // The writes in flight are waited for, even if the fill fails.
+            let result = fill_free_space_with(gen, segments, &mut drive, expect_zero);
+            let flushed = drive.flush();
+            result?;
+            Ok(flushed?)
+        },
+        #[cfg(not(all(target_os = "linux", feature = "io-uring")))]
+        IoEngine::IoUring => unreachable!("the I/O engine is checked at the start"),
+    }
+}
+
+/// The operations that clear ranges of the drive without writing the data.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Clear {
+    /// `Drive::discard`.
+    Discard,
+    /// `Drive::zero_out`.
+    ZeroOut,
+    /// `Drive::punch_hole`.
+    PunchHole,
+}
+
+impl Clear {
+    fn apply(self, drive: &Drive, offset: u64, len: u64) -> std::io::Result<()>
+    {
+        match self {
+            Self::Discard => drive.discard(offset, len),
+            Self::ZeroOut => drive.zero_out(offset, len),
+            Self::PunchHole => drive.punch_hole(offset, len),
+        }
+    }
+}
+
+/// Clears the segments with the operation, in chunks of up to CLEAR_CHUNK bytes. The parts of the
+/// segments that are not aligned to CLEAR_ALIGN are zeroed with writes instead, as is everything
+/// from the first chunk that the operation fails on.
+fn run_clear(clear: Clear, segments: &[Segment], ctx: &mut Context, cfg: &Config)
+    -> anyhow::Result<()>
+{
+    let offset = ctx.drive.offset();
+    let mut zeroed: Vec<Segment> = Vec::new();
+    let mut cleared = 0;
+
+    'segments: for (i, seg) in segments.iter().enumerate() {
+        let start = (offset + seg.start).next_multiple_of(CLEAR_ALIGN) - offset;
+        let end = ((offset + seg.end) / CLEAR_ALIGN * CLEAR_ALIGN).saturating_sub(offset);
+
+        if start >= end {
+            zeroed.push(*seg);
+            continue;
+        }
+
+        if seg.start < start {
+            zeroed.push(Segment { end: start, ..*seg });
+        }
+
+        let mut pos = start;
+        while pos < end {
+            // The parts to zero with writes are not written yet, so the fill stops before the first
+            // of them.
+            if interrupt::requested() {
+                let next = zeroed.first().map_or(pos, |s| std::cmp::min(s.start, pos));
+                return Err(Interrupted { next }.into());
+            }
+
+            let len = std::cmp::min(end - pos, CLEAR_CHUNK);
+            if let Err(e) = clear.apply(&ctx.drive, pos, len) {
+                ctx.logger.logln(0, &format!("{} failed ({}): writing zeroes instead", clear, e));
+                ctx.fill_stats.write_errors += 1;
+                zeroed.push(Segment { start: pos, ..*seg });
+                zeroed.extend_from_slice(&segments[i + 1..]);
+                break 'segments;
+            }
+            ctx.fill_stats.add(len);
+            ctx.progress.update(ctx.fill_stats.bytes_written);
+            cleared += len;
+            pos += len;
+        }
+
+        if end < seg.end {
+            zeroed.push(Segment { start: end, ..*seg });
+        }
+    }
+
+    ctx.logger.logln(1, &format!("{}: {} bytes", clear, cleared));
+
+    run_fill(&|_| ZeroGen::new(), &zeroed, check_zero(cfg), ctx, cfg)
+}
+
+/// Reads the segments and returns the parts of them that are not all zeroes, in blocks of
+/// SKIP_BLOCK_SIZE bytes from the start of each segment.
+fn dirty_segments<D: Read + Seek>(segments: &[Segment], drive: &mut D)
+    -> anyhow::Result<Vec<Segment>>
+{
+    let mut buf = vec![0; SKIP_BLOCK_SIZE];
+    let mut dirty: Vec<Segment> = Vec::new();
+
+    for segment in segments {
+        // Nothing is written yet, so the whole pass is left to do.
+        if interrupt::requested() {
+            return Err(Interrupted { next: segments[0].start }.into());
+        }
+
+        drive.seek(SeekFrom::Start(segment.start))?;
+
+        let mut pos = segment.start;
+        while pos < segment.end {
+            let len = std::cmp::min(segment.end - pos, SKIP_BLOCK_SIZE as u64) as usize;
+            drive.read_exact(&mut buf[..len])?;
+
+            if buf[..len].iter().any(|b| *b != 0) {
+                match dirty.last_mut() {
+                    Some(last) if last.end == pos => last.end = pos + len as u64,
+                    _ => dirty.push(Segment { start: pos, end: pos + len as u64, ..*segment }),
+                }
+            }
+
+            pos += len as u64;
+        }
+    }
+
+    Ok(dirty)
+}
+
+/// Zeroes the segments, in the kernel if the drive is a block device, or by punching holes into an
+/// image file when sparsifying.
+fn run_zero(segments: &[Segment], ctx: &mut Context, cfg: &Config) -> anyhow::Result<()>
+{
+    let clear = if ctx.drive.is_block_device() {
+        Clear::ZeroOut
+    } else if cfg.sparsify {
+        Clear::PunchHole
+    } else {
+        return fill_and_verify(|_| ZeroGen::new(), segments, check_zero(cfg), ctx, cfg);
+    };
+
+    run_clear(clear, segments, ctx, cfg)?;
+    verify_fill(|_| ZeroGen::new(), segments, ctx, cfg)
+}
+
+/// Fills the segments once per pass. Each pass is synced to the drive before the next one starts,
+/// so that it is not merged with the next one in the drive's cache. The random passes use separate
+/// ChaCha20 streams of the same seed.
+fn run_passes(
+    passes: &[Pass],
+    seed: [u8; 32],
+    segments: &[Segment],
+    ctx: &mut Context,
+    cfg: &Config,
+) -> anyhow::Result<()>
+{
+    for (i, pass) in passes.iter().enumerate() {
+        ctx.logger.logln(0, &format!("pass {}/{}: {}", i + 1, passes.len(), pass.source));
+
+        let pass_cfg = Config { verify: cfg.verify || pass.verify, ..cfg.clone() };
+
+        match pass.source {
+            PassSource::Byte(b) => {
+                let expect_zero = b == 0 && check_zero(cfg);
+                fill_and_verify(|_| ByteGen(b), segments, expect_zero, ctx, &pass_cfg)
+            },
+            PassSource::Random => {
+                let new_gen = |stream| {
+                    let mut gen = ChaCha20Rng::from_seed(stream_seed(seed, stream));
+                    gen.set_stream(i as u64);
+                    gen
+                };
+                fill_and_verify(new_gen, segments, false, ctx, &pass_cfg)
+            },
+        }.map_err(|e| match e.downcast_ref::<Interrupted>() {
+            // The segments done in the later passes have missed none of the passes only if no
+            // other pass has started.
+            Some(_) if i > 0 => Interrupted { next: 0 }.into(),
+            Some(_) => e,
+            None => anyhow::anyhow!("pass {}: {}", i + 1, e),
+        })?;
+        ctx.drive.sync_data()?;
+    }
+
+    Ok(())
+}
+
+/// Fills the segments with the generators of `new_gen` and, if configured to, reads them back and
+/// compares them against new generators with the same output.
+/// `new_gen` returns the generator of a stream: the segments are split into one part per thread,
+/// each filled with its own stream.
+fn fill_and_verify<R, F>(
+    new_gen: F,
+    segments: &[Segment],
+    expect_zero: bool,
+    ctx: &mut Context,
+    cfg: &Config,
+) -> anyhow::Result<()>
+where
+    R: SeekGen,
+    F: Fn(u64) -> R + Sync,
+{
+    run_fill(&new_gen, segments, expect_zero, ctx, cfg)?;
+    verify_fill(new_gen, segments, ctx, cfg)
+}
+
+/// Reads the segments back and compares them against the generators, if configured to.
+fn verify_fill<R, F>(new_gen: F, segments: &[Segment], ctx: &mut Context, cfg: &Config)
+    -> anyhow::Result<()>
+where
+    R: SeekGen,
+    F: Fn(u64) -> R,
+{
+    let chunks = thread_chunks(segments, cfg.threads);
+
+    if cfg.verify {
+        drop_cached(segments, ctx)?;
+        ctx.logger.logln(1, "verifying the fill");
+        for (i, chunk) in chunks.iter().enumerate() {
+            verify_free_space_with(&mut new_gen(i as u64), chunk, &mut ctx.drive)?;
+        }
+    } else if let Some(n) = cfg.spot_check {
+        drop_cached(segments, ctx)?;
+        let sampler = &mut rand::thread_rng();
+        let mut checked = 0;
+        for (i, chunk) in chunks.iter().enumerate() {
+            let gen = &mut new_gen(i as u64);
+            checked += spot_check_with(gen, chunk, &mut ctx.drive, n, sampler)?;
+        }
+        ctx.logger.logln(1, &format!("spot check: {} samples verified", checked));
+    }
+
+    Ok(())
+}
+
+/// Syncs the drive and drops the segments from the page cache, so that they are read back from the
+/// device rather than from the buffers of the fill.
+fn drop_cached(segments: &[Segment], ctx: &mut Context) -> anyhow::Result<()>
+{
+    ctx.drive.sync_data()?;
+
+    // One range spanning all the segments, as the selected segments need not be in order.
+    let start = segments.iter().map(|s| s.start).min().unwrap_or(0);
+    let end = segments.iter().map(|s| s.end).max().unwrap_or(0);
+    if start < end {
+        if let Err(e) = ctx.drive.drop_cache(start, end - start) {
+            ctx.logger.logln(0, &format!(
+                "warning: cannot drop the fill from the page cache: {}: it may be read back from it",
+                e,
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns the seed of a stream: the seed, with the stream number XORed into its bytes 8 to 15, so
+/// that the first stream uses the seed as given.
+fn stream_seed(mut seed: [u8; 32], stream: u64) -> [u8; 32]
+{
+    for (b, n) in seed[8..16].iter_mut().zip(stream.to_le_bytes()) {
+        *b ^= n;
+    }
+
+    seed
+}
+
+/// Splits the segments into `threads` parts of about the same size, in the order of their
+/// addresses. The parts are split at multiples of 4096 bytes of the free space; the last part takes
+/// the rest.
+fn thread_chunks(segments: &[Segment], threads: usize) -> Vec<Vec<Segment>>
+{
+    let total: u64 = segments.iter().map(|s| s.end - s.start).sum();
+    let chunk_size = std::cmp::max(total / threads as u64 / 4096, 1) * 4096;
+
+    let mut chunks = Vec::new();
+    let mut rest = segments.to_vec();
+    for _ in 1..threads {
+        let (chunk, tail) = split_segments(&rest, chunk_size);
+        chunks.push(chunk);
+        rest = tail;
+    }
+    chunks.push(rest);
+
+    chunks
+}
+
+/// A writer of the drive with direct I/O. Consecutive writes are gathered into a buffer that is
+/// aligned in memory like its position in the drive, so that its aligned blocks are written
+/// directly; the unaligned starts and ends of the runs are written through the page cache.
+/// The buffer is written when it is full, at a seek elsewhere and at a flush.
+struct DirectWriter<'a> {
+    /// The drive, for the unaligned writes.
+    drive: &'a Drive,
+    /// The drive opened for direct I/O.
+    direct: &'a Drive,
+    buf: Vec<u8>,
+    /// The start of the buffer in `buf`, aligned to DIRECT_ALIGN in memory.
+    buf_start: usize,
+    /// The offset of the start of the buffer in the underlying file, aligned to DIRECT_ALIGN.
+    base: u64,
+    /// The range of the buffer gathered.
+    head: usize,
+    end: usize,
+}
+
+impl<'a> DirectWriter<'a> {
+    fn new(drive: &'a Drive, direct: &'a Drive) -> Self
+    {
+        let buf = vec![0; DIRECT_BUF_SIZE + DIRECT_ALIGN as usize];
+        let buf_start = buf.as_ptr().align_offset(DIRECT_ALIGN as usize);
+
+        let mut writer = Self { drive, direct, buf, buf_start, base: 0, head: 0, end: 0 };
+        writer.start_at(drive.offset());
+
+        writer
+    }
+
+    /// Starts a new run at an offset in the underlying file.
+    fn start_at(&mut self, offset: u64)
+    {
+        let phase = offset % DIRECT_ALIGN;
+        self.base = offset - phase;
+        self.head = phase as usize;
+        self.end = phase as usize;
+    }
+
+    /// Writes the gathered run: the aligned blocks directly, the rest through the page cache.
+    fn write_run(&mut self) -> std::io::Result<()>
+    {
+        let align = DIRECT_ALIGN as usize;
+        let mid_start = std::cmp::min(self.head.next_multiple_of(align), self.end);
+        let mid_end = std::cmp::max(self.end / align * align, mid_start);
+        let buf = &self.buf[self.buf_start..];
+        let pos = |i: usize| self.base + i as u64 - self.drive.offset();
+
+        for (start, end) in [(self.head, mid_start), (mid_end, self.end)] {
+            if start < end {
+                self.drive.write_all_at(&buf[start..end], pos(start))?;
+            }
+        }
+        if mid_start < mid_end {
+            self.direct.write_all_at(&buf[mid_start..mid_end], pos(mid_start))?;
+        }
+
+        self.head = self.end;
+
+        Ok(())
+    }
+}
+
+impl Write for DirectWriter<'_> {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize>
+    {
+        if self.end == DIRECT_BUF_SIZE {
+            self.write_run()?;
+            self.start_at(self.base + DIRECT_BUF_SIZE as u64);
+        }
+
+        let n = std::cmp::min(data.len(), DIRECT_BUF_SIZE - self.end);
+        let start = self.buf_start + self.end;
+        self.buf[start..start + n].copy_from_slice(&data[..n]);
+        self.end += n;
+
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()>
+    {
+        self.write_run()
+    }
+}
+
+impl Seek for DirectWriter<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64>
+    {
+        let n = match pos {
+            SeekFrom::Start(n) => n,
+            _ => return Err(std::io::ErrorKind::Unsupported.into()),
+        };
+
+        let offset = self.drive.offset() + n;
+        if offset != self.base + self.end as u64 {
+            self.write_run()?;
+            self.start_at(offset);
+        }
+
+        Ok(n)
+    }
+}
+
+/// A writer at its own position in the drive, with positioned writes, so that several of them can
+/// write to the drive at once. The bytes written are counted in `written`.
+struct PosWriter<'a> {
+    drive: &'a Drive,
+    pos: u64,
+    written: &'a AtomicU64,
+}
+
+impl Write for PosWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize>
+    {
+        self.drive.write_all_at(buf, self.pos)?;
+        self.pos += buf.len() as u64;
+        self.written.fetch_add(buf.len() as u64, Ordering::Relaxed);
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()>
+    {
+        Ok(())
+    }
+}
+
+impl Seek for PosWriter<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64>
+    {
+        match pos {
+            SeekFrom::Start(n) => self.pos = n,
+            _ => return Err(std::io::ErrorKind::Unsupported.into()),
+        }
+
+        Ok(self.pos)
+    }
+}
+
+/// Fills the segments with one thread per part, as split by `thread_chunks`, each with the
+/// generator of its own stream. The statistics and the progress are updated while the threads run.
+/// If any thread is interrupted, the fill is interrupted before the lowest segment not filled.
+fn run_parallel<R, F>(
+    new_gen: &F,
+    segments: &[Segment],
+    expect_zero: bool,
+    ctx: &mut Context,
+    cfg: &Config,
+) -> anyhow::Result<()>
+where
+    R: RngCore,
+    F: Fn(u64) -> R + Sync,
+{
+    let chunks = thread_chunks(segments, cfg.threads);
+    let written = AtomicU64::new(0);
+    let drive = &ctx.drive;
+    let stats = &mut ctx.fill_stats;
+    let progress = &mut ctx.progress;
+
+    let results: Vec<anyhow::Result<()>> = std::thread::scope(|scope| {
+        let workers: Vec<_> = chunks.iter()
+            .enumerate()
+            .map(|(i, chunk)| {
+                let written = &written;
+                scope.spawn(move || {
+                    let mut w = PosWriter { drive, pos: 0, written };
+                    fill_free_space_with(&mut new_gen(i as u64), chunk, &mut w, expect_zero)
+                })
+            })
+            .collect();
+
+        let mut counted = 0;
+        loop {
+            let finished = workers.iter().all(|w| w.is_finished());
+
+            let now = written.load(Ordering::Relaxed);
+            stats.add(now - counted);
+            progress.update(stats.bytes_written);
+            counted = now;
+
+            if finished {
+                break;
+            }
+            std::thread::sleep(POLL_INTERVAL);
+        }
+
+        workers.into_iter().map(|w| w.join().unwrap()).collect()
+    });
+
+    let mut next = None;
+    for result in results {
+        if let Err(e) = result {
+            match e.downcast_ref::<Interrupted>() {
+                Some(interrupted) => {
+                    next = Some(next.map_or(interrupted.next, |n: u64| n.min(interrupted.next)));
+                },
+                None => return Err(e),
+            }
+        }
+    }
+
+    match next {
+        Some(next) => Err(Interrupted { next }.into()),
+        None => Ok(()),
+    }
+}
+
+/// Parses a seed of 64 hexadecimal digits.
+pub fn parse_seed(s: &str) -> Result<[u8; 32], String>
+{
+    let digits = s.strip_prefix("0x").unwrap_or(s);
+    if digits.len() != 64 || !digits.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(String::from("the seed must consist of 64 hexadecimal digits"));
+    }
+
+    let mut seed = [0; 32];
+    for (i, b) in seed.iter_mut().enumerate() {
+        *b = u8::from_str_radix(&digits[2 * i..2 * i + 2], 16).unwrap();
+    }
+
+    Ok(seed)
+}
+
+/// Splits the segments into the first `size` bytes and the rest.
+fn split_segments(segments: &[Segment], size: u64) -> (Vec<Segment>, Vec<Segment>)
+{
+    let mut head = Vec::new();
+    let mut tail = Vec::new();
+    let mut remaining = size;
+
+    for seg in segments {
+        let seg_size = seg.end - seg.start;
+
+        if remaining >= seg_size {
+            head.push(*seg);
+            remaining -= seg_size;
+        } else if remaining > 0 {
+            head.push(Segment { end: seg.start + remaining, ..*seg });
+            tail.push(Segment { start: seg.start + remaining, ..*seg });
+            remaining = 0;
+        } else {
+            tail.push(*seg);
+        }
+    }
+
+    (head, tail)
+}
+
+/// Estimates the duration of writing `total` bytes, given that `probe` bytes took `elapsed`.
+fn estimate_duration(total: u64, probe: u64, elapsed: Duration) -> Duration
+{
+    if probe == 0 {
+        return Duration::ZERO;
+    }
+
+    elapsed.mul_f64(total as f64 / probe as f64)
+}
+
+/// Formats a duration as hours, minutes and seconds.
+fn format_duration(d: Duration) -> String
+{
+    let secs = d.as_secs();
+
+    format!("{}h {:02}m {:02}s", secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+/// Selects the free segments of the map that are to be filled, in the order of their addresses.
+/// With ranges given, the free segments are cut down to those ranges first. The segments smaller
+/// than the minimum size are then skipped, before the others are selected.
+fn select_segments(map: &UsageMap, cfg: &Config) -> Vec<Segment>
+{
+    let segments: Vec<Segment> = intersect_ranges(map.iter_free().copied().collect(), &cfg.ranges)
+        .into_iter()
+        .filter(|s| s.end - s.start >= cfg.min_segment_size)
+        .collect();
+
+    let segments = match cfg.fill_top {
+        Some(n) => largest_segments(segments, n),
+        None => segments,
+    };
+
+    let total: u64 = segments.iter().map(|s| s.end - s.start).sum();
+    let budget = match (cfg.max_fill_bytes, cfg.fill_percent) {
+        (Some(max), _) => max,
+        (None, Some(percent)) => (total as u128 * percent as u128 / 100) as u64,
+        (None, None) => return segments,
+    };
+
+    budget_segments(segments, budget)
+}
+
+/// Keeps only the `n` largest segments, in the order of their addresses.
+/// Ties are broken in favour of lower addresses.
+fn largest_segments(mut segments: Vec<Segment>, n: usize) -> Vec<Segment>
+{
+    segments.sort_by(|a, b| b.size().cmp(&a.size()).then(a.start.cmp(&b.start)));
+    segments.truncate(n);
+    segments.sort_by_key(|s| s.start);
+
+    segments
+}
+
+/// Cuts the segments down to the parts within any of the ranges, in the order of their addresses.
+/// Without ranges, the segments are kept whole.
+fn intersect_ranges(segments: Vec<Segment>, ranges: &[Range<u64>]) -> Vec<Segment>
+{
+    if ranges.is_empty() {
+        return segments;
+    }
+
+    // Merge the overlapping ranges, so that no part of a segment is selected twice.
+    let mut ranges = ranges.to_vec();
+    ranges.sort_by_key(|r| r.start);
+    let mut merged: Vec<Range<u64>> = Vec::new();
+    for r in ranges {
+        match merged.last_mut() {
+            Some(last) if r.start <= last.end => last.end = std::cmp::max(last.end, r.end),
+            _ => merged.push(r),
+        }
+    }
+
+    let mut selected = Vec::new();
+    for seg in segments {
+        for r in &merged {
+            let start = std::cmp::max(seg.start, r.start);
+            let end = std::cmp::min(seg.end, r.end);
+
+            if start < end {
+                selected.push(Segment { start, end, ..seg });
+            }
+        }
+    }
+
+    selected
+}
+
+/// Parses a byte range, `START-END`, with the end exclusive. Both may have a size suffix.
+pub fn parse_range(s: &str) -> Result<Range<u64>, String>
+{
+    let (start, end) = s.split_once('-').ok_or_else(|| String::from("expected START-END"))?;
+    let range = parse_size(start)?..parse_size(end)?;
+
+    if range.start >= range.end {
+        return Err(String::from("the range is empty"));
+    }
+
+    Ok(range)
+}
+
+/// Keeps the largest segments that fit within `budget` bytes, in the order of their addresses.
+/// The last segment taken is cut short to fill the budget exactly.
+fn budget_segments(mut segments: Vec<Segment>, budget: u64) -> Vec<Segment>
+{
+    segments.sort_by(|a, b| b.size().cmp(&a.size()).then(a.start.cmp(&b.start)));
+
+    let mut remaining = budget;
+    let mut selected = Vec::new();
+    for seg in segments {
+        if remaining == 0 {
+            break;
+        }
+
+        let size = std::cmp::min(seg.end - seg.start, remaining);
+        selected.push(Segment { end: seg.start + size, ..seg });
+        remaining -= size;
+    }
+
+    selected.sort_by_key(|s| s.start);
+
+    selected
+}
+
+/// Parses a size in bytes, optionally with a binary suffix: K, M, G or T (e.g. `10G`).
+pub fn parse_size(s: &str) -> Result<u64, String>
+{
+    let (digits, shift) = match s.char_indices().last() {
+        Some((i, 'K' | 'k')) => (&s[..i], 10),
+        Some((i, 'M' | 'm')) => (&s[..i], 20),
+        Some((i, 'G' | 'g')) => (&s[..i], 30),
+        Some((i, 'T' | 't')) => (&s[..i], 40),
+        _ => (s, 0),
+    };
+
+    let n: u64 = digits.parse().map_err(|e| format!("{}", e))?;
+    n.checked_mul(1 << shift).ok_or_else(|| String::from("the size is too large"))
+}
+
+/// Parses a percentage, from 0 to 100.
+pub fn parse_percent(s: &str) -> Result<u8, String>
+{
+    match s.parse::<u8>() {
+        Ok(p) if p <= 100 => Ok(p),
+        Ok(_) => Err(String::from("the percentage is over 100")),
+        Err(e) => Err(format!("{}", e)),
+    }
+}
+
+/// Parses a number of fill threads, at least 1.
+pub fn parse_threads(s: &str) -> Result<usize, String>
+{
+    match s.parse::<usize>() {
+        Ok(0) => Err(String::from("at least 1 thread is needed")),
+        Ok(n) => Ok(n),
+        Err(e) => Err(format!("{}", e)),
+    }
+}
+
+/// Fills the free segments on the disk, using a supplied byte generator.
+/// With `expect_zero`, the buffer is checked to be all zeroes after each refill, before any of it
+/// is written.
+/// The free segments that are contiguous on the disk are written as one run, with writes of up to
+/// WRITE_SIZE bytes that cross the segment boundaries and a single seek.
+fn fill_free_space_with<'a, R, W, I>(
+    gen: &mut R,
+    segments: I,
+    drive: &mut W,
+    expect_zero: bool,
+) -> anyhow::Result<()>
+where
+    R: RngCore,
+    W: Write + Seek,
+    I: IntoIterator<Item = &'a Segment>,
+{
+    let mut runs: Vec<Range<u64>> = Vec::new();
+    for segment in segments.into_iter().filter(|s| s.status == AllocStatus::Free) {
+        match runs.last_mut() {
+            Some(run) if run.end == segment.start => run.end = segment.end,
+            _ => runs.push(segment.start..segment.end),
+        }
+    }
+
+    let mut buf = vec![0; WRITE_SIZE];
+    // Buffer head.
+    let mut head = 0;
+    refill(gen, &mut buf, expect_zero)?;
+
+    // Iterate through the runs and fill the corresponding drive addresses with the bytes from the
+    // buffer. The buffer is refilled with the byte generator when it is used up.
+
+    for run in runs {
+        drive.seek(SeekFrom::Start(run.start))?;
+
+        let mut written = 0;
+
+        while written < run.end - run.start {
+            // Long runs are interrupted between the writes.
+            if interrupt::requested() {
+                return Err(Interrupted { next: run.start + written }.into());
+            }
+
+            if head == buf.len() {
+                refill(gen, &mut buf, expect_zero)?;
+                head = 0;
+            }
+
+            let buf_remaining = buf.len() - head;
+            let to_write = run.end - run.start - written;
+            let write_size = std::cmp::min(to_write, buf_remaining as u64) as usize;
+
+            drive.write_all(&buf[head..head + write_size])?;
+
+            written += write_size as u64;
+            head += write_size;
+        }
+    }
+
+    Ok(())
+}
+
+/// Reads the free segments back and compares them against the output of a generator, consumed in
+/// the same way as by `fill_free_space_with`.
+fn verify_free_space_with<'a, R, D, I>(gen: &mut R, segments: I, drive: &mut D) -> anyhow::Result<()>
+where
+    R: RngCore,
+    D: Read + Seek,
+    I: IntoIterator<Item = &'a Segment>,
+{
+    let mut expected = [0; 4096];
+    let mut actual = [0; 4096];
+    // Buffer head.
+    let mut head = 0;
+    gen.fill_bytes(&mut expected);
+
+    for segment in segments {
+        if segment.status != AllocStatus::Free {
+            continue;
+        }
+
+        drive.seek(SeekFrom::Start(segment.start))?;
+
+        let mut read = 0;
+
+        while read < segment.size() {
+            if head == expected.len() {
+                gen.fill_bytes(&mut expected);
+                head = 0;
+            }
+
+            let read_size = std::cmp::min(segment.size() - read, expected.len() - head);
+            drive.read_exact(&mut actual[..read_size])?;
+
+            if let Some(i) = (0..read_size).find(|i| actual[*i] != expected[head + i]) {
+                bail!("verification failed at byte {}", segment.start + (read + i) as u64);
+            }
+
+            read += read_size;
+            head += read_size;
+        }
+    }
+
+    Ok(())
+}
+
+/// Reads up to `n` random samples of each free segment back and compares them against the output
+/// of a generator, consumed in the same way as by `fill_free_space_with`. Only the samples are read
+/// from the drive; the output between them is skipped, or generated and discarded if the generator
+/// cannot skip it.
+/// Returns the number of samples checked.
+fn spot_check_with<R, D, S>(
+    gen: &mut R,
+    segments: &[Segment],
+    drive: &mut D,
+    n: usize,
+    sampler: &mut S,
+) -> anyhow::Result<usize>
+where
+    R: SeekGen,
+    D: Read + Seek,
+    S: Rng,
+{
+    let mut expected = [0; REFILL_SIZE];
+    let mut actual = [0; SPOT_SIZE as usize];
+    // The number of buffers generated so far.
+    let mut generated = 0;
+    // The position of the segment in the generated stream.
+    let mut base = 0;
+    let mut checked = 0;
+
+    for segment in segments.iter().filter(|s| s.status == AllocStatus::Free) {
+        let size = segment.end - segment.start;
+        let mut offsets: Vec<u64> = (0..n).map(|_| sampler.gen_range(0..size)).collect();
+        offsets.sort_unstable();
+
+        // The overlapping samples are cut, so that the stream is only consumed forwards.
+        let mut prev_end = 0;
+        for offset in offsets {
+            let start = std::cmp::max(offset, prev_end);
+            if start >= size {
+                break;
+            }
+            let len = std::cmp::min(SPOT_SIZE, size - start);
+            prev_end = start + len;
+
+            drive.seek(SeekFrom::Start(segment.start + start))?;
+            drive.read_exact(&mut actual[..len as usize])?;
+
+            for (i, b) in actual[..len as usize].iter().enumerate() {
+                let pos = base + start + i as u64;
+                let block = pos / expected.len() as u64;
+
+                if block > generated && gen.seek_to(block * expected.len() as u64) {
+                    generated = block;
+                }
+                while generated <= block {
+                    gen.fill_bytes(&mut expected);
+                    generated += 1;
+                }
+
+                if *b != expected[(pos % expected.len() as u64) as usize] {
+                    bail!("spot check failed at byte {}", segment.start + start + i as u64);
+                }
+            }
+
+            checked += 1;
+        }
+
+        base += size;
+    }
+
+    Ok(checked)
+}
+
+/// Refills the buffer with the generator, optionally checking that the result is all zeroes.
+fn refill<R: RngCore>(gen: &mut R, buf: &mut [u8], expect_zero: bool) -> anyhow::Result<()>
+{
+    for piece in buf.chunks_mut(REFILL_SIZE) {
+        gen.fill_bytes(piece);
+    }
+
+    if expect_zero && buf.iter().any(|b| *b != 0) {
+        bail!("zero fill: the fill buffer is not zeroed; aborting before writing it");
+    }
+
+    Ok(())
+}
+
+// Debug and Display implementations.
+
+impl std::fmt::Display for FillStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result
+    {
+        let mib = |rate: f64| rate / (1024.0 * 1024.0);
+
+        write!(
+            f,
+            "{} bytes, {} segments, {} ({:.2} MiB/s; min {:.2}, max {:.2}), {} write errors",
+            self.bytes_written,
+            self.segments_filled,
+            format_duration(Duration::from_secs_f64(self.duration)),
+            mib(self.avg_throughput),
+            mib(self.min_throughput),
+            mib(self.max_throughput),
+            self.write_errors,
+        )
+    }
+}
+
+impl std::fmt::Display for FillPass {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result
+    {
+        match self.verify {
+            true => write!(f, "{} (verified)", self.mode),
+            false => write!(f, "{}", self.mode),
+        }
+    }
+}
+
+impl std::fmt::Display for FillMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result
+    {
+        match self {
+            Self::Zero =>write!(f, "zero"),
+            Self::ChaCha20 => write!(f, "chacha20"),
+            Self::Hc128 => write!(f, "HC128"),
+            Self::AesCtr => write!(f, "AES-256-CTR"),
+            Self::Xoshiro => write!(f, "xoshiro256++"),
+            Self::OsRandom => write!(f, "OS random"),
+            Self::Pattern => write!(f, "pattern"),
+            Self::Dod3 => write!(f, "DoD 5220.22-M (3 passes)"),
+            Self::Dod7 => write!(f, "DoD 5220.22-M ECE (7 passes)"),
+            Self::Discard => write!(f, "discard"),
+        }
+    }
+}
+
+impl std::fmt::Display for Clear {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result
+    {
+        match self {
+            Self::Discard => write!(f, "discarding"),
+            Self::ZeroOut => write!(f, "zeroing out"),
+            Self::PunchHole => write!(f, "punching holes"),
+        }
+    }
+}
+
+impl std::fmt::Display for PassSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result
+    {
+        match self {
+            Self::Byte(b) => write!(f, "{:#04x}", b),
+            Self::Random => write!(f, "random"),
+        }
+    }
+}
+
+// Tests
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn fill()
+    {
+        use super::*;
+
+        let mut f = std::io::Cursor::new(vec![0xffu8; 4096 * 10]);
+        let len = f.seek(SeekFrom::End(0)).unwrap();
+
+        let mut map = UsageMap::new(len);
+
+        map.update(2, 79, AllocStatus::Used);
+        map.update(201, 335, AllocStatus::Used);
+        map.update(700, 1000, AllocStatus::Used);
+        map.update(5000, 7028, AllocStatus::Used);
+        map.update(20000, 2, AllocStatus::Used);
+        map.update(20229, 33, AllocStatus::Used);
+
+        super::fill_free_space_with(&mut ZeroGen::new(), &map, &mut f, true).unwrap();
+
+        for seg in map.iter().filter(|s| { s.status == AllocStatus::Free }) {
+            for b in &f.get_ref()[seg.start as usize..seg.end as usize] {
+                assert_eq!(*b, 0u8);
+            }
+        }
+
+        for seg in map.iter_used() {
+            for b in &f.get_ref()[seg.start as usize..seg.end as usize] {
+                assert_eq!(*b, 0xffu8);
+            }
+        }
+    }
+
+    #[test]
+    fn fill_zero_dirty_buffer()
+    {
+        use super::*;
+
+        let mut f = std::io::Cursor::new(vec![0xffu8; 10000]);
+        let map = UsageMap::new(10000);
+
+        // A generator that leaves garbage in the buffer is caught before anything is written.
+        let mut gen = ChaCha20Rng::seed_from_u64(0);
+        assert!(fill_free_space_with(&mut gen, &map, &mut f, true).is_err());
+        assert!(f.get_ref().iter().all(|b| *b == 0xff));
+
+        // ZeroGen zeroes a dirty buffer.
+        let mut buf = [0xaa; 64];
+        refill(&mut ZeroGen::new(), &mut buf, true).unwrap();
+        assert!(buf.iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn fill_top()
+    {
+        use super::*;
+
+        let mut f = std::io::Cursor::new(vec![0xffu8; 1000]);
+
+        let mut map = UsageMap::new(1000);
+        // Free segments: 0..10, 20..120, 130..160, 170..500, 510..1000.
+        map.update(10, 10, AllocStatus::Used);
+        map.update(120, 10, AllocStatus::Used);
+        map.update(160, 10, AllocStatus::Used);
+        map.update(500, 10, AllocStatus::Used);
+
+        let cfg = Config { fill_top: Some(2), ..Config::default() };
+        let segments = select_segments(&map, &cfg);
+
+        assert_eq!(segments, vec![
+            Segment { start: 170, end: 500, status: AllocStatus::Free },
+            Segment { start: 510, end: 1000, status: AllocStatus::Free },
+        ]);
+
+        super::fill_free_space_with(&mut ZeroGen::new(), &segments, &mut f, true).unwrap();
+
+        let filled = |i: usize| (170..500).contains(&i) || (510..1000).contains(&i);
+        for (i, b) in f.get_ref().iter().enumerate() {
+            assert_eq!(*b, if filled(i) { 0 } else { 0xff }, "byte {}", i);
+        }
+    }
+
+    #[test]
+    fn estimate()
+    {
+        use super::*;
+
+        // 256 MiB in 2 s, i.e. 128 MiB/s; 1 TiB takes 8192 s.
+        let d = estimate_duration(1 << 40, PROBE_SIZE, Duration::from_secs(2));
+        assert_eq!(d.as_secs(), 8192);
+        assert_eq!(format_duration(d), "2h 16m 32s");
+
+        assert_eq!(estimate_duration(100, 100, Duration::from_millis(1500)), Duration::from_millis(1500));
+        assert_eq!(estimate_duration(100, 0, Duration::from_secs(1)), Duration::ZERO);
+    }
+
+    #[test]
+    fn split()
+    {
+        use super::*;
+
+        let segments = [
+            Segment { start: 0, end: 10, status: AllocStatus::Free },
+            Segment { start: 20, end: 40, status: AllocStatus::Free },
+            Segment { start: 50, end: 60, status: AllocStatus::Free },
+        ];
+
+        let (head, tail) = split_segments(&segments, 15);
+
+        assert_eq!(head, vec![
+            Segment { start: 0, end: 10, status: AllocStatus::Free },
+            Segment { start: 20, end: 25, status: AllocStatus::Free },
+        ]);
+        assert_eq!(tail, vec![
+            Segment { start: 25, end: 40, status: AllocStatus::Free },
+            Segment { start: 50, end: 60, status: AllocStatus::Free },
+        ]);
+    }
+
+    #[test]
+    fn dod()
+    {
+        use super::*;
+
+        assert_eq!(schedule(FillMode::Dod3).len(), 3);
+        let dod7 = schedule(FillMode::Dod7);
+        assert_eq!(dod7.len(), 7);
+        assert_eq!(dod7[4], Pass { source: PassSource::Byte(0x00), verify: false });
+        assert!(dod7[6].verify);
+
+        let mut ctx = crate::util::test_context(&[0xaa; 10000]);
+        let mut map = UsageMap::new(10000);
+        map.update(100, 5000, AllocStatus::Used);
+        let segments = select_segments(&map, &Config::default());
+
+        run_passes(&schedule(FillMode::Dod3), [7; 32], &segments, &mut ctx, &Config::default()).unwrap();
+
+        let mut drive = vec![0u8; 10000];
+        ctx.drive.seek(SeekFrom::Start(0)).unwrap();
+        ctx.drive.read_exact(&mut drive).unwrap();
+        assert!(drive[100..5100].iter().all(|b| *b == 0xaa));
+        assert!(drive[..100].iter().chain(&drive[5100..]).any(|b| *b != 0xaa && *b != 0xff));
+
+        // A mismatch is found at its position.
+        let mut f = std::io::Cursor::new(vec![0x01u8; 10000]);
+        f.get_mut()[6000] = 0;
+        verify_free_space_with(&mut ByteGen(0x01), &segments[..1], &mut f).unwrap();
+        let e = verify_free_space_with(&mut ByteGen(0x01), &segments, &mut f).unwrap_err();
+        assert_eq!(e.to_string(), "verification failed at byte 6000");
+    }
+
+    #[test]
+    fn pattern_byte()
+    {
+        use super::*;
+
+        assert_eq!(parse_byte("0xff"), Ok(0xff));
+        assert_eq!(parse_byte("0XA5"), Ok(0xa5));
+        assert_eq!(parse_byte("17"), Ok(17));
+        assert!(parse_byte("0x100").is_err());
+        assert!(parse_byte("ff").is_err());
+
+        let mut f = std::io::Cursor::new(vec![0u8; 100]);
+        let mut map = UsageMap::new(100);
+        map.update(10, 10, AllocStatus::Used);
+
+        fill_free_space_with(&mut ByteGen(0xff), &map, &mut f, false).unwrap();
+        for (i, b) in f.get_ref().iter().enumerate() {
+            assert_eq!(*b, if (10..20).contains(&i) { 0 } else { 0xff }, "byte {}", i);
+        }
+    }
+
+    #[test]
+    fn pattern_file()
+    {
+        use super::*;
+
+        let mut f = std::io::Cursor::new(vec![0u8; 5000]);
+        let mut map = UsageMap::new(5000);
+        map.update(3, 2, AllocStatus::Used);
+
+        // The pattern continues across the segments and the refills of the buffer.
+        let mut gen = PatternGen::new(b"FREE SPACE".to_vec());
+        fill_free_space_with(&mut gen, &map, &mut f, false).unwrap();
+
+        assert_eq!(&f.get_ref()[..15], b"FRE\0\0E SPACEFRE");
+        for i in 5..5000 {
+            assert_eq!(f.get_ref()[i], b"FREE SPACE"[(i - 2) % 10], "byte {}", i);
+        }
+
+        let path = std::env::temp_dir().join(format!("fsfill-pattern-{}", std::process::id()));
+        std::fs::write(&path, b"").unwrap();
+        assert!(read_pattern(&path).is_err());
+        std::fs::write(&path, b"ab").unwrap();
+        assert_eq!(read_pattern(&path).unwrap(), b"ab");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn seed()
+    {
+        use super::*;
+
+        assert_eq!(parse_seed(&"0a".repeat(32)), Ok([0x0a; 32]));
+        assert_eq!(parse_seed(&format!("0x{}", "ff".repeat(32))), Ok([0xff; 32]));
+        assert!(parse_seed("0a").is_err());
+        assert!(parse_seed(&"zz".repeat(32)).is_err());
+        assert!(parse_seed(&"é".repeat(32)).is_err());
+        assert!(parse_seed(&"+f".repeat(32)).is_err());
+
+        let mut map = UsageMap::new(10000);
+        map.update(100, 5000, AllocStatus::Used);
+
+        // The same seed writes the same stream, which the verification re-generates.
+        let cfg = Config {
+            fill_mode: FillMode::Hc128,
+            seed: Some([3; 32]),
+            verify: true,
+            report_only: false,
+            ..Config::default()
+        };
+        let mut drives = Vec::new();
+        for _ in 0..2 {
+            let mut ctx = crate::util::test_context(&[0; 10000]);
+            fill_free_space(&map, &mut ctx, &cfg).unwrap();
+
+            let mut drive = vec![0u8; 10000];
+            ctx.drive.seek(SeekFrom::Start(0)).unwrap();
+            ctx.drive.read_exact(&mut drive).unwrap();
+            drives.push(drive);
+        }
+        assert_eq!(drives[0], drives[1]);
+        assert!(drives[0][..100].iter().any(|b| *b != 0));
+        assert!(drives[0][100..5100].iter().all(|b| *b == 0));
+
+        let mut gen = Hc128Rng::from_seed([3; 32]);
+        let mut f = std::io::Cursor::new(drives.pop().unwrap());
+        verify_free_space_with(&mut gen, &map, &mut f).unwrap();
+    }
+
+    #[test]
+    fn passes()
+    {
+        use super::*;
+
+        assert!(matches!(parse_pass("zero"), Ok(FillPass { mode: FillMode::Zero, verify: false })));
+        assert!(matches!(
+            parse_pass("chacha20:verify"),
+            Ok(FillPass { mode: FillMode::ChaCha20, verify: true }),
+        ));
+        assert!(parse_pass("zero:fast").is_err());
+        assert!(parse_pass("rot13").is_err());
+        assert_eq!(pass_seed([7; 32], 0), [7; 32]);
+        assert_eq!(pass_seed([7; 32], 2)[..2], [5, 7]);
+
+        let mut map = UsageMap::new(10000);
+        map.update(100, 5000, AllocStatus::Used);
+
+        // The last pass is what remains on the drive; the verified pass is read back.
+        let cfg = Config {
+            passes: vec![
+                FillPass { mode: FillMode::Pattern, verify: false },
+                FillPass { mode: FillMode::ChaCha20, verify: true },
+            ],
+            pattern_byte: 0xaa,
+            seed: Some([3; 32]),
+            report_only: false,
+            ..Config::default()
+        };
+        let mut ctx = crate::util::test_context(&[0; 10000]);
+        fill_free_space(&map, &mut ctx, &cfg).unwrap();
+
+        let mut drive = vec![0u8; 10000];
+        ctx.drive.seek(SeekFrom::Start(0)).unwrap();
+        ctx.drive.read_exact(&mut drive).unwrap();
+
+        let mut gen = ChaCha20Rng::from_seed(pass_seed([3; 32], 1));
+        let mut f = std::io::Cursor::new(drive);
+        verify_free_space_with(&mut gen, &map, &mut f).unwrap();
+    }
+
+    #[test]
+    fn skip_zeroed()
+    {
+        use super::*;
+
+        let size = 4 * SKIP_BLOCK_SIZE;
+        let mut contents = vec![0; size];
+        contents[100] = 1;
+        contents[SKIP_BLOCK_SIZE + 5] = 1;
+        contents[3 * SKIP_BLOCK_SIZE + 7] = 1;
+
+        let mut map = UsageMap::new(size as u64);
+        map.update(0, 50, AllocStatus::Used);
+        map.update(3 * SKIP_BLOCK_SIZE as u64, 10, AllocStatus::Used);
+
+        // The blocks are counted from the start of each segment, so that both dirty bytes are in
+        // the first block; the used bytes are kept.
+        let cfg = Config { skip_zeroed: true, report_only: false, ..Config::default() };
+        let mut ctx = crate::util::test_context(&contents);
+        let stats = fill_free_space(&map, &mut ctx, &cfg).unwrap();
+        assert_eq!(stats.bytes_written, SKIP_BLOCK_SIZE as u64);
+
+        let mut drive = vec![0u8; size];
+        ctx.drive.seek(SeekFrom::Start(0)).unwrap();
+        ctx.drive.read_exact(&mut drive).unwrap();
+        contents[100] = 0;
+        contents[SKIP_BLOCK_SIZE + 5] = 0;
+        assert_eq!(drive, contents);
+    }
+
+    #[test]
+    fn runs()
+    {
+        use super::*;
+
+        /// Records the seeks and the sizes of the writes.
+        struct Recorder {
+            inner: std::io::Cursor<Vec<u8>>,
+            seeks: Vec<u64>,
+            writes: Vec<usize>,
+        }
+
+        impl Write for Recorder {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize>
+            {
+                self.writes.push(buf.len());
+                self.inner.write(buf)
+            }
+
+            fn flush(&mut self) -> std::io::Result<()>
+            {
+                Ok(())
+            }
+        }
+
+        impl Seek for Recorder {
+            fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64>
+            {
+                let n = self.inner.seek(pos)?;
+                self.seeks.push(n);
+                Ok(n)
+            }
+        }
+
+        let seg = |start, end, status| Segment { start, end, status };
+        let segments = [
+            seg(0, 100, AllocStatus::Free),
+            seg(100, 5000, AllocStatus::Free),
+            seg(5000, 80000, AllocStatus::Free),
+            seg(80000, 90000, AllocStatus::Used),
+            seg(90000, 100000, AllocStatus::Free),
+        ];
+
+        // The contiguous segments are written as one run, across their boundaries.
+        let mut w = Recorder {
+            inner: std::io::Cursor::new(vec![0; 100000]),
+            seeks: Vec::new(),
+            writes: Vec::new(),
+        };
+        fill_free_space_with(&mut ChaCha20Rng::from_seed([6; 32]), &segments, &mut w, false).unwrap();
+        assert_eq!(w.seeks, vec![0, 90000]);
+        assert_eq!(w.writes, vec![WRITE_SIZE, 80000 - WRITE_SIZE, 10000]);
+
+        // The data does not depend on the size of the writes.
+        let mut gen = ChaCha20Rng::from_seed([6; 32]);
+        let mut expected = vec![0; 90000];
+        for piece in expected.chunks_mut(4096) {
+            gen.fill_bytes(piece);
+        }
+        let drive = w.inner.into_inner();
+        assert_eq!(&drive[..80000], &expected[..80000]);
+        assert_eq!(&drive[90000..], &expected[80000..]);
+    }
+
+    #[test]
+    fn direct_io()
+    {
+        use super::*;
+        use crate::drive::Window;
+
+        let size = DIRECT_BUF_SIZE + 3 * 4096;
+        let mut drive = Drive::new(crate::util::temp_drive(&vec![0xaa; size + 100]));
+        drive.set_window(Some(Window { offset: 100, len: size as u64 })).unwrap();
+
+        // Runs that start and end unaligned, that span more than the buffer, and that are
+        // contiguous across the segments.
+        let seg = |start, end| Segment { start, end, status: AllocStatus::Free };
+        let segments = [
+            seg(10, 20),
+            seg(3000, 9000),
+            seg(9000, 9001),
+            seg(10000, size as u64 - 50),
+        ];
+
+        // Without O_DIRECT, as the temporary directory may not support it.
+        let mut direct = Drive::new(drive.file().try_clone().unwrap());
+        direct.set_window(drive.window()).unwrap();
+        let mut writer = DirectWriter::new(&drive, &direct);
+        let gen = &mut ChaCha20Rng::from_seed([4; 32]);
+        fill_free_space_with(gen, &segments, &mut writer, false).unwrap();
+        writer.flush().unwrap();
+
+        let mut buf = vec![0u8; size];
+        drive.seek(SeekFrom::Start(0)).unwrap();
+        drive.read_exact(&mut buf).unwrap();
+        let mut f = std::io::Cursor::new(buf.clone());
+        verify_free_space_with(&mut ChaCha20Rng::from_seed([4; 32]), &segments, &mut f).unwrap();
+
+        let used = [0..10, 20..3000, 9001..10000, size - 50..size];
+        assert!(used.into_iter().all(|r| buf[r].iter().all(|b| *b == 0xaa)));
+    }
+
+    #[test]
+    fn threads()
+    {
+        use super::*;
+
+        assert_eq!(parse_threads("4"), Ok(4));
+        assert!(parse_threads("0").is_err());
+        assert_eq!(stream_seed([7; 32], 0), [7; 32]);
+        assert_eq!(stream_seed([7; 32], 2)[7..9], [7, 5]);
+
+        let mut map = UsageMap::new(20000);
+        map.update(100, 5000, AllocStatus::Used);
+        map.update(9000, 3, AllocStatus::Used);
+        let segments = select_segments(&map, &Config::default());
+
+        // The parts are of about the same size, split at multiples of 4096 bytes.
+        let chunks = thread_chunks(&segments, 3);
+        let sizes: Vec<u64> = chunks.iter()
+            .map(|c| c.iter().map(|s| s.end - s.start).sum())
+            .collect();
+        assert_eq!(sizes, vec![4096, 4096, 6805]);
+        assert_eq!(thread_chunks(&segments, 1), vec![segments.clone()]);
+
+        // Each part is filled with its own stream, which the verification re-generates.
+        let cfg = Config {
+            fill_mode: FillMode::ChaCha20,
+            seed: Some([3; 32]),
+            threads: 3,
+            verify: true,
+            report_only: false,
+            ..Config::default()
+        };
+        let mut ctx = crate::util::test_context(&[0; 20000]);
+        let stats = fill_free_space(&map, &mut ctx, &cfg).unwrap();
+        assert_eq!(stats.bytes_written, 14997);
+
+        let mut drive = vec![0u8; 20000];
+        ctx.drive.seek(SeekFrom::Start(0)).unwrap();
+        ctx.drive.read_exact(&mut drive).unwrap();
+        assert!(drive[100..5100].iter().all(|b| *b == 0));
+        assert!(drive[9000..9003].iter().all(|b| *b == 0));
+
+        let mut f = std::io::Cursor::new(drive);
+        for (i, chunk) in chunks.iter().enumerate() {
+            let mut gen = ChaCha20Rng::from_seed(stream_seed([3; 32], i as u64));
+            verify_free_space_with(&mut gen, chunk, &mut f).unwrap();
+        }
+    }
+
+    #[test]
+    fn xoshiro()
+    {
+        use super::*;
+
+        // The same seed and stream give the same output, another stream a different one.
+        let mut outputs = Vec::new();
+        for stream in [1, 1, 2] {
+            let mut buf = [0u8; 1000];
+            Xoshiro256PlusPlus::from_seed(stream_seed([3; 32], stream)).fill_bytes(&mut buf);
+            outputs.push(buf);
+        }
+        assert_eq!(outputs[0], outputs[1]);
+        assert_ne!(outputs[0], outputs[2]);
+
+        let mut map = UsageMap::new(20000);
+        map.update(100, 5000, AllocStatus::Used);
+        let segments = select_segments(&map, &Config::default());
+        let chunks = thread_chunks(&segments, 2);
+
+        // The verification of the fill passes, and re-generates the streams of the parts.
+        let cfg = Config {
+            fill_mode: FillMode::Xoshiro,
+            seed: Some([3; 32]),
+            threads: 2,
+            verify: true,
+            report_only: false,
+            ..Config::default()
+        };
+        let mut drives = Vec::new();
+        for _ in 0..2 {
+            let mut ctx = crate::util::test_context(&[0; 20000]);
+            let stats = fill_free_space(&map, &mut ctx, &cfg).unwrap();
+            assert_eq!(stats.bytes_written, 15000);
+
+            let mut drive = vec![0u8; 20000];
+            ctx.drive.seek(SeekFrom::Start(0)).unwrap();
+            ctx.drive.read_exact(&mut drive).unwrap();
+            drives.push(drive);
+        }
+        assert_eq!(drives[0], drives[1]);
+        assert!(drives[0][100..5100].iter().all(|b| *b == 0));
+
+        let mut f = std::io::Cursor::new(drives.pop().unwrap());
+        for (i, chunk) in chunks.iter().enumerate() {
+            let mut gen = Xoshiro256PlusPlus::from_seed(stream_seed([3; 32], i as u64));
+            verify_free_space_with(&mut gen, chunk, &mut f).unwrap();
+        }
+
+        f.get_mut()[19999] ^= 1;
+        let mut gen = Xoshiro256PlusPlus::from_seed(stream_seed([3; 32], 1));
+        assert!(verify_free_space_with(&mut gen, &chunks[1], &mut f).is_err());
+    }
+
+    #[test]
+    fn spot_check()
+    {
+        use super::*;
+
+        let mut map = UsageMap::new(20000);
+        map.update(100, 5000, AllocStatus::Used);
+        map.update(9000, 3, AllocStatus::Used);
+        let segments = select_segments(&map, &Config::default());
+
+        let mut f = std::io::Cursor::new(vec![0u8; 20000]);
+        fill_free_space_with(&mut ChaCha20Rng::from_seed([5; 32]), &segments, &mut f, false).unwrap();
+
+        // The samples of the first segment overlap, and are cut.
+        let sampler = &mut ChaCha20Rng::from_seed([1; 32]);
+        let mut gen = ChaCha20Rng::from_seed([5; 32]);
+        let checked = spot_check_with(&mut gen, &segments, &mut f, 100, sampler).unwrap();
+        assert!(checked > 3);
+
+        // A damaged byte is found, if sampled.
+        f.get_mut()[15000] ^= 1;
+        let mut gen = ChaCha20Rng::from_seed([5; 32]);
+        let err = spot_check_with(&mut gen, &segments, &mut f, 1000, sampler).unwrap_err();
+        assert_eq!(err.to_string(), "spot check failed at byte 15000");
+    }
+
+    #[test]
+    fn seek_gen()
+    {
+        use super::*;
+
+        // The output from a position is the same as when everything before it is generated.
+        fn check<R: SeekGen>(mut new_gen: impl FnMut() -> R)
+        {
+            let mut stream = vec![0u8; 3 * REFILL_SIZE];
+            let gen = &mut new_gen();
+            for piece in stream.chunks_mut(REFILL_SIZE) {
+                gen.fill_bytes(piece);
+            }
+
+            let gen = &mut new_gen();
+            assert!(gen.seek_to(2 * REFILL_SIZE as u64));
+            let mut buf = vec![0u8; REFILL_SIZE];
+            gen.fill_bytes(&mut buf);
+            assert_eq!(buf, &stream[2 * REFILL_SIZE..]);
+        }
+
+        check(|| {
+            let mut gen = ChaCha20Rng::from_seed([5; 32]);
+            gen.set_stream(3);
+            gen
+        });
+        check(|| AesCtrGen::new([5; 32]));
+        check(|| PatternGen::new(vec![1, 2, 3]));
+        check(|| ByteGen(0xaa));
+
+        assert!(!Hc128Rng::from_seed([5; 32]).seek_to(REFILL_SIZE as u64));
+    }
+
+    #[test]
+    fn stats()
+    {
+        use super::*;
+
+        let mut map = UsageMap::new(10000);
+        map.update(100, 5000, AllocStatus::Used);
+
+        // Both passes count.
+        let cfg = Config { fill_mode: FillMode::ChaCha20, final_zero: true, ..Config::default() };
+        let mut ctx = crate::util::test_context(&[0; 10000]);
+        let stats = fill_free_space(&map, &mut ctx, &cfg).unwrap();
+
+        assert_eq!(stats.bytes_written, 2 * 5000);
+        assert_eq!(stats.segments_filled, 2);
+        assert_eq!(stats.write_errors, 0);
+        assert!(stats.min_throughput <= stats.avg_throughput);
+        assert!(stats.avg_throughput <= stats.max_throughput);
+    }
+
+    #[test]
+    fn resume()
+    {
+        use super::*;
+
+        let mut map = UsageMap::new(10000);
+        map.update(100, 5000, AllocStatus::Used);
+
+        // The first segment was filled before the interrupt, and half of the second one is now
+        // used.
+        let path = crate::util::temp_path();
+        let checkpoint = Checkpoint {
+            seed: [0; 32],
+            pass_count: 1,
+            pass: 0,
+            remaining: vec![Segment { start: 5100, end: 10000, status: AllocStatus::Free }],
+        };
+        fill_checkpoint::save(&path, &checkpoint, 10000).unwrap();
+        map.update(7000, 3000, AllocStatus::Used);
+
+        let cfg = Config {
+            fill_mode: FillMode::Pattern,
+            pattern_byte: 0xaa,
+            resume: Some(path.clone()),
+            report_only: false,
+            ..Config::default()
+        };
+        let mut ctx = crate::util::test_context(&[0; 10000]);
+        fill_free_space(&map, &mut ctx, &cfg).unwrap();
+        assert!(!path.exists());
+
+        let mut drive = vec![0u8; 10000];
+        ctx.drive.seek(SeekFrom::Start(0)).unwrap();
+        ctx.drive.read_exact(&mut drive).unwrap();
+        for (i, b) in drive.iter().enumerate() {
+            assert_eq!(*b, if (5100..7000).contains(&i) { 0xaa } else { 0 }, "byte {}", i);
+        }
+
+        // The checkpoint of a different fill is refused.
+        fill_checkpoint::save(&path, &checkpoint, 10000).unwrap();
+        let cfg = Config { final_zero: true, ..cfg };
+        let err = fill_free_space(&map, &mut ctx, &cfg).unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(err.to_string(), "the checkpoint does not match the passes of the fill");
+    }
+
+    #[test]
+    fn presets()
+    {
+        use super::*;
+
+        let mut cfg = Config { fill_mode: FillMode::Dod7, ..Config::default() };
+        Preset::NistClear.apply(&mut cfg);
+        assert!(matches!(cfg.fill_mode, FillMode::Zero));
+        assert!(cfg.verify);
+
+        Preset::NistClearRandom.apply(&mut cfg);
+        assert!(matches!(cfg.fill_mode, FillMode::ChaCha20));
+        assert!(cfg.verify);
+    }
+
+    #[test]
+    fn final_zero()
+    {
+        use super::*;
+
+        let mut map = UsageMap::new(10000);
+        map.update(100, 5000, AllocStatus::Used);
+
+        for (fill_mode, fill) in [(FillMode::Dod3, 0), (FillMode::Pattern, 0xab)] {
+            let cfg = Config {
+                fill_mode,
+                pattern_byte: 0xab,
+                final_zero: true,
+                report_only: false,
+                ..Config::default()
+            };
+            let mut ctx = crate::util::test_context(&[0xff; 10000]);
+            fill_free_space(&map, &mut ctx, &cfg).unwrap();
+
+            let mut drive = vec![0u8; 10000];
+            ctx.drive.seek(SeekFrom::Start(0)).unwrap();
+            ctx.drive.read_exact(&mut drive).unwrap();
+            assert!(drive[100..5100].iter().all(|b| *b == 0xff));
+            assert!(drive[..100].iter().chain(&drive[5100..]).all(|b| *b == fill));
+        }
+    }
+
+    #[test]
+    fn discard()
+    {
+        use super::*;
+
+        let mut map = UsageMap::new(5 * 4096);
+        map.update(100, 4096, AllocStatus::Used);
+        map.update(3 * 4096, 10, AllocStatus::Used);
+
+        // Whether the ranges are discarded or zeroed, the free space reads as zeroes.
+        let cfg = Config { fill_mode: FillMode::Discard, report_only: false, ..Config::default() };
+        let mut ctx = crate::util::test_context(&[0xaa; 5 * 4096]);
+        fill_free_space(&map, &mut ctx, &cfg).unwrap();
+
+        let mut drive = vec![0u8; 5 * 4096];
+        ctx.drive.seek(SeekFrom::Start(0)).unwrap();
+        ctx.drive.read_exact(&mut drive).unwrap();
+        for (i, b) in drive.iter().enumerate() {
+            let used = (100..4196).contains(&i) || (3 * 4096..3 * 4096 + 10).contains(&i);
+            assert_eq!(*b, if used { 0xaa } else { 0 }, "byte {}", i);
+        }
+    }
+
+    #[test]
+    fn discard_interrupted()
+    {
+        use super::*;
+
+        let mut map = UsageMap::new(5 * 4096);
+        map.update(4096, 4096, AllocStatus::Used);
+
+        // Interrupted after the first segment.
+        let path = crate::util::temp_path();
+        let cfg = Config {
+            fill_mode: FillMode::Discard,
+            fill_checkpoint: Some(path.clone()),
+            report_only: false,
+            ..Config::default()
+        };
+        let mut ctx = crate::util::test_context(&[0xaa; 5 * 4096]);
+        interrupt::request_after(1);
+        let err = fill_free_space(&map, &mut ctx, &cfg).unwrap_err();
+        assert_eq!(err.to_string(), "the fill was interrupted");
+
+        let checkpoint = fill_checkpoint::load(&path, map.size()).unwrap();
+        assert_eq!(checkpoint.remaining, vec![
+            Segment { start: 2 * 4096, end: 5 * 4096, status: AllocStatus::Free },
+        ]);
+
+        let mut drive = vec![0u8; 5 * 4096];
+        ctx.drive.seek(SeekFrom::Start(0)).unwrap();
+        ctx.drive.read_exact(&mut drive).unwrap();
+        for (i, b) in drive.iter().enumerate() {
+            assert_eq!(*b, if i < 4096 { 0 } else { 0xaa }, "byte {}", i);
+        }
+
+        // The resumed fill clears the rest.
+        let cfg = Config { resume: Some(path.clone()), ..cfg };
+        fill_free_space(&map, &mut ctx, &cfg).unwrap();
+        assert!(!path.exists());
+
+        ctx.drive.seek(SeekFrom::Start(0)).unwrap();
+        ctx.drive.read_exact(&mut drive).unwrap();
+        for (i, b) in drive.iter().enumerate() {
+            assert_eq!(*b, if (4096..2 * 4096).contains(&i) { 0xaa } else { 0 }, "byte {}", i);
+        }
+    }
+
+    #[test]
+    fn sparsify()
+    {
+        use super::*;
+
+        let mut map = UsageMap::new(5 * 4096);
+        map.update(100, 4096, AllocStatus::Used);
+
+        let cfg = Config { sparsify: true, verify: true, report_only: false, ..Config::default() };
+        let mut ctx = crate::util::test_context(&[0xaa; 5 * 4096]);
+        fill_free_space(&map, &mut ctx, &cfg).unwrap();
+
+        let mut drive = vec![0u8; 5 * 4096];
+        ctx.drive.seek(SeekFrom::Start(0)).unwrap();
+        ctx.drive.read_exact(&mut drive).unwrap();
+        for (i, b) in drive.iter().enumerate() {
+            assert_eq!(*b, if (100..4196).contains(&i) { 0xaa } else { 0 }, "byte {}", i);
+        }
+    }
+
+    #[test]
+    fn aes_ctr()
+    {
+        use super::*;
+
+        // The keystream continues across the calls, and matches the one-shot output.
+        let seed = [0x42; 32];
+        let mut whole = [0u8; 100];
+        AesCtrGen::new(seed).fill_bytes(&mut whole);
+
+        let mut gen = AesCtrGen::new(seed);
+        let mut parts = [0u8; 100];
+        gen.fill_bytes(&mut parts[..7]);
+        gen.fill_bytes(&mut parts[7..]);
+        assert_eq!(parts, whole);
+        assert_ne!(whole, [0; 100]);
+
+        // Known answer: the first keystream block for the all-zero key is AES-256(0).
+        let mut block = [0u8; 16];
+        AesCtrGen::new([0; 32]).fill_bytes(&mut block);
+        assert_eq!(block, [
+            0xdc, 0x95, 0xc0, 0x78, 0xa2, 0x40, 0x89, 0x89,
+            0xad, 0x48, 0xa2, 0x14, 0x92, 0x84, 0x20, 0x87,
+        ]);
+    }
+
+    #[test]
+    fn os_random()
+    {
+        use super::*;
+
+        // The requests of any size are served across the chunks.
+        let mut gen = OsRandomGen::new();
+        let mut buf = vec![0u8; OS_RANDOM_CHUNK + 10];
+        gen.fill_bytes(&mut buf[..OS_RANDOM_CHUNK - 5]);
+        gen.fill_bytes(&mut buf[OS_RANDOM_CHUNK - 5..]);
+        assert_eq!(gen.pos, 10);
+        assert!(buf[OS_RANDOM_CHUNK - 5..].iter().any(|b| *b != 0));
+    }
+
+    #[test]
+    fn partial_fill()
+    {
+        use super::*;
+
+        assert_eq!(parse_size("4096"), Ok(4096));
+        assert_eq!(parse_size("10G"), Ok(10 << 30));
+        assert_eq!(parse_size("3k"), Ok(3072));
+        assert!(parse_size("G").is_err());
+        assert!(parse_size("20000000T").is_err());
+        assert_eq!(parse_percent("100"), Ok(100));
+        assert!(parse_percent("101").is_err());
+
+        let mut map = UsageMap::new(1000);
+        // Free segments: 0..10, 20..120, 130..160, 170..500, 510..1000.
+        map.update(10, 10, AllocStatus::Used);
+        map.update(120, 10, AllocStatus::Used);
+        map.update(160, 10, AllocStatus::Used);
+        map.update(500, 10, AllocStatus::Used);
+
+        // The largest segments come first, the last one is cut short.
+        let cfg = Config { max_fill_bytes: Some(600), ..Config::default() };
+        assert_eq!(select_segments(&map, &cfg), vec![
+            Segment { start: 170, end: 280, status: AllocStatus::Free },
+            Segment { start: 510, end: 1000, status: AllocStatus::Free },
+        ]);
+
+        // 50% of the 960 free bytes.
+        let cfg = Config { fill_percent: Some(50), ..Config::default() };
+        assert_eq!(select_segments(&map, &cfg), vec![
+            Segment { start: 510, end: 990, status: AllocStatus::Free },
+        ]);
+
+        let cfg = Config { max_fill_bytes: Some(5000), ..Config::default() };
+        assert_eq!(select_segments(&map, &cfg).len(), 5);
+    }
+
+    #[test]
+    fn ranges()
+    {
+        use super::*;
+
+        assert_eq!(parse_range("1G-2G"), Ok((1 << 30)..(2 << 30)));
+        assert!(parse_range("100").is_err());
+        assert!(parse_range("200-100").is_err());
+        assert!(parse_range("-100").is_err());
+
+        let mut map = UsageMap::new(1000);
+        // Free segments: 0..10, 20..120, 130..160, 170..1000.
+        map.update(10, 10, AllocStatus::Used);
+        map.update(120, 10, AllocStatus::Used);
+        map.update(160, 10, AllocStatus::Used);
+
+        // The overlapping ranges are merged; the minimum size applies to the cut segments.
+        let cfg = Config {
+            ranges: vec![100..140, 5..15, 120..150, 900..2000],
+            min_segment_size: 10,
+            ..Config::default()
+        };
+        assert_eq!(select_segments(&map, &cfg), vec![
+            Segment { start: 100, end: 120, status: AllocStatus::Free },
+            Segment { start: 130, end: 150, status: AllocStatus::Free },
+            Segment { start: 900, end: 1000, status: AllocStatus::Free },
+        ]);
+    }
+
+    #[test]
+    fn min_segment_size()
+    {
+        use super::*;
+
+        let mut map = UsageMap::new(1000);
+        // Free segments: 0..10, 20..120, 130..160, 170..1000.
+        map.update(10, 10, AllocStatus::Used);
+        map.update(120, 10, AllocStatus::Used);
+        map.update(160, 10, AllocStatus::Used);
+
+        let cfg = Config { min_segment_size: 30, ..Config::default() };
+        assert_eq!(select_segments(&map, &cfg), vec![
+            Segment { start: 20, end: 120, status: AllocStatus::Free },
+            Segment { start: 130, end: 160, status: AllocStatus::Free },
+            Segment { start: 170, end: 1000, status: AllocStatus::Free },
+        ]);
+
+        // The small segments do not count towards the percentage.
+        let cfg = Config { min_segment_size: 31, fill_percent: Some(10), ..Config::default() };
+        assert_eq!(select_segments(&map, &cfg), vec![
+            Segment { start: 170, end: 263, status: AllocStatus::Free },
+        ]);
+    }
+
+    #[test]
+    fn fill_top_more_than_available()
+    {
+        use super::*;
+
+        let mut map = UsageMap::new(100);
+        map.update(10, 10, AllocStatus::Used);
+
+        let cfg = Config { fill_top: Some(5), ..Config::default() };
+
+        assert_eq!(select_segments(&map, &cfg).len(), 2);
     }
 }
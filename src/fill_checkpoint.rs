@@ -0,0 +1,141 @@
+use std::fs::File;
+use std::io::{BufReader, Read, Write};
+use std::path::Path;
+use anyhow::bail;
+
+use crate::map_format;
+use crate::usage_map::{AllocStatus, Segment, UsageMap};
+
+// Fill checkpoint format (all integers are little-endian):
+//
+//  * magic: the bytes `FSFK`,
+//  * version: u8, currently 1,
+//  * seed: 32 bytes, the seed of the random fill modes,
+//  * pass count: u64, including the final zero pass, if any,
+//  * pass: u64, the pass that was interrupted,
+//  * the segments left to fill in that pass, as the free segments of a usage map in the binary map
+//    format (see map_format), the size of the drive.
+
+const MAGIC: &[u8; 4] = b"FSFK";
+const VERSION: u8 = 1;
+
+/// The saved progress of a fill.
+#[derive(Debug)]
+pub struct Checkpoint {
+    pub seed: [u8; 32],
+    pub pass_count: u64,
+    /// The pass that was interrupted.
+    pub pass: u64,
+    /// The segments left to fill in the interrupted pass.
+    pub remaining: Vec<Segment>,
+}
+
+/// Saves the fill progress, for a drive of `drive_size` bytes, replacing the old checkpoint
+/// atomically.
+pub fn save(path: &Path, checkpoint: &Checkpoint, drive_size: u64) -> anyhow::Result<()>
+{
+    let mut map = UsageMap::new(drive_size);
+    map.update(0, drive_size, AllocStatus::Used);
+    for seg in &checkpoint.remaining {
+        map.update(seg.start, seg.end - seg.start, AllocStatus::Free);
+    }
+
+    crate::util::write_atomically(path, |w| {
+        w.write_all(MAGIC)?;
+        w.write_all(&[VERSION])?;
+        w.write_all(&checkpoint.seed)?;
+        w.write_all(&checkpoint.pass_count.to_le_bytes())?;
+        w.write_all(&checkpoint.pass.to_le_bytes())?;
+        map_format::write_bin(&map, None, w)?;
+
+        Ok(())
+    })
+}
+
+/// Loads the fill progress, checking it against the size of the drive.
+pub fn load(path: &Path, drive_size: u64) -> anyhow::Result<Checkpoint>
+{
+    let mut r = BufReader::new(File::open(path)?);
+
+    let mut magic = [0u8; 4];
+    r.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        bail!("not a fill checkpoint: bad magic value");
+    }
+
+    let mut version = [0u8; 1];
+    r.read_exact(&mut version)?;
+    if version[0] != VERSION {
+        bail!("unsupported fill checkpoint version: {}", version[0]);
+    }
+
+    let mut seed = [0u8; 32];
+    r.read_exact(&mut seed)?;
+
+    let mut buf = [0u8; 8];
+    r.read_exact(&mut buf)?;
+    let pass_count = u64::from_le_bytes(buf);
+    r.read_exact(&mut buf)?;
+    let pass = u64::from_le_bytes(buf);
+
+    let (map, _) = map_format::read_bin(&mut r)?;
+    if map.size() != drive_size {
+        bail!("fill checkpoint map size ({}) does not match the drive size ({})", map.size(), drive_size);
+    }
+
+    let remaining = map.iter_free().copied().collect();
+
+    Ok(Checkpoint { seed, pass_count, pass, remaining })
+}
+
+/// Returns the parts of the segments from `next` on, i.e. those not filled before an interrupt.
+pub fn remaining_segments(segments: &[Segment], next: u64) -> Vec<Segment>
+{
+    segments.iter()
+        .filter(|s| s.end > next)
+        .map(|s| Segment { start: std::cmp::max(s.start, next), ..*s })
+        .collect()
+}
+
+// Tests.
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn save_load()
+    {
+        let seg = |start, end| Segment { start, end, status: AllocStatus::Free };
+        let path = crate::util::temp_path();
+        let checkpoint = Checkpoint {
+            seed: [9; 32],
+            pass_count: 3,
+            pass: 1,
+            remaining: vec![seg(100, 200), seg(300, 1000)],
+        };
+
+        save(&path, &checkpoint, 1000).unwrap();
+        let loaded = load(&path, 1000);
+        let wrong_size = load(&path, 2000);
+        std::fs::remove_file(&path).unwrap();
+
+        let loaded = loaded.unwrap();
+        assert_eq!(loaded.seed, [9; 32]);
+        assert_eq!((loaded.pass_count, loaded.pass), (3, 1));
+        assert_eq!(loaded.remaining, checkpoint.remaining);
+        assert!(wrong_size.is_err());
+    }
+
+    #[test]
+    fn remaining()
+    {
+        let seg = |start, end| Segment { start, end, status: AllocStatus::Free };
+        let segments = [seg(0, 10), seg(20, 30), seg(40, 50)];
+
+        assert_eq!(remaining_segments(&segments, 20), vec![seg(20, 30), seg(40, 50)]);
+        assert_eq!(remaining_segments(&segments, 25), vec![seg(25, 30), seg(40, 50)]);
+        assert_eq!(remaining_segments(&segments, 0), segments.to_vec());
+        assert_eq!(remaining_segments(&segments, 50), vec![]);
+    }
+}
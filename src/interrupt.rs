@@ -0,0 +1,83 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Set by SIGINT and SIGTERM: the scan is to stop before the next block group or MMP check, and the
+/// fill before the next write or clear.
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// The error of a fill stopped by an interrupt.
+#[derive(Debug)]
+pub struct Interrupted {
+    /// The first byte that was not filled.
+    pub next: u64,
+}
+
+impl std::fmt::Display for Interrupted {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result
+    {
+        write!(f, "interrupted before byte {}", self.next)
+    }
+}
+
+impl std::error::Error for Interrupted {}
+
+#[cfg(test)]
+thread_local! {
+    /// The number of checks of the current thread after which an interrupt is reported, in tests.
+    static CHECKS_LEFT: std::cell::Cell<Option<u64>> = const { std::cell::Cell::new(None) };
+}
+
+/// Checks whether an interrupt was received.
+pub fn requested() -> bool
+{
+    #[cfg(test)]
+    if let Some(n) = CHECKS_LEFT.get() {
+        CHECKS_LEFT.set(n.checked_sub(1));
+        if n == 0 {
+            return true;
+        }
+    }
+
+    REQUESTED.load(Ordering::Relaxed)
+}
+
+/// Reports an interrupt in the current thread after `checks` more checks, as if a signal arrived
+/// then. Only the next check after that is interrupted.
+#[cfg(test)]
+pub fn request_after(checks: u64)
+{
+    CHECKS_LEFT.set(Some(checks));
+}
+
+/// Installs the handler of SIGINT and SIGTERM, which lets the scan and the fill stop cleanly.
+/// The handler is reset by the first signal, so that a second one terminates fsfill at once.
+#[cfg(target_os = "linux")]
+pub fn install_handler() -> std::io::Result<()>
+{
+    extern "C" fn handle(_: libc::c_int)
+    {
+        REQUESTED.store(true, Ordering::Relaxed);
+    }
+
+    // SAFETY: the handler only stores into an atomic, which is async-signal-safe. SA_RESTART keeps
+    // the interrupted reads and writes from failing with EINTR.
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = handle as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        action.sa_flags = libc::SA_RESTART | libc::SA_RESETHAND;
+        libc::sigemptyset(&mut action.sa_mask);
+
+        for signal in [libc::SIGINT, libc::SIGTERM] {
+            if libc::sigaction(signal, &action, std::ptr::null_mut()) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn install_handler() -> std::io::Result<()>
+{
+    Ok(())
+}
@@ -0,0 +1,41 @@
+use anyhow::bail;
+use clap::ArgEnum;
+
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+mod uring;
+
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+pub use uring::{read_ahead, UringWriter};
+
+/// The ways in which the reads of the scan and the writes of the fill are issued.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ArgEnum)]
+pub enum IoEngine {
+    /// One blocking read or write at a time.
+    Sync,
+    /// Many reads or writes in flight at once, queued to io_uring (Linux only, built with the
+    /// `io-uring` feature).
+    #[clap(name = "io-uring")]
+    IoUring,
+}
+
+impl IoEngine {
+    /// Checks that the engine is built in.
+    pub fn check(self) -> anyhow::Result<()>
+    {
+        if self == IoEngine::IoUring && !cfg!(all(target_os = "linux", feature = "io-uring")) {
+            bail!("the io-uring I/O engine is not built in (see the `io-uring` feature)");
+        }
+
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for IoEngine {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result
+    {
+        match self {
+            IoEngine::Sync => write!(f, "sync"),
+            IoEngine::IoUring => write!(f, "io-uring"),
+        }
+    }
+}
@@ -0,0 +1,262 @@
+use std::io::{Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::os::unix::io::AsRawFd;
+use io_uring::{opcode, squeue, types, IoUring};
+
+use crate::drive::Drive;
+
+/// The number of operations in flight at once.
+const QUEUE_DEPTH: usize = 32;
+
+/// The size of the buffer of each operation.
+const CHUNK_SIZE: usize = 128 * 1024;
+
+/// A ring with a buffer for each operation in flight.
+/// The buffers must outlive the operations that use them: the ring waits for all of them before
+/// it is dropped.
+struct Queue {
+    ring: IoUring,
+    bufs: Vec<Vec<u8>>,
+    /// The length of the operation of each buffer.
+    lens: Vec<usize>,
+    free: Vec<usize>,
+    in_flight: usize,
+    /// Operations that transfer less than their whole buffer do not fail (e.g. reads at the end of
+    /// the drive).
+    allow_short: bool,
+}
+
+impl Queue {
+    fn new(allow_short: bool) -> std::io::Result<Self>
+    {
+        Ok(Self {
+            ring: IoUring::new(QUEUE_DEPTH as u32)?,
+            bufs: vec![vec![0; CHUNK_SIZE]; QUEUE_DEPTH],
+            lens: vec![0; QUEUE_DEPTH],
+            free: (0..QUEUE_DEPTH).collect(),
+            in_flight: 0,
+            allow_short,
+        })
+    }
+
+    /// Returns a free buffer, waiting for an operation to complete if there is none.
+    fn next_buf(&mut self) -> std::io::Result<usize>
+    {
+        if self.free.is_empty() {
+            self.wait_one()?;
+        }
+
+        Ok(self.free.pop().unwrap())
+    }
+
+    /// Queues the operation of a buffer and submits it.
+    fn push(&mut self, buf: usize, len: usize, entry: squeue::Entry) -> std::io::Result<()>
+    {
+        self.lens[buf] = len;
+        self.in_flight += 1;
+
+        // SAFETY: the entry points into `self.bufs[buf]`, which is not used until the operation
+        // completes, and which outlives it (see `Drop`). There is a submission slot for every
+        // buffer, so the queue is never full.
+        unsafe {
+            self.ring.submission().push(&entry.user_data(buf as u64)).unwrap();
+        }
+        self.ring.submit()?;
+
+        Ok(())
+    }
+
+    /// Waits for an operation to complete and frees its buffer.
+    fn wait_one(&mut self) -> std::io::Result<()>
+    {
+        self.ring.submit_and_wait(1)?;
+        let entry = self.ring.completion().next().unwrap();
+        let buf = entry.user_data() as usize;
+        self.free.push(buf);
+        self.in_flight -= 1;
+
+        match entry.result() {
+            n if n < 0 => Err(std::io::Error::from_raw_os_error(-n)),
+            n if n as usize != self.lens[buf] && !self.allow_short => {
+                Err(std::io::ErrorKind::WriteZero.into())
+            },
+            _ => Ok(()),
+        }
+    }
+
+    /// Waits for all the operations in flight, returning the first error.
+    fn drain(&mut self) -> std::io::Result<()>
+    {
+        let mut result = Ok(());
+
+        while self.in_flight > 0 {
+            if let Err(e) = self.wait_one() {
+                if result.is_ok() {
+                    result = Err(e);
+                }
+            }
+        }
+
+        result
+    }
+}
+
+impl Drop for Queue {
+    fn drop(&mut self)
+    {
+        // Only the buffers matter here: the errors were reported, or the fill failed already.
+        let _ = self.drain();
+    }
+}
+
+/// Reads the ranges of the drive with many reads in flight at once, discarding the data, so that
+/// the scan then finds it in the page cache. The ranges are read to their end or to the end of the
+/// drive.
+pub fn read_ahead(drive: &Drive, ranges: &[Range<u64>]) -> std::io::Result<()>
+{
+    let mut queue = Queue::new(true)?;
+    let fd = types::Fd(drive.file().as_raw_fd());
+
+    for range in ranges {
+        for start in (range.start..range.end).step_by(CHUNK_SIZE) {
+            let len = std::cmp::min(CHUNK_SIZE as u64, range.end - start) as usize;
+            let buf = queue.next_buf()?;
+            let entry = opcode::Read::new(fd, queue.bufs[buf].as_mut_ptr(), len as u32)
+                .offset(drive.offset() + start)
+                .build();
+            queue.push(buf, len, entry)?;
+        }
+    }
+
+    queue.drain()
+}
+
+/// A writer of the drive with many writes in flight at once. Consecutive writes are gathered into
+/// buffers of CHUNK_SIZE bytes. The writes are only known to be done after a flush; the writes
+/// that are not flushed are submitted when the writer is dropped, and their errors are lost.
+pub struct UringWriter<'a> {
+    queue: Queue,
+    drive: &'a Drive,
+    /// The position of the next byte written.
+    pos: u64,
+    /// The buffer being gathered, and its length.
+    buf: Option<(usize, usize)>,
+}
+
+impl<'a> UringWriter<'a> {
+    pub fn new(drive: &'a Drive) -> std::io::Result<Self>
+    {
+        Ok(Self { queue: Queue::new(false)?, drive, pos: 0, buf: None })
+    }
+
+    /// Submits the buffer being gathered, if any.
+    fn submit(&mut self) -> std::io::Result<()>
+    {
+        let (buf, len) = match self.buf.take() {
+            Some(b) => b,
+            None => return Ok(()),
+        };
+
+        let start = self.pos - len as u64;
+        if let Err(e) = self.drive.check_range(start, len as u64) {
+            self.queue.free.push(buf);
+            return Err(e);
+        }
+
+        let fd = types::Fd(self.drive.file().as_raw_fd());
+        let entry = opcode::Write::new(fd, self.queue.bufs[buf].as_ptr(), len as u32)
+            .offset(self.drive.offset() + start)
+            .build();
+
+        self.queue.push(buf, len, entry)
+    }
+}
+
+impl Write for UringWriter<'_> {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize>
+    {
+        let (buf, len) = match self.buf {
+            Some(b) => b,
+            None => (self.queue.next_buf()?, 0),
+        };
+
+        let n = std::cmp::min(data.len(), CHUNK_SIZE - len);
+        self.queue.bufs[buf][len..len + n].copy_from_slice(&data[..n]);
+        self.buf = Some((buf, len + n));
+        self.pos += n as u64;
+
+        if len + n == CHUNK_SIZE {
+            self.submit()?;
+        }
+
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()>
+    {
+        self.submit()?;
+        self.queue.drain()
+    }
+}
+
+impl Drop for UringWriter<'_> {
+    fn drop(&mut self)
+    {
+        let _ = self.submit();
+    }
+}
+
+impl Seek for UringWriter<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64>
+    {
+        match pos {
+            SeekFrom::Start(n) if n == self.pos => (),
+            SeekFrom::Start(n) => {
+                self.submit()?;
+                self.pos = n;
+            },
+            _ => return Err(std::io::ErrorKind::Unsupported.into()),
+        }
+
+        Ok(self.pos)
+    }
+}
+
+// Tests.
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::*;
+    use crate::drive::Window;
+    use crate::util::temp_drive;
+
+    #[test]
+    fn write()
+    {
+        let mut drive = Drive::new(temp_drive(&[0; 3 * CHUNK_SIZE]));
+        drive.set_window(Some(Window { offset: 16, len: 3 * CHUNK_SIZE as u64 - 16 })).unwrap();
+
+        let data: Vec<u8> = (0..2 * CHUNK_SIZE + 100).map(|i| i as u8).collect();
+        let mut w = UringWriter::new(&drive).unwrap();
+        w.seek(SeekFrom::Start(10)).unwrap();
+        w.write_all(&data).unwrap();
+        w.flush().unwrap();
+
+        // Writes past the end of the window fail.
+        w.seek(SeekFrom::Start(3 * CHUNK_SIZE as u64 - 20)).unwrap();
+        w.write_all(&[0xff; 8]).unwrap();
+        assert!(w.flush().is_err());
+        drop(w);
+
+        drive.set_window(None).unwrap();
+        let mut buf = Vec::new();
+        drive.read_to_end(&mut buf).unwrap();
+        assert!(buf[..26].iter().all(|b| *b == 0));
+        assert_eq!(&buf[26..26 + data.len()], &data[..]);
+        assert!(buf[26 + data.len()..].iter().all(|b| *b == 0));
+
+        read_ahead(&drive, &[0..100, 1000..3 * CHUNK_SIZE as u64 + 5000]).unwrap();
+    }
+}
@@ -1,8 +1,14 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::fs::{OpenOptions, File};
+use std::io::{BufReader, Seek, SeekFrom, Write};
+use std::ops::Range;
 use clap::Parser;
-use anyhow::anyhow;
+use anyhow::{anyhow, bail};
 
+mod anomaly;
+mod audit;
+mod container;
+mod drive;
 mod filesys;
 mod array;
 mod logger;
@@ -10,10 +16,31 @@ mod fill;
 mod usage_map;
 mod util;
 mod bitmap;
+mod map_format;
+mod map_diff;
+mod partition;
+mod metrics;
+mod progress;
+mod interrupt;
+mod fill_checkpoint;
+mod io_engine;
 
-use filesys::FsType;
+use anomaly::Anomalies;
+use drive::{Drive, Window};
+use filesys::Registry;
+use filesys::e2fs::backup::StaleBackups;
+use filesys::e2fs::Encoding;
+use filesys::e2fs::freecount::FreeCounts;
+use filesys::e2fs::layout::SpaceSummary;
+use filesys::e2fs::mmp::{self, MmpClaim};
+use filesys::e2fs::scrub::{self, ScrubWrite};
 use logger::Logger;
-use fill::FillMode;
+use fill::{FillMode, FillPass, FillStats, Preset};
+use io_engine::IoEngine;
+use map_format::{FsIdentity, OutputFormat, Report};
+use progress::{Progress, ProgressFormat};
+use partition::PartitionTable;
+use usage_map::UsageMap;
 
 /// Command line argument configuration.
 #[derive(Debug, Parser)]
@@ -35,13 +62,108 @@ struct Args {
     #[clap(short, long)]
     report_only: bool,
 
+    /// Read the free space and report the segments that are not zeroed, with the entropy of their
+    /// contents, instead of the usage map
+    #[clap(long, requires = "report-only", conflicts_with = "output-format")]
+    audit_free: bool,
+
+    /// Compare the usage map with an older one loaded from a file (as with --from-map) and report
+    /// the segments that changed status, instead of the usage map
+    #[clap(
+        long,
+        parse(from_os_str),
+        value_name = "OLD",
+        requires = "report-only",
+        conflicts_with_all = &["output-format", "audit-free"],
+    )]
+    diff: Option<PathBuf>,
+
     /// Prettify the output (when using --report-only)
     #[clap(short, long)]
     pretty: bool,
 
-    /// Type of file system
-    #[clap(short = 't', long = "type", arg_enum, value_name = "TYPE")]
-    fs_type: Option<FsType>,
+    /// Format of the usage map (when using --report-only)
+    #[clap(long, arg_enum, value_name = "FORMAT")]
+    output_format: Option<OutputFormat>,
+
+    /// Print an estimate of the free space from the file system's own counts, without scanning
+    #[clap(long, conflicts_with = "from-map")]
+    quick_estimate: bool,
+
+    /// Load the usage map from a file (in the bin format, as JSON or from a report) instead of
+    /// scanning the drive; the fill scans the drive as well and fills only the space free in both
+    #[clap(long, visible_alias = "map-file", parse(from_os_str), value_name = "FILE")]
+    from_map: Option<PathBuf>,
+
+    /// Mark the space used in the usage map from a file (as with --from-map) as used, e.g. to
+    /// exclude it from the fill (can be repeated)
+    #[clap(long, parse(from_os_str), value_name = "FILE", multiple_occurrences = true)]
+    union: Vec<PathBuf>,
+
+    /// Keep only the space that is also used in the usage map from a file as used (can be
+    /// repeated); the result can only narrow down the free space of a fill, when loaded with
+    /// --from-map
+    #[clap(
+        long,
+        parse(from_os_str),
+        value_name = "FILE",
+        multiple_occurrences = true,
+        requires = "report-only",
+    )]
+    intersect: Vec<PathBuf>,
+
+    /// Mark the space used in the usage map from a file as free (can be repeated); the result can
+    /// only narrow down the free space of a fill, when loaded with --from-map
+    #[clap(
+        long,
+        parse(from_os_str),
+        value_name = "FILE",
+        multiple_occurrences = true,
+        requires = "report-only",
+    )]
+    subtract: Vec<PathBuf>,
+
+    /// Write the scan results as Prometheus metrics into a file
+    #[clap(long, parse(from_os_str), value_name = "FILE")]
+    metrics: Option<PathBuf>,
+
+    /// Type of file system (skips the detection)
+    #[clap(short = 't', long = "type", value_name = "TYPE")]
+    fs_type: Option<String>,
+
+    /// Process the drive as this file system type, without validating the identifying superblock fields
+    #[clap(long, value_name = "TYPE", conflicts_with = "fs-type")]
+    force_fs_type: Option<String>,
+
+    /// Scan (and fill) only the Nth partition of a partitioned drive
+    #[clap(long, value_name = "N")]
+    partition: Option<u32>,
+
+    /// Scan every partition of a partitioned drive and fill them all; partitions without a
+    /// recognised file system are left untouched
+    #[clap(
+        long,
+        conflicts_with_all = &[
+            "partition",
+            "fs-type",
+            "force-fs-type",
+            "from-map",
+            "quick-estimate",
+            "scan-checkpoint",
+        ],
+    )]
+    all_partitions: bool,
+
+    /// Also fill the gaps between the partitions of a partitioned drive; without --partition,
+    /// only the gaps are filled. The sectors in front of the first partition of an MBR drive,
+    /// where boot loaders such as GRUB are embedded, are kept
+    #[clap(long, conflicts_with_all = &["from-map", "quick-estimate"])]
+    wipe_partition_gaps: bool,
+
+    /// If the drive is a LUKS container, operate on its opened dm-crypt mapper device instead
+    /// (Linux only)
+    #[clap(long)]
+    luks_mapper: bool,
 
     /// Ignore the recovery error
     #[clap(short = 'R', long)]
@@ -51,6 +173,76 @@ struct Args {
     #[clap(short = 'O', long)]
     ignore_readonly: bool,
 
+    /// Treat recoverable inconsistencies in the file system as errors
+    #[clap(long)]
+    strict: bool,
+
+    /// Warn about metadata with invalid checksums and use it anyway, instead of failing
+    #[clap(long)]
+    ignore_csum_errors: bool,
+
+    /// Save the scan progress into a file and resume the scan from it, if it exists
+    #[clap(long, parse(from_os_str), value_name = "FILE")]
+    scan_checkpoint: Option<PathBuf>,
+
+    /// Compare the backup superblocks against the primary one and report any differences
+    #[clap(long)]
+    check_backups: bool,
+
+    /// Zero the inodes of deleted files, which keep their timestamps, sizes and block pointers
+    /// (ext2/3/4 only)
+    #[clap(long)]
+    scrub_inode_table: bool,
+
+    /// Zero the names of deleted directory entries, which stay in the directory blocks after
+    /// unlinking (ext2/3/4 only)
+    #[clap(long)]
+    scrub_dirents: bool,
+
+    /// Fill the slack past the end of each file as well: the rest of its last block and the
+    /// blocks allocated beyond it (ext2/3/4 only)
+    #[clap(long)]
+    wipe_slack: bool,
+
+    /// Compare the block bitmaps against the space referenced by the inodes, report any
+    /// discrepancies and refuse to fill if there are any
+    #[clap(long)]
+    cross_check: bool,
+
+    /// What to do with backup superblocks that have a bad magic value or checksum: keep them,
+    /// ignore them (and fill over them) or refresh them from the primary copies (before the fill,
+    /// once the device is confirmed)
+    #[clap(long, arg_enum, value_name = "POLICY")]
+    stale_backups: Option<StaleBackups>,
+
+    /// Do not read the metadata of the next block group ahead while scanning
+    #[clap(long)]
+    no_readahead: bool,
+
+    /// The I/O engine of the read-ahead of the scan and of the writes of the fill; io-uring keeps
+    /// many operations in flight at once (Linux only, with the io-uring feature)
+    #[clap(long, arg_enum, value_name = "ENGINE", default_value = "sync")]
+    io_engine: IoEngine,
+
+    /// Scan file systems with unknown incompatible features conservatively, from the block
+    /// bitmaps only (report only)
+    #[clap(long)]
+    allow_unknown_incompat: bool,
+
+    /// Scan file systems with encryption (fscrypt); encrypted inodes are mapped from their
+    /// extent trees and block maps only
+    #[clap(long)]
+    allow_encrypted: bool,
+
+    /// Scan file systems with blocks shared between files (shared_blocks); the block allocation
+    /// is not altered by the fill
+    #[clap(long)]
+    allow_shared_blocks: bool,
+
+    /// Maximum number of extent tree nodes read per inode
+    #[clap(long, value_name = "N")]
+    max_extent_nodes: Option<usize>,
+
     /// Set verbosity of the output (can be used multiple times)
     #[clap(short, long, parse(from_occurrences))]
     verbose: u32,
@@ -59,9 +251,135 @@ struct Args {
     #[clap(short, long, parse(from_os_str), value_name = "FILE")]
     log_file: Option<PathBuf>,
 
+    /// Report the progress of the scan and the fill as events in this format, on stderr
+    #[clap(long, arg_enum, value_name = "FORMAT")]
+    progress_format: Option<ProgressFormat>,
+
+    /// Write the progress events into this file (e.g. a FIFO) instead of stderr
+    #[clap(long, parse(from_os_str), value_name = "FILE", requires = "progress-format")]
+    progress_file: Option<PathBuf>,
+
     /// Mode of disk filling
-    #[clap(short, long, arg_enum, value_name = "MODE")]
-    fill_mode: Option<FillMode>
+    #[clap(short, long, arg_enum, value_name = "MODE", requires_if("pattern", "pattern"))]
+    fill_mode: Option<FillMode>,
+
+    /// Fill profile, setting the fill mode and the verification. Both are NIST SP 800-88 Clear; a
+    /// Purge of flash media takes the drive's cryptographic erase or sanitize commands
+    #[clap(long, arg_enum, value_name = "PRESET", conflicts_with = "fill-mode")]
+    preset: Option<Preset>,
+
+    /// Passes of the fill, in order, as fill modes separated by commas, each optionally followed
+    /// by :verify (e.g. zero,chacha20:verify,zero)
+    #[clap(
+        long,
+        value_name = "PASSES",
+        use_value_delimiter = true,
+        parse(try_from_str = fill::parse_pass),
+        conflicts_with_all = &["fill-mode", "preset"],
+    )]
+    passes: Vec<FillPass>,
+
+    /// Byte with which the pattern mode fills the free space, in decimal or hexadecimal (0xff)
+    #[clap(long, value_name = "BYTE", parse(try_from_str = fill::parse_byte), group = "pattern")]
+    pattern_byte: Option<u8>,
+
+    /// File whose contents the pattern mode repeats over the free space
+    #[clap(long, parse(from_os_str), value_name = "FILE", group = "pattern")]
+    pattern_file: Option<PathBuf>,
+
+    /// Seed of the random fill modes, as 64 hexadecimal digits, for a reproducible fill
+    #[clap(long, value_name = "HEX", parse(try_from_str = fill::parse_seed))]
+    seed: Option<[u8; 32]>,
+
+    /// Zero the free space after a random fill, e.g. for thin-provisioned storage to reclaim it
+    #[clap(long)]
+    final_zero: bool,
+
+    /// Punch holes into an image file instead of writing zeroes, so that the image shrinks on the
+    /// host file system (Linux only)
+    #[clap(long)]
+    sparsify: bool,
+
+    /// Read the free space back after the fill and compare it against the fill data
+    #[clap(long)]
+    verify: bool,
+
+    /// After the fill, read back N random samples of each free segment and compare them against
+    /// the fill data, instead of the whole free space
+    #[clap(long, value_name = "N", conflicts_with = "verify")]
+    spot_check: Option<usize>,
+
+    /// Fill with N threads, each writing its own part of the free space with positioned writes
+    /// and its own random stream
+    #[clap(long, value_name = "N", default_value = "1", parse(try_from_str = fill::parse_threads))]
+    threads: usize,
+
+    /// With the zero fill mode, read the free space first and write only the blocks that are not
+    /// already all zeroes, to spare the writes (and the wear of SSDs) on repeated runs
+    #[clap(long)]
+    skip_zeroed: bool,
+
+    /// Verify the fill data before writing it (currently: that the zero fill writes only zeroes)
+    #[clap(long)]
+    paranoid: bool,
+
+    /// Fill only the N largest free segments
+    #[clap(long, value_name = "N")]
+    fill_top: Option<usize>,
+
+    /// Fill only the free space within this byte range, with the end exclusive, e.g. 1G-2G (can
+    /// be used multiple times)
+    #[clap(long, value_name = "START-END", parse(try_from_str = fill::parse_range))]
+    range: Vec<Range<u64>>,
+
+    /// Skip the free segments smaller than this many bytes, e.g. 4K (suffixes: K, M, G, T)
+    #[clap(long, value_name = "BYTES", parse(try_from_str = fill::parse_size))]
+    min_segment_size: Option<u64>,
+
+    /// Fill at most this many bytes, largest free segments first, e.g. 100G (suffixes: K, M, G, T)
+    #[clap(long, value_name = "BYTES", parse(try_from_str = fill::parse_size), group = "budget")]
+    max_fill_bytes: Option<u64>,
+
+    /// Fill only this percentage of the free space, largest free segments first
+    #[clap(
+        long,
+        value_name = "PERCENT",
+        parse(try_from_str = fill::parse_percent),
+        group = "budget",
+    )]
+    fill_percent: Option<u8>,
+
+    /// Write the fill with O_DIRECT, bypassing the page cache, so that the fill of a whole device
+    /// does not evict the page cache of the host (Linux only)
+    #[clap(long)]
+    direct_io: bool,
+
+    /// Fill with the idle I/O scheduling class and the lowest CPU priority, so that the fill barely
+    /// impacts the other work of the host (Linux only)
+    #[clap(long)]
+    idle_io: bool,
+
+    /// Write synchronously and flush the drive cache at the end of the fill (Linux only; slow)
+    #[clap(long)]
+    barrier: bool,
+
+    /// Fill only if this matches the drive path (as given or resolved), the device serial number or
+    /// the file system UUID
+    #[clap(long, value_name = "STRING")]
+    confirm_device: Option<String>,
+
+    /// File into which the progress of the fill is saved when it is interrupted (SIGINT or SIGTERM)
+    /// [default: fsfill-fill.ckpt]
+    #[clap(long, parse(from_os_str), value_name = "FILE")]
+    fill_checkpoint: Option<PathBuf>,
+
+    /// Resume an interrupted fill from its checkpoint, filling only the segments left
+    #[clap(long, parse(from_os_str), value_name = "FILE")]
+    resume: Option<PathBuf>,
+
+    /// Measure the write throughput at the start of the fill and estimate its duration
+    #[clap(long)]
+    probe: bool,
 }
 
 fn main()
@@ -70,19 +388,89 @@ fn main()
 
     // Process the command line arguments.
 
-    let mut cfg = Config::default();
-    cfg.cmd_name = std::env::args().nth(0).unwrap();
-    cfg.drive_path = args.drive;
+    let mut cfg = Config {
+        cmd_name: std::env::args().next().unwrap(),
+        drive_path: args.drive,
+        ..Config::default()
+    };
     cfg.report_only = args.report_only;
     cfg.verbosity = args.verbose;
     cfg.log_file_path = args.log_file;
+    cfg.progress_format = args.progress_format;
+    cfg.progress_path = args.progress_file;
     cfg.ignore_recovery = args.ignore_recovery;
     cfg.ignore_readonly = args.ignore_readonly;
+    cfg.strict = args.strict;
+    cfg.ignore_csum_errors = args.ignore_csum_errors;
+    cfg.check_backups = args.check_backups;
+    cfg.cross_check = args.cross_check;
+    cfg.scrub_inode_table = args.scrub_inode_table;
+    cfg.scrub_dirents = args.scrub_dirents;
+    cfg.wipe_slack = args.wipe_slack;
+    cfg.allow_unknown_incompat = args.allow_unknown_incompat;
+    cfg.allow_encrypted = args.allow_encrypted;
+    cfg.allow_shared_blocks = args.allow_shared_blocks;
+    cfg.force_fs_type = args.force_fs_type.is_some();
     cfg.pretty = args.pretty;
+    cfg.audit_free = args.audit_free;
+    cfg.diff_path = args.diff;
+    cfg.map_path = args.from_map;
+    cfg.union_paths = args.union;
+    cfg.intersect_paths = args.intersect;
+    cfg.subtract_paths = args.subtract;
+    cfg.quick_estimate = args.quick_estimate;
+    cfg.metrics_path = args.metrics;
+    cfg.scan_checkpoint = args.scan_checkpoint;
+    cfg.readahead = !args.no_readahead;
+    cfg.io_engine = args.io_engine;
+    cfg.fill_top = args.fill_top;
+    cfg.ranges = args.range;
+    cfg.min_segment_size = args.min_segment_size.unwrap_or(0);
+    cfg.max_fill_bytes = args.max_fill_bytes;
+    cfg.fill_percent = args.fill_percent;
+    cfg.paranoid = args.paranoid;
+    cfg.skip_zeroed = args.skip_zeroed;
+    cfg.probe = args.probe;
+    cfg.fill_checkpoint = args.fill_checkpoint;
+    cfg.resume = args.resume;
+    cfg.barrier = args.barrier;
+    cfg.idle_io = args.idle_io;
+    cfg.direct_io = args.direct_io;
+    cfg.confirm_device = args.confirm_device;
+    cfg.partition = args.partition;
+    cfg.wipe_partition_gaps = args.wipe_partition_gaps;
+    cfg.all_partitions = args.all_partitions;
+    cfg.luks_mapper = args.luks_mapper;
 
+    if let Some(n) = args.max_extent_nodes {
+        cfg.max_extent_nodes = n;
+    }
     if let Some(mode) = args.fill_mode {
         cfg.fill_mode = mode;
     }
+    if let Some(b) = args.pattern_byte {
+        cfg.pattern_byte = b;
+    }
+    cfg.passes = args.passes;
+    cfg.pattern_file = args.pattern_file;
+    cfg.seed = args.seed;
+    cfg.verify = args.verify;
+    cfg.spot_check = args.spot_check;
+    cfg.threads = args.threads;
+    cfg.final_zero = args.final_zero;
+    cfg.sparsify = args.sparsify;
+    if let Some(preset) = args.preset {
+        preset.apply(&mut cfg);
+    }
+    if let Some(format) = args.output_format {
+        cfg.output_format = format;
+    }
+    if let Some(policy) = args.stale_backups {
+        cfg.stale_backups = policy;
+    }
+
+    let registry = Registry::default();
+    let fs_type_arg = args.force_fs_type.as_deref().or(args.fs_type.as_deref());
 
     let mut log_file = None;
 
@@ -93,7 +481,7 @@ fn main()
             .create(true)
             .append(true)
             .read(false)
-            .open(&path);
+            .open(path);
 
         log_file = match f {
             Ok(f) => Some(f),
@@ -107,15 +495,31 @@ fn main()
 
     let mut logger = Logger::new(log_file, &cfg);
 
-    // Open the drive.
+    if let Err(e) = cfg.io_engine.check() {
+        logger.logln(0, &format!("{}: {}", cfg.cmd_name, e));
+        std::process::exit(1);
+    }
 
-    let drive = OpenOptions::new()
-        .create(false)
-        .read(true)
-        .write(!cfg.report_only)
-        .open(&cfg.drive_path);
+    if let Err(e) = progress::install_status_handler() {
+        logger.logln(0, &format!("warning: cannot install the SIGUSR1 handler: {}", e));
+    }
+
+    // Open the progress file. Opening a FIFO waits for its reader.
+
+    let progress_file = match &cfg.progress_path {
+        Some(path) => match OpenOptions::new().create(true).append(true).open(path) {
+            Ok(f) => Some(f),
+            Err(e) => {
+                logger.logln(0, &format!("{}: {}: {}", cfg.cmd_name, &path.display(), &e));
+                std::process::exit(1);
+            },
+        },
+        None => None,
+    };
 
-    let drive = match drive {
+    // Open the drive.
+
+    let drive = match open_drive(&cfg.drive_path, &cfg) {
         Ok(f) => f,
         Err(e) => {
             logger.logln(0, &format!("{}: {}: {}", cfg.cmd_name, &cfg.drive_path.display(), &e));
@@ -124,93 +528,773 @@ fn main()
     };
 
     let mut context = Context {
-        drive,
+        drive: Drive::new(drive),
         logger,
+        anomalies: Anomalies::default(),
+        space: None,
+        free_counts: None,
+        encoding: None,
+        fs_identity: None,
+        mmp: Vec::new(),
+        scrub_writes: Vec::new(),
+        fill_stats: FillStats::default(),
+        progress: Progress::new(cfg.progress_format, progress_file),
+        direct_drive: None,
     };
 
-    // Set or detect the FS type.
+    // Follow a LUKS container to its mapper device.
 
-    cfg.fs_type = if let Some(fs_type) = args.fs_type {
-        fs_type
+    if cfg.luks_mapper {
+        match follow_luks(&mut context, &cfg) {
+            Ok(path) => cfg.drive_path = path,
+            Err(e) => {
+                context.logger.logln(0, &format!("{}: {}", cfg.cmd_name, &e));
+                std::process::exit(1);
+            },
+        }
+    }
+
+    // Select the partition. The raw type, loaded maps, all the partitions and the gaps may cover a
+    // whole partitioned drive. A loaded map replaces the scan only when reporting: the fill scans the
+    // drive as well and fills only the space that is free in both.
+
+    let load_only = cfg.map_path.is_some() && cfg.report_only;
+    let whole_drive = load_only
+        || cfg.all_partitions
+        || (cfg.wipe_partition_gaps && cfg.partition.is_none())
+        || fs_type_arg == Some("raw");
+
+    let table = match partition::select_partition(&mut context, &cfg, whole_drive) {
+        Ok(None) if cfg.wipe_partition_gaps || cfg.all_partitions => {
+            context.logger.logln(0, &format!("{}: the drive has no partition table", cfg.cmd_name));
+            std::process::exit(1);
+        },
+        Ok(table) => table,
+        Err(e) => {
+            context.logger.logln(0, &format!("{}: {}", cfg.cmd_name, &e));
+            std::process::exit(1);
+        },
+    };
+
+    // The scan may claim the file system (the MMP block of ext4), which must be released if the scan
+    // is interrupted, as must everything the fill has done.
+
+    if !cfg.report_only {
+        if let Err(e) = interrupt::install_handler() {
+            context.logger.logln(0, &format!("warning: cannot install the SIGINT handler: {}", e));
+        }
+    }
+
+    let map = if let Some(path) = cfg.map_path.as_ref().filter(|_| load_only) {
+        // Load the usage map.
+
+        context.logger.logln(0, &format!("=== loading the usage map from {}", path.display()));
+
+        let (map, identity) = load_map(path, &mut context).unwrap_or_else(|e| {
+            context.logger.logln(0, &format!("{}: {}: {}", cfg.cmd_name, path.display(), &e));
+            std::process::exit(1);
+        });
+        context.fs_identity = identity;
+
+        map
+    } else if cfg.all_partitions {
+        // Scan all the partitions.
+
+        let table = table.as_ref().unwrap();
+        scan_all_partitions(table, &registry, &mut context, &cfg).unwrap_or_else(|e| {
+            context.logger.logln(0, &format!("{}: {}", cfg.cmd_name, &e));
+            release_fs(&mut context, &cfg);
+            std::process::exit(1);
+        })
+    } else if cfg.wipe_partition_gaps && cfg.partition.is_none() {
+        // Map the gaps only.
+
+        context.logger.logln(0, "=== mapping the gaps between the partitions");
+
+        partition::drive_map(&mut context, table.as_ref().unwrap(), &[], true).unwrap_or_else(|e| {
+            context.logger.logln(0, &format!("{}: {}", cfg.cmd_name, &e));
+            std::process::exit(1);
+        })
     } else {
-        context.logger.log(0, "=== detecting the file system type: ");
+        // Set or detect the FS type.
 
-        let fs_type = match filesys::detect_fs(&mut context) {
-            Ok(fs_option) => {
-                if let Some(fs_type) = fs_option {
-                    fs_type
-                } else {
-                    context.logger.logln(0, "unknown");
-                    context.logger.logln(0, &format!("{}: aborting", cfg.cmd_name));
+        let scanner = if let Some(name) = fs_type_arg {
+            match registry.get_checked(&mut context, name) {
+                Ok(Some(scanner)) => scanner,
+                Ok(None) => {
+                    context.logger.logln(0, &format!(
+                        "{}: unknown file system type: {} (known types: {})",
+                        cfg.cmd_name,
+                        name,
+                        registry.names().join(", "),
+                    ));
+                    std::process::exit(1);
+                },
+                Err(e) => {
+                    context.logger.logln(0, &format!("{}: {}", cfg.cmd_name, &e));
+                    std::process::exit(1);
+                },
+            }
+        } else {
+            context.logger.log(0, "=== detecting the file system type: ");
+
+            let scanner = match registry.detect(&mut context) {
+                Ok(scanner_option) => {
+                    if let Some(scanner) = scanner_option {
+                        scanner
+                    } else {
+                        context.logger.logln(0, "unknown");
+                        context.logger.logln(0, &format!("{}: aborting", cfg.cmd_name));
+                        std::process::exit(1);
+                    }
+                },
+                Err(e) => {
+                    context.logger.logln(0, &format!("{}: {}", cfg.cmd_name, &e));
                     std::process::exit(1);
                 }
-            },
-            Err(e) => {
+            };
+
+            context.logger.logln(0, scanner.name());
+
+            scanner
+        };
+        cfg.fs_type = scanner.name();
+
+        // Estimate the free space.
+
+        if cfg.quick_estimate {
+            context.logger.logln(0, "=== estimating the free space");
+
+            let estimate = scanner.quick_estimate(&mut context, &cfg).unwrap_or_else(|e| {
                 context.logger.logln(0, &format!("{}: {}", cfg.cmd_name, &e));
                 std::process::exit(1);
+            });
+
+            if cfg.pretty {
+                println!("{}", serde_json::to_string_pretty(&estimate).unwrap());
+            } else {
+                println!("{}", serde_json::to_string(&estimate).unwrap());
             }
-        };
 
-        match fs_type {
-            FsType::Ext2 => context.logger.logln(0, "ext2"),
-            FsType::Ext3 => context.logger.logln(0, "ext3"),
-            FsType::Ext4 => context.logger.logln(0, "ext4"),
+            return;
         }
 
-        fs_type
-    };
+        // Scan the drive.
+
+        context.logger.logln(0, "=== scanning the drive");
+
+        let map = scanner.scan(&mut context, &cfg).unwrap_or_else(|e| {
+            context.logger.logln(0, &format!("{}: {}", cfg.cmd_name, &e));
+            release_fs(&mut context, &cfg);
+            std::process::exit(1);
+        });
+
+        if context.anomalies.is_empty() {
+            context.logger.logln(1, "scan anomalies: none");
+        } else {
+            let summary = context.anomalies.summary();
+            context.logger.logln(0, &format!("scan anomalies: {}", summary));
+        }
+
+        // Map the gaps around the partition.
+
+        if cfg.wipe_partition_gaps {
+            context.logger.logln(0, "=== mapping the gaps between the partitions");
 
-    // Scan the drive.
+            let table = table.as_ref().unwrap();
+            let offset = context.drive.offset();
 
-    context.logger.logln(0, "=== scanning the drive");
+            partition::drive_map(&mut context, table, &[(offset, map)], true).unwrap_or_else(|e| {
+                context.logger.logln(0, &format!("{}: {}", cfg.cmd_name, &e));
+                release_fs(&mut context, &cfg);
+                std::process::exit(1);
+            })
+        } else {
+            map
+        }
+    };
 
-    let map = match cfg.fs_type {
-        FsType::Ext2 |
-        FsType::Ext3 |
-        FsType::Ext4 => filesys::e2fs::scan_drive(&mut context, &cfg),
-        #[allow(unreachable_patterns)]
-        _ => Err(anyhow!("this filesystem is not implemented yet")),
-    }.unwrap_or_else(|e| {
-        context.logger.logln(0, &format!("{}: {}", cfg.cmd_name, &e));
+    if interrupt::requested() {
+        context.logger.logln(0, &format!("{}: interrupted", cfg.cmd_name));
+        release_fs(&mut context, &cfg);
         std::process::exit(1);
-    });
+    }
+
+    // Narrow the loaded usage map down to the space that is still free.
+
+    let map = match &cfg.map_path {
+        Some(path) if !load_only => {
+            context.logger.logln(0, &format!("=== loading the usage map from {}", path.display()));
+
+            let (loaded, identity) = load_map(path, &mut context).unwrap_or_else(|e| {
+                context.logger.logln(0, &format!("{}: {}: {}", cfg.cmd_name, path.display(), &e));
+                release_fs(&mut context, &cfg);
+                std::process::exit(1);
+            });
+
+            let unchecked = if identity.is_none() {
+                Some("the map does not record the file system it was saved from")
+            } else if context.fs_identity.is_none() {
+                Some("the file system has no UUID")
+            } else {
+                None
+            };
+            if let Some(reason) = unchecked {
+                context.logger.logln(0, &format!(
+                    "warning: {}: {}, so the map is not checked against the file system",
+                    path.display(),
+                    reason,
+                ));
+            }
+
+            map.union(&loaded).unwrap_or_else(|e| {
+                context.logger.logln(0, &format!("{}: {}: {}", cfg.cmd_name, path.display(), &e));
+                release_fs(&mut context, &cfg);
+                std::process::exit(1);
+            })
+        },
+        _ => map,
+    };
+
+    // Combine the usage map with the given ones.
+
+    let combined = cfg.union_paths.len() + cfg.intersect_paths.len() + cfg.subtract_paths.len();
+    let map = if combined > 0 {
+        context.logger.logln(0, &format!("=== combining the usage map with {} maps", combined));
+
+        combine_maps(map, &mut context, &cfg).unwrap_or_else(|e| {
+            context.logger.logln(0, &format!("{}: {}", cfg.cmd_name, &e));
+            release_fs(&mut context, &cfg);
+            std::process::exit(1);
+        })
+    } else {
+        map
+    };
+
+    // Export the metrics.
+
+    if let Some(path) = &cfg.metrics_path {
+        let written = File::create(path).and_then(|mut f| {
+            let device = cfg.drive_path.display().to_string();
+            metrics::write_metrics(&mut f, &device, &map, context.space.as_ref())
+        });
+
+        if let Err(e) = written {
+            context.logger.logln(0, &format!("{}: {}: {}", cfg.cmd_name, path.display(), &e));
+            release_fs(&mut context, &cfg);
+            std::process::exit(1);
+        }
+    }
 
     // Report or fill.
 
-    if cfg.report_only {
-        // Print out the usage map in JSON format.
+    if let Some(path) = &cfg.diff_path {
+        // Compare with the old usage map.
+
+        context.logger.logln(0, &format!("=== comparing with the usage map from {}", path.display()));
+
+        let diff = load_map(path, &mut context)
+            .and_then(|(old, _)| map_diff::diff(&old, &map))
+            .unwrap_or_else(|e| {
+                context.logger.logln(0, &format!("{}: {}: {}", cfg.cmd_name, path.display(), &e));
+                std::process::exit(1);
+            });
+        context.logger.logln(0, &format!(
+            "{} segments changed status: {} bytes newly free, {} bytes newly used",
+            diff.changes.len(),
+            diff.newly_free,
+            diff.newly_used,
+        ));
+
+        if cfg.pretty {
+            println!("{}", serde_json::to_string_pretty(&diff).unwrap());
+        } else {
+            println!("{}", serde_json::to_string(&diff).unwrap());
+        }
+    } else if cfg.audit_free {
+        // Audit the contents of the free space.
+
+        context.logger.logln(0, "=== auditing the free space");
+
+        let audit = audit::audit_free(&map, &mut context.drive).unwrap_or_else(|e| {
+            context.logger.logln(0, &format!("{}: {}", cfg.cmd_name, &e));
+            std::process::exit(1);
+        });
+        context.logger.logln(0, &format!(
+            "{} of {} free segments ({} of {} bytes) are not zeroed",
+            audit.nonzero_segments,
+            audit.free_segments,
+            audit.nonzero_bytes,
+            audit.free_bytes,
+        ));
 
         if cfg.pretty {
-            println!("{}", serde_json::to_string_pretty(&map).unwrap());
+            println!("{}", serde_json::to_string_pretty(&audit).unwrap());
         } else {
-            println!("{}", serde_json::to_string(&map).unwrap());
+            println!("{}", serde_json::to_string(&audit).unwrap());
+        }
+    } else if cfg.report_only {
+        // Print out the usage map in the selected format.
+
+        match cfg.output_format {
+            OutputFormat::Json => if cfg.pretty {
+                println!("{}", serde_json::to_string_pretty(&map).unwrap());
+            } else {
+                println!("{}", serde_json::to_string(&map).unwrap());
+            },
+            OutputFormat::Report => {
+                let report = Report {
+                    map: &map,
+                    identity: context.fs_identity.as_ref(),
+                    anomalies: &context.anomalies,
+                    free_counts: context.free_counts.as_ref(),
+                    encoding: context.encoding.as_ref(),
+                };
+
+                if cfg.pretty {
+                    println!("{}", serde_json::to_string_pretty(&report).unwrap());
+                } else {
+                    println!("{}", serde_json::to_string(&report).unwrap());
+                }
+            },
+            OutputFormat::Bin => {
+                let mut stdout = std::io::stdout().lock();
+
+                let written = map_format::write_bin(&map, context.fs_identity.as_ref(), &mut stdout)
+                    .and_then(|_| Ok(stdout.flush()?));
+
+                if let Err(e) = written {
+                    context.logger.logln(0, &format!("{}: {}", cfg.cmd_name, &e));
+                    std::process::exit(1);
+                }
+            },
+            OutputFormat::Ddrescue => {
+                let mut stdout = std::io::stdout().lock();
+
+                if let Err(e) = map_format::write_ddrescue(&map, &mut stdout).and_then(|_| stdout.flush()) {
+                    context.logger.logln(0, &format!("{}: {}", cfg.cmd_name, &e));
+                    std::process::exit(1);
+                }
+            },
         }
     } else {
+        // Check the device confirmation.
+
+        if let Some(confirmation) = &cfg.confirm_device {
+            let confirmed = device_identities(&cfg, &context)
+                .and_then(|ids| check_device_confirmation(confirmation, &ids));
+
+            if let Err(e) = confirmed {
+                context.logger.logln(0, &format!("{}: {}", cfg.cmd_name, &e));
+                context.logger.logln(0, &format!("{}: aborting", cfg.cmd_name));
+                release_fs(&mut context, &cfg);
+                std::process::exit(1);
+            }
+        }
+
+        // Write the scrubbed metadata and the refreshed backups.
+
+        if !context.scrub_writes.is_empty() {
+            context.logger.logln(0, "=== writing the metadata");
+
+            match scrub::apply(&mut context) {
+                Ok(count) => context.logger.logln(1, &format!("wrote {} metadata ranges", count)),
+                Err(e) => {
+                    context.logger.logln(0, &format!("{}: {}", cfg.cmd_name, &e));
+                    release_fs(&mut context, &cfg);
+                    std::process::exit(1);
+                },
+            }
+        }
+
         // Fill the free space.
 
         context.logger.log(0, "=== filling the free space");
-        context.logger.logln(0, &format!("; fill mode: {}", cfg.fill_mode));
+        if cfg.passes.is_empty() {
+            context.logger.logln(0, &format!("; fill mode: {}", cfg.fill_mode));
+        } else {
+            let passes: Vec<String> = cfg.passes.iter().map(|p| p.to_string()).collect();
+            context.logger.logln(0, &format!("; passes: {}", passes.join(", ")));
+        }
 
-        if let Err(e) = fill::fill_free_space(&map, &mut context, &cfg) {
+        if cfg.idle_io {
+            if let Err(e) = set_idle_priority() {
+                context.logger.logln(0, &format!("warning: cannot lower the priority: {}", e));
+            }
+        }
+
+        let stats = fill::fill_free_space(&map, &mut context, &cfg).unwrap_or_else(|e| {
             context.logger.logln(0, &format!("{}: {}", cfg.cmd_name, &e));
+            release_fs(&mut context, &cfg);
+            std::process::exit(1);
+        });
+        context.logger.logln(0, &format!("=== filled: {}", stats));
+
+        if cfg.pretty {
+            println!("{}", serde_json::to_string_pretty(&stats).unwrap());
+        } else {
+            println!("{}", serde_json::to_string(&stats).unwrap());
+        }
+
+        if !release_fs(&mut context, &cfg) {
             std::process::exit(1);
         }
     }
 }
 
+/// Releases the claims that the scan has taken on the file systems (the MMP blocks of ext4), if
+/// any. Returns whether it succeeded.
+fn release_fs(context: &mut Context, cfg: &Config) -> bool
+{
+    let mut released = true;
+
+    for claim in std::mem::take(&mut context.mmp) {
+        if let Err(e) = mmp::release(claim, context) {
+            context.logger.logln(0, &format!("{}: {}", cfg.cmd_name, &e));
+            released = false;
+        }
+    }
+
+    released
+}
+
+/// Opens the drive, for writing unless only reporting.
+fn open_drive(path: &Path, cfg: &Config) -> std::io::Result<File>
+{
+    let mut open_opts = OpenOptions::new();
+    open_opts
+        .create(false)
+        .read(true)
+        .write(!cfg.report_only);
+
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        open_opts.custom_flags(drive_open_flags(cfg));
+    }
+
+    open_opts.open(path)
+}
+
+/// Replaces the drive, which must be a LUKS container, with its opened dm-crypt mapper device and
+/// returns the path of the mapper device.
+fn follow_luks(ctx: &mut Context, cfg: &Config) -> anyhow::Result<PathBuf>
+{
+    let header = match container::luks::read_header(ctx)? {
+        Some(header) => header,
+        None => bail!("the drive is not a LUKS container"),
+    };
+
+    let path = match container::luks::find_mapper(&header)? {
+        Some(path) => path,
+        None => bail!(
+            "the LUKS{} container {} is not opened: open it first (e.g. `cryptsetup open`)",
+            header.version,
+            header.uuid,
+        ),
+    };
+
+    ctx.logger.logln(0, &format!(
+        "=== using the mapper device of the LUKS container: {}",
+        path.display(),
+    ));
+
+    let drive = open_drive(&path, cfg).map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+    ctx.drive = Drive::new(drive);
+
+    Ok(path)
+}
+
+/// Scans every partition of the drive and returns the usage map of the whole drive.
+/// Partitions without a recognised file system are mapped as used. The gaps between the partitions
+/// are mapped as free only with `wipe_partition_gaps`.
+fn scan_all_partitions(
+    table: &PartitionTable,
+    registry: &Registry,
+    ctx: &mut Context,
+    cfg: &Config,
+) -> anyhow::Result<UsageMap>
+{
+    let mut maps = Vec::new();
+
+    for p in &table.partitions {
+        ctx.drive.set_window(Some(Window { offset: p.start, len: p.size }))?;
+
+        ctx.logger.log(0, &format!("=== detecting the file system of partition {}: ", p.number));
+
+        let scanner = match registry.detect(ctx) {
+            Ok(Some(scanner)) => scanner,
+            Ok(None) => {
+                ctx.logger.logln(0, "unknown");
+                ctx.logger.logln(0, &format!(
+                    "WARNING: partition {} has no recognised file system: it is left untouched",
+                    p.number,
+                ));
+                continue;
+            },
+            Err(e) => bail!("partition {}: {}", p.number, e),
+        };
+        ctx.logger.logln(0, scanner.name());
+
+        ctx.logger.logln(0, &format!("=== scanning partition {}", p.number));
+
+        match scanner.scan(ctx, cfg) {
+            Ok(map) => maps.push((p.start, map)),
+            Err(e) => bail!("partition {}: {}", p.number, e),
+        }
+    }
+
+    // The drive holds several file systems.
+    ctx.fs_identity = None;
+
+    ctx.logger.logln(0, "=== mapping the whole drive");
+
+    partition::drive_map(ctx, table, &maps, cfg.wipe_partition_gaps)
+}
+
+/// Loads a usage map from a file and checks it against the drive and, if both are known, against
+/// the identity of the file system. Returns the identity recorded with the map as well.
+fn load_map(path: &Path, ctx: &mut Context) -> anyhow::Result<(UsageMap, Option<FsIdentity>)>
+{
+    let mut f = BufReader::new(File::open(path)?);
+    let (map, identity) = map_format::read_map(&mut f)?;
+
+    let drive_size = ctx.drive.seek(SeekFrom::End(0))?;
+    if map.size() != drive_size {
+        bail!("map size ({}) does not match the drive size ({})", map.size(), drive_size);
+    }
+
+    if let (Some(saved), Some(current)) = (&identity, &ctx.fs_identity) {
+        if saved.uuid != current.uuid {
+            bail!(
+                "the map belongs to a different file system (UUID {}, not {})",
+                saved.uuid,
+                current.uuid,
+            );
+        }
+        if saved.write_time != current.write_time {
+            bail!(
+                "the file system was written to since the map was saved (last write at {}, not {})",
+                current.write_time,
+                saved.write_time,
+            );
+        }
+    }
+
+    Ok((map, identity))
+}
+
+/// Combines the usage map with the maps from the files: adds their used space, then intersects
+/// and subtracts it.
+fn combine_maps(mut map: UsageMap, ctx: &mut Context, cfg: &Config) -> anyhow::Result<UsageMap>
+{
+    let ops = [
+        (&cfg.union_paths, UsageMap::union as fn(&_, &_) -> _),
+        (&cfg.intersect_paths, UsageMap::intersection),
+        (&cfg.subtract_paths, UsageMap::subtract),
+    ];
+
+    for (paths, op) in ops {
+        for path in paths {
+            map = load_map(path, ctx)
+                .and_then(|(other, _)| op(&map, &other))
+                .map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+        }
+    }
+
+    ctx.logger.logln(1, &format!("combined usage map: {} bytes used", map.total_used()));
+
+    Ok(map)
+}
+
+/// Returns the strings that identify the drive: the path as given, the resolved path, the serial
+/// numbers of the device and the UUID (or serial number) of the scanned file system, if known.
+fn device_identities(cfg: &Config, ctx: &Context) -> anyhow::Result<Vec<String>>
+{
+    let mut ids = vec![cfg.drive_path.display().to_string()];
+
+    let resolved = std::fs::canonicalize(&cfg.drive_path)?.display().to_string();
+    if resolved != ids[0] {
+        ids.push(resolved);
+    }
+
+    ids.extend(ctx.drive.serials());
+    if let Some(identity) = &ctx.fs_identity {
+        ids.push(identity.uuid.clone());
+    }
+
+    Ok(ids)
+}
+
+/// Checks that the device confirmation exactly matches one of the drive's identities. The
+/// identities are not reported, so that they cannot be copied from the error.
+fn check_device_confirmation(confirmation: &str, ids: &[String]) -> anyhow::Result<()>
+{
+    if !ids.iter().any(|id| id == confirmation) {
+        bail!("device confirmation `{}` does not match the drive", confirmation);
+    }
+
+    Ok(())
+}
+
+/// Lowers the priority of fsfill (and of the threads it starts afterwards), so that the fill barely
+/// impacts the other work of the host: the I/O scheduling class is set to idle (`ioprio_set`) and
+/// the CPU priority to the lowest (nice 19).
+#[cfg(target_os = "linux")]
+fn set_idle_priority() -> std::io::Result<()>
+{
+    // From linux/ioprio.h.
+    const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+    const IOPRIO_CLASS_IDLE: libc::c_int = 3;
+    const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+
+    // SAFETY: the calls do not access any memory.
+    unsafe {
+        let prio = IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT;
+        if libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, prio) != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        if libc::setpriority(libc::PRIO_PROCESS, 0, 19) != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_idle_priority() -> std::io::Result<()>
+{
+    Err(std::io::ErrorKind::Unsupported.into())
+}
+
+/// Returns the additional flags for opening the drive.
+/// With the barrier option, the drive is opened with O_SYNC, so that each write returns only after
+/// the data has reached the media.
+#[cfg(target_os = "linux")]
+fn drive_open_flags(cfg: &Config) -> i32
+{
+    if cfg.barrier && !cfg.report_only {
+        libc::O_SYNC
+    } else {
+        0
+    }
+}
+
 /// Configuration options.
 #[derive(Clone, Debug)]
 pub struct Config {
     pub cmd_name: String,
-    pub fs_type: FsType,
+    pub fs_type: &'static str,
     pub drive_path: PathBuf,
     pub log_file_path: Option<PathBuf>,
+    pub progress_format: Option<ProgressFormat>,
+    /// The file into which the progress events are written, instead of stderr.
+    pub progress_path: Option<PathBuf>,
     pub report_only: bool,
     pub verbosity: u32,
     pub fill_mode: FillMode,
+    /// The passes of the fill, instead of a single pass of the fill mode, if not empty.
+    pub passes: Vec<FillPass>,
+    /// The byte written by the pattern fill mode.
+    pub pattern_byte: u8,
+    /// The file whose contents the pattern fill mode repeats, instead of the pattern byte.
+    pub pattern_file: Option<PathBuf>,
+    /// The seed of the random fill modes; a random one if not set.
+    pub seed: Option<[u8; 32]>,
+    /// The fill is read back and compared against the fill data.
+    pub verify: bool,
+    /// The number of samples of each free segment read back after the fill, unless it is
+    /// verified whole.
+    pub spot_check: Option<usize>,
+    /// The number of threads that fill the free space.
+    pub threads: usize,
+    /// A zero pass follows a random fill.
+    pub final_zero: bool,
+    /// Zeroes are written into image files by punching holes.
+    pub sparsify: bool,
+    pub fill_top: Option<usize>,
+    /// The byte ranges to which the fill is restricted; the whole drive if empty.
+    pub ranges: Vec<Range<u64>>,
+    /// The free segments smaller than this are not filled.
+    pub min_segment_size: u64,
+    /// The most bytes filled, from the largest free segments.
+    pub max_fill_bytes: Option<u64>,
+    /// The percentage of the free space filled, from the largest free segments.
+    pub fill_percent: Option<u8>,
+    pub paranoid: bool,
+    /// The zero fill skips the blocks of the free space that are already all zeroes.
+    pub skip_zeroed: bool,
+    pub probe: bool,
+    /// The file into which the progress of an interrupted fill is saved.
+    pub fill_checkpoint: Option<PathBuf>,
+    /// The checkpoint of an interrupted fill, from which the fill is resumed.
+    pub resume: Option<PathBuf>,
+    pub barrier: bool,
+    /// The fill runs with the idle I/O scheduling class and the lowest CPU priority.
+    pub idle_io: bool,
+    /// The fill is written with O_DIRECT.
+    pub direct_io: bool,
+    pub confirm_device: Option<String>,
+    /// The number of the partition to which the drive is restricted.
+    pub partition: Option<u32>,
+    /// The gaps between the partitions are mapped as free, around the selected partition, if any.
+    pub wipe_partition_gaps: bool,
+    /// Every partition of the drive is scanned.
+    pub all_partitions: bool,
+    /// The drive is a LUKS container, replaced by its mapper device.
+    pub luks_mapper: bool,
     pub ignore_recovery: bool,
     pub ignore_readonly: bool,
+    pub strict: bool,
+    /// Metadata with invalid checksums is used with a warning, instead of failing the scan.
+    pub ignore_csum_errors: bool,
+    pub max_extent_nodes: usize,
+    pub check_backups: bool,
+    /// The scanned map is compared against the block bitmaps; the fill is refused on mismatch.
+    pub cross_check: bool,
+    /// The deleted inodes are zeroed before the fill (counted in the report-only mode).
+    pub scrub_inode_table: bool,
+    /// The remnants of deleted entries are zeroed in the directory blocks during the scan
+    /// (counted in the report-only mode).
+    pub scrub_dirents: bool,
+    /// The slack past `i_size` is left free in the map, so that it is filled, instead of being
+    /// kept with the rest of the allocated blocks.
+    pub wipe_slack: bool,
+    pub stale_backups: StaleBackups,
+    /// File systems with unknown incompatible features are scanned from the block bitmaps only.
+    /// Such file systems are never filled.
+    pub allow_unknown_incompat: bool,
+    /// File systems with encryption are scanned; the contents of encrypted files, directories
+    /// and symlinks are not inspected beyond their block mapping.
+    pub allow_encrypted: bool,
+    /// File systems with shared_blocks are scanned and may be filled; shared blocks are kept
+    /// like any other referenced block.
+    pub allow_shared_blocks: bool,
+    /// The file system type was forced: detection is skipped (as with an explicit type) and the
+    /// superblock fields that only identify the file system are not validated. Checks of the
+    /// file system state and features still apply.
+    pub force_fs_type: bool,
     pub pretty: bool,
+    /// The free space is read and its contents reported, instead of the usage map.
+    pub audit_free: bool,
+    /// The usage map is compared with the one in this file, instead of being reported.
+    pub diff_path: Option<PathBuf>,
+    pub output_format: OutputFormat,
+    /// The usage map loaded from this file replaces the scan when reporting, and narrows down the
+    /// free space of the scan when filling.
+    pub map_path: Option<PathBuf>,
+    /// The usage maps combined with the map of the drive, in this order: the used space of these
+    /// maps is added, then intersected, then subtracted.
+    pub union_paths: Vec<PathBuf>,
+    pub intersect_paths: Vec<PathBuf>,
+    pub subtract_paths: Vec<PathBuf>,
+    pub quick_estimate: bool,
+    pub metrics_path: Option<PathBuf>,
+    pub scan_checkpoint: Option<PathBuf>,
+    pub readahead: bool,
+    /// The I/O engine of the read-ahead and of the fill.
+    pub io_engine: IoEngine,
 }
 
 impl Default for Config {
@@ -218,15 +1302,70 @@ impl Default for Config {
     {
         Self {
             cmd_name: String::from("fsfill"),
-            fs_type: FsType::Ext4,
+            fs_type: "ext4",
             drive_path: PathBuf::default(),
             log_file_path: None,
+            progress_format: None,
+            progress_path: None,
             report_only: true,
             verbosity: 0,
             fill_mode: FillMode::Zero,
+            passes: Vec::new(),
+            pattern_byte: 0,
+            pattern_file: None,
+            seed: None,
+            verify: false,
+            spot_check: None,
+            threads: 1,
+            final_zero: false,
+            sparsify: false,
+            fill_top: None,
+            ranges: Vec::new(),
+            min_segment_size: 0,
+            max_fill_bytes: None,
+            fill_percent: None,
+            paranoid: false,
+            skip_zeroed: false,
+            probe: false,
+            fill_checkpoint: None,
+            resume: None,
+            barrier: false,
+            idle_io: false,
+            direct_io: false,
+            confirm_device: None,
+            partition: None,
+            wipe_partition_gaps: false,
+            all_partitions: false,
+            luks_mapper: false,
             ignore_recovery: false,
             ignore_readonly: false,
+            strict: false,
+            ignore_csum_errors: false,
+            // About 22 million extents, in 256MiB of nodes with 4KiB blocks.
+            max_extent_nodes: 65536,
+            check_backups: false,
+            cross_check: false,
+            scrub_inode_table: false,
+            scrub_dirents: false,
+            wipe_slack: false,
+            stale_backups: StaleBackups::Keep,
+            allow_unknown_incompat: false,
+            allow_encrypted: false,
+            allow_shared_blocks: false,
+            force_fs_type: false,
             pretty: false,
+            audit_free: false,
+            diff_path: None,
+            output_format: OutputFormat::Json,
+            map_path: None,
+            union_paths: Vec::new(),
+            intersect_paths: Vec::new(),
+            subtract_paths: Vec::new(),
+            quick_estimate: false,
+            metrics_path: None,
+            scan_checkpoint: None,
+            readahead: true,
+            io_engine: IoEngine::Sync,
         }
     }
 }
@@ -234,6 +1373,80 @@ impl Default for Config {
 /// Shared mutable state.
 #[derive(Debug)]
 pub struct Context {
-    pub drive: File,
+    pub drive: Drive,
     pub logger: Logger,
+    pub anomalies: Anomalies,
+    /// The classification of the used space, if the drive was scanned.
+    pub space: Option<SpaceSummary>,
+    /// The free space of the scan compared against the free counts of the file system.
+    pub free_counts: Option<FreeCounts>,
+    /// The filename encoding of a file system with casefolded directories.
+    pub encoding: Option<Encoding>,
+    /// The identity of the scanned file system, or of the file system of the loaded usage map.
+    pub fs_identity: Option<FsIdentity>,
+    /// The claims on the file systems with multi-mount protection, released after the fill.
+    pub mmp: Vec<MmpClaim>,
+    /// The writes of scrubbed metadata and refreshed backups, applied once the device is confirmed.
+    pub scrub_writes: Vec<ScrubWrite>,
+    /// The statistics of the fill in progress.
+    pub fill_stats: FillStats,
+    /// The progress of the current phase.
+    pub progress: Progress,
+    /// The drive opened for direct I/O, during a fill with `--direct-io`.
+    pub direct_drive: Option<Drive>,
+}
+
+// Tests.
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn device_confirmation()
+    {
+        let ids = vec![
+            String::from("/dev/disk/by-id/drive"),
+            String::from("/dev/sdb"),
+            String::from("0e8c0a8e-3c1a-4a77-9f65-a1b2c3d4e5f6"),
+        ];
+
+        assert!(check_device_confirmation("/dev/sdb", &ids).is_ok());
+        assert!(check_device_confirmation("0e8c0a8e-3c1a-4a77-9f65-a1b2c3d4e5f6", &ids).is_ok());
+        assert!(check_device_confirmation("/dev/sdc", &ids).is_err());
+        assert!(check_device_confirmation("/dev/sd", &ids).is_err());
+    }
+
+    #[test]
+    fn device_identities_uuid()
+    {
+        let mut ctx = util::test_context(&[0; 4096]);
+        ctx.fs_identity = Some(FsIdentity {
+            uuid: "abababab-abab-abab-abab-abababababab".to_string(),
+            write_time: 0,
+        });
+        let cfg = Config { drive_path: std::env::temp_dir(), ..Config::default() };
+
+        let ids = device_identities(&cfg, &ctx).unwrap();
+
+        assert!(check_device_confirmation("abababab-abab-abab-abab-abababababab", &ids).is_ok());
+        // The identities of the drive are not given away.
+        let err = check_device_confirmation("cdcdcdcd-abab-abab-abab-abababababab", &ids).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "device confirmation `cdcdcdcd-abab-abab-abab-abababababab` does not match the drive",
+        );
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn barrier_open_flags()
+    {
+        let fill = Config { report_only: false, ..Config::default() };
+
+        assert_eq!(drive_open_flags(&fill), 0);
+        assert_eq!(drive_open_flags(&Config { barrier: true, ..fill.clone() }), libc::O_SYNC);
+        // Nothing is written in the report-only mode.
+        assert_eq!(drive_open_flags(&Config { barrier: true, ..Config::default() }), 0);
+    }
 }
@@ -0,0 +1,85 @@
+use anyhow::bail;
+use serde::Serialize;
+
+use crate::usage_map::{AllocStatus, Segment, UsageMap};
+
+/// The differences between two usage maps of the same drive.
+#[derive(Debug, Default, Serialize)]
+pub struct Diff {
+    /// The bytes used in the old map and free in the new one.
+    pub newly_free: u64,
+    /// The bytes free in the old map and used in the new one.
+    pub newly_used: u64,
+    /// The segments that changed status, with their status in the new map.
+    pub changes: Vec<Segment>,
+}
+
+/// Compares two usage maps of the same size.
+pub fn diff(old: &UsageMap, new: &UsageMap) -> anyhow::Result<Diff>
+{
+    if old.size() != new.size() {
+        bail!("the map sizes differ: {} and {}", old.size(), new.size());
+    }
+
+    let mut diff = Diff::default();
+    let mut old_segs = old.iter().peekable();
+    let mut new_segs = new.iter().peekable();
+
+    // Walk both maps at once, over the pieces where neither of them changes status.
+    while let (Some(o), Some(n)) = (old_segs.peek(), new_segs.peek()) {
+        let start = std::cmp::max(o.start, n.start);
+        let end = std::cmp::min(o.end, n.end);
+
+        if o.status != n.status {
+            match n.status {
+                AllocStatus::Free => diff.newly_free += end - start,
+                AllocStatus::Used => diff.newly_used += end - start,
+            }
+
+            match diff.changes.last_mut() {
+                Some(last) if last.end == start && last.status == n.status => last.end = end,
+                _ => diff.changes.push(Segment { start, end, status: n.status }),
+            }
+        }
+
+        if o.end == end {
+            old_segs.next();
+        }
+        if n.end == end {
+            new_segs.next();
+        }
+    }
+
+    Ok(diff)
+}
+
+// Tests.
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn changes()
+    {
+        let mut old = UsageMap::new(100);
+        old.update(10, 20, AllocStatus::Used);
+        old.update(60, 10, AllocStatus::Used);
+
+        let mut new = old.clone();
+        new.update(20, 20, AllocStatus::Used);
+        new.update(0, 15, AllocStatus::Free);
+        new.update(60, 10, AllocStatus::Free);
+
+        let diff = diff(&old, &new).unwrap();
+        assert_eq!((diff.newly_free, diff.newly_used), (15, 10));
+        assert_eq!(diff.changes, vec![
+            Segment { start: 10, end: 15, status: AllocStatus::Free },
+            Segment { start: 30, end: 40, status: AllocStatus::Used },
+            Segment { start: 60, end: 70, status: AllocStatus::Free },
+        ]);
+
+        assert!(super::diff(&old, &old).unwrap().changes.is_empty());
+        assert!(super::diff(&old, &UsageMap::new(200)).is_err());
+    }
+}
@@ -0,0 +1,474 @@
+use std::io::{BufRead, Read, Write};
+use anyhow::bail;
+use clap::ArgEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::anomaly::Anomalies;
+use crate::filesys::e2fs::Encoding;
+use crate::filesys::e2fs::freecount::FreeCounts;
+use crate::usage_map::{UsageMap, Segment, AllocStatus};
+
+/// Output formats of the usage map.
+#[derive(Copy, Clone, Debug, ArgEnum)]
+pub enum OutputFormat {
+    /// The usage map as a JSON array of segments.
+    Json,
+    /// The usage map in the binary format.
+    #[clap(alias = "binary")]
+    Bin,
+    /// A JSON object with the usage map and the scan results.
+    Report,
+    /// The usage map as a GNU ddrescue mapfile: the free segments are non-tried and the used
+    /// segments are finished.
+    Ddrescue,
+}
+
+/// The identity of the scanned file system. A report records it, so that the report is only
+/// loaded for the same file system, unchanged since the scan.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FsIdentity {
+    /// The UUID, or the volume serial number on file systems without one.
+    pub uuid: String,
+    /// The time of the last write, which changes whenever the file system is mounted read-write
+    /// (the checkpoint version on F2FS), or 0 if the file system does not record it.
+    pub write_time: u64,
+}
+
+/// The report envelope.
+#[derive(Debug, Serialize)]
+pub struct Report<'a> {
+    pub map: &'a UsageMap,
+    /// The identity of the file system, if the scanner knows it.
+    pub identity: Option<&'a FsIdentity>,
+    pub anomalies: &'a Anomalies,
+    /// The free space of the scan compared against the free counts of the file system.
+    pub free_counts: Option<&'a FreeCounts>,
+    /// The filename encoding, on file systems with casefolded directories.
+    pub encoding: Option<&'a Encoding>,
+}
+
+// Binary format (all integers are little-endian):
+//
+//  * magic: the bytes `FSFM`,
+//  * version: u8, currently 2,
+//  * identity: the byte 0 if the file system is unknown, or the byte 1 followed by
+//      * UUID length: varint,
+//      * UUID: the UTF-8 bytes of `FsIdentity::uuid`,
+//      * write time: u64,
+//  * drive size: u64,
+//  * record count: varint,
+//  * records: each consisting of
+//      * start delta: varint, the distance from the end of the previous record (or from 0 for the
+//        first record); the skipped space is free,
+//      * length and status: varint, `(length << 1) | status`, where status is 0 for free and 1 for
+//        used; the length must not be zero.
+//
+// The space after the last record, up to the drive size, is free. Varints are unsigned LEB128:
+// 7 bits per byte, least significant group first, the high bit set on all but the last byte.
+//
+// The writer only emits used segments; free space is implied by the gaps. Version 1 lacks the
+// identity and is still read.
+
+const MAGIC: &[u8; 4] = b"FSFM";
+const VERSION: u8 = 2;
+
+/// Writes the usage map in the binary format, along with the identity of the file system, if
+/// known.
+pub fn write_bin<W: Write>(map: &UsageMap, identity: Option<&FsIdentity>, w: &mut W) -> anyhow::Result<()>
+{
+    let used: Vec<&Segment> = map.iter_used().collect();
+
+    w.write_all(MAGIC)?;
+    w.write_all(&[VERSION])?;
+    match identity {
+        Some(id) => {
+            w.write_all(&[1])?;
+            write_varint(w, id.uuid.len() as u64)?;
+            w.write_all(id.uuid.as_bytes())?;
+            w.write_all(&id.write_time.to_le_bytes())?;
+        },
+        None => w.write_all(&[0])?,
+    }
+    w.write_all(&map.size().to_le_bytes())?;
+    write_varint(w, used.len() as u64)?;
+
+    let mut prev_end = 0;
+
+    for seg in used {
+        let len = seg.end - seg.start;
+
+        if len >> 63 != 0 {
+            bail!("segment too large to encode: {}", len);
+        }
+
+        write_varint(w, seg.start - prev_end)?;
+        write_varint(w, (len << 1) | 1)?;
+        prev_end = seg.end;
+    }
+
+    Ok(())
+}
+
+/// Reads a usage map in the binary format. Returns the identity of the file system as well, if the
+/// map records it.
+pub fn read_bin<R: Read>(r: &mut R) -> anyhow::Result<(UsageMap, Option<FsIdentity>)>
+{
+    let mut magic = [0u8; 4];
+    r.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        bail!("not a binary usage map: bad magic value");
+    }
+
+    let mut version = [0u8; 1];
+    r.read_exact(&mut version)?;
+    if version[0] == 0 || version[0] > VERSION {
+        bail!("unsupported binary usage map version: {}", version[0]);
+    }
+
+    let identity = if version[0] >= 2 { read_identity(r)? } else { None };
+
+    let mut size = [0u8; 8];
+    r.read_exact(&mut size)?;
+    let size = u64::from_le_bytes(size);
+    if size == 0 {
+        bail!("binary usage map has zero size");
+    }
+
+    let count = read_varint(r)?;
+    let mut segments = Vec::new();
+    let mut prev_end = 0u64;
+
+    for i in 0..count {
+        let delta = read_varint(r)?;
+        let len_status = read_varint(r)?;
+        let len = len_status >> 1;
+        let status = if len_status & 1 == 0 { AllocStatus::Free } else { AllocStatus::Used };
+
+        if len == 0 {
+            bail!("record {} has zero length", i);
+        }
+
+        let start = prev_end.checked_add(delta);
+        let end = start.and_then(|s| s.checked_add(len));
+        let (start, end) = match (start, end) {
+            (Some(s), Some(e)) if e <= size => (s, e),
+            _ => bail!("record {} reaches beyond the end of the map", i),
+        };
+
+        if delta != 0 {
+            segments.push(Segment { start: prev_end, end: start, status: AllocStatus::Free });
+        }
+        segments.push(Segment { start, end, status });
+        prev_end = end;
+    }
+
+    if prev_end < size {
+        segments.push(Segment { start: prev_end, end: size, status: AllocStatus::Free });
+    }
+
+    Ok((UsageMap::from_segments(segments)?, identity))
+}
+
+/// Reads the identity of the file system from the header of the binary format.
+fn read_identity<R: Read>(r: &mut R) -> anyhow::Result<Option<FsIdentity>>
+{
+    let mut known = [0u8; 1];
+    r.read_exact(&mut known)?;
+    match known[0] {
+        0 => return Ok(None),
+        1 => {},
+        _ => bail!("binary usage map has a bad identity flag: {}", known[0]),
+    }
+
+    let len = read_varint(r)?;
+    if len > 256 {
+        bail!("binary usage map has a UUID too long: {} bytes", len);
+    }
+    let mut uuid = vec![0u8; len as usize];
+    r.read_exact(&mut uuid)?;
+    let uuid = match String::from_utf8(uuid) {
+        Ok(uuid) => uuid,
+        Err(_) => bail!("binary usage map has a UUID that is not UTF-8"),
+    };
+
+    let mut write_time = [0u8; 8];
+    r.read_exact(&mut write_time)?;
+
+    Ok(Some(FsIdentity { uuid, write_time: u64::from_le_bytes(write_time) }))
+}
+
+/// Reads a usage map exported as JSON: either the array of its segments or a report, whose map is
+/// used. Returns the identity of the file system as well, if the report records it.
+pub fn read_json<R: Read>(r: &mut R) -> anyhow::Result<(UsageMap, Option<FsIdentity>)>
+{
+    let mut value: serde_json::Value = serde_json::from_reader(r)?;
+    let mut identity = None;
+
+    if let Some(obj) = value.as_object_mut() {
+        if let Some(id) = obj.remove("identity") {
+            identity = Option::<FsIdentity>::deserialize(id)?;
+        }
+        value = match obj.remove("map") {
+            Some(map) => map,
+            None => bail!("the report has no usage map"),
+        };
+    }
+
+    Ok((UsageMap::deserialize(value)?, identity))
+}
+
+/// Reads a usage map in the binary format or as JSON, telling them apart by the magic value of
+/// the binary format. Returns the identity of the file system as well, if the map records it.
+pub fn read_map<R: BufRead>(r: &mut R) -> anyhow::Result<(UsageMap, Option<FsIdentity>)>
+{
+    if r.fill_buf()?.starts_with(MAGIC) {
+        read_bin(r)
+    } else {
+        read_json(r)
+    }
+}
+
+/// Writes the usage map as a GNU ddrescue mapfile, with the free segments non-tried (`?`) and the
+/// used segments finished (`+`), so that ddrescue copies only the free space.
+pub fn write_ddrescue<W: Write>(map: &UsageMap, w: &mut W) -> std::io::Result<()>
+{
+    writeln!(w, "# Mapfile. Created by fsfill {}", env!("CARGO_PKG_VERSION"))?;
+    writeln!(w, "# current_pos  current_status  current_pass")?;
+    writeln!(w, "0x00000000     ?               1")?;
+    writeln!(w, "#      pos        size  status")?;
+
+    for seg in map {
+        let status = match seg.status {
+            AllocStatus::Free => '?',
+            AllocStatus::Used => '+',
+        };
+        writeln!(w, "{:#010x}  {:#010x}  {}", seg.start, seg.end - seg.start, status)?;
+    }
+
+    Ok(())
+}
+
+/// Writes an unsigned LEB128 varint.
+fn write_varint<W: Write>(w: &mut W, mut value: u64) -> std::io::Result<()>
+{
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+
+        if value == 0 {
+            return w.write_all(&[byte]);
+        }
+
+        w.write_all(&[byte | 0x80])?;
+    }
+}
+
+/// Reads an unsigned LEB128 varint.
+fn read_varint<R: Read>(r: &mut R) -> anyhow::Result<u64>
+{
+    let mut value = 0u64;
+    let mut byte = [0u8; 1];
+
+    for shift in (0..64).step_by(7) {
+        r.read_exact(&mut byte)?;
+
+        let bits = (byte[0] & 0x7f) as u64;
+        if shift == 63 && bits > 1 {
+            bail!("varint overflow");
+        }
+
+        value |= bits << shift;
+
+        if byte[0] & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+
+    bail!("varint overflow")
+}
+
+// Debug and Display implementations.
+
+impl std::fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result
+    {
+        match self {
+            Self::Json => write!(f, "json"),
+            Self::Bin => write!(f, "bin"),
+            Self::Report => write!(f, "report"),
+            Self::Ddrescue => write!(f, "ddrescue"),
+        }
+    }
+}
+
+// Tests.
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn varint_round_trip()
+    {
+        for value in [0, 1, 127, 128, 300, u32::MAX as u64, u64::MAX] {
+            let mut buf = Vec::new();
+            write_varint(&mut buf, value).unwrap();
+
+            assert_eq!(read_varint(&mut buf.as_slice()).unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn round_trip()
+    {
+        let mut map = UsageMap::new(1 << 40);
+        map.update(0, 2048, AllocStatus::Used);
+        map.update(4096, 4096, AllocStatus::Used);
+        map.update(1 << 30, 77, AllocStatus::Used);
+        map.update((1 << 40) - 10, 10, AllocStatus::Used);
+
+        let mut buf = Vec::new();
+        write_bin(&map, None, &mut buf).unwrap();
+        let (loaded, _) = read_bin(&mut buf.as_slice()).unwrap();
+
+        assert_eq!(loaded.segments(), map.segments());
+    }
+
+    #[test]
+    fn round_trip_all_free()
+    {
+        let map = UsageMap::new(12345);
+
+        let mut buf = Vec::new();
+        write_bin(&map, None, &mut buf).unwrap();
+        let (loaded, _) = read_bin(&mut buf.as_slice()).unwrap();
+
+        assert_eq!(loaded.segments(), map.segments());
+    }
+
+    #[test]
+    fn explicit_free_records()
+    {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(MAGIC);
+        buf.push(VERSION);
+        buf.push(0);
+        buf.extend_from_slice(&100u64.to_le_bytes());
+        write_varint(&mut buf, 2).unwrap();
+        // Used 10..20, followed by an explicit free 20..30.
+        write_varint(&mut buf, 10).unwrap();
+        write_varint(&mut buf, (10 << 1) | 1).unwrap();
+        write_varint(&mut buf, 0).unwrap();
+        write_varint(&mut buf, 10 << 1).unwrap();
+
+        let (map, _) = read_bin(&mut buf.as_slice()).unwrap();
+
+        assert_eq!(map.len(), 3);
+        assert_eq!(map.segments()[0], Segment { start: 0, end: 10, status: AllocStatus::Free });
+        assert_eq!(map.segments()[1], Segment { start: 10, end: 20, status: AllocStatus::Used });
+        assert_eq!(map.segments()[2], Segment { start: 20, end: 100, status: AllocStatus::Free });
+    }
+
+    #[test]
+    fn identity()
+    {
+        let mut map = UsageMap::new(4096);
+        map.update(100, 200, AllocStatus::Used);
+        let identity = FsIdentity { uuid: "0123".to_string(), write_time: 1700000000 };
+
+        let mut buf = Vec::new();
+        write_bin(&map, Some(&identity), &mut buf).unwrap();
+        let (loaded, loaded_identity) = read_map(&mut buf.as_slice()).unwrap();
+        assert_eq!(loaded.segments(), map.segments());
+        assert_eq!(loaded_identity, Some(identity));
+
+        // Version 1 has no identity.
+        let mut v1 = Vec::new();
+        v1.extend_from_slice(MAGIC);
+        v1.push(1);
+        v1.extend_from_slice(&4096u64.to_le_bytes());
+        write_varint(&mut v1, 1).unwrap();
+        write_varint(&mut v1, 100).unwrap();
+        write_varint(&mut v1, (200 << 1) | 1).unwrap();
+        let (loaded, loaded_identity) = read_map(&mut v1.as_slice()).unwrap();
+        assert_eq!(loaded.segments(), map.segments());
+        assert_eq!(loaded_identity, None);
+    }
+
+    #[test]
+    fn bad_magic()
+    {
+        let buf = b"JSON\x01";
+
+        assert!(read_bin(&mut buf.as_slice()).is_err());
+    }
+
+    #[test]
+    fn record_out_of_bounds()
+    {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(MAGIC);
+        buf.push(VERSION);
+        buf.push(0);
+        buf.extend_from_slice(&100u64.to_le_bytes());
+        write_varint(&mut buf, 1).unwrap();
+        write_varint(&mut buf, 90).unwrap();
+        write_varint(&mut buf, (20 << 1) | 1).unwrap();
+
+        assert!(read_bin(&mut buf.as_slice()).is_err());
+    }
+
+    #[test]
+    fn read_any()
+    {
+        let mut map = UsageMap::new(4096);
+        map.update(100, 200, AllocStatus::Used);
+
+        let mut bin = Vec::new();
+        write_bin(&map, None, &mut bin).unwrap();
+        let json = serde_json::to_vec(&map).unwrap();
+        let report = format!(r#"{{"map":{},"anomalies":[]}}"#, String::from_utf8_lossy(&json));
+
+        for buf in [&bin[..], &json[..], report.as_bytes()] {
+            let (loaded, identity) = read_map(&mut &buf[..]).unwrap();
+            assert_eq!(loaded.segments(), map.segments());
+            assert_eq!(identity, None);
+        }
+
+        let identity = FsIdentity { uuid: "0123".to_string(), write_time: 1700000000 };
+        let report = Report {
+            map: &map,
+            identity: Some(&identity),
+            anomalies: &Anomalies::default(),
+            free_counts: None,
+            encoding: None,
+        };
+        let report = serde_json::to_vec(&report).unwrap();
+        let (loaded, loaded_identity) = read_map(&mut &report[..]).unwrap();
+        assert_eq!(loaded.segments(), map.segments());
+        assert_eq!(loaded_identity, Some(identity));
+
+        assert!(read_map(&mut &b"{}"[..]).is_err());
+        assert!(read_map(&mut &b"[]"[..]).is_err());
+        assert!(read_map(&mut &b"FSF"[..]).is_err());
+    }
+
+    #[test]
+    fn ddrescue()
+    {
+        let mut map = UsageMap::new(0x10000);
+        map.update(0x1000, 0x2000, AllocStatus::Used);
+
+        let mut buf = Vec::new();
+        write_ddrescue(&map, &mut buf).unwrap();
+        let text = String::from_utf8(buf).unwrap();
+
+        assert!(text.starts_with("# Mapfile."));
+        assert!(text.ends_with(concat!(
+            "#      pos        size  status\n",
+            "0x00000000  0x00001000  ?\n",
+            "0x00001000  0x00002000  +\n",
+            "0x00003000  0x0000d000  ?\n",
+        )));
+    }
+}
@@ -0,0 +1,110 @@
+use std::io::Write;
+
+use crate::filesys::e2fs::layout::SpaceSummary;
+use crate::usage_map::UsageMap;
+
+/// Writes the scan results as metrics in the Prometheus text format, labeled by the device.
+/// The metadata metric is only written when the space summary is known, i.e. when the drive was
+/// scanned.
+pub fn write_metrics<W: Write>(
+    w: &mut W,
+    device: &str,
+    map: &UsageMap,
+    space: Option<&SpaceSummary>,
+) -> std::io::Result<()>
+{
+    let mut metrics = vec![
+        ("fsfill_free_bytes", "Free space on the drive, in bytes.", map.total_free()),
+        ("fsfill_used_bytes", "Used space on the drive, in bytes.", map.total_used()),
+    ];
+    if let Some(space) = space {
+        metrics.push(("fsfill_metadata_bytes", "Fixed file system metadata, in bytes.", space.metadata));
+    }
+    metrics.push((
+        "fsfill_free_segments",
+        "Number of free segments.",
+        map.iter_free().count() as u64,
+    ));
+    metrics.push((
+        "fsfill_largest_free_segment",
+        "Size of the largest free segment, in bytes.",
+        map.largest_free().map_or(0, |s| s.end - s.start),
+    ));
+
+    let device = escape_label(device);
+
+    for (name, help, value) in metrics {
+        writeln!(w, "# HELP {} {}", name, help)?;
+        writeln!(w, "# TYPE {} gauge", name)?;
+        writeln!(w, "{}{{device=\"{}\"}} {}", name, device, value)?;
+    }
+
+    Ok(())
+}
+
+/// Escapes a label value.
+fn escape_label(value: &str) -> String
+{
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+// Tests.
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::usage_map::AllocStatus;
+
+    /// Parses the samples of the Prometheus text format into a map from the sample name with its
+    /// labels to the value.
+    fn parse(text: &str) -> HashMap<String, u64>
+    {
+        text.lines()
+            .filter(|l| !l.starts_with('#'))
+            .map(|l| {
+                let (name, value) = l.rsplit_once(' ').unwrap();
+                (name.to_string(), value.parse().unwrap())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn metrics()
+    {
+        let mut map = UsageMap::new(1000);
+        map.update(0, 100, AllocStatus::Used);
+        map.update(300, 50, AllocStatus::Used);
+        let space = SpaceSummary { metadata: 80, growth: 0, data: 70 };
+
+        let mut buf = Vec::new();
+        write_metrics(&mut buf, "/dev/sdb", &map, Some(&space)).unwrap();
+        let text = String::from_utf8(buf).unwrap();
+        let samples = parse(&text);
+
+        assert_eq!(samples.len(), 5);
+        assert_eq!(samples["fsfill_free_bytes{device=\"/dev/sdb\"}"], 850);
+        assert_eq!(samples["fsfill_used_bytes{device=\"/dev/sdb\"}"], 150);
+        assert_eq!(samples["fsfill_metadata_bytes{device=\"/dev/sdb\"}"], 80);
+        assert_eq!(samples["fsfill_free_segments{device=\"/dev/sdb\"}"], 2);
+        assert_eq!(samples["fsfill_largest_free_segment{device=\"/dev/sdb\"}"], 650);
+        assert!(text.contains("# TYPE fsfill_free_bytes gauge\n"));
+    }
+
+    #[test]
+    fn metrics_without_summary()
+    {
+        let map = UsageMap::new(1000);
+
+        let mut buf = Vec::new();
+        write_metrics(&mut buf, "a \"b\"", &map, None).unwrap();
+        let samples = parse(&String::from_utf8(buf).unwrap());
+
+        assert_eq!(samples.len(), 4);
+        assert_eq!(samples["fsfill_free_bytes{device=\"a \\\"b\\\"\"}"], 1000);
+    }
+}
@@ -0,0 +1,307 @@
+use std::io::{Read, Seek, SeekFrom};
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+use bincode::{DefaultOptions, Options};
+
+use crate::array::Array;
+use crate::drive::Window;
+use crate::Context;
+use super::{Partition, PartitionTable, TableType};
+
+// Reference: UEFI Specification 2.10, section 5.3.
+
+const SIGNATURE: &[u8; 8] = b"EFI PART";
+/// The sector sizes at which the header is looked for.
+const SECTOR_SIZES: [u64; 2] = [512, 4096];
+/// The limit of the size of the partition entry array, against corrupted headers.
+const MAX_ENTRY_ARRAY_SIZE: u64 = 1024 * 1024;
+
+/// The GPT header.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Header {
+    pub signature: [u8; 8],
+    pub revision: u32,
+    pub header_size: u32,
+    pub header_crc32: u32,
+    pub reserved: u32,
+    pub my_lba: u64,
+    pub alternate_lba: u64,
+    pub first_usable_lba: u64,
+    pub last_usable_lba: u64,
+    pub disk_guid: [u8; 16],
+    pub partition_entry_lba: u64,
+    pub num_partition_entries: u32,
+    pub size_of_partition_entry: u32,
+    pub partition_entry_array_crc32: u32,
+}
+
+const HEADER_SIZE: usize = 92;
+
+/// A partition entry.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Entry {
+    pub partition_type_guid: [u8; 16],
+    pub unique_partition_guid: [u8; 16],
+    pub starting_lba: u64,
+    /// Inclusive.
+    pub ending_lba: u64,
+    pub attributes: u64,
+    pub partition_name: Array<u16, 36>,
+}
+
+/// Reads the primary GPT header and returns it with the sector size, if the drive has one.
+pub fn read_header(ctx: &mut Context) -> anyhow::Result<Option<(Header, u64)>>
+{
+    let bincode_opt = DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes();
+
+    let drive_size = ctx.drive.seek(SeekFrom::End(0))?;
+
+    for sector_size in SECTOR_SIZES {
+        if drive_size < 2 * sector_size {
+            break;
+        }
+
+        let mut raw = [0u8; HEADER_SIZE];
+        ctx.drive.seek(SeekFrom::Start(sector_size))?;
+        ctx.drive.read_exact(&mut raw)?;
+
+        if &raw[..8] == SIGNATURE {
+            let header: Header = bincode_opt.deserialize(&raw)?;
+            return Ok(Some((header, sector_size)));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Reads the partitions of a GPT drive, if the drive has one. The checksums are verified; the
+/// backup GPT is not used. Everything outside of the usable sectors is taken for metadata.
+pub fn read_table(ctx: &mut Context) -> anyhow::Result<Option<PartitionTable>>
+{
+    let bincode_opt = DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes();
+
+    let (header, sector_size) = match read_header(ctx)? {
+        Some(h) => h,
+        None => return Ok(None),
+    };
+
+    // Verify the header.
+
+    let header_size = header.header_size as usize;
+    if header_size < HEADER_SIZE || header_size as u64 > sector_size {
+        bail!("invalid GPT header size: {}", header_size);
+    }
+    let mut raw = vec![0u8; header_size];
+    ctx.drive.seek(SeekFrom::Start(sector_size))?;
+    ctx.drive.read_exact(&mut raw)?;
+    raw[16..20].fill(0);
+    if crc::crc32::checksum_ieee(&raw) != header.header_crc32 {
+        bail!("bad GPT header checksum");
+    }
+
+    let entry_size = header.size_of_partition_entry as u64;
+    if entry_size < 128 || !entry_size.is_power_of_two() {
+        bail!("invalid GPT partition entry size: {}", entry_size);
+    }
+    let array_size = header.num_partition_entries as u64 * entry_size;
+    if array_size > MAX_ENTRY_ARRAY_SIZE {
+        bail!("the GPT partition entry array is too large: {} bytes", array_size);
+    }
+    let array_start = match header.partition_entry_lba.checked_mul(sector_size) {
+        Some(start) => start,
+        None => bail!("invalid GPT partition entry array LBA: {}", header.partition_entry_lba),
+    };
+
+    // Read and verify the entries.
+
+    let mut array = vec![0u8; array_size as usize];
+    ctx.drive.seek(SeekFrom::Start(array_start))?;
+    ctx.drive.read_exact(&mut array)?;
+    if crc::crc32::checksum_ieee(&array) != header.partition_entry_array_crc32 {
+        bail!("bad GPT partition entry array checksum");
+    }
+
+    let drive_size = ctx.drive.seek(SeekFrom::End(0))?;
+    let mut partitions = Vec::new();
+
+    for (i, raw) in array.chunks(entry_size as usize).enumerate() {
+        let entry: Entry = bincode_opt.deserialize(raw)?;
+        if entry.partition_type_guid == [0; 16] {
+            continue;
+        }
+
+        if entry.ending_lba < entry.starting_lba {
+            bail!("GPT partition {} ends before it starts", i + 1);
+        }
+        let start = entry.starting_lba.checked_mul(sector_size);
+        let size = (entry.ending_lba - entry.starting_lba)
+            .checked_add(1)
+            .and_then(|sectors| sectors.checked_mul(sector_size));
+        let (start, size) = match (start, size) {
+            (Some(start), Some(size)) => (start, size),
+            _ => bail!("GPT partition {} reaches beyond the end of the drive", i + 1),
+        };
+        if start.checked_add(size).is_none_or(|end| end > drive_size) {
+            bail!("GPT partition {} reaches beyond the end of the drive", i + 1);
+        }
+
+        let name_len = entry.partition_name.0.iter().position(|c| *c == 0).unwrap_or(36);
+        partitions.push(Partition {
+            number: i as u32 + 1,
+            start,
+            size,
+            name: String::from_utf16_lossy(&entry.partition_name.0[..name_len]),
+        });
+    }
+
+    let usable_start = header.first_usable_lba.checked_mul(sector_size);
+    let usable_end = header.last_usable_lba
+        .checked_add(1)
+        .and_then(|sectors| sectors.checked_mul(sector_size));
+    let (usable_start, usable_end) = match (usable_start, usable_end) {
+        (Some(start), Some(end)) if start < std::cmp::min(end, drive_size) => {
+            (start, std::cmp::min(end, drive_size))
+        },
+        _ => bail!("the GPT has no usable sectors"),
+    };
+
+    Ok(Some(PartitionTable {
+        table_type: TableType::Gpt,
+        partitions,
+        metadata: vec![
+            Window { offset: 0, len: usable_start },
+            Window { offset: usable_end, len: drive_size - usable_end },
+        ],
+    }))
+}
+
+// Tests.
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::util::test_context;
+
+    /// Creates a drive of `sectors` 512-byte sectors with a GPT that has the supplied partitions
+    /// (first and last sector, inclusive) in its first entries, out of 4.
+    pub fn test_drive(sectors: u64, partitions: &[(u64, u64)]) -> Vec<u8>
+    {
+        let bincode_opt = DefaultOptions::new()
+            .with_fixint_encoding()
+            .allow_trailing_bytes();
+
+        let mut drive = vec![0u8; sectors as usize * 512];
+
+        // The protective MBR.
+        drive[446 + 4] = 0xee;
+        drive[510..512].copy_from_slice(&[0x55, 0xaa]);
+
+        let mut array = Vec::new();
+        for i in 0..4 {
+            let mut name = Array::<u16, 36>::default();
+            let (type_guid, (first, last)) = match partitions.get(i) {
+                Some(p) => {
+                    name.0[0] = 'p' as u16;
+                    name.0[1] = '0' as u16 + i as u16 + 1;
+                    ([0xaf; 16], *p)
+                },
+                None => ([0; 16], (0, 0)),
+            };
+            array.extend(bincode_opt.serialize(&Entry {
+                partition_type_guid: type_guid,
+                unique_partition_guid: [i as u8; 16],
+                starting_lba: first,
+                ending_lba: last,
+                attributes: 0,
+                partition_name: name,
+            }).unwrap());
+        }
+        drive[2 * 512..2 * 512 + array.len()].copy_from_slice(&array);
+
+        let mut header = Header {
+            signature: *SIGNATURE,
+            revision: 0x0001_0000,
+            header_size: HEADER_SIZE as u32,
+            header_crc32: 0,
+            reserved: 0,
+            my_lba: 1,
+            alternate_lba: sectors - 1,
+            first_usable_lba: 3,
+            last_usable_lba: sectors - 3,
+            disk_guid: [0x42; 16],
+            partition_entry_lba: 2,
+            num_partition_entries: 4,
+            size_of_partition_entry: 128,
+            partition_entry_array_crc32: crc::crc32::checksum_ieee(&array),
+        };
+        header.header_crc32 = crc::crc32::checksum_ieee(&bincode_opt.serialize(&header).unwrap());
+        let raw = bincode_opt.serialize(&header).unwrap();
+        drive[512..512 + raw.len()].copy_from_slice(&raw);
+
+        drive
+    }
+
+    #[test]
+    fn partitions()
+    {
+        let mut ctx = test_context(&test_drive(64, &[(4, 19), (32, 61)]));
+
+        let table = read_table(&mut ctx).unwrap().unwrap();
+
+        assert_eq!(table.partitions, vec![
+            Partition { number: 1, start: 4 * 512, size: 16 * 512, name: String::from("p1") },
+            Partition { number: 2, start: 32 * 512, size: 30 * 512, name: String::from("p2") },
+        ]);
+        assert_eq!(table.metadata, vec![
+            Window { offset: 0, len: 3 * 512 },
+            Window { offset: 62 * 512, len: 2 * 512 },
+        ]);
+    }
+
+    #[test]
+    fn bad_checksum()
+    {
+        let mut drive = test_drive(64, &[(4, 19)]);
+        // Move the partition without updating the checksum.
+        drive[2 * 512 + 32] = 5;
+        let mut ctx = test_context(&drive);
+
+        assert!(read_table(&mut ctx).is_err());
+    }
+
+    #[test]
+    fn out_of_range()
+    {
+        let bincode_opt = DefaultOptions::new()
+            .with_fixint_encoding()
+            .allow_trailing_bytes();
+
+        for partition in [(u64::MAX / 256, u64::MAX / 256), (0, u64::MAX), (4, 64)] {
+            let mut ctx = test_context(&test_drive(64, &[partition]));
+            assert!(read_table(&mut ctx).is_err());
+        }
+
+        // The usable sectors reach beyond the range of the LBAs.
+        let mut drive = test_drive(64, &[(4, 19)]);
+        let mut header: Header = bincode_opt.deserialize(&drive[512..]).unwrap();
+        header.last_usable_lba = u64::MAX;
+        header.header_crc32 = 0;
+        header.header_crc32 = crc::crc32::checksum_ieee(&bincode_opt.serialize(&header).unwrap());
+        let raw = bincode_opt.serialize(&header).unwrap();
+        drive[512..512 + raw.len()].copy_from_slice(&raw);
+        let mut ctx = test_context(&drive);
+        assert!(read_table(&mut ctx).is_err());
+    }
+
+    #[test]
+    fn no_gpt()
+    {
+        let mut ctx = test_context(&[0; 64 * 512]);
+
+        assert!(read_table(&mut ctx).unwrap().is_none());
+    }
+}
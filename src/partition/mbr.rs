@@ -0,0 +1,320 @@
+use std::io::{Read, Seek, SeekFrom};
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+use bincode::{DefaultOptions, Options};
+
+use crate::drive::Window;
+use crate::filesys::{fat, ntfs};
+use crate::Context;
+use super::{Partition, PartitionTable, TableType};
+
+/// The offset of the partition entries in the MBR and the EBRs.
+const ENTRIES_OFFSET: usize = 446;
+const SIGNATURE: [u8; 2] = [0x55, 0xaa];
+
+/// The partition type of the protective MBR of a GPT drive.
+const TYPE_GPT_PROTECTIVE: u8 = 0xee;
+/// The limit of the number of logical partitions, against loops in the EBR chain.
+const MAX_LOGICAL: u32 = 128;
+
+/// A partition entry.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Entry {
+    pub status: u8,
+    pub chs_first: [u8; 3],
+    pub partition_type: u8,
+    pub chs_last: [u8; 3],
+    pub lba_first: u32,
+    pub sectors: u32,
+}
+
+impl Entry {
+    fn is_empty(&self) -> bool
+    {
+        self.partition_type == 0 || self.sectors == 0
+    }
+
+    fn is_extended(&self) -> bool
+    {
+        matches!(self.partition_type, 0x05 | 0x0f | 0x85)
+    }
+}
+
+/// Reads a sector of the partition table: the MBR or an EBR. Returns the entries, if the sector has
+/// a boot signature.
+fn read_entries(
+    ctx: &mut Context,
+    lba: u64,
+    sector_size: u64,
+) -> anyhow::Result<Option<([Entry; 4], Vec<u8>)>>
+{
+    let bincode_opt = DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes();
+
+    let mut raw = vec![0u8; sector_size as usize];
+    ctx.drive.seek(SeekFrom::Start(lba * sector_size))?;
+    ctx.drive.read_exact(&mut raw)?;
+
+    if raw[510..512] != SIGNATURE {
+        return Ok(None);
+    }
+
+    let entries: [Entry; 4] = bincode_opt.deserialize(&raw[ENTRIES_OFFSET..])?;
+
+    Ok(Some((entries, raw)))
+}
+
+/// Reads the partitions of an MBR drive, if the drive has an MBR. Boot sectors of FAT32 and NTFS
+/// volumes are not mistaken for an MBR, and neither is the protective MBR of a GPT drive.
+/// The addresses are in the logical sectors of the drive. The sectors in front of the first
+/// partition are taken for metadata, as boot loaders (e.g. the core image of GRUB) are embedded
+/// there.
+pub fn read_table(ctx: &mut Context) -> anyhow::Result<Option<PartitionTable>>
+{
+    let sector_size = ctx.drive.logical_sector_size()?;
+
+    read_table_with(ctx, sector_size)
+}
+
+/// Reads the partitions of an MBR drive with the given sector size.
+fn read_table_with(ctx: &mut Context, sector_size: u64) -> anyhow::Result<Option<PartitionTable>>
+{
+    let bincode_opt = DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes();
+
+    let drive_size = ctx.drive.seek(SeekFrom::End(0))?;
+    if drive_size < sector_size {
+        return Ok(None);
+    }
+    let drive_sectors = drive_size / sector_size;
+
+    let (entries, raw) = match read_entries(ctx, 0, sector_size)? {
+        Some(e) => e,
+        None => return Ok(None),
+    };
+
+    if fat::is_fat32_bpb(&bincode_opt.deserialize(&raw)?)
+        || ntfs::is_ntfs_boot_sector(&bincode_opt.deserialize(&raw)?)
+    {
+        return Ok(None);
+    }
+    if entries.iter().any(|e| e.status != 0 && e.status != 0x80) {
+        return Ok(None);
+    }
+    if entries.iter().all(|e| e.is_empty()) {
+        return Ok(None);
+    }
+    if entries.iter().any(|e| e.partition_type == TYPE_GPT_PROTECTIVE) {
+        return Ok(None);
+    }
+
+    let mut table = PartitionTable {
+        table_type: TableType::Mbr,
+        partitions: Vec::new(),
+        metadata: Vec::new(),
+    };
+    let mut extended = None;
+
+    for (i, entry) in entries.iter().enumerate() {
+        if entry.is_empty() {
+            continue;
+        }
+
+        let number = i as u32 + 1;
+        let (first, sectors) = (entry.lba_first as u64, entry.sectors as u64);
+        if first == 0 || first + sectors > drive_sectors {
+            bail!("MBR partition {} is out of the drive", number);
+        }
+
+        if entry.is_extended() {
+            if extended.is_some() {
+                bail!("the MBR has more than one extended partition");
+            }
+            extended = Some((first, sectors));
+        } else {
+            table.partitions.push(partition(number, first, sectors, sector_size));
+        }
+    }
+
+    // The MBR and the boot loader, up to the first partition or EBR.
+    let first = entries.iter()
+        .filter(|e| !e.is_empty())
+        .map(|e| e.lba_first as u64)
+        .min()
+        .unwrap();
+    table.metadata.push(Window { offset: 0, len: first * sector_size });
+
+    if let Some(extended) = extended {
+        read_logical(ctx, &mut table, extended, sector_size)?;
+    }
+
+    Ok(Some(table))
+}
+
+/// Follows the chain of EBRs of an extended partition and adds its logical partitions, numbered
+/// from 5.
+fn read_logical(
+    ctx: &mut Context,
+    table: &mut PartitionTable,
+    extended: (u64, u64),
+    sector_size: u64,
+) -> anyhow::Result<()>
+{
+    let (ext_first, ext_sectors) = extended;
+    let mut ebr = ext_first;
+
+    for number in 5..5 + MAX_LOGICAL {
+        let entries = match read_entries(ctx, ebr, sector_size)? {
+            Some((entries, _)) => entries,
+            None => bail!("missing boot signature in the EBR at sector {}", ebr),
+        };
+        table.metadata.push(Window { offset: ebr * sector_size, len: sector_size });
+
+        // The first entry is relative to the EBR, the second one (the next EBR) to the extended
+        // partition.
+        let logical = &entries[0];
+        if !logical.is_empty() {
+            let first = ebr + logical.lba_first as u64;
+            let sectors = logical.sectors as u64;
+            if logical.lba_first == 0 || first + sectors > ext_first + ext_sectors {
+                bail!("MBR partition {} is out of the extended partition", number);
+            }
+            table.partitions.push(partition(number, first, sectors, sector_size));
+        }
+
+        let next = &entries[1];
+        if next.is_empty() || !next.is_extended() {
+            return Ok(());
+        }
+        let next_ebr = ext_first + next.lba_first as u64;
+        if next_ebr <= ebr || next_ebr >= ext_first + ext_sectors {
+            bail!("invalid EBR chain at sector {}", ebr);
+        }
+        ebr = next_ebr;
+    }
+
+    bail!("too many logical partitions");
+}
+
+fn partition(number: u32, first: u64, sectors: u64, sector_size: u64) -> Partition
+{
+    Partition {
+        number,
+        start: first * sector_size,
+        size: sectors * sector_size,
+        name: String::new(),
+    }
+}
+
+// Tests.
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::util::test_context;
+
+    fn write_entries(drive: &mut [u8], lba: u64, sector_size: u64, entries: &[Entry])
+    {
+        let bincode_opt = DefaultOptions::new()
+            .with_fixint_encoding()
+            .allow_trailing_bytes();
+
+        let offset = (lba * sector_size) as usize;
+        for (i, e) in entries.iter().enumerate() {
+            let raw = bincode_opt.serialize(e).unwrap();
+            let start = offset + ENTRIES_OFFSET + i * 16;
+            drive[start..start + 16].copy_from_slice(&raw);
+        }
+        drive[offset + 510..offset + 512].copy_from_slice(&SIGNATURE);
+    }
+
+    fn entry(partition_type: u8, lba_first: u32, sectors: u32) -> Entry
+    {
+        Entry { partition_type, lba_first, sectors, ..Entry::default() }
+    }
+
+    /// Creates a drive of 128 sectors with primary partitions at sectors 8-23 and 24-39, and an
+    /// extended partition at 64-127 with logical partitions at 66-79 and 96-111.
+    pub fn test_drive() -> Vec<u8>
+    {
+        test_drive_with(512)
+    }
+
+    fn test_drive_with(sector_size: u64) -> Vec<u8>
+    {
+        let mut drive = vec![0; 128 * sector_size as usize];
+
+        let primary = [entry(0x83, 8, 16), entry(0x07, 24, 16), entry(0x05, 64, 64)];
+        write_entries(&mut drive, 0, sector_size, &primary);
+        write_entries(&mut drive, 64, sector_size, &[entry(0x83, 2, 14), entry(0x05, 30, 34)]);
+        write_entries(&mut drive, 94, sector_size, &[entry(0x83, 2, 16)]);
+
+        drive
+    }
+
+    #[test]
+    fn partitions()
+    {
+        let mut ctx = test_context(&test_drive());
+
+        let table = read_table(&mut ctx).unwrap().unwrap();
+
+        assert_eq!(table.partitions, vec![
+            partition(1, 8, 16, 512),
+            partition(2, 24, 16, 512),
+            partition(5, 66, 14, 512),
+            partition(6, 96, 16, 512),
+        ]);
+        assert_eq!(table.metadata, vec![
+            Window { offset: 0, len: 8 * 512 },
+            Window { offset: 64 * 512, len: 512 },
+            Window { offset: 94 * 512, len: 512 },
+        ]);
+    }
+
+    #[test]
+    fn sector_size_4k()
+    {
+        let mut ctx = test_context(&test_drive_with(4096));
+
+        let table = read_table_with(&mut ctx, 4096).unwrap().unwrap();
+
+        assert_eq!(table.partitions[2], partition(5, 66, 14, 4096));
+        assert_eq!(table.partitions[2].start, 66 * 4096);
+        assert_eq!(table.metadata[1], Window { offset: 64 * 4096, len: 4096 });
+    }
+
+    #[test]
+    fn not_mbr()
+    {
+        // No partitions.
+        let mut drive = vec![0; 128 * 512];
+        drive[510..512].copy_from_slice(&SIGNATURE);
+        let mut ctx = test_context(&drive);
+        assert!(read_table(&mut ctx).unwrap().is_none());
+
+        // A FAT32 boot sector.
+        use crate::filesys::fat::tests::{test_bpb, test_drive};
+        let mut ctx = test_context(&test_drive(&test_bpb(26), 32 * 512, &[]));
+        assert!(read_table(&mut ctx).unwrap().is_none());
+
+        // A GPT protective MBR.
+        let mut drive = vec![0; 128 * 512];
+        write_entries(&mut drive, 0, 512, &[entry(TYPE_GPT_PROTECTIVE, 1, 127)]);
+        let mut ctx = test_context(&drive);
+        assert!(read_table(&mut ctx).unwrap().is_none());
+    }
+
+    #[test]
+    fn ebr_loop()
+    {
+        let mut drive = test_drive();
+        // The second EBR points back to the first one.
+        write_entries(&mut drive, 94, 512, &[entry(0x83, 2, 16), entry(0x05, 0, 34)]);
+        let mut ctx = test_context(&drive);
+
+        assert!(read_table(&mut ctx).is_err());
+    }
+}
@@ -0,0 +1,279 @@
+use std::io::{Seek, SeekFrom};
+use anyhow::bail;
+
+use crate::drive::Window;
+use crate::filesys;
+use crate::usage_map::{AllocStatus, UsageMap};
+use crate::{Config, Context};
+
+pub mod gpt;
+pub mod mbr;
+
+/// A partition of the drive.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Partition {
+    /// The number of the partition, starting at 1, as in the names of the Linux partition devices.
+    pub number: u32,
+    /// The offset of the partition, in bytes.
+    pub start: u64,
+    /// The size of the partition, in bytes.
+    pub size: u64,
+    pub name: String,
+}
+
+/// Supported partition table types.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TableType {
+    Gpt,
+    Mbr,
+}
+
+/// The partition table of the drive.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PartitionTable {
+    pub table_type: TableType,
+    pub partitions: Vec<Partition>,
+    /// The areas of the drive occupied by the partition table itself, and on MBR drives by the boot
+    /// loader in front of the first partition.
+    pub metadata: Vec<Window>,
+}
+
+impl std::fmt::Display for TableType {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result
+    {
+        match self {
+            TableType::Gpt => write!(f, "GPT"),
+            TableType::Mbr => write!(f, "MBR"),
+        }
+    }
+}
+
+/// Reads the partition table, if the drive has one. GPT takes precedence over MBR.
+pub fn read_table(ctx: &mut Context) -> anyhow::Result<Option<PartitionTable>>
+{
+    if let Some(table) = gpt::read_table(ctx)? {
+        return Ok(Some(table));
+    }
+
+    mbr::read_table(ctx)
+}
+
+/// Restricts the drive to the partition selected in the configuration and returns the partition
+/// table, if there is one.
+/// A partitioned drive without a selected partition is refused, unless `whole_drive` is set or a
+/// file system is detected on the whole drive, as none of the file systems would be found on it.
+pub fn select_partition(
+    ctx: &mut Context,
+    cfg: &Config,
+    whole_drive: bool,
+) -> anyhow::Result<Option<PartitionTable>>
+{
+    let table = read_table(ctx)?;
+
+    let (table, number) = match (table, cfg.partition) {
+        (None, None) => return Ok(None),
+        (None, Some(_)) => bail!("the drive has no partition table"),
+        (Some(table), None) if whole_drive => return Ok(Some(table)),
+        (Some(table), None) => {
+            // Detection errors only mean that nothing was recognised.
+            if let Ok(Some(fs_type)) = filesys::detect_fs(ctx) {
+                ctx.logger.logln(1, &format!(
+                    "the drive is partitioned ({}), but it contains a file system ({}): \
+                    using the whole drive",
+                    table.table_type,
+                    fs_type,
+                ));
+                return Ok(Some(table));
+            }
+
+            ctx.logger.logln(0, &format!("the drive is partitioned ({}):", table.table_type));
+            log_partitions(ctx, &table);
+            bail!("select a partition with --partition, or use the partition's device instead");
+        },
+        (Some(table), Some(number)) => (table, number),
+    };
+
+    let partition = match table.partitions.iter().find(|p| p.number == number) {
+        Some(p) => p.clone(),
+        None => bail!("the {} partition table has no partition {}", table.table_type, number),
+    };
+
+    ctx.logger.logln(0, &format!(
+        "=== selecting partition {} ({} bytes at offset {})",
+        partition.number,
+        partition.size,
+        partition.start,
+    ));
+
+    ctx.drive.set_window(Some(Window { offset: partition.start, len: partition.size }))?;
+
+    Ok(Some(table))
+}
+
+/// Returns the usage map of the whole drive, composed of the maps of the partitions, which are
+/// placed at the supplied offsets. The partition table and the partitions without a map are used.
+/// The gaps between the partitions are free if `gaps` is set, else used. If the drive is
+/// restricted to a partition, the restriction is lifted.
+pub fn drive_map(
+    ctx: &mut Context,
+    table: &PartitionTable,
+    partition_maps: &[(u64, UsageMap)],
+    gaps: bool,
+) -> anyhow::Result<UsageMap>
+{
+    ctx.drive.set_window(None)?;
+    let drive_size = ctx.drive.seek(SeekFrom::End(0))?;
+
+    let mut map = UsageMap::new(drive_size);
+
+    if gaps {
+        for area in &table.metadata {
+            map.update(area.offset, area.len, AllocStatus::Used);
+        }
+        for p in &table.partitions {
+            map.update(p.start, p.size, AllocStatus::Used);
+        }
+
+        ctx.logger.logln(0, &format!("gaps between the partitions: {} bytes", map.total_free()));
+    } else {
+        map.update(0, drive_size, AllocStatus::Used);
+    }
+
+    for (offset, partition_map) in partition_maps {
+        if offset + partition_map.size() > drive_size {
+            bail!("the map of the partition at offset {} reaches beyond the drive", offset);
+        }
+
+        for seg in partition_map {
+            map.update(offset + seg.start, seg.size() as u64, seg.status);
+        }
+    }
+
+    Ok(map)
+}
+
+/// Logs the partitions of a table.
+fn log_partitions(ctx: &mut Context, table: &PartitionTable)
+{
+    for p in &table.partitions {
+        ctx.logger.logln(0, &format!(
+            "  partition {}: {} bytes at offset {}{}",
+            p.number,
+            p.size,
+            p.start,
+            if p.name.is_empty() { String::new() } else { format!(" ({})", p.name) },
+        ));
+    }
+}
+
+// Tests.
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::usage_map::Segment;
+    use crate::util::test_context;
+
+    #[test]
+    fn select()
+    {
+        let drive = gpt::tests::test_drive(64, &[(4, 19), (32, 61)]);
+
+        // No partition selected.
+        let mut ctx = test_context(&drive);
+        assert!(select_partition(&mut ctx, &Config::default(), false).is_err());
+        assert!(select_partition(&mut ctx, &Config::default(), true).is_ok());
+        assert_eq!(ctx.drive.window(), None);
+
+        // No such partition.
+        let cfg = Config { partition: Some(3), ..Config::default() };
+        assert!(select_partition(&mut ctx, &cfg, false).is_err());
+
+        let cfg = Config { partition: Some(2), ..Config::default() };
+        select_partition(&mut ctx, &cfg, false).unwrap();
+        assert_eq!(ctx.drive.window(), Some(Window { offset: 32 * 512, len: 30 * 512 }));
+        assert_eq!(ctx.drive.seek(SeekFrom::End(0)).unwrap(), 30 * 512);
+
+        // Not partitioned.
+        let mut ctx = test_context(&[0; 64 * 512]);
+        assert!(select_partition(&mut ctx, &Config::default(), false).is_ok());
+        assert!(select_partition(&mut ctx, &cfg, false).is_err());
+    }
+
+    #[test]
+    fn whole_drive_filesystem()
+    {
+        use crate::filesys::minixfs::tests::{test_sb, test_drive};
+
+        // A partition table in the boot block of a file system is ignored without a selected
+        // partition.
+        let mut drive = test_drive(&test_sb(), &[], 64 * 1024);
+        drive[446 + 4] = 0x83;
+        drive[446 + 8] = 8;
+        drive[446 + 12] = 8;
+        drive[510..512].copy_from_slice(&[0x55, 0xaa]);
+        let mut ctx = test_context(&drive);
+
+        let table = select_partition(&mut ctx, &Config::default(), false).unwrap();
+
+        assert_eq!(table.unwrap().table_type, TableType::Mbr);
+        assert_eq!(ctx.drive.window(), None);
+    }
+
+    #[test]
+    fn gaps()
+    {
+        let mut ctx = test_context(&mbr::tests::test_drive());
+        let table = read_table(&mut ctx).unwrap().unwrap();
+
+        // Only the gaps.
+        let map = drive_map(&mut ctx, &table, &[], true).unwrap();
+        assert_eq!(map.segments(), vec![
+            Segment { start: 0, end: 40 * 512, status: AllocStatus::Used },
+            Segment { start: 40 * 512, end: 64 * 512, status: AllocStatus::Free },
+            Segment { start: 64 * 512, end: 65 * 512, status: AllocStatus::Used },
+            Segment { start: 65 * 512, end: 66 * 512, status: AllocStatus::Free },
+            Segment { start: 66 * 512, end: 80 * 512, status: AllocStatus::Used },
+            Segment { start: 80 * 512, end: 94 * 512, status: AllocStatus::Free },
+            Segment { start: 94 * 512, end: 95 * 512, status: AllocStatus::Used },
+            Segment { start: 95 * 512, end: 96 * 512, status: AllocStatus::Free },
+            Segment { start: 96 * 512, end: 112 * 512, status: AllocStatus::Used },
+            Segment { start: 112 * 512, end: 128 * 512, status: AllocStatus::Free },
+        ]);
+
+        // With the map of the selected partition 2 (sectors 24-39).
+        let cfg = Config { partition: Some(2), ..Config::default() };
+        let table = select_partition(&mut ctx, &cfg, false).unwrap().unwrap();
+        let mut partition_map = UsageMap::new(16 * 512);
+        partition_map.update(0, 4 * 512, AllocStatus::Used);
+
+        let map = drive_map(&mut ctx, &table, &[(24 * 512, partition_map)], true).unwrap();
+        assert_eq!(ctx.drive.window(), None);
+        assert_eq!(&map.segments()[..2], &[
+            Segment { start: 0, end: 28 * 512, status: AllocStatus::Used },
+            Segment { start: 28 * 512, end: 64 * 512, status: AllocStatus::Free },
+        ]);
+    }
+
+    #[test]
+    fn combined()
+    {
+        let mut ctx = test_context(&mbr::tests::test_drive());
+        let table = read_table(&mut ctx).unwrap().unwrap();
+
+        // Partitions 1 and 6 are mapped, the other ones and the gaps stay used.
+        let mut map1 = UsageMap::new(16 * 512);
+        map1.update(0, 2 * 512, AllocStatus::Used);
+        let map6 = UsageMap::new(16 * 512);
+
+        let map = drive_map(&mut ctx, &table, &[(8 * 512, map1), (96 * 512, map6)], false).unwrap();
+
+        assert_eq!(map.segments(), vec![
+            Segment { start: 0, end: 10 * 512, status: AllocStatus::Used },
+            Segment { start: 10 * 512, end: 24 * 512, status: AllocStatus::Free },
+            Segment { start: 24 * 512, end: 96 * 512, status: AllocStatus::Used },
+            Segment { start: 96 * 512, end: 112 * 512, status: AllocStatus::Free },
+            Segment { start: 112 * 512, end: 128 * 512, status: AllocStatus::Used },
+        ]);
+    }
+}
@@ -0,0 +1,224 @@
+use std::io::Write;
+use std::fs::File;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use clap::ArgEnum;
+use serde::Serialize;
+
+/// The least time between two progress events of the same phase.
+const EVENT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Set by SIGUSR1: the status is to be printed at the next update of the progress.
+static STATUS_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Formats of the progress events.
+#[derive(Copy, Clone, Debug, ArgEnum)]
+pub enum ProgressFormat {
+    /// Newline-delimited JSON objects.
+    Json,
+}
+
+/// A progress event.
+#[derive(Serialize)]
+struct Event<'a> {
+    phase: &'a str,
+    bytes_done: u64,
+    bytes_total: u64,
+    /// Bytes per second, since the start of the phase.
+    rate: f64,
+}
+
+/// The progress of the current phase (the scan or the fill), reported as events, if configured.
+/// The events are written into the progress file (e.g. a FIFO), or onto stderr.
+#[derive(Debug)]
+pub struct Progress {
+    format: Option<ProgressFormat>,
+    file: Option<File>,
+    phase: &'static str,
+    /// The block group being scanned, if any.
+    group: Option<u64>,
+    done: u64,
+    total: u64,
+    phase_start: Instant,
+    last_event: Instant,
+}
+
+impl Progress {
+    pub fn new(format: Option<ProgressFormat>, file: Option<File>) -> Self
+    {
+        Self {
+            format,
+            file,
+            phase: "",
+            group: None,
+            done: 0,
+            total: 0,
+            phase_start: Instant::now(),
+            last_event: Instant::now(),
+        }
+    }
+
+    /// Starts a phase of `total` bytes.
+    pub fn start(&mut self, phase: &'static str, total: u64)
+    {
+        self.phase = phase;
+        self.group = None;
+        self.done = 0;
+        self.total = total;
+        self.phase_start = Instant::now();
+        self.emit();
+    }
+
+    /// Sets the bytes done in the current phase, reporting them at most once per EVENT_INTERVAL.
+    /// Prints the status, if requested by SIGUSR1 since the last update.
+    pub fn update(&mut self, done: u64)
+    {
+        self.done = done;
+
+        if self.last_event.elapsed() >= EVENT_INTERVAL {
+            self.emit();
+        }
+        if STATUS_REQUESTED.swap(false, Ordering::Relaxed) {
+            eprintln!("{}", self.status());
+        }
+    }
+
+    /// Sets the block group being scanned.
+    pub fn set_group(&mut self, group: Option<u64>)
+    {
+        self.group = group;
+    }
+
+    /// Ends the current phase, with all of its bytes done.
+    pub fn finish(&mut self)
+    {
+        self.done = self.total;
+        self.emit();
+    }
+
+    /// Returns the status of the current phase, for SIGUSR1.
+    pub fn status(&self) -> String
+    {
+        let group = match self.group {
+            Some(num) => format!(", block group {}", num),
+            None => String::new(),
+        };
+        let percent = self.done as f64 * 100.0 / self.total.max(1) as f64;
+
+        format!(
+            "status: {}{}: {} of {} bytes ({:.1}%), {:.2} MiB/s",
+            self.phase,
+            group,
+            self.done,
+            self.total,
+            percent,
+            self.rate() / (1024.0 * 1024.0),
+        )
+    }
+
+    /// Returns the rate of the current phase, in bytes per second.
+    pub fn rate(&self) -> f64
+    {
+        self.done as f64 / self.phase_start.elapsed().as_secs_f64().max(f64::EPSILON)
+    }
+
+    fn emit(&mut self)
+    {
+        self.last_event = Instant::now();
+
+        let line = match self.format {
+            Some(ProgressFormat::Json) => serde_json::to_string(&Event {
+                phase: self.phase,
+                bytes_done: self.done,
+                bytes_total: self.total,
+                rate: self.rate(),
+            }).unwrap(),
+            None => return,
+        };
+
+        // The progress is best-effort: a reader that went away does not stop the fill.
+        match &mut self.file {
+            Some(file) => { let _ = writeln!(file, "{}", line); },
+            None => eprintln!("{}", line),
+        }
+    }
+}
+
+/// Installs the handler of SIGUSR1, which requests the status to be printed, like with `dd`.
+#[cfg(target_os = "linux")]
+pub fn install_status_handler() -> std::io::Result<()>
+{
+    extern "C" fn handle(_: libc::c_int)
+    {
+        STATUS_REQUESTED.store(true, Ordering::Relaxed);
+    }
+
+    // SAFETY: the handler only stores into an atomic, which is async-signal-safe. SA_RESTART keeps
+    // the interrupted reads and writes from failing with EINTR.
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = handle as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        action.sa_flags = libc::SA_RESTART;
+        libc::sigemptyset(&mut action.sa_mask);
+
+        if libc::sigaction(libc::SIGUSR1, &action, std::ptr::null_mut()) != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn install_status_handler() -> std::io::Result<()>
+{
+    Ok(())
+}
+
+// Tests.
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Seek, SeekFrom};
+
+    use super::*;
+
+    #[test]
+    fn events()
+    {
+        let file = crate::util::temp_drive(&[]);
+        let mut progress = Progress::new(Some(ProgressFormat::Json), Some(file.try_clone().unwrap()));
+
+        // The updates between the events are not reported.
+        progress.start("fill", 100);
+        progress.update(10);
+        progress.update(20);
+        progress.finish();
+
+        let mut out = String::new();
+        let mut file = file;
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_to_string(&mut out).unwrap();
+
+        let events: Vec<serde_json::Value> = out.lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0]["phase"], "fill");
+        assert_eq!(events[0]["bytes_done"], 0);
+        assert_eq!(events[1]["bytes_done"], 100);
+        assert_eq!(events[1]["bytes_total"], 100);
+    }
+
+    #[test]
+    fn status()
+    {
+        let mut progress = Progress::new(None, None);
+        progress.start("scan", 400);
+        progress.set_group(Some(3));
+        progress.update(100);
+
+        let status = progress.status();
+        assert!(status.starts_with("status: scan, block group 3: 100 of 400 bytes (25.0%), "));
+    }
+}
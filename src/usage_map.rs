@@ -1,13 +1,15 @@
-use std::ops::{Index, IndexMut};
-use std::slice::SliceIndex;
+use std::collections::btree_map::{self, BTreeMap};
 
-use serde::Serialize;
-
-const MIN_CAPACITY: usize = 8200;
+use anyhow::bail;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
 /// Data structure for tracking free/used space on a drive.
-#[derive(Clone, Debug, Serialize)]
-pub struct UsageMap(pub Vec<Segment>);
+/// The segments are kept in a B-tree keyed by their start, so that an update takes logarithmic
+/// time in the number of segments, plus the time to remove the segments it covers.
+#[derive(Clone, Debug)]
+pub struct UsageMap {
+    segments: BTreeMap<u64, Segment>,
+}
 
 impl UsageMap {
     /// Creates a new UsageMap with the specified size.
@@ -15,34 +17,189 @@ impl UsageMap {
     {
         assert!(size > 0);
 
-        // TODO: implement better capacity prediction.
-        let capacity = usize::max(
-            MIN_CAPACITY,
-            size as usize / 30000
-        );
-
-        let mut vec = Vec::with_capacity(capacity);
-        vec.push(
-            Segment {
-                start: 0,
-                end: size,
-                status: AllocStatus::Free,
+        let mut segments = BTreeMap::new();
+        segments.insert(0, Segment { start: 0, end: size, status: AllocStatus::Free });
+
+        Self { segments }
+    }
+
+    /// Creates a UsageMap from a list of segments.
+    /// The segments have to be non-empty, sorted, contiguous and start at 0. Neighbours of the
+    /// same status are merged.
+    pub fn from_segments(segments: Vec<Segment>) -> anyhow::Result<Self>
+    {
+        if segments.is_empty() {
+            bail!("usage map has no segments");
+        }
+
+        let mut merged: Vec<Segment> = Vec::with_capacity(segments.len());
+        let mut prev_end = 0;
+
+        for seg in segments {
+            if seg.start != prev_end {
+                bail!("segment {}..{} does not continue from {}", seg.start, seg.end, prev_end);
             }
-        );
+            if seg.start >= seg.end {
+                bail!("segment {}..{} is empty", seg.start, seg.end);
+            }
+
+            prev_end = seg.end;
 
-        Self { 0: vec }
+            match merged.last_mut() {
+                Some(last) if last.status == seg.status => last.end = seg.end,
+                _ => merged.push(seg),
+            }
+        }
+
+        Ok(Self { segments: merged.into_iter().map(|s| (s.start, s)).collect() })
     }
 
     /// Returns the number of segments in the map.
     pub fn len(&self) -> usize
     {
-        self.0.len()
+        self.segments.len()
     }
 
     /// Returns the size of the map, i.e. the max address.
     pub fn size(&self) -> u64
     {
-        self.0.last().unwrap().end
+        self.segments.last_key_value().unwrap().1.end
+    }
+
+    /// Returns an iterator over the segments, in the order of their addresses.
+    pub fn iter(&self) -> btree_map::Values<'_, u64, Segment>
+    {
+        self.segments.values()
+    }
+
+    /// Returns the segments, in the order of their addresses.
+    pub fn segments(&self) -> Vec<Segment>
+    {
+        self.segments.values().copied().collect()
+    }
+
+    /// Returns an iterator over the segments that overlap `start..end`, in the order of their
+    /// addresses. The segments are not cut to the range.
+    pub fn overlapping(&self, start: u64, end: u64) -> impl Iterator<Item = &Segment>
+    {
+        let first = self.segment_at(start).map_or(start, |s| s.start);
+
+        self.segments.range(first..end).map(|(_, s)| s)
+    }
+
+    /// Returns the status of the byte at an address, or None if the address is past the end of the
+    /// map.
+    pub fn status_at(&self, addr: u64) -> Option<AllocStatus>
+    {
+        self.segment_at(addr).map(|s| s.status)
+    }
+
+    /// Returns an iterator over the free segments, in the order of their addresses.
+    pub fn iter_free(&self) -> impl Iterator<Item = &Segment>
+    {
+        self.iter().filter(|s| s.status == AllocStatus::Free)
+    }
+
+    /// Returns an iterator over the used segments, in the order of their addresses.
+    pub fn iter_used(&self) -> impl Iterator<Item = &Segment>
+    {
+        self.iter().filter(|s| s.status == AllocStatus::Used)
+    }
+
+    /// Returns the number of free bytes.
+    pub fn total_free(&self) -> u64
+    {
+        self.iter_free().map(|s| s.end - s.start).sum()
+    }
+
+    /// Returns the number of used bytes.
+    pub fn total_used(&self) -> u64
+    {
+        self.size() - self.total_free()
+    }
+
+    /// Returns the largest free segment, or the first of them if there are several. Returns None
+    /// if there is no free space.
+    pub fn largest_free(&self) -> Option<&Segment>
+    {
+        self.iter_free().reduce(|a, b| if b.end - b.start > a.end - a.start { b } else { a })
+    }
+
+    /// Returns the fragmentation of the free space: the share of it outside the largest free
+    /// segment. It is 0 when the free space is in one segment, or when there is none, and tends
+    /// to 1 as it is split into many small segments.
+    pub fn fragmentation(&self) -> f64
+    {
+        let total = self.total_free();
+        let largest = self.largest_free().map_or(0, |s| s.end - s.start);
+
+        if total == 0 { 0.0 } else { 1.0 - largest as f64 / total as f64 }
+    }
+
+    /// Returns the map of the bytes used in either of the maps.
+    pub fn union(&self, other: &UsageMap) -> anyhow::Result<UsageMap>
+    {
+        self.combine(other, |a, b| match (a, b) {
+            (AllocStatus::Free, AllocStatus::Free) => AllocStatus::Free,
+            _ => AllocStatus::Used,
+        })
+    }
+
+    /// Returns the map of the bytes used in both of the maps.
+    pub fn intersection(&self, other: &UsageMap) -> anyhow::Result<UsageMap>
+    {
+        self.combine(other, |a, b| match (a, b) {
+            (AllocStatus::Used, AllocStatus::Used) => AllocStatus::Used,
+            _ => AllocStatus::Free,
+        })
+    }
+
+    /// Returns the map of the bytes used in this map and free in the other one.
+    pub fn subtract(&self, other: &UsageMap) -> anyhow::Result<UsageMap>
+    {
+        self.combine(other, |a, b| match (a, b) {
+            (AllocStatus::Used, AllocStatus::Free) => AllocStatus::Used,
+            _ => AllocStatus::Free,
+        })
+    }
+
+    /// Combines two maps of the same size byte by byte, with the status given by `f` for the
+    /// statuses in this map and in the other one.
+    fn combine<F>(&self, other: &UsageMap, f: F) -> anyhow::Result<UsageMap>
+    where
+        F: Fn(AllocStatus, AllocStatus) -> AllocStatus,
+    {
+        if self.size() != other.size() {
+            bail!("the map sizes differ: {} and {}", self.size(), other.size());
+        }
+
+        let mut segments = Vec::new();
+        let mut a = self.iter().peekable();
+        let mut b = other.iter().peekable();
+
+        while let (Some(x), Some(y)) = (a.peek(), b.peek()) {
+            let start = std::cmp::max(x.start, y.start);
+            let end = std::cmp::min(x.end, y.end);
+            segments.push(Segment { start, end, status: f(x.status, y.status) });
+
+            if x.end == end {
+                a.next();
+            }
+            if y.end == end {
+                b.next();
+            }
+        }
+
+        UsageMap::from_segments(segments)
+    }
+
+    /// Returns the segment that contains an address, if any.
+    fn segment_at(&self, addr: u64) -> Option<&Segment>
+    {
+        self.segments.range(..=addr)
+            .next_back()
+            .map(|(_, s)| s)
+            .filter(|s| addr < s.end)
     }
 
     /// Updates the map.
@@ -62,101 +219,127 @@ impl UsageMap {
     /// Update the map with a raw Segment.
     pub fn add_segment(&mut self, new: Segment)
     {
-        let vector = &self.0;
-
         if new.start == new.end { return; }
         assert!(new.start < new.end);
-        assert!(new.end <= vector.iter().last().unwrap().end);
-
-        // Get the indices of the segments within which the new segment's start and end are.
+        assert!(new.end <= self.size());
 
-        let start_i = vector.iter().position(|e| {
-            new.start >= e.start && new.start < e.end
-        }).unwrap();
-        let end_i = vector.iter().position(|e| {
-            new.end > e.start && new.end <= e.end
-        }).unwrap();
+        // The segments within which the new segment's start and end are.
 
-        let vector = &mut self.0;
+        let first = *self.segment_at(new.start).unwrap();
+        let last = *self.segment_at(new.end - 1).unwrap();
 
+        // Remove all the segments the new one touches and put back their parts outside of it,
+        // merged with the new segment and the neighbours of the same status.
 
-        // If the start and the end are in one segment, duplicate the segment for consistency.
-        if start_i == end_i {
-            vector.insert(start_i + 1, vector[start_i]);
-        // Else, delete all the segments in-between the start and end segments.
-        } else {
-            for _ in (start_i + 1)..end_i {
-                vector.remove(start_i + 1);
-            }
+        let covered: Vec<u64> = self.segments.range(first.start..new.end).map(|(k, _)| *k).collect();
+        for key in covered {
+            self.segments.remove(&key);
         }
 
-        let end_i = start_i + 1;
-
-        // Business logic XD.
-
-        if vector[start_i].status == vector[end_i].status {
-            // Either join the segments or 'carve in' a new on by inserting a segment in between.
+        let mut merged = new;
 
-            if vector[start_i].status == new.status {
-                // Just join the start and end.
-
-                vector[start_i].end = vector[end_i].end;
-                vector.remove(end_i);
+        if first.start < new.start {
+            if first.status == new.status {
+                merged.start = first.start;
             } else {
-                // Insert a new segment between the two.
-
-                vector[start_i].end = new.start;
-                vector[end_i].start = new.end;
-                vector.insert(start_i + 1, new);
+                self.segments.insert(first.start, Segment { end: new.start, ..first });
             }
-        } else {
-            // Simply shrink and grow the appropriate segments.
-
-            if vector[start_i].status == new.status {
-                vector[start_i].end = new.end;
-                vector[end_i].start = new.end;
+        }
+        if new.end < last.end {
+            if last.status == new.status {
+                merged.end = last.end;
             } else {
-                vector[start_i].end = new.start;
-                vector[end_i].start = new.start;
+                self.segments.insert(new.end, Segment { start: new.end, ..last });
             }
         }
 
-        // Remove remaining zero-sized segments and merge neighbours of the same status.
+        if let Some(prev) = self.segments.range(..merged.start).next_back().map(|(_, s)| *s) {
+            if prev.end == merged.start && prev.status == merged.status {
+                self.segments.remove(&prev.start);
+                merged.start = prev.start;
+            }
+        }
+        if let Some(next) = self.segments.get(&merged.end).copied() {
+            if next.status == merged.status {
+                self.segments.remove(&next.start);
+                merged.end = next.end;
+            }
+        }
 
-        // FIXME: major bottlenecks.
-        self.clean_zero_sized();
-        self.merge_neighbours();
+        self.segments.insert(merged.start, merged);
     }
 
-    /// Remove zero-sized segments.
-    fn clean_zero_sized(&mut self)
+    /// Updates the map with a batch of segments, as `add_segment` does with each of them, in one
+    /// pass over the map: the batch is sorted and merged into the segments of the map.
+    /// The segments of the batch may overlap, but only with the same status; otherwise, the one
+    /// that starts later takes precedence over their overlap.
+    pub fn extend_from<I: IntoIterator<Item = Segment>>(&mut self, batch: I)
     {
-        while let Some(pos) = self.0.iter()
-            .position(|e| { e.start == e.end })
-        {
-            self.0.remove(pos);
+        let size = self.size();
+
+        let mut batch: Vec<Segment> = batch.into_iter().filter(|s| s.start != s.end).collect();
+        if batch.is_empty() {
+            return;
         }
-    }
+        batch.sort_by_key(|s| s.start);
 
-    /// Merge neighbouring segments of the same type.
-    fn merge_neighbours(&mut self)
-    {
-        let vector = &mut self.0;
-        let mut head = 0;
+        // Make the batch disjoint.
 
-        while head + 1 < vector.len() {
-            if vector[head].status == vector[head + 1].status {
-                vector[head].end = vector[head + 1].end;
-                vector.remove(head + 1);
-            } else {
-                head += 1;
+        let mut updates: Vec<Segment> = Vec::with_capacity(batch.len());
+        for seg in batch {
+            assert!(seg.start < seg.end);
+            assert!(seg.end <= size);
+
+            // The updates that the segment overlaps are the last ones: cut them around it.
+            let first = updates.partition_point(|u| u.end <= seg.start);
+            let overlapped: Vec<Segment> = updates.drain(first..).collect();
+
+            for u in &overlapped {
+                if u.start < seg.start {
+                    updates.push(Segment { end: seg.start, ..*u });
+                }
+            }
+            updates.push(seg);
+            for u in &overlapped {
+                if u.end > seg.end {
+                    updates.push(Segment { start: std::cmp::max(u.start, seg.end), ..*u });
+                }
             }
         }
+
+        // Lay the updates over the segments of the map, merging the neighbours of the same status.
+
+        let mut merged: Vec<Segment> = Vec::with_capacity(self.len() + 2 * updates.len());
+        let mut push = |seg: Segment| {
+            if seg.start == seg.end {
+                return;
+            }
+            match merged.last_mut() {
+                Some(last) if last.status == seg.status => last.end = seg.end,
+                _ => merged.push(seg),
+            }
+        };
+
+        let mut updates = updates.into_iter().peekable();
+        // The end of the last update laid over the map.
+        let mut covered = 0;
+        for old in self.segments.values() {
+            let mut pos = std::cmp::max(old.start, covered);
+            while let Some(update) = updates.next_if(|u| u.start < old.end) {
+                push(Segment { start: pos, end: std::cmp::max(pos, update.start), ..*old });
+                push(update);
+                pos = update.end;
+                covered = update.end;
+            }
+            push(Segment { start: std::cmp::min(pos, old.end), ..*old });
+        }
+
+        self.segments = merged.into_iter().map(|s| (s.start, s)).collect();
     }
 }
 
 /// Data structure representing a run of bytes on a drive.
-#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Segment {
     pub start: u64,
     pub end: u64,
@@ -171,7 +354,7 @@ impl Segment {
 }
 
 /// Allocation status of a Segment.
-#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum AllocStatus {
     Free,
@@ -180,57 +363,44 @@ pub enum AllocStatus {
 
 // Iterators.
 
-impl<'a> IntoIterator for UsageMap {
+impl IntoIterator for UsageMap {
     type Item = Segment;
-    type IntoIter = <Vec<Segment> as IntoIterator>::IntoIter;
+    type IntoIter = btree_map::IntoValues<u64, Segment>;
 
     fn into_iter(self) -> Self::IntoIter
     {
-        self.0.into_iter()
+        self.segments.into_values()
     }
 }
 
 impl<'a> IntoIterator for &'a UsageMap {
     type Item = &'a Segment;
-    type IntoIter = <&'a Vec<Segment> as IntoIterator>::IntoIter;
+    type IntoIter = btree_map::Values<'a, u64, Segment>;
 
     fn into_iter(self) -> Self::IntoIter
     {
-        self.0.as_slice().into_iter()
+        self.segments.values()
     }
 }
 
-impl<'a> IntoIterator for &'a mut UsageMap {
-    type Item = &'a mut Segment;
-    type IntoIter = <&'a mut Vec<Segment> as IntoIterator>::IntoIter;
+// Serialization.
 
-    fn into_iter(self) -> Self::IntoIter
+/// The map is serialized as the sequence of its segments.
+impl Serialize for UsageMap {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error>
     {
-        self.0.as_mut_slice().into_iter()
+        serializer.collect_seq(self.segments.values())
     }
 }
 
-// Indexing.
-
-impl<I> Index<I> for UsageMap
-where
-    I: SliceIndex<[Segment]>
-{
-    type Output = I::Output;
-
-    fn index(&self, index: I) -> &Self::Output
+/// The map is deserialized from the sequence of its segments, which are checked as by
+/// `UsageMap::from_segments`.
+impl<'de> Deserialize<'de> for UsageMap {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error>
     {
-        &self.0[index]
-    }
-}
+        let segments = Vec::<Segment>::deserialize(deserializer)?;
 
-impl<I> IndexMut<I> for UsageMap
-where
-    I: SliceIndex<[Segment]>
-{
-    fn index_mut(&mut self, index: I) -> &mut Self::Output
-    {
-        &mut self.0[index]
+        UsageMap::from_segments(segments).map_err(serde::de::Error::custom)
     }
 }
 
@@ -246,9 +416,6 @@ mod tests {
         // NOTE: tests were not done for:
         //  * UsageMap as IntoIterator.
         //  * &UsageMap as IntoIterator.
-        //  * &mut UsageMap as IntoIterator.
-        //  * UsageMap as Index.
-        //  * UsageMap as IndexMut.
         //
         //  * UsageMap::size().
 
@@ -257,7 +424,7 @@ mod tests {
         {
             let map = UsageMap::new(5);
 
-            assert_eq!(map[0], Segment { start: 0, end: 5, status: AllocStatus::Free });
+            assert_eq!(map.segments()[0], Segment { start: 0, end: 5, status: AllocStatus::Free });
         }
 
         #[test]
@@ -284,16 +451,41 @@ mod tests {
             assert_eq!(map.len(), 2);
         }
 
+        #[test]
+        fn from_segments_merges()
+        {
+            let map = UsageMap::from_segments(vec![
+                Segment { start: 0, end: 5, status: AllocStatus::Used },
+                Segment { start: 5, end: 8, status: AllocStatus::Used },
+                Segment { start: 8, end: 10, status: AllocStatus::Free },
+            ]).unwrap();
+
+            assert_eq!(map.len(), 2);
+            assert_eq!(map.segments()[0], Segment { start: 0, end: 8, status: AllocStatus::Used });
+            assert_eq!(map.segments()[1], Segment { start: 8, end: 10, status: AllocStatus::Free });
+        }
+
+        #[test]
+        fn from_segments_gap()
+        {
+            let map = UsageMap::from_segments(vec![
+                Segment { start: 0, end: 5, status: AllocStatus::Used },
+                Segment { start: 6, end: 10, status: AllocStatus::Free },
+            ]);
+
+            assert!(map.is_err());
+        }
+
         #[test]
         fn add_segment_start_eq_end()
         {
             let mut map = UsageMap::new(5);
-            let orig_e = map[0];
+            let orig_e = map.segments()[0];
 
             map.add_segment(Segment { start: 1, end: 1, status: AllocStatus::Used });
 
             assert_eq!(map.len(), 1);
-            assert_eq!(map[0], orig_e);
+            assert_eq!(map.segments()[0], orig_e);
         }
 
         #[test]
@@ -320,9 +512,9 @@ mod tests {
             map.add_segment(new_segment);
 
             assert_eq!(map.len(), 3);
-            assert_eq!(map[0], Segment { start: 0, end: 2, status: AllocStatus::Free });
-            assert_eq!(map[1], new_segment);
-            assert_eq!(map[2], Segment { start: 11, end: 20, status: AllocStatus::Free });
+            assert_eq!(map.segments()[0], Segment { start: 0, end: 2, status: AllocStatus::Free });
+            assert_eq!(map.segments()[1], new_segment);
+            assert_eq!(map.segments()[2], Segment { start: 11, end: 20, status: AllocStatus::Free });
         }
 
         #[test]
@@ -333,9 +525,9 @@ mod tests {
             map.add_segment(new_segment);
 
             assert_eq!(map.len(), 1);
-            assert_eq!(map[0].start, 0);
-            assert_eq!(map[0].end, 20);
-            assert_eq!(map[0].status, AllocStatus::Free);
+            assert_eq!(map.segments()[0].start, 0);
+            assert_eq!(map.segments()[0].end, 20);
+            assert_eq!(map.segments()[0].status, AllocStatus::Free);
         }
 
         #[test]
@@ -347,12 +539,12 @@ mod tests {
             map.add_segment(new_segment);
 
             assert_eq!(map.len(), 2);
-            assert_eq!(map[0].start, 0);
-            assert_eq!(map[0].end, 11);
-            assert_eq!(map[0].status, AllocStatus::Free);
-            assert_eq!(map[1].start, 11);
-            assert_eq!(map[1].end, 20);
-            assert_eq!(map[1].status, AllocStatus::Used);
+            assert_eq!(map.segments()[0].start, 0);
+            assert_eq!(map.segments()[0].end, 11);
+            assert_eq!(map.segments()[0].status, AllocStatus::Free);
+            assert_eq!(map.segments()[1].start, 11);
+            assert_eq!(map.segments()[1].end, 20);
+            assert_eq!(map.segments()[1].status, AllocStatus::Used);
         }
 
         #[test]
@@ -364,12 +556,12 @@ mod tests {
             map.add_segment(new_segment);
 
             assert_eq!(map.len(), 2);
-            assert_eq!(map[0].start, 0);
-            assert_eq!(map[0].end, 5);
-            assert_eq!(map[0].status, AllocStatus::Free);
-            assert_eq!(map[1].start, 5);
-            assert_eq!(map[1].end, 20);
-            assert_eq!(map[1].status, AllocStatus::Used);
+            assert_eq!(map.segments()[0].start, 0);
+            assert_eq!(map.segments()[0].end, 5);
+            assert_eq!(map.segments()[0].status, AllocStatus::Free);
+            assert_eq!(map.segments()[1].start, 5);
+            assert_eq!(map.segments()[1].end, 20);
+            assert_eq!(map.segments()[1].status, AllocStatus::Used);
         }
 
         #[test]
@@ -383,29 +575,29 @@ mod tests {
 
             assert_eq!(map.len(), 7);
 
-            assert_eq!(map[0].status, AllocStatus::Free);
-            assert_eq!(map[1].status, AllocStatus::Used);
-            assert_eq!(map[2].status, AllocStatus::Free);
-            assert_eq!(map[3].status, AllocStatus::Used);
-            assert_eq!(map[4].status, AllocStatus::Free);
-            assert_eq!(map[5].status, AllocStatus::Used);
-            assert_eq!(map[6].status, AllocStatus::Free);
-
-            assert_eq!(map[0].start, 0);
-            assert_eq!(map[1].start, 10);
-            assert_eq!(map[2].start, 20);
-            assert_eq!(map[3].start, 30);
-            assert_eq!(map[4].start, 40);
-            assert_eq!(map[5].start, 50);
-            assert_eq!(map[6].start, 60);
-
-            assert_eq!(map[0].end, 10);
-            assert_eq!(map[1].end, 20);
-            assert_eq!(map[2].end, 30);
-            assert_eq!(map[3].end, 40);
-            assert_eq!(map[4].end, 50);
-            assert_eq!(map[5].end, 60);
-            assert_eq!(map[6].end, 100);
+            assert_eq!(map.segments()[0].status, AllocStatus::Free);
+            assert_eq!(map.segments()[1].status, AllocStatus::Used);
+            assert_eq!(map.segments()[2].status, AllocStatus::Free);
+            assert_eq!(map.segments()[3].status, AllocStatus::Used);
+            assert_eq!(map.segments()[4].status, AllocStatus::Free);
+            assert_eq!(map.segments()[5].status, AllocStatus::Used);
+            assert_eq!(map.segments()[6].status, AllocStatus::Free);
+
+            assert_eq!(map.segments()[0].start, 0);
+            assert_eq!(map.segments()[1].start, 10);
+            assert_eq!(map.segments()[2].start, 20);
+            assert_eq!(map.segments()[3].start, 30);
+            assert_eq!(map.segments()[4].start, 40);
+            assert_eq!(map.segments()[5].start, 50);
+            assert_eq!(map.segments()[6].start, 60);
+
+            assert_eq!(map.segments()[0].end, 10);
+            assert_eq!(map.segments()[1].end, 20);
+            assert_eq!(map.segments()[2].end, 30);
+            assert_eq!(map.segments()[3].end, 40);
+            assert_eq!(map.segments()[4].end, 50);
+            assert_eq!(map.segments()[5].end, 60);
+            assert_eq!(map.segments()[6].end, 100);
         }
 
         #[test]
@@ -421,23 +613,23 @@ mod tests {
 
             assert_eq!(map.len(), 5);
 
-            assert_eq!(map[0].status, AllocStatus::Free);
-            assert_eq!(map[1].status, AllocStatus::Used);
-            assert_eq!(map[2].status, AllocStatus::Free);
-            assert_eq!(map[3].status, AllocStatus::Used);
-            assert_eq!(map[4].status, AllocStatus::Free);
-
-            assert_eq!(map[0].start, 0);
-            assert_eq!(map[1].start, 10);
-            assert_eq!(map[2].start, 45);
-            assert_eq!(map[3].start, 50);
-            assert_eq!(map[4].start, 60);
-
-            assert_eq!(map[0].end, 10);
-            assert_eq!(map[1].end, 45);
-            assert_eq!(map[2].end, 50);
-            assert_eq!(map[3].end, 60);
-            assert_eq!(map[4].end, 100);
+            assert_eq!(map.segments()[0].status, AllocStatus::Free);
+            assert_eq!(map.segments()[1].status, AllocStatus::Used);
+            assert_eq!(map.segments()[2].status, AllocStatus::Free);
+            assert_eq!(map.segments()[3].status, AllocStatus::Used);
+            assert_eq!(map.segments()[4].status, AllocStatus::Free);
+
+            assert_eq!(map.segments()[0].start, 0);
+            assert_eq!(map.segments()[1].start, 10);
+            assert_eq!(map.segments()[2].start, 45);
+            assert_eq!(map.segments()[3].start, 50);
+            assert_eq!(map.segments()[4].start, 60);
+
+            assert_eq!(map.segments()[0].end, 10);
+            assert_eq!(map.segments()[1].end, 45);
+            assert_eq!(map.segments()[2].end, 50);
+            assert_eq!(map.segments()[3].end, 60);
+            assert_eq!(map.segments()[4].end, 100);
         }
 
         #[test]
@@ -453,23 +645,23 @@ mod tests {
 
             assert_eq!(map.len(), 5);
 
-            assert_eq!(map[0].status, AllocStatus::Free);
-            assert_eq!(map[1].status, AllocStatus::Used);
-            assert_eq!(map[2].status, AllocStatus::Free);
-            assert_eq!(map[3].status, AllocStatus::Used);
-            assert_eq!(map[4].status, AllocStatus::Free);
-
-            assert_eq!(map[0].start, 0);
-            assert_eq!(map[1].start, 10);
-            assert_eq!(map[2].start, 15);
-            assert_eq!(map[3].start, 50);
-            assert_eq!(map[4].start, 60);
-
-            assert_eq!(map[0].end, 10);
-            assert_eq!(map[1].end, 15);
-            assert_eq!(map[2].end, 50);
-            assert_eq!(map[3].end, 60);
-            assert_eq!(map[4].end, 100);
+            assert_eq!(map.segments()[0].status, AllocStatus::Free);
+            assert_eq!(map.segments()[1].status, AllocStatus::Used);
+            assert_eq!(map.segments()[2].status, AllocStatus::Free);
+            assert_eq!(map.segments()[3].status, AllocStatus::Used);
+            assert_eq!(map.segments()[4].status, AllocStatus::Free);
+
+            assert_eq!(map.segments()[0].start, 0);
+            assert_eq!(map.segments()[1].start, 10);
+            assert_eq!(map.segments()[2].start, 15);
+            assert_eq!(map.segments()[3].start, 50);
+            assert_eq!(map.segments()[4].start, 60);
+
+            assert_eq!(map.segments()[0].end, 10);
+            assert_eq!(map.segments()[1].end, 15);
+            assert_eq!(map.segments()[2].end, 50);
+            assert_eq!(map.segments()[3].end, 60);
+            assert_eq!(map.segments()[4].end, 100);
         }
 
         #[test]
@@ -483,20 +675,20 @@ mod tests {
 
             assert_eq!(map.len(), 4);
 
-            assert_eq!(map[0].status, AllocStatus::Free);
-            assert_eq!(map[1].status, AllocStatus::Used);
-            assert_eq!(map[2].status, AllocStatus::Free);
-            assert_eq!(map[3].status, AllocStatus::Used);
+            assert_eq!(map.segments()[0].status, AllocStatus::Free);
+            assert_eq!(map.segments()[1].status, AllocStatus::Used);
+            assert_eq!(map.segments()[2].status, AllocStatus::Free);
+            assert_eq!(map.segments()[3].status, AllocStatus::Used);
 
-            assert_eq!(map[0].start, 0);
-            assert_eq!(map[1].start, 10);
-            assert_eq!(map[2].start, 25);
-            assert_eq!(map[3].start, 30);
+            assert_eq!(map.segments()[0].start, 0);
+            assert_eq!(map.segments()[1].start, 10);
+            assert_eq!(map.segments()[2].start, 25);
+            assert_eq!(map.segments()[3].start, 30);
 
-            assert_eq!(map[0].end, 10);
-            assert_eq!(map[1].end, 25);
-            assert_eq!(map[2].end, 30);
-            assert_eq!(map[3].end, 40);
+            assert_eq!(map.segments()[0].end, 10);
+            assert_eq!(map.segments()[1].end, 25);
+            assert_eq!(map.segments()[2].end, 30);
+            assert_eq!(map.segments()[3].end, 40);
         }
 
         #[test]
@@ -510,14 +702,14 @@ mod tests {
 
             assert_eq!(map.len(), 2);
 
-            assert_eq!(map[0].status, AllocStatus::Free);
-            assert_eq!(map[1].status, AllocStatus::Used);
+            assert_eq!(map.segments()[0].status, AllocStatus::Free);
+            assert_eq!(map.segments()[1].status, AllocStatus::Used);
 
-            assert_eq!(map[0].start, 0);
-            assert_eq!(map[1].start, 30);
+            assert_eq!(map.segments()[0].start, 0);
+            assert_eq!(map.segments()[1].start, 30);
 
-            assert_eq!(map[0].end, 30);
-            assert_eq!(map[1].end, 40);
+            assert_eq!(map.segments()[0].end, 30);
+            assert_eq!(map.segments()[1].end, 40);
         }
 
         #[test]
@@ -531,20 +723,20 @@ mod tests {
 
             assert_eq!(map.len(), 4);
 
-            assert_eq!(map[0].status, AllocStatus::Free);
-            assert_eq!(map[1].status, AllocStatus::Used);
-            assert_eq!(map[2].status, AllocStatus::Free);
-            assert_eq!(map[3].status, AllocStatus::Used);
+            assert_eq!(map.segments()[0].status, AllocStatus::Free);
+            assert_eq!(map.segments()[1].status, AllocStatus::Used);
+            assert_eq!(map.segments()[2].status, AllocStatus::Free);
+            assert_eq!(map.segments()[3].status, AllocStatus::Used);
 
-            assert_eq!(map[0].start, 0);
-            assert_eq!(map[1].start, 10);
-            assert_eq!(map[2].start, 15);
-            assert_eq!(map[3].start, 30);
+            assert_eq!(map.segments()[0].start, 0);
+            assert_eq!(map.segments()[1].start, 10);
+            assert_eq!(map.segments()[2].start, 15);
+            assert_eq!(map.segments()[3].start, 30);
 
-            assert_eq!(map[0].end, 10);
-            assert_eq!(map[1].end, 15);
-            assert_eq!(map[2].end, 30);
-            assert_eq!(map[3].end, 40);
+            assert_eq!(map.segments()[0].end, 10);
+            assert_eq!(map.segments()[1].end, 15);
+            assert_eq!(map.segments()[2].end, 30);
+            assert_eq!(map.segments()[3].end, 40);
         }
 
         #[test]
@@ -558,14 +750,189 @@ mod tests {
 
             assert_eq!(map.len(), 2);
 
-            assert_eq!(map[0].status, AllocStatus::Free);
-            assert_eq!(map[1].status, AllocStatus::Used);
+            assert_eq!(map.segments()[0].status, AllocStatus::Free);
+            assert_eq!(map.segments()[1].status, AllocStatus::Used);
+
+            assert_eq!(map.segments()[0].start, 0);
+            assert_eq!(map.segments()[1].start, 10);
+
+            assert_eq!(map.segments()[0].end, 10);
+            assert_eq!(map.segments()[1].end, 40);
+        }
+    }
+
+    mod btree {
+        use rand::prelude::*;
+        use rand_chacha::ChaCha20Rng;
+
+        use super::*;
+
+        #[test]
+        fn add_segment_matches_model()
+        {
+            // Every byte's status, updated naively.
+            let mut model = vec![AllocStatus::Free; 500];
+            let mut map = UsageMap::new(500);
+            let rng = &mut ChaCha20Rng::from_seed([8; 32]);
+
+            for _ in 0..2000 {
+                let start = rng.gen_range(0..500);
+                let end = rng.gen_range(start..=std::cmp::min(start + 60, 500));
+                let status = if rng.gen() { AllocStatus::Used } else { AllocStatus::Free };
+
+                map.add_segment(Segment { start, end, status });
+                model[start as usize..end as usize].fill(status);
+
+                let segments = map.segments();
+                let from_model = UsageMap::from_segments(
+                    (0..500).map(|i| Segment { start: i, end: i + 1, status: model[i as usize] })
+                        .collect()
+                ).unwrap();
+                assert_eq!(segments, from_model.segments());
+            }
+        }
+
+        #[test]
+        fn extend_from_matches_add_segment()
+        {
+            let rng = &mut ChaCha20Rng::from_seed([9; 32]);
+
+            for _ in 0..200 {
+                let mut map = UsageMap::new(1000);
+                for _ in 0..20 {
+                    let start = rng.gen_range(0..1000);
+                    map.update(start, rng.gen_range(0..100), AllocStatus::Used);
+                }
+
+                let mut batch: Vec<Segment> = (0..30).map(|_| {
+                    let start = rng.gen_range(0..1000);
+                    let end = rng.gen_range(start..=std::cmp::min(start + 100, 1000));
+                    let status = if rng.gen() { AllocStatus::Used } else { AllocStatus::Free };
+                    Segment { start, end, status }
+                }).collect();
+
+                let mut extended = map.clone();
+                extended.extend_from(batch.clone());
+
+                // The segments that start later take precedence.
+                batch.sort_by_key(|s| s.start);
+                for seg in batch {
+                    map.add_segment(seg);
+                }
+
+                assert_eq!(extended.segments(), map.segments());
+            }
+        }
+
+        #[test]
+        fn overlapping()
+        {
+            let mut map = UsageMap::new(100);
+            map.update(10, 10, AllocStatus::Used);
+            map.update(30, 10, AllocStatus::Used);
+
+            let starts = |s, e| map.overlapping(s, e).map(|s| s.start).collect::<Vec<_>>();
+            assert_eq!(starts(15, 31), vec![10, 20, 30]);
+            assert_eq!(starts(20, 30), vec![20]);
+            assert_eq!(starts(0, 100).len(), 5);
+            assert!(starts(100, 200).is_empty());
+        }
+
+        #[test]
+        fn serialize()
+        {
+            let mut map = UsageMap::new(100);
+            map.update(10, 10, AllocStatus::Used);
+
+            assert_eq!(serde_json::to_string(&map).unwrap(), concat!(
+                r#"[{"start":0,"end":10,"status":"free"},"#,
+                r#"{"start":10,"end":20,"status":"used"},"#,
+                r#"{"start":20,"end":100,"status":"free"}]"#,
+            ));
+        }
 
-            assert_eq!(map[0].start, 0);
-            assert_eq!(map[1].start, 10);
+        #[test]
+        fn deserialize()
+        {
+            let mut map = UsageMap::new(100);
+            map.update(10, 10, AllocStatus::Used);
+
+            let json = serde_json::to_string(&map).unwrap();
+            let loaded: UsageMap = serde_json::from_str(&json).unwrap();
+            assert_eq!(loaded.segments(), map.segments());
+
+            let from = |s| serde_json::from_str::<UsageMap>(s);
+            assert!(from("[]").is_err());
+            // Not starting at 0, overlapping, with a gap, empty, with a bad status.
+            assert!(from(r#"[{"start":1,"end":10,"status":"free"}]"#).is_err());
+            assert!(from(concat!(
+                r#"[{"start":0,"end":10,"status":"free"},"#,
+                r#"{"start":5,"end":20,"status":"used"}]"#,
+            )).is_err());
+            assert!(from(concat!(
+                r#"[{"start":0,"end":10,"status":"free"},"#,
+                r#"{"start":15,"end":20,"status":"used"}]"#,
+            )).is_err());
+            assert!(from(r#"[{"start":0,"end":0,"status":"free"}]"#).is_err());
+            assert!(from(r#"[{"start":0,"end":10,"status":"gone"}]"#).is_err());
+        }
+    }
+
+    mod query {
+        use super::*;
+
+        #[test]
+        fn queries()
+        {
+            let mut map = UsageMap::new(100);
+            map.update(10, 10, AllocStatus::Used);
+            map.update(30, 40, AllocStatus::Used);
+
+            assert_eq!(map.status_at(0), Some(AllocStatus::Free));
+            assert_eq!(map.status_at(19), Some(AllocStatus::Used));
+            assert_eq!(map.status_at(20), Some(AllocStatus::Free));
+            assert_eq!(map.status_at(100), None);
+
+            assert_eq!(map.iter_free().map(|s| s.start).collect::<Vec<_>>(), vec![0, 20, 70]);
+            assert_eq!(map.iter_used().map(|s| s.start).collect::<Vec<_>>(), vec![10, 30]);
+            assert_eq!((map.total_free(), map.total_used()), (50, 50));
+            assert_eq!(map.largest_free().map(|s| s.start), Some(70));
+            assert!((map.fragmentation() - 0.4).abs() < 1e-9);
+        }
+
+        #[test]
+        fn set_operations()
+        {
+            let mut a = UsageMap::new(100);
+            a.update(10, 30, AllocStatus::Used);
+            let mut b = UsageMap::new(100);
+            b.update(30, 30, AllocStatus::Used);
+            b.update(90, 10, AllocStatus::Used);
+
+            let used = |m: UsageMap| m.iter_used().map(|s| (s.start, s.end)).collect::<Vec<_>>();
+            assert_eq!(used(a.union(&b).unwrap()), vec![(10, 60), (90, 100)]);
+            assert_eq!(used(a.intersection(&b).unwrap()), vec![(30, 40)]);
+            assert_eq!(used(a.subtract(&b).unwrap()), vec![(10, 30)]);
+            assert_eq!(used(b.subtract(&a).unwrap()), vec![(40, 60), (90, 100)]);
+            assert!(a.union(&UsageMap::new(50)).is_err());
+
+            // A map freed by a subtraction and loaded for the fill only narrows down the scan.
+            let freed = a.subtract(&a).unwrap();
+            assert_eq!(freed.total_used(), 0);
+            assert_eq!(used(a.union(&freed).unwrap()), used(a.clone()));
+            assert_eq!(used(b.union(&b.subtract(&a).unwrap()).unwrap()), used(b.clone()));
+        }
+
+        #[test]
+        fn no_free_space()
+        {
+            let mut map = UsageMap::new(100);
+            assert_eq!(map.fragmentation(), 0.0);
 
-            assert_eq!(map[0].end, 10);
-            assert_eq!(map[1].end, 40);
+            map.update(0, 100, AllocStatus::Used);
+            assert_eq!(map.total_free(), 0);
+            assert_eq!(map.largest_free(), None);
+            assert_eq!(map.fragmentation(), 0.0);
         }
     }
 
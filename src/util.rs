@@ -5,3 +5,88 @@ macro_rules! hilo {
         ((($hi as u64) << 32) | $lo as u64)
     };
 }
+
+/// Formats a UUID in the canonical form, e.g. `01234567-89ab-cdef-0123-456789abcdef`.
+pub fn format_uuid(uuid: &[u8; 16]) -> String
+{
+    let hex: String = uuid.iter().map(|b| format!("{:02x}", b)).collect();
+
+    format!("{}-{}-{}-{}-{}", &hex[0..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..32])
+}
+
+/// Writes a file through `write`, atomically: the contents go into a temporary file first, which
+/// then replaces the file, so that an interruption never leaves a partial file behind.
+pub fn write_atomically<F>(path: &std::path::Path, write: F) -> anyhow::Result<()>
+where
+    F: FnOnce(&mut std::io::BufWriter<std::fs::File>) -> anyhow::Result<()>
+{
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+
+    let mut w = std::io::BufWriter::new(std::fs::File::create(&tmp_path)?);
+    write(&mut w)?;
+
+    w.into_inner()?.sync_all()?;
+    std::fs::rename(&tmp_path, path)?;
+
+    Ok(())
+}
+
+/// Returns a unique path in the temporary directory. The file is not created.
+#[cfg(test)]
+pub fn temp_path() -> std::path::PathBuf
+{
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    std::env::temp_dir().join(format!(
+        "fsfill-test-{}-{}",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed),
+    ))
+}
+
+/// Creates a read-write drive backed by an anonymous temporary file with the supplied contents.
+#[cfg(test)]
+pub fn temp_drive(contents: &[u8]) -> std::fs::File
+{
+    use std::io::{Seek, SeekFrom, Write};
+
+    let path = temp_path();
+    let mut f = std::fs::OpenOptions::new()
+        .create(true)
+        .truncate(true)
+        .read(true)
+        .write(true)
+        .open(&path)
+        .unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    f.write_all(contents).unwrap();
+    f.seek(SeekFrom::Start(0)).unwrap();
+
+    f
+}
+
+/// Creates a Context with a temporary drive with the supplied contents.
+#[cfg(test)]
+pub fn test_context(contents: &[u8]) -> crate::Context
+{
+    let cfg = crate::Config::default();
+
+    crate::Context {
+        drive: crate::drive::Drive::new(temp_drive(contents)),
+        logger: crate::logger::Logger::new(None, &cfg),
+        anomalies: crate::anomaly::Anomalies::default(),
+        space: None,
+        free_counts: None,
+        encoding: None,
+        fs_identity: None,
+        mmp: Vec::new(),
+        scrub_writes: Vec::new(),
+        fill_stats: crate::fill::FillStats::default(),
+        progress: crate::progress::Progress::new(None, None),
+        direct_drive: None,
+    }
+}